@@ -26,7 +26,7 @@ echo "📦  Swift → build/libappleai.dylib"
 swiftc \
   -O -whole-module-optimization \
   -emit-library -emit-module -module-name AppleOnDeviceAI \
-  -framework Foundation -framework FoundationModels \
+  -framework Foundation -framework FoundationModels -framework AppKit -framework PDFKit -framework Vision -framework ImagePlayground -framework Speech -framework SoundAnalysis -framework AVFoundation -framework Translation \
   -target arm64-apple-macos26.0 \
   -Xlinker -install_name -Xlinker @rpath/libappleai.dylib \
   -Xlinker -rpath -Xlinker @loader_path \
@@ -38,7 +38,8 @@ echo "✅  Swift dylib built"
 # Build Rust addon
 echo "🦀  Cargo (release)"
 pushd native >/dev/null
-cargo build --release --quiet
+# Optional Cargo features, e.g. CARGO_FEATURES=grpc
+cargo build --release --quiet ${CARGO_FEATURES:+--features "$CARGO_FEATURES"}
 popd >/dev/null
 
 # Copy and rename the compiled addon to the build directory so Node/Bun can load it
@@ -52,4 +53,11 @@ else
     echo "⚠️  Rust addon not found at $ADDON_SRC. Did the build fail?"
 fi
 
+# The CLI finds libappleai.dylib next to itself, like the addon
+CLI_SRC="native/target/release/apple-ai"
+if [[ -f "$CLI_SRC" ]]; then
+    cp "$CLI_SRC" build/apple-ai
+    echo "📁 CLI location: build/apple-ai"
+fi
+
 echo "🎉  All components built successfully!" 
\ No newline at end of file
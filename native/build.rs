@@ -25,4 +25,19 @@ fn main() {
         println!("cargo:rustc-link-arg=-undefined");
         println!("cargo:rustc-link-arg=dynamic_lookup");
     }
+
+    // ────────────────────────────────────────────────────────────────
+    // 3. `grpc` feature: generate the service from the .proto, with a
+    //    pure-Rust compiler so no protoc has to be installed
+    // ────────────────────────────────────────────────────────────────
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/apple_ai.proto");
+        let descriptors = protox::compile(["apple_ai.proto"], ["proto"])
+            .expect("proto/apple_ai.proto doesn't compile");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("cannot generate the gRPC service");
+    }
 }
@@ -0,0 +1,501 @@
+//! Anthropic Messages API compatibility: a Messages request body runs
+//! through the unified generation call, and the result comes back as a
+//! Messages `message` object, so agents written against that API can move to
+//! the on-device model.
+
+use crate::{
+    ChatMessage, ContentPart, GenerateUnifiedTask, GenerationOptions, GenerationRequest,
+    GenerationResult, SamplingOptions, ToolCall, ToolCallFunction,
+};
+use napi::bindgen_prelude::{AsyncTask, Either};
+use napi::{Env, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+
+fn invalid(reason: impl std::fmt::Display) -> napi::Error {
+    napi::Error::new(Status::InvalidArg, reason.to_string())
+}
+
+/// A parsed Messages request.
+pub struct MessagesRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub options: GenerationOptions,
+    pub stream: bool,
+}
+
+/// Translate a Messages request body. `system` becomes a system message,
+/// `tool_result` blocks become tool messages, and `tool_use` blocks become
+/// assistant tool calls.
+pub fn parse_request(request_json: &str) -> napi::Result<MessagesRequest> {
+    let body: Value = serde_json::from_str(request_json)
+        .map_err(|e| invalid(format!("Invalid request JSON: {e}")))?;
+    if !body.is_object() {
+        return Err(invalid("request must be a JSON object"));
+    }
+
+    let mut messages = Vec::new();
+    match body.get("system") {
+        None | Some(Value::Null) => {}
+        Some(system) => {
+            let text = block_text(system)
+                .ok_or_else(|| invalid("system must be a string or an array of text blocks"))?;
+            messages.push(text_message("system", text));
+        }
+    }
+    let turns = body
+        .get("messages")
+        .and_then(Value::as_array)
+        .ok_or_else(|| invalid("messages is required and must be an array"))?;
+    for (index, turn) in turns.iter().enumerate() {
+        parse_turn(index, turn, &mut messages)?;
+    }
+
+    let number = |key: &str| body.get(key).and_then(Value::as_f64);
+    let tools_json = match body.pointer("/tool_choice/type").and_then(Value::as_str) {
+        Some("none") => None,
+        None | Some("auto") => parse_tools(body.get("tools"))?,
+        Some(other) => {
+            return Err(invalid(format!(
+                "tool_choice {other:?} is not supported; use \"auto\" or \"none\""
+            )))
+        }
+    };
+    // Wrapping an out-of-range top_k would sample from a different number of tokens
+    let top_k = match body.get("top_k") {
+        None | Some(Value::Null) => None,
+        Some(top_k) => Some(
+            top_k
+                .as_u64()
+                .and_then(|top_k| u32::try_from(top_k).ok())
+                .ok_or_else(|| {
+                    invalid(format!("top_k must be an integer from 1 to {}", u32::MAX))
+                })?,
+        ),
+    };
+    let options = GenerationOptions {
+        tools_json,
+        temperature: number("temperature"),
+        max_tokens: body
+            .get("max_tokens")
+            .and_then(Value::as_i64)
+            .map(|n| n.clamp(0, i32::MAX.into()) as i32),
+        stop: body
+            .get("stop_sequences")
+            .and_then(Value::as_array)
+            .map(|stops| {
+                stops
+                    .iter()
+                    .filter_map(|s| s.as_str().map(str::to_string))
+                    .collect()
+            }),
+        sampling: Some(SamplingOptions {
+            top_p: number("top_p"),
+            top_k,
+            ..Default::default()
+        }),
+        // The caller runs the tools and sends back `tool_result` blocks
+        stop_after_tool_calls: Some(true),
+        ..Default::default()
+    };
+    Ok(MessagesRequest {
+        model: body
+            .get("model")
+            .and_then(Value::as_str)
+            .unwrap_or(crate::openai::MODEL_NAME)
+            .to_string(),
+        messages,
+        options,
+        stream: body.get("stream").and_then(Value::as_bool).unwrap_or(false),
+    })
+}
+
+fn text_message(role: &str, text: String) -> ChatMessage {
+    ChatMessage {
+        role: role.to_string(),
+        content: Some(Either::A(text)),
+        name: None,
+        tool_call_id: None,
+        tool_calls: None,
+    }
+}
+
+/// Text of a string or of an array of text blocks, joined by newlines.
+fn block_text(content: &Value) -> Option<String> {
+    match content {
+        Value::String(text) => Some(text.clone()),
+        Value::Array(blocks) => Some(
+            blocks
+                .iter()
+                .filter(|block| block.get("type").and_then(Value::as_str) == Some("text"))
+                .filter_map(|block| block.get("text").and_then(Value::as_str))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+        _ => None,
+    }
+}
+
+fn parse_turn(index: usize, turn: &Value, messages: &mut Vec<ChatMessage>) -> napi::Result<()> {
+    let role = match turn.get("role").and_then(Value::as_str) {
+        Some(role @ ("user" | "assistant")) => role,
+        other => {
+            return Err(invalid(format!(
+                "messages[{index}]: role must be \"user\" or \"assistant\", got {other:?}"
+            )))
+        }
+    };
+    let blocks = match turn.get("content") {
+        Some(Value::String(text)) => {
+            messages.push(text_message(role, text.clone()));
+            return Ok(());
+        }
+        Some(Value::Array(blocks)) => blocks,
+        _ => {
+            return Err(invalid(format!(
+                "messages[{index}]: content must be a string or an array of blocks"
+            )))
+        }
+    };
+
+    let mut parts = Vec::new();
+    let mut tool_calls = Vec::new();
+    for (block_index, block) in blocks.iter().enumerate() {
+        let at = || format!("messages[{index}].content[{block_index}]");
+        match block.get("type").and_then(Value::as_str) {
+            Some("text") => parts.push(text_part(
+                block
+                    .get("text")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default(),
+            )),
+            Some("image") if role == "user" => parts.push(image_part(block).ok_or_else(|| {
+                invalid(format!("{}: only base64 image sources are supported", at()))
+            })?),
+            Some("tool_use") if role == "assistant" => {
+                let (Some(id), Some(name)) = (
+                    block.get("id").and_then(Value::as_str),
+                    block.get("name").and_then(Value::as_str),
+                ) else {
+                    return Err(invalid(format!(
+                        "{}: tool_use needs an id and a name",
+                        at()
+                    )));
+                };
+                tool_calls.push(ToolCall {
+                    id: id.to_string(),
+                    kind: "function".to_string(),
+                    function: ToolCallFunction {
+                        name: name.to_string(),
+                        arguments: block.get("input").unwrap_or(&json!({})).to_string(),
+                    },
+                });
+            }
+            Some("tool_result") if role == "user" => {
+                let id = block
+                    .get("tool_use_id")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| invalid(format!("{}: tool_result needs a tool_use_id", at())))?;
+                let mut output = block
+                    .get("content")
+                    .and_then(block_text)
+                    .unwrap_or_default();
+                if block.get("is_error").and_then(Value::as_bool) == Some(true) {
+                    output = json!({ "error": output }).to_string();
+                }
+                // Tool results answer the previous assistant turn, so they
+                // come before anything else the user says
+                messages.push(ChatMessage {
+                    tool_call_id: Some(id.to_string()),
+                    ..text_message("tool", output)
+                });
+            }
+            other => {
+                return Err(invalid(format!(
+                    "{}: block type {other:?} is not supported in a {role} message",
+                    at()
+                )))
+            }
+        }
+    }
+
+    if !tool_calls.is_empty() {
+        let text = parts
+            .iter()
+            .filter_map(|part| part.text.as_deref())
+            .collect::<Vec<_>>()
+            .join("\n");
+        messages.push(ChatMessage {
+            content: Some(text).filter(|t| !t.is_empty()).map(Either::A),
+            tool_calls: Some(tool_calls),
+            ..text_message(role, String::new())
+        });
+    } else if !parts.is_empty() {
+        messages.push(ChatMessage {
+            content: Some(Either::B(parts)),
+            ..text_message(role, String::new())
+        });
+    }
+    Ok(())
+}
+
+fn text_part(text: &str) -> ContentPart {
+    ContentPart {
+        kind: "text".to_string(),
+        text: Some(text.to_string()),
+        data: None,
+        image_path: None,
+        max_dimension: None,
+        orientation: None,
+        mime_type: None,
+    }
+}
+
+fn image_part(block: &Value) -> Option<ContentPart> {
+    let source = block.get("source")?;
+    if source.get("type").and_then(Value::as_str) != Some("base64") {
+        return None;
+    }
+    Some(ContentPart {
+        kind: "image".to_string(),
+        text: None,
+        data: Some(Either::A(source.get("data")?.as_str()?.to_string())),
+        mime_type: source
+            .get("media_type")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        ..text_part("")
+    })
+}
+
+/// Messages `tools` as the definitions the native layer takes.
+fn parse_tools(tools: Option<&Value>) -> napi::Result<Option<String>> {
+    let Some(tools) = tools.filter(|t| !t.is_null()) else {
+        return Ok(None);
+    };
+    let tools = tools
+        .as_array()
+        .ok_or_else(|| invalid("tools must be an array"))?;
+    if tools.is_empty() {
+        return Ok(None);
+    }
+    let definitions = tools
+        .iter()
+        .enumerate()
+        .map(|(i, tool)| {
+            let name = tool
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or_else(|| invalid(format!("tools[{i}]: name is required")))?;
+            Ok(json!({
+                "id": i + 1,
+                "name": name,
+                "description": tool.get("description").and_then(Value::as_str).unwrap_or_default(),
+                "parameters": tool
+                    .get("input_schema")
+                    .cloned()
+                    .unwrap_or_else(|| json!({ "type": "object", "properties": {} })),
+            }))
+        })
+        .collect::<napi::Result<Vec<_>>>()?;
+    Ok(Some(Value::Array(definitions).to_string()))
+}
+
+fn stop_reason(result: &GenerationResult) -> &'static str {
+    match result.finish_reason.as_str() {
+        _ if result.stop_sequence.is_some() => "stop_sequence",
+        "length" => "max_tokens",
+        "tool_calls" => "tool_use",
+        "content_filter" => "refusal",
+        _ => "end_turn",
+    }
+}
+
+/// A Messages `message` object for a finished generation.
+pub fn message_json(id: &str, model: &str, result: &GenerationResult) -> Value {
+    let mut content = Vec::new();
+    if !result.text.is_empty() {
+        content.push(json!({ "type": "text", "text": result.text }));
+    }
+    for call in result.tool_calls.iter().flatten() {
+        let input: Value =
+            serde_json::from_str(&call.function.arguments).unwrap_or_else(|_| json!({}));
+        content.push(json!({
+            "type": "tool_use",
+            "id": call.id,
+            "name": call.function.name,
+            "input": input,
+        }));
+    }
+    let usage = result.usage.as_ref();
+    json!({
+        "id": id,
+        "type": "message",
+        "role": "assistant",
+        "model": model,
+        "content": content,
+        "stop_reason": stop_reason(result),
+        "stop_sequence": result.stop_sequence,
+        "usage": {
+            "input_tokens": usage.map_or(0, |u| u.prompt_tokens),
+            "output_tokens": usage.map_or(0, |u| u.completion_tokens),
+        },
+    })
+}
+
+pub struct MessagesTask {
+    task: GenerateUnifiedTask,
+    model: String,
+}
+
+impl napi::Task for MessagesTask {
+    type Output = String;
+    type JsValue = String;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let result = napi::Task::compute(&mut self.task)?;
+        let id = format!("msg_{}", self.task.request_id);
+        Ok(message_json(&id, &self.model, &result).to_string())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.task.rejection(env, err))
+    }
+}
+
+/// Run an Anthropic Messages request body (JSON) and resolve with the
+/// `message` response (JSON). `tool_use` blocks are returned to the caller
+/// rather than run, as with the Messages API.
+#[napi]
+pub fn anthropic_messages(request_json: String) -> napi::Result<AsyncTask<MessagesTask>> {
+    let request = parse_request(&request_json)?;
+    if request.stream {
+        return Err(invalid(
+            "stream: true is not supported by anthropicMessages",
+        ));
+    }
+    let generation = GenerationRequest::from_messages(&request.messages, request.options)?;
+    Ok(AsyncTask::new(MessagesTask {
+        task: GenerateUnifiedTask::new(generation),
+        model: request.model,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_generation_result, GenerationMetrics};
+    use std::time::Instant;
+
+    fn request(body: Value) -> MessagesRequest {
+        parse_request(&body.to_string()).unwrap_or_else(|e| panic!("{body}: {}", e.reason))
+    }
+
+    fn text(message: &ChatMessage) -> Option<&str> {
+        match &message.content {
+            Some(Either::A(text)) => Some(text),
+            Some(Either::B(parts)) => parts[0].text.as_deref(),
+            None => None,
+        }
+    }
+
+    fn result(raw: Value) -> GenerationResult {
+        let metrics = GenerationMetrics::new(Instant::now(), None, None);
+        parse_generation_result(&raw.to_string(), None, metrics, None).unwrap()
+    }
+
+    #[test]
+    fn tool_results_come_before_the_users_text() {
+        let parsed = request(json!({ "system": "Be brief.", "messages": [
+            { "role": "user", "content": "Weather in Oslo?" },
+            { "role": "assistant", "content": [
+                { "type": "tool_use", "id": "toolu_1", "name": "weather", "input": { "city": "Oslo" } },
+            ]},
+            { "role": "user", "content": [
+                { "type": "text", "text": "And be quick." },
+                { "type": "tool_result", "tool_use_id": "toolu_1", "content": "Sunny" },
+            ]},
+        ]}));
+        let roles: Vec<&str> = parsed.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user", "assistant", "tool", "user"]);
+        assert_eq!(parsed.messages[3].tool_call_id.as_deref(), Some("toolu_1"));
+        assert_eq!(text(&parsed.messages[3]), Some("Sunny"));
+        assert_eq!(text(&parsed.messages[4]), Some("And be quick."));
+    }
+
+    #[test]
+    fn failed_tool_results_are_wrapped_as_errors() {
+        let parsed = request(json!({ "messages": [{ "role": "user", "content": [
+            { "type": "tool_result", "tool_use_id": "toolu_1", "is_error": true,
+              "content": [{ "type": "text", "text": "city not found" }] },
+            { "type": "tool_result", "tool_use_id": "toolu_2", "is_error": false, "content": "ok" },
+        ]}]}));
+        assert_eq!(parsed.messages.len(), 2);
+        assert_eq!(
+            text(&parsed.messages[0]),
+            Some(json!({ "error": "city not found" }).to_string().as_str())
+        );
+        assert_eq!(text(&parsed.messages[1]), Some("ok"));
+    }
+
+    #[test]
+    fn assistant_text_and_tool_use_are_one_message() {
+        let parsed = request(json!({ "messages": [{ "role": "assistant", "content": [
+            { "type": "text", "text": "Let me check." },
+            { "type": "tool_use", "id": "toolu_1", "name": "weather", "input": { "city": "Oslo" } },
+            { "type": "tool_use", "id": "toolu_2", "name": "time" },
+        ]}]}));
+        assert_eq!(parsed.messages.len(), 1);
+        let message = &parsed.messages[0];
+        assert_eq!(message.role, "assistant");
+        assert_eq!(text(message), Some("Let me check."));
+        let calls = message.tool_calls.as_ref().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "toolu_1");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Oslo"}"#);
+        assert_eq!(calls[1].function.arguments, "{}");
+    }
+
+    #[test]
+    fn top_k_must_fit_without_wrapping() {
+        let top_k = |body: Value| request(body).options.sampling.unwrap().top_k;
+        assert_eq!(top_k(json!({ "messages": [], "top_k": 40 })), Some(40));
+        for value in [json!(u64::from(u32::MAX) + 41), json!(-1), json!(0.5)] {
+            let body = json!({ "messages": [], "top_k": value });
+            assert!(parse_request(&body.to_string()).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn stop_reasons_follow_the_messages_api() {
+        let message = |raw: Value| message_json("msg_1", "model", &result(raw));
+        assert_eq!(message(json!({ "text": "Hi" }))["stop_reason"], "end_turn");
+        assert_eq!(
+            message(json!({ "text": "Hi", "finishReason": "length" }))["stop_reason"],
+            "max_tokens"
+        );
+
+        let called = message(json!({ "text": "", "toolCalls": [{
+            "id": "call_1", "function": { "name": "weather", "arguments": "{\"city\":\"Oslo\"}" },
+        }]}));
+        assert_eq!(called["stop_reason"], "tool_use");
+        assert_eq!(
+            called["content"],
+            json!([{ "type": "tool_use", "id": "call_1", "name": "weather", "input": { "city": "Oslo" } }])
+        );
+
+        let mut stopped = result(json!({ "text": "one", "finishReason": "stop" }));
+        stopped.stop_sequence = Some("END".to_string());
+        let stopped = message_json("msg_1", "model", &stopped);
+        assert_eq!(stopped["stop_reason"], "stop_sequence");
+        assert_eq!(stopped["stop_sequence"], "END");
+        assert_eq!(
+            message(json!({ "text": "Hi" }))["stop_sequence"],
+            Value::Null
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! `apple-ai`: the on-device model from the shell. Runs requests through the
+//! same library crate as the Node addon, without Node.
+//!
+//! ```text
+//! apple-ai generate "Summarize this" < notes.txt
+//! apple-ai stream --system "Answer in one line" "Why is the sky blue?"
+//! apple-ai chat
+//! apple-ai availability
+//! apple-ai languages
+//! ```
+
+use apple_ai_napi::ffi::{
+    apple_ai_check_availability, apple_ai_get_availability_reason, apple_ai_get_supported_language,
+    apple_ai_get_supported_languages_count, apple_ai_init, take_c_string,
+};
+use apple_ai_napi::{
+    run_stream, ChatMessage, GenerateUnifiedTask, GenerationOptions, GenerationRequest, StreamSink,
+    TokenUsage,
+};
+use napi::bindgen_prelude::Either;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::process::ExitCode;
+use std::sync::mpsc;
+
+const USAGE: &str = "\
+Usage: apple-ai <command> [options] [prompt]
+
+Commands:
+  generate [prompt]   Print the model's response to the prompt
+  stream [prompt]     Print the response as it is generated
+  chat                Talk to the model interactively (/reset, /exit)
+  availability        Whether the model can be used, and why not
+  languages           Languages the model supports
+
+The prompt is read from stdin when it is omitted or `-`. With both, stdin
+is appended to the prompt, so `apple-ai generate Summarize < notes.txt` works.
+
+Options:
+  -s, --system <text>       Instructions for the model
+  -t, --temperature <n>     Sampling temperature
+  -m, --max-tokens <n>      Most tokens to generate
+      --json                generate: print the result as JSON
+  -h, --help                Show this help
+  -V, --version             Show the version";
+
+#[derive(Default)]
+struct Options {
+    system: Option<String>,
+    temperature: Option<f64>,
+    max_tokens: Option<i32>,
+    json: bool,
+    prompt: Vec<String>,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{name} needs a value"))
+        };
+        match arg.as_str() {
+            "-s" | "--system" => options.system = Some(value(arg)?),
+            "-t" | "--temperature" => {
+                let text = value(arg)?;
+                options.temperature = Some(
+                    text.parse()
+                        .map_err(|_| format!("invalid temperature {text:?}"))?,
+                );
+            }
+            "-m" | "--max-tokens" => {
+                let text = value(arg)?;
+                options.max_tokens = Some(
+                    text.parse()
+                        .ok()
+                        .filter(|&n: &i32| n > 0)
+                        .ok_or_else(|| format!("invalid max tokens {text:?}"))?,
+                );
+            }
+            "--json" => options.json = true,
+            "-" => options.prompt.push(arg.clone()),
+            flag if flag.starts_with('-') => return Err(format!("unknown option {flag}")),
+            _ => options.prompt.push(arg.clone()),
+        }
+    }
+    Ok(options)
+}
+
+/// The prompt from the arguments, with piped stdin appended, or stdin alone.
+fn read_prompt(options: &Options) -> Result<String, String> {
+    let from_stdin = options.prompt.is_empty() || options.prompt.iter().any(|p| p == "-");
+    let words: Vec<&str> = options
+        .prompt
+        .iter()
+        .map(String::as_str)
+        .filter(|p| *p != "-")
+        .collect();
+    let mut prompt = words.join(" ");
+    if from_stdin || !std::io::stdin().is_terminal() {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .map_err(|e| format!("cannot read stdin: {e}"))?;
+        if !input.trim().is_empty() {
+            if !prompt.is_empty() {
+                prompt.push_str("\n\n");
+            }
+            prompt.push_str(input.trim_end());
+        }
+    }
+    if prompt.trim().is_empty() {
+        return Err("no prompt given".to_string());
+    }
+    Ok(prompt)
+}
+
+fn message(role: &str, content: &str) -> ChatMessage {
+    ChatMessage {
+        role: role.to_string(),
+        content: Some(Either::A(content.to_string())),
+        name: None,
+        tool_call_id: None,
+        tool_calls: None,
+    }
+}
+
+fn request(options: &Options, turns: &[(&str, String)]) -> Result<GenerationRequest, String> {
+    let mut messages = Vec::new();
+    if let Some(system) = &options.system {
+        messages.push(message("system", system));
+    }
+    messages.extend(turns.iter().map(|(role, content)| message(role, content)));
+    let generation = GenerationOptions {
+        temperature: options.temperature,
+        max_tokens: options.max_tokens,
+        ..Default::default()
+    };
+    GenerationRequest::from_messages(&messages, generation).map_err(|e| e.reason)
+}
+
+fn usage_json(usage: Option<&TokenUsage>) -> serde_json::Value {
+    usage.map_or(serde_json::Value::Null, |u| {
+        serde_json::json!({
+            "promptTokens": u.prompt_tokens,
+            "completionTokens": u.completion_tokens,
+            "totalTokens": u.total_tokens,
+        })
+    })
+}
+
+fn generate(request: GenerationRequest) -> Result<serde_json::Value, String> {
+    let mut task = GenerateUnifiedTask::new(request);
+    let result = napi::Task::compute(&mut task).map_err(|e| e.reason)?;
+    Ok(serde_json::json!({
+        "text": result.text,
+        "finishReason": result.finish_reason,
+        "usage": usage_json(result.usage.as_ref()),
+    }))
+}
+
+/// Stream the response to stdout, returning the whole text.
+fn stream(request: GenerationRequest) -> Result<String, String> {
+    let (sender, events) = mpsc::channel();
+    run_stream(request, StreamSink::Channel(sender)).map_err(|e| e.reason)?;
+    let mut text = String::new();
+    let mut stdout = std::io::stdout();
+    let outcome = loop {
+        let Ok(event) = events.recv() else {
+            break Err("the stream ended unexpectedly".to_string());
+        };
+        match event.kind.as_str() {
+            "text-delta" => {
+                let chunk = event.text.unwrap_or_default();
+                let _ = stdout
+                    .write_all(chunk.as_bytes())
+                    .and_then(|_| stdout.flush());
+                text.push_str(&chunk);
+            }
+            "finish" => break Ok(text),
+            "error" => {
+                break Err(event
+                    .message
+                    .unwrap_or_else(|| "generation failed".to_string()))
+            }
+            _ => {}
+        }
+    };
+    println!();
+    outcome
+}
+
+fn availability(options: &Options) -> Result<ExitCode, String> {
+    let available = unsafe { apple_ai_check_availability() } == 1;
+    let reason = if available {
+        "Available".to_string()
+    } else {
+        take_c_string(unsafe { apple_ai_get_availability_reason() })
+    };
+    if options.json {
+        println!(
+            "{}",
+            serde_json::json!({ "available": available, "reason": reason })
+        );
+    } else {
+        println!("{reason}");
+    }
+    Ok(if available {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn languages() {
+    let count = unsafe { apple_ai_get_supported_languages_count() };
+    for index in 0..count {
+        println!(
+            "{}",
+            take_c_string(unsafe { apple_ai_get_supported_language(index) })
+        );
+    }
+}
+
+fn chat(options: &Options) -> Result<(), String> {
+    let interactive = std::io::stdin().is_terminal();
+    if interactive {
+        eprintln!(
+            "Chatting with the on-device model. /reset clears the conversation, /exit quits."
+        );
+    }
+    let mut turns: Vec<(&str, String)> = Vec::new();
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        if interactive {
+            eprint!("> ");
+        }
+        let Some(line) = lines.next() else {
+            return Ok(());
+        };
+        let line = line.map_err(|e| format!("cannot read stdin: {e}"))?;
+        match line.trim() {
+            "" => continue,
+            "/exit" | "/quit" => return Ok(()),
+            "/reset" => {
+                turns.clear();
+                continue;
+            }
+            _ => {}
+        }
+        turns.push(("user", line));
+        match request(options, &turns).and_then(stream) {
+            Ok(reply) => turns.push(("assistant", reply)),
+            Err(message) => {
+                // Drop the turn that failed so the next one starts clean
+                turns.pop();
+                eprintln!("apple-ai: {message}");
+            }
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<ExitCode, String> {
+    let Some(command) = args.first() else {
+        eprintln!("{USAGE}");
+        return Ok(ExitCode::from(2));
+    };
+    if matches!(command.as_str(), "-h" | "--help" | "help") {
+        println!("{USAGE}");
+        return Ok(ExitCode::SUCCESS);
+    }
+    if matches!(command.as_str(), "-V" | "--version") {
+        println!("apple-ai {}", env!("CARGO_PKG_VERSION"));
+        return Ok(ExitCode::SUCCESS);
+    }
+    let options = parse_options(&args[1..])?;
+    if !unsafe { apple_ai_init() } {
+        return Err("the Apple Intelligence library could not be initialized".to_string());
+    }
+    match command.as_str() {
+        "generate" => {
+            let prompt = read_prompt(&options)?;
+            let result = generate(request(&options, &[("user", prompt)])?)?;
+            if options.json {
+                println!("{result}");
+            } else {
+                println!(
+                    "{}",
+                    result
+                        .get("text")
+                        .and_then(|t| t.as_str())
+                        .unwrap_or_default()
+                );
+            }
+        }
+        "stream" => {
+            let prompt = read_prompt(&options)?;
+            stream(request(&options, &[("user", prompt)])?)?;
+        }
+        "chat" => chat(&options)?,
+        "availability" => return availability(&options),
+        "languages" => languages(),
+        other => return Err(format!("unknown command {other:?}; see apple-ai --help")),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(code) => code,
+        Err(message) => {
+            eprintln!("apple-ai: {message}");
+            ExitCode::FAILURE
+        }
+    }
+}
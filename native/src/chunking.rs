@@ -0,0 +1,382 @@
+//! Text chunking for retrieval: sentences come from NaturalLanguage and
+//! token counts from the model's tokenizer, so chunks fit a token budget
+//! without the tokenizer in JS.
+
+use crate::tokenization::{token_ranges, Unit};
+use crate::{coded_error, count_text_tokens, ErrorCode};
+use napi::bindgen_prelude::AsyncTask;
+use napi::{Env, Status};
+use napi_derive::napi;
+use std::ops::Range;
+
+const DEFAULT_MAX_TOKENS: u32 = 256;
+const DEFAULT_MAX_SENTENCES: u32 = 5;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ChunkTextOptions {
+    /// `token` (default) packs whole sentences into chunks of up to
+    /// `maxTokens`; `sentence` groups `maxSentences` sentences per chunk
+    #[napi(ts_type = "'token' | 'sentence'")]
+    pub by: Option<String>,
+    /// Token budget of a chunk (default 256)
+    pub max_tokens: Option<u32>,
+    /// Sentences per chunk when chunking by sentence (default 5)
+    pub max_sentences: Option<u32>,
+    /// What consecutive chunks share: up to this many tokens of whole
+    /// sentences by token, this many sentences by sentence (default 0)
+    pub overlap: Option<u32>,
+    /// Language of the text for sentence splitting (default: detected)
+    pub language: Option<String>,
+}
+
+/// A chunk of the text, with its position for mapping results back.
+#[napi(object)]
+pub struct TextChunk {
+    pub text: String,
+    /// Offset of the chunk in the text, in UTF-16 code units like JS strings
+    pub start: u32,
+    pub end: u32,
+    /// Tokens in the chunk, when chunking by token
+    pub tokens: Option<u32>,
+}
+
+pub enum Mode {
+    Token {
+        max_tokens: u32,
+        overlap: u32,
+    },
+    Sentence {
+        max_sentences: usize,
+        overlap: usize,
+    },
+}
+
+impl Mode {
+    pub fn new(options: &ChunkTextOptions) -> napi::Result<Self> {
+        let overlap = options.overlap.unwrap_or(0);
+        match options.by.as_deref() {
+            None | Some("token") => {
+                let max_tokens = options.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+                if max_tokens == 0 || overlap >= max_tokens {
+                    return Err(napi::Error::new(
+                        Status::InvalidArg,
+                        "maxTokens must be at least 1 and more than overlap",
+                    ));
+                }
+                Ok(Self::Token {
+                    max_tokens,
+                    overlap,
+                })
+            }
+            Some("sentence") => {
+                let max_sentences = options.max_sentences.unwrap_or(DEFAULT_MAX_SENTENCES);
+                if max_sentences == 0 || overlap >= max_sentences {
+                    return Err(napi::Error::new(
+                        Status::InvalidArg,
+                        "maxSentences must be at least 1 and more than overlap",
+                    ));
+                }
+                Ok(Self::Sentence {
+                    max_sentences: max_sentences as usize,
+                    overlap: overlap as usize,
+                })
+            }
+            Some(other) => Err(napi::Error::new(
+                Status::InvalidArg,
+                format!("Unknown chunking '{other}', expected 'token' or 'sentence'"),
+            )),
+        }
+    }
+}
+
+fn count(text: &str) -> napi::Result<u32> {
+    count_text_tokens(text).map(|count| count.tokens)
+}
+
+/// Where to split `range` in two: the whitespace nearest its middle, or the
+/// middle character when it has none, as in scripts written without spaces.
+fn split_point(text: &str, range: &Range<usize>) -> Option<usize> {
+    let middle = range.start + (range.end - range.start) / 2;
+    let piece = &text[range.clone()];
+    let whitespace = piece
+        .char_indices()
+        .filter(|(_, c)| c.is_whitespace())
+        .map(|(i, _)| range.start + i)
+        .filter(|&i| i > range.start)
+        .min_by_key(|&i| i.abs_diff(middle));
+    whitespace.or_else(|| {
+        let at = (middle..range.end).find(|&i| text.is_char_boundary(i))?;
+        (at > range.start && at < range.end).then_some(at)
+    })
+}
+
+/// `range` as pieces of at most `max_tokens`, halving until they fit.
+fn fit(
+    text: &str,
+    range: Range<usize>,
+    tokens: u32,
+    max_tokens: u32,
+    out: &mut Vec<(Range<usize>, u32)>,
+) -> napi::Result<()> {
+    let Some(at) = split_point(text, &range).filter(|_| tokens > max_tokens) else {
+        out.push((range, tokens));
+        return Ok(());
+    };
+    for half in [range.start..at, at..range.end] {
+        let piece = &text[half.clone()];
+        let trimmed = piece.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        let start = half.start + piece.len() - piece.trim_start().len();
+        let half = start..start + trimmed.len();
+        let tokens = count(&text[half.clone()])?;
+        fit(text, half, tokens, max_tokens, out)?;
+    }
+    Ok(())
+}
+
+/// Groups of consecutive units of up to `max_tokens` each; a group starts
+/// with the trailing units of the previous one that fit in `overlap`.
+fn pack(units: &[(Range<usize>, u32)], max_tokens: u32, overlap: u32) -> Vec<Range<usize>> {
+    let mut groups = Vec::new();
+    let mut current: Vec<usize> = Vec::new();
+    let mut total = 0;
+    for (index, (_, tokens)) in units.iter().enumerate() {
+        if !current.is_empty() && total + tokens > max_tokens {
+            groups.push(current[0]..current[current.len() - 1] + 1);
+            // Carry over trailing units, never the whole group, so every
+            // chunk adds something new
+            let mut kept = 0;
+            let mut kept_tokens = 0;
+            for &unit in current.iter().skip(1).rev() {
+                let next = kept_tokens + units[unit].1;
+                if next > overlap || next + tokens > max_tokens {
+                    break;
+                }
+                kept += 1;
+                kept_tokens = next;
+            }
+            current.drain(..current.len() - kept);
+            total = kept_tokens;
+        }
+        current.push(index);
+        total += tokens;
+    }
+    if !current.is_empty() {
+        groups.push(current[0]..current[current.len() - 1] + 1);
+    }
+    groups
+}
+
+/// Groups of `size` consecutive items out of `count`, each starting `size -
+/// overlap` after the previous one; the last group ends at the last item.
+fn windows(count: usize, size: usize, overlap: usize) -> Vec<Range<usize>> {
+    let mut groups = Vec::new();
+    let mut first = 0;
+    while first < count {
+        let end = (first + size).min(count);
+        groups.push(first..end);
+        if end == count {
+            break;
+        }
+        first += size - overlap;
+    }
+    groups
+}
+
+/// Chunks of `text` as byte ranges, with token counts when chunking by token.
+pub fn chunk(
+    text: &str,
+    mode: &Mode,
+    language: Option<&str>,
+) -> napi::Result<Vec<(Range<usize>, Option<u32>)>> {
+    if text.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let sentences = token_ranges(text, Unit::Sentence, language)?;
+    match *mode {
+        Mode::Sentence {
+            max_sentences,
+            overlap,
+        } => Ok(windows(sentences.len(), max_sentences, overlap)
+            .into_iter()
+            .map(|group| {
+                (
+                    sentences[group.start].start..sentences[group.end - 1].end,
+                    None,
+                )
+            })
+            .collect()),
+        Mode::Token {
+            max_tokens,
+            overlap,
+        } => {
+            let mut units = Vec::with_capacity(sentences.len());
+            for sentence in sentences {
+                let tokens = count(&text[sentence.clone()])?;
+                fit(text, sentence, tokens, max_tokens, &mut units)?;
+            }
+            pack(&units, max_tokens, overlap)
+                .into_iter()
+                .map(|group| {
+                    let range = units[group.start].0.start..units[group.end - 1].0.end;
+                    let tokens = count(&text[range.clone()])?;
+                    Ok((range, Some(tokens)))
+                })
+                .collect()
+        }
+    }
+}
+
+/// UTF-16 offset of each byte offset at a character boundary of `text`.
+pub fn utf16_offsets(text: &str) -> Vec<u32> {
+    let mut offsets = vec![0; text.len() + 1];
+    let mut utf16 = 0;
+    for (index, c) in text.char_indices() {
+        offsets[index] = utf16;
+        utf16 += c.len_utf16() as u32;
+    }
+    offsets[text.len()] = utf16;
+    offsets
+}
+
+pub struct ChunkTextTask {
+    text: String,
+    mode: Mode,
+    language: Option<String>,
+}
+
+impl napi::Task for ChunkTextTask {
+    type Output = Vec<TextChunk>;
+    type JsValue = Vec<TextChunk>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        crate::ensure_initialized();
+        let chunks = chunk(&self.text, &self.mode, self.language.as_deref())?;
+        let offsets = utf16_offsets(&self.text);
+        Ok(chunks
+            .into_iter()
+            .map(|(range, tokens)| TextChunk {
+                text: self.text[range.clone()].to_string(),
+                start: offsets[range.start],
+                end: offsets[range.end],
+                tokens,
+            })
+            .collect())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(coded_error(env, ErrorCode::of(&err), err.reason))
+    }
+}
+
+/// Split `text` into chunks for embedding and retrieval, in order. By token,
+/// whole sentences are packed into chunks of up to `maxTokens` as the
+/// model's tokenizer counts them, and a sentence longer than that is split
+/// at whitespace; by sentence, each chunk has `maxSentences` sentences.
+#[napi(ts_return_type = "Promise<TextChunk[]>")]
+pub fn chunk_text(
+    text: String,
+    options: Option<ChunkTextOptions>,
+) -> napi::Result<AsyncTask<ChunkTextTask>> {
+    let options = options.unwrap_or_default();
+    let mode = Mode::new(&options)?;
+    Ok(AsyncTask::new(ChunkTextTask {
+        text,
+        mode,
+        language: options.language,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(by: &str, size: u32, overlap: u32) -> ChunkTextOptions {
+        ChunkTextOptions {
+            by: Some(by.into()),
+            max_tokens: Some(size),
+            max_sentences: Some(size),
+            overlap: Some(overlap),
+            language: None,
+        }
+    }
+
+    #[test]
+    fn overlap_must_be_less_than_the_chunk_size() {
+        for by in ["token", "sentence"] {
+            assert!(Mode::new(&options(by, 4, 3)).is_ok());
+            for (size, overlap) in [(4, 4), (4, 5), (0, 0)] {
+                let error = Mode::new(&options(by, size, overlap)).err().unwrap();
+                assert_eq!(error.status, Status::InvalidArg, "{by} {size} {overlap}");
+            }
+        }
+        assert!(matches!(
+            Mode::new(&Default::default()).unwrap(),
+            Mode::Token {
+                max_tokens: DEFAULT_MAX_TOKENS,
+                overlap: 0
+            }
+        ));
+        assert!(Mode::new(&options("word", 4, 0)).is_err());
+    }
+
+    #[test]
+    fn sentence_windows_step_by_size_minus_overlap() {
+        assert_eq!(windows(5, 2, 0), [0..2, 2..4, 4..5]);
+        assert_eq!(windows(5, 3, 1), [0..3, 2..5]);
+        assert_eq!(windows(6, 3, 2), [0..3, 1..4, 2..5, 3..6]);
+        assert_eq!(windows(2, 5, 4), Vec::from_iter(std::iter::once(0..2)));
+        assert!(windows(0, 3, 1).is_empty());
+    }
+
+    #[test]
+    fn packing_fills_the_budget_and_carries_whole_units() {
+        let units = |tokens: &[u32]| -> Vec<(Range<usize>, u32)> {
+            tokens.iter().map(|&tokens| (0..0, tokens)).collect()
+        };
+        assert_eq!(pack(&units(&[3, 3, 3, 3]), 6, 0), [0..2, 2..4]);
+        assert_eq!(pack(&units(&[3, 3, 3, 3]), 7, 3), [0..2, 1..3, 2..4]);
+        // The carried units and the next one must fit together
+        assert_eq!(pack(&units(&[2, 2, 5]), 6, 4), [0..2, 2..3]);
+        // A unit over the budget (one without a split point) is a chunk of its own
+        assert_eq!(pack(&units(&[1, 9, 1]), 4, 0), [0..1, 1..2, 2..3]);
+        assert!(pack(&[], 4, 0).is_empty());
+    }
+
+    #[test]
+    fn long_sentences_split_near_the_middle() {
+        let text = "one two three four";
+        assert_eq!(split_point(text, &(0..text.len())), Some(7));
+        assert_eq!(split_point(text, &(8..13)), Some(10));
+        // Without whitespace, at the character boundary nearest the middle
+        let text = "日本語の文";
+        assert_eq!(split_point(text, &(0..text.len())), Some(9));
+        assert_eq!(split_point(text, &(0..3)), None);
+    }
+
+    #[test]
+    fn empty_text_has_no_chunks() {
+        let token = Mode::new(&Default::default()).unwrap();
+        assert!(chunk("", &token, None).unwrap().is_empty());
+        let sentence = Mode::new(&options("sentence", 2, 1)).unwrap();
+        assert!(chunk(" \n\t", &sentence, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn offsets_count_utf16_code_units() {
+        let text = "aé😀b";
+        let offsets = utf16_offsets(text);
+        assert_eq!(offsets[0], 0);
+        assert_eq!(offsets[1], 1);
+        assert_eq!(offsets[3], 2);
+        assert_eq!(offsets[7], 4);
+        assert_eq!(offsets[text.len()], 5);
+    }
+}
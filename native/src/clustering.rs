@@ -0,0 +1,570 @@
+//! Clustering of texts or vectors by cosine similarity: spherical k-means
+//! for a given number of clusters, or average-linkage agglomerative
+//! clustering down to a similarity threshold.
+
+use crate::embeddings::{embed_all, EmbeddingModel};
+use crate::{coded_error, vector, ErrorCode};
+use napi::bindgen_prelude::{AsyncTask, Either, Float32Array};
+use napi::{Env, Status};
+use napi_derive::napi;
+
+const DEFAULT_MAX_ITERATIONS: u32 = 100;
+/// Most items agglomerative clustering takes: it keeps a similarity matrix,
+/// n² floats
+const MAX_AGGLOMERATIVE: usize = 10_000;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ClusterOptions {
+    /// Number of clusters
+    pub k: Option<u32>,
+    /// Merge clusters while their average cosine similarity is at least this
+    pub threshold: Option<f64>,
+    /// `kmeans` (default with `k`) or `agglomerative` (default with
+    /// `threshold`; with `k`, merges down to `k` clusters)
+    #[napi(ts_type = "'kmeans' | 'agglomerative'")]
+    pub method: Option<String>,
+    /// Most k-means iterations (default 100)
+    pub max_iterations: Option<u32>,
+    /// Seed for picking the initial k-means centers (default 0), so runs are
+    /// reproducible
+    pub seed: Option<u32>,
+    /// Language of texts to embed, e.g. "en" (default: detected per text)
+    pub language: Option<String>,
+    /// Embedding model for texts: `sentence` (default) or `contextual`
+    #[napi(ts_type = "'sentence' | 'contextual'")]
+    pub model: Option<String>,
+}
+
+#[napi(object)]
+pub struct Cluster {
+    /// Positions of the members in the input, in order
+    pub members: Vec<u32>,
+    /// The member closest to the cluster's centroid, as its representative
+    pub exemplar: u32,
+    /// Mean cosine similarity of the members to the centroid, from -1 to 1
+    pub cohesion: f64,
+}
+
+#[napi(object)]
+pub struct ClusterResult {
+    /// Cluster of each input, indexing `clusters`
+    pub assignments: Vec<u32>,
+    /// Clusters, largest first
+    pub clusters: Vec<Cluster>,
+}
+
+enum Method {
+    KMeans {
+        k: usize,
+        max_iterations: u32,
+        seed: u64,
+    },
+    Agglomerative {
+        k: usize,
+        threshold: f32,
+    },
+}
+
+/// SplitMix64, for reproducible k-means seeding without a dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn normalize(vector: &mut [f32]) {
+    let norm = vector::norm(vector);
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Mean of the `members` of `vectors`, scaled to unit length.
+fn centroid(vectors: &[Vec<f32>], members: &[usize]) -> Vec<f32> {
+    let mut centroid = vec![0.0; vectors[0].len()];
+    for &member in members {
+        for (c, x) in centroid.iter_mut().zip(&vectors[member]) {
+            *c += x;
+        }
+    }
+    normalize(&mut centroid);
+    centroid
+}
+
+/// Cluster of each of the unit `vectors` by spherical k-means, with
+/// k-means++ initial centers.
+fn kmeans(vectors: &[Vec<f32>], k: usize, max_iterations: u32, seed: u64) -> Vec<usize> {
+    let n = vectors.len();
+    let mut rng = Rng(seed);
+    let mut centers = vec![vectors[(rng.next() % n as u64) as usize].clone()];
+    // Distance of each vector to its nearest center so far
+    let mut distances: Vec<f64> = vectors
+        .iter()
+        .map(|v| (1.0 - vector::dot(v, &centers[0]) as f64).max(0.0))
+        .collect();
+    while centers.len() < k {
+        let total: f64 = distances.iter().sum();
+        let next = if total > 0.0 {
+            let mut target = rng.unit() * total;
+            distances
+                .iter()
+                .position(|&d| {
+                    target -= d;
+                    target < 0.0
+                })
+                .unwrap_or(n - 1)
+        } else {
+            // Every vector sits on a center already
+            (rng.next() % n as u64) as usize
+        };
+        centers.push(vectors[next].clone());
+        for (distance, v) in distances.iter_mut().zip(vectors) {
+            *distance = distance.min((1.0 - vector::dot(v, &vectors[next]) as f64).max(0.0));
+        }
+    }
+
+    let mut assignments = vec![usize::MAX; n];
+    for _ in 0..max_iterations {
+        let mut changed = false;
+        for (assignment, v) in assignments.iter_mut().zip(vectors) {
+            let nearest = (0..k)
+                .max_by(|&a, &b| {
+                    vector::dot(v, &centers[a]).total_cmp(&vector::dot(v, &centers[b]))
+                })
+                .unwrap_or(0);
+            changed |= *assignment != nearest;
+            *assignment = nearest;
+        }
+        if !changed {
+            break;
+        }
+        let mut members = vec![Vec::new(); k];
+        for (index, &cluster) in assignments.iter().enumerate() {
+            members[cluster].push(index);
+        }
+        for (cluster, members) in members.iter().enumerate() {
+            if !members.is_empty() {
+                centers[cluster] = centroid(vectors, members);
+                continue;
+            }
+            // An empty cluster takes the vector farthest from its center
+            let farthest = (0..n)
+                .min_by(|&a, &b| {
+                    let a_score = vector::dot(&vectors[a], &centers[assignments[a]]);
+                    let b_score = vector::dot(&vectors[b], &centers[assignments[b]]);
+                    a_score.total_cmp(&b_score)
+                })
+                .unwrap_or(0);
+            assignments[farthest] = cluster;
+            centers[cluster] = vectors[farthest].clone();
+        }
+    }
+    assignments
+}
+
+/// Cluster of each of the unit `vectors` by average-linkage agglomerative
+/// clustering, merging the most similar pair of clusters until `k` are left
+/// or no pair averages `threshold`.
+fn agglomerative(vectors: &[Vec<f32>], k: usize, threshold: f32) -> Vec<usize> {
+    let n = vectors.len();
+    let mut similarity = vec![0.0f32; n * n];
+    for i in 0..n {
+        for j in i + 1..n {
+            let s = vector::dot(&vectors[i], &vectors[j]);
+            similarity[i * n + j] = s;
+            similarity[j * n + i] = s;
+        }
+    }
+    let mut active = vec![true; n];
+    let mut sizes = vec![1usize; n];
+    let mut members: Vec<Vec<usize>> = (0..n).map(|i| vec![i]).collect();
+    let best_of = |i: usize, active: &[bool], similarity: &[f32]| -> Option<(usize, f32)> {
+        (0..n)
+            .filter(|&j| j != i && active[j])
+            .map(|j| (j, similarity[i * n + j]))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+    };
+    let mut best: Vec<Option<(usize, f32)>> =
+        (0..n).map(|i| best_of(i, &active, &similarity)).collect();
+    let mut count = n;
+    while count > k {
+        let Some((i, (j, score))) = (0..n)
+            .filter(|&i| active[i])
+            .filter_map(|i| best[i].map(|b| (i, b)))
+            .max_by(|a, b| a.1 .1.total_cmp(&b.1 .1))
+        else {
+            break;
+        };
+        if score < threshold {
+            break;
+        }
+        // Merge j into i; the average similarity to the union weighs each side
+        // by its size
+        for other in (0..n).filter(|&o| active[o] && o != i && o != j) {
+            let merged = (sizes[i] as f32 * similarity[i * n + other]
+                + sizes[j] as f32 * similarity[j * n + other])
+                / (sizes[i] + sizes[j]) as f32;
+            similarity[i * n + other] = merged;
+            similarity[other * n + i] = merged;
+        }
+        active[j] = false;
+        sizes[i] += sizes[j];
+        let moved = std::mem::take(&mut members[j]);
+        members[i].extend(moved);
+        count -= 1;
+        best[j] = None;
+        best[i] = best_of(i, &active, &similarity);
+        for other in (0..n).filter(|&o| active[o] && o != i) {
+            match best[other] {
+                Some((b, _)) if b == i || b == j => {
+                    best[other] = best_of(other, &active, &similarity);
+                }
+                Some((_, s)) if similarity[other * n + i] > s => {
+                    best[other] = Some((i, similarity[other * n + i]));
+                }
+                _ => {}
+            }
+        }
+    }
+    let mut assignments = vec![0; n];
+    for (cluster, members) in members.iter().enumerate() {
+        for &member in members {
+            assignments[member] = cluster;
+        }
+    }
+    assignments
+}
+
+/// Clusters from raw assignments: renumbered largest first (ties by first
+/// member), each with its exemplar and cohesion.
+fn summarize(vectors: &[Vec<f32>], raw: &[usize]) -> ClusterResult {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut numbers = std::collections::HashMap::new();
+    for (index, &cluster) in raw.iter().enumerate() {
+        let number = *numbers.entry(cluster).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[number].push(index);
+    }
+    // Stable, so equal sizes stay in order of their first member
+    groups.sort_by_key(|members| std::cmp::Reverse(members.len()));
+    let mut assignments = vec![0; raw.len()];
+    let clusters = groups
+        .into_iter()
+        .enumerate()
+        .map(|(number, members)| {
+            let center = centroid(vectors, &members);
+            let scores: Vec<f32> = members
+                .iter()
+                .map(|&m| vector::dot(&vectors[m], &center))
+                .collect();
+            let exemplar = members
+                .iter()
+                .zip(&scores)
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .map_or(members[0], |(&m, _)| m);
+            for &member in &members {
+                assignments[member] = number as u32;
+            }
+            Cluster {
+                cohesion: scores.iter().map(|&s| s as f64).sum::<f64>() / members.len() as f64,
+                members: members.into_iter().map(|m| m as u32).collect(),
+                exemplar: exemplar as u32,
+            }
+        })
+        .collect();
+    ClusterResult {
+        assignments,
+        clusters,
+    }
+}
+
+pub struct ClusterTask {
+    texts: Vec<String>,
+    vectors: Vec<Vec<f32>>,
+    method: Method,
+    language: Option<String>,
+    model: EmbeddingModel,
+    error_code: Option<ErrorCode>,
+}
+
+impl ClusterTask {
+    fn fail(&mut self, code: ErrorCode, message: String) -> napi::Error {
+        self.error_code = Some(code);
+        napi::Error::from_reason(message)
+    }
+}
+
+impl napi::Task for ClusterTask {
+    type Output = ClusterResult;
+    type JsValue = ClusterResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let mut vectors = if self.texts.is_empty() {
+            std::mem::take(&mut self.vectors)
+        } else {
+            let concurrency = std::thread::available_parallelism().map_or(4, |n| n.get());
+            embed_all(
+                &self.texts,
+                self.language.as_deref(),
+                self.model,
+                concurrency,
+            )
+            .map_err(|e| self.fail(e.code, e.message))?
+        };
+        if vectors.is_empty() {
+            return Ok(ClusterResult {
+                assignments: Vec::new(),
+                clusters: Vec::new(),
+            });
+        }
+        let dimension = vectors[0].len();
+        if let Some(index) = vectors.iter().position(|v| v.len() != dimension) {
+            let message = format!(
+                "Item {index} has {} dimensions but item 0 has {dimension}",
+                vectors[index].len()
+            );
+            return Err(self.fail(ErrorCode::InvalidArg, message));
+        }
+        vectors.iter_mut().for_each(|v| normalize(v));
+        let n = vectors.len();
+        let raw = match self.method {
+            Method::KMeans {
+                k,
+                max_iterations,
+                seed,
+            } => {
+                if k > n {
+                    let message = format!("k is {k} but there are only {n} items");
+                    return Err(self.fail(ErrorCode::InvalidArg, message));
+                }
+                kmeans(&vectors, k, max_iterations, seed)
+            }
+            Method::Agglomerative { k, threshold } => {
+                if n > MAX_AGGLOMERATIVE {
+                    let message = format!(
+                        "Agglomerative clustering takes at most {MAX_AGGLOMERATIVE} items; use k-means for {n}"
+                    );
+                    return Err(self.fail(ErrorCode::InvalidArg, message));
+                }
+                agglomerative(&vectors, k, threshold)
+            }
+        };
+        Ok(summarize(&vectors, &raw))
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Group texts, embedded on the device, or vectors from `embed` by cosine
+/// similarity: into `k` clusters by k-means, or by merging the most similar
+/// clusters while they average at least `threshold`. Resolves with each
+/// item's cluster and the clusters, largest first, with their exemplars.
+#[napi(ts_return_type = "Promise<ClusterResult>")]
+pub fn cluster_texts(
+    items: Either<Vec<String>, Vec<Float32Array>>,
+    options: Option<ClusterOptions>,
+) -> napi::Result<AsyncTask<ClusterTask>> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: &str| napi::Error::new(Status::InvalidArg, message.to_string());
+    let model = EmbeddingModel::parse(options.model.as_deref()).map_err(|e| invalid(&e.message))?;
+    if options.k == Some(0) {
+        return Err(invalid("k must be at least 1"));
+    }
+    if options
+        .threshold
+        .is_some_and(|t| !(-1.0..=1.0).contains(&t))
+    {
+        return Err(invalid("threshold must be between -1 and 1"));
+    }
+    let method = match (options.method.as_deref(), options.k, options.threshold) {
+        (None | Some("kmeans"), Some(k), None) => Method::KMeans {
+            k: k as usize,
+            max_iterations: options.max_iterations.unwrap_or(DEFAULT_MAX_ITERATIONS),
+            seed: options.seed.unwrap_or(0).into(),
+        },
+        (Some("kmeans"), _, _) => return Err(invalid("kmeans takes k and no threshold")),
+        (None | Some("agglomerative"), k, threshold) if k.is_some() || threshold.is_some() => {
+            Method::Agglomerative {
+                k: k.unwrap_or(1) as usize,
+                threshold: threshold.map_or(f32::NEG_INFINITY, |t| t as f32),
+            }
+        }
+        (None | Some("agglomerative"), _, _) => return Err(invalid("Pass k or threshold")),
+        (Some(other), _, _) => {
+            return Err(invalid(&format!(
+                "Unknown method '{other}', expected 'kmeans' or 'agglomerative'"
+            )))
+        }
+    };
+    let (texts, vectors) = match items {
+        Either::A(texts) => (texts, Vec::new()),
+        Either::B(vectors) => (Vec::new(), vectors.iter().map(|v| v.to_vec()).collect()),
+    };
+    Ok(AsyncTask::new(ClusterTask {
+        texts,
+        vectors,
+        method,
+        language: options.language,
+        model,
+        error_code: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two tight groups: three vectors around the x axis, then three around
+    /// the y axis; the middle one of each sits on its group's centroid.
+    fn two_groups() -> Vec<Vec<f32>> {
+        vec![
+            vec![1.0, 0.1],
+            vec![0.0, 1.0],
+            vec![1.0, 0.0],
+            vec![0.1, 1.0],
+            vec![1.0, -0.1],
+            vec![-0.1, 1.0],
+        ]
+    }
+
+    fn cluster(vectors: Vec<Vec<f32>>, method: Method) -> napi::Result<ClusterResult> {
+        let mut task = ClusterTask {
+            texts: Vec::new(),
+            vectors,
+            method,
+            language: None,
+            model: EmbeddingModel::Sentence,
+            error_code: None,
+        };
+        napi::Task::compute(&mut task)
+    }
+
+    fn kmeans_method(k: usize) -> Method {
+        Method::KMeans {
+            k,
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            seed: 7,
+        }
+    }
+
+    fn check_two_groups(result: &ClusterResult) {
+        let x = result.assignments[0];
+        let y = result.assignments[1];
+        assert_ne!(x, y);
+        assert_eq!(result.assignments, vec![x, y, x, y, x, y]);
+        assert_eq!(result.clusters.len(), 2);
+        let x_cluster = &result.clusters[x as usize];
+        assert_eq!(x_cluster.members, vec![0, 2, 4]);
+        assert_eq!(x_cluster.exemplar, 2);
+        assert!(x_cluster.cohesion > 0.99 && x_cluster.cohesion <= 1.0);
+        let y_cluster = &result.clusters[y as usize];
+        assert_eq!(y_cluster.members, vec![1, 3, 5]);
+        assert_eq!(y_cluster.exemplar, 1);
+    }
+
+    #[test]
+    fn kmeans_separates_groups_and_picks_central_exemplars() {
+        check_two_groups(&cluster(two_groups(), kmeans_method(2)).unwrap());
+    }
+
+    #[test]
+    fn kmeans_is_reproducible_for_a_seed() {
+        let first = cluster(two_groups(), kmeans_method(3)).unwrap();
+        let second = cluster(two_groups(), kmeans_method(3)).unwrap();
+        assert_eq!(first.assignments, second.assignments);
+        assert_eq!(first.clusters.len(), 3);
+    }
+
+    #[test]
+    fn agglomerative_merges_down_to_k() {
+        let method = Method::Agglomerative {
+            k: 2,
+            threshold: f32::NEG_INFINITY,
+        };
+        check_two_groups(&cluster(two_groups(), method).unwrap());
+    }
+
+    #[test]
+    fn agglomerative_stops_at_the_threshold() {
+        let method = Method::Agglomerative {
+            k: 1,
+            threshold: 0.9,
+        };
+        check_two_groups(&cluster(two_groups(), method).unwrap());
+    }
+
+    #[test]
+    fn kmeans_rejects_k_above_the_number_of_items() {
+        let mut task = ClusterTask {
+            texts: Vec::new(),
+            vectors: two_groups(),
+            method: kmeans_method(7),
+            language: None,
+            model: EmbeddingModel::Sentence,
+            error_code: None,
+        };
+        let error = napi::Task::compute(&mut task).err().unwrap();
+        assert_eq!(error.reason, "k is 7 but there are only 6 items");
+        assert_eq!(task.error_code, Some(ErrorCode::InvalidArg));
+    }
+
+    #[test]
+    fn agglomerative_with_k_above_the_number_of_items_keeps_each_apart() {
+        let method = Method::Agglomerative {
+            k: 10,
+            threshold: f32::NEG_INFINITY,
+        };
+        let result = cluster(two_groups(), method).unwrap();
+        assert_eq!(result.assignments, vec![0, 1, 2, 3, 4, 5]);
+        assert!(result
+            .clusters
+            .iter()
+            .enumerate()
+            .all(|(i, c)| c.members == vec![i as u32] && c.exemplar == i as u32));
+    }
+
+    #[test]
+    fn a_single_item_is_its_own_cluster() {
+        for method in [
+            kmeans_method(1),
+            Method::Agglomerative {
+                k: 1,
+                threshold: 0.5,
+            },
+        ] {
+            let result = cluster(vec![vec![3.0, 4.0]], method).unwrap();
+            assert_eq!(result.assignments, vec![0]);
+            assert_eq!(result.clusters.len(), 1);
+            assert_eq!(result.clusters[0].members, vec![0]);
+            assert_eq!(result.clusters[0].exemplar, 0);
+            assert!((result.clusters[0].cohesion - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn no_items_give_no_clusters() {
+        let result = cluster(Vec::new(), kmeans_method(1)).unwrap();
+        assert!(result.assignments.is_empty() && result.clusters.is_empty());
+    }
+}
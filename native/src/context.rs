@@ -0,0 +1,291 @@
+//! Fitting a request's messages to the context window: condensing older
+//! turns into a summary, then applying `contextPolicy` by dropping whole
+//! turns.
+
+use crate::{
+    count_text_tokens, get_model_limits, next_request_id, parse_generation_result, take_c_string,
+    take_usage, GenerationMetrics, GenerationOptions, GenerationRequest,
+};
+use napi::Status;
+use napi_derive::napi;
+use std::time::Instant;
+
+/// How `GenerationOptions.contextPolicy` makes the messages fit.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ContextPolicy {
+    Error,
+    DropOldest,
+    DropMiddle,
+}
+
+impl ContextPolicy {
+    pub fn parse(policy: &str) -> napi::Result<Self> {
+        match policy {
+            "error" => Ok(Self::Error),
+            "dropOldest" => Ok(Self::DropOldest),
+            "dropMiddle" => Ok(Self::DropMiddle),
+            other => Err(napi::Error::new(
+                Status::InvalidArg,
+                format!(
+                    "contextPolicy must be 'error', 'dropOldest' or 'dropMiddle', got {other:?}"
+                ),
+            )),
+        }
+    }
+}
+
+/// Tokens kept free for the response when `maxTokens` isn't set.
+const DEFAULT_RESPONSE_RESERVE: u32 = 512;
+
+const DEFAULT_CONDENSE_THRESHOLD: f64 = 0.8;
+const DEFAULT_CONDENSE_PROMPT: &str = "Summarize the conversation below in a few sentences. Keep names, facts, decisions and open questions that later replies may depend on.";
+
+/// Summarize older turns once the messages use this much of the context
+/// window, instead of failing or dropping them.
+#[napi(object)]
+#[derive(Default)]
+pub struct CondenseOptions {
+    /// Fraction of the available context at which to condense (default 0.8)
+    pub threshold: Option<f64>,
+    /// Instructions for the summarization pass
+    pub prompt: Option<String>,
+}
+
+/// `CondenseOptions` with defaults applied.
+pub struct Condense {
+    threshold: f64,
+    prompt: String,
+}
+
+impl Condense {
+    pub fn new(options: CondenseOptions) -> napi::Result<Self> {
+        let threshold = options.threshold.unwrap_or(DEFAULT_CONDENSE_THRESHOLD);
+        if !(threshold > 0.0 && threshold <= 1.0) {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "condense.threshold must be greater than 0 and at most 1".to_string(),
+            ));
+        }
+        Ok(Self {
+            threshold,
+            prompt: options
+                .prompt
+                .filter(|p| !p.is_empty())
+                .unwrap_or_else(|| DEFAULT_CONDENSE_PROMPT.to_string()),
+        })
+    }
+}
+
+pub fn message_role(message: &serde_json::Value) -> String {
+    message
+        .get("role")
+        .and_then(|r| r.as_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// The text of a serialized message that takes up context: content and tool calls.
+fn message_text(message: &serde_json::Value) -> String {
+    let mut text = message
+        .get("content")
+        .and_then(|c| c.as_str())
+        .unwrap_or_default()
+        .to_string();
+    if let Some(calls) = message.get("tool_calls") {
+        text.push_str(&calls.to_string());
+    }
+    text
+}
+
+fn message_costs(messages: &[serde_json::Value]) -> napi::Result<Vec<u32>> {
+    messages
+        .iter()
+        .map(|message| Ok(count_text_tokens(&message_text(message))?.tokens))
+        .collect()
+}
+
+/// Index of the latest turn, which starts at the last user message.
+fn latest_turn(messages: &[serde_json::Value]) -> usize {
+    messages
+        .iter()
+        .rposition(|m| message_role(m) == "user")
+        .unwrap_or(messages.len().saturating_sub(1))
+}
+
+/// Replace the turns before the latest one with a model-written summary,
+/// kept as a system message. Turns too old to fit the summarization pass are
+/// dropped. Returns whether the messages changed; a failed summary leaves
+/// them alone so the context policy can still apply.
+fn condense_turns(
+    messages: &mut Vec<serde_json::Value>,
+    costs: &[u32],
+    condense: &Condense,
+    request: &GenerationRequest,
+) -> napi::Result<bool> {
+    let latest = latest_turn(messages);
+    let older: Vec<usize> = (0..latest)
+        .filter(|&i| message_role(&messages[i]) != "system")
+        .collect();
+    if older.is_empty() {
+        return Ok(false);
+    }
+
+    // The most recent older turns that fit alongside the summary prompt
+    let room = get_model_limits()?
+        .context_window
+        .saturating_sub(DEFAULT_RESPONSE_RESERVE + count_text_tokens(&condense.prompt)?.tokens);
+    let mut used = 0;
+    let mut first = older.len();
+    for (k, &i) in older.iter().enumerate().rev() {
+        if used + costs[i] > room {
+            break;
+        }
+        used += costs[i];
+        first = k;
+    }
+    let transcript = older[first..]
+        .iter()
+        .map(|&i| {
+            format!(
+                "{}: {}",
+                message_role(&messages[i]),
+                message_text(&messages[i])
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if transcript.is_empty() {
+        return Ok(false);
+    }
+
+    let mut summary_request = GenerationRequest::new(
+        serde_json::json!([
+            { "role": "system", "content": condense.prompt },
+            { "role": "user", "content": transcript },
+        ])
+        .to_string(),
+        GenerationOptions::default(),
+    )?;
+    // Summarize with the model the conversation runs on
+    summary_request.adapter = request.adapter;
+    summary_request.guardrails = request.guardrails;
+    let request_id = next_request_id();
+    let raw = take_c_string(summary_request.invoke(request_id, None)?);
+    // Swift records usage for every request; don't leave this one behind
+    let _ = take_usage(request_id);
+    let metrics = GenerationMetrics::new(Instant::now(), None, None);
+    let Ok(summary) = parse_generation_result(&raw, None, metrics, None) else {
+        return Ok(false);
+    };
+    if summary.text.trim().is_empty() {
+        return Ok(false);
+    }
+
+    let summary = serde_json::json!({
+        "role": "system",
+        "content": format!("Summary of the earlier conversation:\n{}", summary.text.trim()),
+    });
+    let at = older[0];
+    *messages = std::mem::take(messages)
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, message)| (!older.contains(&i)).then_some(message))
+        .collect();
+    messages.insert(at, summary);
+    Ok(true)
+}
+
+impl GenerationRequest {
+    /// Tokens the messages can use: the context window less the tools, the
+    /// schema and room for the response.
+    pub fn message_budget(&self) -> napi::Result<u32> {
+        let reserve = match self.max_tokens {
+            n if n > 0 => n as u32,
+            _ => DEFAULT_RESPONSE_RESERVE,
+        };
+        let mut fixed = reserve;
+        for text in [self.tools_json.as_deref(), self.schema_json.as_deref()]
+            .into_iter()
+            .flatten()
+        {
+            fixed += count_text_tokens(text)?.tokens;
+        }
+        Ok(get_model_limits()?.context_window.saturating_sub(fixed))
+    }
+
+    /// Make `messages_json` fit the context window: summarize older turns
+    /// once past the condensation threshold, then apply the context policy by
+    /// dropping whole turns, or fail if the messages can't be made to fit.
+    pub fn fit_context(&mut self) -> napi::Result<()> {
+        if self.context_policy.is_none() && self.condense.is_none() {
+            return Ok(());
+        }
+        let budget = self.message_budget()?;
+
+        let mut messages: Vec<serde_json::Value> = serde_json::from_str(&self.messages_json)
+            .map_err(|e| napi::Error::from_reason(format!("Invalid messages JSON: {e}")))?;
+        let mut costs = message_costs(&messages)?;
+        let mut needed: u32 = costs.iter().sum();
+        if let Some(condense) = &self.condense {
+            if f64::from(needed) > f64::from(budget) * condense.threshold
+                && condense_turns(&mut messages, &costs, condense, self)?
+            {
+                costs = message_costs(&messages)?;
+                needed = costs.iter().sum();
+                self.messages_json = serde_json::Value::Array(messages.clone()).to_string();
+            }
+        }
+        if needed <= budget {
+            return Ok(());
+        }
+        let Some(policy) = self.context_policy else {
+            return Ok(());
+        };
+        let exceeded = |needed: u32| {
+            napi::Error::from_reason(format!(
+                "Messages need {needed} tokens but only {budget} fit in the context window"
+            ))
+        };
+        if policy == ContextPolicy::Error {
+            return Err(exceeded(needed));
+        }
+
+        let role = |i: usize| message_role(&messages[i]);
+        let latest = latest_turn(&messages);
+        let mut droppable = (0..latest).filter(|&i| role(i) != "system");
+        if policy == ContextPolicy::DropMiddle {
+            droppable.next();
+        }
+        let droppable: Vec<usize> = droppable.collect();
+
+        let mut keep = vec![true; messages.len()];
+        let mut total = needed;
+        for &i in &droppable {
+            if total <= budget {
+                break;
+            }
+            // Tool outputs go together with the assistant message that called them
+            if !keep[i] || role(i) == "tool" && (i == 0 || keep[i - 1]) {
+                continue;
+            }
+            keep[i] = false;
+            total -= costs[i];
+            let mut next = i + 1;
+            while next < latest && role(next) == "tool" && keep[next] {
+                keep[next] = false;
+                total -= costs[next];
+                next += 1;
+            }
+        }
+        if total > budget {
+            return Err(exceeded(total));
+        }
+        let kept: Vec<serde_json::Value> = messages
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(message, keep)| keep.then_some(message))
+            .collect();
+        self.messages_json = serde_json::Value::Array(kept).to_string();
+        Ok(())
+    }
+}
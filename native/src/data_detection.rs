@@ -0,0 +1,254 @@
+//! Structured data in text with NSDataDetector: dates, addresses, links,
+//! phone numbers and flights, with their values parsed.
+
+use crate::chunking::utf16_offsets;
+use crate::tokenization::trimmed;
+use crate::{apple_ai_detect_data, coded_error, take_c_string, ErrorCode};
+use napi::bindgen_prelude::AsyncTask;
+use napi::{Env, Status};
+use napi_derive::napi;
+use serde_json::Value;
+use std::ffi::CString;
+use std::ops::Range;
+
+const DATA_TYPES: [&str; 5] = [
+    "date",
+    "address",
+    "link",
+    "phoneNumber",
+    "transitInformation",
+];
+
+#[napi(object)]
+#[derive(Default)]
+pub struct DetectDataOptions {
+    /// Kinds of data to find (default: all)
+    #[napi(ts_type = "Array<'date' | 'address' | 'link' | 'phoneNumber' | 'transitInformation'>")]
+    pub types: Option<Vec<String>>,
+}
+
+/// A date resolved in its time zone.
+#[napi(object)]
+pub struct DateComponents {
+    pub year: i32,
+    /// 1 to 12
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// 1 (Sunday) to 7 (Saturday)
+    pub weekday: u32,
+}
+
+#[napi(object)]
+pub struct PostalAddress {
+    pub name: Option<String>,
+    pub organization: Option<String>,
+    pub job_title: Option<String>,
+    pub street: Option<String>,
+    pub city: Option<String>,
+    pub state: Option<String>,
+    pub postal_code: Option<String>,
+    pub country: Option<String>,
+}
+
+/// Data found in a text, with the value of its type.
+#[napi(object)]
+pub struct DataMatch {
+    #[napi(
+        js_name = "type",
+        ts_type = "'date' | 'address' | 'link' | 'phoneNumber' | 'transitInformation'"
+    )]
+    pub kind: String,
+    pub text: String,
+    /// Offset in the text, in UTF-16 code units like JS strings
+    pub start: u32,
+    pub end: u32,
+    /// For dates: ISO 8601 with the offset of `timeZone`, for `new Date()`
+    pub date: Option<String>,
+    /// For dates: the time zone named in the text, or the system's
+    pub time_zone: Option<String>,
+    /// For dates: the date's fields in `timeZone`
+    pub components: Option<DateComponents>,
+    /// For date ranges, such as "3–5pm": length in seconds
+    pub duration: Option<f64>,
+    /// For addresses: the parts found
+    pub address: Option<PostalAddress>,
+    /// For links: the URL, with a scheme added when the text has none
+    pub url: Option<String>,
+    /// For phone numbers: the number as written
+    pub phone_number: Option<String>,
+    /// For flights
+    pub airline: Option<String>,
+    pub flight: Option<String>,
+}
+
+fn string(value: &Value) -> Option<String> {
+    value.as_str().map(str::to_string)
+}
+
+fn data_match(text: String, start: u32, end: u32, value: &Value) -> Option<DataMatch> {
+    let components = &value["components"];
+    let address = &value["address"];
+    Some(DataMatch {
+        kind: value["type"].as_str()?.to_string(),
+        text,
+        start,
+        end,
+        date: string(&value["date"]),
+        time_zone: string(&value["timeZone"]),
+        components: components.is_object().then(|| {
+            let field = |name: &str| components[name].as_u64().unwrap_or(0) as u32;
+            DateComponents {
+                year: components["year"].as_i64().unwrap_or(0) as i32,
+                month: field("month"),
+                day: field("day"),
+                hour: field("hour"),
+                minute: field("minute"),
+                second: field("second"),
+                weekday: field("weekday"),
+            }
+        }),
+        duration: value["duration"].as_f64(),
+        address: address.is_object().then(|| PostalAddress {
+            name: string(&address["name"]),
+            organization: string(&address["organization"]),
+            job_title: string(&address["jobTitle"]),
+            street: string(&address["street"]),
+            city: string(&address["city"]),
+            state: string(&address["state"]),
+            postal_code: string(&address["postalCode"]),
+            country: string(&address["country"]),
+        }),
+        url: string(&value["url"]),
+        phone_number: string(&value["phoneNumber"]),
+        airline: string(&value["airline"]),
+        flight: string(&value["flight"]),
+    })
+}
+
+/// Byte ranges of data with Swift's value of each.
+pub type DataSpans = Vec<(Range<usize>, Value)>;
+
+/// Byte ranges of the data of `types_json` (a JSON array of types) found in
+/// `text`, with Swift's value of each.
+pub fn detect(
+    text: &str,
+    types_json: &CString,
+) -> std::result::Result<DataSpans, (ErrorCode, String)> {
+    let c_text = CString::new(text).map_err(|_| {
+        (
+            ErrorCode::InvalidArg,
+            "Text contained null byte".to_string(),
+        )
+    })?;
+    let ptr = unsafe { apple_ai_detect_data(c_text.as_ptr(), types_json.as_ptr()) };
+    if ptr.is_null() {
+        return Err((
+            ErrorCode::GenerationFailed,
+            "Data detection failed".to_string(),
+        ));
+    }
+    let result: Value = serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+        (
+            ErrorCode::GenerationFailed,
+            format!("Invalid JSON returned from native layer: {e}"),
+        )
+    })?;
+    if let Some(message) = result["error"].as_str() {
+        let code = result["code"]
+            .as_str()
+            .and_then(ErrorCode::parse)
+            .unwrap_or(ErrorCode::GenerationFailed);
+        return Err((code, message.to_string()));
+    }
+    let Value::Array(values) = result else {
+        return Ok(Vec::new());
+    };
+    Ok(values
+        .into_iter()
+        .filter_map(|value| {
+            let range = trimmed(
+                text,
+                value["start"].as_u64()? as usize,
+                value["end"].as_u64()? as usize,
+            )?;
+            Some((range, value))
+        })
+        .collect())
+}
+
+pub struct DetectDataTask {
+    text: String,
+    types_json: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for DetectDataTask {
+    type Output = Vec<DataMatch>;
+    type JsValue = Vec<DataMatch>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let found = detect(&self.text, &self.types_json).map_err(|(code, message)| {
+            self.error_code = Some(code);
+            napi::Error::from_reason(message)
+        })?;
+        let offsets = utf16_offsets(&self.text);
+        Ok(found
+            .into_iter()
+            .filter_map(|(range, value)| {
+                data_match(
+                    self.text[range.clone()].to_string(),
+                    offsets[range.start],
+                    offsets[range.end],
+                    &value,
+                )
+            })
+            .collect())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Find dates, addresses, links, phone numbers and flights in `text`, in
+/// order, with their values parsed: dates resolved relative to now, such
+/// as "next Tuesday at 3".
+#[napi(ts_return_type = "Promise<DataMatch[]>")]
+pub fn detect_data(
+    text: String,
+    options: Option<DetectDataOptions>,
+) -> napi::Result<AsyncTask<DetectDataTask>> {
+    let types = options
+        .unwrap_or_default()
+        .types
+        .unwrap_or_else(|| DATA_TYPES.iter().map(|t| t.to_string()).collect());
+    if types.is_empty() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "types must list at least one kind of data",
+        ));
+    }
+    if let Some(unknown) = types.iter().find(|t| !DATA_TYPES.contains(&t.as_str())) {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            format!(
+                "Unknown data type '{unknown}', expected one of {}",
+                DATA_TYPES.join(", ")
+            ),
+        ));
+    }
+    Ok(AsyncTask::new(DetectDataTask {
+        text,
+        types_json: CString::new(serde_json::to_string(&types).unwrap_or_default())
+            .map_err(|_| napi::Error::new(Status::InvalidArg, "Types contained null byte"))?,
+        error_code: None,
+    }))
+}
@@ -0,0 +1,257 @@
+//! Vercel AI SDK data stream protocol: stream events as `TYPE:JSON` lines
+//! (text, tool call, tool result, finish and error parts), the format the
+//! SDK's `useChat` and stream readers consume.
+
+use crate::{
+    next_request_id, start_formatted_stream, ChatMessage, GenerationOptions, GenerationRequest,
+    StreamEvent, TokenUsage,
+};
+use napi::JsFunction;
+use napi_derive::napi;
+use serde_json::{json, Value};
+
+/// One stream part: its type id, a colon, its JSON value and a newline.
+fn part(type_id: char, value: &Value) -> String {
+    format!("{type_id}:{value}\n")
+}
+
+fn finish_reason(reason: &str) -> &'static str {
+    match reason {
+        "stop" => "stop",
+        "length" => "length",
+        "tool_calls" => "tool-calls",
+        "content_filter" => "content-filter",
+        _ => "other",
+    }
+}
+
+fn usage_json(usage: Option<&TokenUsage>) -> Value {
+    json!({
+        "promptTokens": usage.map_or(0, |u| u.prompt_tokens),
+        "completionTokens": usage.map_or(0, |u| u.completion_tokens),
+    })
+}
+
+/// Tool arguments and results are JSON strings; parts carry them as values.
+fn json_value(text: Option<&str>) -> Value {
+    let text = text.unwrap_or_default();
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string()))
+}
+
+/// Turns stream events into data stream parts. The generation is a single
+/// step: a start-step part, its content, then finish-step and
+/// finish-message parts.
+pub struct DataStreamFormatter {
+    message_id: String,
+    started: bool,
+    sent_text: bool,
+}
+
+impl DataStreamFormatter {
+    pub fn new(message_id: String) -> Self {
+        Self {
+            message_id,
+            started: false,
+            sent_text: false,
+        }
+    }
+
+    /// Parts for one stream event; empty for partial objects, which the
+    /// protocol has no part for.
+    pub fn parts(&mut self, event: &StreamEvent) -> String {
+        let mut parts = String::new();
+        if !self.started {
+            self.started = true;
+            parts += &part('f', &json!({ "messageId": self.message_id }));
+        }
+        match event.kind.as_str() {
+            "text-delta" => {
+                self.sent_text = true;
+                parts += &part('0', &json!(event.text.as_deref().unwrap_or_default()));
+            }
+            "tool-call-start" => {
+                parts += &part(
+                    'b',
+                    &json!({ "toolCallId": event.tool_call_id, "toolName": event.tool_name }),
+                );
+            }
+            "tool-call-delta" => {
+                parts += &part(
+                    'c',
+                    &json!({ "toolCallId": event.tool_call_id, "argsTextDelta": event.args_delta }),
+                );
+            }
+            "tool-call" => {
+                parts += &part(
+                    '9',
+                    &json!({
+                        "toolCallId": event.tool_call_id,
+                        "toolName": event.tool_name,
+                        "args": json_value(event.args.as_deref()),
+                    }),
+                );
+            }
+            "tool-result" => {
+                parts += &part(
+                    'a',
+                    &json!({
+                        "toolCallId": event.tool_call_id,
+                        "result": json_value(event.result.as_deref()),
+                    }),
+                );
+            }
+            "finish" => {
+                // Structured output arrives as object snapshots; send the
+                // final object as the text
+                if let Some(object) = event.object.as_ref().filter(|_| !self.sent_text) {
+                    parts += &part('0', &json!(object.to_string()));
+                }
+                let reason = finish_reason(event.finish_reason.as_deref().unwrap_or("stop"));
+                let usage = usage_json(event.usage.as_ref());
+                parts += &part(
+                    'e',
+                    &json!({ "finishReason": reason, "usage": usage, "isContinued": false }),
+                );
+                parts += &part('d', &json!({ "finishReason": reason, "usage": usage }));
+            }
+            "error" => {
+                parts += &part('3', &json!(event.message.as_deref().unwrap_or_default()));
+            }
+            _ => {}
+        }
+        parts
+    }
+}
+
+/// Stream a generation as AI SDK data stream parts, ready to send as the body
+/// of a response with the `x-vercel-ai-data-stream: v1` header. The callback
+/// receives each batch of parts and whether the stream is over, which is
+/// after the finish-message part or an error part.
+#[napi]
+pub fn generate_data_stream(
+    messages: Vec<ChatMessage>,
+    options: Option<GenerationOptions>,
+    #[napi(ts_arg_type = "(err: Error | null, parts: string, done: boolean) => void")]
+    callback: JsFunction,
+) -> napi::Result<()> {
+    let request = GenerationRequest::from_messages(&messages, options.unwrap_or_default())?;
+    let mut formatter = DataStreamFormatter::new(format!("msg-{}", next_request_id()));
+    start_formatted_stream(request, callback, move |event| formatter.parts(event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finish(reason: &str, usage: Option<TokenUsage>, object: Option<Value>) -> StreamEvent {
+        StreamEvent {
+            kind: "finish".to_string(),
+            finish_reason: Some(reason.to_string()),
+            usage,
+            object,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn a_text_stream_is_framed_as_data_stream_parts() {
+        let mut formatter = DataStreamFormatter::new("msg-1".into());
+        let mut parts = formatter.parts(&StreamEvent::text_delta("Hel".into()));
+        parts += &formatter.parts(&StreamEvent::text_delta("lo \"you\"\n".into()));
+        let usage = TokenUsage {
+            prompt_tokens: 5,
+            completion_tokens: 2,
+            total_tokens: 7,
+        };
+        parts += &formatter.parts(&finish("stop", Some(usage), None));
+        assert_eq!(
+            parts,
+            concat!(
+                "f:{\"messageId\":\"msg-1\"}\n",
+                "0:\"Hel\"\n",
+                "0:\"lo \\\"you\\\"\\n\"\n",
+                "e:{\"finishReason\":\"stop\",\"isContinued\":false,",
+                "\"usage\":{\"completionTokens\":2,\"promptTokens\":5}}\n",
+                "d:{\"finishReason\":\"stop\",\"usage\":{\"completionTokens\":2,\"promptTokens\":5}}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn tool_calls_and_results_carry_json_values() {
+        let mut formatter = DataStreamFormatter::new("msg-1".into());
+        formatter.parts(&StreamEvent::text_delta("".into()));
+        let events = [
+            StreamEvent::tool_call_start("call_1".into(), "weather".into()),
+            StreamEvent::tool_call_delta("call_1".into(), "{\"city\":\"Oslo\"}".into()),
+            StreamEvent::tool_call(
+                "call_1".into(),
+                "weather".into(),
+                "{\"city\":\"Oslo\"}".into(),
+            ),
+            StreamEvent::tool_result("call_1".into(), "weather".into(), "{\"c\":4}".into()),
+            // A result that isn't JSON is sent as a string
+            StreamEvent::tool_result("call_2".into(), "echo".into(), "plain".into()),
+        ];
+        let parts: Vec<String> = events.iter().map(|event| formatter.parts(event)).collect();
+        assert_eq!(
+            parts,
+            [
+                "b:{\"toolCallId\":\"call_1\",\"toolName\":\"weather\"}\n",
+                "c:{\"argsTextDelta\":\"{\\\"city\\\":\\\"Oslo\\\"}\",\"toolCallId\":\"call_1\"}\n",
+                "9:{\"args\":{\"city\":\"Oslo\"},\"toolCallId\":\"call_1\",\"toolName\":\"weather\"}\n",
+                "a:{\"result\":{\"c\":4},\"toolCallId\":\"call_1\"}\n",
+                "a:{\"result\":\"plain\",\"toolCallId\":\"call_2\"}\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn finish_reasons_use_the_sdk_names() {
+        for (reason, sdk) in [
+            ("stop", "stop"),
+            ("length", "length"),
+            ("tool_calls", "tool-calls"),
+            ("content_filter", "content-filter"),
+            ("cancelled", "other"),
+        ] {
+            let mut formatter = DataStreamFormatter::new("msg-1".into());
+            let parts = formatter.parts(&finish(reason, None, None));
+            let last = parts.lines().last().unwrap();
+            assert_eq!(
+                last,
+                format!(
+                    "d:{{\"finishReason\":\"{sdk}\",\
+                     \"usage\":{{\"completionTokens\":0,\"promptTokens\":0}}}}"
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn structured_output_and_errors() {
+        let object = json!({ "answer": 42 });
+        let mut formatter = DataStreamFormatter::new("msg-1".into());
+        assert_eq!(
+            formatter.parts(&StreamEvent::object_delta(json!({ "answer": 4 }))),
+            "f:{\"messageId\":\"msg-1\"}\n"
+        );
+        let parts = formatter.parts(&finish("stop", None, Some(object.clone())));
+        assert!(
+            parts.starts_with("0:\"{\\\"answer\\\":42}\"\ne:"),
+            "{parts}"
+        );
+
+        let mut formatter = DataStreamFormatter::new("msg-2".into());
+        formatter.parts(&StreamEvent::text_delta(object.to_string()));
+        let parts = formatter.parts(&finish("stop", None, Some(object)));
+        assert!(parts.starts_with("e:"), "{parts}");
+
+        let mut formatter = DataStreamFormatter::new("msg-3".into());
+        let error = StreamEvent::error(crate::ErrorCode::Timeout, "Too slow".into());
+        assert_eq!(
+            formatter.parts(&error),
+            "f:{\"messageId\":\"msg-3\"}\n3:\"Too slow\"\n"
+        );
+    }
+}
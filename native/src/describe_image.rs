@@ -0,0 +1,267 @@
+//! Image descriptions for alt text and captions: Vision classifies the image
+//! and reads its text, and the language model writes the description from
+//! those findings, seeing the image itself when it takes image input.
+
+use crate::tokenization::{token_ranges, Unit};
+use crate::vision::{image_labels, recognized_text, Image, ImageArg, ImageData, ImageLabel};
+use crate::{
+    apple_ai_classify_image, apple_ai_recognize_text, coded_error, supports_image_input,
+    ChatMessage, ContentPart, ErrorCode, GenerateUnifiedTask, GenerationOptions, GenerationRequest,
+    TokenUsage,
+};
+use napi::bindgen_prelude::{AsyncTask, Either};
+use napi::{Env, Status};
+use napi_derive::napi;
+use serde_json::json;
+
+/// Longest recognized text put into the prompt, in characters
+const MAX_TEXT_CHARS: usize = 4000;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct DescribeImageOptions {
+    /// `altText` (default) for screen readers, `caption` for a line under the
+    /// image, or `description` for prose
+    #[napi(ts_type = "'altText' | 'caption' | 'description'")]
+    pub style: Option<String>,
+    /// Length of the description (default `brief` for alt text and captions,
+    /// `detailed` for descriptions)
+    #[napi(ts_type = "'brief' | 'standard' | 'detailed'")]
+    pub detail: Option<String>,
+    /// Give the model the image as well as Vision's findings when it takes
+    /// image input (default `true`)
+    pub attach_image: Option<bool>,
+}
+
+#[napi(object)]
+pub struct ImageDescription {
+    pub text: String,
+    /// Vision's labels for the image, most confident first
+    pub labels: Vec<ImageLabel>,
+    /// Text Vision read in the image
+    pub recognized_text: String,
+    /// Whether the model saw the image itself rather than only Vision's findings
+    pub saw_image: bool,
+    #[napi(ts_type = "'stop' | 'length' | 'tool_calls' | 'content_filter'")]
+    pub finish_reason: String,
+    pub usage: Option<TokenUsage>,
+}
+
+fn instructions(style: &str, detail: &str) -> String {
+    let task = match style {
+        "caption" => "Write a caption for the image, as printed under a photo.",
+        "description" => "Describe the image in prose: its subject, setting and notable details.",
+        _ => {
+            "Write alt text for the image for someone using a screen reader. Say what it shows \
+             and what matters in it plainly, without starting with \"Image of\"."
+        }
+    };
+    let length = match detail {
+        "brief" => "Use one short sentence.",
+        "standard" => "Use one to three sentences.",
+        _ => "Use a short paragraph.",
+    };
+    format!(
+        "{task} {length} Work from the analysis you are given: the labels come from a \
+         classifier and can be wrong or too broad, so use those that fit together and invent \
+         nothing beyond them. Reply with the text only."
+    )
+}
+
+/// The analysis of the image the model works from.
+fn findings(labels: &[ImageLabel], text: &str) -> String {
+    let mut findings = String::from("Image analysis:\n");
+    if labels.is_empty() {
+        findings.push_str("Likely contents: unknown\n");
+    } else {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|l| {
+                format!(
+                    "{} ({:.0}%)",
+                    l.identifier.replace('_', " "),
+                    l.confidence * 100.0
+                )
+            })
+            .collect();
+        findings.push_str(&format!("Likely contents: {}\n", labels.join(", ")));
+    }
+    if text.is_empty() {
+        findings.push_str("Text in the image: none");
+    } else {
+        let mut text = text.to_string();
+        if let Some((cut, _)) = text.char_indices().nth(MAX_TEXT_CHARS) {
+            // At the end of the last whole word that fits, where there is one
+            let cut = token_ranges(&text, Unit::Word, None)
+                .ok()
+                .and_then(|words| {
+                    words
+                        .iter()
+                        .map(|w| w.end)
+                        .take_while(|&end| end <= cut)
+                        .last()
+                })
+                .unwrap_or(cut);
+            text.truncate(cut);
+            text.push('…');
+        }
+        findings.push_str(&format!("Text in the image:\n\"\"\"\n{text}\n\"\"\""));
+    }
+    findings
+}
+
+fn text_message(role: &str, content: String) -> ChatMessage {
+    ChatMessage {
+        role: role.to_string(),
+        content: Some(Either::A(content)),
+        name: None,
+        tool_call_id: None,
+        tool_calls: None,
+    }
+}
+
+pub struct DescribeImageTask {
+    image: Option<Image>,
+    instructions: String,
+    attach_image: bool,
+    request: Option<GenerationRequest>,
+    error_code: Option<ErrorCode>,
+}
+
+impl DescribeImageTask {
+    fn fail(&mut self, code: ErrorCode, message: String) -> napi::Error {
+        self.error_code = Some(code);
+        napi::Error::from_reason(message)
+    }
+}
+
+impl napi::Task for DescribeImageTask {
+    type Output = ImageDescription;
+    type JsValue = ImageDescription;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let (Some(image), Some(mut request)) = (self.image.take(), self.request.take()) else {
+            return Err(napi::Error::from_reason("The request already ran"));
+        };
+        let labels = image
+            .analyze(
+                apple_ai_classify_image,
+                &json!({ "topK": 8, "minConfidence": 0.15 }),
+            )
+            .map(image_labels)
+            .map_err(|(code, message)| self.fail(code, message))?;
+        let text = image
+            .analyze(apple_ai_recognize_text, &json!({ "level": "accurate" }))
+            .map(recognized_text)
+            .map_err(|(code, message)| self.fail(code, message))?
+            .text;
+
+        let findings = findings(&labels, &text);
+        let saw_image = self.attach_image && supports_image_input();
+        let user = if saw_image {
+            let Image {
+                data,
+                max_dimension,
+                orientation,
+            } = image;
+            let (data, image_path) = match data {
+                ImageData::Buffer(buffer) => (Some(Either::B(buffer)), None),
+                ImageData::Path(path) => (None, Some(path.to_string_lossy().into_owned())),
+            };
+            ChatMessage {
+                content: Some(Either::B(vec![
+                    ContentPart {
+                        kind: "image".to_string(),
+                        text: None,
+                        data,
+                        image_path,
+                        max_dimension,
+                        mime_type: None,
+                        orientation,
+                    },
+                    ContentPart {
+                        kind: "text".to_string(),
+                        text: Some(findings),
+                        data: None,
+                        image_path: None,
+                        max_dimension: None,
+                        mime_type: None,
+                        orientation: None,
+                    },
+                ])),
+                ..text_message("user", String::new())
+            }
+        } else {
+            text_message("user", findings)
+        };
+        request.set_messages(&[text_message("system", self.instructions.clone()), user])?;
+        let mut generation = GenerateUnifiedTask::new(request);
+        let result = napi::Task::compute(&mut generation).map_err(|e| {
+            let code = generation.failure_code(&e);
+            self.fail(code, e.reason)
+        })?;
+        Ok(ImageDescription {
+            text: result.text.trim().to_string(),
+            labels,
+            recognized_text: text,
+            saw_image,
+            finish_reason: result.finish_reason,
+            usage: result.usage,
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Describe an image in words, e.g. as alt text: Vision classifies it and
+/// reads its text, and the on-device model writes the description from
+/// that. `image` is encoded image bytes, a file path or an `ImageSource`; `generation` takes
+/// the usual generation options.
+#[napi(ts_return_type = "Promise<ImageDescription>")]
+pub fn describe_image(
+    #[napi(ts_arg_type = "Buffer | string | ImageSource")] image: ImageArg,
+    options: Option<DescribeImageOptions>,
+    generation: Option<GenerationOptions>,
+) -> napi::Result<AsyncTask<DescribeImageTask>> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: String| napi::Error::new(Status::InvalidArg, message);
+    let style = options.style.as_deref().unwrap_or("altText");
+    if !matches!(style, "altText" | "caption" | "description") {
+        return Err(invalid(format!(
+            "style must be 'altText', 'caption' or 'description', got {style:?}"
+        )));
+    }
+    let detail = match options.detail.as_deref() {
+        Some(detail @ ("brief" | "standard" | "detailed")) => detail,
+        Some(other) => {
+            return Err(invalid(format!(
+                "detail must be 'brief', 'standard' or 'detailed', got {other:?}"
+            )))
+        }
+        None if style == "description" => "detailed",
+        None => "brief",
+    };
+    let generation = generation.unwrap_or_default();
+    if generation.schema_json.is_some() {
+        return Err(invalid(
+            "describeImage answers in text and cannot take a schema".to_string(),
+        ));
+    }
+    // The prompt is assembled once Vision has run
+    let placeholder = text_message("user", String::new());
+    let request = GenerationRequest::from_messages(&[placeholder], generation)?;
+    Ok(AsyncTask::new(DescribeImageTask {
+        image: Some(Image::new(image)?),
+        instructions: instructions(style, detail),
+        attach_image: options.attach_image.unwrap_or(true),
+        request: Some(request),
+        error_code: None,
+    }))
+}
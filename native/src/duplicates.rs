@@ -0,0 +1,314 @@
+//! Near-duplicate detection. Short texts are compared by embedding, which
+//! sees through rewording; long texts by MinHash estimates of the Jaccard
+//! similarity of their word shingles, which embeddings of a whole document
+//! blur. Duplicate pairs are joined into groups.
+
+use crate::embeddings::{embed_all, EmbeddingModel};
+use crate::keyword_index::terms;
+use crate::{coded_error, vector, ErrorCode};
+use napi::bindgen_prelude::AsyncTask;
+use napi::{Env, Status};
+use napi_derive::napi;
+use std::collections::HashMap;
+
+const DEFAULT_THRESHOLD: f64 = 0.95;
+const DEFAULT_JACCARD_THRESHOLD: f64 = 0.8;
+const DEFAULT_LONG_TEXT_WORDS: u32 = 200;
+const DEFAULT_SHINGLE_SIZE: u32 = 5;
+/// Signature length, as bands of rows for locality-sensitive hashing: two
+/// texts are compared when one band matches whole, which catches pairs
+/// well below the default Jaccard threshold
+const BANDS: usize = 32;
+const ROWS: usize = 4;
+const HASHES: usize = BANDS * ROWS;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct FindDuplicatesOptions {
+    /// Cosine similarity of embeddings from which short texts are duplicates
+    /// (default 0.95)
+    pub threshold: Option<f64>,
+    /// Estimated Jaccard similarity of word shingles from which long texts
+    /// are duplicates (default 0.8)
+    pub jaccard_threshold: Option<f64>,
+    /// Texts of more words than this are compared by MinHash instead of by
+    /// embedding (default 200)
+    pub long_text_words: Option<u32>,
+    /// Words per shingle for MinHash (default 5)
+    pub shingle_size: Option<u32>,
+    /// Language of the texts for embedding (default: detected per text)
+    pub language: Option<String>,
+    /// Embedding model: `sentence` (default) or `contextual`
+    #[napi(ts_type = "'sentence' | 'contextual'")]
+    pub model: Option<String>,
+}
+
+#[napi(object)]
+pub struct DuplicateGroup {
+    /// Positions of the texts in the input, in order
+    pub members: Vec<u32>,
+    /// The first member, as the one to keep
+    pub representative: u32,
+    /// Lowest similarity among the pairs that joined the group: cosine for
+    /// short texts, estimated Jaccard for long ones
+    pub similarity: f64,
+}
+
+/// FNV-1a, then a SplitMix64 finalizer per signature row.
+fn hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// MinHash signature of the `size`-word shingles of `words`.
+fn signature(words: &[String], size: usize) -> [u64; HASHES] {
+    let shingles: Vec<u64> = if words.len() <= size {
+        vec![hash(&words.join(" "))]
+    } else {
+        words.windows(size).map(|w| hash(&w.join(" "))).collect()
+    };
+    let mut signature = [u64::MAX; HASHES];
+    for shingle in shingles {
+        for (row, min) in signature.iter_mut().enumerate() {
+            let seed = (row as u64 + 1).wrapping_mul(0x9E3779B97F4A7C15);
+            *min = (*min).min(mix(shingle ^ seed));
+        }
+    }
+    signature
+}
+
+fn estimated_jaccard(a: &[u64; HASHES], b: &[u64; HASHES]) -> f64 {
+    a.iter().zip(b).filter(|(x, y)| x == y).count() as f64 / HASHES as f64
+}
+
+/// Pairs of the long texts, by their `signatures`, whose estimated
+/// Jaccard similarity is at least `threshold`.
+fn minhash_pairs(
+    signatures: &[(usize, [u64; HASHES])],
+    threshold: f64,
+) -> Vec<(usize, usize, f64)> {
+    let mut candidates = std::collections::HashSet::new();
+    for band in 0..BANDS {
+        let mut buckets: HashMap<&[u64], Vec<usize>> = HashMap::new();
+        for (position, (_, signature)) in signatures.iter().enumerate() {
+            buckets
+                .entry(&signature[band * ROWS..(band + 1) * ROWS])
+                .or_default()
+                .push(position);
+        }
+        for bucket in buckets.values() {
+            for (i, &a) in bucket.iter().enumerate() {
+                for &b in &bucket[i + 1..] {
+                    candidates.insert((a, b));
+                }
+            }
+        }
+    }
+    candidates
+        .into_iter()
+        .filter_map(|(a, b)| {
+            let similarity = estimated_jaccard(&signatures[a].1, &signatures[b].1);
+            (similarity >= threshold).then_some((signatures[a].0, signatures[b].0, similarity))
+        })
+        .collect()
+}
+
+/// Pairs of the short texts, by their unit `vectors`, whose cosine
+/// similarity is at least `threshold`, compared on several threads.
+fn embedding_pairs(vectors: &[(usize, Vec<f32>)], threshold: f64) -> Vec<(usize, usize, f64)> {
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let rows: Vec<usize> = (0..vectors.len()).collect();
+    // Interleave rows so every thread gets a similar share of the triangle
+    let per_thread: Vec<Vec<usize>> = (0..threads)
+        .map(|t| rows.iter().copied().skip(t).step_by(threads).collect())
+        .collect();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = per_thread
+            .iter()
+            .map(|rows| {
+                scope.spawn(move || {
+                    let mut pairs = Vec::new();
+                    for &i in rows {
+                        for j in i + 1..vectors.len() {
+                            let similarity = vector::dot(&vectors[i].1, &vectors[j].1) as f64;
+                            if similarity >= threshold {
+                                pairs.push((vectors[i].0, vectors[j].0, similarity));
+                            }
+                        }
+                    }
+                    pairs
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap_or_default())
+            .collect()
+    })
+}
+
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+/// Groups of the `n` texts joined by `pairs`, in order of their first member.
+fn group(n: usize, pairs: &[(usize, usize, f64)]) -> Vec<DuplicateGroup> {
+    let mut parents: Vec<usize> = (0..n).collect();
+    for &(a, b, _) in pairs {
+        let (a, b) = (find(&mut parents, a), find(&mut parents, b));
+        parents[a.max(b)] = a.min(b);
+    }
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut numbers = HashMap::new();
+    for i in 0..n {
+        let root = find(&mut parents, i);
+        let number = *numbers.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[number].push(i);
+    }
+    let mut weakest: HashMap<usize, f64> = HashMap::new();
+    for &(a, _, similarity) in pairs {
+        let number = numbers[&find(&mut parents, a)];
+        let entry = weakest.entry(number).or_insert(similarity);
+        *entry = entry.min(similarity);
+    }
+    groups
+        .into_iter()
+        .enumerate()
+        .filter(|(_, members)| members.len() > 1)
+        .map(|(number, members)| DuplicateGroup {
+            representative: members[0] as u32,
+            members: members.into_iter().map(|m| m as u32).collect(),
+            similarity: weakest.get(&number).copied().unwrap_or(1.0),
+        })
+        .collect()
+}
+
+pub struct FindDuplicatesTask {
+    texts: Vec<String>,
+    threshold: f64,
+    jaccard_threshold: f64,
+    long_text_words: usize,
+    shingle_size: usize,
+    language: Option<String>,
+    model: EmbeddingModel,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for FindDuplicatesTask {
+    type Output = Vec<DuplicateGroup>;
+    type JsValue = Vec<DuplicateGroup>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let mut short = Vec::new();
+        let mut long = Vec::new();
+        for (index, text) in self.texts.iter().enumerate() {
+            let words = terms(text);
+            if words.len() > self.long_text_words {
+                long.push((index, signature(&words, self.shingle_size)));
+            } else {
+                short.push(index);
+            }
+        }
+        let short_texts: Vec<String> = short.iter().map(|&i| self.texts[i].clone()).collect();
+        let concurrency = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let vectors = embed_all(
+            &short_texts,
+            self.language.as_deref(),
+            self.model,
+            concurrency,
+        )
+        .map_err(|e| {
+            self.error_code = Some(e.code);
+            // Name the text by its position in the input
+            let message = e
+                .message
+                .strip_prefix("Text ")
+                .and_then(|rest| rest.split_once(": "))
+                .and_then(|(i, rest)| Some((short[i.parse::<usize>().ok()?], rest)))
+                .map_or(e.message.clone(), |(i, rest)| format!("Text {i}: {rest}"));
+            napi::Error::from_reason(message)
+        })?;
+        let vectors: Vec<(usize, Vec<f32>)> = short
+            .into_iter()
+            .zip(vectors)
+            .map(|(index, mut vector)| {
+                let norm = vector::norm(&vector);
+                if norm > 0.0 {
+                    vector.iter_mut().for_each(|x| *x /= norm);
+                }
+                (index, vector)
+            })
+            .collect();
+        // Embeddings of different dimensions come from different languages
+        let mut by_dimension: HashMap<usize, Vec<(usize, Vec<f32>)>> = HashMap::new();
+        for entry in vectors {
+            by_dimension.entry(entry.1.len()).or_default().push(entry);
+        }
+        let mut pairs = minhash_pairs(&long, self.jaccard_threshold);
+        for vectors in by_dimension.values() {
+            pairs.extend(embedding_pairs(vectors, self.threshold));
+        }
+        Ok(group(self.texts.len(), &pairs))
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Find groups of near-duplicate texts. Texts of up to `longTextWords`
+/// words are compared by embedding cosine similarity, longer ones by
+/// MinHash over word shingles. Resolves with the groups of two or more, in
+/// order of their first member.
+#[napi(ts_return_type = "Promise<DuplicateGroup[]>")]
+pub fn find_duplicates(
+    texts: Vec<String>,
+    options: Option<FindDuplicatesOptions>,
+) -> napi::Result<AsyncTask<FindDuplicatesTask>> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: &str| napi::Error::new(Status::InvalidArg, message.to_string());
+    let model = EmbeddingModel::parse(options.model.as_deref()).map_err(|e| invalid(&e.message))?;
+    let threshold = options.threshold.unwrap_or(DEFAULT_THRESHOLD);
+    if !(-1.0..=1.0).contains(&threshold) {
+        return Err(invalid("threshold must be between -1 and 1"));
+    }
+    let jaccard_threshold = options
+        .jaccard_threshold
+        .unwrap_or(DEFAULT_JACCARD_THRESHOLD);
+    if !(0.0..=1.0).contains(&jaccard_threshold) {
+        return Err(invalid("jaccardThreshold must be between 0 and 1"));
+    }
+    let shingle_size = options.shingle_size.unwrap_or(DEFAULT_SHINGLE_SIZE);
+    if shingle_size == 0 {
+        return Err(invalid("shingleSize must be at least 1"));
+    }
+    Ok(AsyncTask::new(FindDuplicatesTask {
+        texts,
+        threshold,
+        jaccard_threshold,
+        long_text_words: options.long_text_words.unwrap_or(DEFAULT_LONG_TEXT_WORDS) as usize,
+        shingle_size: shingle_size as usize,
+        language: options.language,
+        model,
+        error_code: None,
+    }))
+}
@@ -0,0 +1,430 @@
+//! Cache of text embeddings keyed by a hash of the text, the model and the
+//! requested language, so embedding unchanged documents again costs a
+//! lookup. Optionally persisted to a file in the `vector_store` log format,
+//! with keys as entry ids.
+
+use crate::vector_store::{self, open_for_append, Record};
+use napi_derive::napi;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+const DEFAULT_MAX_ENTRIES: u32 = 10_000;
+
+/// 128-bit FNV-1a: stable across runs and Rust versions, unlike `std`'s
+/// hasher, so keys stay valid on disk.
+fn text_hash(text: &str) -> u128 {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+    text.bytes().fold(OFFSET, |hash, byte| {
+        (hash ^ byte as u128).wrapping_mul(PRIME)
+    })
+}
+
+/// `model:language:hash`, with an empty language when it's detected per text.
+pub fn key(text: &str, model: &str, language: Option<&str>) -> String {
+    format!(
+        "{model}:{}:{:032x}",
+        language.unwrap_or_default(),
+        text_hash(text)
+    )
+}
+
+struct Cache {
+    enabled: bool,
+    max_entries: usize,
+    vectors: HashMap<String, Vec<f32>>,
+    /// Keys oldest first, for eviction
+    order: VecDeque<String>,
+    hits: u64,
+    misses: u64,
+    file: Option<(String, File)>,
+}
+
+impl Cache {
+    /// Write to the cache file, if any; a failed write detaches it rather
+    /// than failing the embedding that caused it.
+    fn append(&mut self, record: Vec<u8>) {
+        if let Some((_, file)) = &mut self.file {
+            if file.write_all(&record).is_err() {
+                self.file = None;
+            }
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.vectors.len() > self.max_entries {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if self.vectors.remove(&oldest).is_some() {
+                self.append(vector_store::encode_remove(&oldest));
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        if self.vectors.remove(key).is_none() {
+            return false;
+        }
+        self.order.retain(|k| k != key);
+        self.append(vector_store::encode_remove(key));
+        true
+    }
+}
+
+fn cache() -> MutexGuard<'static, Cache> {
+    static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+    CACHE
+        .get_or_init(|| {
+            Mutex::new(Cache {
+                enabled: true,
+                max_entries: DEFAULT_MAX_ENTRIES as usize,
+                vectors: HashMap::new(),
+                order: VecDeque::new(),
+                hits: 0,
+                misses: 0,
+                file: None,
+            })
+        })
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn get(key: &str) -> Option<Vec<f32>> {
+    let mut cache = cache();
+    if !cache.enabled {
+        return None;
+    }
+    let vector = cache.vectors.get(key).cloned();
+    if vector.is_some() {
+        cache.hits += 1;
+    } else {
+        cache.misses += 1;
+    }
+    vector
+}
+
+pub fn put(key: String, vector: &[f32]) {
+    let mut cache = cache();
+    if !cache.enabled || cache.vectors.contains_key(&key) {
+        return;
+    }
+    cache.append(vector_store::encode_upsert(&key, None, vector));
+    cache.order.push_back(key.clone());
+    cache.vectors.insert(key, vector.to_vec());
+    cache.evict();
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct EmbeddingCacheOptions {
+    /// Whether embeddings are cached (default `true`)
+    pub enabled: Option<bool>,
+    /// Most embeddings kept; the oldest are evicted first (default 10000)
+    pub max_entries: Option<u32>,
+    /// File to keep the cache in across restarts. Entries already in it are
+    /// loaded, and new ones are appended as they're computed
+    pub path: Option<String>,
+}
+
+#[napi(object)]
+pub struct EmbeddingCacheStats {
+    pub entries: u32,
+    pub max_entries: u32,
+    /// Embeddings answered from the cache since it was last cleared
+    pub hits: i64,
+    /// Embeddings that had to be computed
+    pub misses: i64,
+    /// File the cache persists to
+    pub path: Option<String>,
+    pub enabled: bool,
+}
+
+/// Configure the embedding cache. Giving a `path` loads the embeddings
+/// cached in that file and appends new ones to it; `path: ''` stops
+/// persisting. Options left out keep their current values.
+#[napi]
+pub fn configure_embedding_cache(options: EmbeddingCacheOptions) -> napi::Result<()> {
+    let mut cache = cache();
+    if let Some(enabled) = options.enabled {
+        cache.enabled = enabled;
+    }
+    if let Some(max_entries) = options.max_entries {
+        cache.max_entries = max_entries as usize;
+    }
+    match options.path.as_deref() {
+        None => {}
+        Some("") => cache.file = None,
+        Some(path) => {
+            if std::fs::metadata(path).is_ok() {
+                let Cache { vectors, order, .. } = &mut *cache;
+                vector_store::replay(path, |record| {
+                    match record {
+                        Record::Upsert { id, vector, .. } => {
+                            if vectors.insert(id.to_string(), vector).is_none() {
+                                order.push_back(id.to_string());
+                            }
+                        }
+                        Record::Remove(id) => {
+                            if vectors.remove(id).is_some() {
+                                order.retain(|key| key != id);
+                            }
+                        }
+                        Record::Clear => {
+                            vectors.clear();
+                            order.clear();
+                        }
+                    }
+                    Ok(())
+                })
+                .map_err(napi::Error::from_reason)?;
+            }
+            // Rewrite the file compacted, with what's in memory now
+            cache.file = None;
+            cache.evict();
+            let mut contents = vector_store::header(None);
+            for key in &cache.order {
+                contents.extend(vector_store::encode_upsert(key, None, &cache.vectors[key]));
+            }
+            vector_store::write(path, &contents).map_err(napi::Error::from_reason)?;
+            let file = open_for_append(path).map_err(napi::Error::from_reason)?;
+            cache.file = Some((path.to_string(), file));
+        }
+    }
+    cache.evict();
+    Ok(())
+}
+
+#[napi]
+pub fn embedding_cache_stats() -> EmbeddingCacheStats {
+    let cache = cache();
+    EmbeddingCacheStats {
+        entries: cache.vectors.len() as u32,
+        max_entries: cache.max_entries as u32,
+        hits: cache.hits as i64,
+        misses: cache.misses as i64,
+        path: cache.file.as_ref().map(|(path, _)| path.clone()),
+        enabled: cache.enabled,
+    }
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct EmbeddingCacheFilter {
+    /// Only embeddings of this model
+    #[napi(ts_type = "'sentence' | 'contextual'")]
+    pub model: Option<String>,
+    /// Only embeddings requested with this language
+    pub language: Option<String>,
+}
+
+/// Remove cached embeddings, all of them or those matching `filter`, and
+/// reset the hit and miss counts. Returns how many were removed.
+#[napi]
+pub fn clear_embedding_cache(filter: Option<EmbeddingCacheFilter>) -> u32 {
+    let filter = filter.unwrap_or_default();
+    let mut cache = cache();
+    cache.hits = 0;
+    cache.misses = 0;
+    if filter.model.is_none() && filter.language.is_none() {
+        let removed = cache.vectors.len() as u32;
+        cache.vectors.clear();
+        cache.order.clear();
+        cache.append(vector_store::encode_clear());
+        return removed;
+    }
+    let matching: Vec<String> = cache
+        .vectors
+        .keys()
+        .filter(|key| {
+            let mut parts = key.splitn(3, ':');
+            let (model, language) = (parts.next(), parts.next());
+            filter.model.as_deref().is_none_or(|m| model == Some(m))
+                && filter
+                    .language
+                    .as_deref()
+                    .is_none_or(|l| language == Some(l))
+        })
+        .cloned()
+        .collect();
+    for key in &matching {
+        cache.remove(key);
+    }
+    matching.len() as u32
+}
+
+/// Remove the cached embeddings of `texts`, as embedded with `model`
+/// (default `sentence`) and `language`. Returns how many were removed.
+#[napi]
+pub fn invalidate_embeddings(
+    texts: Vec<String>,
+    #[napi(ts_arg_type = "{ model?: 'sentence' | 'contextual'; language?: string }")]
+    options: Option<EmbeddingCacheFilter>,
+) -> u32 {
+    let options = options.unwrap_or_default();
+    let model = options.model.as_deref().unwrap_or("sentence");
+    let mut cache = cache();
+    texts
+        .iter()
+        .filter(|text| cache.remove(&key(text, model, options.language.as_deref())))
+        .count() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The cache is process-wide; tests that use it take turns and start
+    /// from an empty, default one.
+    fn fresh_cache() -> MutexGuard<'static, ()> {
+        static TURN: Mutex<()> = Mutex::new(());
+        let turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+        configure_embedding_cache(EmbeddingCacheOptions {
+            enabled: Some(true),
+            max_entries: Some(DEFAULT_MAX_ENTRIES),
+            path: Some(String::new()),
+        })
+        .unwrap();
+        clear_embedding_cache(None);
+        turn
+    }
+
+    fn cached(keys: &[&str]) -> Vec<bool> {
+        let cache = cache();
+        keys.iter()
+            .map(|k| cache.vectors.contains_key(*k))
+            .collect()
+    }
+
+    #[test]
+    fn keys_are_stable() {
+        // Keys name entries in cache files, so they must never change
+        assert_eq!(text_hash(""), 0x6c62272e07bb014262b821756295c58d);
+        assert_eq!(text_hash("a"), 0xd228cb696f1a8caf78912b704e4a8964);
+        assert_eq!(
+            key("hello world", "sentence", Some("en")),
+            "sentence:en:6c155799fdc8eec4b91523808e7726b7"
+        );
+        assert_eq!(
+            key("", "contextual", None),
+            "contextual::6c62272e07bb014262b821756295c58d"
+        );
+        assert_ne!(
+            key("hello world", "sentence", Some("en")),
+            key("hello world", "sentence", Some("fr"))
+        );
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted_first() {
+        let _turn = fresh_cache();
+        configure_embedding_cache(EmbeddingCacheOptions {
+            max_entries: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        put("a".into(), &[1.0]);
+        put("b".into(), &[2.0]);
+        // Reading an entry doesn't make it any younger
+        assert_eq!(get("a"), Some(vec![1.0]));
+        put("c".into(), &[3.0]);
+        assert_eq!(cached(&["a", "b", "c"]), [false, true, true]);
+        assert_eq!(get("a"), None);
+        let stats = embedding_cache_stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (2, 1, 1));
+
+        configure_embedding_cache(EmbeddingCacheOptions {
+            max_entries: Some(0),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(embedding_cache_stats().entries, 0);
+        put("d".into(), &[4.0]);
+        assert_eq!(get("d"), None);
+
+        configure_embedding_cache(EmbeddingCacheOptions {
+            enabled: Some(false),
+            max_entries: Some(10),
+            ..Default::default()
+        })
+        .unwrap();
+        put("e".into(), &[5.0]);
+        assert_eq!(cached(&["e"]), [false]);
+    }
+
+    #[test]
+    fn clearing_filters_on_model_and_language() {
+        let _turn = fresh_cache();
+        let keys = [
+            key("one", "sentence", Some("en")),
+            key("two", "sentence", Some("fr")),
+            key("one", "contextual", Some("en")),
+            key("two", "contextual", None),
+        ];
+        for key in &keys {
+            put(key.clone(), &[0.5]);
+        }
+        let filter = |model: Option<&str>, language: Option<&str>| {
+            Some(EmbeddingCacheFilter {
+                model: model.map(str::to_string),
+                language: language.map(str::to_string),
+            })
+        };
+        assert_eq!(
+            clear_embedding_cache(filter(Some("sentence"), Some("fr"))),
+            1
+        );
+        assert_eq!(clear_embedding_cache(filter(None, Some("en"))), 2);
+        // An empty language is the one detected per text
+        assert_eq!(
+            clear_embedding_cache(filter(Some("contextual"), Some(""))),
+            1
+        );
+        assert_eq!(embedding_cache_stats().entries, 0);
+
+        put(keys[0].clone(), &[0.5]);
+        assert_eq!(
+            invalidate_embeddings(vec!["one".into(), "three".into()], filter(None, Some("en"))),
+            1
+        );
+        assert_eq!(embedding_cache_stats().entries, 0);
+    }
+
+    #[test]
+    fn cache_files_survive_a_restart() {
+        let _turn = fresh_cache();
+        let path = std::env::temp_dir()
+            .join(format!(
+                "apple-ai-{}-embedding-cache.vec",
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_file(&path);
+        let persist = |path: &str| {
+            configure_embedding_cache(EmbeddingCacheOptions {
+                path: Some(path.to_string()),
+                ..Default::default()
+            })
+        };
+
+        persist(&path).unwrap();
+        put("kept".into(), &[1.0, -2.0]);
+        put("dropped".into(), &[3.0, 4.0]);
+        assert!(cache().remove("dropped"));
+        assert_eq!(embedding_cache_stats().path.as_deref(), Some(path.as_str()));
+
+        // Forget everything in memory, as a new process would start
+        persist("").unwrap();
+        clear_embedding_cache(None);
+        persist(&path).unwrap();
+        assert_eq!(get("kept"), Some(vec![1.0, -2.0]));
+        assert_eq!(get("dropped"), None);
+        assert_eq!(embedding_cache_stats().entries, 1);
+
+        persist("").unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
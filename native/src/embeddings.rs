@@ -0,0 +1,482 @@
+//! Text embeddings from the NaturalLanguage framework.
+
+use crate::embedding_cache;
+use crate::{
+    apple_ai_contextual_embedding, apple_ai_sentence_embedding, apple_ai_word_distance,
+    apple_ai_word_neighbors, apple_ai_word_vector, coded_error, take_c_string, ErrorCode,
+};
+use napi::bindgen_prelude::{AsyncTask, Float32Array, Uint32Array};
+use napi::Env;
+use napi_derive::napi;
+use std::ffi::{c_char, CString};
+
+/// Room for any embedding the system ships (sentence embeddings have 512
+/// dimensions, contextual ones 768)
+const MAX_DIMENSION: usize = 2048;
+
+/// Which of the framework's embeddings turns text into a vector.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmbeddingModel {
+    /// `NLEmbedding.sentenceEmbedding`: one model per language, always on
+    /// the device
+    Sentence,
+    /// `NLContextualEmbedding`: a transformer per script, mean-pooled over
+    /// the text's tokens; its assets are downloaded on first use
+    Contextual,
+}
+
+impl EmbeddingModel {
+    pub fn parse(name: Option<&str>) -> Result<Self, EmbeddingError> {
+        match name {
+            None | Some("sentence") => Ok(Self::Sentence),
+            Some("contextual") => Ok(Self::Contextual),
+            Some(other) => Err(EmbeddingError::invalid(format!(
+                "Unknown embedding model '{other}', expected 'sentence' or 'contextual'"
+            ))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sentence => "sentence",
+            Self::Contextual => "contextual",
+        }
+    }
+}
+
+/// Why a text couldn't be embedded, with the code a rejection carries.
+#[derive(Debug)]
+pub struct EmbeddingError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl EmbeddingError {
+    fn invalid(message: String) -> Self {
+        Self {
+            code: ErrorCode::InvalidArg,
+            message,
+        }
+    }
+}
+
+/// Embedding of `text` by `model`. `language` (e.g. "en") picks the
+/// embedding; unset, the text's dominant language does, falling back to
+/// English. Served from the embedding cache when the same text was embedded
+/// the same way before.
+pub fn embedding(
+    text: &str,
+    language: Option<&str>,
+    model: EmbeddingModel,
+) -> Result<Vec<f32>, EmbeddingError> {
+    let key = embedding_cache::key(text, model.name(), language);
+    if let Some(vector) = embedding_cache::get(&key) {
+        return Ok(vector);
+    }
+    let vector = compute(text, language, model)?;
+    embedding_cache::put(key, &vector);
+    Ok(vector)
+}
+
+fn compute(
+    text: &str,
+    language: Option<&str>,
+    model: EmbeddingModel,
+) -> Result<Vec<f32>, EmbeddingError> {
+    let c_text = CString::new(text)
+        .map_err(|_| EmbeddingError::invalid("Text contained null byte".into()))?;
+    let c_language = language
+        .map(CString::new)
+        .transpose()
+        .map_err(|_| EmbeddingError::invalid("Language contained null byte".into()))?;
+    let embed: unsafe extern "C" fn(*const c_char, *const c_char, *mut f32, usize) -> isize =
+        match model {
+            EmbeddingModel::Sentence => apple_ai_sentence_embedding,
+            EmbeddingModel::Contextual => apple_ai_contextual_embedding,
+        };
+    let mut vector = vec![0.0; MAX_DIMENSION];
+    let dimension = unsafe {
+        embed(
+            c_text.as_ptr(),
+            c_language.as_ref().map_or(std::ptr::null(), |l| l.as_ptr()),
+            vector.as_mut_ptr(),
+            vector.len(),
+        )
+    };
+    let language = language.unwrap_or("the text's language");
+    match dimension {
+        -2 => Err(EmbeddingError {
+            code: ErrorCode::AssetsUnavailable,
+            message: format!(
+                "The {} embedding assets for {language} could not be downloaded",
+                model.name()
+            ),
+        }),
+        ..=-1 => Err(EmbeddingError {
+            code: ErrorCode::UnsupportedLanguage,
+            message: format!("No {} embedding is available for {language}", model.name()),
+        }),
+        0 => Err(EmbeddingError::invalid(
+            "The text could not be embedded".to_string(),
+        )),
+        n if n as usize > MAX_DIMENSION => Err(EmbeddingError {
+            code: ErrorCode::GenerationFailed,
+            message: format!("Embedding dimension {n} is unsupported"),
+        }),
+        n => {
+            vector.truncate(n as usize);
+            Ok(vector)
+        }
+    }
+}
+
+/// Embeddings of `texts` in order, computed on up to `concurrency` threads.
+/// A failure names the index of the text that failed.
+pub fn embed_all(
+    texts: &[String],
+    language: Option<&str>,
+    model: EmbeddingModel,
+    concurrency: usize,
+) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+    let embed_one = |(index, text): (usize, &String)| {
+        embedding(text, language, model).map_err(|e| EmbeddingError {
+            code: e.code,
+            message: format!("Text {index}: {}", e.message),
+        })
+    };
+    let concurrency = concurrency.clamp(1, texts.len().max(1));
+    if concurrency == 1 {
+        return texts.iter().enumerate().map(embed_one).collect();
+    }
+    let chunk = texts.len().div_ceil(concurrency);
+    std::thread::scope(|scope| {
+        let workers = texts
+            .chunks(chunk)
+            .enumerate()
+            .map(|(n, texts)| {
+                scope.spawn(move || {
+                    texts
+                        .iter()
+                        .enumerate()
+                        .map(|(i, text)| embed_one((n * chunk + i, text)))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut vectors = Vec::with_capacity(texts.len());
+        for worker in workers {
+            vectors.extend(worker.join().expect("embedding worker panicked")?);
+        }
+        Ok(vectors)
+    })
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct EmbedOptions {
+    /// Language of the texts, e.g. "en" (default: detected per text)
+    pub language: Option<String>,
+    /// `sentence` (default) or `contextual`
+    #[napi(ts_type = "'sentence' | 'contextual'")]
+    pub model: Option<String>,
+}
+
+pub struct EmbedTask {
+    texts: Vec<String>,
+    language: Option<String>,
+    model: EmbeddingModel,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for EmbedTask {
+    type Output = Vec<Vec<f32>>;
+    type JsValue = Vec<Float32Array>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        embed_all(&self.texts, self.language.as_deref(), self.model, 1).map_err(|e| {
+            self.error_code = Some(e.code);
+            napi::Error::from_reason(e.message)
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output.into_iter().map(Float32Array::new).collect())
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Embed each text as a vector, in order. Vectors of one model and language
+/// have the same dimension and compare by cosine similarity. Rejects with
+/// code `UnsupportedLanguage` when the model doesn't cover a text's language,
+/// and `AssetsUnavailable` when contextual embedding assets can't be
+/// downloaded.
+#[napi(ts_return_type = "Promise<Float32Array[]>")]
+pub fn embed(
+    texts: Vec<String>,
+    options: Option<EmbedOptions>,
+) -> napi::Result<AsyncTask<EmbedTask>> {
+    let options = options.unwrap_or_default();
+    let model = EmbeddingModel::parse(options.model.as_deref())
+        .map_err(|e| napi::Error::new(napi::Status::InvalidArg, e.message))?;
+    Ok(AsyncTask::new(EmbedTask {
+        texts,
+        language: options.language,
+        model,
+        error_code: None,
+    }))
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct EmbedBatchOptions {
+    /// Language of the texts, e.g. "en" (default: detected per text)
+    pub language: Option<String>,
+    /// `sentence` (default) or `contextual`
+    #[napi(ts_type = "'sentence' | 'contextual'")]
+    pub model: Option<String>,
+    /// Texts embedded at once, on separate threads (default: the number of
+    /// CPU cores)
+    pub concurrency: Option<u32>,
+}
+
+/// The vectors of a batch, end to end in one buffer.
+#[napi(object)]
+pub struct EmbeddingBatch {
+    /// All vectors, in the order of the texts
+    pub data: Float32Array,
+    /// `texts.length + 1` offsets into `data`: vector `i` is
+    /// `data.subarray(offsets[i], offsets[i + 1])`
+    pub offsets: Uint32Array,
+    /// Dimension shared by every vector, or 0 when the texts' languages
+    /// gave vectors of different dimensions
+    pub dimension: u32,
+}
+
+pub struct EmbedBatchTask {
+    texts: Vec<String>,
+    language: Option<String>,
+    model: EmbeddingModel,
+    concurrency: usize,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for EmbedBatchTask {
+    type Output = (Vec<f32>, Vec<u32>, u32);
+    type JsValue = EmbeddingBatch;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let vectors = embed_all(
+            &self.texts,
+            self.language.as_deref(),
+            self.model,
+            self.concurrency,
+        )
+        .map_err(|e| {
+            self.error_code = Some(e.code);
+            napi::Error::from_reason(e.message)
+        })?;
+        let mut data = Vec::with_capacity(vectors.iter().map(Vec::len).sum());
+        let mut offsets = Vec::with_capacity(vectors.len() + 1);
+        offsets.push(0);
+        for vector in &vectors {
+            data.extend_from_slice(vector);
+            offsets.push(data.len() as u32);
+        }
+        let dimension = match vectors.first() {
+            Some(first) if vectors.iter().all(|v| v.len() == first.len()) => first.len() as u32,
+            _ => 0,
+        };
+        Ok((data, offsets, dimension))
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        let (data, offsets, dimension) = output;
+        Ok(EmbeddingBatch {
+            data: Float32Array::new(data),
+            offsets: Uint32Array::new(offsets),
+            dimension,
+        })
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Embed a large array of texts off the main thread, several at once, and
+/// resolve with every vector in one `Float32Array` plus the offsets of each,
+/// instead of an array per text. Rejects like `embed`, naming the index of
+/// the text that failed.
+#[napi(ts_return_type = "Promise<EmbeddingBatch>")]
+pub fn embed_batch(
+    texts: Vec<String>,
+    options: Option<EmbedBatchOptions>,
+) -> napi::Result<AsyncTask<EmbedBatchTask>> {
+    let options = options.unwrap_or_default();
+    let model = EmbeddingModel::parse(options.model.as_deref())
+        .map_err(|e| napi::Error::new(napi::Status::InvalidArg, e.message))?;
+    if options.concurrency == Some(0) {
+        return Err(napi::Error::new(
+            napi::Status::InvalidArg,
+            "concurrency must be at least 1",
+        ));
+    }
+    let concurrency = options.concurrency.map_or_else(
+        || std::thread::available_parallelism().map_or(4, |n| n.get()),
+        |n| n as usize,
+    );
+    Ok(AsyncTask::new(EmbedBatchTask {
+        texts,
+        language: options.language,
+        model,
+        concurrency,
+        error_code: None,
+    }))
+}
+
+/// Language of the word embedding when a call doesn't name one
+const DEFAULT_WORD_LANGUAGE: &str = "en";
+
+#[napi(object)]
+#[derive(Default)]
+pub struct WordEmbeddingOptions {
+    /// Language of the word embedding, e.g. "fr" (default "en")
+    pub language: Option<String>,
+}
+
+/// A word near another in the word embedding.
+#[napi(object)]
+pub struct WordNeighbor {
+    pub word: String,
+    /// Cosine distance, from 0 (same direction) to 2
+    pub distance: f64,
+}
+
+fn word_c_strings(
+    words: &[&str],
+    options: Option<WordEmbeddingOptions>,
+) -> napi::Result<(Vec<CString>, CString, String)> {
+    let language = options
+        .and_then(|o| o.language)
+        .unwrap_or_else(|| DEFAULT_WORD_LANGUAGE.to_string());
+    let c_words = words
+        .iter()
+        .map(|word| CString::new(*word))
+        .collect::<Result<_, _>>()
+        .map_err(|_| napi::Error::new(napi::Status::InvalidArg, "Word contained null byte"))?;
+    let c_language = CString::new(language.as_str())
+        .map_err(|_| napi::Error::new(napi::Status::InvalidArg, "Language contained null byte"))?;
+    Ok((c_words, c_language, language))
+}
+
+fn no_word_embedding(env: Env, language: &str) -> napi::Error {
+    coded_error(
+        env,
+        ErrorCode::UnsupportedLanguage,
+        format!("No word embedding is available for {language}"),
+    )
+}
+
+/// Up to `k` (default 10) words of the language's vocabulary nearest to
+/// `word`, nearest first; empty when `word` isn't in the vocabulary. Throws
+/// with code `UnsupportedLanguage` when the system has no word embedding for
+/// the language.
+#[napi]
+pub fn nearest_neighbors(
+    env: Env,
+    word: String,
+    k: Option<u32>,
+    options: Option<WordEmbeddingOptions>,
+) -> napi::Result<Vec<WordNeighbor>> {
+    let (c_words, c_language, _) = word_c_strings(&[&word], options)?;
+    let count = k.unwrap_or(10) as usize;
+    let ptr = unsafe { apple_ai_word_neighbors(c_words[0].as_ptr(), c_language.as_ptr(), count) };
+    if ptr.is_null() {
+        return Err(napi::Error::from_reason("Nearest neighbor lookup failed"));
+    }
+    let json: serde_json::Value = serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+        napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+    })?;
+    if let Some(message) = json.get("error").and_then(|v| v.as_str()) {
+        let code = json
+            .get("code")
+            .and_then(|v| v.as_str())
+            .and_then(ErrorCode::parse)
+            .unwrap_or(ErrorCode::GenerationFailed);
+        return Err(coded_error(env, code, message.to_string()));
+    }
+    Ok(json
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|neighbor| {
+            Some(WordNeighbor {
+                word: neighbor.get("word")?.as_str()?.to_string(),
+                distance: neighbor.get("distance")?.as_f64()?,
+            })
+        })
+        .collect())
+}
+
+/// Cosine distance between two words, from 0 to 2, or `null` when either
+/// isn't in the language's vocabulary. Throws with code `UnsupportedLanguage`
+/// when the system has no word embedding for the language.
+#[napi]
+pub fn word_distance(
+    env: Env,
+    a: String,
+    b: String,
+    options: Option<WordEmbeddingOptions>,
+) -> napi::Result<Option<f64>> {
+    let (c_words, c_language, language) = word_c_strings(&[&a, &b], options)?;
+    let distance = unsafe {
+        apple_ai_word_distance(
+            c_words[0].as_ptr(),
+            c_words[1].as_ptr(),
+            c_language.as_ptr(),
+        )
+    };
+    // -1 is a missing embedding, -2 a word outside the vocabulary
+    if distance == -1.0 {
+        Err(no_word_embedding(env, &language))
+    } else {
+        Ok((distance >= 0.0).then_some(distance))
+    }
+}
+
+/// Vector of `word` in the language's word embedding, or `null` when the
+/// word isn't in its vocabulary. Throws with code `UnsupportedLanguage` when
+/// the system has no word embedding for the language.
+#[napi(ts_return_type = "Float32Array | null")]
+pub fn word_vector(
+    env: Env,
+    word: String,
+    options: Option<WordEmbeddingOptions>,
+) -> napi::Result<Option<Float32Array>> {
+    let (c_words, c_language, language) = word_c_strings(&[&word], options)?;
+    let mut vector = vec![0.0; MAX_DIMENSION];
+    let dimension = unsafe {
+        apple_ai_word_vector(
+            c_words[0].as_ptr(),
+            c_language.as_ptr(),
+            vector.as_mut_ptr(),
+            vector.len(),
+        )
+    };
+    match dimension {
+        ..=-1 => Err(no_word_embedding(env, &language)),
+        0 => Ok(None),
+        n if n as usize > MAX_DIMENSION => Err(napi::Error::from_reason(format!(
+            "Embedding dimension {n} is unsupported"
+        ))),
+        n => {
+            vector.truncate(n as usize);
+            Ok(Some(Float32Array::new(vector)))
+        }
+    }
+}
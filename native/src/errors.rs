@@ -0,0 +1,185 @@
+//! Error codes: the stable `code` and `retryable` every rejection and
+//! stream `error` event carries, and how Swift's errors map onto them.
+
+use napi::{Env, Status};
+
+/// Stable `code` of every error a promise rejects with and of every stream
+/// `error` event. Swift maps the framework's error types to these codes and
+/// reports them through the stream's error callback, or as a `[Code] ` prefix
+/// of the error message for non-streaming requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Invalid options, messages, schema or tool definitions; also the
+    /// `code` of errors thrown synchronously for these
+    InvalidArg,
+    /// Apple Intelligence is unsupported, disabled, or the model isn't ready
+    ModelUnavailable,
+    /// The model assets aren't on the device
+    AssetsUnavailable,
+    /// The safety guardrails blocked the prompt or the output
+    GuardrailViolation,
+    /// The model declined to produce the requested structured output
+    Refusal,
+    ContextWindowExceeded,
+    UnsupportedLanguage,
+    /// Messages contain images but the model can't take them
+    MultimodalUnsupported,
+    /// A schema constraint the framework can't enforce during generation
+    UnsupportedGuide,
+    /// Structured output couldn't be decoded
+    DecodingFailure,
+    /// The system throttled the request, e.g. while the app is in the background
+    RateLimited,
+    /// The session is already answering another request
+    ConcurrentRequests,
+    Cancelled,
+    Timeout,
+    /// A tool call failed in a way the model couldn't recover from
+    ToolFailure,
+    /// Structured output didn't match the schema after all retries
+    SchemaValidation,
+    AdapterNotFound,
+    InvalidAdapter,
+    /// The adapter was trained for a different version of the system model
+    AdapterIncompatible,
+    /// Image generation isn't supported on this device or system, or is
+    /// turned off
+    ImageGenerationUnavailable,
+    /// The user or system hasn't allowed speech recognition or the microphone
+    PermissionDenied,
+    /// Any other failure
+    GenerationFailed,
+}
+
+impl ErrorCode {
+    const ALL: [Self; 22] = [
+        Self::InvalidArg,
+        Self::ModelUnavailable,
+        Self::AssetsUnavailable,
+        Self::GuardrailViolation,
+        Self::Refusal,
+        Self::ContextWindowExceeded,
+        Self::UnsupportedLanguage,
+        Self::MultimodalUnsupported,
+        Self::UnsupportedGuide,
+        Self::DecodingFailure,
+        Self::RateLimited,
+        Self::ConcurrentRequests,
+        Self::Cancelled,
+        Self::Timeout,
+        Self::ToolFailure,
+        Self::SchemaValidation,
+        Self::AdapterNotFound,
+        Self::InvalidAdapter,
+        Self::AdapterIncompatible,
+        Self::ImageGenerationUnavailable,
+        Self::PermissionDenied,
+        Self::GenerationFailed,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::InvalidArg => "InvalidArg",
+            Self::ModelUnavailable => "ModelUnavailable",
+            Self::AssetsUnavailable => "AssetsUnavailable",
+            Self::GuardrailViolation => "GuardrailViolation",
+            Self::Refusal => "Refusal",
+            Self::ContextWindowExceeded => "ContextWindowExceeded",
+            Self::UnsupportedLanguage => "UnsupportedLanguage",
+            Self::MultimodalUnsupported => "MultimodalUnsupported",
+            Self::UnsupportedGuide => "UnsupportedGuide",
+            Self::DecodingFailure => "DecodingFailure",
+            Self::RateLimited => "RateLimited",
+            Self::ConcurrentRequests => "ConcurrentRequests",
+            Self::Cancelled => "Cancelled",
+            Self::Timeout => "Timeout",
+            Self::ToolFailure => "ToolFailure",
+            Self::SchemaValidation => "SchemaValidation",
+            Self::AdapterNotFound => "AdapterNotFound",
+            Self::InvalidAdapter => "InvalidAdapter",
+            Self::AdapterIncompatible => "AdapterIncompatible",
+            Self::ImageGenerationUnavailable => "ImageGenerationUnavailable",
+            Self::PermissionDenied => "PermissionDenied",
+            Self::GenerationFailed => "GenerationFailed",
+        }
+    }
+
+    pub fn parse(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.as_str() == code)
+    }
+
+    /// Whether the same request may succeed when retried later, because the
+    /// cause is transient rather than the request itself.
+    pub fn retryable(self) -> bool {
+        matches!(
+            self,
+            Self::AssetsUnavailable | Self::RateLimited | Self::ConcurrentRequests | Self::Timeout
+        )
+    }
+
+    /// Code of an error raised in Rust without a typed cause.
+    pub fn of(error: &napi::Error) -> Self {
+        match error.status {
+            Status::InvalidArg => Self::InvalidArg,
+            Status::Cancelled => Self::Cancelled,
+            _ => Self::GenerationFailed,
+        }
+    }
+}
+
+/// Split an error message from Swift into its code and the message text.
+pub fn native_error(message: &str) -> (ErrorCode, String) {
+    message
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("] "))
+        .and_then(|(code, text)| Some((ErrorCode::parse(code)?, text.to_string())))
+        .unwrap_or_else(|| (ErrorCode::GenerationFailed, message.to_string()))
+}
+
+/// A JS error carrying `code` and `retryable`, for failures `napi::Status`
+/// has no variant for.
+pub fn coded_error(env: Env, code: ErrorCode, message: String) -> napi::Error {
+    let build = || -> napi::Result<napi::Error> {
+        let mut error = env.create_error(napi::Error::from_reason(message.clone()))?;
+        error.set_named_property("code", env.create_string(code.as_str())?)?;
+        error.set_named_property("retryable", env.get_boolean(code.retryable())?)?;
+        Ok(napi::Error::from(error.into_unknown()))
+    };
+    build().unwrap_or_else(|e| e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn native_error_strips_every_code_swift_reports() {
+        // Every code `errorCode` in apple-ai.swift returns
+        let codes = [
+            "Cancelled",
+            "ModelUnavailable",
+            "InvalidArg",
+            "MultimodalUnsupported",
+            "AdapterNotFound",
+            "ContextWindowExceeded",
+            "AssetsUnavailable",
+            "GuardrailViolation",
+            "UnsupportedGuide",
+            "UnsupportedLanguage",
+            "DecodingFailure",
+            "RateLimited",
+            "ConcurrentRequests",
+            "Refusal",
+            "ToolFailure",
+            "GenerationFailed",
+        ];
+        for code in codes {
+            let (parsed, message) = native_error(&format!("[{code}] went wrong"));
+            assert_eq!(parsed.as_str(), code);
+            assert_eq!(message, "went wrong");
+        }
+        let (parsed, message) = native_error("[Unknown] went wrong");
+        assert_eq!(parsed, ErrorCode::GenerationFailed);
+        assert_eq!(message, "[Unknown] went wrong");
+    }
+}
@@ -0,0 +1,224 @@
+//! Declarations of the Swift library's C interface, shared by the addon and
+//! the `apple-ai` command line tool.
+
+use libc::{c_char, c_double, c_int};
+use std::ffi::CStr;
+
+#[link(name = "appleai")]
+extern "C" {
+    pub fn apple_ai_init() -> bool;
+    pub fn apple_ai_check_availability() -> c_int;
+    pub fn apple_ai_get_availability_reason() -> *mut c_char;
+
+    pub fn apple_ai_get_supported_languages_count() -> c_int;
+    pub fn apple_ai_get_supported_language(index: c_int) -> *mut c_char;
+    pub fn apple_ai_supports_locale(identifier: *const c_char) -> bool;
+    pub fn apple_ai_identify_language(
+        text: *const c_char,
+        options_json: *const c_char,
+    ) -> *mut c_char;
+    pub fn apple_ai_sentence_embedding(
+        text: *const c_char,
+        language: *const c_char,
+        out: *mut f32,
+        capacity: usize,
+    ) -> isize;
+    pub fn apple_ai_contextual_embedding(
+        text: *const c_char,
+        language: *const c_char,
+        out: *mut f32,
+        capacity: usize,
+    ) -> isize;
+    pub fn apple_ai_word_vector(
+        word: *const c_char,
+        language: *const c_char,
+        out: *mut f32,
+        capacity: usize,
+    ) -> isize;
+    pub fn apple_ai_word_distance(
+        first: *const c_char,
+        second: *const c_char,
+        language: *const c_char,
+    ) -> f64;
+    pub fn apple_ai_token_ranges(
+        text: *const c_char,
+        unit: *const c_char,
+        language: *const c_char,
+    ) -> *mut c_char;
+    pub fn apple_ai_sentiment(text: *const c_char, options_json: *const c_char) -> *mut c_char;
+    pub fn apple_ai_named_entities(text: *const c_char, options_json: *const c_char)
+        -> *mut c_char;
+    pub fn apple_ai_tag_text(text: *const c_char, options_json: *const c_char) -> *mut c_char;
+    pub fn apple_ai_create_gazetteer(
+        name: *const c_char,
+        entries_json: *const c_char,
+        language: *const c_char,
+    ) -> *mut c_char;
+    pub fn apple_ai_remove_gazetteer(name: *const c_char) -> bool;
+    pub fn apple_ai_detect_data(text: *const c_char, types_json: *const c_char) -> *mut c_char;
+    pub fn apple_ai_check_text(text: *const c_char, options_json: *const c_char) -> *mut c_char;
+    pub fn apple_ai_extract_text(path: *const c_char) -> *mut c_char;
+    // Vision; images are `data` and `length`, or the file at `path` when `data` is null
+    pub fn apple_ai_recognize_text(
+        data: *const u8,
+        length: usize,
+        path: *const c_char,
+        options_json: *const c_char,
+    ) -> *mut c_char;
+    pub fn apple_ai_classify_image(
+        data: *const u8,
+        length: usize,
+        path: *const c_char,
+        options_json: *const c_char,
+    ) -> *mut c_char;
+    pub fn apple_ai_analyze_saliency(
+        data: *const u8,
+        length: usize,
+        path: *const c_char,
+        options_json: *const c_char,
+    ) -> *mut c_char;
+    // ImageCreator; PNGs are returned in malloc'd buffers for the caller to free
+    pub fn apple_ai_image_generation_status() -> *mut c_char;
+    pub fn apple_ai_generate_image(
+        options_json: *const c_char,
+        images: *mut *mut u8,
+        lengths: *mut usize,
+        capacity: usize,
+    ) -> *mut c_char;
+    // Speech
+    pub fn apple_ai_transcribe_file(
+        path: *const c_char,
+        options_json: *const c_char,
+    ) -> *mut c_char;
+    // Microphone transcription; `on_event` gets each event as JSON to free
+    pub fn apple_ai_start_transcription(
+        options_json: *const c_char,
+        on_event: extern "C" fn(*mut c_char),
+    ) -> *mut c_char;
+    pub fn apple_ai_stop_transcription() -> bool;
+    // Sound classification; microphone events go to `on_event` as JSON to free
+    pub fn apple_ai_classify_sound_file(
+        path: *const c_char,
+        options_json: *const c_char,
+    ) -> *mut c_char;
+    pub fn apple_ai_start_sound_classification(
+        options_json: *const c_char,
+        on_event: extern "C" fn(*mut c_char),
+    ) -> *mut c_char;
+    pub fn apple_ai_stop_sound_classification() -> bool;
+    // Speech synthesis; audio is returned in a malloc'd buffer for the caller to free
+    pub fn apple_ai_list_voices(options_json: *const c_char) -> *mut c_char;
+    pub fn apple_ai_synthesize_speech(
+        text: *const c_char,
+        options_json: *const c_char,
+        audio: *mut *mut u8,
+        length: *mut usize,
+    ) -> *mut c_char;
+    // Speech streaming; `on_event` gets `context`, the event JSON to free, and
+    // the audio of `audio` events, valid for the call
+    pub fn apple_ai_stream_speech(
+        id: u64,
+        text: *const c_char,
+        options_json: *const c_char,
+        context: *mut std::ffi::c_void,
+        on_event: extern "C" fn(*mut std::ffi::c_void, *mut c_char, *const u8, usize),
+    ) -> *mut c_char;
+    pub fn apple_ai_stop_speech(id: u64) -> bool;
+    // Translation
+    pub fn apple_ai_translation_languages() -> *mut c_char;
+    pub fn apple_ai_translation_status(from: *const c_char, to: *const c_char) -> *mut c_char;
+    pub fn apple_ai_open_translation_settings() -> bool;
+    pub fn apple_ai_translate(options_json: *const c_char) -> *mut c_char;
+    pub fn apple_ai_word_neighbors(
+        word: *const c_char,
+        language: *const c_char,
+        count: usize,
+    ) -> *mut c_char;
+
+    // Tool callback registration and tool-based generation. The callback must
+    // return promptly; the result is delivered later for the same call id.
+    pub fn apple_ai_register_tool_callback(
+        cb: Option<extern "C" fn(u64, u64, u64, *const c_char, *const c_char)>,
+    );
+    pub fn apple_ai_tool_result_callback(call_id: u64, result_json: *const c_char);
+    pub fn apple_ai_tool_error_callback(call_id: u64, message: *const c_char);
+
+    // Unified generation function
+    pub fn apple_ai_generate_unified(
+        request_id: u64,
+        messages_json: *const c_char,
+        tools_json: *const c_char,    // nullable
+        schema_json: *const c_char,   // nullable
+        prefix: *const c_char,        // nullable, assistant prefill
+        image_data: *const *const u8, // images referenced from messages by index
+        image_lens: *const usize,
+        image_count: usize,
+        temperature: c_double,
+        max_tokens: c_int,
+        top_p: c_double, // 0 = unset
+        top_k: c_int,    // 0 = unset
+        seed: i64,       // -1 = unset
+        greedy: bool,
+        stream: bool,
+        stop_after_tool_calls: bool, // new parameter
+        adapter_id: u64,             // 0 = system model
+        use_case: c_int,             // 0 = general, 1 = content tagging
+        guardrails: c_int,           // see `GuardrailSetting`
+        on_chunk: Option<extern "C" fn(u64, *const c_char)>, // nullable; (request id, chunk)
+        // nullable; (request id, JSON snapshot) for structured output, valid for the call
+        on_object: Option<extern "C" fn(u64, *const c_char)>,
+        // nullable; (request id, code, message), called instead of ending the stream
+        on_error: Option<extern "C" fn(u64, *const c_char, *const c_char)>,
+    ) -> *mut c_char;
+
+    // Cancel an in-flight generation started with the given request id
+    pub fn apple_ai_cancel_generation(request_id: u64);
+
+    // Token usage recorded for a finished request (JSON, nullable); removes the record
+    pub fn apple_ai_take_usage(request_id: u64) -> *mut c_char;
+
+    // Whether the system model accepts image input
+    pub fn apple_ai_supports_image_input() -> bool;
+
+    // Load the model ahead of a request; false if the model is unavailable
+    pub fn apple_ai_prewarm(
+        instructions: *const c_char,
+        prompt_prefix: *const c_char,
+        adapter_id: u64,   // 0 = system model
+        use_case: c_int,   // 0 = general, 1 = content tagging
+        guardrails: c_int, // see `GuardrailSetting`
+    ) -> bool;
+
+    // Drop resources kept between requests; returns the number of requests in flight
+    pub fn apple_ai_release() -> c_int;
+
+    // Model provenance as JSON
+    pub fn apple_ai_model_info() -> *mut c_char;
+
+    // Static model limits as JSON
+    pub fn apple_ai_model_limits() -> *mut c_char;
+
+    // Load an .fmadapter package; JSON `{id, name}` or `{code, error}`
+    pub fn apple_ai_load_adapter(path: *const c_char) -> *mut c_char;
+
+    // Forget a loaded adapter; false if the handle wasn't loaded
+    pub fn apple_ai_unload_adapter(adapter_id: u64) -> bool;
+
+    // Token count of a prompt text as JSON `{tokens, estimated}`, or an `Error: …` string
+    pub fn apple_ai_count_tokens(text: *const c_char) -> *mut c_char;
+}
+
+/// Copy a string the Swift library allocated and free it. `ptr` must be
+/// null or a string from one of the functions above, not yet freed.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[inline(always)]
+pub fn take_c_string(ptr: *mut c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    unsafe {
+        let s = CStr::from_ptr(ptr).to_string_lossy().into_owned();
+        libc::free(ptr as *mut _);
+        s
+    }
+}
@@ -0,0 +1,495 @@
+//! Preprocessing of `schemaJson` before Swift turns it into a generation schema.
+//!
+//! The Swift schema builder only understands self-contained schemas, so local
+//! `$ref`s are inlined here, recursive definitions are unrolled to a fixed
+//! depth, `allOf` is merged, multi-type `type` arrays become `anyOf` unions,
+//! and numeric `enum`/`const` values become ranges. Constructs that can't be
+//! expressed during decoding are rejected with the path where they occur.
+
+use regex::Regex;
+use serde_json::{json, Map, Value};
+
+/// How many times one `$ref` may be expanded inside itself. Deeper levels are
+/// cut off: optional properties are dropped and arrays are left empty.
+const MAX_RECURSION_DEPTH: usize = 3;
+
+/// Keywords that constrain the output in ways generation can't follow.
+const UNSUPPORTED_KEYWORDS: [&str; 12] = [
+    "not",
+    "if",
+    "then",
+    "else",
+    "patternProperties",
+    "dependentSchemas",
+    "dependencies",
+    "prefixItems",
+    "unevaluatedProperties",
+    "unevaluatedItems",
+    "$dynamicRef",
+    "$recursiveRef",
+];
+
+/// Keywords with no meaning once refs are inlined.
+const DROPPED_KEYWORDS: [&str; 4] = ["definitions", "$defs", "$schema", "$id"];
+
+/// A self-contained equivalent of `schema`, or why it can't be generated.
+pub fn prepare(schema: &Value) -> Result<Value, String> {
+    resolve(schema, schema, "#", &mut Vec::new())?
+        .ok_or_else(|| "the root schema refers to itself without end".to_string())
+}
+
+/// Why a tool's parameter schema can't be generated. Tool schemas go to Swift
+/// as they are, so only the numeric bounds are checked.
+pub fn check_parameters(schema: &Value) -> Result<(), String> {
+    fn walk(value: &Value, path: &str) -> Result<(), String> {
+        match value {
+            Value::Object(map) => {
+                check_bounds(map, path)?;
+                map.iter()
+                    .try_for_each(|(key, value)| walk(value, &format!("{path}/{key}")))
+            }
+            Value::Array(values) => values
+                .iter()
+                .enumerate()
+                .try_for_each(|(i, value)| walk(value, &format!("{path}/{i}"))),
+            _ => Ok(()),
+        }
+    }
+    walk(schema, "#")
+}
+
+/// Reject numeric bounds that leave nothing to generate, which the Swift
+/// builder would otherwise drop. Integer bounds count whole numbers only.
+fn check_bounds(map: &Map<String, Value>, path: &str) -> Result<(), String> {
+    let bound = |key: &str| map.get(key).and_then(Value::as_f64);
+    let integer = map.get("type").and_then(Value::as_str) == Some("integer");
+    let lower = match (bound("minimum"), bound("exclusiveMinimum")) {
+        (Some(min), Some(excl)) if excl >= min => Some((excl, true)),
+        (Some(min), _) => Some((min, false)),
+        (None, excl) => excl.map(|excl| (excl, true)),
+    };
+    let upper = match (bound("maximum"), bound("exclusiveMaximum")) {
+        (Some(max), Some(excl)) if excl <= max => Some((excl, true)),
+        (Some(max), _) => Some((max, false)),
+        (None, excl) => excl.map(|excl| (excl, true)),
+    };
+    let (Some((lower, lower_excl)), Some((upper, upper_excl))) = (lower, upper) else {
+        return Ok(());
+    };
+    let empty = if integer {
+        let lower = if lower_excl {
+            lower.floor() + 1.0
+        } else {
+            lower.ceil()
+        };
+        let upper = if upper_excl {
+            upper.ceil() - 1.0
+        } else {
+            upper.floor()
+        };
+        lower > upper
+    } else {
+        lower > upper || (lower == upper && (lower_excl || upper_excl))
+    };
+    if empty {
+        let kind = if integer { "integer" } else { "number" };
+        return Err(format!("the bounds at {path} admit no {kind}"));
+    }
+    Ok(())
+}
+
+/// `None` means the schema was cut off at the recursion limit.
+fn resolve(
+    schema: &Value,
+    root: &Value,
+    path: &str,
+    refs: &mut Vec<String>,
+) -> Result<Option<Value>, String> {
+    let map = match schema {
+        Value::Object(map) => map,
+        Value::Bool(true) => return Ok(Some(json!({}))),
+        _ => return Err(format!("schema at {path} must be an object")),
+    };
+
+    if let Some(reference) = map.get("$ref") {
+        return resolve_ref(reference, map, root, path, refs);
+    }
+    if let Some(keyword) = UNSUPPORTED_KEYWORDS.iter().find(|k| map.contains_key(**k)) {
+        return Err(format!("'{keyword}' at {path} is not supported"));
+    }
+    if let Some(pattern) = map.get("pattern") {
+        let compiled = pattern
+            .as_str()
+            .ok_or_else(|| "must be a string".to_string())
+            .and_then(|p| Regex::new(p).map_err(|e| e.to_string()));
+        if let Err(e) = compiled {
+            return Err(format!("invalid 'pattern' at {path}: {e}"));
+        }
+    }
+    check_bounds(map, path)?;
+    if map.get("items").is_some_and(Value::is_array) {
+        return Err(format!(
+            "tuple 'items' at {path} is not supported; use a single item schema"
+        ));
+    }
+
+    let required: Vec<&str> = map
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|r| r.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let mut out = Map::new();
+    for (key, value) in map {
+        if DROPPED_KEYWORDS.contains(&key.as_str()) {
+            continue;
+        }
+        let child = format!("{path}/{key}");
+        match key.as_str() {
+            "properties" => {
+                let Some(properties) = value.as_object() else {
+                    return Err(format!("'properties' at {path} must be an object"));
+                };
+                let mut resolved = Map::new();
+                for (name, sub) in properties {
+                    match resolve(sub, root, &format!("{child}/{name}"), refs)? {
+                        Some(sub) => {
+                            resolved.insert(name.clone(), sub);
+                        }
+                        None if required.contains(&name.as_str()) => {
+                            return Err(format!(
+                                "required property '{name}' at {path} recurses without end; make it optional or an array"
+                            ));
+                        }
+                        None => {}
+                    }
+                }
+                out.insert(key.clone(), Value::Object(resolved));
+            }
+            "items" => match resolve(value, root, &child, refs)? {
+                Some(items) => {
+                    out.insert(key.clone(), items);
+                }
+                None => {
+                    out.insert(key.clone(), json!({ "type": "string" }));
+                    out.insert("maxItems".to_string(), json!(0));
+                }
+            },
+            "additionalProperties" if value.is_object() => {
+                let resolved = resolve(value, root, &child, refs)?;
+                out.insert(key.clone(), resolved.unwrap_or(Value::Bool(false)));
+            }
+            "anyOf" | "oneOf" | "allOf" => {
+                let Some(options) = value.as_array() else {
+                    return Err(format!("'{key}' at {path} must be an array"));
+                };
+                let mut resolved = Vec::new();
+                for (i, sub) in options.iter().enumerate() {
+                    match resolve(sub, root, &format!("{child}/{i}"), refs)? {
+                        Some(sub) => resolved.push(sub),
+                        None if key == "allOf" => {
+                            return Err(format!("'allOf' at {path} recurses without end"));
+                        }
+                        None => {}
+                    }
+                }
+                if resolved.is_empty() {
+                    return Err(format!("'{key}' at {path} has no usable alternatives"));
+                }
+                out.insert(key.clone(), Value::Array(resolved));
+            }
+            _ => {
+                out.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    if let Some(Value::Array(parts)) = out.remove("allOf") {
+        for part in parts {
+            merge(&mut out, part);
+        }
+    }
+    Ok(Some(type_union(numeric_literals(out))))
+}
+
+/// Numeric `enum`s and `const`s as single-value ranges (a union of them for
+/// several values), which Swift enforces with range guides while decoding.
+/// String literals are generated natively and left alone.
+fn numeric_literals(mut schema: Map<String, Value>) -> Map<String, Value> {
+    let values = match (schema.get("enum"), schema.get("const")) {
+        (Some(Value::Array(values)), _) => values.clone(),
+        (None, Some(value)) => vec![value.clone()],
+        _ => return schema,
+    };
+    if values.is_empty() || !values.iter().all(Value::is_number) || schema.contains_key("anyOf") {
+        return schema;
+    }
+    schema.remove("enum");
+    schema.remove("const");
+    let kind = if values.iter().all(|v| v.is_i64() || v.is_u64()) {
+        "integer"
+    } else {
+        "number"
+    };
+    let mut ranges = values
+        .into_iter()
+        .map(|v| json!({ "type": kind, "minimum": v, "maximum": v }));
+    if ranges.len() == 1 {
+        if let Some(Value::Object(range)) = ranges.next() {
+            schema.extend(range);
+        }
+    } else {
+        schema.remove("type");
+        schema.insert("anyOf".to_string(), Value::Array(ranges.collect()));
+    }
+    schema
+}
+
+/// `{"type": ["string", "integer"]}` as an `anyOf` of single-type schemas,
+/// which Swift generates as alternatives. `null` is dropped: generation can
+/// only leave optional properties out, not produce an explicit null.
+fn type_union(mut schema: Map<String, Value>) -> Value {
+    let Some(Value::Array(types)) = schema.get("type") else {
+        return Value::Object(schema);
+    };
+    let types: Vec<Value> = types
+        .iter()
+        .filter(|t| t.as_str() != Some("null"))
+        .cloned()
+        .collect();
+    if types.len() <= 1 {
+        match types.into_iter().next() {
+            Some(single) => schema.insert("type".to_string(), single),
+            None => schema.remove("type"),
+        };
+        return Value::Object(schema);
+    }
+
+    let mut union = Map::new();
+    for annotation in ["title", "description"] {
+        if let Some(value) = schema.remove(annotation) {
+            union.insert(annotation.to_string(), value);
+        }
+    }
+    let alternatives = types
+        .into_iter()
+        .map(|t| {
+            let mut alternative = schema.clone();
+            alternative.insert("type".to_string(), t);
+            Value::Object(alternative)
+        })
+        .collect();
+    union.insert("anyOf".to_string(), Value::Array(alternatives));
+    Value::Object(union)
+}
+
+fn resolve_ref(
+    reference: &Value,
+    siblings: &Map<String, Value>,
+    root: &Value,
+    path: &str,
+    refs: &mut Vec<String>,
+) -> Result<Option<Value>, String> {
+    let Some(reference) = reference.as_str() else {
+        return Err(format!("'$ref' at {path} must be a string"));
+    };
+    let Some(pointer) = reference.strip_prefix('#') else {
+        return Err(format!(
+            "only local $refs are supported, found '{reference}' at {path}"
+        ));
+    };
+    let Some(target) = root.pointer(pointer) else {
+        return Err(format!("'$ref' '{reference}' at {path} does not resolve"));
+    };
+    let depth = refs.iter().filter(|r| *r == reference).count();
+    if depth >= MAX_RECURSION_DEPTH {
+        return Ok(None);
+    }
+
+    refs.push(reference.to_string());
+    let resolved = resolve(target, root, reference, refs);
+    refs.pop();
+    let Some(Value::Object(mut resolved)) = resolved? else {
+        return Ok(None);
+    };
+
+    // Annotations next to the ref (e.g. `description`) apply to the target too
+    for (key, value) in siblings {
+        if key != "$ref" && !DROPPED_KEYWORDS.contains(&key.as_str()) {
+            resolved.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+    // Keep the definition's name; levels of a recursive one need distinct names
+    if let Some(name) = pointer.rsplit('/').next().filter(|n| !n.is_empty()) {
+        let title = match depth {
+            0 => name.to_string(),
+            _ => format!("{name}{}", depth + 1),
+        };
+        resolved.entry("title").or_insert(Value::String(title));
+    }
+    Ok(Some(Value::Object(resolved)))
+}
+
+/// Fold one `allOf` member into the schema: properties and `required` are
+/// combined, and other keywords already present win.
+fn merge(into: &mut Map<String, Value>, part: Value) {
+    let Value::Object(part) = part else {
+        return;
+    };
+    for (key, value) in part {
+        match (key.as_str(), into.get_mut(&key), value) {
+            ("properties", Some(Value::Object(existing)), Value::Object(more)) => {
+                for (name, sub) in more {
+                    existing.entry(name).or_insert(sub);
+                }
+            }
+            ("required", Some(Value::Array(existing)), Value::Array(more)) => {
+                for name in more {
+                    if !existing.contains(&name) {
+                        existing.push(name);
+                    }
+                }
+            }
+            (_, Some(_), _) => {}
+            (_, None, value) => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Most distinct values a repeated string field may have to become an `enum`.
+const MAX_INFERRED_ENUM: usize = 8;
+
+/// A JSON Schema describing `sample`: every property seen is typed, nested
+/// objects and arrays are described recursively, and properties present in
+/// every array element are required. String fields that repeat a few values
+/// across array elements become an `enum` of those values.
+pub fn from_example(sample: &Value) -> Value {
+    infer(&[sample])
+}
+
+/// Schema for all values seen at one position of the sample.
+fn infer(values: &[&Value]) -> Value {
+    let present: Vec<&Value> = values.iter().copied().filter(|v| !v.is_null()).collect();
+    let mut kinds: Vec<&str> = Vec::new();
+    for value in &present {
+        let kind = match value {
+            Value::Object(_) => "object",
+            Value::Array(_) => "array",
+            Value::String(_) => "string",
+            Value::Bool(_) => "boolean",
+            Value::Number(n) if n.is_f64() => "number",
+            Value::Number(_) => "integer",
+            Value::Null => continue,
+        };
+        if !kinds.contains(&kind) {
+            kinds.push(kind);
+        }
+    }
+    // Any fractional value makes the whole position a number
+    if kinds.contains(&"number") {
+        kinds.retain(|k| *k != "integer");
+    }
+
+    match kinds.as_slice() {
+        // Only nulls seen: nothing to go on, so ask for text
+        [] => json!({ "type": "string" }),
+        [kind] => infer_kind(kind, &present),
+        _ => json!({
+            "anyOf": kinds
+                .iter()
+                .map(|kind| infer_kind(kind, &present))
+                .collect::<Vec<_>>()
+        }),
+    }
+}
+
+fn infer_kind(kind: &str, values: &[&Value]) -> Value {
+    match kind {
+        "object" => {
+            let objects: Vec<&Map<String, Value>> =
+                values.iter().filter_map(|v| v.as_object()).collect();
+            let mut properties = Map::new();
+            let mut required = Vec::new();
+            for key in objects.iter().flat_map(|o| o.keys()) {
+                if properties.contains_key(key) {
+                    continue;
+                }
+                let seen: Vec<&Value> = objects.iter().filter_map(|o| o.get(key)).collect();
+                if seen.len() == objects.len() && seen.iter().all(|v| !v.is_null()) {
+                    required.push(key.clone());
+                }
+                properties.insert(key.clone(), infer(&seen));
+            }
+            json!({ "type": "object", "properties": properties, "required": required })
+        }
+        "array" => {
+            let items: Vec<&Value> = values
+                .iter()
+                .filter_map(|v| v.as_array())
+                .flatten()
+                .collect();
+            json!({ "type": "array", "items": infer(&items) })
+        }
+        "string" => {
+            let strings: Vec<&str> = values.iter().filter_map(|v| v.as_str()).collect();
+            let mut distinct: Vec<&str> = Vec::new();
+            for s in &strings {
+                if !distinct.contains(s) {
+                    distinct.push(s);
+                }
+            }
+            // Repeats suggest a fixed set of values, e.g. a status field
+            if (2..=MAX_INFERRED_ENUM).contains(&distinct.len()) && strings.len() > distinct.len() {
+                json!({ "type": "string", "enum": distinct })
+            } else {
+                json!({ "type": "string" })
+            }
+        }
+        kind => json!({ "type": kind }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_that_admit_no_value_are_rejected() {
+        let inverted = json!({ "type": "number", "minimum": 5, "maximum": 1 });
+        assert!(prepare(&inverted).unwrap_err().contains("admit no number"));
+        let touching = json!({ "type": "number", "minimum": 1, "exclusiveMaximum": 1 });
+        assert!(prepare(&touching).is_err());
+        let between = json!({ "type": "integer", "exclusiveMinimum": 1, "exclusiveMaximum": 2 });
+        assert!(prepare(&between).unwrap_err().contains("admit no integer"));
+        let fractional = json!({ "type": "integer", "minimum": 1.2, "maximum": 1.8 });
+        assert!(prepare(&fractional).is_err());
+    }
+
+    #[test]
+    fn bounds_that_admit_a_value_are_kept() {
+        let point = json!({ "type": "number", "minimum": 1, "maximum": 1 });
+        assert_eq!(prepare(&point).unwrap(), point);
+        let huge = json!({ "type": "integer", "minimum": 0, "maximum": 1e20 });
+        assert!(prepare(&huge).is_ok());
+        let one = json!({ "type": "integer", "exclusiveMinimum": 1, "maximum": 2 });
+        assert!(prepare(&one).is_ok());
+    }
+
+    #[test]
+    fn tool_parameters_are_checked_at_every_depth() {
+        let parameters = json!({
+            "type": "object",
+            "properties": {
+                "limit": { "type": "integer", "minimum": 10, "maximum": 0 },
+            },
+        });
+        let error = check_parameters(&parameters).unwrap_err();
+        assert!(error.contains("#/properties/limit"), "{error}");
+        let fine = json!({
+            "type": "object",
+            "properties": { "minimum": { "type": "integer", "minimum": 0 } },
+        });
+        assert!(check_parameters(&fine).is_ok());
+    }
+}
@@ -0,0 +1,323 @@
+//! gRPC service for the model, generated from `proto/apple_ai.proto` and
+//! only built with the `grpc` feature. Generation takes the same messages
+//! and options as the socket server, and streams carry the same events.
+//! Nothing runs until `startGrpcServer` is called.
+
+// Handlers must fail with `tonic::Status`, so the helpers do too
+#![allow(clippy::result_large_err)]
+
+use crate::{
+    apple_ai_cancel_generation, check_availability, run_stream, ChatMessage, ErrorCode,
+    GenerateUnifiedTask, GenerationOptions, GenerationRequest, GenerationResult, SamplingOptions,
+    StreamSink, TokenUsage, ToolCall, ToolCallFunction,
+};
+use napi::bindgen_prelude::Either;
+use napi::{Env, JsObject};
+use napi_derive::napi;
+use proto::apple_ai_server::{AppleAi, AppleAiServer};
+use serde_json::{json, Value};
+use std::net::TcpListener;
+use std::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("apple_ai.v1");
+}
+
+const DEFAULT_PORT: u32 = 50051;
+const DEFAULT_HOST: &str = "127.0.0.1";
+/// Stream events buffered for a client that reads slower than the model writes
+const STREAM_BUFFER: usize = 64;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct GrpcServerOptions {
+    /// Port to listen on (default 50051); 0 picks a free one
+    pub port: Option<u32>,
+    /// Address to bind (default `127.0.0.1`, local connections only)
+    pub host: Option<String>,
+}
+
+/// A failure as a gRPC status, with the library's error code in the
+/// `apple-ai-error-code` metadata entry.
+fn status(code: ErrorCode, message: String) -> Status {
+    let grpc_code = match code {
+        ErrorCode::InvalidArg
+        | ErrorCode::UnsupportedLanguage
+        | ErrorCode::MultimodalUnsupported
+        | ErrorCode::UnsupportedGuide
+        | ErrorCode::ContextWindowExceeded => Code::InvalidArgument,
+        ErrorCode::ModelUnavailable | ErrorCode::AssetsUnavailable => Code::Unavailable,
+        ErrorCode::RateLimited | ErrorCode::ConcurrentRequests => Code::ResourceExhausted,
+        ErrorCode::Cancelled => Code::Cancelled,
+        ErrorCode::Timeout => Code::DeadlineExceeded,
+        ErrorCode::AdapterNotFound => Code::NotFound,
+        _ => Code::Internal,
+    };
+    let mut status = Status::new(grpc_code, message);
+    status.metadata_mut().insert(
+        "apple-ai-error-code",
+        MetadataValue::from_static(code.as_str()),
+    );
+    status
+}
+
+fn invalid(message: impl Into<String>) -> Status {
+    status(ErrorCode::InvalidArg, message.into())
+}
+
+fn non_empty(text: String) -> Option<String> {
+    Some(text).filter(|text| !text.is_empty())
+}
+
+fn message(index: usize, message: proto::Message) -> Result<ChatMessage, Status> {
+    if message.role.is_empty() {
+        return Err(invalid(format!("messages[{index}]: role is required")));
+    }
+    let tool_calls = message
+        .tool_calls
+        .into_iter()
+        .map(|call| ToolCall {
+            id: call.id,
+            kind: "function".to_string(),
+            function: ToolCallFunction {
+                name: call.name,
+                arguments: non_empty(call.arguments_json).unwrap_or_else(|| "{}".to_string()),
+            },
+        })
+        .collect::<Vec<_>>();
+    Ok(ChatMessage {
+        role: message.role,
+        content: Some(Either::A(message.content)),
+        name: None,
+        tool_call_id: non_empty(message.tool_call_id),
+        tool_calls: Some(tool_calls).filter(|calls| !calls.is_empty()),
+    })
+}
+
+/// Tool definitions in the shape `toolsJson` takes.
+fn tools_json(tools: Vec<proto::Tool>) -> Result<Option<String>, Status> {
+    if tools.is_empty() {
+        return Ok(None);
+    }
+    let definitions = tools
+        .into_iter()
+        .enumerate()
+        .map(|(i, tool)| {
+            if tool.name.is_empty() {
+                return Err(invalid(format!("tools[{i}]: name is required")));
+            }
+            let parameters = match non_empty(tool.parameters_json) {
+                Some(parameters) => serde_json::from_str::<Value>(&parameters).map_err(|e| {
+                    invalid(format!(
+                        "tools[{i}]: parameters_json is not valid JSON: {e}"
+                    ))
+                })?,
+                None => json!({ "type": "object", "properties": {} }),
+            };
+            Ok(json!({
+                "id": i + 1,
+                "name": tool.name,
+                "description": tool.description,
+                "parameters": parameters,
+            }))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Some(Value::Array(definitions).to_string()))
+}
+
+fn parse_generation(request: proto::GenerateRequest) -> Result<GenerationRequest, Status> {
+    let messages = request
+        .messages
+        .into_iter()
+        .enumerate()
+        .map(|(index, m)| message(index, m))
+        .collect::<Result<Vec<_>, _>>()?;
+    let options = GenerationOptions {
+        tools_json: tools_json(request.tools)?,
+        schema_json: non_empty(request.schema_json),
+        temperature: request.temperature,
+        max_tokens: request.max_tokens,
+        stop: Some(request.stop).filter(|stop| !stop.is_empty()),
+        sampling: Some(SamplingOptions {
+            top_p: request.top_p,
+            top_k: request.top_k,
+            seed: request.seed,
+            ..Default::default()
+        }),
+        timeout_ms: request.timeout_ms,
+        // The caller runs the tools and sends their output back
+        stop_after_tool_calls: Some(true),
+        ..Default::default()
+    };
+    GenerationRequest::from_messages(&messages, options)
+        .map_err(|e| status(ErrorCode::of(&e), e.reason))
+}
+
+fn usage(usage: Option<TokenUsage>) -> Option<proto::Usage> {
+    usage.map(|u| proto::Usage {
+        prompt_tokens: u.prompt_tokens,
+        completion_tokens: u.completion_tokens,
+        total_tokens: u.total_tokens,
+    })
+}
+
+fn object_json(object: Option<Value>) -> String {
+    object.map(|o| o.to_string()).unwrap_or_default()
+}
+
+fn response(result: GenerationResult) -> proto::GenerateResponse {
+    proto::GenerateResponse {
+        text: result.text,
+        object_json: object_json(result.object),
+        tool_calls: result
+            .tool_calls
+            .unwrap_or_default()
+            .into_iter()
+            .map(|call| proto::ToolCall {
+                id: call.id,
+                name: call.function.name,
+                arguments_json: call.function.arguments,
+            })
+            .collect(),
+        finish_reason: result.finish_reason,
+        usage: usage(result.usage),
+    }
+}
+
+fn stream_event(event: crate::StreamEvent) -> proto::StreamEvent {
+    proto::StreamEvent {
+        r#type: event.kind,
+        text: event.text.unwrap_or_default(),
+        object_json: object_json(event.object),
+        tool_call_id: event.tool_call_id.unwrap_or_default(),
+        tool_name: event.tool_name.unwrap_or_default(),
+        args_json: event.args.unwrap_or_default(),
+        args_delta: event.args_delta.unwrap_or_default(),
+        code: event.code.unwrap_or_default(),
+        message: event.message.unwrap_or_default(),
+        retryable: event.retryable.unwrap_or_default(),
+        finish_reason: event.finish_reason.unwrap_or_default(),
+        usage: usage(event.usage),
+    }
+}
+
+struct Service;
+
+#[tonic::async_trait]
+impl AppleAi for Service {
+    async fn generate(
+        &self,
+        request: Request<proto::GenerateRequest>,
+    ) -> Result<Response<proto::GenerateResponse>, Status> {
+        let generation = parse_generation(request.into_inner())?;
+        tokio::task::spawn_blocking(move || {
+            let mut task = GenerateUnifiedTask::new(generation);
+            match napi::Task::compute(&mut task) {
+                Ok(result) => Ok(Response::new(response(result))),
+                Err(error) => Err(status(task.failure_code(&error), error.reason)),
+            }
+        })
+        .await
+        .map_err(|e| Status::internal(format!("The generation panicked: {e}")))?
+    }
+
+    type GenerateStreamStream = ReceiverStream<Result<proto::StreamEvent, Status>>;
+
+    /// Forward the stream's events until it finishes. A client that goes
+    /// away cancels the generation.
+    async fn generate_stream(
+        &self,
+        request: Request<proto::GenerateRequest>,
+    ) -> Result<Response<Self::GenerateStreamStream>, Status> {
+        let generation = parse_generation(request.into_inner())?;
+        let (sender, events) = mpsc::channel();
+        let request_id = run_stream(generation, StreamSink::Channel(sender))
+            .map_err(|e| status(ErrorCode::of(&e), e.reason))?;
+        let (client, stream) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            for event in events {
+                let done = matches!(event.kind.as_str(), "finish" | "error");
+                if client.blocking_send(Ok(stream_event(event))).is_err() {
+                    unsafe { apple_ai_cancel_generation(request_id) };
+                    return;
+                }
+                if done {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+
+    async fn availability(
+        &self,
+        _request: Request<proto::AvailabilityRequest>,
+    ) -> Result<Response<proto::AvailabilityResponse>, Status> {
+        let availability = tokio::task::spawn_blocking(check_availability)
+            .await
+            .map_err(|e| Status::internal(format!("The availability check panicked: {e}")))?
+            .map_err(|e| status(ErrorCode::of(&e), e.reason))?;
+        Ok(Response::new(proto::AvailabilityResponse {
+            available: availability.available,
+            reason: availability.reason,
+        }))
+    }
+}
+
+/// Serve the `apple_ai.v1.AppleAI` gRPC service and return `{ address,
+/// port, stop }` once it is listening. `stop()` stops accepting calls and
+/// lets calls in progress finish.
+#[napi(ts_return_type = "{ address: string; port: number; stop: () => void }")]
+pub fn start_grpc_server(env: Env, options: Option<GrpcServerOptions>) -> napi::Result<JsObject> {
+    let options = options.unwrap_or_default();
+    let host = options.host.unwrap_or_else(|| DEFAULT_HOST.to_string());
+    let port = options.port.unwrap_or(DEFAULT_PORT);
+    let port = u16::try_from(port).map_err(|_| {
+        napi::Error::new(
+            napi::Status::InvalidArg,
+            format!("port {port} is out of range"),
+        )
+    })?;
+    let listener = TcpListener::bind((host.as_str(), port))
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .map_err(|e| napi::Error::from_reason(format!("Cannot listen on {host}:{port}: {e}")))?;
+    let address = listener
+        .local_addr()
+        .map_err(|e| napi::Error::from_reason(format!("Cannot read the server's address: {e}")))?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| napi::Error::from_reason(format!("Cannot start the gRPC runtime: {e}")))?;
+    crate::ensure_initialized();
+
+    let (stop_sender, stopped) = tokio::sync::oneshot::channel::<()>();
+    std::thread::spawn(move || {
+        runtime.block_on(async move {
+            let Ok(listener) = tokio::net::TcpListener::from_std(listener) else {
+                return;
+            };
+            let _ = tonic::transport::Server::builder()
+                .add_service(AppleAiServer::new(Service))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                    let _ = stopped.await;
+                })
+                .await;
+        });
+    });
+
+    let stop_sender = Mutex::new(Some(stop_sender));
+    let stop = env.create_function_from_closure("stop", move |ctx| {
+        if let Some(stop) = stop_sender.lock().unwrap().take() {
+            let _ = stop.send(());
+        }
+        ctx.env.get_undefined()
+    })?;
+    let mut handle = env.create_object()?;
+    handle.set_named_property("address", env.create_string(&address.to_string())?)?;
+    handle.set_named_property("port", env.create_uint32(address.port().into())?)?;
+    handle.set_named_property("stop", stop)?;
+    Ok(handle)
+}
@@ -0,0 +1,342 @@
+//! Hybrid search over a `VectorIndex`: the embedding ranking and the BM25
+//! keyword ranking of the same query, fused into one.
+
+use crate::embeddings::{embedding, EmbeddingModel};
+use crate::vector_index::{SharedEntries, VectorIndex, VectorSearchHit, VectorSearchOptions};
+use crate::{coded_error, ErrorCode};
+use napi::bindgen_prelude::{AsyncTask, Float32Array};
+use napi::{Env, Status};
+use napi_derive::napi;
+use serde_json::Value;
+use std::collections::HashMap;
+
+const DEFAULT_TOP_K: u32 = 10;
+const DEFAULT_RRF_K: f64 = 60.0;
+const DEFAULT_VECTOR_WEIGHT: f64 = 0.5;
+const MIN_CANDIDATES: u32 = 50;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct HybridSearchOptions {
+    /// Number of results (default 10)
+    pub top_k: Option<u32>,
+    /// Only entries whose metadata has each of these properties with an
+    /// equal value, as in `VectorIndex.search`
+    #[napi(ts_type = "Record<string, unknown>")]
+    pub filter: Option<Value>,
+    /// `rrf` (default) adds the reciprocal ranks from each search; `weighted`
+    /// adds the scores of each search, scaled to 0..1 over its candidates
+    #[napi(ts_type = "'rrf' | 'weighted'")]
+    pub fusion: Option<String>,
+    /// Share of the vector search in the fused score, from 0 (keywords only)
+    /// to 1 (vectors only); default 0.5
+    pub vector_weight: Option<f64>,
+    /// Constant of reciprocal rank fusion, damping the lead of top ranks
+    /// (default 60)
+    pub rrf_k: Option<f64>,
+    /// Results taken from each search before fusing (default: `topK` * 4, at
+    /// least 50)
+    pub candidates: Option<u32>,
+    /// Embedding of the query, when already computed; otherwise the query is
+    /// embedded with `language` and `model`
+    pub vector: Option<Float32Array>,
+    /// Language of the query for embedding, as the index was built with
+    pub language: Option<String>,
+    /// Embedding model the index was built with (default `sentence`)
+    #[napi(ts_type = "'sentence' | 'contextual'")]
+    pub model: Option<String>,
+}
+
+/// An entry found by `hybrid_search`.
+#[napi(object)]
+pub struct HybridSearchHit {
+    pub id: String,
+    /// Fused score; higher is better
+    pub score: f64,
+    /// Cosine similarity to the query, when the vector search found it
+    pub vector_score: Option<f64>,
+    /// BM25 score, when the keyword search found it
+    pub keyword_score: Option<f64>,
+    pub metadata: Option<Value>,
+}
+
+#[derive(Clone, Copy)]
+enum Fusion {
+    Rrf { k: f64 },
+    Weighted,
+}
+
+/// Each hit's score scaled to 0..1 over `hits`, the best being 1.
+fn normalized(hits: &[VectorSearchHit]) -> Vec<f64> {
+    let max = hits
+        .iter()
+        .map(|h| h.score)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let min = hits.iter().map(|h| h.score).fold(f64::INFINITY, f64::min);
+    hits.iter()
+        .map(|h| {
+            if max > min {
+                (h.score - min) / (max - min)
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+fn fuse(
+    mut vector_hits: Vec<VectorSearchHit>,
+    mut keyword_hits: Vec<VectorSearchHit>,
+    fusion: Fusion,
+    vector_weight: f64,
+    top_k: usize,
+) -> Vec<HybridSearchHit> {
+    // A search with no weight adds nothing to the ranking; leave its hits
+    // out rather than trailing them with a score of 0
+    if vector_weight == 0.0 {
+        vector_hits.clear();
+    } else if vector_weight == 1.0 {
+        keyword_hits.clear();
+    }
+    let contributions = |hits: &[VectorSearchHit], weight: f64| -> Vec<f64> {
+        match fusion {
+            Fusion::Rrf { k } => (0..hits.len())
+                .map(|rank| weight / (k + rank as f64 + 1.0))
+                .collect(),
+            Fusion::Weighted => normalized(hits).into_iter().map(|s| weight * s).collect(),
+        }
+    };
+    let vector_scores = contributions(&vector_hits, vector_weight);
+    let keyword_scores = contributions(&keyword_hits, 1.0 - vector_weight);
+    let mut fused: Vec<HybridSearchHit> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for (hit, contribution) in vector_hits.into_iter().zip(vector_scores) {
+        positions.insert(hit.id.clone(), fused.len());
+        fused.push(HybridSearchHit {
+            id: hit.id,
+            score: contribution,
+            vector_score: Some(hit.score),
+            keyword_score: None,
+            metadata: hit.metadata,
+        });
+    }
+    for (hit, contribution) in keyword_hits.into_iter().zip(keyword_scores) {
+        if let Some(&position) = positions.get(&hit.id) {
+            fused[position].score += contribution;
+            fused[position].keyword_score = Some(hit.score);
+            continue;
+        }
+        fused.push(HybridSearchHit {
+            id: hit.id,
+            score: contribution,
+            vector_score: None,
+            keyword_score: Some(hit.score),
+            metadata: hit.metadata,
+        });
+    }
+    fused.sort_by(|a, b| b.score.total_cmp(&a.score));
+    fused.truncate(top_k);
+    fused
+}
+
+pub struct HybridSearchTask {
+    entries: SharedEntries,
+    query: String,
+    vector: Option<Vec<f32>>,
+    candidates: VectorSearchOptions,
+    fusion: Fusion,
+    vector_weight: f64,
+    top_k: usize,
+    language: Option<String>,
+    model: EmbeddingModel,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for HybridSearchTask {
+    type Output = Vec<HybridSearchHit>;
+    type JsValue = Vec<HybridSearchHit>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let query = match self.vector.take() {
+            Some(vector) => vector,
+            None => embedding(&self.query, self.language.as_deref(), self.model).map_err(|e| {
+                self.error_code = Some(e.code);
+                napi::Error::from_reason(format!("Query: {}", e.message))
+            })?,
+        };
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        let vector_hits = entries.search(&query, &self.candidates)?;
+        let keyword_hits = entries.keyword_search(&self.query, &self.candidates)?;
+        Ok(fuse(
+            vector_hits,
+            keyword_hits,
+            self.fusion,
+            self.vector_weight,
+            self.top_k,
+        ))
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Search `index` for `query` by meaning and by keywords at once: the
+/// nearest entries by embedding and the best BM25 matches of the entries'
+/// text, fused into one ranking. Keywords catch the exact identifiers and
+/// code tokens that embeddings miss.
+#[napi(ts_return_type = "Promise<HybridSearchHit[]>")]
+pub fn hybrid_search(
+    index: &VectorIndex,
+    query: String,
+    options: Option<HybridSearchOptions>,
+) -> napi::Result<AsyncTask<HybridSearchTask>> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: String| napi::Error::new(Status::InvalidArg, message);
+    let model = EmbeddingModel::parse(options.model.as_deref()).map_err(|e| invalid(e.message))?;
+    let fusion = match options.fusion.as_deref() {
+        None | Some("rrf") => Fusion::Rrf {
+            k: options.rrf_k.unwrap_or(DEFAULT_RRF_K),
+        },
+        Some("weighted") => Fusion::Weighted,
+        Some(other) => {
+            return Err(invalid(format!(
+                "Unknown fusion '{other}', expected 'rrf' or 'weighted'"
+            )))
+        }
+    };
+    if matches!(fusion, Fusion::Rrf { k } if k < 0.0 || k.is_nan()) {
+        return Err(invalid("rrfK must not be negative".to_string()));
+    }
+    let vector_weight = options.vector_weight.unwrap_or(DEFAULT_VECTOR_WEIGHT);
+    if !(0.0..=1.0).contains(&vector_weight) {
+        return Err(invalid("vectorWeight must be between 0 and 1".to_string()));
+    }
+    let top_k = options.top_k.unwrap_or(DEFAULT_TOP_K);
+    let candidates = options
+        .candidates
+        .unwrap_or_else(|| top_k.saturating_mul(4).max(MIN_CANDIDATES))
+        .max(top_k);
+    Ok(AsyncTask::new(HybridSearchTask {
+        entries: index.shared(),
+        query,
+        vector: options.vector.map(|v| v.to_vec()),
+        candidates: VectorSearchOptions {
+            top_k: Some(candidates),
+            filter: options.filter,
+            min_score: None,
+        },
+        fusion,
+        vector_weight,
+        top_k: top_k as usize,
+        language: options.language,
+        model,
+        error_code: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hits(ranked: &[(&str, f64)]) -> Vec<VectorSearchHit> {
+        ranked
+            .iter()
+            .map(|&(id, score)| VectorSearchHit {
+                id: id.to_string(),
+                score,
+                metadata: None,
+            })
+            .collect()
+    }
+
+    fn ids(fused: &[HybridSearchHit]) -> Vec<&str> {
+        fused.iter().map(|hit| hit.id.as_str()).collect()
+    }
+
+    const RRF: Fusion = Fusion::Rrf { k: DEFAULT_RRF_K };
+
+    #[test]
+    fn rrf_rewards_hits_found_by_both_searches() {
+        let vector = hits(&[("a", 0.9), ("b", 0.8), ("c", 0.7)]);
+        let keyword = hits(&[("b", 12.0), ("d", 3.0)]);
+        let fused = fuse(vector, keyword, RRF, 0.5, 10);
+        assert_eq!(ids(&fused), ["b", "a", "d", "c"]);
+        assert!((fused[0].score - (0.5 / 62.0 + 0.5 / 61.0)).abs() < 1e-12);
+        assert!((fused[1].score - 0.5 / 61.0).abs() < 1e-12);
+        assert_eq!(fused.len(), 4);
+
+        let fused = fuse(
+            hits(&[("a", 0.9), ("b", 0.8), ("c", 0.7)]),
+            hits(&[("b", 12.0), ("d", 3.0)]),
+            RRF,
+            0.5,
+            2,
+        );
+        assert_eq!(ids(&fused), ["b", "a"]);
+    }
+
+    #[test]
+    fn hits_keep_the_scores_of_the_searches_that_found_them() {
+        let fused = fuse(
+            hits(&[("a", 0.9), ("b", 0.8)]),
+            hits(&[("b", 12.0), ("d", 3.0)]),
+            RRF,
+            0.5,
+            10,
+        );
+        let hit = |id: &str| fused.iter().find(|hit| hit.id == id).unwrap();
+        assert_eq!(
+            (hit("a").vector_score, hit("a").keyword_score),
+            (Some(0.9), None)
+        );
+        assert_eq!(
+            (hit("b").vector_score, hit("b").keyword_score),
+            (Some(0.8), Some(12.0))
+        );
+        assert_eq!(
+            (hit("d").vector_score, hit("d").keyword_score),
+            (None, Some(3.0))
+        );
+    }
+
+    #[test]
+    fn weights_of_zero_and_one_use_a_single_search() {
+        let vector = || hits(&[("a", 0.9), ("b", 0.8)]);
+        let keyword = || hits(&[("c", 5.0), ("b", 4.0)]);
+        for fusion in [RRF, Fusion::Weighted] {
+            assert_eq!(ids(&fuse(vector(), keyword(), fusion, 0.0, 10)), ["c", "b"]);
+            assert_eq!(ids(&fuse(vector(), keyword(), fusion, 1.0, 10)), ["a", "b"]);
+        }
+        let keywords_only = fuse(vector(), keyword(), RRF, 0.0, 10);
+        assert_eq!(keywords_only[1].vector_score, None);
+    }
+
+    #[test]
+    fn weighted_fusion_adds_normalized_scores() {
+        let vector = hits(&[("a", 0.9), ("b", 0.5), ("c", 0.1)]);
+        let keyword = hits(&[("c", 20.0), ("a", 10.0)]);
+        let fused = fuse(vector, keyword, Fusion::Weighted, 0.25, 10);
+        // a: 0.25 * 1 + 0.75 * 0; c: 0.25 * 0 + 0.75 * 1; b: 0.25 * 0.5
+        assert_eq!(ids(&fused), ["c", "a", "b"]);
+        let scores: Vec<f64> = fused.iter().map(|hit| hit.score).collect();
+        assert_eq!(scores, [0.75, 0.25, 0.125]);
+    }
+
+    #[test]
+    fn normalized_scales_to_the_best_hit() {
+        assert_eq!(
+            normalized(&hits(&[("a", 4.0), ("b", 3.0), ("c", 2.0)])),
+            [1.0, 0.5, 0.0]
+        );
+        // Equal scores can't be told apart, so they all count as best
+        assert_eq!(normalized(&hits(&[("a", 0.3), ("b", 0.3)])), [1.0, 1.0]);
+        assert_eq!(normalized(&hits(&[("a", -0.2)])), [1.0]);
+        assert!(normalized(&[]).is_empty());
+    }
+}
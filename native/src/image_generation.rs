@@ -0,0 +1,160 @@
+//! On-device image generation with Image Playground's `ImageCreator`. Images
+//! come back from Swift as PNG data.
+
+use crate::{
+    apple_ai_generate_image, apple_ai_image_generation_status, coded_error, take_c_string,
+    ErrorCode,
+};
+use napi::bindgen_prelude::{AsyncTask, Buffer};
+use napi::{Env, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::ffi::CString;
+
+/// Most images one request can ask for, as `ImageCreator` allows
+const MAX_COUNT: u32 = 4;
+
+fn native_json(ptr: *mut std::ffi::c_char) -> napi::Result<Value> {
+    if ptr.is_null() {
+        return Err(napi::Error::from_reason("Image generation failed"));
+    }
+    serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+        napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+    })
+}
+
+#[napi(object)]
+pub struct ImageGenerationAvailability {
+    pub available: bool,
+    /// Why images can't be generated, when they can't
+    pub reason: Option<String>,
+    /// Styles this device can generate in
+    #[napi(ts_type = "Array<'animation' | 'illustration' | 'sketch'>")]
+    pub styles: Vec<String>,
+}
+
+pub struct ImageGenerationAvailabilityTask;
+
+impl napi::Task for ImageGenerationAvailabilityTask {
+    type Output = ImageGenerationAvailability;
+    type JsValue = ImageGenerationAvailability;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let status = native_json(unsafe { apple_ai_image_generation_status() })?;
+        Ok(ImageGenerationAvailability {
+            available: status["available"].as_bool().unwrap_or(false),
+            reason: status["reason"].as_str().map(str::to_string),
+            styles: status["styles"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|s| Some(s.as_str()?.to_string()))
+                .collect(),
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+/// Whether this device can generate images, and in which styles. Image
+/// generation needs Apple Intelligence turned on and its image assets.
+#[napi(ts_return_type = "Promise<ImageGenerationAvailability>")]
+pub fn image_generation_availability() -> AsyncTask<ImageGenerationAvailabilityTask> {
+    AsyncTask::new(ImageGenerationAvailabilityTask)
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct GenerateImageOptions {
+    /// Style of the images (default: the first the device has, usually
+    /// `animation`)
+    #[napi(ts_type = "'animation' | 'illustration' | 'sketch'")]
+    pub style: Option<String>,
+    /// Number of images, from 1 to 4 (default 1)
+    pub count: Option<u32>,
+}
+
+pub struct GenerateImageTask {
+    options_json: CString,
+    count: usize,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for GenerateImageTask {
+    type Output = Vec<Vec<u8>>;
+    type JsValue = Vec<Buffer>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let mut images = vec![std::ptr::null_mut::<u8>(); self.count];
+        let mut lengths = vec![0usize; self.count];
+        let result = native_json(unsafe {
+            apple_ai_generate_image(
+                self.options_json.as_ptr(),
+                images.as_mut_ptr(),
+                lengths.as_mut_ptr(),
+                self.count,
+            )
+        })?;
+        if let Some(message) = result["error"].as_str() {
+            self.error_code = result["code"].as_str().and_then(ErrorCode::parse);
+            return Err(napi::Error::from_reason(message.to_string()));
+        }
+        let count = result["count"].as_u64().unwrap_or(0) as usize;
+        Ok(images
+            .into_iter()
+            .zip(lengths)
+            .take(count.min(self.count))
+            .filter(|(ptr, _)| !ptr.is_null())
+            .map(|(ptr, length)| unsafe {
+                let png = std::slice::from_raw_parts(ptr, length).to_vec();
+                libc::free(ptr.cast());
+                png
+            })
+            .collect())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output.into_iter().map(Buffer::from).collect())
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Generate images for `prompt` on the device with Image Playground.
+/// Resolves with PNG data, one `Buffer` per image; rejects with code
+/// `ImageGenerationUnavailable` where the device or system can't generate
+/// images.
+#[napi(ts_return_type = "Promise<Buffer[]>")]
+pub fn generate_image(
+    prompt: String,
+    options: Option<GenerateImageOptions>,
+) -> napi::Result<AsyncTask<GenerateImageTask>> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: String| napi::Error::new(Status::InvalidArg, message);
+    if prompt.trim().is_empty() {
+        return Err(invalid("prompt must not be empty".to_string()));
+    }
+    if let Some(style) = options.style.as_deref() {
+        if !matches!(style, "animation" | "illustration" | "sketch") {
+            return Err(invalid(format!(
+                "style must be 'animation', 'illustration' or 'sketch', got {style:?}"
+            )));
+        }
+    }
+    let count = options.count.unwrap_or(1);
+    if !(1..=MAX_COUNT).contains(&count) {
+        return Err(invalid(format!("count must be between 1 and {MAX_COUNT}")));
+    }
+    let options_json = json!({ "prompt": prompt, "style": options.style, "count": count });
+    Ok(AsyncTask::new(GenerateImageTask {
+        options_json: CString::new(options_json.to_string())
+            .map_err(|_| invalid("prompt contained null byte".to_string()))?,
+        count: count as usize,
+        error_code: None,
+    }))
+}
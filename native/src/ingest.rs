@@ -0,0 +1,326 @@
+//! Document ingestion: text extracted from PDFs (PDFKit) and text or RTF
+//! files, chunked, embedded and written into a `VectorIndex`, file by file
+//! on one worker thread.
+
+use crate::chunking::{chunk, utf16_offsets, ChunkTextOptions, Mode};
+use crate::embeddings::{embed_all, EmbeddingModel};
+use crate::vector_index::{SharedEntries, VectorIndex};
+use crate::{apple_ai_extract_text, coded_error, take_c_string, ErrorCode};
+use napi::bindgen_prelude::AsyncTask;
+use napi::threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{Env, JsFunction, Status};
+use napi_derive::napi;
+use serde_json::{json, Map, Value};
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_EXTENSIONS: [&str; 5] = ["pdf", "txt", "md", "markdown", "rtf"];
+
+#[napi(object)]
+#[derive(Default)]
+pub struct IngestOptions {
+    /// Walk subfolders of the folders given (default `true`)
+    pub recursive: Option<bool>,
+    /// File extensions to pick up in folders (default pdf, txt, md,
+    /// markdown, rtf). Files given directly are read whatever their extension
+    pub extensions: Option<Vec<String>>,
+    /// How to chunk each document, as in `chunkText`
+    pub chunking: Option<ChunkTextOptions>,
+    /// Language of the documents, for chunking and embedding (default:
+    /// detected per chunk)
+    pub language: Option<String>,
+    /// Embedding model (default `sentence`)
+    #[napi(ts_type = "'sentence' | 'contextual'")]
+    pub model: Option<String>,
+    /// Chunks embedded at once (default: the number of CPU cores)
+    pub concurrency: Option<u32>,
+    /// Properties added to the metadata of every chunk
+    #[napi(ts_type = "Record<string, unknown>")]
+    pub metadata: Option<Value>,
+}
+
+/// Reported to the progress callback after each file.
+#[napi(object)]
+#[derive(Clone)]
+pub struct IngestProgress {
+    pub path: String,
+    /// Files finished so far, this one included
+    pub files_done: u32,
+    pub files_total: u32,
+    /// Chunks this file was split into
+    pub chunks: u32,
+    /// Why the file was skipped, when it was
+    pub error: Option<String>,
+}
+
+#[napi(object)]
+pub struct IngestFailure {
+    pub path: String,
+    pub error: String,
+}
+
+#[napi(object)]
+pub struct IngestResult {
+    /// Files indexed
+    pub files: u32,
+    /// Chunks written to the index
+    pub chunks: u32,
+    /// Files that couldn't be read, chunked or embedded
+    pub failed: Vec<IngestFailure>,
+}
+
+type ProgressFn = ThreadsafeFunction<IngestProgress, ErrorStrategy::CalleeHandled>;
+
+/// Text of the file at `path`, one string per PDF page or one for the file.
+fn extract(path: &str) -> Result<Vec<String>, String> {
+    let c_path = CString::new(path).map_err(|_| "Path contained null byte".to_string())?;
+    let ptr = unsafe { apple_ai_extract_text(c_path.as_ptr()) };
+    if ptr.is_null() {
+        return Err("Text extraction failed".to_string());
+    }
+    let result: Value = serde_json::from_str(&take_c_string(ptr))
+        .map_err(|e| format!("Invalid JSON returned from native layer: {e}"))?;
+    if let Some(error) = result.get("error").and_then(Value::as_str) {
+        return Err(error.to_string());
+    }
+    serde_json::from_value(result["pages"].clone())
+        .map_err(|e| format!("Invalid JSON returned from native layer: {e}"))
+}
+
+/// The files under `paths`, in order: files as given, then folders' files
+/// with one of `extensions`, sorted, skipping hidden ones.
+fn collect_files(
+    paths: &[String],
+    extensions: &[String],
+    recursive: bool,
+) -> napi::Result<Vec<String>> {
+    fn walk(
+        folder: &Path,
+        extensions: &[String],
+        recursive: bool,
+        out: &mut Vec<PathBuf>,
+    ) -> std::io::Result<()> {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(folder)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<_>>()?;
+        entries.sort();
+        for path in entries {
+            if path
+                .file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with('.'))
+            {
+                continue;
+            }
+            if path.is_dir() {
+                if recursive {
+                    walk(&path, extensions, recursive, out)?;
+                }
+            } else if path.extension().is_some_and(|e| {
+                let e = e.to_string_lossy();
+                extensions.iter().any(|x| x.eq_ignore_ascii_case(&e))
+            }) {
+                out.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        let as_path = Path::new(path);
+        if as_path.is_dir() {
+            walk(as_path, extensions, recursive, &mut files)
+                .map_err(|e| napi::Error::from_reason(format!("Cannot read {path}: {e}")))?;
+        } else if as_path.is_file() {
+            files.push(as_path.to_path_buf());
+        } else {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                format!("{path} is not a file or folder"),
+            ));
+        }
+    }
+    Ok(files
+        .into_iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect())
+}
+
+pub struct IngestTask {
+    entries: SharedEntries,
+    paths: Vec<String>,
+    extensions: Vec<String>,
+    recursive: bool,
+    mode: Mode,
+    language: Option<String>,
+    model: EmbeddingModel,
+    concurrency: usize,
+    metadata: Map<String, Value>,
+    progress: Option<ProgressFn>,
+}
+
+impl IngestTask {
+    /// Chunk and embed the file at `path`, then replace its chunks in the
+    /// index. Returns how many chunks it has.
+    fn ingest(&self, path: &str) -> Result<u32, String> {
+        let pages = extract(path)?;
+        let paged = pages.len() > 1 || path.to_lowercase().ends_with(".pdf");
+        let mut texts = Vec::new();
+        let mut metadata = Vec::new();
+        for (page, text) in pages.iter().enumerate() {
+            let chunks = chunk(text, &self.mode, self.language.as_deref()).map_err(|e| e.reason)?;
+            let offsets = utf16_offsets(text);
+            for (range, _) in chunks {
+                let mut properties = self.metadata.clone();
+                properties.insert("source".into(), json!(path));
+                if paged {
+                    properties.insert("page".into(), json!(page + 1));
+                }
+                properties.insert("chunk".into(), json!(texts.len()));
+                properties.insert("start".into(), json!(offsets[range.start]));
+                properties.insert("end".into(), json!(offsets[range.end]));
+                texts.push(text[range].to_string());
+                metadata.push(properties);
+            }
+        }
+        if texts.is_empty() {
+            return Err("No text could be extracted".to_string());
+        }
+        let vectors = embed_all(
+            &texts,
+            self.language.as_deref(),
+            self.model,
+            self.concurrency,
+        )
+        .map_err(|e| e.message)?;
+        let count = texts.len() as u32;
+
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        let text_field = entries.text_field().to_string();
+        let source = Map::from_iter([("source".to_string(), json!(path))]);
+        entries.remove_where(&source).map_err(|e| e.reason)?;
+        for (index, ((text, vector), mut properties)) in
+            texts.into_iter().zip(vectors).zip(metadata).enumerate()
+        {
+            properties.insert(text_field.clone(), json!(text));
+            entries
+                .insert(
+                    format!("{path}#{index}"),
+                    &vector,
+                    Some(Value::Object(properties)),
+                )
+                .map_err(|e| e.reason)?;
+        }
+        Ok(count)
+    }
+}
+
+impl napi::Task for IngestTask {
+    type Output = IngestResult;
+    type JsValue = IngestResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        crate::ensure_initialized();
+        let files = collect_files(&self.paths, &self.extensions, self.recursive)?;
+        let mut result = IngestResult {
+            files: 0,
+            chunks: 0,
+            failed: Vec::new(),
+        };
+        for (done, path) in files.iter().enumerate() {
+            let outcome = self.ingest(path);
+            let (chunks, error) = match outcome {
+                Ok(chunks) => {
+                    result.files += 1;
+                    result.chunks += chunks;
+                    (chunks, None)
+                }
+                Err(error) => {
+                    result.failed.push(IngestFailure {
+                        path: path.clone(),
+                        error: error.clone(),
+                    });
+                    (0, Some(error))
+                }
+            };
+            if let Some(progress) = &self.progress {
+                progress.call(
+                    Ok(IngestProgress {
+                        path: path.clone(),
+                        files_done: done as u32 + 1,
+                        files_total: files.len() as u32,
+                        chunks,
+                        error,
+                    }),
+                    ThreadsafeFunctionCallMode::NonBlocking,
+                );
+            }
+        }
+        Ok(result)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(coded_error(env, ErrorCode::of(&err), err.reason))
+    }
+}
+
+/// Index documents: every file in `paths`, and the files with a known
+/// extension in the folders among them, is read, chunked, embedded and
+/// written into `index`, replacing the chunks it had from an earlier run.
+/// Chunk ids are `path#n`; metadata holds the text, `source`, `page` for
+/// PDFs, `chunk` and the chunk's `start` and `end` in its page. A file that
+/// fails is reported and skipped.
+#[napi(ts_return_type = "Promise<IngestResult>")]
+pub fn ingest_documents(
+    index: &VectorIndex,
+    paths: Vec<String>,
+    options: Option<IngestOptions>,
+    #[napi(ts_arg_type = "(err: Error | null, progress: IngestProgress) => void")]
+    on_progress: Option<JsFunction>,
+) -> napi::Result<AsyncTask<IngestTask>> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: String| napi::Error::new(Status::InvalidArg, message);
+    let model = EmbeddingModel::parse(options.model.as_deref()).map_err(|e| invalid(e.message))?;
+    let mut chunking = options.chunking.unwrap_or_default();
+    if chunking.language.is_none() {
+        chunking.language = options.language.clone();
+    }
+    let mode = Mode::new(&chunking)?;
+    let concurrency = match options.concurrency {
+        Some(0) => return Err(invalid("concurrency must be at least 1".to_string())),
+        Some(n) => n as usize,
+        None => std::thread::available_parallelism().map_or(4, |n| n.get()),
+    };
+    let metadata = match options.metadata {
+        None => Map::new(),
+        Some(Value::Object(metadata)) => metadata,
+        Some(_) => return Err(invalid("metadata must be an object".to_string())),
+    };
+    let progress = on_progress
+        .map(|callback| {
+            callback.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<IngestProgress>| {
+                Ok(vec![ctx.value])
+            })
+        })
+        .transpose()?;
+    Ok(AsyncTask::new(IngestTask {
+        entries: index.shared(),
+        paths,
+        extensions: options
+            .extensions
+            .unwrap_or_else(|| DEFAULT_EXTENSIONS.iter().map(|e| e.to_string()).collect()),
+        recursive: options.recursive.unwrap_or(true),
+        mode,
+        language: chunking.language,
+        model,
+        concurrency,
+        metadata,
+        progress,
+    }))
+}
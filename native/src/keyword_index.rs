@@ -0,0 +1,235 @@
+//! BM25 keyword scoring for `VectorIndex` entries, for the exact matches
+//! embeddings miss: identifiers, error codes, version numbers. Terms are kept
+//! per row, like the vectors, so a removal is the same swap-remove and a
+//! search scans the rows it's asked to.
+
+use std::collections::HashMap;
+
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+/// Characters of scripts written without spaces, indexed one by one.
+fn unspaced(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF // Hiragana, Katakana
+        | 0x3400..=0x4DBF // CJK Extension A
+        | 0x4E00..=0x9FFF // CJK Unified Ideographs
+        | 0xAC00..=0xD7AF // Hangul syllables
+        | 0xF900..=0xFAFF // CJK Compatibility Ideographs
+        | 0x0E00..=0x0E7F // Thai
+    )
+}
+
+/// Lowercased terms of `text`. A compound token like `ERR_CONN_RESET`,
+/// `std::fs` or `v1.2.3` is a term of its own as well as its alphanumeric
+/// parts, so searching either form finds it.
+pub fn terms(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let joiner = |c: char| matches!(c, '_' | '.' | '-' | ':' | '/' | '#' | '@');
+    for token in text.split(|c: char| !(c.is_alphanumeric() || joiner(c))) {
+        let token = token.trim_matches(joiner);
+        if token.is_empty() {
+            continue;
+        }
+        let lower = token.to_lowercase();
+        let mut parts = 0;
+        let mut word = String::new();
+        for c in lower.chars() {
+            if unspaced(c) {
+                if !word.is_empty() {
+                    terms.push(std::mem::take(&mut word));
+                    parts += 1;
+                }
+                terms.push(c.to_string());
+                parts += 1;
+            } else if c.is_alphanumeric() {
+                word.push(c);
+            } else if !word.is_empty() {
+                terms.push(std::mem::take(&mut word));
+                parts += 1;
+            }
+        }
+        if !word.is_empty() {
+            terms.push(word);
+            parts += 1;
+        }
+        if parts > 1 && !lower.chars().any(unspaced) {
+            terms.push(lower);
+        }
+    }
+    terms
+}
+
+/// Term frequencies and lengths of the rows, and how many rows have each
+/// term.
+#[derive(Default)]
+pub struct KeywordIndex {
+    rows: Vec<HashMap<String, u32>>,
+    lengths: Vec<u32>,
+    total_length: u64,
+    document_frequency: HashMap<String, u32>,
+}
+
+impl KeywordIndex {
+    fn forget(&mut self, row: usize) {
+        self.total_length -= self.lengths[row] as u64;
+        for term in self.rows[row].keys() {
+            if let Some(count) = self.document_frequency.get_mut(term) {
+                *count -= 1;
+                if *count == 0 {
+                    self.document_frequency.remove(term);
+                }
+            }
+        }
+    }
+
+    /// Index `text` as row `row`, either the next row or one to replace.
+    pub fn set(&mut self, row: usize, text: Option<&str>) {
+        let terms = text.map(terms).unwrap_or_default();
+        let mut frequencies = HashMap::new();
+        for term in &terms {
+            *frequencies.entry(term.clone()).or_insert(0) += 1;
+        }
+        for term in frequencies.keys() {
+            *self.document_frequency.entry(term.clone()).or_insert(0) += 1;
+        }
+        self.total_length += terms.len() as u64;
+        if row < self.rows.len() {
+            self.forget(row);
+            self.rows[row] = frequencies;
+            self.lengths[row] = terms.len() as u32;
+        } else {
+            self.rows.push(frequencies);
+            self.lengths.push(terms.len() as u32);
+        }
+    }
+
+    /// Remove `row`, moving the last row into its place.
+    pub fn swap_remove(&mut self, row: usize) {
+        self.forget(row);
+        self.rows.swap_remove(row);
+        self.lengths.swap_remove(row);
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// The distinct terms of `query` with their inverse document frequency.
+    pub fn query(&self, query: &str) -> Vec<(String, f32)> {
+        let count = self.rows.len() as f32;
+        let mut query = terms(query);
+        query.sort_unstable();
+        query.dedup();
+        query
+            .into_iter()
+            .filter_map(|term| {
+                let frequency = *self.document_frequency.get(&term)? as f32;
+                let idf = (1.0 + (count - frequency + 0.5) / (frequency + 0.5)).ln();
+                Some((term, idf))
+            })
+            .collect()
+    }
+
+    /// BM25 score of `row` for terms from `query`; 0 when it has none of them.
+    pub fn score(&self, row: usize, query: &[(String, f32)]) -> f32 {
+        let average = self.total_length as f32 / self.rows.len().max(1) as f32;
+        let length = self.lengths[row] as f32 / average.max(1.0);
+        query
+            .iter()
+            .filter_map(|(term, idf)| {
+                let frequency = *self.rows[row].get(term)? as f32;
+                Some(idf * frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length)))
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORPUS: [&str; 4] = [
+        "The cat sat on the mat.",
+        "The dog chased the cat around the garden.",
+        "Connection failed with ERR_CONN_RESET in std::fs after v1.2.3",
+        "The weather in 東京 is mild.",
+    ];
+
+    fn index() -> KeywordIndex {
+        let mut index = KeywordIndex::default();
+        for (row, text) in CORPUS.iter().enumerate() {
+            index.set(row, Some(text));
+        }
+        index
+    }
+
+    /// Rows with a positive score for `query`, best first.
+    fn ranked(index: &KeywordIndex, query: &str) -> Vec<usize> {
+        let query = index.query(query);
+        let mut scored: Vec<(usize, f32)> = (0..index.rows.len())
+            .map(|row| (row, index.score(row, &query)))
+            .filter(|&(_, score)| score > 0.0)
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().map(|(row, _)| row).collect()
+    }
+
+    #[test]
+    fn compound_tokens_are_terms_with_their_parts() {
+        assert_eq!(
+            terms("ERR_CONN_RESET"),
+            ["err", "conn", "reset", "err_conn_reset"]
+        );
+        assert_eq!(terms("v1.2.3."), ["v1", "2", "3", "v1.2.3"]);
+        assert_eq!(terms("std::fs"), ["std", "fs", "std::fs"]);
+        assert_eq!(terms("Hello, World"), ["hello", "world"]);
+        assert_eq!(terms("東京タワー"), ["東", "京", "タ", "ワ", "ー"]);
+        assert!(terms(" -- ").is_empty());
+    }
+
+    #[test]
+    fn bm25_ranks_rows_by_matching_terms() {
+        let index = index();
+        assert_eq!(ranked(&index, "cat"), [0, 1]);
+        // Both terms beat one, and the rarer "dog" beats the common "cat"
+        assert_eq!(ranked(&index, "dog cat"), [1, 0]);
+        assert_eq!(ranked(&index, "err_conn_reset"), [2]);
+        assert_eq!(ranked(&index, "RESET"), [2]);
+        assert_eq!(ranked(&index, "V1.2.3"), [2]);
+        assert_eq!(ranked(&index, "京"), [3]);
+        assert!(ranked(&index, "unicorn").is_empty());
+    }
+
+    #[test]
+    fn rarer_terms_weigh_more() {
+        let index = index();
+        let idf = |term: &str| index.query(term)[0].1;
+        assert!(idf("dog") > idf("cat"));
+        assert!(idf("cat") > idf("the"));
+        assert!(index.query("unicorn").is_empty());
+        // Repeated query terms count once
+        assert_eq!(index.query("cat cat CAT").len(), 1);
+    }
+
+    #[test]
+    fn replacing_and_removing_rows_keeps_statistics() {
+        let mut index = index();
+        index.set(1, Some("A bird sang."));
+        assert_eq!(ranked(&index, "dog"), Vec::<usize>::new());
+        assert_eq!(ranked(&index, "bird"), [1]);
+        assert_eq!(ranked(&index, "cat"), [0]);
+
+        // The last row moves into the removed one's place
+        index.swap_remove(0);
+        assert_eq!(ranked(&index, "cat"), Vec::<usize>::new());
+        assert_eq!(ranked(&index, "京"), [0]);
+        assert_eq!(index.rows.len(), 3);
+        let total: u32 = index.lengths.iter().sum();
+        assert_eq!(index.total_length, u64::from(total));
+
+        index.clear();
+        assert!(index.query("bird").is_empty());
+        assert_eq!(index.total_length, 0);
+    }
+}
@@ -0,0 +1,130 @@
+//! Language identification with NLLanguageRecognizer: `detectLanguage`, and
+//! the detection the crate does itself for `respondIn` and `language: "auto"`.
+
+use crate::{apple_ai_identify_language, response_language, take_c_string};
+use napi::Status;
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ffi::CString;
+
+const DEFAULT_MAX_HYPOTHESES: u32 = 5;
+
+/// Probability from which the top hypothesis is taken as the language of a
+/// prompt, for `language: "auto"`.
+const CONFIDENT_PROBABILITY: f64 = 0.8;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct DetectLanguageOptions {
+    /// Most hypotheses to return (default 5)
+    pub max_hypotheses: Option<u32>,
+    /// Prior probabilities by language code, e.g. `{ en: 0.8, de: 0.2 }`, for
+    /// text that could be one of several languages
+    pub hints: Option<HashMap<String, f64>>,
+    /// Language codes to choose between; others are never reported
+    pub constraints: Option<Vec<String>>,
+}
+
+#[napi(object)]
+pub struct LanguageHypothesis {
+    /// BCP 47 code, e.g. `en`, `pt` or `zh-Hans`
+    pub language: String,
+    /// 0..1
+    pub probability: f64,
+}
+
+#[napi(object)]
+pub struct LanguageDetection {
+    /// The dominant language, absent when the text doesn't tell, e.g. when
+    /// it's empty or only digits
+    pub language: Option<String>,
+    /// Candidate languages, most probable first
+    pub hypotheses: Vec<LanguageHypothesis>,
+}
+
+/// Detection for `text`, or `None` when the native layer can't run it.
+pub(crate) fn identify(
+    text: &str,
+    options: Option<&DetectLanguageOptions>,
+) -> Option<LanguageDetection> {
+    let default = DetectLanguageOptions::default();
+    let options = options.unwrap_or(&default);
+    let c_text = CString::new(text).ok()?;
+    let c_options = CString::new(
+        json!({
+            "maxHypotheses": options.max_hypotheses.unwrap_or(DEFAULT_MAX_HYPOTHESES).max(1),
+            "hints": options.hints,
+            "constraints": options.constraints,
+        })
+        .to_string(),
+    )
+    .ok()?;
+    let ptr = unsafe { apple_ai_identify_language(c_text.as_ptr(), c_options.as_ptr()) };
+    if ptr.is_null() {
+        return None;
+    }
+    let result: Value = serde_json::from_str(&take_c_string(ptr)).ok()?;
+    if result["error"].is_string() {
+        return None;
+    }
+    let hypotheses = result["hypotheses"]
+        .as_array()
+        .map(|hypotheses| {
+            hypotheses
+                .iter()
+                .filter_map(|h| {
+                    Some(LanguageHypothesis {
+                        language: h["language"].as_str()?.to_string(),
+                        probability: h["probability"].as_f64()?,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Some(LanguageDetection {
+        language: result["language"].as_str().map(str::to_string),
+        hypotheses,
+    })
+}
+
+/// Language of `text` when there's enough of it and the recognizer is sure.
+pub(crate) fn confident_language(text: &str) -> Option<String> {
+    if text.chars().count() < response_language::MIN_DETECTABLE_CHARS {
+        return None;
+    }
+    let detection = identify(
+        text,
+        Some(&DetectLanguageOptions {
+            max_hypotheses: Some(1),
+            ..Default::default()
+        }),
+    )?;
+    detection
+        .hypotheses
+        .into_iter()
+        .next()
+        .filter(|top| top.probability >= CONFIDENT_PROBABILITY)
+        .map(|top| top.language)
+}
+
+/// Dominant language of `text`, with the likeliest candidates and their
+/// probabilities.
+#[napi]
+pub fn detect_language(
+    text: String,
+    options: Option<DetectLanguageOptions>,
+) -> napi::Result<LanguageDetection> {
+    if text.contains('\0') {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "Text contained null byte",
+        ));
+    }
+    identify(&text, options.as_ref()).ok_or_else(|| {
+        napi::Error::new(
+            Status::GenericFailure,
+            "Language detection failed in native layer",
+        )
+    })
+}
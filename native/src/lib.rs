@@ -3,51 +3,140 @@ use napi::bindgen_prelude::*;
 use napi::threadsafe_function::{
     ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
 };
-use napi::JsString;
+use napi::JsObject;
 use napi_derive::napi;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ffi::{CStr, CString};
-use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-// -------- FFI declarations to Swift dylib --------
-#[link(name = "appleai")]
-extern "C" {
-    fn apple_ai_init() -> bool;
-    fn apple_ai_check_availability() -> c_int;
-    fn apple_ai_get_availability_reason() -> *mut c_char;
+mod anthropic;
+mod chunking;
+mod clustering;
+mod context;
+mod data_detection;
+mod data_stream;
+mod describe_image;
+mod duplicates;
+mod embedding_cache;
+mod embeddings;
+mod errors;
+pub mod ffi;
+mod generation_schema;
+#[cfg(feature = "grpc")]
+mod grpc_server;
+mod hybrid_search;
+mod image_generation;
+mod ingest;
+mod injection;
+mod keyword_index;
+mod language_id;
+mod mcp;
+mod mcp_server;
+mod native_tools;
+mod openai;
+mod rag;
+mod redaction;
+mod rerank;
+mod response_language;
+mod schema;
+mod search;
+mod server;
+mod socket_server;
+mod sound;
+mod speech;
+mod speech_synthesis;
+mod text_analysis;
+mod text_checking;
+mod tokenization;
+mod translation;
+mod vector;
+mod vector_index;
+mod vector_store;
+mod vision;
 
-    fn apple_ai_get_supported_languages_count() -> c_int;
-    fn apple_ai_get_supported_language(index: c_int) -> *mut c_char;
-
-    // Tool callback registration and tool-based generation
-    fn apple_ai_register_tool_callback(
-        cb: Option<extern "C" fn(u64, *const c_char) -> *mut c_char>,
-    );
-    fn apple_ai_tool_result_callback(tool_id: u64, result_json: *const c_char);
+pub use anthropic::anthropic_messages;
+pub use chunking::chunk_text;
+pub use clustering::cluster_texts;
+use context::message_role;
+pub use context::{Condense, CondenseOptions, ContextPolicy};
+pub use data_detection::detect_data;
+pub use data_stream::generate_data_stream;
+pub use describe_image::describe_image;
+pub use duplicates::find_duplicates;
+pub use embedding_cache::{
+    clear_embedding_cache, configure_embedding_cache, embedding_cache_stats, invalidate_embeddings,
+};
+pub use embeddings::{embed, embed_batch, nearest_neighbors, word_distance, word_vector};
+pub use errors::ErrorCode;
+use errors::{coded_error, native_error};
+#[cfg(feature = "grpc")]
+pub use grpc_server::start_grpc_server;
+pub use hybrid_search::hybrid_search;
+pub use image_generation::{generate_image, image_generation_availability};
+pub use ingest::ingest_documents;
+pub use injection::scan_for_injection;
+use injection::{ScanToolResultsOptions, ToolResultScan};
+pub use language_id::detect_language;
+use language_id::LanguageHypothesis;
+use mcp::McpTools;
+pub use mcp::{connect_mcp_server, disconnect_mcp_server, list_mcp_servers};
+pub use mcp_server::start_mcp_server;
+use native_tools::{NativeTool, NativeToolsOptions};
+pub use openai::{openai_chat_completion, openai_chat_completion_stream};
+pub use rag::rag_generate;
+pub use redaction::redact;
+pub use rerank::rerank;
+pub use search::semantic_search;
+pub use server::start_server;
+pub use socket_server::start_socket_server;
+pub use sound::{classify_sound, start_sound_classification, stop_sound_classification};
+pub use speech::{start_transcription, stop_transcription, transcribe_file};
+pub use speech_synthesis::{list_voices, stream_speech, synthesize_speech};
+pub use text_analysis::{
+    analyze_sentiment, create_gazetteer, extract_entities, remove_gazetteer, tag_text,
+};
+pub use text_checking::check_text;
+pub use tokenization::tokenize;
+pub use translation::{
+    download_language_pair, get_translation_languages, is_pair_installed, translate,
+};
+pub use vector_index::VectorIndex;
+pub use vision::{analyze_saliency, classify_image, recognize_text};
 
-    // Unified generation function
-    fn apple_ai_generate_unified(
-        messages_json: *const c_char,
-        tools_json: *const c_char,  // nullable
-        schema_json: *const c_char, // nullable
-        temperature: c_double,
-        max_tokens: c_int,
-        stream: bool,
-        stop_after_tool_calls: bool,                    // new parameter
-        on_chunk: Option<extern "C" fn(*const c_char)>, // nullable
-    ) -> *mut c_char;
-}
+use ffi::*;
 
 // --------------------------------------------------
 
-/// Lazily ensure the Swift library is initialized exactly once.
+/// Whether the Swift library is initialized; cleared by `release_model`.
+static INITIALIZED: Mutex<bool> = Mutex::new(false);
+
+/// Lazily ensure the Swift library is initialized, again after `release_model`.
 fn ensure_initialized() {
-    static ONCE: std::sync::Once = std::sync::Once::new();
-    ONCE.call_once(|| unsafe {
-        if !apple_ai_init() {
+    let mut initialized = INITIALIZED.lock().unwrap();
+    if !*initialized {
+        if !unsafe { apple_ai_init() } {
             panic!("Failed to initialize Apple AI native library");
         }
-    });
+        *initialized = true;
+    }
+}
+
+/// Release the model resources kept between requests (such as a prewarmed
+/// session) so the system can reclaim the memory, e.g. when the app goes
+/// idle. The next request initializes everything again. Returns `false` if
+/// requests were still in flight; their resources are freed when they finish.
+#[napi]
+pub fn release_model() -> bool {
+    let mut initialized = INITIALIZED.lock().unwrap();
+    if !*initialized {
+        return true;
+    }
+    let active = unsafe { apple_ai_release() };
+    *initialized = false;
+    active == 0
 }
 
 #[napi(object)]
@@ -56,16 +145,89 @@ pub struct ModelAvailability {
     pub reason: String,
 }
 
-#[inline(always)]
-fn take_c_string(ptr: *mut c_char) -> String {
-    if ptr.is_null() {
-        return String::new();
-    }
-    unsafe {
-        let s = CStr::from_ptr(ptr).to_string_lossy().into_owned();
-        libc::free(ptr as *mut _);
-        s
-    }
+/// Download state of the model, reported by `watch_model_status`.
+#[napi(object)]
+pub struct ModelStatus {
+    /// `downloading` while the system is still fetching the model assets
+    #[napi(ts_type = "'available' | 'downloading' | 'unavailable'")]
+    pub state: String,
+    pub reason: String,
+    /// How long the model has been downloading since watching started. The
+    /// framework doesn't report a completed fraction, so this is what a
+    /// progress indicator can show
+    pub downloading_ms: Option<u32>,
+}
+
+const DEFAULT_STATUS_INTERVAL_MS: u32 = 2_000;
+
+/// Poll model availability and report each change of state to `callback`,
+/// starting with the current one. Watching ends once the model is available
+/// or `stop()` is called.
+#[napi(ts_return_type = "{ stop: () => void }")]
+pub fn watch_model_status(
+    env: Env,
+    #[napi(ts_arg_type = "(err: Error | null, status: ModelStatus) => void")] callback: JsFunction,
+    interval_ms: Option<u32>,
+) -> napi::Result<JsObject> {
+    ensure_initialized();
+    let tsfn: ThreadsafeFunction<ModelStatus, ErrorStrategy::CalleeHandled> = callback
+        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<ModelStatus>| {
+            Ok(vec![ctx.value])
+        })?;
+    let interval = Duration::from_millis(
+        interval_ms
+            .filter(|ms| *ms > 0)
+            .unwrap_or(DEFAULT_STATUS_INTERVAL_MS)
+            .into(),
+    );
+    let (stop, stopped) = mpsc::channel::<()>();
+
+    std::thread::spawn(move || {
+        let mut last = None;
+        let mut downloading_since = None;
+        loop {
+            let status = unsafe { apple_ai_check_availability() };
+            let state = match status {
+                1 => "available",
+                -3 => "downloading",
+                _ => "unavailable",
+            };
+            if state == "downloading" {
+                downloading_since.get_or_insert_with(Instant::now);
+            }
+            if last != Some(status) || state == "downloading" {
+                let reason = take_c_string(unsafe { apple_ai_get_availability_reason() });
+                let _ = tsfn.call(
+                    Ok(ModelStatus {
+                        state: state.to_string(),
+                        reason,
+                        downloading_ms: downloading_since
+                            .filter(|_| state == "downloading")
+                            .map(|since| since.elapsed().as_millis() as u32),
+                    }),
+                    ThreadsafeFunctionCallMode::NonBlocking,
+                );
+                last = Some(status);
+            }
+            if state == "available" {
+                return;
+            }
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+        }
+    });
+
+    let stop = Mutex::new(Some(stop));
+    let stop = env.create_function_from_closure("stop", move |ctx| {
+        // Dropping the sender wakes and ends the watcher
+        stop.lock().unwrap().take();
+        ctx.env.get_undefined()
+    })?;
+    let mut handle = env.create_object()?;
+    handle.set_named_property("stop", stop)?;
+    Ok(handle)
 }
 
 #[napi]
@@ -106,304 +268,3905 @@ pub fn get_supported_languages() -> napi::Result<Vec<String>> {
     }
 }
 
-// ---------------- Async generation tasks ----------------
-
-const ERROR_SENTINEL: u8 = 0x02;
+/// Whether the on-device model supports a locale, given as an identifier
+/// such as "de-DE" or "ja".
+#[napi]
+pub fn is_language_supported(locale: String) -> napi::Result<bool> {
+    ensure_initialized();
+    let c_locale = CString::new(locale)
+        .map_err(|_| napi::Error::new(Status::InvalidArg, "Locale contained null byte"))?;
+    Ok(unsafe { apple_ai_supports_locale(c_locale.as_ptr()) })
+}
 
-// ---------- Global tool handler state ----------
+/// Whether the on-device model accepts image content parts on this system.
+#[napi]
+pub fn supports_image_input() -> bool {
+    ensure_initialized();
+    unsafe { apple_ai_supports_image_input() }
+}
 
-// Async tool dispatcher - like streaming
-static TOOL_CALLBACK: OnceLock<
-    Mutex<Option<ThreadsafeFunction<(u64, String), ErrorStrategy::CalleeHandled>>>,
-> = OnceLock::new();
-static TOOL_RESULTS: OnceLock<Mutex<HashMap<u64, std::sync::mpsc::Sender<String>>>> =
-    OnceLock::new();
-
-fn tool_callback(
-) -> &'static Mutex<Option<ThreadsafeFunction<(u64, String), ErrorStrategy::CalleeHandled>>> {
-    TOOL_CALLBACK.get_or_init(|| Mutex::new(None))
+/// Infer a JSON Schema (as JSON) from an example value given as JSON, for
+/// structured output without a hand-written schema.
+#[napi]
+pub fn schema_from_example(sample_json: String) -> napi::Result<String> {
+    let sample: serde_json::Value = serde_json::from_str(&sample_json)
+        .map_err(|e| napi::Error::new(Status::InvalidArg, format!("Invalid sample JSON: {e}")))?;
+    Ok(generation_schema::from_example(&sample).to_string())
 }
 
-fn tool_results() -> &'static Mutex<HashMap<u64, std::sync::mpsc::Sender<String>>> {
-    TOOL_RESULTS.get_or_init(|| Mutex::new(HashMap::new()))
+#[napi(object)]
+#[derive(Default)]
+pub struct PrewarmOptions {
+    /// System instructions the upcoming request will use
+    pub instructions: Option<String>,
+    /// Known start of the upcoming prompt, processed ahead of time
+    pub prompt_prefix: Option<String>,
+    /// Loaded adapter the upcoming request will use, instead of the active one
+    pub adapter: Option<String>,
+    /// Use case of the model the upcoming request will use
+    #[napi(ts_type = "'general' | 'contentTagging'")]
+    pub use_case: Option<String>,
+    /// Guardrails the upcoming request will use
+    #[napi(ts_type = "'developerProvided' | 'default' | 'permissiveContentTransformations'")]
+    pub guardrails: Option<String>,
 }
 
+/// Load the model (and optionally process instructions and a prompt prefix)
+/// before a request, to cut its time to first token. Returns immediately;
+/// `false` if the model is unavailable.
 #[napi]
-pub fn set_tool_callback(callback: JsFunction) -> napi::Result<()> {
-    // Replace any existing callback atomically
-    let tsfn: ThreadsafeFunction<(u64, String), ErrorStrategy::CalleeHandled> = callback
-        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<(u64, String)>| {
-            let env = ctx.env;
-            let (tool_id, args_json) = ctx.value;
-            let js_tool_id = env.create_uint32(tool_id as u32)?;
-            let js_args = env.create_string(&args_json)?;
-            Ok(vec![js_tool_id.into_unknown(), js_args.into_unknown()])
-        })?;
+pub fn prewarm(options: Option<PrewarmOptions>) -> napi::Result<bool> {
+    ensure_initialized();
+    let options = options.unwrap_or_default();
+    let to_c = |text: Option<String>| {
+        text.map(CString::new)
+            .transpose()
+            .map_err(|_| napi::Error::from_reason("Prewarm text contained null byte".to_string()))
+    };
+    let instructions = to_c(options.instructions)?;
+    let prompt_prefix = to_c(options.prompt_prefix)?;
+    let (adapter, use_case) =
+        model_choice(options.adapter.as_deref(), options.use_case.as_deref())?;
+    let guardrails = options
+        .guardrails
+        .as_deref()
+        .map(GuardrailSetting::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let ptr = |c: &Option<CString>| c.as_ref().map_or(std::ptr::null(), |c| c.as_ptr());
+    Ok(unsafe {
+        apple_ai_prewarm(
+            ptr(&instructions),
+            ptr(&prompt_prefix),
+            adapter,
+            use_case as c_int,
+            guardrails as c_int,
+        )
+    })
+}
 
-    let mut guard = tool_callback().lock().unwrap();
-    if let Some(old) = guard.take() {
-        let _ = old.abort();
-    }
-    *guard = Some(tsfn);
-    Ok(())
+/// Which model answers requests on this system, for bug reports and telemetry.
+#[napi(object)]
+pub struct ModelInfo {
+    /// Operating system version, e.g. "Version 26.0 (Build 25A354)"
+    pub os_version: String,
+    /// Newest Foundation Models feature level available, e.g. "26.4"
+    pub framework_level: String,
+    /// `default` for the system model, `adapter` when a custom adapter is active
+    #[napi(ts_type = "'default' | 'adapter'")]
+    pub model: String,
+    /// Name of the active adapter, if any
+    pub adapter: Option<String>,
+    /// Use case the model is specialized for
+    pub use_case: String,
+    pub available: bool,
 }
 
 #[napi]
-pub fn clear_tool_callback() -> napi::Result<()> {
-    let mut guard = tool_callback().lock().unwrap();
-    if let Some(tsfn) = guard.take() {
-        let _ = tsfn.abort();
-    }
-    Ok(())
+pub fn get_model_info() -> napi::Result<ModelInfo> {
+    ensure_initialized();
+    let raw = take_c_string(unsafe { apple_ai_model_info() });
+    let json: serde_json::Value = serde_json::from_str(&raw).map_err(|e| {
+        napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+    })?;
+    let text = |key: &str| {
+        json.get(key)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let adapter = adapters().lock().unwrap().active.clone();
+    Ok(ModelInfo {
+        os_version: text("osVersion"),
+        framework_level: text("frameworkLevel"),
+        model: if adapter.is_some() {
+            "adapter".to_string()
+        } else {
+            text("model")
+        },
+        adapter,
+        use_case: text("useCase"),
+        available: json
+            .get("available")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+    })
 }
 
-#[napi]
-pub fn tool_result(tool_id: f64, result_json: String) -> napi::Result<()> {
-    // Notify Swift via the result callback
-    unsafe {
-        let tool_id_u64 = tool_id as u64;
-        let c_result = CString::new(result_json.clone()).unwrap();
-        apple_ai_tool_result_callback(tool_id_u64, c_result.as_ptr());
-    }
+// ---------------- Adapters ----------------
 
-    // Also notify our internal Rust channel for the blocking wait
-    if let Some(sender) = tool_results().lock().unwrap().remove(&(tool_id as u64)) {
-        let _ = sender.send(result_json);
-    }
-    Ok(())
+/// An adapter loaded into Swift, known there by `handle`.
+struct LoadedAdapter {
+    handle: u64,
+    path: String,
 }
 
-fn ensure_tool_callback_registered() {
-    static ONCE: std::sync::Once = std::sync::Once::new();
-    ONCE.call_once(|| unsafe {
-        apple_ai_register_tool_callback(Some(js_tool_dispatch));
-    });
+/// Adapters loaded with `load_adapter` by name, and the one requests use
+/// unless they name another.
+#[derive(Default)]
+struct AdapterRegistry {
+    loaded: HashMap<String, LoadedAdapter>,
+    active: Option<String>,
 }
 
-extern "C" fn js_tool_dispatch(_tool_id: u64, _args_json: *const c_char) -> *mut c_char {
-    ensure_initialized();
-
-    let args_json = unsafe {
-        if _args_json.is_null() {
-            "{}".to_string()
-        } else {
-            CStr::from_ptr(_args_json).to_string_lossy().into_owned()
-        }
-    };
+static ADAPTERS: OnceLock<Mutex<AdapterRegistry>> = OnceLock::new();
 
-    // Create channel for result
-    let (tx, rx) = std::sync::mpsc::channel::<String>();
-    tool_results().lock().unwrap().insert(_tool_id, tx);
+fn adapters() -> &'static Mutex<AdapterRegistry> {
+    ADAPTERS.get_or_init(|| Mutex::new(AdapterRegistry::default()))
+}
 
-    // Call JS side async, swallow any error to avoid unwinding across FFI
-    if let Some(ref tsfn) = *tool_callback().lock().unwrap() {
-        let _ = std::panic::catch_unwind(|| {
-            tsfn.call(
-                Ok((_tool_id, args_json)),
-                ThreadsafeFunctionCallMode::NonBlocking,
-            )
-        });
+impl AdapterRegistry {
+    /// Swift handle of the named adapter, or of the active one if `name` is
+    /// `None`; 0 selects the system model.
+    fn handle(&self, name: Option<&str>) -> napi::Result<u64> {
+        let Some(name) = name.or(self.active.as_deref()) else {
+            return Ok(0);
+        };
+        self.loaded
+            .get(name)
+            .map(|adapter| adapter.handle)
+            .ok_or_else(|| {
+                napi::Error::new(
+                    Status::InvalidArg,
+                    format!("no adapter named '{name}' is loaded"),
+                )
+            })
     }
 
-    // Wait for result from separate JS callback
-    let response = match rx.recv_timeout(std::time::Duration::from_secs(10)) {
-        Ok(r) => r,
-        Err(_) => {
-            // remove dangling sender to avoid leak
-            tool_results().lock().unwrap().remove(&_tool_id);
-            "{}".to_string()
+    fn info(&self, name: &str, adapter: &LoadedAdapter) -> AdapterInfo {
+        AdapterInfo {
+            name: name.to_string(),
+            path: adapter.path.clone(),
+            active: self.active.as_deref() == Some(name),
         }
-    };
-    CString::new(response).unwrap().into_raw()
+    }
 }
 
-// ---------------- Unified Generation ----------------
+/// A custom adapter loaded with `load_adapter`.
+#[napi(object)]
+pub struct AdapterInfo {
+    /// Name requests select the adapter by
+    pub name: String,
+    pub path: String,
+    /// Whether requests that don't name an adapter use this one
+    pub active: bool,
+}
 
-pub struct GenerateUnifiedTask {
-    pub messages_json: String,
-    pub tools_json: Option<String>,
-    pub schema_json: Option<String>,
-    pub temperature: f64,
-    pub max_tokens: i32,
-    pub stop_after_tool_calls: bool, // new field
+#[napi(object)]
+#[derive(Default)]
+pub struct LoadAdapterOptions {
+    /// Name to register the adapter under (default: the package file name)
+    pub name: Option<String>,
+    /// Make this the adapter requests use unless they name another (default `true`)
+    pub activate: Option<bool>,
 }
 
-impl napi::Task for GenerateUnifiedTask {
-    type Output = String;
-    type JsValue = JsString;
+pub struct LoadAdapterTask {
+    path: String,
+    options: LoadAdapterOptions,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for LoadAdapterTask {
+    type Output = AdapterInfo;
+    type JsValue = AdapterInfo;
 
     fn compute(&mut self) -> napi::Result<Self::Output> {
         ensure_initialized();
-        if self.tools_json.is_some() {
-            ensure_tool_callback_registered();
+        let c_path = CString::new(self.path.as_str())
+            .map_err(|_| napi::Error::from_reason("Path contained null byte".to_string()))?;
+        let raw = take_c_string(unsafe { apple_ai_load_adapter(c_path.as_ptr()) });
+        let json: serde_json::Value = serde_json::from_str(&raw).map_err(|e| {
+            napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+        })?;
+        if let Some(message) = json.get("error").and_then(|v| v.as_str()) {
+            self.error_code = json
+                .get("code")
+                .and_then(|v| v.as_str())
+                .and_then(ErrorCode::parse);
+            return Err(napi::Error::from_reason(message.to_string()));
         }
+        let handle = json.get("id").and_then(|v| v.as_u64()).unwrap_or(0);
+        let name = self.options.name.clone().unwrap_or_else(|| {
+            json.get("name")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        });
 
-        let c_messages = CString::new(self.messages_json.clone())
-            .map_err(|_| napi::Error::from_reason("Messages contained null byte".to_string()))?;
-
-        // Convert optional strings to nullable pointers
-        let c_tools = self
-            .tools_json
-            .as_ref()
-            .map(|s| CString::new(s.clone()))
-            .transpose()
-            .map_err(|_| napi::Error::from_reason("Tools JSON contained null byte".to_string()))?;
+        let mut registry = adapters().lock().unwrap();
+        let adapter = LoadedAdapter {
+            handle,
+            path: self.path.clone(),
+        };
+        // Loading under a taken name replaces that adapter
+        if let Some(previous) = registry.loaded.insert(name.clone(), adapter) {
+            unsafe { apple_ai_unload_adapter(previous.handle) };
+        }
+        if self.options.activate.unwrap_or(true) {
+            registry.active = Some(name.clone());
+        }
+        Ok(registry.info(&name, &registry.loaded[&name]))
+    }
 
-        let c_schema = self
-            .schema_json
-            .as_ref()
-            .map(|s| CString::new(s.clone()))
-            .transpose()
-            .map_err(|_| napi::Error::from_reason("Schema JSON contained null byte".to_string()))?;
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
 
-        unsafe {
-            let result_ptr = apple_ai_generate_unified(
-                c_messages.as_ptr(),
-                c_tools.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
-                c_schema.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
-                self.temperature as c_double,
-                self.max_tokens as c_int,
-                false, // not streaming
-                self.stop_after_tool_calls,
-                None, // no callback for non-streaming
-            );
-            if result_ptr.is_null() {
-                return Err(napi::Error::from_reason(
-                    "Generation returned null".to_string(),
-                ));
-            }
-            Ok(take_c_string(result_ptr))
-        }
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
     }
+}
 
-    fn resolve(&mut self, env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
-        env.create_string(&output)
+/// Load a custom adapter (an `.fmadapter` package from Apple's adapter
+/// toolkit) under a name that requests select it by with `options.adapter`.
+/// Unless `activate` is `false` it also becomes the adapter for requests
+/// that don't name one. Rejects with code `AdapterNotFound`,
+/// `InvalidAdapter`, or `AdapterIncompatible` when the adapter was trained
+/// for a different version of the system model.
+#[napi(ts_return_type = "Promise<AdapterInfo>")]
+pub fn load_adapter(
+    path: String,
+    options: Option<LoadAdapterOptions>,
+) -> napi::Result<AsyncTask<LoadAdapterTask>> {
+    let options = options.unwrap_or_default();
+    if options.name.as_deref() == Some("") {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "adapter name cannot be empty".to_string(),
+        ));
     }
+    Ok(AsyncTask::new(LoadAdapterTask {
+        path,
+        options,
+        error_code: None,
+    }))
 }
 
+/// Unload the named adapter, or the active one if no name is given; requests
+/// already running finish with it. Returns `false` if it wasn't loaded.
 #[napi]
-pub fn generate_unified(
-    messages_json: String,
-    #[napi(ts_arg_type = "string | undefined | null")] tools_json: Option<String>,
-    #[napi(ts_arg_type = "string | undefined | null")] schema_json: Option<String>,
-    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
-    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
-    #[napi(ts_arg_type = "boolean | undefined")] stop_after_tool_calls: Option<bool>,
-) -> napi::Result<AsyncTask<GenerateUnifiedTask>> {
-    let task = GenerateUnifiedTask {
-        messages_json,
-        tools_json: tools_json.filter(|s| !s.is_empty()),
-        schema_json: schema_json.filter(|s| !s.is_empty()),
-        temperature: temperature.unwrap_or(0.0),
-        max_tokens: max_tokens.unwrap_or(0),
-        stop_after_tool_calls: stop_after_tool_calls.unwrap_or(true), // default to true
+pub fn unload_adapter(name: Option<String>) -> bool {
+    let mut registry = adapters().lock().unwrap();
+    let Some(name) = name.or_else(|| registry.active.clone()) else {
+        return false;
     };
-    Ok(AsyncTask::new(task))
+    let Some(adapter) = registry.loaded.remove(&name) else {
+        return false;
+    };
+    if registry.active.as_deref() == Some(name.as_str()) {
+        registry.active = None;
+    }
+    unsafe { apple_ai_unload_adapter(adapter.handle) }
 }
 
+/// Make the named adapter the one requests use unless they name another.
 #[napi]
-pub fn generate_unified_stream(
-    messages_json: String,
-    #[napi(ts_arg_type = "string | undefined | null")] tools_json: Option<String>,
-    #[napi(ts_arg_type = "string | undefined | null")] schema_json: Option<String>,
-    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
-    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
-    #[napi(ts_arg_type = "boolean | undefined")] stop_after_tool_calls: Option<bool>,
-    callback: JsFunction,
-) -> napi::Result<()> {
-    ensure_initialized();
-    if tools_json.is_some() {
-        ensure_tool_callback_registered();
+pub fn activate_adapter(name: String) -> napi::Result<()> {
+    let mut registry = adapters().lock().unwrap();
+    registry.handle(Some(&name))?;
+    registry.active = Some(name);
+    Ok(())
+}
+
+/// Run requests that don't name an adapter against the system model again.
+/// The adapters stay loaded.
+#[napi]
+pub fn deactivate_adapter() {
+    adapters().lock().unwrap().active = None;
+}
+
+/// The loaded adapters, by name.
+#[napi]
+pub fn list_adapters() -> Vec<AdapterInfo> {
+    let registry = adapters().lock().unwrap();
+    let mut list: Vec<AdapterInfo> = registry
+        .loaded
+        .iter()
+        .map(|(name, adapter)| registry.info(name, adapter))
+        .collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+    list
+}
+
+/// Specialized variant of the system model, chosen with `useCase`.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum UseCase {
+    #[default]
+    General = 0,
+    /// Tagging and classification, e.g. extracting topics or entities
+    ContentTagging = 1,
+}
+
+impl UseCase {
+    fn parse(use_case: &str) -> napi::Result<Self> {
+        match use_case {
+            "general" => Ok(Self::General),
+            "contentTagging" => Ok(Self::ContentTagging),
+            other => Err(napi::Error::new(
+                Status::InvalidArg,
+                format!("useCase must be 'general' or 'contentTagging', got {other:?}"),
+            )),
+        }
     }
+}
 
-    let ts_fn: ThreadsafeFunction<String, ErrorStrategy::CalleeHandled> = callback
-        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<String>| {
-            let env = ctx.env;
-            let js_string = env.create_string(&ctx.value)?;
-            Ok(vec![js_string])
-        })?;
+/// Safety guardrails a request runs with, chosen with `guardrails`.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum GuardrailSetting {
+    /// The configuration this library has always used
+    #[default]
+    DeveloperProvided = 0,
+    /// The framework's default guardrails
+    Default = 1,
+    /// Relaxed checks for transforming text the user supplied (summarizing,
+    /// rewriting, tagging) that may itself contain sensitive content
+    PermissiveContentTransformations = 2,
+}
 
-    // Unified stream state
-    struct UnifiedState {
-        tsfn: ThreadsafeFunction<String, ErrorStrategy::CalleeHandled>,
-        _messages: CString,
-        _tools: Option<CString>,
-        _schema: Option<CString>,
+impl GuardrailSetting {
+    fn parse(setting: &str) -> napi::Result<Self> {
+        match setting {
+            "developerProvided" => Ok(Self::DeveloperProvided),
+            "default" => Ok(Self::Default),
+            "permissiveContentTransformations" => Ok(Self::PermissiveContentTransformations),
+            other => Err(napi::Error::new(
+                Status::InvalidArg,
+                format!(
+                    "guardrails must be 'developerProvided', 'default' or \
+                     'permissiveContentTransformations', got {other:?}"
+                ),
+            )),
+        }
     }
+}
 
-    static UNIFIED_STREAM: OnceLock<Mutex<Option<UnifiedState>>> = OnceLock::new();
-    let mutex = UNIFIED_STREAM.get_or_init(|| Mutex::new(None));
+/// Adapter handle and use case a request runs against. Adapters are trained
+/// on the general model, so they don't combine with another use case; the
+/// active adapter doesn't apply to such requests.
+fn model_choice(adapter: Option<&str>, use_case: Option<&str>) -> napi::Result<(u64, UseCase)> {
+    let use_case = use_case
+        .map(UseCase::parse)
+        .transpose()?
+        .unwrap_or_default();
+    if use_case == UseCase::General {
+        return Ok((adapters().lock().unwrap().handle(adapter)?, use_case));
+    }
+    if adapter.is_some() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "adapter cannot be combined with a useCase other than 'general'".to_string(),
+        ));
+    }
+    Ok((0, use_case))
+}
 
-    let c_messages = CString::new(messages_json)?;
-    let c_tools = tools_json
-        .filter(|s| !s.is_empty())
-        .map(|s| CString::new(s))
-        .transpose()?;
-    let c_schema = schema_json
-        .filter(|s| !s.is_empty())
-        .map(|s| CString::new(s))
-        .transpose()?;
+/// Static limits of the on-device model, for context budgeting in JS.
+#[napi(object)]
+pub struct ModelLimits {
+    /// Tokens the prompt and output share
+    pub context_window: u32,
+    /// Most tokens a single response can have
+    pub max_output_tokens: u32,
+    /// Whether the OS reported the context window; if not it's the documented default
+    pub reported: bool,
+    /// Whether `countTokens` uses the model's tokenizer rather than an estimate
+    pub exact_token_counts: bool,
+    pub supports_image_input: bool,
+}
 
-    {
-        let mut guard = mutex.lock().unwrap();
-        *guard = Some(UnifiedState {
-            tsfn: ts_fn.clone(),
-            _messages: c_messages.clone(),
-            _tools: c_tools.clone(),
-            _schema: c_schema.clone(),
-        });
+#[napi]
+pub fn get_model_limits() -> napi::Result<ModelLimits> {
+    ensure_initialized();
+    let raw = take_c_string(unsafe { apple_ai_model_limits() });
+    let json: serde_json::Value = serde_json::from_str(&raw).map_err(|e| {
+        napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+    })?;
+    let number = |key: &str| json.get(key).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    let flag = |key: &str| json.get(key).and_then(|v| v.as_bool()).unwrap_or(false);
+    Ok(ModelLimits {
+        context_window: number("contextWindow"),
+        max_output_tokens: number("maxOutputTokens"),
+        reported: flag("reported"),
+        exact_token_counts: flag("exactTokenCounts"),
+        supports_image_input: flag("supportsImageInput"),
+    })
+}
+
+// ---------------- Token counting ----------------
+
+/// Size of a prompt as the on-device model counts it.
+#[napi(object)]
+pub struct TokenCount {
+    pub tokens: u32,
+    /// Set on systems without tokenizer access, where the count is estimated
+    /// at about four characters per token
+    pub estimated: bool,
+}
+
+/// Token count of `text` from the Swift tokenizer (or its estimate). Blocks.
+fn count_text_tokens(text: &str) -> napi::Result<TokenCount> {
+    ensure_initialized();
+    let c_text = CString::new(text)
+        .map_err(|_| napi::Error::from_reason("Text contained null byte".to_string()))?;
+    let raw = take_c_string(unsafe { apple_ai_count_tokens(c_text.as_ptr()) });
+    if let Some(message) = raw.strip_prefix("Error: ") {
+        return Err(napi::Error::from_reason(message.to_string()));
     }
+    let json: serde_json::Value = serde_json::from_str(&raw).map_err(|e| {
+        napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+    })?;
+    Ok(TokenCount {
+        tokens: json.get("tokens").and_then(|t| t.as_u64()).unwrap_or(0) as u32,
+        estimated: json
+            .get("estimated")
+            .and_then(|e| e.as_bool())
+            .unwrap_or(true),
+    })
+}
 
-    extern "C" fn unified_chunk_cb(ptr: *const c_char) {
-        let mutex = UNIFIED_STREAM.get().unwrap();
-        let mut guard = mutex.lock().unwrap();
-        if let Some(state) = guard.as_mut() {
-            if ptr.is_null() {
-                // Send the end-of-stream signal to JavaScript
-                let _ = state
-                    .tsfn
-                    .call(Ok(String::new()), ThreadsafeFunctionCallMode::NonBlocking);
+pub struct CountTokensTask {
+    text: String,
+}
 
-                // Don't abort immediately - let the callback complete naturally
-                // The cleanup will happen when the state is dropped
-                *guard = None;
-                return;
-            }
+impl napi::Task for CountTokensTask {
+    type Output = TokenCount;
+    type JsValue = TokenCount;
 
-            // Take ownership and free C string
-            let slice_owned = take_c_string(ptr as *mut c_char);
-            if slice_owned.is_empty() {
-                return;
-            }
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        count_text_tokens(&self.text)
+    }
 
-            // Check for error sentinel
-            let bytes = slice_owned.as_bytes();
-            if !bytes.is_empty() && bytes[0] == ERROR_SENTINEL {
-                let msg = String::from_utf8_lossy(&bytes[1..]).into_owned();
-                let _ = state.tsfn.call(
-                    Err(napi::Error::from_reason(msg)),
-                    ThreadsafeFunctionCallMode::NonBlocking,
-                );
-                return;
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(coded_error(env, ErrorCode::of(&err), err.reason))
+    }
+}
+
+/// Count the tokens of a prompt text or of chat messages, the way usage is
+/// counted for `promptTokens`. Image content is not counted.
+#[napi(ts_return_type = "Promise<TokenCount>")]
+pub fn count_tokens(
+    input: Either<String, Vec<ChatMessage>>,
+) -> napi::Result<AsyncTask<CountTokensTask>> {
+    let text = match input {
+        Either::A(text) => text,
+        Either::B(messages) => {
+            let mut parts = Vec::new();
+            for (index, message) in messages.iter().enumerate() {
+                message.validate(index)?;
+                parts.extend(message.text());
+                for call in message.tool_calls.iter().flatten() {
+                    parts.push(format!(
+                        "{}({})",
+                        call.function.name, call.function.arguments
+                    ));
+                }
             }
+            parts.join("\n")
+        }
+    };
+    Ok(AsyncTask::new(CountTokensTask { text }))
+}
+
+// ---------------- Async generation tasks ----------------
+
+// ---------- Global tool handler state ----------
+
+type ToolCallbackFn = ThreadsafeFunction<(u64, String), ErrorStrategy::CalleeHandled>;
+/// Gets (call id, tool name, arguments JSON) before a call runs; answered
+/// with `toolApproval`
+type ToolApprovalFn = ThreadsafeFunction<(u64, String, String), ErrorStrategy::CalleeHandled>;
+
+// Async tool dispatcher - like streaming
+static TOOL_CALLBACK: OnceLock<Mutex<Option<ToolCallbackFn>>> = OnceLock::new();
+// Named handlers take precedence over the catch-all TOOL_CALLBACK
+static TOOL_REGISTRY: OnceLock<Mutex<HashMap<String, RegisteredTool>>> = OnceLock::new();
+// Handlers scoped to a single request take precedence over both
+static REQUEST_TOOLS: OnceLock<Mutex<HashMap<u64, RequestTools>>> = OnceLock::new();
+// Identifies each `registerTool` and `setToolCallback` handler, so results
+// of a replaced handler are never served for its successor
+static NEXT_REGISTRATION: AtomicU64 = AtomicU64::new(1);
+// Registration of the current catch-all handler; 0 when there is none
+static TOOL_CALLBACK_REGISTRATION: AtomicU64 = AtomicU64::new(0);
+// Successful tool results shared by requests that cache them, by `CacheScope::key`
+static TOOL_RESULT_CACHE: OnceLock<Mutex<HashMap<String, CachedToolResult>>> = OnceLock::new();
+
+/// A cached tool result, dropped once it outlives the TTL it was stored with.
+struct CachedToolResult {
+    result: String,
+    stored: Instant,
+    expires: Instant,
+}
+
+fn tool_result_cache() -> &'static Mutex<HashMap<String, CachedToolResult>> {
+    TOOL_RESULT_CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Cache the result of a successful call for `ttl`, evicting expired entries.
+fn cache_tool_result(key: String, result: &str, ttl: Duration) {
+    let now = Instant::now();
+    let mut cache = tool_result_cache().lock().unwrap();
+    cache.retain(|_, entry| entry.expires > now);
+    cache.insert(
+        key,
+        CachedToolResult {
+            result: result.to_string(),
+            stored: now,
+            expires: now + ttl,
+        },
+    );
+}
+
+/// A result cached for `key` within the last `ttl`, and still within the TTL
+/// it was stored with.
+fn cached_tool_result(key: &str, ttl: Duration) -> Option<String> {
+    let cache = tool_result_cache().lock().unwrap();
+    let entry = cache.get(key)?;
+    (entry.stored.elapsed() < ttl && entry.expires > Instant::now()).then(|| entry.result.clone())
+}
+
+/// Tool handlers, parameter schemas and in-flight calls belonging to one request.
+#[derive(Default)]
+struct RequestTools {
+    handlers: HashMap<String, ToolCallbackFn>,
+    schemas: HashMap<String, Arc<schema::Schema>>,
+    /// Tools answered in Rust, by name
+    native: HashMap<String, NativeTool>,
+    /// MCP tools, by name, with the server that answers them
+    mcp: McpTools,
+    /// Most handler calls allowed to run at once; `None` means unlimited
+    max_parallel: Option<usize>,
+    /// Native call ids handed to JS and not yet answered
+    active: HashSet<u64>,
+    /// Calls waiting for a free slot, started in arrival order
+    queued: VecDeque<PendingToolCall>,
+    /// Whether to keep `calls`; set for agent runs that replay tool outputs
+    record_calls: bool,
+    calls: Vec<ToolCallRecord>,
+    /// Timing of calls that haven't completed yet, by native call id
+    timings: HashMap<u64, CallTiming>,
+    /// Timing of completed calls, in completion order
+    traces: Vec<ToolCallTrace>,
+    /// How long successful results are reused; `None` disables the cache
+    cache_ttl: Option<Duration>,
+    /// How results are screened for prompt injection before the model gets them
+    result_scan: Option<ToolResultScan>,
+    /// Asked before each call runs, whichever kind of tool answers it
+    approval: Option<ToolApprovalFn>,
+    /// Calls waiting for their approval decision: native call id → (tool
+    /// name, arguments JSON)
+    awaiting_approval: HashMap<u64, (String, String)>,
+}
+
+struct CallTiming {
+    name: String,
+    args_bytes: usize,
+    dispatched: Instant,
+    started: Option<Instant>,
+    /// Key the result is cached under, when the request caches results
+    cache_key: Option<String>,
+    /// Whether the result came from the cache
+    cached: bool,
+}
+
+/// What answers a tool call, so results of different native tool
+/// configurations, MCP connections, per-request handlers or registrations
+/// never share a cache entry.
+enum CacheScope<'a> {
+    Native(&'a NativeTool),
+    /// An MCP connection, by `McpClient::connection_id`
+    Mcp(u64),
+    /// A handler passed with the request, by request id
+    Request(u64),
+    /// A `registerTool` handler or the catch-all handler, by registration
+    Registered(u64),
+}
 
-            let _ = state
-                .tsfn
-                .call(Ok(slice_owned), ThreadsafeFunctionCallMode::NonBlocking);
+impl<'a> CacheScope<'a> {
+    /// The scope of `name` for a request, checked in the order calls are
+    /// dispatched.
+    fn of(tools: &'a RequestTools, request_id: u64, name: &str) -> Self {
+        if let Some(tool) = tools.native.get(name) {
+            Self::Native(tool)
+        } else if let Some(server) = tools.mcp.get(name) {
+            Self::Mcp(server.connection_id())
+        } else if tools.handlers.contains_key(name) {
+            Self::Request(request_id)
+        } else {
+            Self::Registered(registration(name))
         }
     }
 
-    unsafe {
-        apple_ai_generate_unified(
-            c_messages.as_ptr(),
-            c_tools.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
-            c_schema.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
-            temperature.unwrap_or(0.0) as c_double,
-            max_tokens.unwrap_or(0) as c_int,
-            true,                                  // streaming
-            stop_after_tool_calls.unwrap_or(true), // default to true
-            Some(unified_chunk_cb),
-        );
+    /// Cache key for a call: the scope, the tool name, and its arguments
+    /// reserialized, which sorts object keys so equivalent argument objects
+    /// share an entry.
+    fn key(&self, name: &str, args_json: &str) -> String {
+        let args = serde_json::from_str::<serde_json::Value>(args_json)
+            .map(|v| v.to_string())
+            .unwrap_or_else(|_| args_json.to_string());
+        let scope = match self {
+            Self::Native(tool) => format!("native {tool:?}"),
+            Self::Mcp(connection) => format!("mcp {connection}"),
+            Self::Request(request_id) => format!("request {request_id}"),
+            Self::Registered(registration) => format!("registration {registration}"),
+        };
+        format!("{scope}\0{name}\0{args}")
+    }
+}
+
+/// A tool call made during a request and the output the model received.
+struct ToolCallRecord {
+    call_id: u64,
+    name: String,
+    args_json: String,
+    output: Option<String>,
+}
+
+/// A tool call routed to its JS handler but not necessarily started yet.
+struct PendingToolCall {
+    call_id: u64,
+    tsfn: ToolCallbackFn,
+    args_json: String,
+}
+
+impl PendingToolCall {
+    fn start(self) {
+        // Call JS side async, swallow any error to avoid unwinding across FFI
+        let Self {
+            call_id,
+            tsfn,
+            args_json,
+        } = self;
+        mark_tool_started(call_id);
+        let status = std::panic::catch_unwind(|| {
+            tsfn.call(
+                Ok((call_id, args_json)),
+                ThreadsafeFunctionCallMode::NonBlocking,
+            )
+        });
+        if !matches!(status, Ok(Status::Ok)) {
+            fail_tool_call(call_id, "Tool handler could not be called");
+        }
+    }
+}
+
+/// Start `call` now, or queue it if its request is at its parallelism limit.
+fn start_tool_call(request_id: u64, call: PendingToolCall) {
+    let ready = {
+        let mut requests = request_tools().lock().unwrap();
+        match requests.get_mut(&request_id) {
+            Some(tools)
+                if tools
+                    .max_parallel
+                    .is_some_and(|max| tools.active.len() >= max) =>
+            {
+                tools.queued.push_back(call);
+                None
+            }
+            Some(tools) => {
+                tools.active.insert(call.call_id);
+                Some(call)
+            }
+            None => Some(call),
+        }
+    };
+    if let Some(call) = ready {
+        call.start();
+    }
+}
+
+/// Start timing a tool call, and remember it for requests that record calls.
+/// Returns the cached result if an identical call succeeded within the TTL.
+fn record_tool_call(request_id: u64, call_id: u64, name: &str, args_json: &str) -> Option<String> {
+    let mut requests = request_tools().lock().unwrap();
+    let tools = requests.get_mut(&request_id)?;
+    let cache_key = tools
+        .cache_ttl
+        .map(|_| CacheScope::of(tools, request_id, name).key(name, args_json));
+    let cached = cache_key
+        .as_deref()
+        .zip(tools.cache_ttl)
+        .and_then(|(key, ttl)| cached_tool_result(key, ttl));
+    tools.timings.insert(
+        call_id,
+        CallTiming {
+            name: name.to_string(),
+            args_bytes: args_json.len(),
+            dispatched: Instant::now(),
+            started: None,
+            cache_key,
+            cached: cached.is_some(),
+        },
+    );
+    if tools.record_calls {
+        tools.calls.push(ToolCallRecord {
+            call_id,
+            name: name.to_string(),
+            args_json: args_json.to_string(),
+            output: None,
+        });
+    }
+    cached
+}
+
+/// Note that `call_id` left the queue and was handed to its handler.
+fn mark_tool_started(call_id: u64) {
+    let mut requests = request_tools().lock().unwrap();
+    if let Some(timing) = requests
+        .values_mut()
+        .find_map(|tools| tools.timings.get_mut(&call_id))
+    {
+        timing.started.get_or_insert_with(Instant::now);
+    }
+}
+
+/// Timing of the tool calls completed for `request_id` since the last call.
+fn take_tool_traces(request_id: u64) -> Option<Vec<ToolCallTrace>> {
+    request_tools()
+        .lock()
+        .unwrap()
+        .get_mut(&request_id)
+        .map(|tools| std::mem::take(&mut tools.traces))
+        .filter(|traces| !traces.is_empty())
+}
+
+/// Tool calls recorded for `request_id` since the last call.
+fn take_tool_calls(request_id: u64) -> Vec<ToolCallRecord> {
+    request_tools()
+        .lock()
+        .unwrap()
+        .get_mut(&request_id)
+        .map(|tools| std::mem::take(&mut tools.calls))
+        .unwrap_or_default()
+}
+
+/// Screen the successful result of `call_id` for prompt injection, if its
+/// request asks to: `Ok` with the output to pass on instead, if any, or `Err`
+/// with the message to fail the call with.
+fn screen_tool_result(
+    call_id: u64,
+    result_json: &str,
+) -> std::result::Result<Option<String>, String> {
+    let scan = request_tools()
+        .lock()
+        .unwrap()
+        .values()
+        .find(|tools| tools.timings.contains_key(&call_id))
+        .and_then(|tools| tools.result_scan);
+    match scan {
+        Some(scan) => scan.screen(result_json),
+        None => Ok(None),
+    }
+}
+
+/// Note the output of `call_id`, free its slot and start the next queued
+/// call, if any. Runs before Swift is resumed, so the output is recorded by
+/// the time the generation returns.
+fn complete_tool_call(call_id: u64, output: &str, failed: bool) {
+    let next = {
+        let mut requests = request_tools().lock().unwrap();
+        for tools in requests.values_mut() {
+            tools.awaiting_approval.remove(&call_id);
+            if let Some(record) = tools.calls.iter_mut().find(|c| c.call_id == call_id) {
+                record.output.get_or_insert_with(|| output.to_string());
+            }
+            if let Some(timing) = tools.timings.remove(&call_id) {
+                let started = timing.started.unwrap_or(timing.dispatched);
+                if let Some((key, ttl)) = timing
+                    .cache_key
+                    .zip(tools.cache_ttl)
+                    .filter(|_| !failed && !timing.cached)
+                {
+                    cache_tool_result(key, output, ttl);
+                }
+                tools.traces.push(ToolCallTrace {
+                    tool_name: timing.name,
+                    args_bytes: timing.args_bytes as u32,
+                    result_bytes: output.len() as u32,
+                    queue_wait_ms: started.duration_since(timing.dispatched).as_secs_f64() * 1000.0,
+                    execution_ms: started.elapsed().as_secs_f64() * 1000.0,
+                    failed,
+                    cached: timing.cached,
+                });
+            }
+        }
+        requests
+            .values_mut()
+            .find_map(|tools| tools.active.remove(&call_id).then_some(tools))
+            .and_then(|tools| {
+                let next = tools.queued.pop_front()?;
+                tools.active.insert(next.call_id);
+                Some(next)
+            })
+    };
+    if let Some(next) = next {
+        next.start();
+    }
+}
+
+fn tool_callback() -> &'static Mutex<Option<ToolCallbackFn>> {
+    TOOL_CALLBACK.get_or_init(|| Mutex::new(None))
+}
+
+/// A process-wide tool from `registerTool`.
+struct RegisteredTool {
+    handler: ToolCallbackFn,
+    /// From `NEXT_REGISTRATION`; registering the name again gets a new one
+    registration: u64,
+    /// `{ name, description, parameters }`, when registered with a definition;
+    /// needed for requests to expose the tool by name
+    definition: Option<serde_json::Value>,
+}
+
+fn tool_registry() -> &'static Mutex<HashMap<String, RegisteredTool>> {
+    TOOL_REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Registration of the handler that answers `name` outside of requests: its
+/// `registerTool` handler, else the catch-all.
+fn registration(name: &str) -> u64 {
+    let registry = tool_registry().lock().unwrap();
+    registry.get(name).map_or_else(
+        || TOOL_CALLBACK_REGISTRATION.load(Ordering::Relaxed),
+        |tool| tool.registration,
+    )
+}
+
+fn request_tools() -> &'static Mutex<HashMap<u64, RequestTools>> {
+    REQUEST_TOOLS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Keeps a request's own tool handlers and schemas routable until dropped.
+struct RequestToolsGuard {
+    request_id: u64,
+}
+
+impl RequestToolsGuard {
+    fn install(request_id: u64, request: &GenerationRequest) -> Option<Self> {
+        if request.tools_json.is_none() && request.tool_handlers.is_empty() {
+            return None;
+        }
+        let tools = RequestTools {
+            handlers: request.tool_handlers.clone(),
+            schemas: request.tool_schemas.clone(),
+            native: request
+                .native_tools
+                .iter()
+                .map(|tool| (tool.name().to_string(), tool.clone()))
+                .collect(),
+            mcp: request.mcp_tools.clone(),
+            max_parallel: request.max_parallel_tool_calls,
+            cache_ttl: request.tool_cache_ttl,
+            record_calls: request.record_tool_calls,
+            result_scan: request.tool_result_scan,
+            approval: request.tool_approval.clone(),
+            ..Default::default()
+        };
+        request_tools().lock().unwrap().insert(request_id, tools);
+        Some(Self { request_id })
+    }
+}
+
+impl Drop for RequestToolsGuard {
+    fn drop(&mut self) {
+        request_tools().lock().unwrap().remove(&self.request_id);
+    }
+}
+
+fn create_tool_tsfn(callback: JsFunction) -> napi::Result<ToolCallbackFn> {
+    callback.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<(u64, String)>| {
+        let env = ctx.env;
+        let (tool_id, args_json) = ctx.value;
+        let js_tool_id = env.create_uint32(tool_id as u32)?;
+        let js_args = env.create_string(&args_json)?;
+        Ok(vec![js_tool_id.into_unknown(), js_args.into_unknown()])
+    })
+}
+
+fn create_tool_approval_tsfn(callback: JsFunction) -> napi::Result<ToolApprovalFn> {
+    callback.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<(u64, String, String)>| {
+        let env = ctx.env;
+        let (tool_id, tool_name, args_json) = ctx.value;
+        Ok(vec![
+            env.create_uint32(tool_id as u32)?.into_unknown(),
+            env.create_string(&tool_name)?.into_unknown(),
+            env.create_string(&args_json)?.into_unknown(),
+        ])
+    })
+}
+
+/// Catch-all handler for tools without a handler from `registerTool`.
+#[napi]
+pub fn set_tool_callback(callback: JsFunction) -> napi::Result<()> {
+    // Replace any existing callback atomically
+    let tsfn = create_tool_tsfn(callback)?;
+
+    let mut guard = tool_callback().lock().unwrap();
+    if let Some(old) = guard.take() {
+        let _ = old.abort();
+    }
+    *guard = Some(tsfn);
+    TOOL_CALLBACK_REGISTRATION.store(
+        NEXT_REGISTRATION.fetch_add(1, Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+    Ok(())
+}
+
+#[napi]
+pub fn clear_tool_callback() -> napi::Result<()> {
+    let mut guard = tool_callback().lock().unwrap();
+    if let Some(tsfn) = guard.take() {
+        let _ = tsfn.abort();
+    }
+    TOOL_CALLBACK_REGISTRATION.store(0, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Route calls of the tool named `name` to `callback`, replacing any previous
+/// handler for that name. The handler answers with `toolResult(toolId, json)`;
+/// `toolId` identifies the individual call, so concurrent calls don't clash.
+///
+/// With `definition_json` (`{ description, parameters }`), requests can expose
+/// the tool to the model by listing its name in `registeredTools`.
+#[napi]
+pub fn register_tool(
+    name: String,
+    #[napi(ts_arg_type = "(err: Error | null, toolId: number, argsJson: string) => void")]
+    callback: JsFunction,
+    definition_json: Option<String>,
+) -> napi::Result<()> {
+    let definition = definition_json
+        .map(|json| {
+            let mut definition: serde_json::Value = serde_json::from_str(&json).map_err(|e| {
+                napi::Error::new(Status::InvalidArg, format!("Invalid tool definition: {e}"))
+            })?;
+            let Some(fields) = definition.as_object_mut() else {
+                return Err(napi::Error::new(
+                    Status::InvalidArg,
+                    "Tool definition must be a JSON object".to_string(),
+                ));
+            };
+            fields.insert("name".to_string(), name.clone().into());
+            Ok(definition)
+        })
+        .transpose()?;
+    let tool = RegisteredTool {
+        handler: create_tool_tsfn(callback)?,
+        registration: NEXT_REGISTRATION.fetch_add(1, Ordering::Relaxed),
+        definition,
+    };
+    if let Some(old) = tool_registry().lock().unwrap().insert(name, tool) {
+        let _ = old.handler.abort();
+    }
+    Ok(())
+}
+
+/// Remove the handler registered for `name`. Returns whether one existed.
+#[napi]
+pub fn unregister_tool(name: String) -> bool {
+    match tool_registry().lock().unwrap().remove(&name) {
+        Some(tool) => {
+            let _ = tool.handler.abort();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Answer the tool call identified by `tool_id` (the id passed to the tool
+/// handler). Generation resumes with `result_json` as the tool's output.
+#[napi]
+pub fn tool_result(tool_id: f64, result_json: String) -> napi::Result<()> {
+    let call_id = tool_id as u64;
+    // Calls from MCP clients aren't part of a generation
+    if mcp_server::answer_call(call_id, Ok(&result_json)) {
+        return Ok(());
+    }
+    let result_json = match screen_tool_result(call_id, &result_json) {
+        Ok(screened) => screened.unwrap_or(result_json),
+        Err(message) => {
+            fail_tool_call(call_id, &message);
+            return Ok(());
+        }
+    };
+    let c_result = CString::new(result_json.as_str())
+        .map_err(|_| napi::Error::from_reason("Tool result contained null byte".to_string()))?;
+    emit_tool_result_event(call_id, &result_json);
+    complete_tool_call(call_id, &result_json, false);
+    // Resumes the suspended Swift tool call; unknown or already answered ids are ignored
+    unsafe { apple_ai_tool_result_callback(call_id, c_result.as_ptr()) };
+    Ok(())
+}
+
+/// Fail the tool call identified by `tool_id`. The model receives `message` as
+/// the tool's error output and can recover or explain the failure.
+#[napi]
+pub fn tool_error(tool_id: f64, message: String) -> napi::Result<()> {
+    let call_id = tool_id as u64;
+    if mcp_server::answer_call(call_id, Err(&message)) {
+        return Ok(());
+    }
+    let c_message = CString::new(message.as_str())
+        .map_err(|_| napi::Error::from_reason("Tool error contained null byte".to_string()))?;
+    let output = tool_error_json(&message);
+    emit_tool_result_event(call_id, &output);
+    complete_tool_call(call_id, &output, true);
+    unsafe { apple_ai_tool_error_callback(call_id, c_message.as_ptr()) };
+    Ok(())
+}
+
+/// Answer the approval check of the tool call identified by `tool_id`:
+/// `{ "type": "approve" }` runs it, `{ "type": "modify", "args" }` runs it
+/// with other arguments, and `{ "type": "deny", "reason"? }` reports it to
+/// the model as declined without running it.
+#[napi]
+pub fn tool_approval(tool_id: f64, decision_json: String) -> napi::Result<()> {
+    let call_id = tool_id as u64;
+    let decision: serde_json::Value = serde_json::from_str(&decision_json).map_err(|e| {
+        napi::Error::new(
+            Status::InvalidArg,
+            format!("Invalid approval decision: {e}"),
+        )
+    })?;
+    let modified_args = match decision["type"].as_str() {
+        Some("approve" | "deny") => None,
+        Some("modify") if decision["args"].is_object() => Some(decision["args"].to_string()),
+        _ => {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "Approval decision must be approve, deny, or modify with an args object"
+                    .to_string(),
+            ))
+        }
+    };
+    // Unknown or already decided ids are ignored
+    let Some((request_id, (tool_name, original_args))) = request_tools()
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .find_map(|(request_id, tools)| {
+            Some((*request_id, tools.awaiting_approval.remove(&call_id)?))
+        })
+    else {
+        return Ok(());
+    };
+    if decision["type"] == "deny" {
+        let reason = decision["reason"]
+            .as_str()
+            .unwrap_or("The user declined this tool call");
+        let output = serde_json::json!({ "declined": true, "reason": reason }).to_string();
+        record_tool_call(request_id, call_id, &tool_name, &original_args);
+        emit_tool_result_event(call_id, &output);
+        // Reported as failed so the declined output is never cached
+        answer_tool_call(call_id, &output, true);
+        return Ok(());
+    }
+    let args_json = modified_args.unwrap_or(original_args);
+    dispatch_tool_call(request_id, call_id, tool_name, args_json);
+    Ok(())
+}
+
+/// The `tool-result` payload reported for a failed tool call.
+fn tool_error_json(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+fn ensure_tool_callback_registered() {
+    static ONCE: std::sync::Once = std::sync::Once::new();
+    ONCE.call_once(|| unsafe {
+        apple_ai_register_tool_callback(Some(js_tool_dispatch));
+    });
+}
+
+fn answer_tool_call(call_id: u64, result_json: &str, failed: bool) {
+    complete_tool_call(call_id, result_json, failed);
+    let c_result = CString::new(result_json).unwrap_or_default();
+    unsafe { apple_ai_tool_result_callback(call_id, c_result.as_ptr()) };
+}
+
+fn fail_tool_call(call_id: u64, message: &str) {
+    let output = tool_error_json(message);
+    emit_tool_result_event(call_id, &output);
+    complete_tool_call(call_id, &output, true);
+    let c_message = CString::new(message).unwrap_or_default();
+    unsafe { apple_ai_tool_error_callback(call_id, c_message.as_ptr()) };
+}
+
+/// Answer a call to a tool implemented in Rust (native or MCP). These may do
+/// I/O, so the call runs off the Swift thread.
+fn run_rust_tool_call(
+    call_id: u64,
+    args_json: &str,
+    call: impl FnOnce(serde_json::Value) -> std::result::Result<String, String> + Send + 'static,
+) {
+    let args = serde_json::from_str(args_json).unwrap_or_else(|_| serde_json::json!({}));
+    std::thread::spawn(move || {
+        mark_tool_started(call_id);
+        match call(args)
+            .and_then(|result| Ok(screen_tool_result(call_id, &result)?.unwrap_or(result)))
+        {
+            Ok(result) => {
+                emit_tool_result_event(call_id, &result);
+                answer_tool_call(call_id, &result, false);
+            }
+            Err(message) => fail_tool_call(call_id, &message),
+        }
+    });
+}
+
+/// Called by Swift for every tool invocation. Hands the call to the JS
+/// handler (or queues it behind the request's `maxParallelToolCalls` limit)
+/// and returns immediately; Swift stays suspended (without holding a
+/// thread) until `tool_result` delivers the answer for `call_id`. A request
+/// with a `toolApproval` hook has it decide each call first.
+extern "C" fn js_tool_dispatch(
+    request_id: u64,
+    call_id: u64,
+    _tool_id: u64,
+    tool_name: *const c_char,
+    args_json: *const c_char,
+) {
+    ensure_initialized();
+
+    let tool_name = unsafe {
+        if tool_name.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(tool_name).to_string_lossy().into_owned()
+        }
+    };
+    let args_json = unsafe {
+        if args_json.is_null() {
+            "{}".to_string()
+        } else {
+            CStr::from_ptr(args_json).to_string_lossy().into_owned()
+        }
+    };
+
+    emit_tool_call_event(request_id, call_id, &tool_name, &args_json);
+    let approval = request_tools()
+        .lock()
+        .unwrap()
+        .get_mut(&request_id)
+        .and_then(|tools| {
+            let approval = tools.approval.clone()?;
+            tools
+                .awaiting_approval
+                .insert(call_id, (tool_name.clone(), args_json.clone()));
+            Some(approval)
+        });
+    match approval {
+        Some(approval) => {
+            let status = std::panic::catch_unwind(|| {
+                approval.call(
+                    Ok((call_id, tool_name, args_json)),
+                    ThreadsafeFunctionCallMode::NonBlocking,
+                )
+            });
+            if !matches!(status, Ok(Status::Ok)) {
+                fail_tool_call(call_id, "Tool approval handler could not be called");
+            }
+        }
+        None => dispatch_tool_call(request_id, call_id, tool_name, args_json),
+    }
+}
+
+/// Run an approved tool call: answer it from the cache, or route it by name
+/// to request-scoped handlers, then registered tools, then the catch-all.
+/// Arguments that don't match the tool's parameter schema never reach the
+/// handler; the model gets the validation errors back as the tool output
+/// instead.
+fn dispatch_tool_call(request_id: u64, call_id: u64, tool_name: String, args_json: String) {
+    let (handler, schema, native, mcp) = request_tools()
+        .lock()
+        .unwrap()
+        .get(&request_id)
+        .map(|tools| {
+            (
+                tools.handlers.get(&tool_name).cloned(),
+                tools.schemas.get(&tool_name).cloned(),
+                tools.native.get(&tool_name).cloned(),
+                tools.mcp.get(&tool_name).cloned(),
+            )
+        })
+        .unwrap_or_default();
+    let handler = handler
+        .or_else(|| {
+            let registry = tool_registry().lock().unwrap();
+            registry.get(&tool_name).map(|tool| tool.handler.clone())
+        })
+        .or_else(|| tool_callback().lock().unwrap().clone());
+    if let Some(result) = record_tool_call(request_id, call_id, &tool_name, &args_json) {
+        emit_tool_result_event(call_id, &result);
+        answer_tool_call(call_id, &result, false);
+        return;
+    }
+
+    if let Some(schema) = schema {
+        let errors = match serde_json::from_str(&args_json) {
+            Ok(args) => schema.validate(&args),
+            Err(e) => vec![schema::ValidationError {
+                path: String::new(),
+                message: format!("arguments are not valid JSON: {e}"),
+            }],
+        };
+        if !errors.is_empty() {
+            let result = schema::invalid_arguments_result(&tool_name, &errors);
+            emit_tool_result_event(call_id, &result);
+            answer_tool_call(call_id, &result, true);
+            return;
+        }
+    }
+
+    if let Some(tool) = native {
+        run_rust_tool_call(call_id, &args_json, move |args| tool.call(&args));
+        return;
+    }
+    if let Some(server) = mcp {
+        run_rust_tool_call(call_id, &args_json, move |args| {
+            server.call_tool(&tool_name, args)
+        });
+        return;
+    }
+
+    let Some(tsfn) = handler else {
+        // Nobody will answer; resume the model right away
+        answer_tool_call(call_id, "{}", false);
+        return;
+    };
+
+    start_tool_call(
+        request_id,
+        PendingToolCall {
+            call_id,
+            tsfn,
+            args_json,
+        },
+    );
+}
+
+// ---------------- Request tracking / cancellation ----------------
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Allocate a process-unique id used to address a generation on the Swift side.
+fn next_request_id() -> u64 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+fn cancelled_error() -> napi::Error {
+    napi::Error::new(Status::Cancelled, "Generation cancelled".to_string())
+}
+
+fn timeout_message(timeout: Duration) -> String {
+    format!("Generation timed out after {} ms", timeout.as_millis())
+}
+
+// ---------------- Timeouts ----------------
+
+/// Runs `on_timeout` on a helper thread unless dropped before `timeout` elapses.
+struct Watchdog {
+    _disarm: mpsc::Sender<()>,
+}
+
+impl Watchdog {
+    fn arm(timeout: Duration, on_timeout: impl FnOnce() + Send + 'static) -> Self {
+        let (disarm, disarmed) = mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = disarmed.recv_timeout(timeout) {
+                on_timeout();
+            }
+        });
+        Self { _disarm: disarm }
+    }
+}
+
+// ---------------- Streaming events ----------------
+
+/// A single event delivered to the streaming callback.
+#[napi(object)]
+#[derive(Default)]
+pub struct StreamEvent {
+    #[napi(
+        js_name = "type",
+        ts_type = "'text-delta' | 'object-delta' | 'tool-call-start' | 'tool-call-delta' | 'tool-call' | 'tool-result' | 'finish' | 'error'"
+    )]
+    pub kind: String,
+    /// Text delta for `text-delta` events
+    pub text: Option<String>,
+    /// Structured output generated so far for `object-delta` events, and the
+    /// final object on the `finish` event. Fields fill in as generation goes on
+    pub object: Option<serde_json::Value>,
+    /// Identifies a tool call across its `tool-call` and `tool-result` events
+    pub tool_call_id: Option<String>,
+    pub tool_name: Option<String>,
+    /// Tool arguments as a JSON string
+    pub args: Option<String>,
+    /// Tool arguments JSON for `tool-call-delta` events. The model doesn't
+    /// stream arguments, so this is always the whole JSON, in one delta
+    pub args_delta: Option<String>,
+    /// Tool result as a JSON string
+    pub result: Option<String>,
+    /// Error message for `error` events
+    pub message: Option<String>,
+    /// Error code for `error` events, e.g. `Timeout`; see `ErrorCode`
+    pub code: Option<String>,
+    /// Whether retrying the same request later may succeed, for `error` events
+    pub retryable: Option<bool>,
+    /// Details for `GuardrailViolation` errors
+    pub guardrail: Option<GuardrailViolation>,
+    /// Why generation ended, reported on the `finish` event
+    #[napi(ts_type = "'stop' | 'length' | 'tool_calls' | 'content_filter'")]
+    pub finish_reason: Option<String>,
+    /// Token usage, reported on the `finish` event
+    pub usage: Option<TokenUsage>,
+    /// Latency metrics, reported on the `finish` event
+    pub metrics: Option<GenerationMetrics>,
+    /// Seed used for random sampling, reported on the `finish` event; drawn
+    /// at random when the request set none
+    pub seed: Option<u32>,
+    /// Timing of each tool call, reported on the `finish` event
+    pub tool_traces: Option<Vec<ToolCallTrace>>,
+}
+
+impl StreamEvent {
+    fn new(kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn text_delta(text: String) -> Self {
+        Self {
+            text: Some(text),
+            ..Self::new("text-delta")
+        }
+    }
+
+    fn object_delta(object: serde_json::Value) -> Self {
+        Self {
+            object: Some(object),
+            ..Self::new("object-delta")
+        }
+    }
+
+    fn tool_call_start(tool_call_id: String, tool_name: String) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id),
+            tool_name: Some(tool_name),
+            ..Self::new("tool-call-start")
+        }
+    }
+
+    fn tool_call_delta(tool_call_id: String, args_delta: String) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id),
+            args_delta: Some(args_delta),
+            ..Self::new("tool-call-delta")
+        }
+    }
+
+    fn tool_call(tool_call_id: String, tool_name: String, args: String) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id),
+            tool_name: Some(tool_name),
+            args: Some(args),
+            ..Self::new("tool-call")
+        }
+    }
+
+    fn tool_result(tool_call_id: String, tool_name: String, result: String) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id),
+            tool_name: Some(tool_name),
+            result: Some(result),
+            ..Self::new("tool-result")
+        }
+    }
+
+    fn finish(
+        finish_reason: &str,
+        usage: Option<TokenUsage>,
+        metrics: GenerationMetrics,
+        seed: Option<u32>,
+    ) -> Self {
+        Self {
+            finish_reason: Some(finish_reason.to_string()),
+            usage,
+            metrics: Some(metrics),
+            seed,
+            ..Self::new("finish")
+        }
+    }
+
+    fn error(code: ErrorCode, message: String) -> Self {
+        Self {
+            message: Some(message),
+            code: Some(code.as_str().to_string()),
+            retryable: Some(code.retryable()),
+            ..Self::new("error")
+        }
+    }
+
+    fn timeout(timeout: Duration) -> Self {
+        Self::error(ErrorCode::Timeout, timeout_message(timeout))
+    }
+}
+
+pub type StreamEventFn = ThreadsafeFunction<StreamEvent, ErrorStrategy::CalleeHandled>;
+
+/// Where a stream's events go: a JS callback, or a channel for streams
+/// consumed in Rust, such as the HTTP server's.
+pub enum StreamSink {
+    Js(StreamEventFn),
+    Channel(mpsc::Sender<StreamEvent>),
+}
+
+// Unified stream state
+struct UnifiedState {
+    sink: StreamSink,
+    request_id: u64,
+    seed: Option<u32>,
+    started: Instant,
+    first_token_at: Option<Instant>,
+    stop: StopMatcher,
+    /// A stop sequence was reached and Swift cancelled; the stream finishes
+    /// when Swift ends, once it has recorded the usage
+    stop_reached: bool,
+    /// Native call id → (stream call id, tool name) for calls awaiting a result
+    pending_calls: HashMap<u64, (String, String)>,
+    saw_tool_call: bool,
+    /// Latest structured object snapshot, reported again on `finish`
+    object: Option<serde_json::Value>,
+    /// Ends the stream with a `Timeout` error; disarmed when the state is dropped
+    _watchdog: Option<Watchdog>,
+    /// Request-scoped tool handlers, unregistered when the state is dropped
+    _tools: Option<RequestToolsGuard>,
+}
+
+impl UnifiedState {
+    fn send(&self, event: StreamEvent) {
+        match &self.sink {
+            StreamSink::Js(tsfn) => {
+                let _ = tsfn.call(Ok(event), ThreadsafeFunctionCallMode::NonBlocking);
+            }
+            StreamSink::Channel(sender) => {
+                let _ = sender.send(event);
+            }
+        }
+    }
+
+    fn finish_event(&self) -> StreamEvent {
+        let (usage, length) = match take_usage(self.request_id) {
+            Some((usage, reason)) => (Some(usage), reason == "length"),
+            None => (None, false),
+        };
+        let finish_reason = if self.saw_tool_call {
+            "tool_calls"
+        } else if length {
+            "length"
+        } else {
+            "stop"
+        };
+        let metrics = GenerationMetrics::new(self.started, self.first_token_at, usage.as_ref());
+        StreamEvent {
+            object: self.object.clone(),
+            tool_traces: take_tool_traces(self.request_id),
+            ..StreamEvent::finish(finish_reason, usage, metrics, self.seed)
+        }
+    }
+}
+
+// Active streams by request id; Swift passes the id with every chunk
+static UNIFIED_STREAMS: OnceLock<Mutex<HashMap<u64, UnifiedState>>> = OnceLock::new();
+static NEXT_TOOL_CALL_ID: AtomicU64 = AtomicU64::new(1);
+
+fn unified_streams() -> &'static Mutex<HashMap<u64, UnifiedState>> {
+    UNIFIED_STREAMS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Report a tool invocation to its request's stream, if it is streamed, as
+/// `tool-call-start`, `tool-call-delta` and `tool-call` events.
+///
+/// The arguments are not streamed: FoundationModels only snapshots the
+/// response content, and hands a tool its arguments once they are complete.
+/// All three events are therefore sent together when the tool is invoked,
+/// with the whole arguments JSON in the single delta. They exist so
+/// consumers of the AI SDK and OpenAI stream shapes see the parts they expect.
+fn emit_tool_call_event(request_id: u64, native_call_id: u64, tool_name: &str, args_json: &str) {
+    let mut streams = unified_streams().lock().unwrap();
+    if let Some(state) = streams.get_mut(&request_id) {
+        let tool_name = tool_name.to_string();
+        let call_id = format!("call_{}", NEXT_TOOL_CALL_ID.fetch_add(1, Ordering::Relaxed));
+        state
+            .pending_calls
+            .insert(native_call_id, (call_id.clone(), tool_name.clone()));
+        state.saw_tool_call = true;
+        let events = [
+            StreamEvent::tool_call_start(call_id.clone(), tool_name.clone()),
+            StreamEvent::tool_call_delta(call_id.clone(), args_json.to_string()),
+            StreamEvent::tool_call(call_id, tool_name, args_json.to_string()),
+        ];
+        for event in events {
+            state.send(event);
+        }
+    }
+}
+
+/// Report a tool result to the stream the call originated in, if any.
+fn emit_tool_result_event(native_call_id: u64, result_json: &str) {
+    let mut streams = unified_streams().lock().unwrap();
+    for state in streams.values_mut() {
+        if let Some((call_id, tool_name)) = state.pending_calls.remove(&native_call_id) {
+            state.send(StreamEvent::tool_result(
+                call_id,
+                tool_name,
+                result_json.to_string(),
+            ));
+            return;
+        }
+    }
+}
+
+// ---------------- Generation results ----------------
+
+/// Function name and JSON-encoded arguments of a tool call.
+#[napi(object)]
+#[derive(Clone)]
+pub struct ToolCallFunction {
+    pub name: String,
+    pub arguments: String,
+}
+
+/// A tool call requested by the model, in OpenAI-compatible shape.
+#[napi(object)]
+#[derive(Clone)]
+pub struct ToolCall {
+    pub id: String,
+    #[napi(js_name = "type", ts_type = "'function'")]
+    pub kind: String,
+    pub function: ToolCallFunction,
+}
+
+/// Token counts for a single request.
+#[napi(object)]
+#[derive(Clone)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+/// Fetch (and clear) the token usage Swift recorded for a request, with
+/// its finish reason: `length` when an exact count shows `maxTokens` cut it
+/// off, else `stop`.
+fn take_usage(request_id: u64) -> Option<(TokenUsage, String)> {
+    let raw = take_c_string(unsafe { apple_ai_take_usage(request_id) });
+    let json: serde_json::Value = serde_json::from_str(&raw).ok()?;
+    let prompt_tokens = json.get("promptTokens")?.as_u64()? as u32;
+    let completion_tokens = json.get("completionTokens")?.as_u64()? as u32;
+    let finish_reason = json
+        .get("finishReason")
+        .and_then(|r| r.as_str())
+        .unwrap_or("stop");
+    Some((
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        },
+        finish_reason.to_string(),
+    ))
+}
+
+/// Latency measured on the Rust side around the native call.
+#[napi(object)]
+#[derive(Clone)]
+pub struct GenerationMetrics {
+    /// Time until the first text chunk arrived; only known for streaming requests
+    pub time_to_first_token_ms: Option<f64>,
+    /// Wall-clock time of the whole request
+    pub total_ms: f64,
+    /// Completion tokens per second of wall-clock time
+    pub tokens_per_second: Option<f64>,
+}
+
+impl GenerationMetrics {
+    fn new(started: Instant, first_token_at: Option<Instant>, usage: Option<&TokenUsage>) -> Self {
+        let elapsed = started.elapsed().as_secs_f64();
+        Self {
+            time_to_first_token_ms: first_token_at
+                .map(|at| at.duration_since(started).as_secs_f64() * 1000.0),
+            total_ms: elapsed * 1000.0,
+            tokens_per_second: usage
+                .filter(|_| elapsed > 0.0)
+                .map(|u| u.completion_tokens as f64 / elapsed),
+        }
+    }
+}
+
+/// Timing of a single tool call, for finding slow tools.
+#[napi(object)]
+#[derive(Clone)]
+pub struct ToolCallTrace {
+    pub tool_name: String,
+    /// Size of the arguments JSON in bytes
+    pub args_bytes: u32,
+    /// Size of the result JSON in bytes
+    pub result_bytes: u32,
+    /// Time spent waiting for a free slot under `maxParallelToolCalls`
+    pub queue_wait_ms: f64,
+    /// Time from handing the call to its handler until the result arrived
+    pub execution_ms: f64,
+    /// Whether the handler failed or the arguments were rejected
+    pub failed: bool,
+    /// Whether the result was served from the tool result cache
+    pub cached: bool,
+}
+
+/// Final result of a non-streaming generation.
+#[napi(object)]
+#[derive(Clone)]
+pub struct GenerationResult {
+    pub text: String,
+    /// Parsed object for structured (schema) generation
+    pub object: Option<serde_json::Value>,
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[napi(ts_type = "'stop' | 'length' | 'tool_calls' | 'content_filter'")]
+    pub finish_reason: String,
+    pub usage: Option<TokenUsage>,
+    pub metrics: GenerationMetrics,
+    /// Seed used for random sampling, drawn at random when the request set
+    /// none; pass it back as `seed` to reproduce this run. Absent for greedy
+    /// sampling
+    pub seed: Option<u32>,
+    /// Every candidate when `n` > 1. The top-level fields mirror the first
+    /// candidate, except `usage` and `metrics` which cover the whole request
+    pub candidates: Option<Vec<GenerationResult>>,
+    /// Timing of each tool call made during the generation
+    pub tool_traces: Option<Vec<ToolCallTrace>>,
+    /// Why the guardrails stopped the generation, when `finish_reason` is `content_filter`
+    pub guardrail: Option<GuardrailViolation>,
+    /// The entry of `stop` that ended the generation, if one did
+    pub stop_sequence: Option<String>,
+}
+
+/// Why the safety guardrails stopped a generation. The framework doesn't say
+/// which check fired or name a category, so the side is inferred from
+/// whether any output had been generated yet.
+#[napi(object)]
+#[derive(Clone)]
+pub struct GuardrailViolation {
+    /// `prompt` when blocked before any output arrived, `output` when
+    /// generated output was blocked, `unknown` for non-streaming requests
+    #[napi(ts_type = "'prompt' | 'output' | 'unknown'")]
+    pub side: String,
+    /// The framework's description of the violation
+    pub detail: Option<String>,
+    /// Whether asking the user to rephrase may get a response; generated
+    /// output being blocked usually means the topic itself is off limits
+    pub suggest_rephrase: bool,
+}
+
+impl GuardrailViolation {
+    fn new(side: &str, detail: Option<String>) -> Self {
+        Self {
+            side: side.to_string(),
+            detail: detail.filter(|d| !d.is_empty()),
+            suggest_rephrase: side != "output",
+        }
+    }
+}
+
+fn parse_tool_calls(value: &serde_json::Value) -> Vec<ToolCall> {
+    let Some(calls) = value.as_array() else {
+        return Vec::new();
+    };
+    calls
+        .iter()
+        .filter_map(|call| {
+            let function = call.get("function")?;
+            Some(ToolCall {
+                id: call.get("id")?.as_str()?.to_string(),
+                kind: "function".to_string(),
+                function: ToolCallFunction {
+                    name: function.get("name")?.as_str()?.to_string(),
+                    arguments: function
+                        .get("arguments")
+                        .and_then(|a| a.as_str())
+                        .unwrap_or("{}")
+                        .to_string(),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Convert the JSON (or `Error: …` string) returned by Swift into a `GenerationResult`.
+fn parse_generation_result(
+    raw: &str,
+    usage: Option<TokenUsage>,
+    metrics: GenerationMetrics,
+    seed: Option<u32>,
+) -> napi::Result<GenerationResult> {
+    if let Some(message) = raw.strip_prefix("Error: ") {
+        return Err(napi::Error::from_reason(native_error(message).1));
+    }
+    let mut json: serde_json::Value = serde_json::from_str(raw).map_err(|e| {
+        napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+    })?;
+
+    let text = json
+        .get("text")
+        .and_then(|t| t.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let object = json.get_mut("object").map(serde_json::Value::take);
+    let tool_calls = json
+        .get("toolCalls")
+        .map(parse_tool_calls)
+        .filter(|calls| !calls.is_empty());
+    let guardrail = json.get("guardrail").map(|g| {
+        let detail = g.get("detail").and_then(|d| d.as_str()).map(str::to_string);
+        GuardrailViolation::new("unknown", detail)
+    });
+    let finish_reason = match json.get("finishReason").and_then(|r| r.as_str()) {
+        Some(reason) => reason.to_string(),
+        None if tool_calls.is_some() => "tool_calls".to_string(),
+        None => "stop".to_string(),
+    };
+
+    Ok(GenerationResult {
+        text,
+        object,
+        tool_calls,
+        finish_reason,
+        usage,
+        metrics,
+        seed,
+        candidates: None,
+        tool_traces: None,
+        guardrail,
+        stop_sequence: None,
+    })
+}
+
+// ---------------- Chat messages ----------------
+
+const MESSAGE_ROLES: [&str; 5] = ["system", "user", "assistant", "tool", "tool_calls"];
+
+/// One part of a multimodal message: either text or an image.
+#[napi(object)]
+pub struct ContentPart {
+    #[napi(js_name = "type", ts_type = "'text' | 'image'")]
+    pub kind: String,
+    pub text: Option<String>,
+    /// Image bytes in any format ImageIO reads (PNG, JPEG, HEIC, TIFF, WebP,
+    /// …), either base64-encoded or as a `Buffer`. Buffers cross the FFI as
+    /// pointer + length without being re-encoded
+    #[napi(ts_type = "string | Buffer")]
+    pub data: Option<Either<String, Buffer>>,
+    /// Image file to load instead of `data`
+    pub image_path: Option<String>,
+    /// Longest edge in pixels; Swift downscales larger images before
+    /// inference (default 1024)
+    pub max_dimension: Option<u32>,
+    /// How to turn the image upright: `auto` (default) by its EXIF
+    /// orientation, `up` as stored, or `right`, `down` or `left` to turn it a
+    /// quarter clockwise, half or a quarter counterclockwise
+    #[napi(ts_type = "'auto' | 'up' | 'right' | 'down' | 'left'")]
+    pub orientation: Option<String>,
+    /// e.g. `image/png`; Swift sniffs the format when omitted
+    pub mime_type: Option<String>,
+}
+
+impl ContentPart {
+    fn validate(&self, index: usize, part: usize) -> napi::Result<()> {
+        match self.kind.as_str() {
+            "text" if self.text.is_some() => Ok(()),
+            "text" => Err(invalid_message(
+                index,
+                format!("content[{part}] is a text part without text"),
+            )),
+            "image" if self.image_path.is_some() => {
+                let path = self.image_path.as_deref().unwrap_or_default();
+                if self.data.is_some() {
+                    return Err(invalid_message(
+                        index,
+                        format!("content[{part}] cannot set both data and imagePath"),
+                    ));
+                }
+                if !std::path::Path::new(path).is_file() {
+                    return Err(invalid_message(
+                        index,
+                        format!("content[{part}] imagePath {path:?} is not a readable file"),
+                    ));
+                }
+                self.validate_preprocessing(index, part)
+            }
+            "image" => {
+                let valid = match &self.data {
+                    Some(Either::A(data)) => {
+                        !data.is_empty()
+                            && data.bytes().all(|b| {
+                                b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=')
+                            })
+                    }
+                    Some(Either::B(buffer)) => !buffer.is_empty(),
+                    None => false,
+                };
+                if !valid {
+                    return Err(invalid_message(
+                        index,
+                        format!(
+                            "content[{part}] image needs data (base64 or Buffer) or an imagePath"
+                        ),
+                    ));
+                }
+                self.validate_preprocessing(index, part)
+            }
+            other => Err(invalid_message(
+                index,
+                format!("content[{part}] has unknown type {other:?}, expected text or image"),
+            )),
+        }
+    }
+
+    fn validate_preprocessing(&self, index: usize, part: usize) -> napi::Result<()> {
+        if self.max_dimension == Some(0) {
+            return Err(invalid_message(
+                index,
+                format!("content[{part}] maxDimension must be at least 1"),
+            ));
+        }
+        vision::check_orientation(self.orientation.as_deref())
+            .map_err(|reason| invalid_message(index, format!("content[{part}] {reason}")))
+    }
+}
+
+/// A single conversation message.
+///
+/// A trailing assistant message without tool calls is an assistant prefill:
+/// its content primes the reply, and the result contains only the continuation.
+#[napi(object)]
+pub struct ChatMessage {
+    #[napi(ts_type = "'system' | 'user' | 'assistant' | 'tool' | 'tool_calls'")]
+    pub role: String,
+    /// Plain text or content parts. May be omitted only on assistant
+    /// messages that carry `toolCalls`
+    #[napi(ts_type = "string | ContentPart[] | null")]
+    pub content: Option<Either<String, Vec<ContentPart>>>,
+    pub name: Option<String>,
+    /// Id of the tool call a `tool` message answers
+    pub tool_call_id: Option<String>,
+    /// Tool calls previously made by the assistant
+    pub tool_calls: Option<Vec<ToolCall>>,
+}
+
+fn invalid_message(index: usize, reason: impl std::fmt::Display) -> napi::Error {
+    napi::Error::new(Status::InvalidArg, format!("messages[{index}]: {reason}"))
+}
+
+impl ChatMessage {
+    /// Text content, with the text parts of multimodal content joined by newlines.
+    fn text(&self) -> Option<String> {
+        match self.content.as_ref()? {
+            Either::A(text) => Some(text.clone()),
+            Either::B(parts) => Some(
+                parts
+                    .iter()
+                    .filter_map(|part| part.text.as_deref())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        }
+    }
+
+    fn images(&self) -> impl Iterator<Item = &ContentPart> {
+        let parts = match &self.content {
+            Some(Either::B(parts)) => parts.as_slice(),
+            _ => &[],
+        };
+        parts.iter().filter(|part| part.kind == "image")
+    }
+
+    fn validate(&self, index: usize) -> napi::Result<()> {
+        let role = self.role.to_lowercase();
+        if !MESSAGE_ROLES.contains(&role.as_str()) {
+            return Err(invalid_message(
+                index,
+                format!(
+                    "unknown role {:?}, expected one of {}",
+                    self.role,
+                    MESSAGE_ROLES.join(", ")
+                ),
+            ));
+        }
+        let has_tool_calls = self.tool_calls.as_ref().is_some_and(|c| !c.is_empty());
+        if has_tool_calls && role != "assistant" {
+            return Err(invalid_message(
+                index,
+                "toolCalls are only allowed on assistant messages",
+            ));
+        }
+        if self.content.is_none() && !has_tool_calls {
+            return Err(invalid_message(index, "content is required"));
+        }
+        if role == "tool" && self.tool_call_id.as_deref().is_none_or(str::is_empty) {
+            return Err(invalid_message(index, "tool messages require a toolCallId"));
+        }
+        if let Some(Either::B(parts)) = &self.content {
+            for (part, content) in parts.iter().enumerate() {
+                content.validate(index, part)?;
+            }
+            if role != "user" && self.images().next().is_some() {
+                return Err(invalid_message(
+                    index,
+                    "image parts are only allowed on user messages",
+                ));
+            }
+        }
+        for (i, call) in self.tool_calls.iter().flatten().enumerate() {
+            if call.id.is_empty() || call.function.name.is_empty() {
+                return Err(invalid_message(
+                    index,
+                    format!("toolCalls[{i}] requires an id and a function name"),
+                ));
+            }
+            if serde_json::from_str::<serde_json::Value>(&call.function.arguments).is_err() {
+                return Err(invalid_message(
+                    index,
+                    format!("toolCalls[{i}].function.arguments is not valid JSON"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// OpenAI-style JSON, as decoded by the Swift `ChatMessage`. Buffer
+    /// images are moved to `buffers` and referenced by index.
+    fn to_json(&self, buffers: &mut Vec<Buffer>) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "role": self.role,
+            "content": self.text(),
+        });
+        let images: Vec<serde_json::Value> = self
+            .images()
+            .map(|image| match &image.data {
+                Some(Either::B(buffer)) => {
+                    buffers.push(buffer.clone());
+                    serde_json::json!({
+                        "buffer": buffers.len() - 1,
+                        "maxDimension": image.max_dimension,
+                        "orientation": image.orientation,
+                        "mimeType": image.mime_type,
+                    })
+                }
+                _ if image.image_path.is_some() => serde_json::json!({
+                    "path": image.image_path,
+                    "maxDimension": image.max_dimension,
+                    "orientation": image.orientation,
+                }),
+                data => serde_json::json!({
+                    "data": data.as_ref().and_then(|d| match d {
+                        Either::A(base64) => Some(base64.as_str()),
+                        Either::B(_) => None,
+                    }),
+                    "maxDimension": image.max_dimension,
+                    "orientation": image.orientation,
+                    "mimeType": image.mime_type,
+                }),
+            })
+            .collect();
+        if !images.is_empty() {
+            json["images"] = images.into();
+        }
+        if let Some(name) = &self.name {
+            json["name"] = name.as_str().into();
+        }
+        if let Some(id) = &self.tool_call_id {
+            json["tool_call_id"] = id.as_str().into();
+        }
+        if let Some(calls) = &self.tool_calls {
+            json["tool_calls"] = calls
+                .iter()
+                .map(|call| {
+                    serde_json::json!({
+                        "id": call.id,
+                        "type": call.kind,
+                        "function": {
+                            "name": call.function.name,
+                            "arguments": call.function.arguments,
+                        },
+                    })
+                })
+                .collect();
+        }
+        json
+    }
+}
+
+/// Validate `messages` and serialize them for the Swift side, along with
+/// the image buffers the JSON refers to.
+///
+/// OpenAI-style `tool` messages are rewritten into the tool output format the
+/// Swift transcript builder expects, taking the tool name from the assistant
+/// tool call they answer.
+fn messages_to_json(messages: &[ChatMessage]) -> napi::Result<(String, Vec<Buffer>)> {
+    if messages.is_empty() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "messages must contain at least one message".to_string(),
+        ));
+    }
+    let mut call_names: HashMap<&str, &str> = HashMap::new();
+    for (index, message) in messages.iter().enumerate() {
+        message.validate(index)?;
+        for call in message.tool_calls.iter().flatten() {
+            call_names.insert(&call.id, &call.function.name);
+        }
+        if message.role.eq_ignore_ascii_case("tool") {
+            let id = message.tool_call_id.as_deref().unwrap_or_default();
+            if !call_names.contains_key(id) {
+                return Err(invalid_message(
+                    index,
+                    format!("toolCallId {id:?} does not match any earlier assistant tool call"),
+                ));
+            }
+        }
+    }
+    let mut buffers = Vec::new();
+    let json: Vec<serde_json::Value> = messages
+        .iter()
+        .map(|message| {
+            let mut json = message.to_json(&mut buffers);
+            if message.role.eq_ignore_ascii_case("tool") {
+                let id = message.tool_call_id.as_deref().unwrap_or_default();
+                let output = serde_json::json!({
+                    "tool_calls": [{
+                        "id": id,
+                        "toolName": call_names[id],
+                        "segments": [{ "type": "text", "text": message.text().unwrap_or_default() }],
+                    }],
+                });
+                json["content"] = output.to_string().into();
+            }
+            json
+        })
+        .collect();
+    Ok((serde_json::Value::Array(json).to_string(), buffers))
+}
+
+/// Split a trailing assistant message off as a prefill. Its content primes the
+/// model's reply, which then continues from where the prefix leaves off.
+fn split_prefill(messages: &[ChatMessage]) -> napi::Result<(&[ChatMessage], Option<String>)> {
+    let Some((last, rest)) = messages.split_last() else {
+        return Ok((messages, None));
+    };
+    let is_prefill = last.role.eq_ignore_ascii_case("assistant")
+        && last.tool_calls.as_ref().is_none_or(Vec::is_empty);
+    if !is_prefill {
+        return Ok((messages, None));
+    }
+    last.validate(rest.len())?;
+    match rest.last() {
+        Some(previous) if !previous.role.eq_ignore_ascii_case("assistant") => {}
+        _ => {
+            return Err(invalid_message(
+                rest.len(),
+                "an assistant prefill must follow a user or tool message",
+            ))
+        }
+    }
+    let prefix = last.text().filter(|c| !c.is_empty());
+    Ok((rest, prefix))
+}
+
+// ---------------- Generation options ----------------
+
+/// A seed for a request that didn't set one.
+fn random_seed() -> u32 {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish() as u32
+}
+
+/// Sampling strategy for a request.
+#[napi(object)]
+#[derive(Clone, Default)]
+pub struct SamplingOptions {
+    /// Nucleus sampling threshold in (0, 1]
+    pub top_p: Option<f64>,
+    /// Sample from the `topK` most likely tokens
+    pub top_k: Option<u32>,
+    /// Seed for reproducible random sampling
+    pub seed: Option<u32>,
+    /// Always pick the most likely token
+    pub greedy: Option<bool>,
+}
+
+/// Options accepted by `generate`, `generateAbortable` and `generateStream`.
+#[napi(object)]
+#[derive(Default)]
+pub struct GenerationOptions {
+    /// Tool definitions as a JSON array
+    pub tools_json: Option<String>,
+    /// JSON Schema for structured output
+    pub schema_json: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<i32>,
+    /// Stop sequences; output is cut before the first match
+    pub stop: Option<Vec<String>>,
+    pub sampling: Option<SamplingOptions>,
+    /// End generation once the model has called a tool (default `true`)
+    pub stop_after_tool_calls: Option<bool>,
+    /// Number of candidate completions to generate (default 1, at most 128).
+    /// Candidates run one after another; with a seed, candidate `i` uses
+    /// `seed + i`
+    pub n: Option<u32>,
+    /// Cancel the generation and fail with a `Timeout` error after this long
+    pub timeout_ms: Option<u32>,
+    /// What to do when the messages don't fit the context window alongside
+    /// the tools, schema and room for the response: fail up front with a
+    /// `ContextWindowExceeded` error, or drop whole turns, oldest first or
+    /// after the first user turn. System messages and the latest turn are
+    /// always kept. Unset, the messages are sent as they are
+    #[napi(ts_type = "'error' | 'dropOldest' | 'dropMiddle'")]
+    pub context_policy: Option<String>,
+    /// Summarize older turns into a system message when the messages approach
+    /// the context window, before `contextPolicy` applies
+    pub condense: Option<CondenseOptions>,
+    /// Reuse a tool's successful result for identical arguments (same tool
+    /// name, same arguments regardless of key order) for this long, instead
+    /// of calling the handler again. Results are kept for the process and
+    /// shared with other requests that set this option when the same
+    /// handler, native tool configuration or MCP connection answers them;
+    /// registering a handler again leaves the old one's results behind
+    pub tool_cache_ttl_ms: Option<u32>,
+    /// Scan each tool result with `scanForInjection` before the model gets
+    /// it, and warn the model about or withhold those that look like prompt
+    /// injection attempts. Applies to every tool of the request
+    pub scan_tool_results: Option<ScanToolResultsOptions>,
+    /// Times to re-prompt the model with the validation errors when its
+    /// structured output doesn't match `schemaJson` (default 0). Output that
+    /// still doesn't match fails with a `SchemaValidation` error
+    pub schema_retries: Option<u32>,
+    /// Names of tools from `registerTool` to expose to the model for this
+    /// request; each must have been registered with a definition
+    pub registered_tools: Option<Vec<String>>,
+    /// Tools answered in Rust without calling into JS; each must be enabled
+    pub native_tools: Option<NativeToolsOptions>,
+    /// Names of connected MCP servers (see `connectMcpServer`) whose tools to
+    /// expose; calls are forwarded to the server without calling into JS
+    pub mcp_servers: Option<Vec<String>>,
+    /// Name of a loaded adapter to run this request against, instead of the
+    /// active one
+    pub adapter: Option<String>,
+    /// Locale the conversation is in, e.g. "de-DE", or "auto" for the
+    /// language of the last user message. A locale the model doesn't support
+    /// fails up front with an `UnsupportedLanguage` error
+    pub language: Option<String>,
+    /// Language to answer in, e.g. "ja". The model is instructed to use it,
+    /// and a non-streamed text response detected to be in another language is
+    /// regenerated once. Streams and agent steps get the instruction only
+    pub respond_in: Option<String>,
+    /// Model variant to use: the general model (default) or the one
+    /// specialized for tagging and classification
+    #[napi(ts_type = "'general' | 'contentTagging'")]
+    pub use_case: Option<String>,
+    /// Safety guardrails: the framework's `default`, or
+    /// `permissiveContentTransformations` for apps that rewrite, summarize or
+    /// tag user-generated content. Unset, the developer-provided configuration
+    /// this library has always used applies
+    #[napi(ts_type = "'developerProvided' | 'default' | 'permissiveContentTransformations'")]
+    pub guardrails: Option<String>,
+    /// Most tool handlers to run at once when the model calls several tools in
+    /// one turn (default unlimited). Further calls wait for a free slot
+    pub max_parallel_tool_calls: Option<u32>,
+    /// Tool name → handler, used for this request only. Handlers answer with
+    /// `toolResult(toolId, json)`, like those passed to `registerTool`
+    #[napi(
+        ts_type = "Record<string, (err: Error | null, toolId: number, argsJson: string) => void>"
+    )]
+    pub tool_handlers: Option<HashMap<String, JsFunction>>,
+    /// Called before each tool call runs, whether a request tool, a
+    /// registered, native or MCP tool answers it. Answer with
+    /// `toolApproval(toolId, decisionJson)`; the call waits until then
+    #[napi(
+        ts_type = "(err: Error | null, toolId: number, toolName: string, argsJson: string) => void"
+    )]
+    pub tool_approval: Option<JsFunction>,
+}
+
+/// Sampling settings forwarded to Swift's `GenerationOptions.sampling`.
+///
+/// At most one of `top_p` / `top_k` may be set, and `greedy` excludes all
+/// random-sampling settings including the seed.
+#[derive(Clone, Copy, Default)]
+pub struct Sampling {
+    pub top_p: Option<f64>,
+    pub top_k: Option<u32>,
+    pub seed: Option<u32>,
+    pub greedy: bool,
+}
+
+impl Sampling {
+    fn new(options: SamplingOptions) -> napi::Result<Self> {
+        let SamplingOptions {
+            top_p,
+            top_k,
+            seed,
+            greedy,
+        } = options;
+        let greedy = greedy.unwrap_or(false);
+        if let Some(p) = top_p {
+            if !(p > 0.0 && p <= 1.0) {
+                return Err(napi::Error::new(
+                    Status::InvalidArg,
+                    format!("topP must be in (0, 1], got {p}"),
+                ));
+            }
+        }
+        if top_k == Some(0) {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "topK must be at least 1".to_string(),
+            ));
+        }
+        if top_p.is_some() && top_k.is_some() {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "topP and topK cannot be combined".to_string(),
+            ));
+        }
+        if greedy && (top_p.is_some() || top_k.is_some() || seed.is_some()) {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "greedy sampling cannot be combined with topP, topK or seed".to_string(),
+            ));
+        }
+        // Random sampling always runs with a known seed, so any run can be
+        // reproduced from the seed its result reports
+        let seed = if greedy {
+            None
+        } else {
+            Some(seed.unwrap_or_else(random_seed))
+        };
+        Ok(Self {
+            top_p,
+            top_k,
+            seed,
+            greedy,
+        })
+    }
+
+    fn ffi_top_p(&self) -> c_double {
+        self.top_p.unwrap_or(0.0) as c_double
+    }
+
+    fn ffi_top_k(&self) -> c_int {
+        self.top_k.map_or(0, |k| k.min(c_int::MAX as u32) as c_int)
+    }
+
+    fn ffi_seed(&self) -> i64 {
+        self.seed.map_or(-1, i64::from)
+    }
+}
+
+/// Definitions of the registered tools named in `names`.
+fn registered_tool_definitions(names: &[String]) -> napi::Result<Vec<serde_json::Value>> {
+    let registry = tool_registry().lock().unwrap();
+    names
+        .iter()
+        .map(|name| {
+            registry
+                .get(name)
+                .and_then(|tool| tool.definition.clone())
+                .ok_or_else(|| {
+                    napi::Error::new(
+                        Status::InvalidArg,
+                        format!("no tool named '{name}' is registered with a definition"),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Append `extra` definitions (native and registered tools) to the caller's
+/// tool definitions, numbering them after the caller's ids.
+fn with_extra_tools(
+    tools_json: Option<String>,
+    extra: Vec<serde_json::Value>,
+) -> napi::Result<Option<String>> {
+    if extra.is_empty() {
+        return Ok(tools_json);
+    }
+    let mut tools: Vec<serde_json::Value> = match &tools_json {
+        Some(json) => serde_json::from_str(json).map_err(|e| {
+            napi::Error::new(Status::InvalidArg, format!("Invalid tools JSON: {e}"))
+        })?,
+        None => Vec::new(),
+    };
+    let mut next_id = tools
+        .iter()
+        .filter_map(|tool| tool.get("id")?.as_u64())
+        .max()
+        .unwrap_or(0);
+    for mut tool in extra {
+        let name = tool.get("name").cloned();
+        if tools.iter().any(|t| t.get("name") == name.as_ref()) {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                format!(
+                    "tool {} is defined more than once",
+                    name.unwrap_or_default()
+                ),
+            ));
+        }
+        next_id += 1;
+        tool["id"] = next_id.into();
+        tools.push(tool);
+    }
+    Ok(Some(serde_json::Value::Array(tools).to_string()))
+}
+
+/// Most candidates one request may ask for; they run one after another
+pub const MAX_CANDIDATES: u32 = 128;
+
+/// Validated form of a generation request, ready to cross the FFI.
+pub struct GenerationRequest {
+    pub messages_json: String,
+    pub tools_json: Option<String>,
+    /// Parameter schemas by tool name, used to validate tool call arguments
+    pub tool_schemas: HashMap<String, Arc<schema::Schema>>,
+    /// Tools answered in Rust; their definitions are part of `tools_json`
+    pub native_tools: Vec<NativeTool>,
+    /// Server answering each MCP tool, by tool name; definitions are part of `tools_json`
+    pub mcp_tools: McpTools,
+    pub schema_json: Option<String>,
+    /// `schema_json` compiled, for validating structured output
+    pub schema: Option<Arc<schema::Schema>>,
+    pub schema_retries: u32,
+    /// Assistant prefill taken from a trailing assistant message
+    pub prefix: Option<String>,
+    /// Image bytes referenced from `messages_json` by index
+    pub images: Vec<Buffer>,
+    pub temperature: f64,
+    pub max_tokens: i32,
+    pub sampling: Sampling,
+    pub stop: Vec<String>,
+    pub stop_after_tool_calls: bool,
+    pub n: u32,
+    pub timeout: Option<Duration>,
+    pub tool_handlers: HashMap<String, ToolCallbackFn>,
+    pub tool_approval: Option<ToolApprovalFn>,
+    pub max_parallel_tool_calls: Option<usize>,
+    pub tool_cache_ttl: Option<Duration>,
+    pub tool_result_scan: Option<ToolResultScan>,
+    /// Keep every tool call and its output for the agent loop
+    pub record_tool_calls: bool,
+    pub context_policy: Option<ContextPolicy>,
+    pub condense: Option<Condense>,
+    /// Swift handle of the adapter the request runs against; 0 for the system model
+    pub adapter: u64,
+    pub use_case: UseCase,
+    pub guardrails: GuardrailSetting,
+    /// Expected locale, checked before generating
+    pub language: Option<String>,
+    /// Language the response must be in; its instruction is already part
+    /// of `messages_json`
+    pub respond_in: Option<String>,
+}
+
+/// Add the `respondIn` instruction after the last system message, so it
+/// ends up last in the instructions.
+fn with_language_instruction(messages_json: &str, code: &str) -> napi::Result<String> {
+    let mut messages: Vec<serde_json::Value> = serde_json::from_str(messages_json)
+        .map_err(|e| napi::Error::new(Status::InvalidArg, format!("Invalid messages JSON: {e}")))?;
+    let at = messages
+        .iter()
+        .rposition(|m| message_role(m) == "system")
+        .map_or(0, |i| i + 1);
+    messages.insert(
+        at,
+        serde_json::json!({ "role": "system", "content": response_language::instruction(code) }),
+    );
+    Ok(serde_json::Value::Array(messages).to_string())
+}
+
+impl GenerationRequest {
+    fn new(messages_json: String, options: GenerationOptions) -> napi::Result<Self> {
+        let stop: Vec<String> = options
+            .stop
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect();
+        let sampling = Sampling::new(options.sampling.unwrap_or_default())?;
+        let n = options.n.unwrap_or(1);
+        if !(1..=MAX_CANDIDATES).contains(&n) {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                format!("n must be from 1 to {MAX_CANDIDATES}"),
+            ));
+        }
+        if n > 1 && sampling.greedy {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "n > 1 needs random sampling; greedy sampling would repeat one completion"
+                    .to_string(),
+            ));
+        }
+        let tool_handlers = options
+            .tool_handlers
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, callback)| Ok((name, create_tool_tsfn(callback)?)))
+            .collect::<napi::Result<_>>()?;
+        let tool_approval = options
+            .tool_approval
+            .map(create_tool_approval_tsfn)
+            .transpose()?;
+        if options.timeout_ms == Some(0) {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "timeoutMs must be at least 1".to_string(),
+            ));
+        }
+        if options.max_parallel_tool_calls == Some(0) {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "maxParallelToolCalls must be at least 1".to_string(),
+            ));
+        }
+        let schema = options
+            .schema_json
+            .filter(|s| !s.is_empty())
+            .map(|json| {
+                let schema: serde_json::Value = serde_json::from_str(&json).map_err(|e| {
+                    napi::Error::new(Status::InvalidArg, format!("Invalid schema JSON: {e}"))
+                })?;
+                generation_schema::prepare(&schema).map_err(|e| {
+                    napi::Error::new(Status::InvalidArg, format!("Unsupported schema: {e}"))
+                })
+            })
+            .transpose()?;
+        // Swift builds the generation schema from the self-contained form
+        let schema_json = schema.as_ref().map(serde_json::Value::to_string);
+        let schema = schema
+            .map(|schema| {
+                schema::Schema::compile(schema).map(Arc::new).map_err(|e| {
+                    napi::Error::new(Status::InvalidArg, format!("Unsupported schema: {e}"))
+                })
+            })
+            .transpose()?;
+        let native_tools = native_tools::enabled(options.native_tools.unwrap_or_default())?;
+        if tool_approval.is_none() {
+            if let Some(tool) = native_tools.iter().find(|tool| tool.requires_approval()) {
+                return Err(napi::Error::new(
+                    Status::InvalidArg,
+                    format!(
+                        "The native {} tool requires approval, but the request has no toolApproval hook",
+                        tool.name()
+                    ),
+                ));
+            }
+        }
+        let mut extra_tools =
+            registered_tool_definitions(options.registered_tools.as_deref().unwrap_or_default())?;
+        extra_tools.extend(native_tools.iter().map(NativeTool::definition));
+        let (mcp_definitions, mcp_tools) =
+            mcp::tools_for(options.mcp_servers.as_deref().unwrap_or_default())?;
+        extra_tools.extend(mcp_definitions);
+        let tools_json =
+            with_extra_tools(options.tools_json.filter(|s| !s.is_empty()), extra_tools)?;
+        let (adapter, use_case) =
+            model_choice(options.adapter.as_deref(), options.use_case.as_deref())?;
+        let language = options.language.filter(|l| !l.is_empty());
+        if language.as_deref().is_some_and(|l| l.contains('\0')) {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "language contained null byte".to_string(),
+            ));
+        }
+        let respond_in = options.respond_in.filter(|l| !l.is_empty());
+        let messages_json = match &respond_in {
+            Some(code) => with_language_instruction(&messages_json, code)?,
+            None => messages_json,
+        };
+        let tool_schemas = tools_json
+            .as_deref()
+            .map(schema::tool_schemas)
+            .unwrap_or_default();
+        let tool_schemas = tool_schemas
+            .into_iter()
+            .map(|(name, parameters)| {
+                generation_schema::check_parameters(&parameters)
+                    .and_then(|()| schema::Schema::compile(parameters))
+                    .map(|compiled| (name.clone(), Arc::new(compiled)))
+                    .map_err(|e| {
+                        napi::Error::new(
+                            Status::InvalidArg,
+                            format!("Unsupported parameters for tool {name}: {e}"),
+                        )
+                    })
+            })
+            .collect::<napi::Result<HashMap<_, _>>>()?;
+        Ok(Self {
+            messages_json,
+            tool_schemas,
+            native_tools,
+            mcp_tools,
+            tools_json,
+            schema,
+            schema_retries: options.schema_retries.unwrap_or(0),
+            schema_json,
+            prefix: None,
+            images: Vec::new(),
+            temperature: options.temperature.unwrap_or(0.0),
+            max_tokens: options.max_tokens.unwrap_or(0),
+            sampling,
+            stop,
+            stop_after_tool_calls: options.stop_after_tool_calls.unwrap_or(true), // default to true
+            n,
+            timeout: options
+                .timeout_ms
+                .map(|ms| Duration::from_millis(ms.into())),
+            tool_handlers,
+            tool_approval,
+            max_parallel_tool_calls: options.max_parallel_tool_calls.map(|n| n as usize),
+            tool_cache_ttl: options
+                .tool_cache_ttl_ms
+                .filter(|ms| *ms > 0)
+                .map(|ms| Duration::from_millis(ms.into())),
+            tool_result_scan: options
+                .scan_tool_results
+                .map(ToolResultScan::new)
+                .transpose()?,
+            record_tool_calls: false,
+            context_policy: options
+                .context_policy
+                .as_deref()
+                .map(ContextPolicy::parse)
+                .transpose()?,
+            condense: options.condense.map(Condense::new).transpose()?,
+            adapter,
+            use_case,
+            guardrails: options
+                .guardrails
+                .as_deref()
+                .map(GuardrailSetting::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            language,
+            respond_in,
+        })
+    }
+
+    /// Fail if the model doesn't support the expected `language`, before
+    /// anything is generated.
+    fn check_language(&self) -> napi::Result<()> {
+        let language = match self.language.as_deref() {
+            None => return Ok(()),
+            Some("auto") => match self.prompt_language() {
+                Some(language) => language,
+                None => return Ok(()),
+            },
+            Some(language) => language.to_string(),
+        };
+        if is_language_supported(language.clone())? {
+            return Ok(());
+        }
+        Err(napi::Error::from_reason(format!(
+            "The on-device model doesn't support the language {language:?}"
+        )))
+    }
+
+    /// Fail if the request has images and the model on this system doesn't
+    /// take image input.
+    fn check_images(&self) -> napi::Result<()> {
+        if self.images.is_empty() || supports_image_input() {
+            return Ok(());
+        }
+        Err(napi::Error::from_reason(
+            "Multimodal input is not supported by the on-device model on this system".to_string(),
+        ))
+    }
+
+    /// Language of the last user message, when it's long enough to tell
+    /// with confidence.
+    fn prompt_language(&self) -> Option<String> {
+        let messages: Vec<serde_json::Value> = serde_json::from_str(&self.messages_json).ok()?;
+        let content = &messages.iter().rev().find(|m| m["role"] == "user")?["content"];
+        let text = match content.as_array() {
+            Some(parts) => parts
+                .iter()
+                .filter_map(|part| part["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            None => content.as_str()?.to_string(),
+        };
+        language_id::confident_language(&text)
+    }
+
+    /// Build a request from typed messages, validating each one and peeling
+    /// off a trailing assistant prefill.
+    pub fn from_messages(
+        messages: &[ChatMessage],
+        options: GenerationOptions,
+    ) -> napi::Result<Self> {
+        let (messages, prefix) = split_prefill(messages)?;
+        let (messages_json, images) = messages_to_json(messages)?;
+        let mut request = Self::new(messages_json, options)?;
+        if prefix.is_some() && request.schema_json.is_some() {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "assistant prefill cannot be combined with structured output".to_string(),
+            ));
+        }
+        request.prefix = prefix;
+        request.images = images;
+        Ok(request)
+    }
+
+    /// Replace the messages of a request built earlier, for prompts that are
+    /// only known once work off the JS thread is done.
+    fn set_messages(&mut self, messages: &[ChatMessage]) -> napi::Result<()> {
+        let (messages_json, images) = messages_to_json(messages)?;
+        self.messages_json = match &self.respond_in {
+            Some(code) => with_language_instruction(&messages_json, code)?,
+            None => messages_json,
+        };
+        self.images = images;
+        Ok(())
+    }
+
+    /// Call into Swift. Swift copies every string argument before returning,
+    /// so the temporary C strings only need to live for the duration of the call.
+    fn invoke(
+        &self,
+        request_id: u64,
+        on_chunk: Option<extern "C" fn(u64, *const c_char)>,
+    ) -> napi::Result<*mut c_char> {
+        let on_object = on_chunk.map(|_| stream_object_cb as extern "C" fn(_, _));
+        let on_error = on_chunk.map(|_| stream_error_cb as extern "C" fn(_, _, _));
+        let c_messages = CString::new(self.messages_json.as_str())
+            .map_err(|_| napi::Error::from_reason("Messages contained null byte".to_string()))?;
+
+        // Convert optional strings to nullable pointers
+        let c_tools = self
+            .tools_json
+            .as_deref()
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| napi::Error::from_reason("Tools JSON contained null byte".to_string()))?;
+
+        let c_schema = self
+            .schema_json
+            .as_deref()
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| napi::Error::from_reason("Schema JSON contained null byte".to_string()))?;
+
+        let c_prefix = self
+            .prefix
+            .as_deref()
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| napi::Error::from_reason("Prefill contained null byte".to_string()))?;
+
+        // The buffers stay referenced by `self` until Swift has copied them
+        let image_data: Vec<*const u8> = self.images.iter().map(|b| b.as_ptr()).collect();
+        let image_lens: Vec<usize> = self.images.iter().map(|b| b.len()).collect();
+
+        let result = unsafe {
+            apple_ai_generate_unified(
+                request_id,
+                c_messages.as_ptr(),
+                c_tools.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+                c_schema.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+                c_prefix.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+                image_data.as_ptr(),
+                image_lens.as_ptr(),
+                self.images.len(),
+                self.temperature as c_double,
+                self.max_tokens as c_int,
+                self.sampling.ffi_top_p(),
+                self.sampling.ffi_top_k(),
+                self.sampling.ffi_seed(),
+                self.sampling.greedy,
+                on_chunk.is_some(), // streaming
+                self.stop_after_tool_calls,
+                self.adapter,
+                self.use_case as c_int,
+                self.guardrails as c_int,
+                on_chunk,
+                on_object,
+                on_error,
+            )
+        };
+        Ok(result)
+    }
+}
+
+// ---------------- Stop sequences ----------------
+
+/// Byte offset of the earliest stop sequence in `text`, and which one it is.
+fn find_stop<'a>(text: &str, stops: &'a [String]) -> Option<(usize, &'a str)> {
+    stops
+        .iter()
+        .filter_map(|stop| text.find(stop.as_str()).map(|idx| (idx, stop.as_str())))
+        .min_by_key(|&(idx, _)| idx)
+}
+
+/// Detects stop sequences across streamed chunks, holding back any trailing
+/// text that could still turn out to be the start of a stop sequence.
+struct StopMatcher {
+    stops: Vec<String>,
+    pending: String,
+}
+
+impl StopMatcher {
+    fn new(stops: Vec<String>) -> Self {
+        Self {
+            stops,
+            pending: String::new(),
+        }
+    }
+
+    /// Feed a chunk. Returns the text that is safe to emit and whether a stop
+    /// sequence was reached (in which case nothing after it is returned).
+    fn push(&mut self, chunk: &str) -> (String, bool) {
+        self.pending.push_str(chunk);
+        if self.stops.is_empty() {
+            return (std::mem::take(&mut self.pending), false);
+        }
+        if let Some((idx, _)) = find_stop(&self.pending, &self.stops) {
+            let emit = self.pending[..idx].to_string();
+            self.pending.clear();
+            return (emit, true);
+        }
+
+        // Longest suffix of `pending` that is a proper prefix of some stop sequence
+        let held = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|&i| {
+                let suffix = &self.pending[i..];
+                self.stops.iter().any(|stop| stop.starts_with(suffix))
+            })
+            .unwrap_or(self.pending.len());
+        let emit = self.pending[..held].to_string();
+        self.pending.drain(..held);
+        (emit, false)
+    }
+
+    /// Release whatever is still held back once the stream has ended.
+    fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+// ---------------- Unified Generation ----------------
+
+pub struct GenerateUnifiedTask {
+    pub request_id: u64,
+    pub cancelled: Arc<AtomicBool>,
+    pub timed_out: Arc<AtomicBool>,
+    /// Code for the rejection when the failure has a typed cause, e.g.
+    /// `SchemaValidation` when structured output failed all retries
+    pub error_code: Option<ErrorCode>,
+    pub request: GenerationRequest,
+}
+
+impl GenerateUnifiedTask {
+    pub fn new(request: GenerationRequest) -> Self {
+        Self {
+            request_id: next_request_id(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            timed_out: Arc::new(AtomicBool::new(false)),
+            error_code: None,
+            request,
+        }
+    }
+
+    /// Typed error for a finished request that was interrupted, if any.
+    fn interrupted(&self) -> Option<napi::Error> {
+        if self.timed_out.load(Ordering::SeqCst) {
+            let timeout = self.request.timeout.unwrap_or_default();
+            Some(napi::Error::from_reason(timeout_message(timeout)))
+        } else if self.cancelled.load(Ordering::SeqCst) {
+            Some(cancelled_error())
+        } else {
+            None
+        }
+    }
+
+    /// The error a failed request rejects with, carrying its `ErrorCode`.
+    fn rejection(&self, env: Env, err: napi::Error) -> napi::Error {
+        coded_error(env, self.failure_code(&err), err.reason)
+    }
+
+    /// Code for `err`, a failure of this task.
+    pub fn failure_code(&self, err: &napi::Error) -> ErrorCode {
+        if self.timed_out.load(Ordering::SeqCst) {
+            ErrorCode::Timeout
+        } else {
+            self.error_code.unwrap_or_else(|| ErrorCode::of(err))
+        }
+    }
+
+    /// Fit the request's messages to the context window. Runs once per task,
+    /// before any correction turns are added so they can't push out the
+    /// user's turn; retries and candidates reuse the condensed messages.
+    fn fit_context(&mut self) -> napi::Result<()> {
+        self.request.fit_context().inspect_err(|_| {
+            self.error_code = Some(ErrorCode::ContextWindowExceeded);
+        })
+    }
+
+    fn generate_once(&mut self) -> napi::Result<GenerationResult> {
+        // Candidates, retries and agent steps all come through here under the
+        // same request id, so a cancel or timeout between two of them stops
+        // the next one before it reaches Swift
+        if let Some(error) = self.interrupted() {
+            return Err(error);
+        }
+        if let Err(error) = self.request.check_images() {
+            self.error_code = Some(ErrorCode::MultimodalUnsupported);
+            return Err(error);
+        }
+        if let Err(error) = self.request.check_language() {
+            self.error_code = Some(ErrorCode::UnsupportedLanguage);
+            return Err(error);
+        }
+
+        let started = Instant::now();
+        let result_ptr = self.request.invoke(self.request_id, None)?;
+        if let Some(error) = self.interrupted() {
+            // Swift reports the cancellation as an error string; surface a typed error instead
+            drop(take_c_string(result_ptr));
+            return Err(error);
+        }
+        if result_ptr.is_null() {
+            return Err(napi::Error::from_reason(
+                "Generation returned null".to_string(),
+            ));
+        }
+        let raw = take_c_string(result_ptr);
+        if let Some(error) = raw.strip_prefix("Error: ") {
+            let (code, message) = native_error(error);
+            self.error_code = Some(code);
+            return Err(napi::Error::from_reason(message));
+        }
+
+        // Swift reports the finish reason in the result itself
+        let usage = take_usage(self.request_id).map(|(usage, _)| usage);
+        let metrics = GenerationMetrics::new(started, None, usage.as_ref());
+        let mut result = parse_generation_result(&raw, usage, metrics, self.request.sampling.seed)?;
+        result.tool_traces = take_tool_traces(self.request_id);
+        if let Some((idx, stop)) = find_stop(&result.text, &self.request.stop) {
+            result.text.truncate(idx);
+            result.finish_reason = "stop".to_string();
+            result.stop_sequence = Some(stop.to_string());
+        }
+        Ok(result)
+    }
+
+    /// `generate_once`, with structured output validated against the request
+    /// schema. Invalid output goes back to the model together with the
+    /// validation errors, up to `schema_retries` times, before failing.
+    fn generate_validated(&mut self) -> napi::Result<GenerationResult> {
+        let Some(schema) = self.request.schema.clone() else {
+            return self.generate_in_language();
+        };
+        let original_messages = self.request.messages_json.clone();
+        let mut usages = Vec::new();
+        let outcome = loop {
+            let mut result = match self.generate_once() {
+                Ok(result) => result,
+                Err(e) => break Err(e),
+            };
+            usages.push(result.usage.clone());
+            let object = result
+                .object
+                .take()
+                .or_else(|| serde_json::from_str(&result.text).ok());
+            let errors = match &object {
+                Some(object) => schema.validate(object),
+                None => vec![schema::ValidationError {
+                    path: String::new(),
+                    message: "output is not valid JSON".to_string(),
+                }],
+            };
+            if errors.is_empty() {
+                result.object = object;
+                break Ok(result);
+            }
+            if usages.len() > self.request.schema_retries as usize {
+                self.error_code = Some(ErrorCode::SchemaValidation);
+                break Err(napi::Error::from_reason(format!(
+                    "Structured output does not match the schema: {}",
+                    schema::summary(&errors)
+                )));
+            }
+
+            // Show the model its output and what was wrong with it
+            let mut messages: Vec<serde_json::Value> =
+                serde_json::from_str(&self.request.messages_json).unwrap_or_default();
+            messages.push(serde_json::json!({ "role": "assistant", "content": result.text }));
+            messages.push(serde_json::json!({
+                "role": "user",
+                "content": format!(
+                    "That output does not match the required schema: {}. Reply again with corrected output.",
+                    schema::summary(&errors)
+                ),
+            }));
+            self.request.messages_json = serde_json::Value::Array(messages).to_string();
+        };
+        self.request.messages_json = original_messages;
+        outcome.map(|mut result| {
+            result.usage = total_usage(usages.iter().map(Option::as_ref));
+            result
+        })
+    }
+
+    /// Generate once and, with `respondIn`, once more if the response was
+    /// detected to be in another language. The retry's result stands either
+    /// way.
+    fn generate_in_language(&mut self) -> napi::Result<GenerationResult> {
+        let result = self.generate_once()?;
+        let Some(code) = self.request.respond_in.clone() else {
+            return Ok(result);
+        };
+        if result.tool_calls.is_some()
+            || result.text.chars().count() < response_language::MIN_DETECTABLE_CHARS
+        {
+            return Ok(result);
+        }
+        let in_language = |hypothesis: &LanguageHypothesis| {
+            response_language::matches(&code, &hypothesis.language)
+                && hypothesis.probability >= response_language::MIN_PROBABILITY
+        };
+        match language_id::identify(&result.text, None) {
+            Some(detection)
+                if detection.language.is_some()
+                    && !detection.hypotheses.iter().any(in_language) => {}
+            _ => return Ok(result),
+        }
+
+        let original_messages = self.request.messages_json.clone();
+        let mut messages: Vec<serde_json::Value> =
+            serde_json::from_str(&self.request.messages_json).unwrap_or_default();
+        messages.push(serde_json::json!({ "role": "assistant", "content": result.text }));
+        messages.push(serde_json::json!({
+            "role": "user",
+            "content": response_language::correction(&code),
+        }));
+        self.request.messages_json = serde_json::Value::Array(messages).to_string();
+        let retry = self.generate_once();
+        self.request.messages_json = original_messages;
+        retry.map(|mut retry| {
+            retry.usage = total_usage([result.usage.as_ref(), retry.usage.as_ref()]);
+            retry
+        })
+    }
+
+    /// Make the request's tools routable and arm its timeout; both last until
+    /// the returned guards are dropped.
+    fn prepare(&self) -> (Option<RequestToolsGuard>, Option<Watchdog>) {
+        ensure_initialized();
+        if self.request.tools_json.is_some() {
+            ensure_tool_callback_registered();
+        }
+
+        let tools = RequestToolsGuard::install(self.request_id, &self.request);
+        let watchdog = self.request.timeout.map(|timeout| {
+            let request_id = self.request_id;
+            let timed_out = self.timed_out.clone();
+            Watchdog::arm(timeout, move || {
+                timed_out.store(true, Ordering::SeqCst);
+                unsafe { apple_ai_cancel_generation(request_id) };
+            })
+        });
+        (tools, watchdog)
+    }
+}
+
+/// Sum of `usages`, or `None` if any of them is unknown.
+fn total_usage<'a>(usages: impl IntoIterator<Item = Option<&'a TokenUsage>>) -> Option<TokenUsage> {
+    usages
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .map(|usages| TokenUsage {
+            prompt_tokens: usages.iter().map(|u| u.prompt_tokens).sum(),
+            completion_tokens: usages.iter().map(|u| u.completion_tokens).sum(),
+            total_tokens: usages.iter().map(|u| u.total_tokens).sum(),
+        })
+}
+
+impl napi::Task for GenerateUnifiedTask {
+    type Output = GenerationResult;
+    type JsValue = GenerationResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let _guards = self.prepare();
+        self.fit_context()?;
+        if self.request.n == 1 {
+            return self.generate_validated();
+        }
+
+        let started = Instant::now();
+        let base_seed = self.request.sampling.seed;
+        let mut candidates = Vec::with_capacity(self.request.n as usize);
+        for i in 0..self.request.n {
+            self.request.sampling.seed = base_seed.map(|seed| seed.wrapping_add(i));
+            candidates.push(self.generate_validated()?);
+        }
+        self.request.sampling.seed = base_seed;
+
+        let usage = total_usage(candidates.iter().map(|c| c.usage.as_ref()));
+        let mut result = candidates[0].clone();
+        result.metrics = GenerationMetrics::new(started, None, usage.as_ref());
+        result.usage = usage;
+        result.candidates = Some(candidates);
+        Ok(result)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.rejection(env, err))
+    }
+}
+
+/// Run a generation and resolve with its `GenerationResult`.
+#[napi]
+pub fn generate(
+    messages: Vec<ChatMessage>,
+    options: Option<GenerationOptions>,
+) -> napi::Result<AsyncTask<GenerateUnifiedTask>> {
+    let request = GenerationRequest::from_messages(&messages, options.unwrap_or_default())?;
+    Ok(AsyncTask::new(GenerateUnifiedTask::new(request)))
+}
+
+/// Same as `generate`, but returns `{ promise, cancel }` so the caller can
+/// abort the underlying Swift generation. A cancelled request rejects with an
+/// error whose `code` is `"Cancelled"`.
+#[napi(ts_return_type = "{ promise: Promise<GenerationResult>; cancel: () => void }")]
+pub fn generate_abortable(
+    env: Env,
+    messages: Vec<ChatMessage>,
+    options: Option<GenerationOptions>,
+) -> napi::Result<JsObject> {
+    let request = GenerationRequest::from_messages(&messages, options.unwrap_or_default())?;
+    spawn_abortable(env, request)
+}
+
+fn spawn_abortable(env: Env, request: GenerationRequest) -> napi::Result<JsObject> {
+    let task = GenerateUnifiedTask::new(request);
+    let request_id = task.request_id;
+    let cancelled = task.cancelled.clone();
+
+    let promise = env.spawn(task)?.promise_object();
+    let cancel = env.create_function_from_closure("cancel", move |ctx| {
+        // Only the first cancel reaches Swift; later calls are no-ops
+        if !cancelled.swap(true, Ordering::SeqCst) {
+            unsafe { apple_ai_cancel_generation(request_id) };
+        }
+        ctx.env.get_undefined()
+    })?;
+
+    let mut handle = env.create_object()?;
+    handle.set_named_property("promise", promise)?;
+    handle.set_named_property("cancel", cancel)?;
+    Ok(handle)
+}
+
+/// Stream a generation, delivering `StreamEvent`s to `callback`.
+#[napi]
+pub fn generate_stream(
+    messages: Vec<ChatMessage>,
+    options: Option<GenerationOptions>,
+    #[napi(ts_arg_type = "(err: Error | null, event: StreamEvent) => void")] callback: JsFunction,
+) -> napi::Result<()> {
+    let request = GenerationRequest::from_messages(&messages, options.unwrap_or_default())?;
+    start_stream(request, callback)
+}
+
+/// Same as `generate_stream`, but returns `{ cancel }` so the caller can
+/// stop the stream, which then ends with an `error` event whose `code` is
+/// `"Cancelled"`.
+#[napi(ts_return_type = "{ cancel: () => void }")]
+pub fn generate_stream_abortable(
+    env: Env,
+    messages: Vec<ChatMessage>,
+    options: Option<GenerationOptions>,
+    #[napi(ts_arg_type = "(err: Error | null, event: StreamEvent) => void")] callback: JsFunction,
+) -> napi::Result<JsObject> {
+    let request = GenerationRequest::from_messages(&messages, options.unwrap_or_default())?;
+    let ts_fn: StreamEventFn = callback
+        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<StreamEvent>| {
+            Ok(vec![ctx.value])
+        })?;
+    let request_id = run_stream(request, StreamSink::Js(ts_fn))?;
+    let cancel = env.create_function_from_closure("cancel", move |ctx| {
+        // A stream that already ended is left alone
+        let state = unified_streams().lock().unwrap().remove(&request_id);
+        if let Some(state) = state {
+            state.send(StreamEvent::error(
+                ErrorCode::Cancelled,
+                "Generation cancelled".to_string(),
+            ));
+            drop(state);
+            unsafe { apple_ai_cancel_generation(request_id) };
+        }
+        ctx.env.get_undefined()
+    })?;
+    let mut handle = env.create_object()?;
+    handle.set_named_property("cancel", cancel)?;
+    Ok(handle)
+}
+
+fn start_stream(request: GenerationRequest, callback: JsFunction) -> napi::Result<()> {
+    let ts_fn: StreamEventFn = callback
+        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<StreamEvent>| {
+            Ok(vec![ctx.value])
+        })?;
+    run_stream(request, StreamSink::Js(ts_fn)).map(|_| ())
+}
+
+/// Stream `request` in a wire format: `format` turns each event into text,
+/// and `callback` receives `(err, text, done)`, with `done` set on the
+/// `finish` or `error` event that ends the stream.
+fn start_formatted_stream(
+    request: GenerationRequest,
+    callback: JsFunction,
+    mut format: impl FnMut(&StreamEvent) -> String + Send + 'static,
+) -> napi::Result<()> {
+    let ts_fn: StreamEventFn = callback.create_threadsafe_function(
+        0,
+        move |ctx: ThreadSafeCallContext<StreamEvent>| {
+            let done = matches!(ctx.value.kind.as_str(), "finish" | "error");
+            let text = ctx.env.create_string(&format(&ctx.value))?;
+            let done = ctx.env.get_boolean(done)?;
+            Ok(vec![text.into_unknown(), done.into_unknown()])
+        },
+    )?;
+    run_stream(request, StreamSink::Js(ts_fn)).map(|_| ())
+}
+
+/// Start streaming `request`, delivering its events to `sink`. Returns the
+/// stream's request id. Streams are tracked by request id, so any number
+/// can run at once.
+pub fn run_stream(mut request: GenerationRequest, sink: StreamSink) -> napi::Result<u64> {
+    if request.n > 1 {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "n > 1 is not supported for streaming".to_string(),
+        ));
+    }
+    ensure_initialized();
+    if request.tools_json.is_some() {
+        ensure_tool_callback_registered();
+    }
+
+    let request_id = next_request_id();
+
+    {
+        let mut streams = unified_streams().lock().unwrap();
+        streams.insert(
+            request_id,
+            UnifiedState {
+                sink,
+                request_id,
+                seed: request.sampling.seed,
+                started: Instant::now(),
+                first_token_at: None,
+                stop: StopMatcher::new(request.stop.clone()),
+                stop_reached: false,
+                pending_calls: HashMap::new(),
+                saw_tool_call: false,
+                object: None,
+                _tools: RequestToolsGuard::install(request_id, &request),
+                _watchdog: request.timeout.map(|timeout| {
+                    Watchdog::arm(timeout, move || {
+                        let state = unified_streams().lock().unwrap().remove(&request_id);
+                        let Some(state) = state else {
+                            return;
+                        };
+                        state.send(StreamEvent::timeout(timeout));
+                        drop(state);
+                        unsafe { apple_ai_cancel_generation(request_id) };
+                    })
+                }),
+            },
+        );
+    }
+
+    extern "C" fn unified_chunk_cb(request_id: u64, ptr: *const c_char) {
+        let mut streams = unified_streams().lock().unwrap();
+        if ptr.is_null() {
+            if let Some(mut state) = streams.remove(&request_id) {
+                let rest = state.stop.flush();
+                if !rest.is_empty() {
+                    state.send(StreamEvent::text_delta(rest));
+                }
+
+                // Send the end-of-stream signal; cleanup happens when the state is dropped
+                state.send(state.finish_event());
+            }
+            return;
+        }
+
+        // Take ownership and free C string, even for a stream that already ended
+        let slice_owned = take_c_string(ptr as *mut c_char);
+        let Some(state) = streams.get_mut(&request_id) else {
+            return;
+        };
+        if slice_owned.is_empty() || state.stop_reached {
+            return;
+        }
+
+        state.first_token_at.get_or_insert_with(Instant::now);
+        let (text, stopped) = state.stop.push(&slice_owned);
+        if !text.is_empty() {
+            state.send(StreamEvent::text_delta(text));
+        }
+        if stopped {
+            // A stop sequence was reached: stop Swift, which then ends the stream
+            state.stop_reached = true;
+            drop(streams);
+            unsafe { apple_ai_cancel_generation(request_id) };
+        }
+    }
+
+    if let Err(error) = request.check_images() {
+        end_stream(
+            request_id,
+            StreamEvent::error(ErrorCode::MultimodalUnsupported, error.reason),
+        );
+        return Ok(request_id);
+    }
+    if let Err(error) = request.check_language() {
+        end_stream(
+            request_id,
+            StreamEvent::error(ErrorCode::UnsupportedLanguage, error.reason),
+        );
+        return Ok(request_id);
+    }
+    // Fitting counts tokens through Swift and condensing runs a whole
+    // summarization pass; keep both off the JS thread
+    if request.context_policy.is_some() || request.condense.is_some() {
+        std::thread::spawn(move || {
+            if let Err(error) = request.fit_context() {
+                end_stream(request_id, context_exceeded_event(error));
+            } else if let Err(error) = request.invoke(request_id, Some(unified_chunk_cb)) {
+                end_stream(
+                    request_id,
+                    StreamEvent::error(ErrorCode::of(&error), error.reason),
+                );
+            }
+        });
+        return Ok(request_id);
+    }
+    if let Err(error) = request.invoke(request_id, Some(unified_chunk_cb)) {
+        end_stream(
+            request_id,
+            StreamEvent::error(ErrorCode::of(&error), error.reason),
+        );
+    }
+    Ok(request_id)
+}
+
+fn context_exceeded_event(error: napi::Error) -> StreamEvent {
+    StreamEvent::error(ErrorCode::ContextWindowExceeded, error.reason)
+}
+
+/// Swift's object callback for structured streams: each call is a snapshot
+/// of the whole object so far, sent on as an `object-delta` event.
+extern "C" fn stream_object_cb(request_id: u64, json: *const c_char) {
+    if json.is_null() {
+        return;
+    }
+    let json = unsafe { CStr::from_ptr(json) }.to_string_lossy();
+    let Ok(object) = serde_json::from_str::<serde_json::Value>(&json) else {
+        return;
+    };
+    let mut streams = unified_streams().lock().unwrap();
+    if let Some(state) = streams.get_mut(&request_id).filter(|s| !s.stop_reached) {
+        state.first_token_at.get_or_insert_with(Instant::now);
+        state.object = Some(object.clone());
+        state.send(StreamEvent::object_delta(object));
+    }
+}
+
+/// Swift's error callback for streams: ends the stream with an `error` event.
+/// Swift sends no end-of-stream marker after an error.
+extern "C" fn stream_error_cb(request_id: u64, code: *const c_char, message: *const c_char) {
+    let text = |ptr: *const c_char| {
+        if ptr.is_null() {
+            return String::new();
+        }
+        unsafe { CStr::from_ptr(ptr) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    let code = ErrorCode::parse(&text(code)).unwrap_or(ErrorCode::GenerationFailed);
+    {
+        let mut streams = unified_streams().lock().unwrap();
+        if streams.get(&request_id).is_some_and(|s| s.stop_reached) {
+            // The cancellation that followed a stop sequence
+            if let Some(state) = streams.remove(&request_id) {
+                state.send(state.finish_event());
+            }
+            return;
+        }
+    }
+    let mut event = StreamEvent::error(code, text(message));
+    if code == ErrorCode::GuardrailViolation {
+        let output_started = unified_streams()
+            .lock()
+            .unwrap()
+            .get(&request_id)
+            .is_some_and(|s| s.first_token_at.is_some());
+        let side = if output_started { "output" } else { "prompt" };
+        event.guardrail = Some(GuardrailViolation::new(side, event.message.clone()));
+    }
+    end_stream(request_id, event);
+}
+
+/// Send a final event to the stream for `request_id`, if it's still active, and end it.
+fn end_stream(request_id: u64, event: StreamEvent) {
+    let state = unified_streams().lock().unwrap().remove(&request_id);
+    if let Some(state) = state {
+        state.send(event);
+    }
+}
+
+// ---------------- Agent loop ----------------
+
+/// Output of one tool call made during an agent step.
+#[napi(object)]
+#[derive(Clone)]
+pub struct AgentToolResult {
+    pub tool_call_id: String,
+    pub tool_name: String,
+    /// Tool output as the model received it (JSON string)
+    pub result: String,
+}
+
+/// One generate → tool-call → tool-result round of an agent run.
+#[napi(object)]
+#[derive(Clone)]
+pub struct AgentStep {
+    /// 1-based step number
+    pub step: u32,
+    pub text: String,
+    pub tool_calls: Vec<ToolCall>,
+    pub tool_results: Vec<AgentToolResult>,
+    #[napi(ts_type = "'stop' | 'length' | 'tool_calls' | 'content_filter'")]
+    pub finish_reason: String,
+    pub usage: Option<TokenUsage>,
+    /// Timing of this step's tool calls
+    pub tool_traces: Vec<ToolCallTrace>,
+}
+
+/// Final result of an agent run.
+#[napi(object)]
+pub struct AgentResult {
+    /// Text of the last step
+    pub text: String,
+    pub steps: Vec<AgentStep>,
+    /// `tool_calls` if the step limit was reached while the model was still calling tools
+    #[napi(ts_type = "'stop' | 'length' | 'tool_calls' | 'content_filter'")]
+    pub finish_reason: String,
+    /// Usage summed over all steps
+    pub usage: Option<TokenUsage>,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct AgentOptions {
+    /// Most generate → tool → regenerate rounds to run (default 10)
+    pub max_steps: Option<u32>,
+    /// Called with each `AgentStep` as it completes
+    #[napi(ts_type = "(err: Error | null, step: AgentStep) => void")]
+    pub on_step: Option<JsFunction>,
+}
+
+const DEFAULT_MAX_STEPS: u32 = 10;
+
+type AgentStepFn = ThreadsafeFunction<AgentStep, ErrorStrategy::CalleeHandled>;
+
+/// One model generation of an agent step
+type Generate<'a> = dyn FnMut(&mut GenerateUnifiedTask) -> napi::Result<GenerationResult> + 'a;
+
+pub struct AgentTask {
+    task: GenerateUnifiedTask,
+    messages: Vec<ChatMessage>,
+    max_steps: u32,
+    on_step: Option<AgentStepFn>,
+}
+
+impl AgentTask {
+    fn new(
+        messages: Vec<ChatMessage>,
+        options: GenerationOptions,
+        agent: AgentOptions,
+    ) -> napi::Result<Self> {
+        let mut request = GenerationRequest::from_messages(&messages, options)?;
+        if request.prefix.is_some() {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "runAgent does not support assistant prefill".to_string(),
+            ));
+        }
+        if request.n > 1 {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "n > 1 is not supported for agent runs".to_string(),
+            ));
+        }
+        let max_steps = agent.max_steps.unwrap_or(DEFAULT_MAX_STEPS);
+        if max_steps == 0 {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                "maxSteps must be at least 1".to_string(),
+            ));
+        }
+        let on_step = agent
+            .on_step
+            .map(|callback| {
+                callback.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<AgentStep>| {
+                    Ok(vec![ctx.value])
+                })
+            })
+            .transpose()?;
+        // Every step ends at its tool calls; the loop feeds their outputs back
+        request.stop_after_tool_calls = true;
+        request.record_tool_calls = true;
+        Ok(Self {
+            task: GenerateUnifiedTask::new(request),
+            messages,
+            max_steps,
+            on_step,
+        })
+    }
+
+    /// Run one step with a single call to `generate`, and append its tool
+    /// calls and outputs to the conversation.
+    fn step(&mut self, step: u32, generate: &mut Generate<'_>) -> napi::Result<AgentStep> {
+        let (messages_json, images) = messages_to_json(&self.messages)?;
+        self.task.request.messages_json = messages_json;
+        self.task.request.images = images;
+        self.task.fit_context()?;
+        let result = generate(&mut self.task)?;
+
+        let records = take_tool_calls(self.task.request_id);
+        let tool_calls: Vec<ToolCall> = records
+            .iter()
+            .map(|record| ToolCall {
+                id: format!("call_{}", record.call_id),
+                kind: "function".to_string(),
+                function: ToolCallFunction {
+                    name: record.name.clone(),
+                    arguments: record.args_json.clone(),
+                },
+            })
+            .collect();
+        let tool_results: Vec<AgentToolResult> = records
+            .into_iter()
+            .zip(&tool_calls)
+            .map(|(record, call)| AgentToolResult {
+                tool_call_id: call.id.clone(),
+                tool_name: record.name,
+                result: record
+                    .output
+                    .unwrap_or_else(|| tool_error_json("Tool call did not complete")),
+            })
+            .collect();
+
+        if !tool_calls.is_empty() {
+            self.messages.push(ChatMessage {
+                role: "assistant".to_string(),
+                content: (!result.text.is_empty()).then(|| Either::A(result.text.clone())),
+                name: None,
+                tool_call_id: None,
+                tool_calls: Some(tool_calls.clone()),
+            });
+            self.messages
+                .extend(tool_results.iter().map(|output| ChatMessage {
+                    role: "tool".to_string(),
+                    content: Some(Either::A(output.result.clone())),
+                    name: Some(output.tool_name.clone()),
+                    tool_call_id: Some(output.tool_call_id.clone()),
+                    tool_calls: None,
+                }));
+        }
+
+        Ok(AgentStep {
+            step,
+            text: result.text,
+            finish_reason: if tool_calls.is_empty() {
+                result.finish_reason
+            } else {
+                "tool_calls".to_string()
+            },
+            tool_calls,
+            tool_results,
+            usage: result.usage,
+            tool_traces: result.tool_traces.unwrap_or_default(),
+        })
+    }
+
+    /// Run steps until one ends without tool calls or `max_steps` is reached.
+    fn run(&mut self, generate: &mut Generate<'_>) -> napi::Result<AgentResult> {
+        let mut steps: Vec<AgentStep> = Vec::new();
+        for step in 1..=self.max_steps {
+            let agent_step = self.step(step, generate)?;
+            let done = agent_step.tool_calls.is_empty();
+            if let Some(on_step) = &self.on_step {
+                let _ = on_step.call(
+                    Ok(agent_step.clone()),
+                    ThreadsafeFunctionCallMode::NonBlocking,
+                );
+            }
+            steps.push(agent_step);
+            if done {
+                break;
+            }
+        }
+
+        let last = steps.last().expect("max_steps is at least 1");
+        Ok(AgentResult {
+            text: last.text.clone(),
+            finish_reason: last.finish_reason.clone(),
+            usage: total_usage(steps.iter().map(|s| s.usage.as_ref())),
+            steps,
+        })
+    }
+}
+
+impl napi::Task for AgentTask {
+    type Output = AgentResult;
+    type JsValue = AgentResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let _guards = self.task.prepare();
+        self.run(&mut GenerateUnifiedTask::generate_once)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.task.rejection(env, err))
+    }
+}
+
+/// Run the generate → tool-call → tool-result → regenerate loop until the
+/// model answers without calling a tool or `maxSteps` is reached. Tool calls
+/// are answered by the handlers in `options.toolHandlers` (or registered ones).
+#[napi]
+pub fn run_agent(
+    messages: Vec<ChatMessage>,
+    options: Option<GenerationOptions>,
+    agent: Option<AgentOptions>,
+) -> napi::Result<AsyncTask<AgentTask>> {
+    let task = AgentTask::new(
+        messages,
+        options.unwrap_or_default(),
+        agent.unwrap_or_default(),
+    )?;
+    Ok(AsyncTask::new(task))
+}
+
+// ---------------- Positional wrappers (backward compatibility) ----------------
+
+fn positional_options(
+    tools_json: Option<String>,
+    schema_json: Option<String>,
+    temperature: Option<f64>,
+    max_tokens: Option<i32>,
+    stop_after_tool_calls: Option<bool>,
+) -> GenerationOptions {
+    GenerationOptions {
+        tools_json,
+        schema_json,
+        temperature,
+        max_tokens,
+        stop_after_tool_calls,
+        ..Default::default()
+    }
+}
+
+#[napi]
+pub fn generate_unified(
+    messages_json: String,
+    #[napi(ts_arg_type = "string | undefined | null")] tools_json: Option<String>,
+    #[napi(ts_arg_type = "string | undefined | null")] schema_json: Option<String>,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "boolean | undefined")] stop_after_tool_calls: Option<bool>,
+) -> napi::Result<AsyncTask<GenerateUnifiedTask>> {
+    let options = positional_options(
+        tools_json,
+        schema_json,
+        temperature,
+        max_tokens,
+        stop_after_tool_calls,
+    );
+    let request = GenerationRequest::new(messages_json, options)?;
+    Ok(AsyncTask::new(GenerateUnifiedTask::new(request)))
+}
+
+#[napi(ts_return_type = "{ promise: Promise<GenerationResult>; cancel: () => void }")]
+pub fn generate_unified_abortable(
+    env: Env,
+    messages_json: String,
+    #[napi(ts_arg_type = "string | undefined | null")] tools_json: Option<String>,
+    #[napi(ts_arg_type = "string | undefined | null")] schema_json: Option<String>,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "boolean | undefined")] stop_after_tool_calls: Option<bool>,
+) -> napi::Result<JsObject> {
+    let options = positional_options(
+        tools_json,
+        schema_json,
+        temperature,
+        max_tokens,
+        stop_after_tool_calls,
+    );
+    spawn_abortable(env, GenerationRequest::new(messages_json, options)?)
+}
+
+#[napi]
+pub fn generate_unified_stream(
+    messages_json: String,
+    #[napi(ts_arg_type = "string | undefined | null")] tools_json: Option<String>,
+    #[napi(ts_arg_type = "string | undefined | null")] schema_json: Option<String>,
+    #[napi(ts_arg_type = "number | undefined")] temperature: Option<f64>,
+    #[napi(ts_arg_type = "number | undefined")] max_tokens: Option<i32>,
+    #[napi(ts_arg_type = "boolean | undefined")] stop_after_tool_calls: Option<bool>,
+    #[napi(ts_arg_type = "(err: Error | null, event: StreamEvent) => void")] callback: JsFunction,
+) -> napi::Result<()> {
+    let options = positional_options(
+        tools_json,
+        schema_json,
+        temperature,
+        max_tokens,
+        stop_after_tool_calls,
+    );
+    start_stream(GenerationRequest::new(messages_json, options)?, callback)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(stops: &[&str]) -> StopMatcher {
+        StopMatcher::new(stops.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn stop_matcher_passes_text_through_without_stops() {
+        let mut stop = matcher(&[]);
+        assert_eq!(stop.push("Hello, "), ("Hello, ".to_string(), false));
+        assert_eq!(stop.push("world"), ("world".to_string(), false));
+        assert_eq!(stop.flush(), "");
+    }
+
+    #[test]
+    fn stop_matcher_cuts_before_a_stop_in_one_chunk() {
+        let mut stop = matcher(&["END"]);
+        assert_eq!(stop.push("one END two"), ("one ".to_string(), true));
+        assert_eq!(stop.flush(), "");
+    }
+
+    #[test]
+    fn stop_matcher_finds_a_stop_split_across_chunks() {
+        let mut stop = matcher(&["</answer>"]);
+        assert_eq!(stop.push("42</an"), ("42".to_string(), false));
+        assert_eq!(stop.push("swer"), (String::new(), false));
+        assert_eq!(stop.push("> trailing"), (String::new(), true));
+        assert_eq!(stop.flush(), "");
+    }
+
+    #[test]
+    fn stop_matcher_releases_a_false_start() {
+        let mut stop = matcher(&["STOP"]);
+        assert_eq!(stop.push("a ST"), ("a ".to_string(), false));
+        assert_eq!(stop.push("ay"), ("STay".to_string(), false));
+    }
+
+    #[test]
+    fn stop_matcher_uses_the_earliest_of_several_stops() {
+        let mut stop = matcher(&["\n\n", "User:"]);
+        assert_eq!(stop.push("hi User: x\n\n"), ("hi ".to_string(), true));
+    }
+
+    #[test]
+    fn stop_matcher_flushes_the_held_back_tail() {
+        let mut stop = matcher(&["###"]);
+        assert_eq!(stop.push("done #"), ("done ".to_string(), false));
+        assert_eq!(stop.push("#"), (String::new(), false));
+        assert_eq!(stop.flush(), "##");
+        assert_eq!(stop.flush(), "");
+    }
+
+    #[test]
+    fn stop_matcher_holds_back_on_char_boundaries() {
+        let mut stop = matcher(&["éé"]);
+        assert_eq!(stop.push("café"), ("caf".to_string(), false));
+        assert_eq!(stop.flush(), "é");
+    }
+
+    #[test]
+    fn find_stop_names_the_earliest_stop() {
+        let stops = ["\n\n".to_string(), "User:".to_string()];
+        assert_eq!(find_stop("hi User: x\n\n", &stops), Some((3, "User:")));
+        assert_eq!(find_stop("hi", &stops), None);
+    }
+
+    fn read_file(root: &str) -> NativeTool {
+        NativeTool::ReadFile {
+            root: root.into(),
+            max_bytes: 1024,
+            require_approval: false,
+        }
+    }
+
+    #[test]
+    fn tool_cache_keys_sort_argument_keys() {
+        assert_eq!(
+            CacheScope::Registered(1).key("lookup", r#"{"b":1,"a":{"d":2,"c":3}}"#),
+            CacheScope::Registered(1).key("lookup", r#"{ "a": {"c":3,"d":2}, "b":1 }"#)
+        );
+        assert_ne!(
+            CacheScope::Registered(1).key("lookup", r#"{"a":1}"#),
+            CacheScope::Registered(1).key("other", r#"{"a":1}"#)
+        );
+    }
+
+    #[test]
+    fn tool_cache_keys_separate_native_roots() {
+        let mut first = RequestTools::default();
+        first
+            .native
+            .insert("read_file".to_string(), read_file("/srv/a"));
+        let mut second = RequestTools::default();
+        second
+            .native
+            .insert("read_file".to_string(), read_file("/srv/b"));
+        let key = |tools: &RequestTools, request_id| {
+            CacheScope::of(tools, request_id, "read_file")
+                .key("read_file", r#"{"path":"notes.txt"}"#)
+        };
+        assert_ne!(key(&first, 1), key(&second, 2));
+        // The same configuration shares results across requests
+        assert_eq!(key(&first, 1), key(&first, 2));
+    }
+
+    #[test]
+    fn tool_cache_keys_separate_requests_and_connections() {
+        let args = r#"{"q":"x"}"#;
+        assert_ne!(
+            CacheScope::Request(1).key("lookup", args),
+            CacheScope::Request(2).key("lookup", args)
+        );
+        assert_ne!(
+            CacheScope::Mcp(1).key("lookup", args),
+            CacheScope::Mcp(2).key("lookup", args)
+        );
+        assert_ne!(
+            CacheScope::Request(1).key("lookup", args),
+            CacheScope::Registered(1).key("lookup", args)
+        );
+        // Registering a handler again starts over with an empty cache
+        assert_ne!(
+            CacheScope::Registered(1).key("lookup", args),
+            CacheScope::Registered(2).key("lookup", args)
+        );
+        let tools = RequestTools::default();
+        assert!(matches!(
+            CacheScope::of(&tools, 1, "lookup"),
+            CacheScope::Registered(0)
+        ));
+    }
+
+    #[test]
+    fn candidates_are_capped() {
+        let request = |n| {
+            GenerationRequest::new(
+                "[]".to_string(),
+                GenerationOptions {
+                    n: Some(n),
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(request(MAX_CANDIDATES).unwrap().n, MAX_CANDIDATES);
+        for n in [0, MAX_CANDIDATES + 1, u32::MAX] {
+            let reason = request(n).err().unwrap().reason;
+            assert_eq!(reason, format!("n must be from 1 to {MAX_CANDIDATES}"));
+        }
+    }
+
+    #[test]
+    fn agent_steps_generate_once_each() {
+        let messages = vec![ChatMessage {
+            role: "user".to_string(),
+            content: Some(Either::A("What's the weather?".to_string())),
+            name: None,
+            tool_call_id: None,
+            tool_calls: None,
+        }];
+        let options = GenerationOptions {
+            tools_json: Some(
+                r#"[{"type":"function","function":{"name":"weather","parameters":{"type":"object","properties":{}}}}]"#
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        let mut agent = AgentTask::new(messages, options, AgentOptions::default()).unwrap();
+        let _tools = RequestToolsGuard::install(agent.task.request_id, &agent.task.request);
+
+        let mut generations = 0;
+        let result = agent
+            .run(&mut |task: &mut GenerateUnifiedTask| {
+                generations += 1;
+                // Swift must end the turn at the calls rather than answer them too
+                assert!(task.request.stop_after_tool_calls);
+                let raw = if generations == 1 {
+                    let call_id = NEXT_TOOL_CALL_ID.fetch_add(1, Ordering::SeqCst);
+                    record_tool_call(task.request_id, call_id, "weather", "{}");
+                    r#"{"text":"","finishReason":"tool_calls"}"#
+                } else {
+                    r#"{"text":"Sunny.","finishReason":"stop"}"#
+                };
+                let metrics = GenerationMetrics::new(Instant::now(), None, None);
+                parse_generation_result(raw, None, metrics, None)
+            })
+            .unwrap();
+
+        assert_eq!(generations, 2);
+        assert_eq!(result.steps.len(), 2);
+        assert_eq!(result.steps[0].finish_reason, "tool_calls");
+        assert_eq!(result.steps[0].tool_calls.len(), 1);
+        assert_eq!(result.text, "Sunny.");
+        assert_eq!(result.finish_reason, "stop");
     }
-    Ok(())
 }
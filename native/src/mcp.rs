@@ -0,0 +1,638 @@
+//! Model Context Protocol client. Connects to MCP servers over stdio or
+//! Streamable HTTP, lists their tools, and answers the model's calls to those
+//! tools by forwarding them to the server, without a round-trip to JS.
+//!
+//! Connected servers are kept by name; a request exposes their tools through
+//! `GenerationOptions.mcpServers`.
+
+use crate::native_tools::{self, HttpResponse, HttpUrl};
+use napi::bindgen_prelude::AsyncTask;
+use napi::{Env, Status, Task};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+const PROTOCOL_VERSION: &str = "2025-06-18";
+const DEFAULT_TIMEOUT_MS: u32 = 30_000;
+/// Most `tools/list` pages read from one server
+const MAX_TOOL_PAGES: usize = 100;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+static MCP_SERVERS: OnceLock<Mutex<HashMap<String, Arc<McpClient>>>> = OnceLock::new();
+
+fn mcp_servers() -> &'static Mutex<HashMap<String, Arc<McpClient>>> {
+    MCP_SERVERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn invalid(message: impl Into<String>) -> napi::Error {
+    napi::Error::new(Status::InvalidArg, message.into())
+}
+
+/// How to reach an MCP server: set `command` to launch it and talk over
+/// stdio, or `url` for a Streamable HTTP endpoint.
+#[napi(object)]
+pub struct McpServerConfig {
+    /// Name the server is connected under; requests refer to it by this name
+    pub name: String,
+    /// Executable to launch as a stdio server
+    pub command: Option<String>,
+    pub args: Option<Vec<String>>,
+    /// Variables added to the inherited environment of the launched server
+    pub env: Option<HashMap<String, String>>,
+    /// Working directory of the launched server
+    pub cwd: Option<String>,
+    /// `http://` endpoint of a Streamable HTTP server. There is no TLS, so
+    /// `https://` URLs are refused; reach remote servers through a local proxy
+    pub url: Option<String>,
+    /// Extra headers sent with every HTTP request, e.g. `Authorization`.
+    /// Names and values can't contain line breaks
+    pub headers: Option<HashMap<String, String>>,
+    /// How long to wait for each response from the server (default 30000)
+    pub timeout_ms: Option<u32>,
+}
+
+#[napi(object)]
+pub struct McpToolInfo {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[napi(object)]
+pub struct McpServerInfo {
+    /// Name the server is connected under
+    pub name: String,
+    /// Name and version the server reported about itself
+    pub server_name: Option<String>,
+    pub server_version: Option<String>,
+    pub tools: Vec<McpToolInfo>,
+}
+
+/// The server answering each MCP tool, by tool name.
+pub type McpTools = HashMap<String, Arc<McpClient>>;
+
+/// Responses awaited on a stdio connection, by JSON-RPC id.
+type Pending = Arc<Mutex<HashMap<u64, mpsc::Sender<Value>>>>;
+
+struct StdioTransport {
+    child: Mutex<Child>,
+    stdin: Arc<Mutex<ChildStdin>>,
+    pending: Pending,
+}
+
+impl StdioTransport {
+    fn spawn(command: &str, config: &McpServerConfig) -> Result<Self, String> {
+        let mut process = Command::new(command);
+        process
+            .args(config.args.iter().flatten())
+            .envs(config.env.iter().flatten())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        if let Some(cwd) = &config.cwd {
+            process.current_dir(cwd);
+        }
+        let mut child = process
+            .spawn()
+            .map_err(|e| format!("cannot launch '{command}': {e}"))?;
+        let stdin = Arc::new(Mutex::new(child.stdin.take().ok_or("no stdin pipe")?));
+        let stdout = child.stdout.take().ok_or("no stdout pipe")?;
+        let pending = Pending::default();
+
+        let replies = stdin.clone();
+        let waiting = pending.clone();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                    continue;
+                };
+                match (message.get("id"), message.get("method")) {
+                    // A request from the server; only ping is supported
+                    (Some(id), Some(method)) => {
+                        let reply = if method == "ping" {
+                            json!({ "jsonrpc": "2.0", "id": id, "result": {} })
+                        } else {
+                            json!({
+                                "jsonrpc": "2.0",
+                                "id": id,
+                                "error": { "code": -32601, "message": "Method not found" },
+                            })
+                        };
+                        let _ = writeln!(replies.lock().unwrap(), "{reply}");
+                    }
+                    (Some(id), None) => {
+                        let sender = id
+                            .as_u64()
+                            .and_then(|id| waiting.lock().unwrap().remove(&id));
+                        if let Some(sender) = sender {
+                            let _ = sender.send(message);
+                        }
+                    }
+                    // Notifications (progress, list changes, logging) are ignored
+                    _ => {}
+                }
+            }
+            // The server is gone; fail everyone still waiting
+            waiting.lock().unwrap().clear();
+        });
+
+        Ok(Self {
+            child: Mutex::new(child),
+            stdin,
+            pending,
+        })
+    }
+
+    fn send(&self, message: &Value) -> Result<(), String> {
+        let mut stdin = self.stdin.lock().unwrap();
+        writeln!(stdin, "{message}")
+            .and_then(|_| stdin.flush())
+            .map_err(|e| format!("cannot write to the server: {e}"))
+    }
+
+    fn request(&self, id: u64, message: &Value, timeout: Duration) -> Result<Value, String> {
+        let (sender, response) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+        if let Err(message) = self.send(message) {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(message);
+        }
+        response.recv_timeout(timeout).map_err(|e| {
+            self.pending.lock().unwrap().remove(&id);
+            match e {
+                mpsc::RecvTimeoutError::Timeout => {
+                    format!("no response within {}ms", timeout.as_millis())
+                }
+                mpsc::RecvTimeoutError::Disconnected => "the server exited".to_string(),
+            }
+        })
+    }
+}
+
+impl Drop for StdioTransport {
+    fn drop(&mut self) {
+        let child = self.child.get_mut().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Streamable HTTP: every message is a POST answered with JSON or with an
+/// event stream holding the response. Requests use HTTP/1.0 so the response
+/// is never chunked and ends when the server closes the connection.
+struct HttpTransport {
+    url: HttpUrl,
+    headers: Vec<(String, String)>,
+    /// `Mcp-Session-Id` assigned by the server at initialization
+    session: Mutex<Option<String>>,
+}
+
+impl HttpTransport {
+    /// POST one message. Returns the response carrying `id`, or `None` for
+    /// notifications, which the server acknowledges with 202.
+    fn post(
+        &self,
+        message: &Value,
+        id: Option<u64>,
+        timeout: Duration,
+    ) -> Result<Option<Value>, String> {
+        let mut stream = native_tools::connect(&self.url, timeout)?;
+        let body = message.to_string();
+        let mut request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nAccept: application/json, text/event-stream\r\nContent-Length: {}\r\nMCP-Protocol-Version: {PROTOCOL_VERSION}\r\n",
+            self.url.path,
+            self.url.authority,
+            body.len()
+        );
+        if let Some(session) = self.session.lock().unwrap().as_deref() {
+            request += &format!("Mcp-Session-Id: {session}\r\n");
+        }
+        for (name, value) in &self.headers {
+            request += &format!("{name}: {value}\r\n");
+        }
+        request += "\r\n";
+        request += &body;
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|e| format!("request failed: {e}"))?;
+        let mut response = Vec::new();
+        stream
+            .read_to_end(&mut response)
+            .map_err(|e| format!("read failed: {e}"))?;
+
+        let response = HttpResponse::parse(&response)?;
+        if let Some(session) = response.header("mcp-session-id") {
+            *self.session.lock().unwrap() = Some(session);
+        }
+        let body = String::from_utf8_lossy(response.body);
+        if !(200..300).contains(&response.status) {
+            return Err(format!("HTTP {}: {}", response.status, body.trim()));
+        }
+        let Some(id) = id else {
+            return Ok(None);
+        };
+        let event_stream = response
+            .header("content-type")
+            .is_some_and(|kind| kind.starts_with("text/event-stream"));
+        let reply = if event_stream {
+            sse_messages(&body).into_iter().find(|message| {
+                message.get("id").and_then(Value::as_u64) == Some(id)
+                    && message.get("method").is_none()
+            })
+        } else {
+            serde_json::from_str(&body).ok()
+        };
+        reply
+            .map(Some)
+            .ok_or_else(|| "the server sent no response".to_string())
+    }
+}
+
+/// JSON messages in the `data` fields of an event stream.
+fn sse_messages(body: &str) -> Vec<Value> {
+    body.replace("\r\n", "\n")
+        .split("\n\n")
+        .filter_map(|event| {
+            let data = event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                .collect::<Vec<_>>()
+                .join("\n");
+            serde_json::from_str(&data).ok()
+        })
+        .collect()
+}
+
+enum Transport {
+    Stdio(StdioTransport),
+    Http(HttpTransport),
+}
+
+/// A connected MCP server and the tools it offered at connection time.
+pub struct McpClient {
+    /// Unique to this connection, even after a reconnect under the same name
+    connection_id: u64,
+    transport: Transport,
+    next_id: AtomicU64,
+    timeout: Duration,
+    server_name: Option<String>,
+    server_version: Option<String>,
+    /// `tools/list` entries
+    tools: Vec<Value>,
+}
+
+impl McpClient {
+    /// Launch or reach the server, run the initialization handshake, and
+    /// list its tools.
+    fn connect(config: &McpServerConfig) -> Result<Self, String> {
+        let transport = match (&config.command, &config.url) {
+            (Some(command), None) => Transport::Stdio(StdioTransport::spawn(command, config)?),
+            (None, Some(url)) => Transport::Http(HttpTransport {
+                url: native_tools::parse_http_url(url)?,
+                headers: config
+                    .headers
+                    .iter()
+                    .flatten()
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+                session: Mutex::new(None),
+            }),
+            _ => return Err("set exactly one of command and url".to_string()),
+        };
+        let mut client = Self {
+            transport,
+            connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            next_id: AtomicU64::new(1),
+            timeout: Duration::from_millis(
+                config
+                    .timeout_ms
+                    .unwrap_or(DEFAULT_TIMEOUT_MS)
+                    .max(1)
+                    .into(),
+            ),
+            server_name: None,
+            server_version: None,
+            tools: Vec::new(),
+        };
+
+        let initialized = client.request(
+            "initialize",
+            json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": {},
+                "clientInfo": { "name": "apple-ai", "version": env!("CARGO_PKG_VERSION") },
+            }),
+        )?;
+        let info = |key: &str| {
+            initialized
+                .pointer(&format!("/serverInfo/{key}"))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
+        client.server_name = info("name");
+        client.server_version = info("version");
+        client.notify("notifications/initialized")?;
+
+        let mut cursor: Option<String> = None;
+        for _ in 0..MAX_TOOL_PAGES {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let page = client.request("tools/list", params)?;
+            if let Some(tools) = page.get("tools").and_then(Value::as_array) {
+                client.tools.extend(tools.iter().cloned());
+            }
+            cursor = page
+                .get("nextCursor")
+                .and_then(Value::as_str)
+                .map(str::to_string);
+            if cursor.is_none() {
+                break;
+            }
+        }
+        Ok(client)
+    }
+
+    fn request(&self, method: &str, params: Value) -> Result<Value, String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let response = match &self.transport {
+            Transport::Stdio(stdio) => stdio.request(id, &message, self.timeout)?,
+            Transport::Http(http) => http
+                .post(&message, Some(id), self.timeout)?
+                .unwrap_or_default(),
+        };
+        if let Some(error) = response.get("error") {
+            let message = error
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown error");
+            return Err(format!("{method} failed: {message}"));
+        }
+        Ok(response.get("result").cloned().unwrap_or_default())
+    }
+
+    fn notify(&self, method: &str) -> Result<(), String> {
+        let message = json!({ "jsonrpc": "2.0", "method": method });
+        match &self.transport {
+            Transport::Stdio(stdio) => stdio.send(&message),
+            Transport::Http(http) => http.post(&message, None, self.timeout).map(|_| ()),
+        }
+    }
+
+    fn tool_names(&self) -> impl Iterator<Item = &str> {
+        self.tools
+            .iter()
+            .filter_map(|tool| tool.get("name").and_then(Value::as_str))
+    }
+
+    /// Tool definitions in the shape of the `toolsJson` entries, minus the id.
+    fn definitions(&self) -> Vec<Value> {
+        self.tools
+            .iter()
+            .filter(|tool| tool.get("name").is_some_and(Value::is_string))
+            .map(|tool| {
+                json!({
+                    "name": tool["name"],
+                    "description": tool.get("description").and_then(Value::as_str).unwrap_or_default(),
+                    "parameters": tool
+                        .get("inputSchema")
+                        .cloned()
+                        .unwrap_or_else(|| json!({ "type": "object", "properties": {} })),
+                })
+            })
+            .collect()
+    }
+
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// Call a tool on the server. `Ok` is the JSON output; `Err` is an error
+    /// message for the model, including errors the tool itself reported.
+    pub fn call_tool(&self, name: &str, args: Value) -> Result<String, String> {
+        let result = self.request("tools/call", json!({ "name": name, "arguments": args }))?;
+        let text = result
+            .get("content")
+            .and_then(Value::as_array)
+            .map(|items| {
+                items
+                    .iter()
+                    .filter_map(content_text)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+            .unwrap_or_default();
+        if result.get("isError").and_then(Value::as_bool) == Some(true) {
+            return Err(text);
+        }
+        Ok(match result.get("structuredContent") {
+            Some(structured) => structured.to_string(),
+            None => json!({ "content": text }).to_string(),
+        })
+    }
+
+    fn info(&self, name: &str) -> McpServerInfo {
+        McpServerInfo {
+            name: name.to_string(),
+            server_name: self.server_name.clone(),
+            server_version: self.server_version.clone(),
+            tools: self
+                .tools
+                .iter()
+                .filter_map(|tool| {
+                    Some(McpToolInfo {
+                        name: tool.get("name")?.as_str()?.to_string(),
+                        description: tool
+                            .get("description")
+                            .and_then(Value::as_str)
+                            .map(str::to_string),
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Text the model sees for one item of a tool result's `content`. Binary
+/// items are named rather than included.
+fn content_text(item: &Value) -> Option<String> {
+    let field = |key: &str| item.get(key).and_then(Value::as_str);
+    match field("type")? {
+        "text" => field("text").map(str::to_string),
+        "resource" => {
+            let resource = item.get("resource")?;
+            resource
+                .get("text")
+                .or_else(|| resource.get("uri"))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        }
+        "resource_link" => field("uri").map(|uri| format!("[resource {uri}]")),
+        kind => Some(format!("[{kind}]")),
+    }
+}
+
+/// Tool definitions and the server answering each tool, for the named
+/// connected servers.
+pub fn tools_for(names: &[String]) -> napi::Result<(Vec<Value>, McpTools)> {
+    let servers = mcp_servers().lock().unwrap();
+    let mut definitions = Vec::new();
+    let mut clients = HashMap::new();
+    for name in names {
+        let client = servers
+            .get(name)
+            .ok_or_else(|| invalid(format!("no MCP server named '{name}' is connected")))?;
+        definitions.extend(client.definitions());
+        for tool in client.tool_names() {
+            clients.insert(tool.to_string(), client.clone());
+        }
+    }
+    Ok((definitions, clients))
+}
+
+pub struct ConnectTask {
+    config: McpServerConfig,
+}
+
+impl Task for ConnectTask {
+    type Output = McpServerInfo;
+    type JsValue = McpServerInfo;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let name = &self.config.name;
+        let client = McpClient::connect(&self.config).map_err(|e| {
+            napi::Error::from_reason(format!("Cannot connect to MCP server '{name}': {e}"))
+        })?;
+        let info = client.info(name);
+        mcp_servers()
+            .lock()
+            .unwrap()
+            .insert(name.clone(), Arc::new(client));
+        Ok(info)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+/// A header written into the request as is, so a line break in it would
+/// end the header or smuggle in another one.
+fn check_header(name: &str, value: &str) -> napi::Result<()> {
+    let token = |c: char| c.is_ascii_graphic() && c != ':';
+    if name.is_empty() || !name.chars().all(token) {
+        return Err(invalid(format!("header name '{name}' is not valid")));
+    }
+    if value.contains(['\r', '\n', '\0']) {
+        return Err(invalid(format!(
+            "header '{name}' must not contain line breaks"
+        )));
+    }
+    Ok(())
+}
+
+/// Connect to an MCP server and list its tools. Connecting under a name
+/// already in use replaces that connection.
+#[napi]
+pub fn connect_mcp_server(config: McpServerConfig) -> napi::Result<AsyncTask<ConnectTask>> {
+    if config.name.is_empty() {
+        return Err(invalid("name must not be empty"));
+    }
+    if config.command.is_some() == config.url.is_some() {
+        return Err(invalid("set exactly one of command and url"));
+    }
+    if config.timeout_ms == Some(0) {
+        return Err(invalid("timeoutMs must be at least 1"));
+    }
+    if let Some(url) = &config.url {
+        native_tools::parse_http_url(url).map_err(invalid)?;
+    }
+    for (name, value) in config.headers.iter().flatten() {
+        check_header(name, value)?;
+    }
+    Ok(AsyncTask::new(ConnectTask { config }))
+}
+
+/// Disconnect the named MCP server, stopping it if it was launched. Calls
+/// already in progress finish first. Returns whether it was connected.
+#[napi]
+pub fn disconnect_mcp_server(name: String) -> bool {
+    mcp_servers().lock().unwrap().remove(&name).is_some()
+}
+
+/// The connected MCP servers and their tools.
+#[napi]
+pub fn list_mcp_servers() -> Vec<McpServerInfo> {
+    let mut servers: Vec<_> = mcp_servers()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, client)| client.info(name))
+        .collect();
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+    servers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(url: &str, headers: &[(&str, &str)]) -> McpServerConfig {
+        McpServerConfig {
+            name: "remote".into(),
+            command: None,
+            args: None,
+            env: None,
+            cwd: None,
+            url: Some(url.into()),
+            headers: Some(
+                headers
+                    .iter()
+                    .map(|&(name, value)| (name.into(), value.into()))
+                    .collect(),
+            ),
+            timeout_ms: None,
+        }
+    }
+
+    fn rejection(config: McpServerConfig) -> String {
+        connect_mcp_server(config).err().expect("rejected").reason
+    }
+
+    #[test]
+    fn headers_cannot_break_the_request() {
+        let url = "http://localhost:3001/mcp";
+        assert!(connect_mcp_server(config(url, &[("Authorization", "Bearer a b")])).is_ok());
+        for value in ["x\r\nX-Admin: 1", "x\n", "x\r", "x\0"] {
+            let reason = rejection(config(url, &[("Authorization", value)]));
+            assert!(reason.contains("line breaks"), "{value:?}: {reason}");
+        }
+        for name in ["", "X-A\r\nX-B", "X A", "X-A:", "Ä"] {
+            let reason = rejection(config(url, &[(name, "1")]));
+            assert!(reason.contains("is not valid"), "{name:?}: {reason}");
+        }
+    }
+
+    #[test]
+    fn only_http_urls_are_accepted() {
+        for url in [
+            "https://example.com/mcp",
+            "ftp://example.com",
+            "localhost:3001",
+        ] {
+            assert_eq!(
+                connect_mcp_server(config(url, &[])).err().unwrap().status,
+                Status::InvalidArg,
+                "{url}"
+            );
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! Model Context Protocol server. Serves the on-device model as a `generate`
+//! tool, next to chosen tools from `registerTool` and static text resources,
+//! so editors and other MCP hosts can use the model through this process.
+//! Speaks newline-delimited JSON-RPC over stdio, or Streamable HTTP (JSON
+//! responses only) at `/mcp`.
+
+use crate::server::{self, HttpRequest};
+use crate::{
+    registered_tool_definitions, schema, tool_registry, ChatMessage, GenerateUnifiedTask,
+    GenerationOptions, GenerationRequest,
+};
+use napi::bindgen_prelude::Either;
+use napi::threadsafe_function::ThreadsafeFunctionCallMode;
+use napi::{Env, JsObject, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// Protocol versions this server speaks, newest first
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+const DEFAULT_PORT: u32 = 8081;
+const DEFAULT_HOST: &str = "127.0.0.1";
+const GENERATE_TOOL: &str = "generate";
+/// How long a JS tool handler has to answer a call from an MCP client
+const TOOL_CALL_TIMEOUT: Duration = Duration::from_secs(120);
+/// Ids for calls from MCP clients, above those Swift assigns. Handlers get
+/// ids as 32-bit numbers, so stay within that range.
+const FIRST_CALL_ID: u64 = 1 << 31;
+
+/// Where a JS handler's output or error message goes
+type CallAnswer = mpsc::Sender<Result<String, String>>;
+
+static NEXT_CALL_ID: AtomicU64 = AtomicU64::new(FIRST_CALL_ID);
+/// Calls from MCP clients waiting on a JS handler, by call id
+static WAITING_CALLS: OnceLock<Mutex<HashMap<u64, CallAnswer>>> = OnceLock::new();
+/// Set while a stdio server owns stdin and stdout
+static STDIO_IN_USE: AtomicBool = AtomicBool::new(false);
+
+fn waiting_calls() -> &'static Mutex<HashMap<u64, CallAnswer>> {
+    WAITING_CALLS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Deliver a handler's answer to a call from an MCP client. Returns false
+/// if `call_id` isn't one, so the answer belongs to a generation.
+pub fn answer_call(call_id: u64, outcome: Result<&str, &str>) -> bool {
+    match waiting_calls().lock().unwrap().remove(&call_id) {
+        Some(sender) => {
+            let _ = sender.send(outcome.map(str::to_string).map_err(str::to_string));
+            true
+        }
+        None => false,
+    }
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct McpServerOptions {
+    /// `stdio` (default) serves on this process's stdin and stdout, for hosts
+    /// that launch the server; `http` listens for Streamable HTTP at `/mcp`
+    #[napi(ts_type = "'stdio' | 'http'")]
+    pub transport: Option<String>,
+    /// HTTP port (default 8081); 0 picks a free one
+    pub port: Option<u32>,
+    /// HTTP address to bind (default `127.0.0.1`, local connections only)
+    pub host: Option<String>,
+    /// Require `Authorization: Bearer <apiKey>` on every HTTP request
+    pub api_key: Option<String>,
+    /// Names of tools from `registerTool` to serve; each must have been
+    /// registered with a definition
+    pub tools: Option<Vec<String>>,
+    /// Text resources to serve
+    pub resources: Option<Vec<McpResource>>,
+}
+
+#[napi(object)]
+#[derive(Clone)]
+pub struct McpResource {
+    pub uri: String,
+    pub name: String,
+    pub description: Option<String>,
+    /// Default `text/plain`
+    pub mime_type: Option<String>,
+    pub text: String,
+}
+
+/// What the server offers and how it answers each JSON-RPC message.
+struct McpService {
+    /// Names of the registered tools served next to `generate`
+    tools: Vec<String>,
+    resources: Vec<McpResource>,
+}
+
+fn rpc_error(id: &Value, code: i32, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+/// A `tools/call` result holding one text item.
+fn text_result(text: &str, is_error: bool) -> Value {
+    json!({ "content": [{ "type": "text", "text": text }], "isError": is_error })
+}
+
+impl McpService {
+    /// The reply to one message, or `None` for notifications and responses.
+    fn handle(&self, message: &Value) -> Option<Value> {
+        let Some(method) = message.get("method").and_then(Value::as_str) else {
+            return match message.get("id") {
+                // A response to a request we never send
+                Some(_) if message.get("result").is_some() || message.get("error").is_some() => {
+                    None
+                }
+                _ => Some(rpc_error(&Value::Null, -32600, "Invalid request")),
+            };
+        };
+        let id = message.get("id")?;
+        let params = message.get("params").cloned().unwrap_or_else(|| json!({}));
+        let result = match method {
+            "initialize" => Ok(self.initialize(&params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": self.tool_definitions() })),
+            "tools/call" => self.call_tool(&params),
+            "resources/list" => Ok(json!({
+                "resources": self.resources.iter().map(|resource| json!({
+                    "uri": resource.uri,
+                    "name": resource.name,
+                    "description": resource.description,
+                    "mimeType": resource.mime_type.as_deref().unwrap_or("text/plain"),
+                })).collect::<Vec<_>>(),
+            })),
+            "resources/read" => self.read_resource(&params),
+            _ => Err((-32601, format!("Method not found: {method}"))),
+        };
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => rpc_error(id, code, &message),
+        })
+    }
+
+    fn initialize(&self, params: &Value) -> Value {
+        let requested = params.get("protocolVersion").and_then(Value::as_str);
+        let version = requested
+            .filter(|version| PROTOCOL_VERSIONS.contains(version))
+            .unwrap_or(PROTOCOL_VERSIONS[0]);
+        let mut capabilities = json!({ "tools": {} });
+        if !self.resources.is_empty() {
+            capabilities["resources"] = json!({});
+        }
+        json!({
+            "protocolVersion": version,
+            "capabilities": capabilities,
+            "serverInfo": { "name": "apple-on-device-ai", "version": env!("CARGO_PKG_VERSION") },
+            "instructions": "Use the generate tool to run a prompt on Apple's on-device foundation model.",
+        })
+    }
+
+    fn tool_definitions(&self) -> Vec<Value> {
+        let mut tools = vec![json!({
+            "name": GENERATE_TOOL,
+            "description": "Generate a response with Apple's on-device foundation model. Runs locally and privately; best for summarizing, rewriting, extracting and classifying text.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "prompt": { "type": "string", "description": "What to ask the model" },
+                    "system": { "type": "string", "description": "Instructions for the model" },
+                    "schema": {
+                        "type": "object",
+                        "description": "JSON Schema the answer must match; the answer is then returned as structured content",
+                    },
+                    "temperature": { "type": "number", "minimum": 0, "maximum": 2 },
+                    "maxTokens": { "type": "integer", "minimum": 1 },
+                },
+                "required": ["prompt"],
+            },
+        })];
+        // Tools unregistered since the server started are left out
+        let registry = tool_registry().lock().unwrap();
+        tools.extend(self.tools.iter().filter_map(|name| {
+            let definition = registry.get(name)?.definition.as_ref()?;
+            Some(json!({
+                "name": name,
+                "description": definition.get("description").and_then(Value::as_str).unwrap_or_default(),
+                "inputSchema": definition
+                    .get("parameters")
+                    .cloned()
+                    .unwrap_or_else(|| json!({ "type": "object", "properties": {} })),
+            }))
+        }));
+        tools
+    }
+
+    fn call_tool(&self, params: &Value) -> Result<Value, (i32, String)> {
+        let name = params
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or((-32602, "params.name is required".to_string()))?;
+        let args = params
+            .get("arguments")
+            .cloned()
+            .unwrap_or_else(|| json!({}));
+        if name == GENERATE_TOOL {
+            return Ok(generate(&args));
+        }
+        if !self.tools.iter().any(|tool| tool == name) {
+            return Err((-32602, format!("Unknown tool: {name}")));
+        }
+        Ok(call_registered_tool(name, &args))
+    }
+
+    fn read_resource(&self, params: &Value) -> Result<Value, (i32, String)> {
+        let uri = params
+            .get("uri")
+            .and_then(Value::as_str)
+            .ok_or((-32602, "params.uri is required".to_string()))?;
+        let resource = self
+            .resources
+            .iter()
+            .find(|resource| resource.uri == uri)
+            .ok_or((-32002, format!("Resource not found: {uri}")))?;
+        Ok(json!({
+            "contents": [{
+                "uri": resource.uri,
+                "mimeType": resource.mime_type.as_deref().unwrap_or("text/plain"),
+                "text": resource.text,
+            }],
+        }))
+    }
+}
+
+fn text_message(role: &str, text: &str) -> ChatMessage {
+    ChatMessage {
+        role: role.to_string(),
+        content: Some(Either::A(text.to_string())),
+        name: None,
+        tool_call_id: None,
+        tool_calls: None,
+    }
+}
+
+/// Run the `generate` tool. Failures are tool errors the client's model can
+/// read, not protocol errors.
+fn generate(args: &Value) -> Value {
+    let Some(prompt) = args.get("prompt").and_then(Value::as_str) else {
+        return text_result("missing string argument 'prompt'", true);
+    };
+    let mut messages = Vec::new();
+    if let Some(system) = args.get("system").and_then(Value::as_str) {
+        messages.push(text_message("system", system));
+    }
+    messages.push(text_message("user", prompt));
+    let options = GenerationOptions {
+        schema_json: args
+            .get("schema")
+            .filter(|schema| schema.is_object())
+            .map(Value::to_string),
+        temperature: args.get("temperature").and_then(Value::as_f64),
+        max_tokens: args
+            .get("maxTokens")
+            .and_then(Value::as_i64)
+            .map(|n| n.clamp(0, i32::MAX.into()) as i32),
+        ..Default::default()
+    };
+    let result = GenerationRequest::from_messages(&messages, options).and_then(|request| {
+        let mut task = GenerateUnifiedTask::new(request);
+        napi::Task::compute(&mut task)
+    });
+    match result {
+        Ok(result) if result.finish_reason == "content_filter" => {
+            text_result("The model's safety guardrails blocked this request.", true)
+        }
+        Ok(result) => match result.object {
+            Some(object) => json!({
+                "content": [{ "type": "text", "text": object.to_string() }],
+                "structuredContent": object,
+                "isError": false,
+            }),
+            None => text_result(&result.text, false),
+        },
+        Err(error) => text_result(&error.reason, true),
+    }
+}
+
+/// Hand a call to the tool's JS handler and wait for `toolResult` or
+/// `toolError`. Arguments that don't match the tool's parameters are
+/// answered with the validation errors without calling the handler.
+fn call_registered_tool(name: &str, args: &Value) -> Value {
+    let (handler, parameters) = {
+        let registry = tool_registry().lock().unwrap();
+        let Some(tool) = registry.get(name) else {
+            return text_result(&format!("tool '{name}' is no longer registered"), true);
+        };
+        let parameters = tool
+            .definition
+            .as_ref()
+            .and_then(|definition| definition.get("parameters").cloned());
+        (tool.handler.clone(), parameters)
+    };
+    if let Some(parameters) = parameters {
+        let errors = match schema::Schema::compile(parameters) {
+            Ok(parameters) => parameters.validate(args),
+            Err(message) => vec![schema::ValidationError {
+                path: String::new(),
+                message,
+            }],
+        };
+        if !errors.is_empty() {
+            return text_result(&schema::invalid_arguments_result(name, &errors), true);
+        }
+    }
+
+    let call_id = NEXT_CALL_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, outcome) = mpsc::channel();
+    waiting_calls().lock().unwrap().insert(call_id, sender);
+    let status = handler.call(
+        Ok((call_id, args.to_string())),
+        ThreadsafeFunctionCallMode::NonBlocking,
+    );
+    let outcome = if status == Status::Ok {
+        outcome
+            .recv_timeout(TOOL_CALL_TIMEOUT)
+            .unwrap_or_else(|_| Err("the tool handler did not answer in time".to_string()))
+    } else {
+        Err("Tool handler could not be called".to_string())
+    };
+    waiting_calls().lock().unwrap().remove(&call_id);
+    match outcome {
+        Ok(output) => text_result(&output, false),
+        Err(message) => text_result(&message, true),
+    }
+}
+
+/// Answer one line or body of JSON-RPC.
+fn handle_json(service: &McpService, body: &str) -> Option<Value> {
+    match serde_json::from_str::<Value>(body) {
+        Ok(message) if message.is_object() => service.handle(&message),
+        Ok(_) => Some(rpc_error(&Value::Null, -32600, "Invalid request")),
+        Err(_) => Some(rpc_error(&Value::Null, -32700, "Parse error")),
+    }
+}
+
+/// Serve on stdin and stdout. Each message is answered on its own thread so
+/// long generations don't hold up pings or other calls.
+fn serve_stdio(service: Arc<McpService>, stopped: Arc<AtomicBool>) {
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let service = service.clone();
+            std::thread::spawn(move || {
+                if let Some(reply) = handle_json(&service, &line) {
+                    let mut stdout = std::io::stdout().lock();
+                    let _ = writeln!(stdout, "{reply}").and_then(|_| stdout.flush());
+                }
+            });
+        }
+        STDIO_IN_USE.store(false, Ordering::SeqCst);
+    });
+}
+
+fn handle_http(mut stream: TcpStream, service: &McpService, api_key: Option<&str>) {
+    let Some(HttpRequest {
+        method, path, body, ..
+    }) = server::accept_request(&mut stream, api_key)
+    else {
+        return;
+    };
+    match (method.as_str(), path.as_str()) {
+        ("POST", "/mcp") => {
+            let body = String::from_utf8_lossy(&body);
+            match handle_json(service, &body) {
+                Some(reply) => server::respond(&mut stream, 200, &reply),
+                None => {
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    );
+                }
+            }
+        }
+        // No server-initiated stream is offered
+        (_, "/mcp") => server::respond_http_error(
+            &mut stream,
+            405,
+            "invalid_request_error",
+            &format!("{method} is not allowed here"),
+        ),
+        (_, path) => server::respond_http_error(
+            &mut stream,
+            404,
+            "invalid_request_error",
+            &format!("No route for {path}"),
+        ),
+    }
+}
+
+/// Start serving the on-device model over MCP. Over HTTP the returned
+/// handle is `{ url, port, stop }`; over stdio it is `{ stop }`. `stop()`
+/// stops taking new messages and lets calls in progress finish.
+#[napi(ts_return_type = "{ url?: string; port?: number; stop: () => void }")]
+pub fn start_mcp_server(env: Env, options: Option<McpServerOptions>) -> napi::Result<JsObject> {
+    let options = options.unwrap_or_default();
+    let tools = options.tools.unwrap_or_default();
+    // Fails for tools that aren't registered with a definition
+    registered_tool_definitions(&tools)?;
+    if tools.iter().any(|name| name == GENERATE_TOOL) {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("a registered tool can't be served as '{GENERATE_TOOL}'; that name is taken"),
+        ));
+    }
+    let service = Arc::new(McpService {
+        tools,
+        resources: options.resources.unwrap_or_default(),
+    });
+
+    match options.transport.as_deref().unwrap_or("stdio") {
+        "stdio" => {
+            if STDIO_IN_USE.swap(true, Ordering::SeqCst) {
+                return Err(napi::Error::new(
+                    Status::GenericFailure,
+                    "an MCP server is already serving on stdio".to_string(),
+                ));
+            }
+            crate::ensure_initialized();
+            let stopped = Arc::new(AtomicBool::new(false));
+            serve_stdio(service, stopped.clone());
+            let stop = env.create_function_from_closure("stop", move |ctx| {
+                stopped.store(true, Ordering::SeqCst);
+                ctx.env.get_undefined()
+            })?;
+            let mut handle = env.create_object()?;
+            handle.set_named_property("stop", stop)?;
+            Ok(handle)
+        }
+        "http" => {
+            let host = options.host.unwrap_or_else(|| DEFAULT_HOST.to_string());
+            let api_key: Option<Arc<str>> =
+                options.api_key.filter(|key| !key.is_empty()).map(Arc::from);
+            server::listen(
+                env,
+                &host,
+                options.port.unwrap_or(DEFAULT_PORT),
+                move |connection| handle_http(connection, &service, api_key.as_deref()),
+            )
+        }
+        other => Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("transport must be \"stdio\" or \"http\", got {other:?}"),
+        )),
+    }
+}
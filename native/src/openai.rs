@@ -0,0 +1,857 @@
+//! OpenAI `chat.completions` compatibility: a request body in OpenAI's
+//! format runs through the unified generation call, and the result comes
+//! back as an OpenAI `chat.completion` object, or as `chat.completion.chunk`
+//! server-sent events when streamed.
+
+use crate::{
+    next_request_id, start_formatted_stream, ChatMessage, ContentPart, ErrorCode,
+    GenerateUnifiedTask, GenerationOptions, GenerationRequest, GenerationResult, SamplingOptions,
+    StreamEvent, ToolCall, ToolCallFunction, MAX_CANDIDATES,
+};
+use napi::bindgen_prelude::{AsyncTask, Either};
+use napi::{Env, JsFunction, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Model name reported when the request doesn't name one.
+pub const MODEL_NAME: &str = "apple-on-device";
+
+fn invalid(reason: impl std::fmt::Display) -> napi::Error {
+    napi::Error::new(Status::InvalidArg, reason.to_string())
+}
+
+/// A parsed `chat.completions` request.
+pub struct ChatCompletionRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub options: GenerationOptions,
+    pub stream: bool,
+    /// `stream_options.include_usage`: end the stream with a usage chunk
+    pub include_usage: bool,
+}
+
+/// Translate an OpenAI request body. Fields without an on-device
+/// equivalent that don't change the answer (`user`, `store`, ...) are
+/// ignored; ones that would are rejected.
+pub fn parse_request(request_json: &str) -> napi::Result<ChatCompletionRequest> {
+    let body: Value = serde_json::from_str(request_json)
+        .map_err(|e| invalid(format!("Invalid request JSON: {e}")))?;
+    if !body.is_object() {
+        return Err(invalid("request must be a JSON object"));
+    }
+    let messages = body
+        .get("messages")
+        .and_then(Value::as_array)
+        .ok_or_else(|| invalid("messages is required and must be an array"))?
+        .iter()
+        .enumerate()
+        .map(|(index, message)| parse_message(index, message))
+        .collect::<napi::Result<_>>()?;
+
+    let number = |key: &str| body.get(key).and_then(Value::as_f64);
+    let tools_json = match body.get("tool_choice") {
+        Some(Value::String(choice)) if choice == "none" => None,
+        None | Some(Value::Null) => parse_tools(body.get("tools"))?,
+        Some(Value::String(choice)) if choice == "auto" => parse_tools(body.get("tools"))?,
+        Some(other) => {
+            return Err(invalid(format!(
+                "tool_choice {other} is not supported; use \"auto\" or \"none\""
+            )))
+        }
+    };
+    // Wrapping out-of-range seeds would make different seeds sample alike
+    let seed = match body.get("seed") {
+        None | Some(Value::Null) => None,
+        Some(seed) => Some(
+            seed.as_u64()
+                .and_then(|seed| u32::try_from(seed).ok())
+                .ok_or_else(|| {
+                    invalid(format!("seed must be an integer from 0 to {}", u32::MAX))
+                })?,
+        ),
+    };
+    let n = match body.get("n") {
+        None | Some(Value::Null) => None,
+        Some(n) => Some(
+            n.as_u64()
+                .and_then(|n| u32::try_from(n).ok())
+                .ok_or_else(|| {
+                    invalid(format!("n must be an integer from 1 to {MAX_CANDIDATES}"))
+                })?,
+        ),
+    };
+    let options = GenerationOptions {
+        tools_json,
+        schema_json: parse_response_format(body.get("response_format"))?,
+        temperature: number("temperature"),
+        max_tokens: body
+            .get("max_completion_tokens")
+            .or_else(|| body.get("max_tokens"))
+            .and_then(Value::as_i64)
+            .map(|n| n.clamp(0, i32::MAX.into()) as i32),
+        stop: match body.get("stop") {
+            Some(Value::String(stop)) => Some(vec![stop.clone()]),
+            Some(Value::Array(stops)) => Some(
+                stops
+                    .iter()
+                    .filter_map(|s| s.as_str().map(str::to_string))
+                    .collect(),
+            ),
+            _ => None,
+        },
+        sampling: Some(SamplingOptions {
+            top_p: number("top_p"),
+            seed,
+            ..Default::default()
+        }),
+        n,
+        // The caller runs the tools and sends their output back, as with OpenAI
+        stop_after_tool_calls: Some(true),
+        ..Default::default()
+    };
+    Ok(ChatCompletionRequest {
+        model: body
+            .get("model")
+            .and_then(Value::as_str)
+            .unwrap_or(MODEL_NAME)
+            .to_string(),
+        messages,
+        options,
+        stream: body.get("stream").and_then(Value::as_bool).unwrap_or(false),
+        include_usage: body
+            .pointer("/stream_options/include_usage")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+    })
+}
+
+fn parse_message(index: usize, message: &Value) -> napi::Result<ChatMessage> {
+    let field = |key: &str| message.get(key).and_then(Value::as_str).map(str::to_string);
+    let role = match field("role").as_deref() {
+        // Newer models call the system role "developer"
+        Some("developer") => "system".to_string(),
+        Some(role) => role.to_string(),
+        None => return Err(invalid(format!("messages[{index}]: role is required"))),
+    };
+    let content = match message.get("content") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(text)) => Some(Either::A(text.clone())),
+        Some(Value::Array(parts)) => Some(Either::B(
+            parts
+                .iter()
+                .enumerate()
+                .map(|(part, value)| parse_content_part(index, part, value))
+                .collect::<napi::Result<_>>()?,
+        )),
+        Some(_) => {
+            return Err(invalid(format!(
+                "messages[{index}]: content must be a string or an array of parts"
+            )))
+        }
+    };
+    let tool_calls = message
+        .get("tool_calls")
+        .and_then(Value::as_array)
+        .map(|calls| {
+            calls
+                .iter()
+                .map(|call| {
+                    let function = call.get("function");
+                    let text = |v: Option<&Value>| v.and_then(Value::as_str).map(str::to_string);
+                    Some(ToolCall {
+                        id: text(call.get("id"))?,
+                        kind: "function".to_string(),
+                        function: ToolCallFunction {
+                            name: text(function?.get("name"))?,
+                            arguments: text(function?.get("arguments"))
+                                .unwrap_or_else(|| "{}".to_string()),
+                        },
+                    })
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "messages[{index}]: each tool call needs an id and a function name"
+                    ))
+                })
+        })
+        .transpose()?;
+    Ok(ChatMessage {
+        role,
+        content,
+        name: field("name"),
+        tool_call_id: field("tool_call_id"),
+        tool_calls,
+    })
+}
+
+fn parse_content_part(index: usize, part: usize, value: &Value) -> napi::Result<ContentPart> {
+    let text_part = |text: Option<String>| ContentPart {
+        kind: "text".to_string(),
+        text,
+        data: None,
+        image_path: None,
+        max_dimension: None,
+        orientation: None,
+        mime_type: None,
+    };
+    match value.get("type").and_then(Value::as_str) {
+        Some("text") => Ok(text_part(
+            value.get("text").and_then(Value::as_str).map(str::to_string),
+        )),
+        Some("image_url") => {
+            let url = value
+                .pointer("/image_url/url")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            // Remote images aren't fetched; only inline data URLs are accepted
+            let (mime_type, data) = url
+                .strip_prefix("data:")
+                .and_then(|rest| rest.split_once(";base64,"))
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "messages[{index}]: content[{part}] image_url must be a base64 data: URL"
+                    ))
+                })?;
+            Ok(ContentPart {
+                kind: "image".to_string(),
+                data: Some(Either::A(data.to_string())),
+                mime_type: Some(mime_type.to_string()).filter(|m| !m.is_empty()),
+                ..text_part(None)
+            })
+        }
+        other => Err(invalid(format!(
+            "messages[{index}]: content[{part}] has unsupported type {other:?}, expected text or image_url"
+        ))),
+    }
+}
+
+/// OpenAI `tools` as the definitions the native layer takes.
+fn parse_tools(tools: Option<&Value>) -> napi::Result<Option<String>> {
+    let Some(tools) = tools.filter(|t| !t.is_null()) else {
+        return Ok(None);
+    };
+    let tools = tools
+        .as_array()
+        .ok_or_else(|| invalid("tools must be an array"))?;
+    if tools.is_empty() {
+        return Ok(None);
+    }
+    let definitions = tools
+        .iter()
+        .enumerate()
+        .map(|(i, tool)| {
+            if tool.get("type").and_then(Value::as_str) != Some("function") {
+                return Err(invalid(format!("tools[{i}]: only function tools are supported")));
+            }
+            let function = tool.get("function").unwrap_or(&Value::Null);
+            let name = function
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or_else(|| invalid(format!("tools[{i}]: function.name is required")))?;
+            Ok(json!({
+                "id": i + 1,
+                "name": name,
+                "description": function.get("description").and_then(Value::as_str).unwrap_or_default(),
+                "parameters": function
+                    .get("parameters")
+                    .cloned()
+                    .unwrap_or_else(|| json!({ "type": "object", "properties": {} })),
+            }))
+        })
+        .collect::<napi::Result<Vec<_>>>()?;
+    Ok(Some(Value::Array(definitions).to_string()))
+}
+
+/// The JSON Schema of a `json_schema` response format.
+fn parse_response_format(format: Option<&Value>) -> napi::Result<Option<String>> {
+    let Some(format) = format.filter(|f| !f.is_null()) else {
+        return Ok(None);
+    };
+    match format.get("type").and_then(Value::as_str) {
+        Some("text") => Ok(None),
+        Some("json_schema") => format
+            .pointer("/json_schema/schema")
+            .map(|schema| Some(schema.to_string()))
+            .ok_or_else(|| invalid("response_format.json_schema.schema is required")),
+        Some("json_object") => Err(invalid(
+            "response_format json_object is not supported; use json_schema with a schema",
+        )),
+        other => Err(invalid(format!(
+            "response_format has unsupported type {other:?}"
+        ))),
+    }
+}
+
+fn usage_json(usage: Option<&crate::TokenUsage>) -> Value {
+    json!({
+        "prompt_tokens": usage.map_or(0, |u| u.prompt_tokens),
+        "completion_tokens": usage.map_or(0, |u| u.completion_tokens),
+        "total_tokens": usage.map_or(0, |u| u.total_tokens),
+    })
+}
+
+fn message_json(result: &GenerationResult) -> Value {
+    let tool_calls = result.tool_calls.as_ref().filter(|calls| !calls.is_empty());
+    let content = match &result.object {
+        Some(object) if result.text.is_empty() => Some(object.to_string()),
+        _ => Some(result.text.clone()).filter(|text| !text.is_empty() || tool_calls.is_none()),
+    };
+    let mut message = json!({
+        "role": "assistant",
+        "content": content,
+        "refusal": null,
+    });
+    if let Some(calls) = tool_calls {
+        message["tool_calls"] = calls
+            .iter()
+            .map(|call| {
+                json!({
+                    "id": call.id,
+                    "type": "function",
+                    "function": { "name": call.function.name, "arguments": call.function.arguments },
+                })
+            })
+            .collect();
+    }
+    message
+}
+
+/// A `chat.completion` object for a finished generation, with one choice
+/// per candidate.
+pub fn completion_json(id: &str, created: u64, model: &str, result: &GenerationResult) -> Value {
+    let candidates = result
+        .candidates
+        .as_deref()
+        .unwrap_or(std::slice::from_ref(result));
+    let choices: Vec<Value> = candidates
+        .iter()
+        .enumerate()
+        .map(|(index, candidate)| {
+            json!({
+                "index": index,
+                "message": message_json(candidate),
+                "finish_reason": candidate.finish_reason,
+                "logprobs": null,
+            })
+        })
+        .collect();
+    json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": created,
+        "model": model,
+        "choices": choices,
+        "usage": usage_json(result.usage.as_ref()),
+    })
+}
+
+/// Seconds since the epoch, for `created`.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// HTTP status for a failure with `code`, as OpenAI would answer it.
+pub fn http_status(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::RateLimited | ErrorCode::ConcurrentRequests => 429,
+        ErrorCode::ModelUnavailable
+        | ErrorCode::AssetsUnavailable
+        | ErrorCode::ImageGenerationUnavailable => 503,
+        ErrorCode::PermissionDenied => 403,
+        ErrorCode::Timeout => 504,
+        ErrorCode::Cancelled | ErrorCode::ToolFailure | ErrorCode::GenerationFailed => 500,
+        ErrorCode::DecodingFailure | ErrorCode::SchemaValidation => 500,
+        _ => 400,
+    }
+}
+
+/// An OpenAI error object for a failure with `code`.
+pub fn error_json(code: ErrorCode, message: &str) -> Value {
+    let kind = match http_status(code) {
+        400 => "invalid_request_error",
+        429 => "rate_limit_error",
+        _ => "server_error",
+    };
+    json!({ "error": {
+        "message": message,
+        "type": kind,
+        "param": null,
+        "code": code.as_str(),
+    }})
+}
+
+/// One server-sent event carrying `data`.
+fn frame(data: &Value) -> String {
+    format!("data: {data}\n\n")
+}
+
+/// Turns stream events into `chat.completion.chunk` server-sent events,
+/// keeping the bookkeeping OpenAI clients expect: the role on the first
+/// delta, an index per tool call, and the finish reason on its own chunk.
+pub struct ChunkFormatter {
+    id: String,
+    created: u64,
+    model: String,
+    include_usage: bool,
+    sent_role: bool,
+    sent_content: bool,
+    /// Stream tool call id → its index in `tool_calls` deltas
+    tool_calls: HashMap<String, usize>,
+}
+
+impl ChunkFormatter {
+    pub fn new(id: String, model: String, include_usage: bool) -> Self {
+        Self {
+            id,
+            created: unix_time(),
+            model,
+            include_usage,
+            sent_role: false,
+            sent_content: false,
+            tool_calls: HashMap::new(),
+        }
+    }
+
+    fn chunk(&self, choices: Value) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+        })
+    }
+
+    fn delta(&mut self, mut delta: Value) -> String {
+        if !self.sent_role {
+            self.sent_role = true;
+            delta["role"] = json!("assistant");
+        }
+        let chunk = self.chunk(
+            json!([{ "index": 0, "delta": delta, "logprobs": null, "finish_reason": null }]),
+        );
+        frame(&chunk)
+    }
+
+    /// Server-sent events for one stream event: empty for events OpenAI
+    /// has no counterpart for (tool results, partial objects), and ending
+    /// with `data: [DONE]` or an error event once the stream is over.
+    pub fn frames(&mut self, event: &StreamEvent) -> String {
+        match event.kind.as_str() {
+            "text-delta" => {
+                let text = event.text.clone().unwrap_or_default();
+                self.sent_content = true;
+                self.delta(json!({ "content": text }))
+            }
+            "tool-call-start" => {
+                let id = event.tool_call_id.clone().unwrap_or_default();
+                let index = self.tool_calls.len();
+                self.tool_calls.insert(id.clone(), index);
+                self.delta(json!({ "tool_calls": [{
+                    "index": index,
+                    "id": id,
+                    "type": "function",
+                    "function": { "name": event.tool_name, "arguments": "" },
+                }]}))
+            }
+            "tool-call-delta" => {
+                let id = event.tool_call_id.as_deref().unwrap_or_default();
+                let Some(&index) = self.tool_calls.get(id) else {
+                    return String::new();
+                };
+                self.delta(json!({ "tool_calls": [{
+                    "index": index,
+                    "function": { "arguments": event.args_delta },
+                }]}))
+            }
+            "finish" => {
+                let mut frames = String::new();
+                // Structured output streams as whole-object snapshots, which
+                // don't form text deltas; send the final object as content
+                if let Some(object) = event.object.as_ref().filter(|_| !self.sent_content) {
+                    frames += &self.delta(json!({ "content": object.to_string() }));
+                }
+                let finish_reason = event.finish_reason.as_deref().unwrap_or("stop");
+                frames += &frame(&self.chunk(json!([{
+                    "index": 0,
+                    "delta": {},
+                    "logprobs": null,
+                    "finish_reason": finish_reason,
+                }])));
+                // Usage goes on a final chunk without choices, as OpenAI sends it
+                if self.include_usage {
+                    let mut usage = self.chunk(json!([]));
+                    usage["usage"] = usage_json(event.usage.as_ref());
+                    frames += &frame(&usage);
+                }
+                frames + "data: [DONE]\n\n"
+            }
+            "error" => {
+                let code = event
+                    .code
+                    .as_deref()
+                    .and_then(ErrorCode::parse)
+                    .unwrap_or(ErrorCode::GenerationFailed);
+                frame(&error_json(
+                    code,
+                    event.message.as_deref().unwrap_or_default(),
+                ))
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+pub struct ChatCompletionTask {
+    task: GenerateUnifiedTask,
+    model: String,
+}
+
+impl napi::Task for ChatCompletionTask {
+    type Output = String;
+    type JsValue = String;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let result = napi::Task::compute(&mut self.task)?;
+        let id = format!("chatcmpl-{}", self.task.request_id);
+        Ok(completion_json(&id, unix_time(), &self.model, &result).to_string())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(self.task.rejection(env, err))
+    }
+}
+
+/// Run an OpenAI `chat.completions` request body (JSON) and resolve with the
+/// `chat.completion` response (JSON). Tool calls are returned to the caller
+/// rather than run, as with OpenAI.
+#[napi]
+pub fn openai_chat_completion(request_json: String) -> napi::Result<AsyncTask<ChatCompletionTask>> {
+    let request = parse_request(&request_json)?;
+    if request.stream {
+        return Err(invalid(
+            "stream: true is not supported by openaiChatCompletion; use openaiChatCompletionStream",
+        ));
+    }
+    let generation = GenerationRequest::from_messages(&request.messages, request.options)?;
+    Ok(AsyncTask::new(ChatCompletionTask {
+        task: GenerateUnifiedTask::new(generation),
+        model: request.model,
+    }))
+}
+
+/// Stream an OpenAI `chat.completions` request body (JSON) as
+/// `chat.completion.chunk` server-sent events, ready to write to an HTTP
+/// response. The callback receives each batch of events and whether the
+/// stream is over, which is after `data: [DONE]` or an error event.
+#[napi]
+pub fn openai_chat_completion_stream(
+    request_json: String,
+    #[napi(ts_arg_type = "(err: Error | null, frames: string, done: boolean) => void")]
+    callback: JsFunction,
+) -> napi::Result<()> {
+    let request = parse_request(&request_json)?;
+    let generation = GenerationRequest::from_messages(&request.messages, request.options)?;
+    let mut formatter = ChunkFormatter::new(
+        format!("chatcmpl-{}", next_request_id()),
+        request.model,
+        request.include_usage,
+    );
+    start_formatted_stream(generation, callback, move |event| formatter.frames(event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TokenUsage;
+
+    fn request(body: Value) -> ChatCompletionRequest {
+        parse_request(&body.to_string()).unwrap_or_else(|e| panic!("{body}: {}", e.reason))
+    }
+
+    fn rejection(body: Value) -> String {
+        match parse_request(&body.to_string()) {
+            Ok(_) => panic!("{body} was accepted"),
+            Err(error) => error.reason,
+        }
+    }
+
+    fn with_messages(mut body: Value) -> Value {
+        body["messages"] = json!([{ "role": "user", "content": "Hi" }]);
+        body
+    }
+
+    /// The JSON of each `data:` event in `frames`, with `[DONE]` as a string.
+    fn events(frames: &str) -> Vec<Value> {
+        frames
+            .split_terminator("\n\n")
+            .map(|event| {
+                let data = event.strip_prefix("data: ").expect("every event is data");
+                serde_json::from_str(data).unwrap_or_else(|_| json!(data))
+            })
+            .collect()
+    }
+
+    fn finish(usage: Option<TokenUsage>, object: Option<Value>) -> StreamEvent {
+        StreamEvent {
+            kind: "finish".to_string(),
+            finish_reason: Some("stop".to_string()),
+            usage,
+            object,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tool_choice_auto_and_none() {
+        let tools = json!([{ "type": "function", "function": { "name": "weather" } }]);
+        for choice in [json!(null), json!("auto")] {
+            let parsed = request(with_messages(
+                json!({ "tools": tools, "tool_choice": choice }),
+            ));
+            let definitions: Value =
+                serde_json::from_str(&parsed.options.tools_json.unwrap()).unwrap();
+            assert_eq!(definitions[0]["name"], "weather");
+            assert_eq!(
+                definitions[0]["parameters"],
+                json!({ "type": "object", "properties": {} })
+            );
+        }
+        let parsed = request(with_messages(
+            json!({ "tools": tools, "tool_choice": "none" }),
+        ));
+        assert!(parsed.options.tools_json.is_none());
+
+        for choice in [
+            json!("required"),
+            json!({ "type": "function", "function": { "name": "weather" } }),
+        ] {
+            let reason = rejection(with_messages(
+                json!({ "tools": tools, "tool_choice": choice }),
+            ));
+            assert!(reason.contains("tool_choice"), "{reason}");
+        }
+    }
+
+    #[test]
+    fn developer_messages_are_system_messages() {
+        let parsed = request(json!({ "messages": [
+            { "role": "developer", "content": "Be brief." },
+            { "role": "user", "content": "Hi" },
+        ]}));
+        assert_eq!(parsed.messages[0].role, "system");
+        assert_eq!(parsed.messages[1].role, "user");
+    }
+
+    #[test]
+    fn images_must_be_inline_data_urls() {
+        let image = |url: &str| {
+            json!({ "messages": [{ "role": "user", "content": [
+                { "type": "text", "text": "What is this?" },
+                { "type": "image_url", "image_url": { "url": url } },
+            ]}]})
+        };
+        let parsed = request(image("data:image/png;base64,iVBORw0KGgo="));
+        let Some(Either::B(parts)) = &parsed.messages[0].content else {
+            panic!("content should be parts");
+        };
+        assert_eq!(parts[1].kind, "image");
+        assert_eq!(parts[1].mime_type.as_deref(), Some("image/png"));
+        assert!(matches!(&parts[1].data, Some(Either::A(data)) if data == "iVBORw0KGgo="));
+
+        for url in [
+            "https://example.com/cat.png",
+            "file:///etc/passwd",
+            "data:image/png,notbase64",
+            "",
+        ] {
+            let reason = rejection(image(url));
+            assert!(reason.contains("base64 data: URL"), "{url}: {reason}");
+        }
+    }
+
+    #[test]
+    fn seeds_and_n_must_fit_without_wrapping() {
+        let sampling = |body: Value| request(with_messages(body)).options.sampling.unwrap();
+        assert_eq!(sampling(json!({ "seed": 0 })).seed, Some(0));
+        assert_eq!(sampling(json!({ "seed": u32::MAX })).seed, Some(u32::MAX));
+        assert_eq!(sampling(json!({ "seed": null })).seed, None);
+        for seed in [
+            json!(u64::from(u32::MAX) + 1),
+            json!(-1),
+            json!(1.5),
+            json!("7"),
+        ] {
+            assert!(rejection(with_messages(json!({ "seed": seed }))).contains("seed"));
+        }
+
+        assert_eq!(request(with_messages(json!({ "n": 3 }))).options.n, Some(3));
+        // 2^32 + 1 would have wrapped to a single candidate
+        for n in [json!(u64::from(u32::MAX) + 2), json!(-1), json!(2.5)] {
+            assert!(rejection(with_messages(json!({ "n": n }))).contains("n must be"));
+        }
+    }
+
+    #[test]
+    fn only_the_first_delta_carries_the_role() {
+        let mut formatter = ChunkFormatter::new("chatcmpl-1".into(), MODEL_NAME.into(), false);
+        let first = events(&formatter.frames(&StreamEvent::text_delta("Hel".into())));
+        let second = events(&formatter.frames(&StreamEvent::text_delta("lo".into())));
+        assert_eq!(
+            first[0]["choices"][0]["delta"],
+            json!({ "role": "assistant", "content": "Hel" })
+        );
+        assert_eq!(second[0]["choices"][0]["delta"], json!({ "content": "lo" }));
+        assert_eq!(second[0]["object"], "chat.completion.chunk");
+        assert_eq!(second[0]["id"], "chatcmpl-1");
+    }
+
+    #[test]
+    fn tool_calls_stream_with_their_own_index() {
+        let mut formatter = ChunkFormatter::new("chatcmpl-1".into(), MODEL_NAME.into(), false);
+        let mut frames = String::new();
+        for (id, name) in [("call_a", "weather"), ("call_b", "time")] {
+            frames += &formatter.frames(&StreamEvent::tool_call_start(id.into(), name.into()));
+        }
+        frames += &formatter.frames(&StreamEvent::tool_call_delta("call_b".into(), "{}".into()));
+        frames += &formatter.frames(&StreamEvent::tool_call_delta(
+            "call_a".into(),
+            "{\"city\":\"Oslo\"}".into(),
+        ));
+        // Events with no OpenAI counterpart send nothing
+        assert_eq!(
+            formatter.frames(&StreamEvent::tool_call(
+                "call_a".into(),
+                "weather".into(),
+                "{}".into()
+            )),
+            ""
+        );
+        assert_eq!(
+            formatter.frames(&StreamEvent::tool_call_delta(
+                "call_unknown".into(),
+                "{}".into()
+            )),
+            ""
+        );
+
+        let calls: Vec<Value> = events(&frames)
+            .iter()
+            .map(|chunk| chunk["choices"][0]["delta"]["tool_calls"][0].clone())
+            .collect();
+        assert_eq!(
+            calls[0],
+            json!({ "index": 0, "id": "call_a", "type": "function",
+                    "function": { "name": "weather", "arguments": "" } })
+        );
+        assert_eq!(calls[1]["index"], 1);
+        assert_eq!(calls[1]["id"], "call_b");
+        assert_eq!(
+            calls[2],
+            json!({ "index": 1, "function": { "arguments": "{}" } })
+        );
+        assert_eq!(
+            calls[3],
+            json!({ "index": 0, "function": { "arguments": "{\"city\":\"Oslo\"}" } })
+        );
+    }
+
+    #[test]
+    fn finish_then_usage_then_done() {
+        let usage = TokenUsage {
+            prompt_tokens: 5,
+            completion_tokens: 2,
+            total_tokens: 7,
+        };
+        let mut formatter = ChunkFormatter::new("chatcmpl-1".into(), MODEL_NAME.into(), true);
+        formatter.frames(&StreamEvent::text_delta("Hi".into()));
+        let sent = events(&formatter.frames(&finish(Some(usage), None)));
+        assert_eq!(sent.len(), 3);
+        assert_eq!(
+            sent[0]["choices"],
+            json!([{ "index": 0, "delta": {}, "logprobs": null, "finish_reason": "stop" }])
+        );
+        assert_eq!(sent[1]["choices"], json!([]));
+        assert_eq!(
+            sent[1]["usage"],
+            json!({ "prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7 })
+        );
+        assert_eq!(sent[2], "[DONE]");
+
+        let mut formatter = ChunkFormatter::new("chatcmpl-2".into(), MODEL_NAME.into(), false);
+        formatter.frames(&StreamEvent::text_delta("Hi".into()));
+        let sent = events(&formatter.frames(&finish(None, None)));
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1], "[DONE]");
+    }
+
+    #[test]
+    fn structured_output_is_sent_as_content_when_no_text_was() {
+        let object = json!({ "answer": 42 });
+        let mut formatter = ChunkFormatter::new("chatcmpl-1".into(), MODEL_NAME.into(), false);
+        assert_eq!(
+            formatter.frames(&StreamEvent::object_delta(json!({ "answer": 4 }))),
+            ""
+        );
+        let sent = events(&formatter.frames(&finish(None, Some(object.clone()))));
+        assert_eq!(
+            sent[0]["choices"][0]["delta"],
+            json!({ "role": "assistant", "content": object.to_string() })
+        );
+        assert_eq!(sent[1]["choices"][0]["finish_reason"], "stop");
+
+        let mut formatter = ChunkFormatter::new("chatcmpl-2".into(), MODEL_NAME.into(), false);
+        formatter.frames(&StreamEvent::text_delta(object.to_string()));
+        let sent = events(&formatter.frames(&finish(None, Some(object))));
+        assert_eq!(sent[0]["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[test]
+    fn frames_are_exact_server_sent_events() {
+        let mut formatter = ChunkFormatter::new("chatcmpl-1".into(), MODEL_NAME.into(), true);
+        formatter.created = 1700000000;
+        let chunk = |choices: &str| {
+            format!(
+                "data: {{\"choices\":{choices},\"created\":1700000000,\"id\":\"chatcmpl-1\",\
+                 \"model\":\"{MODEL_NAME}\",\"object\":\"chat.completion.chunk\"}}\n\n"
+            )
+        };
+        assert_eq!(
+            formatter.frames(&StreamEvent::text_delta("Hi".into())),
+            chunk(
+                r#"[{"delta":{"content":"Hi","role":"assistant"},"finish_reason":null,"index":0,"logprobs":null}]"#
+            )
+        );
+        let usage = TokenUsage {
+            prompt_tokens: 5,
+            completion_tokens: 2,
+            total_tokens: 7,
+        };
+        let usage_chunk = chunk("[]").replace(
+            "\"object\":\"chat.completion.chunk\"",
+            r#""object":"chat.completion.chunk","usage":{"completion_tokens":2,"prompt_tokens":5,"total_tokens":7}"#,
+        );
+        assert_eq!(
+            formatter.frames(&finish(Some(usage), None)),
+            chunk(r#"[{"delta":{},"finish_reason":"stop","index":0,"logprobs":null}]"#)
+                + &usage_chunk
+                + "data: [DONE]\n\n"
+        );
+
+        let error = StreamEvent::error(ErrorCode::Timeout, "Too slow".into());
+        assert_eq!(
+            formatter.frames(&error),
+            "data: {\"error\":{\"code\":\"Timeout\",\"message\":\"Too slow\",\
+             \"param\":null,\"type\":\"server_error\"}}\n\n"
+        );
+    }
+}
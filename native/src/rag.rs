@@ -0,0 +1,283 @@
+//! Retrieval-augmented generation: embed the query, retrieve chunks from a
+//! `VectorIndex`, fit as many as the context window allows into the prompt
+//! and generate, all on one worker thread.
+
+use crate::embeddings::{embedding, EmbeddingModel};
+use crate::vector_index::{SharedEntries, VectorIndex, VectorSearchOptions};
+use crate::{
+    coded_error, count_text_tokens, ChatMessage, ErrorCode, GenerateUnifiedTask, GenerationOptions,
+    GenerationRequest, TokenUsage,
+};
+use napi::bindgen_prelude::{AsyncTask, Either};
+use napi::{Env, Status};
+use napi_derive::napi;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+const DEFAULT_TOP_K: u32 = 5;
+
+const DEFAULT_TEMPLATE: &str = "Answer the question using only the context below. If the context doesn't contain the answer, say that you don't know.{citations}\n\nContext:\n{context}\n\nQuestion: {query}";
+const CITATION_INSTRUCTION: &str =
+    " Cite the passages you use by their number in square brackets, like [1].";
+
+#[napi(object)]
+#[derive(Default)]
+pub struct RagOptions {
+    /// Chunks to retrieve (default 5)
+    pub top_k: Option<u32>,
+    /// Only chunks whose metadata has each of these properties with an equal
+    /// value, as in `VectorIndex.search`
+    #[napi(ts_type = "Record<string, unknown>")]
+    pub filter: Option<Value>,
+    /// Leave out chunks scoring below this
+    pub min_score: Option<f64>,
+    /// Prompt with `{context}` and `{query}` placeholders, and optionally
+    /// `{citations}` for the instruction to cite passages
+    pub prompt_template: Option<String>,
+    /// Number the passages and ask the model to cite them as `[n]` (default
+    /// `true`)
+    pub citations: Option<bool>,
+    /// Metadata property holding each chunk's text (default: the index's
+    /// `textField`)
+    pub text_field: Option<String>,
+    /// Most tokens of retrieved context (default: what fits the context
+    /// window next to the prompt and the response)
+    pub context_tokens: Option<u32>,
+    /// Language of the query for embedding, as the index was built with
+    pub language: Option<String>,
+    /// Embedding model the index was built with (default `sentence`)
+    #[napi(ts_type = "'sentence' | 'contextual'")]
+    pub model: Option<String>,
+}
+
+/// A retrieved chunk that went into the prompt.
+#[napi(object)]
+pub struct RagSource {
+    pub id: String,
+    /// Cosine similarity to the query
+    pub score: f64,
+    pub text: String,
+    pub metadata: Option<Value>,
+    /// Number of the passage in the prompt, as the answer cites it
+    pub citation: u32,
+    /// Whether the answer cites this passage
+    pub cited: bool,
+}
+
+#[napi(object)]
+pub struct RagResult {
+    pub text: String,
+    /// The chunks the prompt contained, best match first
+    pub sources: Vec<RagSource>,
+    #[napi(ts_type = "'stop' | 'length' | 'tool_calls' | 'content_filter'")]
+    pub finish_reason: String,
+    pub usage: Option<TokenUsage>,
+}
+
+fn fill(template: &str, citations: bool, context: &str, query: &str) -> String {
+    template
+        .replace(
+            "{citations}",
+            if citations { CITATION_INSTRUCTION } else { "" },
+        )
+        .replace("{context}", context)
+        .replace("{query}", query)
+}
+
+/// Passage numbers cited as `[n]` or `[n, m]` in `text`.
+fn cited_numbers(text: &str) -> HashSet<u32> {
+    static CITATION: OnceLock<Regex> = OnceLock::new();
+    let citation = CITATION.get_or_init(|| Regex::new(r"\[(\d+(?:\s*,\s*\d+)*)\]").unwrap());
+    citation
+        .captures_iter(text)
+        .flat_map(|c| {
+            c[1].split(',')
+                .filter_map(|n| n.trim().parse().ok())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+pub struct RagTask {
+    entries: SharedEntries,
+    query: String,
+    search: VectorSearchOptions,
+    template: String,
+    citations: bool,
+    text_field: Option<String>,
+    context_tokens: Option<u32>,
+    language: Option<String>,
+    model: EmbeddingModel,
+    request: Option<GenerationRequest>,
+    error_code: Option<ErrorCode>,
+}
+
+impl RagTask {
+    fn fail(&mut self, code: ErrorCode, message: String) -> napi::Error {
+        self.error_code = Some(code);
+        napi::Error::from_reason(message)
+    }
+}
+
+impl napi::Task for RagTask {
+    type Output = RagResult;
+    type JsValue = RagResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let Some(mut request) = self.request.take() else {
+            return Err(napi::Error::from_reason("The request already ran"));
+        };
+        let query = embedding(&self.query, self.language.as_deref(), self.model)
+            .map_err(|e| self.fail(e.code, format!("Query: {}", e.message)))?;
+        let (hits, text_field) = {
+            let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+            let text_field = self
+                .text_field
+                .clone()
+                .unwrap_or_else(|| entries.text_field().to_string());
+            (entries.search(&query, &self.search)?, text_field)
+        };
+
+        // Best matches first, each as long as it fits what's left
+        let frame = fill(&self.template, self.citations, "", &self.query);
+        let mut budget = request
+            .message_budget()?
+            .saturating_sub(count_text_tokens(&frame)?.tokens);
+        if let Some(limit) = self.context_tokens {
+            budget = budget.min(limit);
+        }
+        let mut sources = Vec::new();
+        let mut passages = Vec::new();
+        for hit in hits {
+            let Some(text) = hit
+                .metadata
+                .as_ref()
+                .and_then(|m| m.get(&text_field))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            let number = sources.len() as u32 + 1;
+            let passage = if self.citations {
+                format!("[{number}] {text}")
+            } else {
+                text.clone()
+            };
+            let tokens = count_text_tokens(&passage)?.tokens + 1;
+            if tokens > budget {
+                continue;
+            }
+            budget -= tokens;
+            passages.push(passage);
+            sources.push(RagSource {
+                id: hit.id,
+                score: hit.score,
+                text,
+                metadata: hit.metadata,
+                citation: number,
+                cited: false,
+            });
+        }
+
+        let prompt = fill(
+            &self.template,
+            self.citations,
+            &passages.join("\n\n"),
+            &self.query,
+        );
+        request.set_messages(&[ChatMessage {
+            role: "user".to_string(),
+            content: Some(Either::A(prompt)),
+            name: None,
+            tool_call_id: None,
+            tool_calls: None,
+        }])?;
+        let mut generation = GenerateUnifiedTask::new(request);
+        let result = napi::Task::compute(&mut generation).map_err(|e| {
+            let code = generation.failure_code(&e);
+            self.fail(code, e.reason)
+        })?;
+        if self.citations {
+            let cited = cited_numbers(&result.text);
+            for source in &mut sources {
+                source.cited = cited.contains(&source.citation);
+            }
+        }
+        Ok(RagResult {
+            text: result.text,
+            sources,
+            finish_reason: result.finish_reason,
+            usage: result.usage,
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Answer `query` from the chunks of `index`: embed the query, retrieve the
+/// `topK` nearest chunks, put as many as fit the context window into the
+/// prompt and generate. Resolves with the answer and the chunks it was
+/// given, marking those it cites.
+#[napi(ts_return_type = "Promise<RagResult>")]
+pub fn rag_generate(
+    index: &VectorIndex,
+    query: String,
+    options: Option<RagOptions>,
+    generation: Option<GenerationOptions>,
+) -> napi::Result<AsyncTask<RagTask>> {
+    let options = options.unwrap_or_default();
+    let model = EmbeddingModel::parse(options.model.as_deref())
+        .map_err(|e| napi::Error::new(Status::InvalidArg, e.message))?;
+    let template = options
+        .prompt_template
+        .unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+    if !template.contains("{context}") {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "promptTemplate must contain {context}",
+        ));
+    }
+    let generation = generation.unwrap_or_default();
+    if generation.schema_json.is_some() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "ragGenerate answers in text and cannot take a schema",
+        ));
+    }
+    // The prompt is assembled after retrieval; the query stands in until then
+    let placeholder = ChatMessage {
+        role: "user".to_string(),
+        content: Some(Either::A(query.clone())),
+        name: None,
+        tool_call_id: None,
+        tool_calls: None,
+    };
+    let request = GenerationRequest::from_messages(&[placeholder], generation)?;
+    Ok(AsyncTask::new(RagTask {
+        entries: index.shared(),
+        query,
+        search: VectorSearchOptions {
+            top_k: Some(options.top_k.unwrap_or(DEFAULT_TOP_K)),
+            filter: options.filter,
+            min_score: options.min_score,
+        },
+        template,
+        citations: options.citations.unwrap_or(true),
+        text_field: options.text_field,
+        context_tokens: options.context_tokens,
+        language: options.language,
+        model,
+        request: Some(request),
+        error_code: None,
+    }))
+}
@@ -0,0 +1,563 @@
+//! PII redaction: names from the name tagger, contact details from
+//! NSDataDetector and patterns from regex rules, masked or replaced with
+//! placeholders that can be mapped back.
+
+use crate::chunking::utf16_offsets;
+use crate::data_detection::detect;
+use crate::text_analysis::{entity_options_json, named_entities};
+use crate::{coded_error, ErrorCode};
+use napi::bindgen_prelude::AsyncTask;
+use napi::{Env, Status};
+use napi_derive::napi;
+use regex::Regex;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+const CATEGORIES: [&str; 10] = [
+    "name",
+    "organization",
+    "place",
+    "address",
+    "phone",
+    "email",
+    "url",
+    "creditCard",
+    "ssn",
+    "ipAddress",
+];
+
+const DEFAULT_CATEGORIES: [&str; 7] = [
+    "name",
+    "address",
+    "phone",
+    "email",
+    "creditCard",
+    "ssn",
+    "ipAddress",
+];
+
+#[napi(object)]
+pub struct RedactionRule {
+    /// Category of what the pattern matches, used in its placeholders
+    pub category: String,
+    /// Regular expression (Rust `regex` syntax)
+    pub pattern: String,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct RedactOptions {
+    /// What to redact (default: names, addresses, phone numbers, emails,
+    /// credit card numbers, SSNs and IP addresses)
+    #[napi(
+        ts_type = "Array<'name' | 'organization' | 'place' | 'address' | 'phone' | 'email' | 'url' | 'creditCard' | 'ssn' | 'ipAddress'>"
+    )]
+    pub categories: Option<Vec<String>>,
+    /// Patterns to redact as well
+    pub rules: Option<Vec<RedactionRule>>,
+    /// `placeholder` (default) replaces each value with one like
+    /// `[EMAIL_1]`, the same for the same value; `mask` with `*` per
+    /// character; `remove` deletes it
+    #[napi(ts_type = "'placeholder' | 'mask' | 'remove'")]
+    pub strategy: Option<String>,
+    /// Return the placeholders' values in `mapping`, to restore them later
+    /// (default false; placeholders only)
+    pub reversible: Option<bool>,
+    /// Language of the text for name recognition (default: detected)
+    pub language: Option<String>,
+}
+
+/// A value that was redacted.
+#[napi(object)]
+pub struct RedactedSpan {
+    pub category: String,
+    /// The original value
+    pub text: String,
+    /// Offset in the original text, in UTF-16 code units like JS strings
+    pub start: u32,
+    pub end: u32,
+    /// What replaced it
+    pub replacement: String,
+}
+
+#[napi(object)]
+pub struct RedactResult {
+    pub text: String,
+    /// The redacted values, in order
+    pub spans: Vec<RedactedSpan>,
+    /// Original value of each placeholder, when `reversible`
+    pub mapping: Option<HashMap<String, String>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Strategy {
+    Placeholder,
+    Mask,
+    Remove,
+}
+
+struct Pattern {
+    category: String,
+    regex: Regex,
+}
+
+/// A built-in pattern's category, regex, and the check of each match the
+/// regex can't make.
+type Builtin = (&'static str, Regex, fn(&str) -> bool);
+
+/// Byte ranges of values to redact with their category.
+type Candidates = Vec<(Range<usize>, String)>;
+
+fn builtin_patterns() -> &'static [Builtin] {
+    static PATTERNS: OnceLock<Vec<Builtin>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        vec![
+            (
+                "email",
+                Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}")
+                    .unwrap(),
+                |_| true,
+            ),
+            (
+                "creditCard",
+                Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").unwrap(),
+                luhn,
+            ),
+            ("ssn", Regex::new(r"\b\d{3}-\d{2}-\d{4}\b").unwrap(), |_| {
+                true
+            }),
+            (
+                "ipAddress",
+                Regex::new(
+                    r"\b(?:(?:25[0-5]|2[0-4]\d|1?\d?\d)\.){3}(?:25[0-5]|2[0-4]\d|1?\d?\d)\b",
+                )
+                .unwrap(),
+                |_| true,
+            ),
+        ]
+    })
+}
+
+/// Whether the digits of `number` pass the Luhn check card numbers carry.
+fn luhn(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match (i % 2 == 1, d * 2) {
+            (true, doubled) if doubled > 9 => doubled - 9,
+            (true, doubled) => doubled,
+            (false, _) => d,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// `[CREDIT_CARD_2]` for the second credit card number.
+fn placeholder(category: &str, n: usize) -> String {
+    let mut name = String::new();
+    for c in category.chars() {
+        if c.is_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        if c.is_alphanumeric() {
+            name.push(c.to_ascii_uppercase());
+        } else if !name.ends_with('_') {
+            name.push('_');
+        }
+    }
+    format!("[{name}_{n}]")
+}
+
+pub struct RedactTask {
+    text: String,
+    categories: Vec<String>,
+    patterns: Vec<Pattern>,
+    strategy: Strategy,
+    reversible: bool,
+    entity_options: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl RedactTask {
+    fn wants(&self, category: &str) -> bool {
+        self.categories.iter().any(|c| c == category)
+    }
+
+    /// What to redact, as byte ranges with their category, possibly
+    /// overlapping.
+    fn candidates(&self) -> std::result::Result<Candidates, (ErrorCode, String)> {
+        let mut found = Vec::new();
+        if ["name", "organization", "place"]
+            .iter()
+            .any(|c| self.wants(c))
+        {
+            for (range, kind) in named_entities(&self.text, &self.entity_options)? {
+                let category = if kind == "person" { "name" } else { &kind };
+                if self.wants(category) {
+                    found.push((range, category.to_string()));
+                }
+            }
+        }
+        let types: Vec<&str> = [
+            ("address", "address"),
+            ("phone", "phoneNumber"),
+            ("email", "link"),
+            ("url", "link"),
+        ]
+        .iter()
+        .filter(|(category, _)| self.wants(category))
+        .map(|&(_, kind)| kind)
+        .collect();
+        if !types.is_empty() {
+            let types_json = CString::new(serde_json::json!(types).to_string())
+                .map_err(|_| (ErrorCode::InvalidArg, "Invalid types".to_string()))?;
+            for (range, value) in detect(&self.text, &types_json)? {
+                let category = match value["type"].as_str() {
+                    Some("address") => "address",
+                    Some("phoneNumber") => "phone",
+                    Some("link")
+                        if value["url"]
+                            .as_str()
+                            .is_some_and(|u| u.starts_with("mailto:")) =>
+                    {
+                        "email"
+                    }
+                    Some("link") => "url",
+                    _ => continue,
+                };
+                if self.wants(category) {
+                    found.push((range, category.to_string()));
+                }
+            }
+        }
+        found.extend(self.pattern_candidates());
+        Ok(found)
+    }
+
+    /// The candidates found by the built-in patterns and `rules`.
+    fn pattern_candidates(&self) -> Candidates {
+        let mut found = Vec::new();
+        for (category, regex, check) in builtin_patterns() {
+            if self.wants(category) {
+                found.extend(
+                    regex
+                        .find_iter(&self.text)
+                        .filter(|m| check(m.as_str()))
+                        .map(|m| (m.range(), category.to_string())),
+                );
+            }
+        }
+        for pattern in &self.patterns {
+            found.extend(
+                pattern
+                    .regex
+                    .find_iter(&self.text)
+                    .filter(|m| !m.is_empty())
+                    .map(|m| (m.range(), pattern.category.clone())),
+            );
+        }
+        found
+    }
+}
+
+/// Replace the `candidates` in `text` by `strategy`.
+fn replace(
+    text: &str,
+    mut candidates: Candidates,
+    strategy: Strategy,
+    reversible: bool,
+) -> RedactResult {
+    // Of overlapping values the first wins, and of those starting
+    // together the longest
+    candidates.sort_by_key(|(range, _)| (range.start, std::cmp::Reverse(range.end)));
+    let offsets = utf16_offsets(text);
+    let mut redacted = String::with_capacity(text.len());
+    let mut spans = Vec::new();
+    let mut placeholders: HashMap<(String, String), String> = HashMap::new();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut last = 0;
+    for (range, category) in candidates {
+        if range.start < last {
+            continue;
+        }
+        let value = &text[range.clone()];
+        let replacement = match strategy {
+            Strategy::Placeholder => placeholders
+                .entry((category.clone(), value.to_string()))
+                .or_insert_with(|| {
+                    let n = counts.entry(category.clone()).or_default();
+                    *n += 1;
+                    placeholder(&category, *n)
+                })
+                .clone(),
+            Strategy::Mask => "*".repeat(value.chars().count()),
+            Strategy::Remove => String::new(),
+        };
+        redacted.push_str(&text[last..range.start]);
+        redacted.push_str(&replacement);
+        last = range.end;
+        spans.push(RedactedSpan {
+            category,
+            text: value.to_string(),
+            start: offsets[range.start],
+            end: offsets[range.end],
+            replacement,
+        });
+    }
+    redacted.push_str(&text[last..]);
+    let mapping = reversible.then(|| {
+        placeholders
+            .into_iter()
+            .map(|((_, value), placeholder)| (placeholder, value))
+            .collect()
+    });
+    RedactResult {
+        text: redacted,
+        spans,
+        mapping,
+    }
+}
+
+impl napi::Task for RedactTask {
+    type Output = RedactResult;
+    type JsValue = RedactResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let candidates = self.candidates().map_err(|(code, message)| {
+            self.error_code = Some(code);
+            napi::Error::from_reason(message)
+        })?;
+        Ok(replace(
+            &self.text,
+            candidates,
+            self.strategy,
+            self.reversible,
+        ))
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Redact personal information in `text` before it's logged or sent on:
+/// names from the name tagger, addresses, phone numbers and links from
+/// NSDataDetector, and emails, card numbers, SSNs, IP addresses and `rules`
+/// by pattern.
+#[napi(ts_return_type = "Promise<RedactResult>")]
+pub fn redact(text: String, options: Option<RedactOptions>) -> napi::Result<AsyncTask<RedactTask>> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: String| napi::Error::new(Status::InvalidArg, message);
+    let categories = options
+        .categories
+        .unwrap_or_else(|| DEFAULT_CATEGORIES.iter().map(|c| c.to_string()).collect());
+    if let Some(unknown) = categories
+        .iter()
+        .find(|c| !CATEGORIES.contains(&c.as_str()))
+    {
+        return Err(invalid(format!(
+            "Unknown category '{unknown}', expected one of {}",
+            CATEGORIES.join(", ")
+        )));
+    }
+    let patterns = options
+        .rules
+        .unwrap_or_default()
+        .into_iter()
+        .map(|rule| {
+            if rule.category.trim().is_empty() {
+                return Err(invalid("A rule's category cannot be empty".to_string()));
+            }
+            let regex = Regex::new(&rule.pattern)
+                .map_err(|e| invalid(format!("Invalid pattern for '{}': {e}", rule.category)))?;
+            Ok(Pattern {
+                category: rule.category,
+                regex,
+            })
+        })
+        .collect::<napi::Result<Vec<_>>>()?;
+    let strategy = match options.strategy.as_deref() {
+        None | Some("placeholder") => Strategy::Placeholder,
+        Some("mask") => Strategy::Mask,
+        Some("remove") => Strategy::Remove,
+        Some(other) => {
+            return Err(invalid(format!(
+                "Unknown strategy '{other}', expected 'placeholder', 'mask' or 'remove'"
+            )))
+        }
+    };
+    let reversible = options.reversible.unwrap_or(false);
+    if reversible && strategy != Strategy::Placeholder {
+        return Err(invalid(
+            "reversible needs the 'placeholder' strategy".to_string(),
+        ));
+    }
+    Ok(AsyncTask::new(RedactTask {
+        text,
+        categories,
+        patterns,
+        strategy,
+        reversible,
+        entity_options: entity_options_json(options.language.as_deref(), None)?,
+        error_code: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(text: &str, categories: &[&str], rules: &[(&str, &str)]) -> RedactTask {
+        RedactTask {
+            text: text.to_string(),
+            categories: categories.iter().map(|c| c.to_string()).collect(),
+            patterns: rules
+                .iter()
+                .map(|&(category, pattern)| Pattern {
+                    category: category.to_string(),
+                    regex: Regex::new(pattern).unwrap(),
+                })
+                .collect(),
+            strategy: Strategy::Placeholder,
+            reversible: false,
+            entity_options: CString::default(),
+            error_code: None,
+        }
+    }
+
+    /// Redact what the patterns find; names, addresses, phone numbers and
+    /// links come from the system frameworks, which tests don't reach.
+    fn by_pattern(text: &str, categories: &[&str], rules: &[(&str, &str)]) -> RedactResult {
+        let candidates = task(text, categories, rules).pattern_candidates();
+        replace(text, candidates, Strategy::Placeholder, true)
+    }
+
+    fn span(text: &str, value: &str, category: &str) -> (Range<usize>, String) {
+        let start = text.find(value).unwrap();
+        (start..start + value.len(), category.to_string())
+    }
+
+    fn restore(result: &RedactResult) -> String {
+        let mut text = result.text.clone();
+        for (placeholder, value) in result.mapping.as_ref().unwrap() {
+            text = text.replace(placeholder, value);
+        }
+        text
+    }
+
+    #[test]
+    fn matches_emails() {
+        let result = by_pattern(
+            "Mail ann.lee+work@mail.example.co.uk or bob@x.io, not a@b.",
+            &["email"],
+            &[],
+        );
+        assert_eq!(result.text, "Mail [EMAIL_1] or [EMAIL_2], not a@b.");
+        assert_eq!(result.spans[0].text, "ann.lee+work@mail.example.co.uk");
+        assert_eq!(result.spans[1].text, "bob@x.io");
+    }
+
+    #[test]
+    fn matches_card_numbers_that_pass_luhn() {
+        let result = by_pattern(
+            "Visa 4111 1111 1111 1111, typo 4111 1111 1111 1112, dashed 5500-0000-0000-0004",
+            &["creditCard"],
+            &[],
+        );
+        assert_eq!(
+            result.text,
+            "Visa [CREDIT_CARD_1], typo 4111 1111 1111 1112, dashed [CREDIT_CARD_2]"
+        );
+        assert!(luhn("79927398713"));
+        assert!(!luhn("79927398710"));
+    }
+
+    #[test]
+    fn matches_ssns_and_ip_addresses() {
+        let result = by_pattern(
+            "SSN 123-45-6789 from 192.168.0.1, not 999.1.1.1 or 1234-56-789",
+            &["ssn", "ipAddress"],
+            &[],
+        );
+        assert_eq!(
+            result.text,
+            "SSN [SSN_1] from [IP_ADDRESS_1], not 999.1.1.1 or 1234-56-789"
+        );
+    }
+
+    #[test]
+    fn only_matches_the_requested_categories_and_rules() {
+        let result = by_pattern(
+            "bob@x.io 123-45-6789 EMP-0042",
+            &["ssn"],
+            &[("employee id", r"EMP-\d+")],
+        );
+        assert_eq!(result.text, "bob@x.io [SSN_1] [EMPLOYEE_ID_1]");
+    }
+
+    #[test]
+    fn names_placeholders_after_the_category() {
+        assert_eq!(placeholder("creditCard", 2), "[CREDIT_CARD_2]");
+        assert_eq!(placeholder("ipAddress", 1), "[IP_ADDRESS_1]");
+        assert_eq!(placeholder("employee id", 3), "[EMPLOYEE_ID_3]");
+    }
+
+    #[test]
+    fn reversible_placeholders_restore_emails_and_phones() {
+        let text = "Call 415-555-0100 or ann@example.com; again ann@example.com, then 415 555 0199";
+        let mut candidates = task(text, &["email"], &[]).pattern_candidates();
+        // Phone numbers as NSDataDetector reports them
+        candidates.push(span(text, "415-555-0100", "phone"));
+        candidates.push(span(text, "415 555 0199", "phone"));
+        let result = replace(text, candidates, Strategy::Placeholder, true);
+        assert_eq!(
+            result.text,
+            "Call [PHONE_1] or [EMAIL_1]; again [EMAIL_1], then [PHONE_2]"
+        );
+        let mapping = result.mapping.as_ref().unwrap();
+        assert_eq!(mapping.len(), 3);
+        assert_eq!(mapping["[EMAIL_1]"], "ann@example.com");
+        assert_eq!(mapping["[PHONE_1]"], "415-555-0100");
+        assert_eq!(mapping["[PHONE_2]"], "415 555 0199");
+        assert_eq!(restore(&result), text);
+    }
+
+    #[test]
+    fn overlapping_matches_keep_the_first_and_longest() {
+        let text = "Reach 415-555-0100@sms.example.com or ann@example.com";
+        let mut candidates = task(text, &["email"], &[]).pattern_candidates();
+        // The detector finds the email again as a link, and the phone
+        // number inside the first address
+        candidates.push(span(text, "ann@example.com", "email"));
+        candidates.push(span(text, "415-555-0100", "phone"));
+        let result = replace(text, candidates, Strategy::Placeholder, true);
+        assert_eq!(result.text, "Reach [EMAIL_1] or [EMAIL_2]");
+        assert_eq!(result.spans.len(), 2);
+        assert_eq!(restore(&result), text);
+
+        // Starting together, the longer match wins
+        let result = by_pattern("123-45-6789", &["ssn"], &[("area", r"\d{3}")]);
+        assert_eq!(result.text, "[SSN_1]");
+    }
+
+    #[test]
+    fn masks_and_removes_with_utf16_offsets() {
+        let text = "é bob@x.io";
+        let candidates = task(text, &["email"], &[]).pattern_candidates();
+        let masked = replace(text, candidates.clone(), Strategy::Mask, false);
+        assert_eq!(masked.text, "é ********");
+        assert!(masked.mapping.is_none());
+        assert_eq!((masked.spans[0].start, masked.spans[0].end), (2, 10));
+        let removed = replace(text, candidates, Strategy::Remove, false);
+        assert_eq!(removed.text, "é ");
+    }
+}
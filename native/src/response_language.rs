@@ -0,0 +1,77 @@
+//! `GenerationOptions.respondIn`: the instruction that asks for a response
+//! language, and the check that the response is in it.
+
+/// Language code, English name, and the name in the language itself.
+const LANGUAGES: &[(&str, &str, &str)] = &[
+    ("da", "Danish", "dansk"),
+    ("de", "German", "Deutsch"),
+    ("en", "English", "English"),
+    ("es", "Spanish", "español"),
+    ("fr", "French", "français"),
+    ("it", "Italian", "italiano"),
+    ("ja", "Japanese", "日本語"),
+    ("ko", "Korean", "한국어"),
+    ("nb", "Norwegian Bokmål", "norsk bokmål"),
+    ("nl", "Dutch", "Nederlands"),
+    ("pt", "Portuguese", "português"),
+    ("sv", "Swedish", "svenska"),
+    ("tr", "Turkish", "Türkçe"),
+    ("vi", "Vietnamese", "Tiếng Việt"),
+    ("zh", "Chinese", "中文"),
+];
+
+/// Responses shorter than this are too little for language detection to be
+/// trusted, so they're accepted as they are.
+pub const MIN_DETECTABLE_CHARS: usize = 20;
+
+/// Probability from which a detection hypothesis counts: a response the
+/// requested language is this likely for is taken to be in it.
+pub const MIN_PROBABILITY: f64 = 0.2;
+
+/// Primary language subtag, lowercased: "pt-BR" → "pt". Norwegian "no" is
+/// treated as Bokmål, which is what the detector reports.
+fn primary(code: &str) -> String {
+    let primary = code
+        .split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    if primary == "no" {
+        "nb".to_string()
+    } else {
+        primary
+    }
+}
+
+/// How to name the language in instructions, e.g. "Japanese (日本語)".
+/// Codes outside the table are named by the code itself.
+pub fn display_name(code: &str) -> String {
+    let primary = primary(code);
+    match LANGUAGES.iter().find(|(c, _, _)| *c == primary) {
+        Some((_, english, native)) if english == native => english.to_string(),
+        Some((_, english, native)) => format!("{english} ({native})"),
+        None => code.to_string(),
+    }
+}
+
+/// System instruction asking for responses in `code`.
+pub fn instruction(code: &str) -> String {
+    format!(
+        "Always respond in {}, even when the user writes in another language.",
+        display_name(code)
+    )
+}
+
+/// User message asking for the previous response again in `code`.
+pub fn correction(code: &str) -> String {
+    format!(
+        "Your previous reply was not in {0}. Reply again, entirely in {0}.",
+        display_name(code)
+    )
+}
+
+/// Whether a detected language (e.g. "zh-Hant") satisfies the requested
+/// one (e.g. "zh-TW"). Only the primary language is compared.
+pub fn matches(requested: &str, detected: &str) -> bool {
+    primary(requested) == primary(detected)
+}
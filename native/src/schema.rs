@@ -0,0 +1,459 @@
+//! Minimal JSON Schema validation for model-produced tool arguments.
+//!
+//! Covers the keywords tool parameter schemas use in practice: `type`, `enum`,
+//! `const`, `properties`, `required`, `additionalProperties`, `items`, the
+//! length/size/range bounds, `pattern`, `allOf`/`anyOf`/`oneOf` and local
+//! `$ref`s. Unknown keywords (such as `format`) are ignored rather than rejected.
+
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// A single schema violation, located by JSON pointer into the instance.
+#[derive(Debug, Clone)]
+pub struct ValidationError {
+    pub path: String,
+    pub message: String,
+}
+
+/// A schema ready to validate against, with its patterns compiled.
+pub struct Schema {
+    root: Value,
+    patterns: HashMap<String, Regex>,
+}
+
+impl Schema {
+    /// Compile every `pattern` in `root`, failing on one the regex engine
+    /// can't compile.
+    pub fn compile(root: Value) -> Result<Self, String> {
+        fn collect(value: &Value, patterns: &mut HashMap<String, Regex>) -> Result<(), String> {
+            match value {
+                Value::Object(map) => {
+                    if let Some(Value::String(pattern)) = map.get("pattern") {
+                        if !patterns.contains_key(pattern) {
+                            let regex = Regex::new(pattern)
+                                .map_err(|e| format!("invalid 'pattern' {pattern:?}: {e}"))?;
+                            patterns.insert(pattern.clone(), regex);
+                        }
+                    }
+                    map.values().try_for_each(|value| collect(value, patterns))
+                }
+                Value::Array(values) => {
+                    values.iter().try_for_each(|value| collect(value, patterns))
+                }
+                _ => Ok(()),
+            }
+        }
+        let mut patterns = HashMap::new();
+        collect(&root, &mut patterns)?;
+        Ok(Self { root, patterns })
+    }
+
+    /// Validate `instance`, returning every violation found.
+    pub fn validate(&self, instance: &Value) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        self.check(&self.root, instance, "", 0, &mut errors);
+        errors
+    }
+}
+
+/// How many `$ref`s are followed without moving into the instance; a schema
+/// that refers to itself deeper than this accepts the value.
+const MAX_REF_DEPTH: usize = 8;
+
+/// Violations as one line, e.g. `/city: expected string, got integer`.
+pub fn summary(errors: &[ValidationError]) -> String {
+    errors
+        .iter()
+        .map(|e| {
+            if e.path.is_empty() {
+                e.message.clone()
+            } else {
+                format!("{}: {}", e.path, e.message)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Parameter schemas by tool name from a tools JSON array
+/// (`[{ name, parameters }]`). Tools without an object schema are skipped.
+pub fn tool_schemas(tools_json: &str) -> HashMap<String, Value> {
+    let Ok(Value::Array(tools)) = serde_json::from_str::<Value>(tools_json) else {
+        return HashMap::new();
+    };
+    tools
+        .into_iter()
+        .filter_map(|tool| {
+            let name = tool.get("name")?.as_str()?.to_string();
+            let parameters = tool.get("parameters").filter(|p| p.is_object())?;
+            Some((name, parameters.clone()))
+        })
+        .collect()
+}
+
+/// The tool output handed back to the model when its arguments don't match
+/// the declared schema, so it can correct them and call the tool again.
+pub fn invalid_arguments_result(tool_name: &str, errors: &[ValidationError]) -> String {
+    let details: Vec<Value> = errors
+        .iter()
+        .map(|e| json!({ "path": e.path, "message": e.message }))
+        .collect();
+    json!({
+        "error": "InvalidToolArguments",
+        "message": format!(
+            "Arguments for tool '{tool_name}' do not match its parameter schema. Fix them and call the tool again."
+        ),
+        "errors": details,
+    })
+    .to_string()
+}
+
+impl Schema {
+    /// `refs` counts the `$ref`s followed since the last step into `instance`.
+    fn check(
+        &self,
+        schema: &Value,
+        instance: &Value,
+        path: &str,
+        refs: usize,
+        errors: &mut Vec<ValidationError>,
+    ) {
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => {
+                push(errors, path, "no value is allowed here".to_string());
+                return;
+            }
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+
+        // Local refs (`#/definitions/…`, `#/$defs/…`) are resolved as they're reached
+        let target = schema
+            .get("$ref")
+            .and_then(Value::as_str)
+            .and_then(|r| r.strip_prefix('#'))
+            .and_then(|pointer| self.root.pointer(pointer));
+        if let Some(target) = target {
+            if refs < MAX_REF_DEPTH {
+                self.check(target, instance, path, refs + 1, errors);
+            }
+            return;
+        }
+
+        if let Some(expected) = schema.get("type") {
+            let allowed: Vec<&str> = match expected {
+                Value::String(t) => vec![t.as_str()],
+                Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !allowed.is_empty() && !allowed.iter().any(|t| has_type(instance, t)) {
+                push(
+                    errors,
+                    path,
+                    format!(
+                        "expected {}, got {}",
+                        allowed.join(" or "),
+                        type_name(instance)
+                    ),
+                );
+                // Further keywords would only repeat the mismatch
+                return;
+            }
+        }
+
+        if let Some(Value::Array(options)) = schema.get("enum") {
+            if !options.contains(instance) {
+                push(
+                    errors,
+                    path,
+                    format!("must be one of {}", Value::Array(options.clone())),
+                );
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if constant != instance {
+                push(errors, path, format!("must equal {constant}"));
+            }
+        }
+
+        match instance {
+            Value::Object(object) => self.check_object(schema, object, path, errors),
+            Value::Array(items) => self.check_array(schema, items, path, errors),
+            Value::String(s) => self.check_string(schema, s, path, errors),
+            Value::Number(_) => check_number(schema, instance, path, errors),
+            _ => {}
+        }
+
+        if let Some(Value::Array(all)) = schema.get("allOf") {
+            for sub in all {
+                self.check(sub, instance, path, refs, errors);
+            }
+        }
+        if let Some(Value::Array(any)) = schema.get("anyOf") {
+            if !any
+                .iter()
+                .any(|sub| self.validate_at(sub, instance, path, refs))
+            {
+                push(
+                    errors,
+                    path,
+                    "does not match any allowed schema".to_string(),
+                );
+            }
+        }
+        if let Some(Value::Array(one)) = schema.get("oneOf") {
+            let matches = one
+                .iter()
+                .filter(|sub| self.validate_at(sub, instance, path, refs))
+                .count();
+            if matches != 1 {
+                push(
+                    errors,
+                    path,
+                    format!("must match exactly one allowed schema, matched {matches}"),
+                );
+            }
+        }
+    }
+
+    fn check_object(
+        &self,
+        schema: &Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &str,
+        errors: &mut Vec<ValidationError>,
+    ) {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for key in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(key) {
+                    push(errors, path, format!("missing required property '{key}'"));
+                }
+            }
+        }
+
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (key, value) in object {
+            let child = format!("{path}/{}", escape_pointer(key));
+            match properties.and_then(|p| p.get(key)) {
+                Some(sub) => self.check(sub, value, &child, 0, errors),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        push(errors, path, format!("unexpected property '{key}'"));
+                    }
+                    Some(sub @ Value::Object(_)) => self.check(sub, value, &child, 0, errors),
+                    _ => {}
+                },
+            }
+        }
+
+        if let Some(min) = schema.get("minProperties").and_then(Value::as_u64) {
+            if (object.len() as u64) < min {
+                push(errors, path, format!("must have at least {min} properties"));
+            }
+        }
+        if let Some(max) = schema.get("maxProperties").and_then(Value::as_u64) {
+            if object.len() as u64 > max {
+                push(errors, path, format!("must have at most {max} properties"));
+            }
+        }
+    }
+
+    fn check_array(
+        &self,
+        schema: &Map<String, Value>,
+        items: &[Value],
+        path: &str,
+        errors: &mut Vec<ValidationError>,
+    ) {
+        if let Some(item_schema) = schema.get("items").filter(|s| !s.is_array()) {
+            for (i, item) in items.iter().enumerate() {
+                self.check(item_schema, item, &format!("{path}/{i}"), 0, errors);
+            }
+        }
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if (items.len() as u64) < min {
+                push(errors, path, format!("must have at least {min} items"));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if items.len() as u64 > max {
+                push(errors, path, format!("must have at most {max} items"));
+            }
+        }
+        if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+            let duplicate = items
+                .iter()
+                .enumerate()
+                .any(|(i, a)| items[i + 1..].contains(a));
+            if duplicate {
+                push(errors, path, "items must be unique".to_string());
+            }
+        }
+    }
+
+    fn check_string(
+        &self,
+        schema: &Map<String, Value>,
+        s: &str,
+        path: &str,
+        errors: &mut Vec<ValidationError>,
+    ) {
+        let len = s.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+            if len < min {
+                push(errors, path, format!("must be at least {min} characters"));
+            }
+        }
+        if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+            if len > max {
+                push(errors, path, format!("must be at most {max} characters"));
+            }
+        }
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            if self.patterns.get(pattern).is_some_and(|re| !re.is_match(s)) {
+                push(errors, path, format!("must match pattern {pattern}"));
+            }
+        }
+    }
+
+    fn validate_at(&self, schema: &Value, instance: &Value, path: &str, refs: usize) -> bool {
+        let mut errors = Vec::new();
+        self.check(schema, instance, path, refs, &mut errors);
+        errors.is_empty()
+    }
+}
+
+fn check_number(
+    schema: &Map<String, Value>,
+    instance: &Value,
+    path: &str,
+    errors: &mut Vec<ValidationError>,
+) {
+    let Some(n) = instance.as_f64() else {
+        return;
+    };
+    let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+    if let Some(min) = bound("minimum") {
+        if n < min {
+            push(errors, path, format!("must be >= {min}"));
+        }
+    }
+    if let Some(max) = bound("maximum") {
+        if n > max {
+            push(errors, path, format!("must be <= {max}"));
+        }
+    }
+    if let Some(min) = bound("exclusiveMinimum") {
+        if n <= min {
+            push(errors, path, format!("must be > {min}"));
+        }
+    }
+    if let Some(max) = bound("exclusiveMaximum") {
+        if n >= max {
+            push(errors, path, format!("must be < {max}"));
+        }
+    }
+    if let Some(step) = bound("multipleOf").filter(|s| *s > 0.0) {
+        if ((n / step).round() * step - n).abs() > f64::EPSILON * n.abs().max(1.0) {
+            push(errors, path, format!("must be a multiple of {step}"));
+        }
+    }
+}
+
+fn has_type(instance: &Value, expected: &str) -> bool {
+    match expected {
+        "object" => instance.is_object(),
+        "array" => instance.is_array(),
+        "string" => instance.is_string(),
+        "boolean" => instance.is_boolean(),
+        "null" => instance.is_null(),
+        "number" => instance.is_number(),
+        "integer" => instance
+            .as_f64()
+            .is_some_and(|n| n.fract() == 0.0 && n.is_finite()),
+        // Unknown type names can't be checked; don't reject on them
+        _ => true,
+    }
+}
+
+fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::String(_) => "string",
+        Value::Bool(_) => "boolean",
+        Value::Null => "null",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+    }
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn push(errors: &mut Vec<ValidationError>, path: &str, message: String) {
+    errors.push(ValidationError {
+        path: path.to_string(),
+        message,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recursive_refs_are_followed_into_nested_values() {
+        let schema = Schema::compile(json!({
+            "$ref": "#/definitions/node",
+            "definitions": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "children": { "type": "array", "items": { "$ref": "#/definitions/node" } },
+                    },
+                    "required": ["name"],
+                },
+            },
+        }))
+        .unwrap();
+        let mut tree = json!({ "name": "leaf" });
+        for depth in 0..20 {
+            tree = json!({ "name": format!("level {depth}"), "children": [tree] });
+        }
+        assert!(schema.validate(&tree).is_empty());
+
+        let broken = json!({ "name": "root", "children": [{ "children": [] }] });
+        let errors = schema.validate(&broken);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "/children/0");
+    }
+
+    #[test]
+    fn refs_that_never_reach_a_value_accept_it() {
+        let schema = Schema::compile(
+            json!({ "$ref": "#/$defs/loop", "$defs": { "loop": { "$ref": "#/$defs/loop" } } }),
+        )
+        .unwrap();
+        assert!(schema.validate(&json!(1)).is_empty());
+    }
+
+    #[test]
+    fn patterns_are_compiled_up_front() {
+        let error = Schema::compile(json!({ "type": "string", "pattern": "(" }))
+            .err()
+            .unwrap();
+        assert!(error.contains("invalid 'pattern'"), "{error}");
+
+        let schema = Schema::compile(json!({ "type": "string", "pattern": "^[a-z]+$" })).unwrap();
+        assert!(schema.validate(&json!("abc")).is_empty());
+        assert_eq!(
+            summary(&schema.validate(&json!("ABC"))),
+            "must match pattern ^[a-z]+$"
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Semantic search over documents given as texts or precomputed embeddings.
+
+use crate::embeddings::{embed_all, embedding, EmbeddingModel};
+use crate::{coded_error, vector, ErrorCode};
+use napi::bindgen_prelude::{AsyncTask, Either, Float32Array};
+use napi::{Env, Status};
+use napi_derive::napi;
+
+const DEFAULT_TOP_K: u32 = 10;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct SemanticSearchOptions {
+    /// Number of results (default 10)
+    pub top_k: Option<u32>,
+    /// Language of texts to embed, e.g. "en" (default: detected per text)
+    pub language: Option<String>,
+    /// Embedding model for texts: `sentence` (default) or `contextual`
+    #[napi(ts_type = "'sentence' | 'contextual'")]
+    pub model: Option<String>,
+}
+
+/// A document ranked by `semantic_search`.
+#[napi(object)]
+pub struct SemanticSearchHit {
+    /// Position of the document in `documents`
+    pub index: u32,
+    /// Cosine similarity to the query, from -1 to 1
+    pub score: f64,
+}
+
+/// Texts to embed, or vectors embedded earlier.
+enum Input<T> {
+    Texts(T),
+    Vectors(Vec<Vec<f32>>),
+}
+
+pub struct SemanticSearchTask {
+    query: Input<String>,
+    documents: Input<Vec<String>>,
+    top_k: usize,
+    language: Option<String>,
+    model: EmbeddingModel,
+    error_code: Option<ErrorCode>,
+}
+
+impl SemanticSearchTask {
+    fn embed(&mut self, texts: &[String]) -> napi::Result<Vec<Vec<f32>>> {
+        let concurrency = std::thread::available_parallelism().map_or(4, |n| n.get());
+        embed_all(texts, self.language.as_deref(), self.model, concurrency).map_err(|e| {
+            self.error_code = Some(e.code);
+            napi::Error::from_reason(e.message)
+        })
+    }
+}
+
+impl napi::Task for SemanticSearchTask {
+    type Output = Vec<SemanticSearchHit>;
+    type JsValue = Vec<SemanticSearchHit>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let query = match std::mem::replace(&mut self.query, Input::Vectors(Vec::new())) {
+            Input::Texts(text) => {
+                embedding(&text, self.language.as_deref(), self.model).map_err(|e| {
+                    self.error_code = Some(e.code);
+                    napi::Error::from_reason(format!("Query: {}", e.message))
+                })?
+            }
+            Input::Vectors(mut vectors) => vectors.remove(0),
+        };
+        let documents = match std::mem::replace(&mut self.documents, Input::Vectors(Vec::new())) {
+            Input::Texts(texts) => self.embed(&texts)?,
+            Input::Vectors(vectors) => vectors,
+        };
+        if let Some(index) = documents.iter().position(|d| d.len() != query.len()) {
+            self.error_code = Some(ErrorCode::InvalidArg);
+            return Err(napi::Error::from_reason(format!(
+                "Document {index} has {} dimensions but the query has {}",
+                documents[index].len(),
+                query.len()
+            )));
+        }
+        let query_norm = vector::norm(&query);
+        let scored = documents
+            .iter()
+            .enumerate()
+            .map(|(index, document)| {
+                (
+                    index,
+                    vector::cosine_with_norm(&query, query_norm, document),
+                )
+            })
+            .collect();
+        Ok(vector::top_k(scored, self.top_k)
+            .into_iter()
+            .map(|(index, score)| SemanticSearchHit {
+                index: index as u32,
+                score: score as f64,
+            })
+            .collect())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Rank `documents` by cosine similarity to `query` and resolve with the
+/// `topK` best as `{ index, score }`, best first. The query and documents
+/// are texts, embedded on the device, or vectors from `embed`; vectors must
+/// all have the query's dimension.
+#[napi(ts_return_type = "Promise<SemanticSearchHit[]>")]
+pub fn semantic_search(
+    query: Either<String, Float32Array>,
+    documents: Either<Vec<String>, Vec<Float32Array>>,
+    options: Option<SemanticSearchOptions>,
+) -> napi::Result<AsyncTask<SemanticSearchTask>> {
+    let options = options.unwrap_or_default();
+    let model = EmbeddingModel::parse(options.model.as_deref())
+        .map_err(|e| napi::Error::new(Status::InvalidArg, e.message))?;
+    let query = match query {
+        Either::A(text) => Input::Texts(text),
+        Either::B(vector) => Input::Vectors(vec![vector.to_vec()]),
+    };
+    let documents = match documents {
+        Either::A(texts) => Input::Texts(texts),
+        Either::B(vectors) => Input::Vectors(vectors.iter().map(|v| v.to_vec()).collect()),
+    };
+    Ok(AsyncTask::new(SemanticSearchTask {
+        query,
+        documents,
+        top_k: options.top_k.unwrap_or(DEFAULT_TOP_K) as usize,
+        language: options.language,
+        model,
+        error_code: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use napi::Task;
+
+    fn search(
+        query: Vec<f32>,
+        documents: Vec<Vec<f32>>,
+        top_k: usize,
+    ) -> napi::Result<Vec<(u32, f64)>> {
+        let mut task = SemanticSearchTask {
+            query: Input::Vectors(vec![query]),
+            documents: Input::Vectors(documents),
+            top_k,
+            language: None,
+            model: EmbeddingModel::Sentence,
+            error_code: None,
+        };
+        let hits = task.compute();
+        if hits.is_err() {
+            assert_eq!(task.error_code, Some(ErrorCode::InvalidArg));
+        }
+        Ok(hits?
+            .into_iter()
+            .map(|hit| (hit.index, hit.score))
+            .collect())
+    }
+
+    #[test]
+    fn precomputed_vectors_rank_by_cosine_similarity() {
+        let documents = vec![
+            vec![0.0, 1.0],
+            vec![3.0, 0.0],
+            vec![1.0, 1.0],
+            vec![-1.0, 0.0],
+        ];
+        let hits = search(vec![2.0, 0.0], documents.clone(), 10).unwrap();
+        let indexes: Vec<u32> = hits.iter().map(|hit| hit.0).collect();
+        assert_eq!(indexes, [1, 2, 0, 3]);
+        let scores: Vec<f64> = hits.iter().map(|hit| hit.1).collect();
+        let expected = [1.0, std::f64::consts::FRAC_1_SQRT_2, 0.0, -1.0];
+        for (score, expected) in scores.iter().zip(expected) {
+            assert!((score - expected).abs() < 1e-6, "{score} != {expected}");
+        }
+
+        assert_eq!(
+            search(vec![2.0, 0.0], documents.clone(), 2).unwrap().len(),
+            2
+        );
+        assert_eq!(
+            search(vec![0.0, 5.0], documents.clone(), 1).unwrap()[0].0,
+            0
+        );
+        assert!(search(vec![2.0, 0.0], documents, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn zero_vectors_score_zero() {
+        let hits = search(vec![0.0; 3], vec![vec![1.0, 2.0, 3.0], vec![0.0; 3]], 10).unwrap();
+        assert!(hits.iter().all(|hit| hit.1 == 0.0));
+        let hits = search(vec![1.0, 2.0, 3.0], vec![vec![0.0; 3]], 10).unwrap();
+        assert_eq!(hits, [(0, 0.0)]);
+    }
+
+    #[test]
+    fn documents_must_match_the_query_dimension() {
+        let error = search(
+            vec![1.0, 0.0],
+            vec![vec![1.0, 0.0], vec![1.0, 0.0, 0.0]],
+            10,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.reason,
+            "Document 1 has 3 dimensions but the query has 2"
+        );
+        assert!(search(vec![1.0, 0.0], Vec::new(), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn cosine_covers_the_lane_remainder() {
+        // 19 dimensions: two full lanes of 8 and a tail of 3
+        let a: Vec<f32> = (1..=19).map(|i| i as f32).collect();
+        let b: Vec<f32> = (1..=19).map(|i| (20 - i) as f32).collect();
+        let naive_dot: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+        assert_eq!(vector::dot(&a, &b), naive_dot);
+        let naive = naive_dot / (vector::norm(&a) * vector::norm(&b));
+        let cosine = vector::cosine_with_norm(&a, vector::norm(&a), &b);
+        assert!((cosine - naive).abs() < 1e-6);
+        let scaled: Vec<f32> = a.iter().map(|x| x * 4.0).collect();
+        assert!((vector::cosine_with_norm(&a, vector::norm(&a), &scaled) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn unknown_models_are_rejected() {
+        let options = SemanticSearchOptions {
+            model: Some("word".into()),
+            ..Default::default()
+        };
+        let error = semantic_search(Either::A("q".into()), Either::A(Vec::new()), Some(options))
+            .err()
+            .unwrap();
+        assert_eq!(error.status, Status::InvalidArg);
+    }
+}
@@ -0,0 +1,623 @@
+//! Embedded OpenAI-compatible HTTP server. Serves `/v1/chat/completions`
+//! and `/v1/models` on the on-device model from Rust, so clients outside
+//! Node (curl, LangChain, editor plugins) can use it locally. Nothing runs
+//! until `startServer` is called.
+
+use crate::openai::{self, ChunkFormatter, MODEL_NAME};
+use crate::{
+    apple_ai_cancel_generation, run_stream, ErrorCode, GenerateUnifiedTask, GenerationRequest,
+    StreamSink,
+};
+use napi::{Env, JsObject, Status};
+use napi_derive::napi;
+use serde_json::json;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+const DEFAULT_PORT: u32 = 8080;
+const DEFAULT_HOST: &str = "127.0.0.1";
+/// Request bodies carry base64 images, so allow more than plain chat needs
+const MAX_BODY_BYTES: usize = 32 * 1024 * 1024;
+const MAX_HEADER_LINES: usize = 100;
+/// Request line and headers together; read before the API key is checked
+const MAX_HEADER_BYTES: usize = 64 * 1024;
+/// Connections answered at once; more are refused (with 503 over HTTP)
+/// rather than each getting a thread
+const MAX_CONNECTIONS: usize = 64;
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ServerOptions {
+    /// Port to listen on (default 8080); 0 picks a free one
+    pub port: Option<u32>,
+    /// Address to bind (default `127.0.0.1`, local connections only)
+    pub host: Option<String>,
+    /// Require `Authorization: Bearer <apiKey>` on every request
+    pub api_key: Option<String>,
+}
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub authorization: Option<String>,
+    pub origin: Option<String>,
+    pub host: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// Read one line of the request head into `line`, out of the `budget` of
+/// header bytes left.
+fn read_head_line(
+    reader: &mut impl BufRead,
+    line: &mut String,
+    budget: &mut usize,
+) -> Result<(), (u16, String)> {
+    line.clear();
+    let read = reader
+        .take(*budget as u64)
+        .read_line(line)
+        .map_err(|e| (400, format!("reading request failed: {e}")))?;
+    *budget -= read;
+    if *budget == 0 && !line.ends_with('\n') {
+        return Err((
+            431,
+            format!("request headers exceed {MAX_HEADER_BYTES} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+/// Read an HTTP/1.1 request, or fail with the status to answer and why.
+pub fn read_request(stream: &TcpStream) -> Result<HttpRequest, (u16, String)> {
+    let malformed = |message: String| (400, message);
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let mut budget = MAX_HEADER_BYTES;
+    read_head_line(&mut reader, &mut line, &mut budget)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(malformed("malformed request line".to_string()));
+    };
+    let method = method.to_string();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    let mut origin = None;
+    let mut host = None;
+    for _ in 0..MAX_HEADER_LINES {
+        read_head_line(&mut reader, &mut line, &mut budget)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            let mut body = vec![0; content_length];
+            reader
+                .read_exact(&mut body)
+                .map_err(|e| malformed(format!("reading body failed: {e}")))?;
+            return Ok(HttpRequest {
+                method,
+                path,
+                authorization,
+                origin,
+                host,
+                body,
+            });
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(malformed(format!("malformed header {header:?}")));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| malformed(format!("invalid Content-Length {value:?}")))?;
+            if content_length > MAX_BODY_BYTES {
+                return Err(malformed(format!("body exceeds {MAX_BODY_BYTES} bytes")));
+            }
+        } else if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("origin") {
+            origin = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("host") {
+            host = Some(value.to_string());
+        }
+    }
+    Err((431, "too many headers".to_string()))
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    }
+}
+
+pub fn respond(stream: &mut TcpStream, status: u16, body: &serde_json::Value) {
+    let body = body.to_string();
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        reason_phrase(status),
+        body.len()
+    );
+}
+
+fn respond_error(stream: &mut TcpStream, code: ErrorCode, message: &str) {
+    respond(
+        stream,
+        openai::http_status(code),
+        &openai::error_json(code, message),
+    );
+}
+
+/// Error responses that aren't generation failures (auth, routing).
+pub fn respond_http_error(stream: &mut TcpStream, status: u16, kind: &str, message: &str) {
+    let body =
+        json!({ "error": { "message": message, "type": kind, "param": null, "code": null } });
+    respond(stream, status, &body);
+}
+
+/// Browsers send an `Origin`; only pages served from this machine may call
+/// the server, which keeps DNS-rebinding pages out.
+fn local_origin(origin: &str) -> bool {
+    let host = origin
+        .split_once("://")
+        .map_or(origin, |(_, rest)| rest)
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !name.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+/// Every request names the server in `Host`; after DNS rebinding it is the
+/// attacker's domain, even for same-origin requests that send no `Origin`.
+/// Loopback names and the address the connection came in on are allowed,
+/// with the port the server listens on.
+fn local_host(host: &str, bound: SocketAddr) -> bool {
+    let (name, port) = match host.rfind(':').filter(|&i| !host[i..].contains(']')) {
+        Some(i) => (&host[..i], host[i + 1..].parse::<u16>().ok()),
+        None => (host, Some(80)),
+    };
+    let own = match bound {
+        SocketAddr::V6(_) => format!("[{}]", bound.ip()),
+        SocketAddr::V4(_) => bound.ip().to_string(),
+    };
+    port == Some(bound.port())
+        && (matches!(name, "localhost" | "127.0.0.1" | "[::1]") || name.eq_ignore_ascii_case(&own))
+}
+
+/// Compare without stopping at the first difference, so response times
+/// don't reveal how much of a guessed key was right.
+fn same_key(given: &[u8], key: &[u8]) -> bool {
+    given.len() == key.len() && given.iter().zip(key).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Read the request on `stream`, answering it with an error when it is
+/// malformed, is addressed to or comes from a page on another host, or lacks
+/// the bearer token `api_key`.
+pub fn accept_request(stream: &mut TcpStream, api_key: Option<&str>) -> Option<HttpRequest> {
+    let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+    let request = match read_request(stream) {
+        Ok(request) => request,
+        Err((status, message)) => {
+            respond_http_error(stream, status, "invalid_request_error", &message);
+            return None;
+        }
+    };
+    if request
+        .origin
+        .as_deref()
+        .is_some_and(|origin| !local_origin(origin))
+    {
+        respond_http_error(stream, 403, "forbidden", "Origin not allowed");
+        return None;
+    }
+    let bound = stream.local_addr();
+    if !request
+        .host
+        .as_deref()
+        .zip(bound.ok())
+        .is_some_and(|(host, bound)| local_host(host, bound))
+    {
+        respond_http_error(stream, 403, "forbidden", "Host not allowed");
+        return None;
+    }
+    if let Some(key) = api_key {
+        let token = request
+            .authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !token.is_some_and(|token| same_key(token.as_bytes(), key.as_bytes())) {
+            respond_http_error(
+                stream,
+                401,
+                "authentication_error",
+                "Missing or invalid API key",
+            );
+            return None;
+        }
+    }
+    Some(request)
+}
+
+fn handle_connection(mut stream: TcpStream, api_key: Option<&str>) {
+    let Some(request) = accept_request(&mut stream, api_key) else {
+        return;
+    };
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/v1/models") => respond(&mut stream, 200, &models_json()),
+        ("POST", "/v1/chat/completions") => chat_completions(&mut stream, &request.body),
+        (_, "/v1/models" | "/v1/chat/completions") => respond_http_error(
+            &mut stream,
+            405,
+            "invalid_request_error",
+            &format!("{} is not allowed here", request.method),
+        ),
+        (_, path) => respond_http_error(
+            &mut stream,
+            404,
+            "invalid_request_error",
+            &format!("No route for {path}"),
+        ),
+    }
+}
+
+fn models_json() -> serde_json::Value {
+    json!({
+        "object": "list",
+        "data": [{
+            "id": MODEL_NAME,
+            "object": "model",
+            "created": 0,
+            "owned_by": "apple",
+        }],
+    })
+}
+
+fn chat_completions(stream: &mut TcpStream, body: &[u8]) {
+    let prepared = std::str::from_utf8(body)
+        .map_err(|_| napi::Error::new(Status::InvalidArg, "body is not UTF-8".to_string()))
+        .and_then(openai::parse_request)
+        .and_then(|request| {
+            let generation = GenerationRequest::from_messages(&request.messages, request.options)?;
+            Ok((
+                generation,
+                request.model,
+                request.stream,
+                request.include_usage,
+            ))
+        });
+    let (generation, model, streamed, include_usage) = match prepared {
+        Ok(prepared) => prepared,
+        Err(error) => {
+            respond_error(stream, ErrorCode::of(&error), &error.reason);
+            return;
+        }
+    };
+
+    if streamed {
+        let formatter = ChunkFormatter::new(
+            format!("chatcmpl-{}", crate::next_request_id()),
+            model,
+            include_usage,
+        );
+        stream_completion(stream, generation, formatter);
+        return;
+    }
+    let mut task = GenerateUnifiedTask::new(generation);
+    match napi::Task::compute(&mut task) {
+        Ok(result) => {
+            let id = format!("chatcmpl-{}", task.request_id);
+            let completion = openai::completion_json(&id, openai::unix_time(), &model, &result);
+            respond(stream, 200, &completion);
+        }
+        Err(error) => respond_error(stream, task.failure_code(&error), &error.reason),
+    }
+}
+
+/// Write the generation to `stream` as server-sent events, cancelling it if
+/// the client goes away.
+fn stream_completion(
+    stream: &mut TcpStream,
+    generation: GenerationRequest,
+    mut formatter: ChunkFormatter,
+) {
+    let (sender, events) = mpsc::channel();
+    let request_id = match run_stream(generation, StreamSink::Channel(sender)) {
+        Ok(request_id) => request_id,
+        Err(error) => {
+            respond_error(stream, ErrorCode::of(&error), &error.reason);
+            return;
+        }
+    };
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    if stream.write_all(head.as_bytes()).is_err() {
+        unsafe { apple_ai_cancel_generation(request_id) };
+        return;
+    }
+    // The sender is dropped with the stream state, which ends this loop too
+    for event in events {
+        let done = matches!(event.kind.as_str(), "finish" | "error");
+        let frames = formatter.frames(&event);
+        if !frames.is_empty() && stream.write_all(frames.as_bytes()).is_err() {
+            unsafe { apple_ai_cancel_generation(request_id) };
+            return;
+        }
+        if done {
+            return;
+        }
+    }
+}
+
+/// Start the embedded OpenAI-compatible server and return `{ url, port,
+/// stop }` once it is listening. `stop()` stops accepting connections,
+/// letting requests already being answered finish.
+#[napi(ts_return_type = "{ url: string; port: number; stop: () => void }")]
+pub fn start_server(env: Env, options: Option<ServerOptions>) -> napi::Result<JsObject> {
+    let options = options.unwrap_or_default();
+    let host = options.host.unwrap_or_else(|| DEFAULT_HOST.to_string());
+    let api_key: Option<Arc<str>> = options.api_key.filter(|key| !key.is_empty()).map(Arc::from);
+    listen(
+        env,
+        &host,
+        options.port.unwrap_or(DEFAULT_PORT),
+        move |connection| handle_connection(connection, api_key.as_deref()),
+    )
+}
+
+/// Listen on `host:port`, answering each connection on its own thread with
+/// `handle`, and return `{ url, port, stop }` for JS.
+pub fn listen(
+    env: Env,
+    host: &str,
+    port: u32,
+    handle: impl Fn(TcpStream) + Send + Sync + 'static,
+) -> napi::Result<JsObject> {
+    let port = u16::try_from(port).map_err(|_| {
+        napi::Error::new(Status::InvalidArg, format!("port {port} is out of range"))
+    })?;
+    let listener = TcpListener::bind((host, port))
+        .map_err(|e| napi::Error::from_reason(format!("Cannot listen on {host}:{port}: {e}")))?;
+    let address = listener
+        .local_addr()
+        .map_err(|e| napi::Error::from_reason(format!("Cannot read the server's address: {e}")))?;
+    crate::ensure_initialized();
+
+    let stopped = accept_loop(
+        move || listener.accept().map(|(c, _)| c),
+        |mut connection: TcpStream| {
+            respond_http_error(&mut connection, 503, "server_error", "Too many connections")
+        },
+        handle,
+    );
+    let stop = env.create_function_from_closure("stop", move |ctx| {
+        if !stopped.swap(true, Ordering::SeqCst) {
+            // Wake the accept loop so it sees the flag
+            let _ = TcpStream::connect_timeout(&wake_address(address), Duration::from_secs(1));
+        }
+        ctx.env.get_undefined()
+    })?;
+
+    let url_host = match address {
+        SocketAddr::V6(_) => format!("[{}]", address.ip()),
+        SocketAddr::V4(_) => address.ip().to_string(),
+    };
+    let mut handle = env.create_object()?;
+    handle.set_named_property(
+        "url",
+        env.create_string(&format!("http://{url_host}:{}", address.port()))?,
+    )?;
+    handle.set_named_property("port", env.create_uint32(address.port().into())?)?;
+    handle.set_named_property("stop", stop)?;
+    Ok(handle)
+}
+
+/// Take connections from `accept` on a thread of their own, answering each
+/// on its own thread with `handle`, until the returned flag is set. Whoever
+/// sets it connects once more so the loop wakes up and sees it. While
+/// `MAX_CONNECTIONS` are being answered, new ones go to `refuse` instead.
+pub fn accept_loop<C: Send + 'static>(
+    mut accept: impl FnMut() -> std::io::Result<C> + Send + 'static,
+    refuse: impl Fn(C) + Send + 'static,
+    handle: impl Fn(C) + Send + Sync + 'static,
+) -> Arc<AtomicBool> {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let accepting = stopped.clone();
+    let handle = Arc::new(handle);
+    let active = Arc::new(AtomicUsize::new(0));
+    std::thread::spawn(move || loop {
+        let connection = accept();
+        if accepting.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(connection) = connection else {
+            continue;
+        };
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            refuse(connection);
+            continue;
+        }
+        let handle = handle.clone();
+        let active = active.clone();
+        std::thread::spawn(move || {
+            handle(connection);
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+    });
+    stopped
+}
+
+/// Where to connect to reach a listener bound to `address`; a wildcard
+/// address is reached through loopback.
+fn wake_address(address: SocketAddr) -> SocketAddr {
+    let mut target = address;
+    if address.ip().is_unspecified() {
+        target.set_ip(match address {
+            SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_origins_are_allowed() {
+        for origin in [
+            "http://localhost",
+            "http://localhost:3000",
+            "https://127.0.0.1:8443/",
+            "http://[::1]:8080",
+        ] {
+            assert!(local_origin(origin), "{origin}");
+        }
+    }
+
+    #[test]
+    fn other_origins_are_refused() {
+        for origin in [
+            "https://example.com",
+            "http://localhost.example.com",
+            "http://127.0.0.1.example.com:80",
+            "http://[::2]:8080",
+            "null",
+        ] {
+            assert!(!local_origin(origin), "{origin}");
+        }
+    }
+
+    #[test]
+    fn hosts_must_name_this_server() {
+        let loopback: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        for host in ["localhost:8080", "127.0.0.1:8080", "[::1]:8080"] {
+            assert!(local_host(host, loopback), "{host}");
+        }
+        for host in [
+            "localhost",
+            "localhost:9090",
+            "attacker.example:8080",
+            "localhost.example:8080",
+            "192.168.1.5:8080",
+            "[::1]",
+            "",
+        ] {
+            assert!(!local_host(host, loopback), "{host}");
+        }
+
+        let lan: SocketAddr = "192.168.1.5:80".parse().unwrap();
+        assert!(local_host("192.168.1.5", lan));
+        assert!(local_host("192.168.1.5:80", lan));
+        assert!(!local_host("attacker.example", lan));
+        let v6: SocketAddr = "[fe80::1]:8080".parse().unwrap();
+        assert!(local_host("[FE80::1]:8080", v6));
+    }
+
+    #[test]
+    fn keys_match_only_exactly() {
+        assert!(same_key(b"secret", b"secret"));
+        assert!(!same_key(b"secreT", b"secret"));
+        assert!(!same_key(b"secret2", b"secret"));
+        assert!(!same_key(b"", b"secret"));
+    }
+
+    /// What `read_request` makes of `raw` sent by a client.
+    fn read_sent(raw: Vec<u8>) -> Result<HttpRequest, (u16, String)> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            // The server may stop reading early and close the connection
+            let _ = stream.write_all(&raw);
+            stream
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let request = read_request(&stream);
+        drop(client.join());
+        request
+    }
+
+    #[test]
+    fn requests_are_read_up_to_the_header_limit() {
+        let request = read_sent(
+            b"POST /v1/chat/completions?x=1 HTTP/1.1\r\nHost: localhost:8080\r\nContent-Length: 2\r\n\r\n{}"
+                .to_vec(),
+        )
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/v1/chat/completions");
+        assert_eq!(request.host.as_deref(), Some("localhost:8080"));
+        assert_eq!(request.body, b"{}");
+
+        let mut long_header = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        long_header.resize(MAX_HEADER_BYTES + 1024, b'a');
+        assert_eq!(read_sent(long_header).err().map(|e| e.0), Some(431));
+
+        let mut endless_line = b"GET /".to_vec();
+        endless_line.resize(MAX_HEADER_BYTES * 2, b'a');
+        assert_eq!(read_sent(endless_line).err().map(|e| e.0), Some(431));
+    }
+
+    #[test]
+    fn connections_past_the_limit_are_refused() {
+        let (connect, connections) = mpsc::channel::<u32>();
+        let connections = std::sync::Mutex::new(connections);
+        let (refused, refusals) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Arc::new(std::sync::Mutex::new(released));
+        let (answered, answers) = mpsc::channel();
+        let stopped = accept_loop(
+            move || Ok(connections.lock().unwrap().recv().unwrap()),
+            move |connection| refused.send(connection).unwrap(),
+            move |connection| {
+                answered.send(connection).unwrap();
+                let _ = released.lock().unwrap().recv();
+            },
+        );
+
+        for connection in 0..=MAX_CONNECTIONS as u32 {
+            connect.send(connection).unwrap();
+        }
+        let timeout = Duration::from_secs(5);
+        assert_eq!(refusals.recv_timeout(timeout), Ok(MAX_CONNECTIONS as u32));
+        for _ in 0..MAX_CONNECTIONS {
+            answers.recv_timeout(timeout).unwrap();
+        }
+
+        // A finished connection frees its slot, once its thread has wound down
+        release.send(()).unwrap();
+        let admitted = (100..200).find(|&connection| {
+            connect.send(connection).unwrap();
+            match refusals.recv_timeout(Duration::from_millis(20)) {
+                Ok(_) => false,
+                Err(_) => answers.recv_timeout(timeout) == Ok(connection),
+            }
+        });
+        assert!(admitted.is_some());
+
+        stopped.store(true, Ordering::SeqCst);
+        connect.send(101).unwrap();
+        drop(release);
+    }
+}
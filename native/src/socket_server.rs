@@ -0,0 +1,367 @@
+//! JSON-RPC 2.0 over a Unix domain socket, one message per line. Lets helper
+//! processes and non-Node runtimes on the same Mac share the model this
+//! process keeps warm, without going through HTTP.
+//!
+//! Methods:
+//! - `generate`: params are an OpenAI chat completions request body; the
+//!   result is `{ text, object, toolCalls, finishReason, usage }`
+//! - `stream`: the same params; each stream event arrives as a
+//!   `stream.event` notification `{ id, event }` carrying the request's id,
+//!   and the result is `{ finishReason, usage }` once the stream ends
+//! - `embed`: `{ texts, language?, model? }` to `{ embeddings }`
+//! - `availability`: `{ available, reason }`
+//! - `ping`
+
+use crate::server;
+use crate::{
+    apple_ai_cancel_generation, check_availability, embeddings, openai, run_stream, ErrorCode,
+    GenerateUnifiedTask, GenerationRequest, GenerationResult, StreamEvent, StreamSink, TokenUsage,
+};
+use napi::{Env, JsObject, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+
+/// Owner read and write only: other users on the Mac can't connect
+const DEFAULT_PERMISSIONS: u32 = 0o600;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct SocketServerOptions {
+    /// File mode of the socket (default 0o600, the current user only)
+    pub permissions: Option<u32>,
+}
+
+/// Replies and notifications for one connection; requests on it are
+/// answered concurrently, so writes are serialized.
+type Writer = Arc<Mutex<UnixStream>>;
+
+/// Requests one connection may have in progress; later lines wait unread
+const MAX_IN_FLIGHT: usize = 8;
+
+fn send(writer: &Writer, message: &Value) -> bool {
+    let mut stream = writer.lock().unwrap();
+    writeln!(stream, "{message}")
+        .and_then(|_| stream.flush())
+        .is_ok()
+}
+
+struct RpcError {
+    code: i32,
+    message: String,
+    /// The library's error code, for failures of the model
+    error_code: Option<ErrorCode>,
+}
+
+impl RpcError {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self {
+            code: -32602,
+            message: message.into(),
+            error_code: Some(ErrorCode::InvalidArg),
+        }
+    }
+
+    /// A failed generation; `code` is what the library reports for it.
+    fn generation(code: ErrorCode, message: String) -> Self {
+        Self {
+            code: if code == ErrorCode::InvalidArg {
+                -32602
+            } else {
+                -32000
+            },
+            message,
+            error_code: Some(code),
+        }
+    }
+
+    fn to_json(&self, id: &Value) -> Value {
+        let data = self
+            .error_code
+            .map(|code| json!({ "code": code.as_str(), "retryable": code.retryable() }));
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": self.code, "message": self.message, "data": data },
+        })
+    }
+}
+
+fn usage_json(usage: Option<&TokenUsage>) -> Value {
+    usage.map_or(Value::Null, |u| {
+        json!({
+            "promptTokens": u.prompt_tokens,
+            "completionTokens": u.completion_tokens,
+            "totalTokens": u.total_tokens,
+        })
+    })
+}
+
+fn result_json(result: &GenerationResult) -> Value {
+    let tool_calls = result.tool_calls.as_ref().map(|calls| {
+        calls
+            .iter()
+            .map(|call| {
+                json!({
+                    "id": call.id,
+                    "name": call.function.name,
+                    "arguments": call.function.arguments,
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+    json!({
+        "text": result.text,
+        "object": result.object,
+        "toolCalls": tool_calls,
+        "finishReason": result.finish_reason,
+        "usage": usage_json(result.usage.as_ref()),
+    })
+}
+
+/// A stream event with the field names JS callbacks see.
+fn event_json(event: &StreamEvent) -> Value {
+    let mut fields = json!({
+        "type": event.kind,
+        "text": event.text,
+        "object": event.object,
+        "toolCallId": event.tool_call_id,
+        "toolName": event.tool_name,
+        "args": event.args,
+        "argsDelta": event.args_delta,
+        "result": event.result,
+        "message": event.message,
+        "code": event.code,
+        "retryable": event.retryable,
+        "finishReason": event.finish_reason,
+        "usage": usage_json(event.usage.as_ref()),
+    });
+    if let Some(fields) = fields.as_object_mut() {
+        fields.retain(|_, value| !value.is_null());
+    }
+    fields
+}
+
+fn parse_generation(params: &Value) -> Result<GenerationRequest, RpcError> {
+    let request = openai::parse_request(&params.to_string())
+        .map_err(|e| RpcError::invalid_params(e.reason))?;
+    GenerationRequest::from_messages(&request.messages, request.options)
+        .map_err(|e| RpcError::generation(ErrorCode::of(&e), e.reason))
+}
+
+fn generate(params: &Value) -> Result<Value, RpcError> {
+    let mut task = GenerateUnifiedTask::new(parse_generation(params)?);
+    match napi::Task::compute(&mut task) {
+        Ok(result) => Ok(result_json(&result)),
+        Err(error) => Err(RpcError::generation(
+            task.failure_code(&error),
+            error.reason,
+        )),
+    }
+}
+
+/// Forward the stream's events as notifications until it finishes. A
+/// client that goes away cancels the generation.
+fn stream(writer: &Writer, id: &Value, params: &Value) -> Result<Value, RpcError> {
+    let generation = parse_generation(params)?;
+    let (sender, events) = mpsc::channel();
+    let request_id = run_stream(generation, StreamSink::Channel(sender))
+        .map_err(|e| RpcError::generation(ErrorCode::of(&e), e.reason))?;
+    for event in events {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "stream.event",
+            "params": { "id": id, "event": event_json(&event) },
+        });
+        if !send(writer, &notification) {
+            unsafe { apple_ai_cancel_generation(request_id) };
+            break;
+        }
+        match event.kind.as_str() {
+            "finish" => {
+                return Ok(json!({
+                    "finishReason": event.finish_reason,
+                    "usage": usage_json(event.usage.as_ref()),
+                }))
+            }
+            "error" => {
+                let code = event
+                    .code
+                    .as_deref()
+                    .and_then(ErrorCode::parse)
+                    .unwrap_or(ErrorCode::GenerationFailed);
+                return Err(RpcError::generation(
+                    code,
+                    event.message.unwrap_or_default(),
+                ));
+            }
+            _ => {}
+        }
+    }
+    Err(RpcError::generation(
+        ErrorCode::Cancelled,
+        "The stream ended without finishing".to_string(),
+    ))
+}
+
+fn embed(params: &Value) -> Result<Value, RpcError> {
+    let texts = params
+        .get("texts")
+        .and_then(Value::as_array)
+        .ok_or_else(|| RpcError::invalid_params("params.texts must be an array of strings"))?;
+    let language = params.get("language").and_then(Value::as_str);
+    let model = embeddings::EmbeddingModel::parse(params.get("model").and_then(Value::as_str))
+        .map_err(|e| RpcError::invalid_params(e.message))?;
+    let embeddings = texts
+        .iter()
+        .map(|text| {
+            let text = text
+                .as_str()
+                .ok_or_else(|| RpcError::invalid_params("params.texts must be strings"))?;
+            embeddings::embedding(text, language, model)
+                .map_err(|e| RpcError::generation(e.code, e.message))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(json!({ "embeddings": embeddings }))
+}
+
+fn handle_message(writer: &Writer, line: &str) {
+    let message: Value = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(_) => {
+            send(
+                writer,
+                &json!({ "jsonrpc": "2.0", "id": null, "error": { "code": -32700, "message": "Parse error" } }),
+            );
+            return;
+        }
+    };
+    let (Some(id), Some(method)) = (
+        message.get("id"),
+        message.get("method").and_then(Value::as_str),
+    ) else {
+        // Notifications need no answer; there are none to act on
+        if message.get("method").is_none() {
+            send(
+                writer,
+                &json!({ "jsonrpc": "2.0", "id": null, "error": { "code": -32600, "message": "Invalid request" } }),
+            );
+        }
+        return;
+    };
+    let params = message.get("params").cloned().unwrap_or_else(|| json!({}));
+    let result = match method {
+        "generate" => generate(&params),
+        "stream" => stream(writer, id, &params),
+        "embed" => embed(&params),
+        "availability" => check_availability()
+            .map(|a| json!({ "available": a.available, "reason": a.reason }))
+            .map_err(|e| RpcError::generation(ErrorCode::of(&e), e.reason)),
+        "ping" => Ok(json!({})),
+        _ => Err(RpcError {
+            code: -32601,
+            message: format!("Method not found: {method}"),
+            error_code: None,
+        }),
+    };
+    let reply = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => error.to_json(id),
+    };
+    send(writer, &reply);
+}
+
+fn handle_connection(stream: UnixStream) {
+    let Ok(writer) = stream.try_clone() else {
+        return;
+    };
+    let writer: Writer = Arc::new(Mutex::new(writer));
+    let in_flight = Arc::new((Mutex::new(0usize), Condvar::new()));
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        {
+            let (count, finished) = &*in_flight;
+            let count = count.lock().unwrap();
+            let mut count = finished
+                .wait_while(count, |count| *count >= MAX_IN_FLIGHT)
+                .unwrap();
+            *count += 1;
+        }
+        let writer = writer.clone();
+        let in_flight = in_flight.clone();
+        std::thread::spawn(move || {
+            handle_message(&writer, &line);
+            let (count, finished) = &*in_flight;
+            *count.lock().unwrap() -= 1;
+            finished.notify_one();
+        });
+    }
+}
+
+/// Serve the model as JSON-RPC on a Unix domain socket at `path` and return
+/// `{ path, stop }`. A stale socket left at `path` is replaced; any other
+/// file there is an error.
+/// `stop()` stops accepting connections and removes the socket file;
+/// connections already open are served until their clients close them.
+#[napi(ts_return_type = "{ path: string; stop: () => void }")]
+pub fn start_socket_server(
+    env: Env,
+    path: String,
+    options: Option<SocketServerOptions>,
+) -> napi::Result<JsObject> {
+    let options = options.unwrap_or_default();
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                format!("{path} exists and is not a socket"),
+            ));
+        }
+        if UnixStream::connect(&path).is_ok() {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                format!("{path} is already in use by a running server"),
+            ));
+        }
+        std::fs::remove_file(&path)
+            .map_err(|e| napi::Error::from_reason(format!("Cannot replace {path}: {e}")))?;
+    }
+    let mode = options.permissions.unwrap_or(DEFAULT_PERMISSIONS) & 0o777;
+    // The socket is created with its final mode, so there is no moment
+    // when other users could connect. The umask is the process's, so it is
+    // only narrowed for the bind.
+    let previous = unsafe { libc::umask((!mode & 0o777) as libc::mode_t) };
+    let bound = UnixListener::bind(&path);
+    unsafe { libc::umask(previous) };
+    let listener =
+        bound.map_err(|e| napi::Error::from_reason(format!("Cannot listen on {path}: {e}")))?;
+    crate::ensure_initialized();
+
+    // A refused connection is closed unanswered; there is no request yet to reply to
+    let stopped = server::accept_loop(
+        move || listener.accept().map(|(c, _)| c),
+        drop,
+        handle_connection,
+    );
+
+    let socket_path = path.clone();
+    let stop = env.create_function_from_closure("stop", move |ctx| {
+        if !stopped.swap(true, Ordering::SeqCst) {
+            // Wake the accept loop so it sees the flag
+            let _ = UnixStream::connect(&socket_path);
+            let _ = std::fs::remove_file(&socket_path);
+        }
+        ctx.env.get_undefined()
+    })?;
+    let mut handle = env.create_object()?;
+    handle.set_named_property("path", env.create_string(&path)?)?;
+    handle.set_named_property("stop", stop)?;
+    Ok(handle)
+}
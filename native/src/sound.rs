@@ -0,0 +1,340 @@
+//! Sound classification with SoundAnalysis's built-in classifier: of audio
+//! files or buffers, and live from the microphone with windows streamed to a
+//! callback.
+
+use crate::{
+    apple_ai_classify_sound_file, apple_ai_start_sound_classification,
+    apple_ai_stop_sound_classification, coded_error, next_request_id, take_c_string, ErrorCode,
+};
+use napi::bindgen_prelude::{AsyncTask, Buffer, Either};
+use napi::threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{Env, JsFunction, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::ffi::{c_char, CString};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const DEFAULT_TOP_K: u32 = 3;
+const DEFAULT_MIN_CONFIDENCE: f64 = 0.3;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ClassifySoundOptions {
+    /// Most labels per window (default 3)
+    pub top_k: Option<u32>,
+    /// Lowest confidence of a label, from 0 to 1 (default 0.3); windows with
+    /// no label above it are left out
+    pub min_confidence: Option<f64>,
+    /// Length of a window in seconds, rounded to one the classifier supports
+    /// (default: the classifier's, about 1.5 seconds)
+    pub window_duration: Option<f64>,
+    /// How much consecutive windows overlap, from 0 up to 1 (default 0.5)
+    pub overlap: Option<f64>,
+}
+
+impl ClassifySoundOptions {
+    /// The options as the JSON Swift takes.
+    fn to_c_json(&self) -> napi::Result<CString> {
+        let invalid = |message: &str| Err(napi::Error::new(Status::InvalidArg, message));
+        let min_confidence = self.min_confidence.unwrap_or(DEFAULT_MIN_CONFIDENCE);
+        if !(0.0..=1.0).contains(&min_confidence) {
+            return invalid("minConfidence must be between 0 and 1");
+        }
+        if self.window_duration.is_some_and(|d| d <= 0.0) {
+            return invalid("windowDuration must be greater than 0");
+        }
+        if self.overlap.is_some_and(|o| !(0.0..1.0).contains(&o)) {
+            return invalid("overlap must be at least 0 and less than 1");
+        }
+        CString::new(
+            json!({
+                "topK": self.top_k.unwrap_or(DEFAULT_TOP_K).max(1),
+                "minConfidence": min_confidence,
+                "windowDuration": self.window_duration,
+                "overlap": self.overlap,
+            })
+            .to_string(),
+        )
+        .map_err(|_| napi::Error::new(Status::InvalidArg, "Options contained null byte"))
+    }
+}
+
+#[napi(object)]
+pub struct SoundClassification {
+    /// e.g. `speech`, `dog_bark` or `applause`
+    pub identifier: String,
+    /// From 0 to 1
+    pub confidence: f64,
+}
+
+/// The sounds heard in a stretch of the audio.
+#[napi(object)]
+pub struct SoundWindow {
+    /// Start in seconds from the beginning of the audio
+    pub start: f64,
+    /// Length in seconds
+    pub duration: f64,
+    /// Most confident first
+    pub classifications: Vec<SoundClassification>,
+}
+
+/// Window from Swift's `{start, duration, classifications}` JSON.
+fn sound_window(window: &Value) -> SoundWindow {
+    SoundWindow {
+        start: window["start"].as_f64().unwrap_or(0.0),
+        duration: window["duration"].as_f64().unwrap_or(0.0),
+        classifications: window["classifications"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|c| SoundClassification {
+                identifier: c["identifier"].as_str().unwrap_or_default().to_string(),
+                confidence: c["confidence"].as_f64().unwrap_or(0.0),
+            })
+            .collect(),
+    }
+}
+
+pub enum SoundSource {
+    Path(CString),
+    Buffer(Buffer),
+}
+
+pub struct ClassifySoundTask {
+    source: SoundSource,
+    options_json: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl ClassifySoundTask {
+    fn classify(&mut self, path: &CString) -> napi::Result<Vec<SoundWindow>> {
+        let ptr =
+            unsafe { apple_ai_classify_sound_file(path.as_ptr(), self.options_json.as_ptr()) };
+        if ptr.is_null() {
+            return Err(napi::Error::from_reason("Sound classification failed"));
+        }
+        let result: Value = serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+            napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+        })?;
+        if let Some(message) = result["error"].as_str() {
+            self.error_code = result["code"].as_str().and_then(ErrorCode::parse);
+            return Err(napi::Error::from_reason(message.to_string()));
+        }
+        Ok(result["windows"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(sound_window)
+            .collect())
+    }
+}
+
+impl napi::Task for ClassifySoundTask {
+    type Output = Vec<SoundWindow>;
+    type JsValue = Vec<SoundWindow>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        match &self.source {
+            SoundSource::Path(path) => {
+                let path = path.clone();
+                self.classify(&path)
+            }
+            SoundSource::Buffer(buffer) => {
+                // SoundAnalysis reads files, so the bytes go through one
+                let file: PathBuf = std::env::temp_dir().join(format!(
+                    "apple-ai-sound-{}-{}",
+                    std::process::id(),
+                    next_request_id()
+                ));
+                std::fs::write(&file, buffer.as_ref()).map_err(|e| {
+                    napi::Error::from_reason(format!("Cannot write audio to a temporary file: {e}"))
+                })?;
+                let path = CString::new(file.to_string_lossy().into_owned())
+                    .map_err(|_| napi::Error::from_reason("Temporary path contained null byte"));
+                let result = path.and_then(|path| self.classify(&path));
+                let _ = std::fs::remove_file(&file);
+                result
+            }
+        }
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Classify the sounds in audio, a file path or a `Buffer` of encoded audio
+/// in any format AVFoundation reads, with the built-in classifier of several
+/// hundred sounds. Resolves with the windows in time order; rejects with code
+/// `InvalidArg` for audio that can't be read.
+#[napi(ts_return_type = "Promise<SoundWindow[]>")]
+pub fn classify_sound(
+    audio: Either<String, Buffer>,
+    options: Option<ClassifySoundOptions>,
+) -> napi::Result<AsyncTask<ClassifySoundTask>> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: String| napi::Error::new(Status::InvalidArg, message);
+    let source = match audio {
+        Either::A(path) => {
+            if !std::path::Path::new(&path).is_file() {
+                return Err(invalid(format!("{path:?} is not a readable file")));
+            }
+            SoundSource::Path(
+                CString::new(path).map_err(|_| invalid("Path contained null byte".to_string()))?,
+            )
+        }
+        Either::B(buffer) => {
+            if buffer.is_empty() {
+                return Err(invalid("Audio buffer is empty".to_string()));
+            }
+            SoundSource::Buffer(buffer)
+        }
+    };
+    Ok(AsyncTask::new(ClassifySoundTask {
+        source,
+        options_json: options.to_c_json()?,
+        error_code: None,
+    }))
+}
+
+/// An event of a microphone sound classification.
+#[napi(object)]
+pub struct SoundClassificationEvent {
+    /// `window` for each stretch of audio with a sound above
+    /// `minConfidence`; `error` when classification fails, followed by `end`
+    /// when the session is over
+    #[napi(js_name = "type", ts_type = "'window' | 'error' | 'end'")]
+    pub kind: String,
+    /// The sounds heard, for `window` events, timed from when listening
+    /// started
+    pub window: Option<SoundWindow>,
+    /// Error message for `error` events
+    pub message: Option<String>,
+    /// Error code for `error` events; see `ErrorCode`
+    pub code: Option<String>,
+}
+
+type SoundClassificationFn =
+    ThreadsafeFunction<SoundClassificationEvent, ErrorStrategy::CalleeHandled>;
+
+/// Callback of the running microphone sound classification. Swift runs one
+/// at a time, and the callback is dropped after its `end` event.
+static SOUND_CLASSIFICATION: Mutex<Option<SoundClassificationFn>> = Mutex::new(None);
+
+extern "C" fn sound_classification_event_cb(ptr: *mut c_char) {
+    let Ok(event) = serde_json::from_str::<Value>(&take_c_string(ptr)) else {
+        return;
+    };
+    let kind = event["type"].as_str().unwrap_or("error").to_string();
+    let mut slot = SOUND_CLASSIFICATION.lock().unwrap();
+    let Some(tsfn) = slot.as_ref() else {
+        return;
+    };
+    let event = SoundClassificationEvent {
+        window: (kind == "window").then(|| sound_window(&event)),
+        message: event["error"].as_str().map(str::to_string),
+        code: event["code"].as_str().map(str::to_string),
+        kind,
+    };
+    let end = event.kind == "end";
+    let _ = tsfn.call(Ok(event), ThreadsafeFunctionCallMode::NonBlocking);
+    if end {
+        *slot = None;
+    }
+}
+
+pub struct StartSoundClassificationTask {
+    options_json: CString,
+    callback: Option<SoundClassificationFn>,
+    error_code: Option<ErrorCode>,
+}
+
+impl StartSoundClassificationTask {
+    fn fail(&mut self, code: ErrorCode, message: String) -> napi::Error {
+        self.error_code = Some(code);
+        napi::Error::from_reason(message)
+    }
+}
+
+impl napi::Task for StartSoundClassificationTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        {
+            let mut slot = SOUND_CLASSIFICATION.lock().unwrap();
+            if slot.is_some() {
+                return Err(self.fail(
+                    ErrorCode::ConcurrentRequests,
+                    "A sound classification is already running".to_string(),
+                ));
+            }
+            *slot = self.callback.take();
+        }
+        let ptr = unsafe {
+            apple_ai_start_sound_classification(
+                self.options_json.as_ptr(),
+                sound_classification_event_cb,
+            )
+        };
+        let result: Value = serde_json::from_str(&take_c_string(ptr)).unwrap_or_default();
+        if let Some(message) = result["error"].as_str() {
+            // A session that never started sends no `end`
+            SOUND_CLASSIFICATION.lock().unwrap().take();
+            let code = result["code"]
+                .as_str()
+                .and_then(ErrorCode::parse)
+                .unwrap_or(ErrorCode::GenerationFailed);
+            return Err(self.fail(code, message.to_string()));
+        }
+        Ok(())
+    }
+
+    fn resolve(&mut self, _env: Env, _output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(())
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Classify sounds from the microphone until `stop_sound_classification`,
+/// sending a `window` event to `callback` for each stretch of audio with a
+/// recognized sound. Resolves once the microphone is listening; rejects with
+/// code `PermissionDenied` when the microphone isn't allowed, and
+/// `ConcurrentRequests` while another sound classification runs. It can run
+/// alongside a transcription.
+#[napi(ts_return_type = "Promise<void>")]
+pub fn start_sound_classification(
+    options: Option<ClassifySoundOptions>,
+    #[napi(ts_arg_type = "(err: Error | null, event: SoundClassificationEvent) => void")]
+    callback: JsFunction,
+) -> napi::Result<AsyncTask<StartSoundClassificationTask>> {
+    let options_json = options.unwrap_or_default().to_c_json()?;
+    let callback: SoundClassificationFn = callback
+        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<SoundClassificationEvent>| {
+            Ok(vec![ctx.value])
+        })?;
+    Ok(AsyncTask::new(StartSoundClassificationTask {
+        options_json,
+        callback: Some(callback),
+        error_code: None,
+    }))
+}
+
+/// Stop the microphone sound classification. Windows of the audio heard so
+/// far are still sent, then the `end` event. Returns whether one was running.
+#[napi]
+pub fn stop_sound_classification() -> bool {
+    unsafe { apple_ai_stop_sound_classification() }
+}
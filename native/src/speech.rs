@@ -0,0 +1,298 @@
+//! Speech recognition with the Speech framework, always on the device: of
+//! audio files, and live from the microphone with results streamed to a
+//! callback.
+
+use crate::{
+    apple_ai_start_transcription, apple_ai_stop_transcription, apple_ai_transcribe_file,
+    coded_error, take_c_string, ErrorCode,
+};
+use napi::bindgen_prelude::AsyncTask;
+use napi::threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{Env, JsFunction, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::ffi::{c_char, CString};
+use std::sync::Mutex;
+
+const DEFAULT_PAUSE_MS: u32 = 1_200;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct TranscribeOptions {
+    /// Locale of the speech, e.g. `en-US` (default: the system's)
+    pub locale: Option<String>,
+    /// Words and phrases the audio is likely to contain, such as names and
+    /// jargon, to recognize them more reliably
+    pub contextual_strings: Option<Vec<String>>,
+    /// Punctuate the transcript (default `true`)
+    pub punctuation: Option<bool>,
+}
+
+#[napi(object)]
+pub struct TranscriptSegment {
+    pub text: String,
+    /// Start in seconds from the beginning of the audio
+    pub start: f64,
+    /// Length in seconds
+    pub duration: f64,
+    /// From 0 to 1
+    pub confidence: f64,
+}
+
+#[napi(object)]
+pub struct Transcript {
+    pub text: String,
+    /// Locale the speech was recognized in
+    pub locale: String,
+    /// The transcript word by word, with timings
+    pub segments: Vec<TranscriptSegment>,
+}
+
+/// Transcript from Swift's `{text, locale, segments}` JSON.
+pub fn transcript(result: &Value) -> Transcript {
+    Transcript {
+        text: result["text"].as_str().unwrap_or_default().to_string(),
+        locale: result["locale"].as_str().unwrap_or_default().to_string(),
+        segments: result["segments"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|segment| TranscriptSegment {
+                text: segment["text"].as_str().unwrap_or_default().to_string(),
+                start: segment["start"].as_f64().unwrap_or(0.0),
+                duration: segment["duration"].as_f64().unwrap_or(0.0),
+                confidence: segment["confidence"].as_f64().unwrap_or(0.0),
+            })
+            .collect(),
+    }
+}
+
+fn to_c_json(json: Value) -> napi::Result<CString> {
+    CString::new(json.to_string())
+        .map_err(|_| napi::Error::new(Status::InvalidArg, "Options contained null byte"))
+}
+
+/// Recognition options as the JSON Swift takes.
+fn options_json(options: &TranscribeOptions) -> napi::Result<CString> {
+    to_c_json(json!({
+        "locale": options.locale,
+        "contextualStrings": options.contextual_strings,
+        "punctuation": options.punctuation.unwrap_or(true),
+    }))
+}
+
+pub struct TranscribeFileTask {
+    path: CString,
+    options_json: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for TranscribeFileTask {
+    type Output = Transcript;
+    type JsValue = Transcript;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let ptr =
+            unsafe { apple_ai_transcribe_file(self.path.as_ptr(), self.options_json.as_ptr()) };
+        if ptr.is_null() {
+            return Err(napi::Error::from_reason("Transcription failed"));
+        }
+        let result: Value = serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+            napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+        })?;
+        if let Some(message) = result["error"].as_str() {
+            self.error_code = result["code"].as_str().and_then(ErrorCode::parse);
+            return Err(napi::Error::from_reason(message.to_string()));
+        }
+        Ok(transcript(&result))
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Transcribe the audio file at `path` (any format AVFoundation reads, such
+/// as WAV, M4A, MP3 or CAF) on the device. Rejects with code
+/// `PermissionDenied` when speech recognition isn't allowed,
+/// `UnsupportedLanguage` for a locale the recognizer doesn't know, and
+/// `AssetsUnavailable` when its on-device model isn't installed.
+#[napi(ts_return_type = "Promise<Transcript>")]
+pub fn transcribe_file(
+    path: String,
+    options: Option<TranscribeOptions>,
+) -> napi::Result<AsyncTask<TranscribeFileTask>> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: String| napi::Error::new(Status::InvalidArg, message);
+    if !std::path::Path::new(&path).is_file() {
+        return Err(invalid(format!("{path:?} is not a readable file")));
+    }
+    Ok(AsyncTask::new(TranscribeFileTask {
+        path: CString::new(path).map_err(|_| invalid("Path contained null byte".to_string()))?,
+        options_json: options_json(&options)?,
+        error_code: None,
+    }))
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct StartTranscriptionOptions {
+    /// Locale of the speech, e.g. `en-US` (default: the system's)
+    pub locale: Option<String>,
+    /// Words and phrases the speech is likely to contain, such as names and
+    /// jargon, to recognize them more reliably
+    pub contextual_strings: Option<Vec<String>>,
+    /// Punctuate the transcript (default `true`)
+    pub punctuation: Option<bool>,
+    /// Silence in milliseconds after which an utterance is final and the
+    /// next one starts (default 1200)
+    pub pause_ms: Option<u32>,
+}
+
+/// An event of a microphone transcription.
+#[napi(object)]
+pub struct TranscriptionEvent {
+    /// `partial` as an utterance is recognized, each replacing the last;
+    /// `final` once it's settled; `error` when recognition fails, followed
+    /// by `end` when the session is over
+    #[napi(js_name = "type", ts_type = "'partial' | 'final' | 'error' | 'end'")]
+    pub kind: String,
+    /// The utterance so far for `partial` events, and whole for `final`
+    pub transcript: Option<Transcript>,
+    /// Error message for `error` events
+    pub message: Option<String>,
+    /// Error code for `error` events; see `ErrorCode`
+    pub code: Option<String>,
+}
+
+type TranscriptionFn = ThreadsafeFunction<TranscriptionEvent, ErrorStrategy::CalleeHandled>;
+
+/// Callback of the running microphone transcription. Swift runs one at a
+/// time, and the callback is dropped after its `end` event.
+static TRANSCRIPTION: Mutex<Option<TranscriptionFn>> = Mutex::new(None);
+
+extern "C" fn transcription_event_cb(ptr: *mut c_char) {
+    let Ok(event) = serde_json::from_str::<Value>(&take_c_string(ptr)) else {
+        return;
+    };
+    let kind = event["type"].as_str().unwrap_or("error").to_string();
+    let mut slot = TRANSCRIPTION.lock().unwrap();
+    let Some(tsfn) = slot.as_ref() else {
+        return;
+    };
+    let event = TranscriptionEvent {
+        transcript: matches!(kind.as_str(), "partial" | "final").then(|| transcript(&event)),
+        message: event["error"].as_str().map(str::to_string),
+        code: event["code"].as_str().map(str::to_string),
+        kind,
+    };
+    let end = event.kind == "end";
+    let _ = tsfn.call(Ok(event), ThreadsafeFunctionCallMode::NonBlocking);
+    if end {
+        *slot = None;
+    }
+}
+
+pub struct StartTranscriptionTask {
+    options_json: CString,
+    callback: Option<TranscriptionFn>,
+    error_code: Option<ErrorCode>,
+}
+
+impl StartTranscriptionTask {
+    fn fail(&mut self, code: ErrorCode, message: String) -> napi::Error {
+        self.error_code = Some(code);
+        napi::Error::from_reason(message)
+    }
+}
+
+impl napi::Task for StartTranscriptionTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        {
+            let mut slot = TRANSCRIPTION.lock().unwrap();
+            if slot.is_some() {
+                return Err(self.fail(
+                    ErrorCode::ConcurrentRequests,
+                    "A transcription is already running".to_string(),
+                ));
+            }
+            *slot = self.callback.take();
+        }
+        let ptr = unsafe {
+            apple_ai_start_transcription(self.options_json.as_ptr(), transcription_event_cb)
+        };
+        let result: Value = serde_json::from_str(&take_c_string(ptr)).unwrap_or_default();
+        if let Some(message) = result["error"].as_str() {
+            // A session that never started sends no `end`
+            TRANSCRIPTION.lock().unwrap().take();
+            let code = result["code"]
+                .as_str()
+                .and_then(ErrorCode::parse)
+                .unwrap_or(ErrorCode::GenerationFailed);
+            return Err(self.fail(code, message.to_string()));
+        }
+        Ok(())
+    }
+
+    fn resolve(&mut self, _env: Env, _output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(())
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Transcribe speech from the microphone on the device until
+/// `stop_transcription`, sending `partial` and `final` results per utterance
+/// to `callback`. Resolves once the microphone is listening; rejects with
+/// code `PermissionDenied` when the microphone or speech recognition isn't
+/// allowed, and `ConcurrentRequests` while another transcription runs.
+#[napi(ts_return_type = "Promise<void>")]
+pub fn start_transcription(
+    options: Option<StartTranscriptionOptions>,
+    #[napi(ts_arg_type = "(err: Error | null, event: TranscriptionEvent) => void")]
+    callback: JsFunction,
+) -> napi::Result<AsyncTask<StartTranscriptionTask>> {
+    let options = options.unwrap_or_default();
+    let pause_ms = options.pause_ms.unwrap_or(DEFAULT_PAUSE_MS);
+    if pause_ms == 0 {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "pauseMs must be at least 1",
+        ));
+    }
+    let callback: TranscriptionFn = callback
+        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<TranscriptionEvent>| {
+            Ok(vec![ctx.value])
+        })?;
+    Ok(AsyncTask::new(StartTranscriptionTask {
+        options_json: to_c_json(json!({
+            "locale": options.locale,
+            "contextualStrings": options.contextual_strings,
+            "punctuation": options.punctuation.unwrap_or(true),
+            "pauseMs": pause_ms,
+        }))?,
+        callback: Some(callback),
+        error_code: None,
+    }))
+}
+
+/// Stop the microphone transcription. The utterance in progress still ends
+/// with its `final` event, then the `end` event follows. Returns whether a
+/// transcription was running.
+#[napi]
+pub fn stop_transcription() -> bool {
+    unsafe { apple_ai_stop_transcription() }
+}
@@ -0,0 +1,363 @@
+//! Speech synthesis with `AVSpeechSynthesizer`, on the device. Audio comes
+//! back from Swift as WAV data, or streamed as 16-bit PCM while it's
+//! synthesized.
+
+use crate::{
+    apple_ai_list_voices, apple_ai_stop_speech, apple_ai_stream_speech, apple_ai_synthesize_speech,
+    coded_error, next_request_id, take_c_string, ErrorCode,
+};
+use napi::bindgen_prelude::{AsyncTask, Buffer};
+use napi::threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{Env, JsFunction, JsObject, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::ffi::{c_char, c_void, CString};
+
+fn native_json(ptr: *mut std::ffi::c_char) -> napi::Result<Value> {
+    if ptr.is_null() {
+        return Err(napi::Error::from_reason("Speech synthesis failed"));
+    }
+    serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+        napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+    })
+}
+
+fn to_c_json(json: Value) -> napi::Result<CString> {
+    CString::new(json.to_string())
+        .map_err(|_| napi::Error::new(Status::InvalidArg, "Options contained null byte"))
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ListVoicesOptions {
+    /// Only voices for this language, e.g. `en` or `en-GB`
+    pub language: Option<String>,
+    /// Ask the user for access to their personal voices if they haven't
+    /// been asked yet (default `false`)
+    pub request_personal_voice: Option<bool>,
+}
+
+#[napi(object)]
+pub struct Voice {
+    /// Pass as `voice` to pick this voice
+    pub identifier: String,
+    pub name: String,
+    /// BCP 47 code, e.g. `en-US`
+    pub language: String,
+    /// `enhanced` and `premium` voices sound more natural, and are
+    /// downloaded in System Settings > Accessibility > Spoken Content
+    #[napi(ts_type = "'default' | 'enhanced' | 'premium'")]
+    pub quality: String,
+    #[napi(ts_type = "'male' | 'female'")]
+    pub gender: Option<String>,
+    /// A personal voice the user made of their own voice
+    pub personal: bool,
+}
+
+#[napi(object)]
+pub struct VoiceList {
+    pub voices: Vec<Voice>,
+    /// Whether personal voices can be listed and used; they're only among
+    /// `voices` once `authorized`
+    #[napi(ts_type = "'authorized' | 'denied' | 'notDetermined' | 'unsupported'")]
+    pub personal_voice: String,
+}
+
+pub struct ListVoicesTask {
+    options_json: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for ListVoicesTask {
+    type Output = VoiceList;
+    type JsValue = VoiceList;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let result = native_json(unsafe { apple_ai_list_voices(self.options_json.as_ptr()) })?;
+        if let Some(message) = result["error"].as_str() {
+            self.error_code = result["code"].as_str().and_then(ErrorCode::parse);
+            return Err(napi::Error::from_reason(message.to_string()));
+        }
+        let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+        Ok(VoiceList {
+            voices: result["voices"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|voice| Voice {
+                    identifier: text(&voice["identifier"]),
+                    name: text(&voice["name"]),
+                    language: text(&voice["language"]),
+                    quality: text(&voice["quality"]),
+                    gender: voice["gender"].as_str().map(str::to_string),
+                    personal: voice["personal"].as_bool().unwrap_or(false),
+                })
+                .collect(),
+            personal_voice: text(&result["personalVoice"]),
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// The voices speech synthesis can use on this device, for a voice picker.
+/// Personal voices are included once the user has allowed access to them.
+#[napi(ts_return_type = "Promise<VoiceList>")]
+pub fn list_voices(options: Option<ListVoicesOptions>) -> napi::Result<AsyncTask<ListVoicesTask>> {
+    let options = options.unwrap_or_default();
+    Ok(AsyncTask::new(ListVoicesTask {
+        options_json: to_c_json(json!({
+            "language": options.language,
+            "requestPersonalVoice": options.request_personal_voice,
+        }))?,
+        error_code: None,
+    }))
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct SpeechOptions {
+    /// Identifier of the voice from `listVoices` (default: the system voice
+    /// for `language`)
+    pub voice: Option<String>,
+    /// Language to speak in when no `voice` is given, e.g. `fr-FR` (default:
+    /// the system's)
+    pub language: Option<String>,
+    /// Speaking rate from 0 (slowest) to 1 (fastest), default 0.5
+    pub rate: Option<f64>,
+    /// Pitch multiplier from 0.5 to 2 (default 1)
+    pub pitch: Option<f64>,
+    /// Volume from 0 to 1 (default 1)
+    pub volume: Option<f64>,
+}
+
+/// Speech options as the JSON Swift takes, once checked.
+pub fn speech_options_json(options: &SpeechOptions) -> napi::Result<CString> {
+    let invalid = |message: &str| napi::Error::new(Status::InvalidArg, message.to_string());
+    if options.rate.is_some_and(|r| !(0.0..=1.0).contains(&r)) {
+        return Err(invalid("rate must be between 0 and 1"));
+    }
+    if options.pitch.is_some_and(|p| !(0.5..=2.0).contains(&p)) {
+        return Err(invalid("pitch must be between 0.5 and 2"));
+    }
+    if options.volume.is_some_and(|v| !(0.0..=1.0).contains(&v)) {
+        return Err(invalid("volume must be between 0 and 1"));
+    }
+    to_c_json(json!({
+        "voice": options.voice,
+        "language": options.language,
+        "rate": options.rate,
+        "pitch": options.pitch,
+        "volume": options.volume,
+    }))
+}
+
+pub struct SynthesizeSpeechTask {
+    text: CString,
+    options_json: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for SynthesizeSpeechTask {
+    type Output = Vec<u8>;
+    type JsValue = Buffer;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let mut audio = std::ptr::null_mut::<u8>();
+        let mut length = 0usize;
+        let result = native_json(unsafe {
+            apple_ai_synthesize_speech(
+                self.text.as_ptr(),
+                self.options_json.as_ptr(),
+                &mut audio,
+                &mut length,
+            )
+        })?;
+        if let Some(message) = result["error"].as_str() {
+            self.error_code = result["code"].as_str().and_then(ErrorCode::parse);
+            return Err(napi::Error::from_reason(message.to_string()));
+        }
+        if audio.is_null() {
+            return Err(napi::Error::from_reason(
+                "Speech synthesis produced no audio",
+            ));
+        }
+        Ok(unsafe {
+            let wav = std::slice::from_raw_parts(audio, length).to_vec();
+            libc::free(audio.cast());
+            wav
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(Buffer::from(output))
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Speak `text` on the device. Resolves with the audio as WAV data (16-bit
+/// PCM); rejects with code `InvalidArg` for an unknown `voice` and
+/// `UnsupportedLanguage` when no voice speaks `language`.
+#[napi(ts_return_type = "Promise<Buffer>")]
+pub fn synthesize_speech(
+    text: String,
+    options: Option<SpeechOptions>,
+) -> napi::Result<AsyncTask<SynthesizeSpeechTask>> {
+    let options = options.unwrap_or_default();
+    if text.trim().is_empty() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "text must not be empty",
+        ));
+    }
+    Ok(AsyncTask::new(SynthesizeSpeechTask {
+        text: CString::new(text)
+            .map_err(|_| napi::Error::new(Status::InvalidArg, "text contained null byte"))?,
+        options_json: speech_options_json(&options)?,
+        error_code: None,
+    }))
+}
+
+/// An event of a speech stream.
+#[napi(object)]
+#[derive(Default)]
+pub struct SpeechEvent {
+    /// `audio` for each piece of audio as it's synthesized; `word` just
+    /// before the audio a word starts in; `end` once all is spoken or the
+    /// stream is stopped, or `error` instead
+    #[napi(js_name = "type", ts_type = "'audio' | 'word' | 'end' | 'error'")]
+    pub kind: String,
+    /// Interleaved 16-bit little-endian PCM samples, for `audio` events
+    pub audio: Option<Buffer>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    /// Where the word starts in the text, as a string index, for `word`
+    /// events
+    pub start: Option<u32>,
+    /// Length of the word in string indices
+    pub length: Option<u32>,
+    /// Seconds into the audio where the word starts
+    pub time: Option<f64>,
+    /// Whether the stream was stopped before the end, for the `end` event
+    pub stopped: Option<bool>,
+    /// Error message for `error` events
+    pub message: Option<String>,
+    /// Error code for `error` events; see `ErrorCode`
+    pub code: Option<String>,
+}
+
+type SpeechEventFn = ThreadsafeFunction<SpeechEvent, ErrorStrategy::CalleeHandled>;
+
+/// Swift's event callback for speech streams; `context` is the stream's
+/// `SpeechEventFn`.
+extern "C" fn speech_event_cb(
+    context: *mut c_void,
+    json: *mut c_char,
+    audio: *const u8,
+    length: usize,
+) {
+    let callback = unsafe { &*(context as *const SpeechEventFn) };
+    let Ok(event) = serde_json::from_str::<Value>(&take_c_string(json)) else {
+        return;
+    };
+    let number = |key: &str| event[key].as_u64().map(|n| n as u32);
+    let event = SpeechEvent {
+        kind: event["type"].as_str().unwrap_or_default().to_string(),
+        audio: (!audio.is_null())
+            .then(|| Buffer::from(unsafe { std::slice::from_raw_parts(audio, length) }.to_vec())),
+        sample_rate: number("sampleRate"),
+        channels: number("channels"),
+        start: number("start"),
+        length: number("length"),
+        time: event["time"].as_f64(),
+        ..Default::default()
+    };
+    callback.call(Ok(event), ThreadsafeFunctionCallMode::NonBlocking);
+}
+
+/// The event that ends a speech stream, from Swift's result.
+fn end_event(result: napi::Result<Value>) -> SpeechEvent {
+    let error = |message: String, code: Option<&str>| SpeechEvent {
+        kind: "error".to_string(),
+        message: Some(message),
+        code: Some(
+            code.unwrap_or(ErrorCode::GenerationFailed.as_str())
+                .to_string(),
+        ),
+        ..Default::default()
+    };
+    match result {
+        Ok(result) => match result["error"].as_str() {
+            Some(message) => error(message.to_string(), result["code"].as_str()),
+            None => SpeechEvent {
+                kind: "end".to_string(),
+                stopped: result["stopped"].as_bool(),
+                ..Default::default()
+            },
+        },
+        Err(e) => error(e.reason, None),
+    }
+}
+
+/// Speak `text` on the device, sending the audio to `callback` as it's
+/// synthesized, with `word` events marking where each word starts, so
+/// playback can begin before a long text is all synthesized. Returns a
+/// handle whose `stop()` ends the stream early.
+#[napi(ts_return_type = "{ stop: () => boolean }")]
+pub fn stream_speech(
+    env: Env,
+    text: String,
+    options: Option<SpeechOptions>,
+    #[napi(ts_arg_type = "(err: Error | null, event: SpeechEvent) => void")] callback: JsFunction,
+) -> napi::Result<JsObject> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: &str| napi::Error::new(Status::InvalidArg, message.to_string());
+    if text.trim().is_empty() {
+        return Err(invalid("text must not be empty"));
+    }
+    let text = CString::new(text).map_err(|_| invalid("text contained null byte"))?;
+    let options_json = speech_options_json(&options)?;
+    let callback: SpeechEventFn = callback
+        .create_threadsafe_function(0, |ctx: ThreadSafeCallContext<SpeechEvent>| {
+            Ok(vec![ctx.value])
+        })?;
+    let id = next_request_id();
+
+    std::thread::spawn(move || {
+        // Swift makes every call to `speech_event_cb` before it returns
+        let context = &callback as *const SpeechEventFn as *mut c_void;
+        let ptr = unsafe {
+            apple_ai_stream_speech(
+                id,
+                text.as_ptr(),
+                options_json.as_ptr(),
+                context,
+                speech_event_cb,
+            )
+        };
+        callback.call(
+            Ok(end_event(native_json(ptr))),
+            ThreadsafeFunctionCallMode::NonBlocking,
+        );
+    });
+
+    let stop = env.create_function_from_closure("stop", move |ctx| {
+        ctx.env.get_boolean(unsafe { apple_ai_stop_speech(id) })
+    })?;
+    let mut handle = env.create_object()?;
+    handle.set_named_property("stop", stop)?;
+    Ok(handle)
+}
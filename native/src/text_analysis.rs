@@ -0,0 +1,522 @@
+//! Text analysis with NaturalLanguage taggers: cheap, on-device answers to
+//! questions that don't need the language model, like polarity, the names
+//! in a text or its parts of speech.
+
+use crate::chunking::utf16_offsets;
+use crate::tokenization::trimmed;
+use crate::{
+    apple_ai_create_gazetteer, apple_ai_named_entities, apple_ai_remove_gazetteer,
+    apple_ai_sentiment, apple_ai_tag_text, coded_error, take_c_string, ErrorCode,
+};
+use napi::bindgen_prelude::AsyncTask;
+use napi::{Env, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::ops::Range;
+use std::sync::{Mutex, OnceLock};
+
+/// Swift's JSON result, or the error it reports with its code.
+fn native_json(ptr: *mut std::ffi::c_char) -> std::result::Result<Value, (ErrorCode, String)> {
+    if ptr.is_null() {
+        return Err((
+            ErrorCode::GenerationFailed,
+            "Text analysis failed".to_string(),
+        ));
+    }
+    let result: Value = serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+        (
+            ErrorCode::GenerationFailed,
+            format!("Invalid JSON returned from native layer: {e}"),
+        )
+    })?;
+    if let Some(message) = result["error"].as_str() {
+        let code = result["code"]
+            .as_str()
+            .and_then(ErrorCode::parse)
+            .unwrap_or(ErrorCode::GenerationFailed);
+        return Err((code, message.to_string()));
+    }
+    Ok(result)
+}
+
+fn c_text(text: &str) -> napi::Result<CString> {
+    CString::new(text).map_err(|_| napi::Error::new(Status::InvalidArg, "Text contained null byte"))
+}
+
+/// The `[{"start", "end", ...}]` units Swift found, as trimmed byte ranges of
+/// `text` with the rest of each unit.
+fn spans<'a>(text: &str, result: &'a Value) -> Vec<(Range<usize>, &'a Value)> {
+    result
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|unit| {
+            let range = trimmed(
+                text,
+                unit["start"].as_u64()? as usize,
+                unit["end"].as_u64()? as usize,
+            )?;
+            Some((range, unit))
+        })
+        .collect()
+}
+
+fn options_json(options: Value) -> napi::Result<CString> {
+    CString::new(options.to_string())
+        .map_err(|_| napi::Error::new(Status::InvalidArg, "Options contained null byte"))
+}
+
+const ENTITY_TYPES: [&str; 3] = ["person", "place", "organization"];
+
+/// Labels of the registered gazetteers, by name.
+fn gazetteers() -> &'static Mutex<HashMap<String, Vec<String>>> {
+    static GAZETTEERS: OnceLock<Mutex<HashMap<String, Vec<String>>>> = OnceLock::new();
+    GAZETTEERS.get_or_init(Default::default)
+}
+
+/// Labels of the named gazetteers, or an error naming one that isn't
+/// registered.
+fn gazetteer_labels(names: &[String]) -> napi::Result<Vec<String>> {
+    let registry = gazetteers().lock().unwrap();
+    let mut labels = Vec::new();
+    for name in names {
+        let Some(found) = registry.get(name) else {
+            return Err(napi::Error::new(
+                Status::InvalidArg,
+                format!("No gazetteer named '{name}'; create it with createGazetteer first"),
+            ));
+        };
+        labels.extend(found.iter().cloned());
+    }
+    Ok(labels)
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct CreateGazetteerOptions {
+    /// Language the terms are in (default: any)
+    pub language: Option<String>,
+}
+
+#[napi(object)]
+pub struct GazetteerInfo {
+    pub name: String,
+    pub labels: Vec<String>,
+    /// Terms across all labels
+    pub terms: u32,
+}
+
+pub struct CreateGazetteerTask {
+    name: String,
+    entries: HashMap<String, Vec<String>>,
+    language: Option<String>,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for CreateGazetteerTask {
+    type Output = GazetteerInfo;
+    type JsValue = GazetteerInfo;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let c_name = CString::new(self.name.as_str())
+            .map_err(|_| napi::Error::new(Status::InvalidArg, "Name contained null byte"))?;
+        let c_entries = options_json(json!(self.entries))?;
+        let c_language = self
+            .language
+            .as_deref()
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| napi::Error::new(Status::InvalidArg, "Language contained null byte"))?;
+        let ptr = unsafe {
+            apple_ai_create_gazetteer(
+                c_name.as_ptr(),
+                c_entries.as_ptr(),
+                c_language.as_ref().map_or(std::ptr::null(), |l| l.as_ptr()),
+            )
+        };
+        native_json(ptr).map_err(|(code, message)| {
+            self.error_code = Some(code);
+            napi::Error::from_reason(message)
+        })?;
+        let mut labels: Vec<String> = self.entries.keys().cloned().collect();
+        labels.sort();
+        gazetteers()
+            .lock()
+            .unwrap()
+            .insert(self.name.clone(), labels.clone());
+        Ok(GazetteerInfo {
+            name: self.name.clone(),
+            labels,
+            terms: self.entries.values().map(Vec::len).sum::<usize>() as u32,
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Compile a gazetteer of domain terms, `{ label: [term, ...] }`, and
+/// register it as `name` for `extractEntities` and `tagText`, which tag the
+/// terms with their label. Replaces a gazetteer of the same name.
+#[napi(ts_return_type = "Promise<GazetteerInfo>")]
+pub fn create_gazetteer(
+    name: String,
+    entries: HashMap<String, Vec<String>>,
+    options: Option<CreateGazetteerOptions>,
+) -> napi::Result<AsyncTask<CreateGazetteerTask>> {
+    if name.is_empty() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "gazetteer name cannot be empty",
+        ));
+    }
+    if entries.is_empty() || entries.keys().any(|label| label.trim().is_empty()) {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "entries must map at least one non-empty label to its terms",
+        ));
+    }
+    Ok(AsyncTask::new(CreateGazetteerTask {
+        name,
+        entries,
+        language: options.unwrap_or_default().language,
+        error_code: None,
+    }))
+}
+
+/// Unregister the named gazetteer. Returns `false` if there was none.
+#[napi]
+pub fn remove_gazetteer(name: String) -> bool {
+    let Ok(c_name) = CString::new(name.as_str()) else {
+        return false;
+    };
+    gazetteers().lock().unwrap().remove(&name);
+    unsafe { apple_ai_remove_gazetteer(c_name.as_ptr()) }
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct AnalyzeSentimentOptions {
+    /// `document` (default) scores the text as a whole, `sentence` each
+    /// sentence in it
+    #[napi(ts_type = "'document' | 'sentence'")]
+    pub granularity: Option<String>,
+    /// Language of the text (default: detected)
+    pub language: Option<String>,
+}
+
+/// Sentiment of a document or sentence.
+#[napi(object)]
+pub struct SentimentScore {
+    pub text: String,
+    /// Offset in the text, in UTF-16 code units like JS strings
+    pub start: u32,
+    pub end: u32,
+    /// From -1 (negative) through 0 (neutral) to 1 (positive)
+    pub score: f64,
+}
+
+pub struct AnalyzeSentimentTask {
+    text: String,
+    options_json: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for AnalyzeSentimentTask {
+    type Output = Vec<SentimentScore>;
+    type JsValue = Vec<SentimentScore>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let c_text = c_text(&self.text)?;
+        let ptr = unsafe { apple_ai_sentiment(c_text.as_ptr(), self.options_json.as_ptr()) };
+        let result = native_json(ptr).map_err(|(code, message)| {
+            self.error_code = Some(code);
+            napi::Error::from_reason(message)
+        })?;
+        let offsets = utf16_offsets(&self.text);
+        Ok(spans(&self.text, &result)
+            .into_iter()
+            .filter_map(|(range, unit)| {
+                Some(SentimentScore {
+                    text: self.text[range.clone()].to_string(),
+                    start: offsets[range.start],
+                    end: offsets[range.end],
+                    score: unit["score"].as_f64()?,
+                })
+            })
+            .collect())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Score the sentiment of `text`, as a whole or per sentence, with
+/// NaturalLanguage's sentiment model rather than the language model.
+#[napi(ts_return_type = "Promise<SentimentScore[]>")]
+pub fn analyze_sentiment(
+    text: String,
+    options: Option<AnalyzeSentimentOptions>,
+) -> napi::Result<AsyncTask<AnalyzeSentimentTask>> {
+    let options = options.unwrap_or_default();
+    let granularity = options.granularity.as_deref().unwrap_or("document");
+    if !matches!(granularity, "document" | "sentence") {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("Unknown granularity '{granularity}', expected 'document' or 'sentence'"),
+        ));
+    }
+    Ok(AsyncTask::new(AnalyzeSentimentTask {
+        text,
+        options_json: options_json(
+            json!({ "granularity": granularity, "language": options.language }),
+        )?,
+        error_code: None,
+    }))
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ExtractEntitiesOptions {
+    /// Kinds of entity to return, including labels of the `gazetteers`
+    /// (default: all)
+    #[napi(ts_type = "Array<'person' | 'place' | 'organization' | (string & {})>")]
+    pub types: Option<Vec<String>>,
+    /// Names of gazetteers from `createGazetteer` whose terms are entities
+    /// too, typed with their label
+    pub gazetteers: Option<Vec<String>>,
+    /// Language of the text (default: detected)
+    pub language: Option<String>,
+}
+
+/// A person, place or organization named in a text, or a gazetteer term.
+#[napi(object)]
+pub struct Entity {
+    pub text: String,
+    /// The kind, or the gazetteer label of a term
+    #[napi(
+        js_name = "type",
+        ts_type = "'person' | 'place' | 'organization' | (string & {})"
+    )]
+    pub kind: String,
+    /// Offset in the text, in UTF-16 code units like JS strings
+    pub start: u32,
+    pub end: u32,
+}
+
+pub struct ExtractEntitiesTask {
+    text: String,
+    types: Option<Vec<String>>,
+    options_json: CString,
+    error_code: Option<ErrorCode>,
+}
+
+/// Byte ranges of entities with their types.
+pub type EntitySpans = Vec<(Range<usize>, String)>;
+
+/// Byte ranges of the entities named in `text`, with their types, given
+/// Swift's `{language, gazetteers}` options.
+pub fn named_entities(
+    text: &str,
+    options_json: &CString,
+) -> std::result::Result<EntitySpans, (ErrorCode, String)> {
+    let c_text = CString::new(text).map_err(|_| {
+        (
+            ErrorCode::InvalidArg,
+            "Text contained null byte".to_string(),
+        )
+    })?;
+    let ptr = unsafe { apple_ai_named_entities(c_text.as_ptr(), options_json.as_ptr()) };
+    let result = native_json(ptr)?;
+    Ok(spans(text, &result)
+        .into_iter()
+        .filter_map(|(range, unit)| Some((range, unit["type"].as_str()?.to_string())))
+        .collect())
+}
+
+/// Swift's options for `named_entities`.
+pub fn entity_options_json(
+    language: Option<&str>,
+    gazetteers: Option<&[String]>,
+) -> napi::Result<CString> {
+    options_json(json!({ "language": language, "gazetteers": gazetteers }))
+}
+
+impl napi::Task for ExtractEntitiesTask {
+    type Output = Vec<Entity>;
+    type JsValue = Vec<Entity>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let entities =
+            named_entities(&self.text, &self.options_json).map_err(|(code, message)| {
+                self.error_code = Some(code);
+                napi::Error::from_reason(message)
+            })?;
+        let offsets = utf16_offsets(&self.text);
+        Ok(entities
+            .into_iter()
+            .filter(|(_, kind)| self.types.as_ref().is_none_or(|types| types.contains(kind)))
+            .map(|(range, kind)| Entity {
+                text: self.text[range.clone()].to_string(),
+                kind,
+                start: offsets[range.start],
+                end: offsets[range.end],
+            })
+            .collect())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Find the people, places and organizations named in `text`, in order,
+/// with NaturalLanguage's name tagger.
+#[napi(ts_return_type = "Promise<Entity[]>")]
+pub fn extract_entities(
+    text: String,
+    options: Option<ExtractEntitiesOptions>,
+) -> napi::Result<AsyncTask<ExtractEntitiesTask>> {
+    let options = options.unwrap_or_default();
+    let labels = gazetteer_labels(options.gazetteers.as_deref().unwrap_or_default())?;
+    if let Some(unknown) = options
+        .types
+        .iter()
+        .flatten()
+        .find(|t| !ENTITY_TYPES.contains(&t.as_str()) && !labels.contains(t))
+    {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            format!(
+                "Unknown entity type '{unknown}', expected 'person', 'place', 'organization' or a gazetteer label"
+            ),
+        ));
+    }
+    Ok(AsyncTask::new(ExtractEntitiesTask {
+        text,
+        types: options.types,
+        options_json: entity_options_json(
+            options.language.as_deref(),
+            options.gazetteers.as_deref(),
+        )?,
+        error_code: None,
+    }))
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct TagTextOptions {
+    /// `lexicalClass` (default) tags parts of speech, `nameTypeOrLexicalClass`
+    /// tags names as such and other words by part of speech, and `lemma`
+    /// gives each word's dictionary form
+    #[napi(ts_type = "'lexicalClass' | 'nameTypeOrLexicalClass' | 'lemma'")]
+    pub scheme: Option<String>,
+    /// Leave punctuation out (default false)
+    pub omit_punctuation: Option<bool>,
+    /// Names of gazetteers from `createGazetteer` whose terms are tagged
+    /// with their label
+    pub gazetteers: Option<Vec<String>>,
+    /// Language of the text (default: detected)
+    pub language: Option<String>,
+}
+
+/// A word of a text with its tag.
+#[napi(object)]
+pub struct TaggedToken {
+    pub text: String,
+    /// Offset in the text, in UTF-16 code units like JS strings
+    pub start: u32,
+    pub end: u32,
+    /// e.g. `Noun`, `Verb` or `PersonalName`, the lemma, or a gazetteer
+    /// label; absent when the tagger has none for the word
+    pub tag: Option<String>,
+}
+
+pub struct TagTextTask {
+    text: String,
+    options_json: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for TagTextTask {
+    type Output = Vec<TaggedToken>;
+    type JsValue = Vec<TaggedToken>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let c_text = c_text(&self.text)?;
+        let ptr = unsafe { apple_ai_tag_text(c_text.as_ptr(), self.options_json.as_ptr()) };
+        let result = native_json(ptr).map_err(|(code, message)| {
+            self.error_code = Some(code);
+            napi::Error::from_reason(message)
+        })?;
+        let offsets = utf16_offsets(&self.text);
+        Ok(spans(&self.text, &result)
+            .into_iter()
+            .map(|(range, unit)| TaggedToken {
+                text: self.text[range.clone()].to_string(),
+                start: offsets[range.start],
+                end: offsets[range.end],
+                tag: unit["tag"].as_str().map(str::to_string),
+            })
+            .collect())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Split `text` into words tagged with a NaturalLanguage scheme: parts of
+/// speech, names, or lemmas.
+#[napi(ts_return_type = "Promise<TaggedToken[]>")]
+pub fn tag_text(
+    text: String,
+    options: Option<TagTextOptions>,
+) -> napi::Result<AsyncTask<TagTextTask>> {
+    let options = options.unwrap_or_default();
+    let scheme = options.scheme.as_deref().unwrap_or("lexicalClass");
+    if !matches!(scheme, "lexicalClass" | "nameTypeOrLexicalClass" | "lemma") {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            format!(
+                "Unknown scheme '{scheme}', expected 'lexicalClass', 'nameTypeOrLexicalClass' or 'lemma'"
+            ),
+        ));
+    }
+    gazetteer_labels(options.gazetteers.as_deref().unwrap_or_default())?;
+    Ok(AsyncTask::new(TagTextTask {
+        text,
+        options_json: options_json(json!({
+            "scheme": scheme,
+            "language": options.language,
+            "omitPunctuation": options.omit_punctuation.unwrap_or(false),
+            "gazetteers": options.gazetteers,
+        }))?,
+        error_code: None,
+    }))
+}
@@ -0,0 +1,122 @@
+//! Spelling and grammar checking with the system's checker: deterministic
+//! issues with their ranges and suggested replacements.
+
+use crate::chunking::utf16_offsets;
+use crate::{apple_ai_check_text, coded_error, take_c_string, ErrorCode};
+use napi::bindgen_prelude::AsyncTask;
+use napi::{Env, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::ffi::CString;
+
+const DEFAULT_MAX_SUGGESTIONS: u32 = 5;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct CheckTextOptions {
+    /// Language of the text, e.g. `en` or `en-GB` (default: detected)
+    pub language: Option<String>,
+    /// Check grammar as well as spelling (default `true`)
+    pub grammar: Option<bool>,
+    /// Most suggestions per issue (default 5)
+    pub max_suggestions: Option<u32>,
+}
+
+/// A misspelling or grammar problem.
+#[napi(object)]
+pub struct TextIssue {
+    #[napi(js_name = "type", ts_type = "'spelling' | 'grammar'")]
+    pub kind: String,
+    /// The text at fault
+    pub text: String,
+    /// Offset in the text, in UTF-16 code units like JS strings
+    pub start: u32,
+    pub end: u32,
+    /// What's wrong, for grammar issues
+    pub message: Option<String>,
+    /// Replacements for `text`, best first; may be empty
+    pub suggestions: Vec<String>,
+}
+
+pub struct CheckTextTask {
+    text: String,
+    options_json: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for CheckTextTask {
+    type Output = Vec<TextIssue>;
+    type JsValue = Vec<TextIssue>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let c_text = CString::new(self.text.as_str())
+            .map_err(|_| napi::Error::new(Status::InvalidArg, "Text contained null byte"))?;
+        let ptr = unsafe { apple_ai_check_text(c_text.as_ptr(), self.options_json.as_ptr()) };
+        if ptr.is_null() {
+            return Err(napi::Error::from_reason("Text checking failed"));
+        }
+        let result: Value = serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+            napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+        })?;
+        if let Some(message) = result["error"].as_str() {
+            self.error_code = result["code"].as_str().and_then(ErrorCode::parse);
+            return Err(napi::Error::from_reason(message.to_string()));
+        }
+        let offsets = utf16_offsets(&self.text);
+        let mut issues: Vec<TextIssue> = result
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|issue| {
+                let start = issue["start"].as_u64()? as usize;
+                let end = issue["end"].as_u64()? as usize;
+                Some(TextIssue {
+                    kind: issue["kind"].as_str()?.to_string(),
+                    text: self.text.get(start..end)?.to_string(),
+                    start: offsets[start],
+                    end: offsets[end],
+                    message: issue["message"].as_str().map(str::to_string),
+                    suggestions: issue["suggestions"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|s| s.as_str().map(str::to_string))
+                        .collect(),
+                })
+            })
+            .collect();
+        issues.sort_by_key(|issue| (issue.start, issue.end));
+        Ok(issues)
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Check the spelling and grammar of `text` with the system's checker,
+/// resolving with the issues in order. Rejects with code
+/// `UnsupportedLanguage` for a language it has no dictionary for.
+#[napi(ts_return_type = "Promise<TextIssue[]>")]
+pub fn check_text(
+    text: String,
+    options: Option<CheckTextOptions>,
+) -> napi::Result<AsyncTask<CheckTextTask>> {
+    let options = options.unwrap_or_default();
+    let options_json = json!({
+        "language": options.language,
+        "grammar": options.grammar.unwrap_or(true),
+        "maxSuggestions": options.max_suggestions.unwrap_or(DEFAULT_MAX_SUGGESTIONS),
+    });
+    Ok(AsyncTask::new(CheckTextTask {
+        text,
+        options_json: CString::new(options_json.to_string())
+            .map_err(|_| napi::Error::new(Status::InvalidArg, "Options contained null byte"))?,
+        error_code: None,
+    }))
+}
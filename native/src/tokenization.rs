@@ -0,0 +1,151 @@
+//! Word, sentence and paragraph boundaries from NaturalLanguage's tokenizer:
+//! `tokenize`, and the splitting chunking and truncation do themselves.
+
+use crate::chunking::utf16_offsets;
+use crate::{apple_ai_token_ranges, coded_error, take_c_string, ErrorCode};
+use napi::bindgen_prelude::AsyncTask;
+use napi::{Env, Status};
+use napi_derive::napi;
+use std::ffi::CString;
+use std::ops::Range;
+
+#[derive(Clone, Copy)]
+pub enum Unit {
+    Word,
+    Sentence,
+    Paragraph,
+}
+
+impl Unit {
+    fn parse(unit: Option<&str>) -> napi::Result<Self> {
+        match unit {
+            None | Some("word") => Ok(Self::Word),
+            Some("sentence") => Ok(Self::Sentence),
+            Some("paragraph") => Ok(Self::Paragraph),
+            Some(other) => Err(napi::Error::new(
+                Status::InvalidArg,
+                format!("Unknown unit '{other}', expected 'word', 'sentence' or 'paragraph'"),
+            )),
+        }
+    }
+
+    fn as_c_str(self) -> &'static std::ffi::CStr {
+        match self {
+            Self::Word => c"word",
+            Self::Sentence => c"sentence",
+            Self::Paragraph => c"paragraph",
+        }
+    }
+}
+
+/// `start..end` of `text` without surrounding whitespace, if anything is left.
+pub fn trimmed(text: &str, start: usize, end: usize) -> Option<Range<usize>> {
+    let piece = text.get(start..end)?;
+    let rest = piece.trim_start();
+    let start = start + piece.len() - rest.len();
+    let end = start + rest.trim_end().len();
+    (start < end).then_some(start..end)
+}
+
+/// Byte ranges of the words, sentences or paragraphs of `text`, without
+/// surrounding whitespace.
+pub fn token_ranges(
+    text: &str,
+    unit: Unit,
+    language: Option<&str>,
+) -> napi::Result<Vec<Range<usize>>> {
+    let c_text = CString::new(text)
+        .map_err(|_| napi::Error::new(Status::InvalidArg, "Text contained null byte"))?;
+    let c_language = language
+        .map(CString::new)
+        .transpose()
+        .map_err(|_| napi::Error::new(Status::InvalidArg, "Language contained null byte"))?;
+    let ptr = unsafe {
+        apple_ai_token_ranges(
+            c_text.as_ptr(),
+            unit.as_c_str().as_ptr(),
+            c_language.as_ref().map_or(std::ptr::null(), |l| l.as_ptr()),
+        )
+    };
+    if ptr.is_null() {
+        return Err(napi::Error::from_reason("Tokenization failed"));
+    }
+    let ranges: Vec<[usize; 2]> = serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+        napi::Error::from_reason(format!("Invalid JSON returned from native layer: {e}"))
+    })?;
+    Ok(ranges
+        .into_iter()
+        .filter_map(|[start, end]| trimmed(text, start, end))
+        .collect())
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct TokenizeOptions {
+    /// What to split into (default `word`)
+    #[napi(ts_type = "'word' | 'sentence' | 'paragraph'")]
+    pub unit: Option<String>,
+    /// Language of the text (default: detected)
+    pub language: Option<String>,
+}
+
+/// A word, sentence or paragraph of a text.
+#[napi(object)]
+pub struct Token {
+    pub text: String,
+    /// Offset in the text, in UTF-16 code units like JS strings
+    pub start: u32,
+    pub end: u32,
+    /// Offset in the text's UTF-8 encoding, e.g. for a `Buffer` of it
+    pub byte_start: u32,
+    pub byte_end: u32,
+}
+
+pub struct TokenizeTask {
+    text: String,
+    unit: Unit,
+    language: Option<String>,
+}
+
+impl napi::Task for TokenizeTask {
+    type Output = Vec<Token>;
+    type JsValue = Vec<Token>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let ranges = token_ranges(&self.text, self.unit, self.language.as_deref())?;
+        let offsets = utf16_offsets(&self.text);
+        Ok(ranges
+            .into_iter()
+            .map(|range| Token {
+                text: self.text[range.clone()].to_string(),
+                start: offsets[range.start],
+                end: offsets[range.end],
+                byte_start: range.start as u32,
+                byte_end: range.end as u32,
+            })
+            .collect())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        Err(coded_error(env, ErrorCode::of(&err), err.reason))
+    }
+}
+
+/// Split `text` into words, sentences or paragraphs, in order, with
+/// NaturalLanguage's tokenizer. Words leave out punctuation and whitespace.
+#[napi(ts_return_type = "Promise<Token[]>")]
+pub fn tokenize(
+    text: String,
+    options: Option<TokenizeOptions>,
+) -> napi::Result<AsyncTask<TokenizeTask>> {
+    let options = options.unwrap_or_default();
+    Ok(AsyncTask::new(TokenizeTask {
+        text,
+        unit: Unit::parse(options.unit.as_deref())?,
+        language: options.language,
+    }))
+}
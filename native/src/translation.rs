@@ -0,0 +1,388 @@
+//! Offline translation with the Translation framework: the languages it
+//! supports, whether a pair's languages are on the device, getting them
+//! downloaded, and translating with them.
+
+use crate::{
+    apple_ai_open_translation_settings, apple_ai_translate, apple_ai_translation_languages,
+    apple_ai_translation_status, coded_error, take_c_string, ErrorCode,
+};
+use napi::bindgen_prelude::{AsyncTask, Either};
+use napi::threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{Env, JsFunction, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::ffi::CString;
+use std::time::{Duration, Instant};
+
+const DEFAULT_POLL_INTERVAL_MS: u32 = 2_000;
+const DEFAULT_DOWNLOAD_TIMEOUT_MS: u32 = 600_000;
+
+/// Swift's JSON result, or the error it reports with its code.
+fn native_json(ptr: *mut std::ffi::c_char) -> std::result::Result<Value, (ErrorCode, String)> {
+    if ptr.is_null() {
+        return Err((
+            ErrorCode::GenerationFailed,
+            "Translation failed".to_string(),
+        ));
+    }
+    let result: Value = serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+        (
+            ErrorCode::GenerationFailed,
+            format!("Invalid JSON returned from native layer: {e}"),
+        )
+    })?;
+    if let Some(message) = result["error"].as_str() {
+        let code = result["code"]
+            .as_str()
+            .and_then(ErrorCode::parse)
+            .unwrap_or(ErrorCode::GenerationFailed);
+        return Err((code, message.to_string()));
+    }
+    Ok(result)
+}
+
+fn language_code(name: &str, code: String) -> napi::Result<CString> {
+    if code.trim().is_empty() {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("{name} must be a language code, e.g. \"en\" or \"pt-BR\""),
+        ));
+    }
+    CString::new(code)
+        .map_err(|_| napi::Error::new(Status::InvalidArg, format!("{name} contained null byte")))
+}
+
+/// `installed`, `supported` (needs downloading) or `unsupported`.
+fn pair_status(from: &CString, to: &CString) -> std::result::Result<String, (ErrorCode, String)> {
+    let result = native_json(unsafe { apple_ai_translation_status(from.as_ptr(), to.as_ptr()) })?;
+    Ok(result["status"]
+        .as_str()
+        .unwrap_or("unsupported")
+        .to_string())
+}
+
+#[napi(object)]
+pub struct TranslationLanguage {
+    /// BCP 47 code, e.g. `en` or `zh-Hant`
+    pub code: String,
+    /// Name in the user's language
+    pub name: String,
+}
+
+pub struct TranslationLanguagesTask {
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for TranslationLanguagesTask {
+    type Output = Vec<TranslationLanguage>;
+    type JsValue = Vec<TranslationLanguage>;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let result = native_json(unsafe { apple_ai_translation_languages() }).map_err(
+            |(code, message)| {
+                self.error_code = Some(code);
+                napi::Error::from_reason(message)
+            },
+        )?;
+        let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+        Ok(result["languages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|language| TranslationLanguage {
+                code: text(&language["code"]),
+                name: text(&language["name"]),
+            })
+            .collect())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// The languages offline translation supports, installed or not.
+#[napi(ts_return_type = "Promise<TranslationLanguage[]>")]
+pub fn get_translation_languages() -> AsyncTask<TranslationLanguagesTask> {
+    AsyncTask::new(TranslationLanguagesTask { error_code: None })
+}
+
+pub struct PairInstalledTask {
+    from: CString,
+    to: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for PairInstalledTask {
+    type Output = bool;
+    type JsValue = bool;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let status = pair_status(&self.from, &self.to).map_err(|(code, message)| {
+            self.error_code = Some(code);
+            napi::Error::from_reason(message)
+        })?;
+        Ok(status == "installed")
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Whether translating `from` into `to` works offline now, with both
+/// languages on the device.
+#[napi(ts_return_type = "Promise<boolean>")]
+pub fn is_pair_installed(from: String, to: String) -> napi::Result<AsyncTask<PairInstalledTask>> {
+    Ok(AsyncTask::new(PairInstalledTask {
+        from: language_code("from", from)?,
+        to: language_code("to", to)?,
+        error_code: None,
+    }))
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct DownloadLanguagePairOptions {
+    /// Open System Settings where the languages are downloaded (default
+    /// `true`)
+    pub open_settings: Option<bool>,
+    /// How often to check whether the languages arrived, in milliseconds
+    /// (default 2000)
+    pub interval_ms: Option<u32>,
+    /// Give up after this many milliseconds (default 600000)
+    pub timeout_ms: Option<u32>,
+}
+
+/// Progress of `download_language_pair`.
+#[napi(object)]
+pub struct LanguagePairProgress {
+    #[napi(ts_type = "'notInstalled' | 'installed'")]
+    pub state: String,
+    /// Time since the download was asked for. The framework doesn't report
+    /// a completed fraction, so this is what a progress indicator can show
+    pub elapsed_ms: u32,
+}
+
+type ProgressFn = ThreadsafeFunction<LanguagePairProgress, ErrorStrategy::CalleeHandled>;
+
+pub struct DownloadLanguagePairTask {
+    from: CString,
+    to: CString,
+    open_settings: bool,
+    interval: Duration,
+    timeout: Duration,
+    progress: Option<ProgressFn>,
+    error_code: Option<ErrorCode>,
+}
+
+impl DownloadLanguagePairTask {
+    fn fail(&mut self, code: ErrorCode, message: String) -> napi::Error {
+        self.error_code = Some(code);
+        napi::Error::from_reason(message)
+    }
+
+    fn report(&self, state: &str, started: Instant) {
+        if let Some(progress) = &self.progress {
+            progress.call(
+                Ok(LanguagePairProgress {
+                    state: state.to_string(),
+                    elapsed_ms: started.elapsed().as_millis() as u32,
+                }),
+                ThreadsafeFunctionCallMode::NonBlocking,
+            );
+        }
+    }
+}
+
+impl napi::Task for DownloadLanguagePairTask {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let started = Instant::now();
+        let names = format!(
+            "{} to {}",
+            self.from.to_string_lossy(),
+            self.to.to_string_lossy()
+        );
+        let mut status = pair_status(&self.from, &self.to)
+            .map_err(|(code, message)| self.fail(code, message))?;
+        if status == "unsupported" {
+            return Err(self.fail(
+                ErrorCode::UnsupportedLanguage,
+                format!("Translation from {names} is not supported"),
+            ));
+        }
+        if status != "installed" && self.open_settings {
+            unsafe { apple_ai_open_translation_settings() };
+        }
+        while status != "installed" {
+            self.report("notInstalled", started);
+            if started.elapsed() >= self.timeout {
+                return Err(self.fail(
+                    ErrorCode::Timeout,
+                    format!(
+                        "The languages for translation from {names} weren't downloaded within {} s",
+                        self.timeout.as_secs()
+                    ),
+                ));
+            }
+            std::thread::sleep(self.interval);
+            status = pair_status(&self.from, &self.to)
+                .map_err(|(code, message)| self.fail(code, message))?;
+        }
+        self.report("installed", started);
+        Ok(())
+    }
+
+    fn resolve(&mut self, _env: Env, _output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(())
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Get the languages for translating `from` into `to` onto the device. The
+/// system downloads translation languages only with the user's consent, so
+/// this opens System Settings where they're downloaded and reports progress
+/// until both are installed. Resolves at once when they already are;
+/// rejects with code `UnsupportedLanguage` for a pair translation doesn't
+/// support and `Timeout` when they don't arrive within `timeoutMs`.
+#[napi(ts_return_type = "Promise<void>")]
+pub fn download_language_pair(
+    from: String,
+    to: String,
+    options: Option<DownloadLanguagePairOptions>,
+    #[napi(ts_arg_type = "(err: Error | null, progress: LanguagePairProgress) => void")]
+    on_progress: Option<JsFunction>,
+) -> napi::Result<AsyncTask<DownloadLanguagePairTask>> {
+    let options = options.unwrap_or_default();
+    let interval_ms = options.interval_ms.unwrap_or(DEFAULT_POLL_INTERVAL_MS);
+    if interval_ms == 0 {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "intervalMs must be at least 1",
+        ));
+    }
+    let progress = on_progress
+        .map(|callback| {
+            callback.create_threadsafe_function(
+                0,
+                |ctx: ThreadSafeCallContext<LanguagePairProgress>| Ok(vec![ctx.value]),
+            )
+        })
+        .transpose()?;
+    Ok(AsyncTask::new(DownloadLanguagePairTask {
+        from: language_code("from", from)?,
+        to: language_code("to", to)?,
+        open_settings: options.open_settings.unwrap_or(true),
+        interval: Duration::from_millis(interval_ms.into()),
+        timeout: Duration::from_millis(
+            options
+                .timeout_ms
+                .unwrap_or(DEFAULT_DOWNLOAD_TIMEOUT_MS)
+                .into(),
+        ),
+        progress,
+        error_code: None,
+    }))
+}
+
+#[napi(object)]
+pub struct TranslateOptions {
+    /// Language to translate into, e.g. `de`
+    pub to: String,
+    /// Language of the text (default: detected)
+    pub from: Option<String>,
+}
+
+#[napi(object)]
+pub struct TranslationResult {
+    /// Translations in the order of the texts
+    pub translations: Vec<String>,
+    /// Language translated from, as given or detected
+    pub from: String,
+}
+
+pub struct TranslateTask {
+    options_json: CString,
+    error_code: Option<ErrorCode>,
+}
+
+impl napi::Task for TranslateTask {
+    type Output = TranslationResult;
+    type JsValue = TranslationResult;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let result = native_json(unsafe { apple_ai_translate(self.options_json.as_ptr()) })
+            .map_err(|(code, message)| {
+                self.error_code = Some(code);
+                napi::Error::from_reason(message)
+            })?;
+        Ok(TranslationResult {
+            translations: result["translations"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|t| t.as_str().unwrap_or_default().to_string())
+                .collect(),
+            from: result["from"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+/// Translate `text`, one string or several, on the device. Rejects with
+/// code `AssetsUnavailable` while the pair's languages aren't downloaded
+/// (see `download_language_pair`) and `UnsupportedLanguage` for a pair
+/// translation doesn't support.
+#[napi(ts_return_type = "Promise<TranslationResult>")]
+pub fn translate(
+    #[napi(ts_arg_type = "string | string[]")] text: Either<String, Vec<String>>,
+    options: TranslateOptions,
+) -> napi::Result<AsyncTask<TranslateTask>> {
+    let texts = match text {
+        Either::A(text) => vec![text],
+        Either::B(texts) => texts,
+    };
+    if texts.iter().all(|t| t.trim().is_empty()) {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "text must not be empty",
+        ));
+    }
+    language_code("to", options.to.clone())?;
+    if let Some(from) = &options.from {
+        language_code("from", from.clone())?;
+    }
+    let options_json = json!({ "texts": texts, "from": options.from, "to": options.to });
+    Ok(AsyncTask::new(TranslateTask {
+        options_json: CString::new(options_json.to_string())
+            .map_err(|_| napi::Error::new(Status::InvalidArg, "text contained null byte"))?,
+        error_code: None,
+    }))
+}
@@ -0,0 +1,50 @@
+//! Vector math for similarity search. Sums run in independent lanes so the
+//! compiler turns them into SIMD instructions.
+
+const LANES: usize = 8;
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let (a_chunks, b_chunks) = (a.chunks_exact(LANES), b.chunks_exact(LANES));
+    let tail: f32 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+    let mut sums = [0.0f32; LANES];
+    for (x, y) in a_chunks.zip(b_chunks) {
+        for ((sum, x), y) in sums.iter_mut().zip(x).zip(y) {
+            *sum += x * y;
+        }
+    }
+    sums.iter().sum::<f32>() + tail
+}
+
+pub fn norm(a: &[f32]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+/// Cosine similarity of `a` and `b` given the norm of `a`; 0 when either is
+/// the zero vector.
+pub fn cosine_with_norm(a: &[f32], a_norm: f32, b: &[f32]) -> f32 {
+    let denominator = a_norm * norm(b);
+    if denominator == 0.0 {
+        0.0
+    } else {
+        dot(a, b) / denominator
+    }
+}
+
+/// The `k` entries with the highest scores, highest first.
+pub fn top_k<T>(mut scored: Vec<(T, f32)>, k: usize) -> Vec<(T, f32)> {
+    let by_score = |a: &(T, f32), b: &(T, f32)| b.1.total_cmp(&a.1);
+    if k == 0 {
+        return Vec::new();
+    }
+    if k < scored.len() {
+        scored.select_nth_unstable_by(k - 1, by_score);
+        scored.truncate(k);
+    }
+    scored.sort_by(by_score);
+    scored
+}
@@ -0,0 +1,714 @@
+//! In-memory vector index for local semantic search: a flat store of
+//! vectors, each with an id and optional JSON metadata, searched exhaustively
+//! by cosine similarity. Exact results and no build step; a scan of 100k
+//! 512-dimension vectors takes milliseconds. Each entry's text, from a
+//! metadata property, is also indexed for BM25 keyword search (see
+//! `keyword_index`). A saved index keeps its file up to date, appending each
+//! change (see `vector_store`).
+
+use crate::keyword_index::KeywordIndex;
+use crate::vector;
+use crate::vector_store::{self, open_for_append, Record};
+use napi::bindgen_prelude::Float32Array;
+use napi::Status;
+use napi_derive::napi;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+const DEFAULT_TOP_K: u32 = 10;
+const DEFAULT_TEXT_FIELD: &str = "text";
+
+#[napi(object)]
+#[derive(Default)]
+pub struct VectorIndexOptions {
+    /// Dimension of every vector (default: that of the first one added)
+    pub dimension: Option<u32>,
+    /// Metadata property holding each entry's text, indexed for keyword
+    /// search (default "text")
+    pub text_field: Option<String>,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct VectorSearchOptions {
+    /// Number of results (default 10)
+    pub top_k: Option<u32>,
+    /// Only entries whose metadata has each of these properties with an
+    /// equal (JSON) value
+    #[napi(ts_type = "Record<string, unknown>")]
+    pub filter: Option<Value>,
+    /// Leave out entries scoring below this
+    pub min_score: Option<f64>,
+}
+
+/// An entry found by `VectorIndex.search` or `VectorIndex.keywordSearch`.
+#[napi(object)]
+pub struct VectorSearchHit {
+    pub id: String,
+    /// Cosine similarity to the query, from -1 to 1, or the BM25 score of a
+    /// keyword search
+    pub score: f64,
+    pub metadata: Option<Value>,
+}
+
+#[napi(object)]
+pub struct VectorEntry {
+    pub id: String,
+    pub vector: Float32Array,
+    pub metadata: Option<Value>,
+}
+
+/// The file a saved index appends its changes to.
+struct Log {
+    path: String,
+    file: File,
+}
+
+/// The entries of an index. Rows are stored end to end in one buffer;
+/// removing one moves the last row into its place.
+pub struct Entries {
+    dimension: Option<usize>,
+    ids: Vec<String>,
+    vectors: Vec<f32>,
+    norms: Vec<f32>,
+    metadata: Vec<Option<Value>>,
+    rows: HashMap<String, usize>,
+    text_field: String,
+    keywords: KeywordIndex,
+    log: Option<Log>,
+}
+
+fn invalid(message: String) -> napi::Error {
+    napi::Error::new(Status::InvalidArg, message)
+}
+
+/// Whether `metadata` has every property of `filter` with an equal value.
+fn matches(metadata: Option<&Value>, filter: &serde_json::Map<String, Value>) -> bool {
+    filter
+        .iter()
+        .all(|(key, value)| metadata.and_then(|m| m.get(key)) == Some(value))
+}
+
+impl Entries {
+    fn new(options: VectorIndexOptions) -> napi::Result<Self> {
+        if options.dimension == Some(0) {
+            return Err(invalid("dimension must be at least 1".to_string()));
+        }
+        Ok(Self {
+            dimension: options.dimension.map(|d| d as usize),
+            ids: Vec::new(),
+            vectors: Vec::new(),
+            norms: Vec::new(),
+            metadata: Vec::new(),
+            rows: HashMap::new(),
+            text_field: options
+                .text_field
+                .unwrap_or_else(|| DEFAULT_TEXT_FIELD.to_string()),
+            keywords: KeywordIndex::default(),
+            log: None,
+        })
+    }
+
+    pub fn text_field(&self) -> &str {
+        &self.text_field
+    }
+
+    fn text<'a>(&self, metadata: Option<&'a Value>) -> Option<&'a str> {
+        metadata?.get(&self.text_field)?.as_str()
+    }
+
+    fn row(&self, row: usize) -> &[f32] {
+        let dimension = self.dimension.unwrap_or_default();
+        &self.vectors[row * dimension..(row + 1) * dimension]
+    }
+
+    /// Fix the dimension on the first vector and check it on the others.
+    fn check_dimension(&mut self, vector: &[f32]) -> napi::Result<()> {
+        if vector.is_empty() {
+            return Err(invalid("Vectors must not be empty".to_string()));
+        }
+        match self.dimension {
+            Some(dimension) if dimension != vector.len() => Err(invalid(format!(
+                "Vector has {} dimensions but the index has {dimension}",
+                vector.len()
+            ))),
+            Some(_) => Ok(()),
+            None => {
+                self.dimension = Some(vector.len());
+                Ok(())
+            }
+        }
+    }
+
+    /// Write a change to the index's file, if it has one, before making it.
+    fn append(&mut self, record: Vec<u8>) -> napi::Result<()> {
+        let Some(log) = &mut self.log else {
+            return Ok(());
+        };
+        log.file
+            .write_all(&record)
+            .map_err(|e| napi::Error::from_reason(format!("Cannot write to {}: {e}", log.path)))
+    }
+
+    pub fn insert(
+        &mut self,
+        id: String,
+        vector: &[f32],
+        metadata: Option<Value>,
+    ) -> napi::Result<()> {
+        self.check_dimension(vector)?;
+        self.append(vector_store::encode_upsert(&id, metadata.as_ref(), vector))?;
+        let norm = vector::norm(vector);
+        if let Some(&row) = self.rows.get(&id) {
+            let dimension = vector.len();
+            self.vectors[row * dimension..(row + 1) * dimension].copy_from_slice(vector);
+            self.norms[row] = norm;
+            self.keywords.set(row, self.text(metadata.as_ref()));
+            self.metadata[row] = metadata;
+            return Ok(());
+        }
+        self.keywords
+            .set(self.ids.len(), self.text(metadata.as_ref()));
+        self.rows.insert(id.clone(), self.ids.len());
+        self.ids.push(id);
+        self.vectors.extend_from_slice(vector);
+        self.norms.push(norm);
+        self.metadata.push(metadata);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &str) -> napi::Result<bool> {
+        if !self.rows.contains_key(id) {
+            return Ok(false);
+        }
+        self.append(vector_store::encode_remove(id))?;
+        let Some(row) = self.rows.remove(id) else {
+            return Ok(false);
+        };
+        let dimension = self.dimension.unwrap_or_default();
+        let last = self.ids.len() - 1;
+        if row != last {
+            self.vectors
+                .copy_within(last * dimension..(last + 1) * dimension, row * dimension);
+            self.rows.insert(self.ids[last].clone(), row);
+        }
+        self.vectors.truncate(last * dimension);
+        self.ids.swap_remove(row);
+        self.norms.swap_remove(row);
+        self.metadata.swap_remove(row);
+        self.keywords.swap_remove(row);
+        Ok(true)
+    }
+
+    /// Remove the entries whose metadata matches `filter`; returns how many.
+    pub fn remove_where(&mut self, filter: &serde_json::Map<String, Value>) -> napi::Result<u32> {
+        let ids: Vec<String> = (0..self.ids.len())
+            .filter(|&row| matches(self.metadata[row].as_ref(), filter))
+            .map(|row| self.ids[row].clone())
+            .collect();
+        for id in &ids {
+            self.remove(id)?;
+        }
+        Ok(ids.len() as u32)
+    }
+
+    fn clear(&mut self) -> napi::Result<()> {
+        self.append(vector_store::encode_clear())?;
+        self.ids.clear();
+        self.vectors.clear();
+        self.norms.clear();
+        self.metadata.clear();
+        self.rows.clear();
+        self.keywords.clear();
+        Ok(())
+    }
+
+    /// The `topK` entries most similar to `query`, best first.
+    pub fn search(
+        &self,
+        query: &[f32],
+        options: &VectorSearchOptions,
+    ) -> napi::Result<Vec<VectorSearchHit>> {
+        let Some(dimension) = self.dimension else {
+            return Ok(Vec::new());
+        };
+        if query.len() != dimension {
+            return Err(invalid(format!(
+                "Query has {} dimensions but the index has {dimension}",
+                query.len()
+            )));
+        }
+        let filter = filter(options)?;
+        let min_score = options.min_score.unwrap_or(f64::NEG_INFINITY);
+        let query_norm = vector::norm(query);
+        let scored = (0..self.ids.len())
+            .filter(|&row| filter.is_none_or(|f| matches(self.metadata[row].as_ref(), f)))
+            .map(|row| {
+                let denominator = query_norm * self.norms[row];
+                let score = if denominator == 0.0 {
+                    0.0
+                } else {
+                    vector::dot(query, self.row(row)) / denominator
+                };
+                (row, score)
+            })
+            .filter(|&(_, score)| score as f64 >= min_score)
+            .collect();
+        Ok(self.hits(scored, options))
+    }
+
+    /// The `topK` entries whose text best matches the terms of `query` by
+    /// BM25, best first; entries with none of the terms are left out.
+    pub fn keyword_search(
+        &self,
+        query: &str,
+        options: &VectorSearchOptions,
+    ) -> napi::Result<Vec<VectorSearchHit>> {
+        let filter = filter(options)?;
+        let min_score = options.min_score.unwrap_or(f64::NEG_INFINITY);
+        let terms = self.keywords.query(query);
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let scored = (0..self.ids.len())
+            .filter(|&row| filter.is_none_or(|f| matches(self.metadata[row].as_ref(), f)))
+            .map(|row| (row, self.keywords.score(row, &terms)))
+            .filter(|&(_, score)| score > 0.0 && score as f64 >= min_score)
+            .collect();
+        Ok(self.hits(scored, options))
+    }
+
+    fn hits(
+        &self,
+        scored: Vec<(usize, f32)>,
+        options: &VectorSearchOptions,
+    ) -> Vec<VectorSearchHit> {
+        let top_k = options.top_k.unwrap_or(DEFAULT_TOP_K) as usize;
+        vector::top_k(scored, top_k)
+            .into_iter()
+            .map(|(row, score)| VectorSearchHit {
+                id: self.ids[row].clone(),
+                score: score as f64,
+                metadata: self.metadata[row].clone(),
+            })
+            .collect()
+    }
+}
+
+fn filter(options: &VectorSearchOptions) -> napi::Result<Option<&serde_json::Map<String, Value>>> {
+    match &options.filter {
+        None => Ok(None),
+        Some(Value::Object(filter)) => Ok(Some(filter)),
+        Some(_) => Err(invalid("filter must be an object".to_string())),
+    }
+}
+
+/// Shared handle to an index's entries, for searching it off the JS thread.
+pub type SharedEntries = Arc<RwLock<Entries>>;
+
+/// Vectors with ids and metadata, searched by cosine similarity.
+#[napi]
+pub struct VectorIndex {
+    entries: SharedEntries,
+}
+
+impl VectorIndex {
+    fn read(&self) -> RwLockReadGuard<'_, Entries> {
+        self.entries.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Entries> {
+        self.entries.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn shared(&self) -> SharedEntries {
+        self.entries.clone()
+    }
+}
+
+#[napi]
+impl VectorIndex {
+    #[napi(constructor)]
+    pub fn new(options: Option<VectorIndexOptions>) -> napi::Result<Self> {
+        let entries = Entries::new(options.unwrap_or_default())?;
+        Ok(Self {
+            entries: Arc::new(RwLock::new(entries)),
+        })
+    }
+
+    /// Number of entries
+    #[napi(getter)]
+    pub fn size(&self) -> u32 {
+        self.read().ids.len() as u32
+    }
+
+    /// Dimension of the vectors, unset until the first is added
+    #[napi(getter)]
+    pub fn dimension(&self) -> Option<u32> {
+        self.read().dimension.map(|d| d as u32)
+    }
+
+    /// Metadata property whose text is indexed for keyword search
+    #[napi(getter)]
+    pub fn text_field(&self) -> String {
+        self.read().text_field.clone()
+    }
+
+    /// Add a vector under a new id. Throws if the id is taken or the vector's
+    /// dimension differs from the index's.
+    #[napi]
+    pub fn add(
+        &mut self,
+        id: String,
+        vector: Float32Array,
+        metadata: Option<Value>,
+    ) -> napi::Result<()> {
+        let mut entries = self.write();
+        if entries.rows.contains_key(&id) {
+            return Err(invalid(format!("The index already has an entry '{id}'")));
+        }
+        entries.insert(id, &vector, metadata)
+    }
+
+    /// Add a vector, or replace the vector and metadata of an existing id.
+    #[napi]
+    pub fn upsert(
+        &mut self,
+        id: String,
+        vector: Float32Array,
+        metadata: Option<Value>,
+    ) -> napi::Result<()> {
+        self.write().insert(id, &vector, metadata)
+    }
+
+    /// Remove an entry; returns whether there was one.
+    #[napi]
+    pub fn remove(&mut self, id: String) -> napi::Result<bool> {
+        self.write().remove(&id)
+    }
+
+    /// Remove every entry whose metadata has each property of `filter` with
+    /// an equal value, e.g. all chunks of one document; returns how many.
+    #[napi]
+    pub fn remove_where(
+        &mut self,
+        #[napi(ts_arg_type = "Record<string, unknown>")] filter: Value,
+    ) -> napi::Result<u32> {
+        let Value::Object(filter) = filter else {
+            return Err(invalid("filter must be an object".to_string()));
+        };
+        self.write().remove_where(&filter)
+    }
+
+    #[napi]
+    pub fn has(&self, id: String) -> bool {
+        self.read().rows.contains_key(&id)
+    }
+
+    /// The entry stored under `id`, or `null`
+    #[napi]
+    pub fn get(&self, id: String) -> Option<VectorEntry> {
+        let entries = self.read();
+        let &row = entries.rows.get(&id)?;
+        Some(VectorEntry {
+            id,
+            vector: Float32Array::new(entries.row(row).to_vec()),
+            metadata: entries.metadata[row].clone(),
+        })
+    }
+
+    /// Remove every entry; the dimension stays.
+    #[napi]
+    pub fn clear(&mut self) -> napi::Result<()> {
+        self.write().clear()
+    }
+
+    /// Write the index to `path`, replacing any file there, and from then on
+    /// append every change to it.
+    #[napi]
+    pub fn save(&mut self, path: String) -> napi::Result<()> {
+        let mut entries = self.write();
+        let mut contents = vector_store::header(entries.dimension);
+        for (row, id) in entries.ids.iter().enumerate() {
+            contents.extend(vector_store::encode_upsert(
+                id,
+                entries.metadata[row].as_ref(),
+                entries.row(row),
+            ));
+        }
+        vector_store::write(&path, &contents).map_err(napi::Error::from_reason)?;
+        let file = open_for_append(&path).map_err(napi::Error::from_reason)?;
+        entries.log = Some(Log { path, file });
+        Ok(())
+    }
+
+    /// Open an index saved with `save`. Changes to it are appended to the
+    /// file, so they survive restarts without saving again. The keyword index
+    /// is rebuilt from the entries' `textField`.
+    #[napi(factory)]
+    pub fn open(path: String, options: Option<VectorIndexOptions>) -> napi::Result<Self> {
+        let options = options.unwrap_or_default();
+        let expected = options.dimension;
+        let mut entries = Entries::new(VectorIndexOptions {
+            dimension: None,
+            ..options
+        })?;
+        let header_dimension = vector_store::replay(&path, |record| {
+            match record {
+                Record::Upsert {
+                    id,
+                    metadata,
+                    vector,
+                } => entries.insert(id.to_string(), &vector, metadata),
+                Record::Remove(id) => entries.remove(id).map(|_| ()),
+                Record::Clear => entries.clear(),
+            }
+            .map_err(|e| e.reason)
+        })
+        .map_err(napi::Error::from_reason)?;
+        match (header_dimension, entries.dimension) {
+            (Some(saved), Some(found)) if saved != found => {
+                return Err(napi::Error::from_reason(format!(
+                    "{path} is corrupt: it has {found}-dimension vectors in a {saved}-dimension index"
+                )))
+            }
+            (Some(saved), None) => entries.dimension = Some(saved),
+            _ => {}
+        }
+        if let Some(expected) = expected.filter(|&d| entries.dimension != Some(d as usize)) {
+            return Err(invalid(format!(
+                "{path} does not hold {expected}-dimension vectors"
+            )));
+        }
+        let file = open_for_append(&path).map_err(napi::Error::from_reason)?;
+        entries.log = Some(Log { path, file });
+        Ok(Self {
+            entries: Arc::new(RwLock::new(entries)),
+        })
+    }
+
+    /// File the index appends its changes to, after `save` or `open`
+    #[napi(getter)]
+    pub fn path(&self) -> Option<String> {
+        self.read().log.as_ref().map(|log| log.path.clone())
+    }
+
+    /// The `topK` entries most similar to `query`, best first.
+    #[napi]
+    pub fn search(
+        &self,
+        query: Float32Array,
+        options: Option<VectorSearchOptions>,
+    ) -> napi::Result<Vec<VectorSearchHit>> {
+        self.read().search(&query, &options.unwrap_or_default())
+    }
+
+    /// The `topK` entries whose text best matches the words of `query` by
+    /// BM25, for exact terms like identifiers that embeddings miss.
+    #[napi]
+    pub fn keyword_search(
+        &self,
+        query: String,
+        options: Option<VectorSearchOptions>,
+    ) -> napi::Result<Vec<VectorSearchHit>> {
+        self.read()
+            .keyword_search(&query, &options.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entries() -> Entries {
+        let mut entries = Entries::new(VectorIndexOptions::default()).unwrap();
+        entries
+            .insert(
+                "x".into(),
+                &[1.0, 0.0],
+                Some(json!({ "doc": 1, "text": "red apple" })),
+            )
+            .unwrap();
+        entries
+            .insert(
+                "y".into(),
+                &[0.0, 1.0],
+                Some(json!({ "doc": 2, "text": "green pear" })),
+            )
+            .unwrap();
+        entries
+            .insert(
+                "xy".into(),
+                &[1.0, 1.0],
+                Some(json!({ "doc": 1, "text": "apple pear" })),
+            )
+            .unwrap();
+        entries
+    }
+
+    fn search(entries: &Entries, query: &[f32], options: VectorSearchOptions) -> Vec<String> {
+        entries
+            .search(query, &options)
+            .unwrap()
+            .into_iter()
+            .map(|hit| hit.id)
+            .collect()
+    }
+
+    #[test]
+    fn search_ranks_by_cosine_similarity() {
+        let entries = entries();
+        let hits = entries.search(&[2.0, 0.0], &Default::default()).unwrap();
+        let ids: Vec<&str> = hits.iter().map(|hit| hit.id.as_str()).collect();
+        assert_eq!(ids, ["x", "xy", "y"]);
+        assert!((hits[0].score - 1.0).abs() < 1e-6);
+        assert!((hits[1].score - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert!(hits[2].score.abs() < 1e-6);
+        assert_eq!(
+            hits[0].metadata,
+            Some(json!({ "doc": 1, "text": "red apple" }))
+        );
+
+        let top = VectorSearchOptions {
+            top_k: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(search(&entries, &[0.0, 3.0], top), ["y"]);
+        // A zero query is similar to nothing, rather than NaN
+        let hits = entries.search(&[0.0, 0.0], &Default::default()).unwrap();
+        assert!(hits.iter().all(|hit| hit.score == 0.0));
+    }
+
+    #[test]
+    fn filters_and_min_score_narrow_the_results() {
+        let entries = entries();
+        let doc = |doc: Value| VectorSearchOptions {
+            filter: Some(json!({ "doc": doc })),
+            ..Default::default()
+        };
+        assert_eq!(search(&entries, &[0.0, 1.0], doc(json!(1))), ["xy", "x"]);
+        assert!(search(&entries, &[0.0, 1.0], doc(json!("1"))).is_empty());
+        let min_score = VectorSearchOptions {
+            min_score: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(search(&entries, &[0.0, 1.0], min_score), ["y", "xy"]);
+
+        let not_an_object = VectorSearchOptions {
+            filter: Some(json!(["doc"])),
+            ..Default::default()
+        };
+        assert!(entries.search(&[0.0, 1.0], &not_an_object).is_err());
+
+        let keyword_hits = entries.keyword_search("apple", &doc(json!(1))).unwrap();
+        let ids: Vec<&str> = keyword_hits.iter().map(|hit| hit.id.as_str()).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&"x") && ids.contains(&"xy"));
+        assert!(entries
+            .keyword_search("pear", &doc(json!(1)))
+            .unwrap()
+            .iter()
+            .all(|hit| hit.id == "xy"));
+    }
+
+    #[test]
+    fn upserts_replace_and_removals_move_the_last_row() {
+        let mut entries = entries();
+        entries
+            .insert(
+                "y".into(),
+                &[-1.0, 0.0],
+                Some(json!({ "text": "blue plum" })),
+            )
+            .unwrap();
+        assert_eq!(entries.ids.len(), 3);
+        assert_eq!(entries.row(entries.rows["y"]), [-1.0, 0.0]);
+        assert!(
+            entries
+                .keyword_search("pear", &Default::default())
+                .unwrap()
+                .len()
+                == 1
+        );
+        assert_eq!(
+            entries.keyword_search("plum", &Default::default()).unwrap()[0].id,
+            "y"
+        );
+
+        assert!(entries.remove("x").unwrap());
+        assert!(!entries.remove("x").unwrap());
+        // "xy" took the removed row; its vector, metadata and text moved with it
+        assert_eq!(entries.rows["xy"], 0);
+        assert_eq!(entries.row(0), [1.0, 1.0]);
+        assert_eq!(
+            entries.metadata[0],
+            Some(json!({ "doc": 1, "text": "apple pear" }))
+        );
+        assert_eq!(search(&entries, &[1.0, 1.0], Default::default())[0], "xy");
+        assert_eq!(
+            entries
+                .keyword_search("apple", &Default::default())
+                .unwrap()[0]
+                .id,
+            "xy"
+        );
+
+        let doc = json!({ "doc": 1 });
+        assert_eq!(entries.remove_where(doc.as_object().unwrap()).unwrap(), 1);
+        assert_eq!(entries.ids, ["y"]);
+        entries.clear().unwrap();
+        assert!(entries.ids.is_empty() && entries.vectors.is_empty());
+        assert_eq!(entries.dimension, Some(2));
+    }
+
+    #[test]
+    fn dimensions_must_match() {
+        let mut entries = entries();
+        let error = entries
+            .insert("z".into(), &[1.0, 2.0, 3.0], None)
+            .unwrap_err();
+        assert_eq!(error.reason, "Vector has 3 dimensions but the index has 2");
+        assert!(entries.insert("z".into(), &[], None).is_err());
+        let error = entries.search(&[1.0], &Default::default()).err().unwrap();
+        assert_eq!(error.reason, "Query has 1 dimensions but the index has 2");
+
+        let fixed = VectorIndexOptions {
+            dimension: Some(3),
+            ..Default::default()
+        };
+        let mut entries = Entries::new(fixed).unwrap();
+        assert!(entries.insert("a".into(), &[1.0, 2.0], None).is_err());
+        let zero = VectorIndexOptions {
+            dimension: Some(0),
+            ..Default::default()
+        };
+        assert!(Entries::new(zero).is_err());
+        // An empty index without a dimension finds nothing for any query
+        let empty = Entries::new(VectorIndexOptions::default()).unwrap();
+        assert!(empty
+            .search(&[1.0], &Default::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn add_refuses_a_taken_id() {
+        let mut index = VectorIndex::new(None).unwrap();
+        index
+            .add("a".into(), Float32Array::new(vec![1.0, 0.0]), None)
+            .unwrap();
+        assert!(index
+            .add("a".into(), Float32Array::new(vec![0.0, 1.0]), None)
+            .is_err());
+        index
+            .upsert("a".into(), Float32Array::new(vec![0.0, 1.0]), None)
+            .unwrap();
+        assert_eq!(index.size(), 1);
+        assert_eq!(index.dimension(), Some(2));
+        assert!(index.has("a".into()));
+    }
+}
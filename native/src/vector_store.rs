@@ -0,0 +1,375 @@
+//! On-disk format of a saved `VectorIndex`: an append-only log, so changes
+//! to an open index are written as they happen instead of rewriting the
+//! file. Saving writes a compacted log with one upsert per entry.
+//!
+//! Layout, little endian: the magic `AAIVEC01`, the dimension as u32 (0
+//! while unset), then records, each a tag byte followed by
+//! - upsert (1): id length u32, id, metadata JSON length u32 (0 for none),
+//!   metadata JSON, vector length u32, vector as f32s
+//! - remove (2): id length u32, id
+//! - clear (3): nothing
+
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+
+const MAGIC: &[u8; 8] = b"AAIVEC01";
+const HEADER_LEN: usize = MAGIC.len() + 4;
+
+const UPSERT: u8 = 1;
+const REMOVE: u8 = 2;
+const CLEAR: u8 = 3;
+
+pub enum Record<'a> {
+    Upsert {
+        id: &'a str,
+        metadata: Option<Value>,
+        vector: Vec<f32>,
+    },
+    Remove(&'a str),
+    Clear,
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+pub fn header(dimension: Option<usize>) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&(dimension.unwrap_or(0) as u32).to_le_bytes());
+    out
+}
+
+pub fn encode_upsert(id: &str, metadata: Option<&Value>, vector: &[f32]) -> Vec<u8> {
+    let mut out = vec![UPSERT];
+    put_bytes(&mut out, id.as_bytes());
+    let metadata = metadata.map(Value::to_string).unwrap_or_default();
+    put_bytes(&mut out, metadata.as_bytes());
+    out.extend_from_slice(&(vector.len() as u32).to_le_bytes());
+    for value in vector {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    out
+}
+
+pub fn encode_remove(id: &str) -> Vec<u8> {
+    let mut out = vec![REMOVE];
+    put_bytes(&mut out, id.as_bytes());
+    out
+}
+
+pub fn encode_clear() -> Vec<u8> {
+    vec![CLEAR]
+}
+
+/// A file mapped read-only into memory.
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(file: &File, len: usize) -> std::io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Reads records from the mapped log; `None` at the end or at a record cut
+/// short by a crash mid-append.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.at..self.at.checked_add(len)?)?;
+        self.at += len;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<usize> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?) as usize)
+    }
+
+    fn sized(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()?;
+        self.take(len)
+    }
+
+    fn record(&mut self) -> Option<Result<Record<'a>, String>> {
+        let tag = self.take(1)?[0];
+        Some(match tag {
+            UPSERT => {
+                let id = self.sized()?;
+                let metadata = self.sized()?;
+                let len = self.u32()?;
+                let vector = self
+                    .take(len.checked_mul(4)?)?
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect();
+                parse_id(id).and_then(|id| {
+                    let metadata = if metadata.is_empty() {
+                        None
+                    } else {
+                        Some(
+                            serde_json::from_slice(metadata)
+                                .map_err(|e| format!("invalid metadata: {e}"))?,
+                        )
+                    };
+                    Ok(Record::Upsert {
+                        id,
+                        metadata,
+                        vector,
+                    })
+                })
+            }
+            REMOVE => parse_id(self.sized()?).map(Record::Remove),
+            CLEAR => Ok(Record::Clear),
+            other => Err(format!("unknown record type {other}")),
+        })
+    }
+}
+
+fn parse_id(bytes: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(bytes).map_err(|_| "invalid id".to_string())
+}
+
+/// Replay the log at `path` into `apply` and return the dimension from its
+/// header. A record cut short at the end, left by a crash mid-append, is
+/// dropped from the file so appends continue after the last whole record.
+pub fn replay(
+    path: &str,
+    mut apply: impl FnMut(Record) -> Result<(), String>,
+) -> Result<Option<usize>, String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| format!("Cannot open {path}: {e}"))?;
+    let len = file
+        .metadata()
+        .map_err(|e| format!("Cannot read {path}: {e}"))?
+        .len() as usize;
+    let not_an_index = || format!("{path} is not a vector index file");
+    if len < HEADER_LEN {
+        return Err(not_an_index());
+    }
+    let mapping = Mapping::new(&file, len).map_err(|e| format!("Cannot map {path}: {e}"))?;
+    let mut reader = Reader {
+        bytes: mapping.bytes(),
+        at: 0,
+    };
+    if reader.take(MAGIC.len()) != Some(MAGIC.as_slice()) {
+        return Err(not_an_index());
+    }
+    let dimension = reader.u32().filter(|&d| d > 0);
+    let mut valid_len = reader.at;
+    while let Some(record) = reader.record() {
+        record
+            .and_then(&mut apply)
+            .map_err(|e| format!("{path} is corrupt at byte {valid_len}: {e}"))?;
+        valid_len = reader.at;
+    }
+    drop(mapping);
+    if valid_len < len {
+        file.set_len(valid_len as u64)
+            .map_err(|e| format!("Cannot repair {path}: {e}"))?;
+    }
+    Ok(dimension)
+}
+
+/// Write a new log to `path` through a temporary file, so a failure leaves
+/// any earlier file whole.
+pub fn write(path: &str, contents: &[u8]) -> Result<(), String> {
+    let temporary = format!("{path}.tmp");
+    let result = File::create(&temporary)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&temporary, path));
+    result.map_err(|e| {
+        let _ = std::fs::remove_file(&temporary);
+        format!("Cannot write {path}: {e}")
+    })
+}
+
+/// The log at `path`, opened for appends.
+pub fn open_for_append(path: &str) -> Result<File, String> {
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Cannot open {path}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug, PartialEq)]
+    enum Owned {
+        Upsert(String, Option<Value>, Vec<f32>),
+        Remove(String),
+        Clear,
+    }
+
+    /// A log file in the temp directory, removed when dropped.
+    struct TempLog(String);
+
+    impl TempLog {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("apple-ai-{}-{name}.vec", std::process::id()))
+                .to_string_lossy()
+                .into_owned();
+            write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn replay_all(path: &str) -> Result<(Option<usize>, Vec<Owned>), String> {
+        let mut records = Vec::new();
+        let dimension = replay(path, |record| {
+            records.push(match record {
+                Record::Upsert {
+                    id,
+                    metadata,
+                    vector,
+                } => Owned::Upsert(id.to_string(), metadata, vector),
+                Record::Remove(id) => Owned::Remove(id.to_string()),
+                Record::Clear => Owned::Clear,
+            });
+            Ok(())
+        })?;
+        Ok((dimension, records))
+    }
+
+    #[test]
+    fn replays_upserts_removes_and_clears_in_order() {
+        let mut contents = header(Some(3));
+        contents.extend(encode_upsert(
+            "a",
+            Some(&json!({ "tag": "x" })),
+            &[1.0, -2.5, 0.0],
+        ));
+        contents.extend(encode_upsert("b", None, &[0.5, 0.25, 4.0]));
+        contents.extend(encode_remove("a"));
+        contents.extend(encode_clear());
+        contents.extend(encode_upsert("c", None, &[]));
+        let log = TempLog::new("round-trip", &contents);
+
+        let (dimension, records) = replay_all(&log.0).unwrap();
+        assert_eq!(dimension, Some(3));
+        assert_eq!(
+            records,
+            vec![
+                Owned::Upsert(
+                    "a".into(),
+                    Some(json!({ "tag": "x" })),
+                    vec![1.0, -2.5, 0.0]
+                ),
+                Owned::Upsert("b".into(), None, vec![0.5, 0.25, 4.0]),
+                Owned::Remove("a".into()),
+                Owned::Clear,
+                Owned::Upsert("c".into(), None, vec![]),
+            ]
+        );
+        assert_eq!(
+            std::fs::metadata(&log.0).unwrap().len(),
+            contents.len() as u64
+        );
+    }
+
+    #[test]
+    fn unset_dimension_replays_as_none() {
+        let log = TempLog::new("no-dimension", &header(None));
+        assert_eq!(replay_all(&log.0).unwrap(), (None, vec![]));
+    }
+
+    #[test]
+    fn truncates_a_record_cut_short_mid_append() {
+        let mut contents = header(Some(2));
+        contents.extend(encode_upsert("kept", None, &[1.0, 2.0]));
+        let whole = contents.len();
+        let torn = encode_upsert("torn", None, &[3.0, 4.0]);
+        contents.extend(&torn[..torn.len() - 3]);
+        let log = TempLog::new("torn", &contents);
+
+        let (_, records) = replay_all(&log.0).unwrap();
+        assert_eq!(
+            records,
+            vec![Owned::Upsert("kept".into(), None, vec![1.0, 2.0])]
+        );
+        assert_eq!(std::fs::metadata(&log.0).unwrap().len(), whole as u64);
+
+        // Appends continue after the last whole record
+        open_for_append(&log.0)
+            .unwrap()
+            .write_all(&encode_remove("kept"))
+            .unwrap();
+        let (_, records) = replay_all(&log.0).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                Owned::Upsert("kept".into(), None, vec![1.0, 2.0]),
+                Owned::Remove("kept".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_indexes() {
+        let log = TempLog::new("not-an-index", b"NOTVEC01\0\0\0\0");
+        assert!(replay_all(&log.0)
+            .unwrap_err()
+            .contains("is not a vector index file"));
+        let short = TempLog::new("short", b"AAIV");
+        assert!(replay_all(&short.0).is_err());
+    }
+
+    #[test]
+    fn reports_an_unknown_record_type_as_corrupt() {
+        let mut contents = header(Some(1));
+        contents.push(9);
+        let log = TempLog::new("unknown-tag", &contents);
+        let error = replay_all(&log.0).unwrap_err();
+        assert!(
+            error.contains(&format!("is corrupt at byte {HEADER_LEN}")),
+            "{error}"
+        );
+    }
+}
@@ -0,0 +1,542 @@
+//! Image analysis with Apple's Vision framework. Images cross to Swift as
+//! encoded bytes from a `Buffer` or as a file path, in any format ImageIO
+//! reads, and Swift orients and scales them as asked before analysis.
+
+use crate::{
+    apple_ai_analyze_saliency, apple_ai_classify_image, apple_ai_recognize_text, coded_error,
+    take_c_string, ErrorCode,
+};
+use napi::bindgen_prelude::{AsyncTask, Buffer, Either3, ToNapiValue, TypeName};
+use napi::{Env, Status};
+use napi_derive::napi;
+use serde_json::{json, Value};
+use std::ffi::{c_char, CString};
+
+const ORIENTATIONS: [&str; 5] = ["auto", "up", "right", "down", "left"];
+
+/// Check an `orientation` option: how to turn an image upright.
+pub fn check_orientation(orientation: Option<&str>) -> std::result::Result<(), String> {
+    match orientation {
+        Some(o) if !ORIENTATIONS.contains(&o) => Err(format!(
+            "orientation must be 'auto', 'up', 'right', 'down' or 'left', got {o:?}"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// An image with how to prepare it for analysis.
+#[napi(object)]
+pub struct ImageSource {
+    /// Encoded image bytes in any format ImageIO reads (PNG, JPEG, HEIC,
+    /// TIFF, WebP, …)
+    pub data: Option<Buffer>,
+    /// Image file to read instead of `data`
+    pub path: Option<String>,
+    /// Longest edge in pixels; larger images are downscaled before analysis
+    /// (default: full size)
+    pub max_dimension: Option<u32>,
+    /// How to turn the image upright: `auto` (default) by its EXIF
+    /// orientation, `up` as stored, or `right`, `down` or `left` to turn it a
+    /// quarter clockwise, half or a quarter counterclockwise
+    #[napi(ts_type = "'auto' | 'up' | 'right' | 'down' | 'left'")]
+    pub orientation: Option<String>,
+}
+
+/// Image argument of the Vision functions: bytes, a path, or either with
+/// preprocessing options.
+pub type ImageArg = Either3<Buffer, String, ImageSource>;
+
+/// Where an image's encoded bytes are.
+pub enum ImageData {
+    Buffer(Buffer),
+    Path(CString),
+}
+
+/// An image to analyze.
+pub struct Image {
+    pub data: ImageData,
+    pub max_dimension: Option<u32>,
+    pub orientation: Option<String>,
+}
+
+/// A Swift Vision entry point: `(data, length, path, options_json)`, where
+/// the options' `image` says how to preprocess the image.
+pub type Analyze =
+    unsafe extern "C" fn(*const u8, usize, *const c_char, *const c_char) -> *mut c_char;
+
+impl Image {
+    pub fn new(image: ImageArg) -> napi::Result<Self> {
+        let invalid = |message: String| napi::Error::new(Status::InvalidArg, message);
+        let (data, path, max_dimension, orientation) = match image {
+            Either3::A(buffer) => (Some(buffer), None, None, None),
+            Either3::B(path) => (None, Some(path), None, None),
+            Either3::C(source) => (
+                source.data,
+                source.path,
+                source.max_dimension,
+                source.orientation,
+            ),
+        };
+        if max_dimension == Some(0) {
+            return Err(invalid("maxDimension must be at least 1".to_string()));
+        }
+        check_orientation(orientation.as_deref()).map_err(invalid)?;
+        let data = match (data, path) {
+            (Some(_), Some(_)) => {
+                return Err(invalid("image cannot have both data and path".to_string()))
+            }
+            (Some(buffer), None) if buffer.is_empty() => {
+                return Err(invalid("image must not be an empty Buffer".to_string()))
+            }
+            (Some(buffer), None) => ImageData::Buffer(buffer),
+            (None, Some(path)) => {
+                if !std::path::Path::new(&path).is_file() {
+                    return Err(invalid(format!("{path:?} is not a readable file")));
+                }
+                CString::new(path)
+                    .map(ImageData::Path)
+                    .map_err(|_| invalid("Path contained null byte".to_string()))?
+            }
+            (None, None) => return Err(invalid("image needs data or a path".to_string())),
+        };
+        Ok(Self {
+            data,
+            max_dimension,
+            orientation,
+        })
+    }
+
+    /// Run the Vision entry point `analyze` on the image with `options`, and
+    /// decode its JSON result, or its `{error, code}`.
+    pub fn analyze(
+        &self,
+        analyze: Analyze,
+        options: &Value,
+    ) -> std::result::Result<Value, (ErrorCode, String)> {
+        let mut options = options.clone();
+        options["image"] = json!({
+            "maxDimension": self.max_dimension,
+            "orientation": self.orientation,
+        });
+        let options = CString::new(options.to_string()).map_err(|_| {
+            (
+                ErrorCode::InvalidArg,
+                "Options contained null byte".to_string(),
+            )
+        })?;
+        let ptr = match &self.data {
+            ImageData::Buffer(buffer) => unsafe {
+                analyze(
+                    buffer.as_ptr(),
+                    buffer.len(),
+                    std::ptr::null(),
+                    options.as_ptr(),
+                )
+            },
+            ImageData::Path(path) => unsafe {
+                analyze(std::ptr::null(), 0, path.as_ptr(), options.as_ptr())
+            },
+        };
+        if ptr.is_null() {
+            return Err((ErrorCode::GenerationFailed, "Image analysis failed".into()));
+        }
+        let result: Value = serde_json::from_str(&take_c_string(ptr)).map_err(|e| {
+            (
+                ErrorCode::GenerationFailed,
+                format!("Invalid JSON returned from native layer: {e}"),
+            )
+        })?;
+        if let Some(message) = result.get("error").and_then(Value::as_str) {
+            let code = result
+                .get("code")
+                .and_then(Value::as_str)
+                .and_then(ErrorCode::parse)
+                .unwrap_or(ErrorCode::GenerationFailed);
+            return Err((code, message.to_string()));
+        }
+        Ok(result)
+    }
+}
+
+/// A region of an image as fractions of its width and height, from the top
+/// left corner.
+#[napi(object)]
+pub struct BoundingBox {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl BoundingBox {
+    pub fn from_json(bounds: &Value) -> Self {
+        let get = |key: &str| bounds.get(key).and_then(Value::as_f64).unwrap_or(0.0);
+        Self {
+            x: get("x"),
+            y: get("y"),
+            width: get("width"),
+            height: get("height"),
+        }
+    }
+}
+
+fn dimension(result: &Value, key: &str) -> u32 {
+    result.get(key).and_then(Value::as_u64).unwrap_or(0) as u32
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct RecognizeTextOptions {
+    /// Languages to recognize, most likely first, e.g. `["en-US", "fr-FR"]`
+    /// (default: detected)
+    pub languages: Option<Vec<String>>,
+    /// `accurate` (default) or `fast`, which suits real-time use but reads
+    /// less reliably
+    #[napi(ts_type = "'accurate' | 'fast'")]
+    pub level: Option<String>,
+    /// Correct recognized words with a language model (default `true`); turn
+    /// off for codes and identifiers
+    pub language_correction: Option<bool>,
+}
+
+/// A line of text found in an image.
+#[napi(object)]
+pub struct TextBlock {
+    pub text: String,
+    /// From 0 to 1
+    pub confidence: f64,
+    pub bounds: BoundingBox,
+}
+
+#[napi(object)]
+pub struct RecognizedText {
+    /// The blocks' text, one per line
+    pub text: String,
+    /// In reading order
+    pub blocks: Vec<TextBlock>,
+    /// Size of the image analyzed in pixels, once oriented and scaled
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Runs one Vision entry point on an image off the main thread and turns its
+/// JSON result into `T`.
+pub struct VisionTask<T> {
+    image: Image,
+    options: Value,
+    analyze: Analyze,
+    parse: Box<dyn Fn(Value) -> T + Send>,
+    error_code: Option<ErrorCode>,
+}
+
+impl<T: ToNapiValue + TypeName + Send + 'static> VisionTask<T> {
+    fn new(
+        image: ImageArg,
+        options: Value,
+        analyze: Analyze,
+        parse: impl Fn(Value) -> T + Send + 'static,
+    ) -> napi::Result<AsyncTask<Self>> {
+        Ok(AsyncTask::new(Self {
+            image: Image::new(image)?,
+            options,
+            analyze,
+            parse: Box::new(parse),
+            error_code: None,
+        }))
+    }
+}
+
+impl<T: ToNapiValue + TypeName + Send + 'static> napi::Task for VisionTask<T> {
+    type Output = T;
+    type JsValue = T;
+
+    fn compute(&mut self) -> napi::Result<Self::Output> {
+        let result =
+            self.image
+                .analyze(self.analyze, &self.options)
+                .map_err(|(code, message)| {
+                    self.error_code = Some(code);
+                    napi::Error::from_reason(message)
+                })?;
+        Ok((self.parse)(result))
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+        Ok(output)
+    }
+
+    fn reject(&mut self, env: Env, err: napi::Error) -> napi::Result<Self::JsValue> {
+        let code = self.error_code.unwrap_or_else(|| ErrorCode::of(&err));
+        Err(coded_error(env, code, err.reason))
+    }
+}
+
+pub fn recognized_text(result: Value) -> RecognizedText {
+    let blocks: Vec<TextBlock> = result
+        .get("blocks")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|block| TextBlock {
+            text: block
+                .get("text")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            confidence: block
+                .get("confidence")
+                .and_then(Value::as_f64)
+                .unwrap_or(0.0),
+            bounds: BoundingBox::from_json(&block["bounds"]),
+        })
+        .collect();
+    RecognizedText {
+        text: blocks
+            .iter()
+            .map(|b| b.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        width: dimension(&result, "width"),
+        height: dimension(&result, "height"),
+        blocks,
+    }
+}
+
+/// Recognize the text in a screenshot, photo or scan with Vision, on the
+/// device. `image` is encoded image bytes, a file path or an `ImageSource`. Resolves with each
+/// line's text, confidence and position, and the whole text.
+#[napi(ts_return_type = "Promise<RecognizedText>")]
+pub fn recognize_text(
+    #[napi(ts_arg_type = "Buffer | string | ImageSource")] image: ImageArg,
+    options: Option<RecognizeTextOptions>,
+) -> napi::Result<AsyncTask<VisionTask<RecognizedText>>> {
+    let options = options.unwrap_or_default();
+    let level = options.level.as_deref().unwrap_or("accurate");
+    if !matches!(level, "accurate" | "fast") {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            format!("level must be 'accurate' or 'fast', got {level:?}"),
+        ));
+    }
+    let options_json = json!({
+        "languages": options.languages,
+        "level": level,
+        "languageCorrection": options.language_correction,
+    });
+    VisionTask::new(
+        image,
+        options_json,
+        apple_ai_recognize_text,
+        recognized_text,
+    )
+}
+
+const DEFAULT_TOP_K: u32 = 10;
+const DEFAULT_MIN_CONFIDENCE: f64 = 0.1;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ClassifyImageOptions {
+    /// Most labels returned (default 10)
+    pub top_k: Option<u32>,
+    /// Least confidence of a label returned, from 0 to 1 (default 0.1)
+    pub min_confidence: Option<f64>,
+}
+
+/// A label from Vision's image classifier, such as `dog` or `document`.
+#[napi(object)]
+pub struct ImageLabel {
+    pub identifier: String,
+    /// From 0 to 1
+    pub confidence: f64,
+}
+
+pub fn image_labels(result: Value) -> Vec<ImageLabel> {
+    result
+        .get("labels")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|label| ImageLabel {
+            identifier: label
+                .get("identifier")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            confidence: label
+                .get("confidence")
+                .and_then(Value::as_f64)
+                .unwrap_or(0.0),
+        })
+        .collect()
+}
+
+/// Classify an image with Vision's on-device classifier. `image` is encoded
+/// image bytes or a file path. Resolves with the labels that reach
+/// `minConfidence`, most confident first.
+#[napi(ts_return_type = "Promise<ImageLabel[]>")]
+pub fn classify_image(
+    #[napi(ts_arg_type = "Buffer | string | ImageSource")] image: ImageArg,
+    options: Option<ClassifyImageOptions>,
+) -> napi::Result<AsyncTask<VisionTask<Vec<ImageLabel>>>> {
+    let options = options.unwrap_or_default();
+    let top_k = options.top_k.unwrap_or(DEFAULT_TOP_K);
+    if top_k == 0 {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "topK must be at least 1",
+        ));
+    }
+    let min_confidence = options.min_confidence.unwrap_or(DEFAULT_MIN_CONFIDENCE);
+    if !(0.0..=1.0).contains(&min_confidence) {
+        return Err(napi::Error::new(
+            Status::InvalidArg,
+            "minConfidence must be between 0 and 1",
+        ));
+    }
+    VisionTask::new(
+        image,
+        json!({ "topK": top_k, "minConfidence": min_confidence }),
+        apple_ai_classify_image,
+        image_labels,
+    )
+}
+
+const DEFAULT_PADDING: f64 = 0.1;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct AnalyzeSaliencyOptions {
+    /// Width-to-height ratio of the suggested crop, e.g. `1` for a square
+    /// thumbnail (default: the salient region's own)
+    pub aspect_ratio: Option<f64>,
+    /// Margin kept around the salient region in the crop, as a fraction of
+    /// its width and height (default 0.1)
+    pub padding: Option<f64>,
+}
+
+#[napi(object)]
+pub struct SalientRegion {
+    pub bounds: BoundingBox,
+    /// From 0 to 1
+    pub confidence: f64,
+}
+
+#[napi(object)]
+pub struct SaliencyAnalysis {
+    /// Regions people tend to look at first
+    pub attention: Vec<SalientRegion>,
+    /// The image's main objects
+    pub objects: Vec<SalientRegion>,
+    /// Crop keeping the attention regions (the objects when there are none),
+    /// with `padding` and the `aspectRatio` asked for
+    pub crop: BoundingBox,
+    /// Size of the image analyzed in pixels, once oriented and scaled
+    pub width: u32,
+    pub height: u32,
+}
+
+fn salient_regions(regions: &Value) -> Vec<SalientRegion> {
+    regions
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|region| SalientRegion {
+            bounds: BoundingBox::from_json(&region["bounds"]),
+            confidence: region["confidence"].as_f64().unwrap_or(0.0),
+        })
+        .collect()
+}
+
+/// Crop of a `width` × `height` image around `regions`, padded and widened
+/// or heightened to `aspect_ratio`, shrunk to fit the image if need be.
+fn suggested_crop(
+    regions: &[SalientRegion],
+    width: f64,
+    height: f64,
+    aspect_ratio: Option<f64>,
+    padding: f64,
+) -> BoundingBox {
+    if width <= 0.0 || height <= 0.0 {
+        return BoundingBox::from_json(&json!({ "width": 1.0, "height": 1.0 }));
+    }
+    // Region in pixels, the whole image when nothing stands out
+    let (mut x0, mut y0, mut x1, mut y1) = if regions.is_empty() {
+        (0.0, 0.0, width, height)
+    } else {
+        regions.iter().fold(
+            (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+            |(x0, y0, x1, y1), r| {
+                let b = &r.bounds;
+                (
+                    x0.min(b.x * width),
+                    y0.min(b.y * height),
+                    x1.max((b.x + b.width) * width),
+                    y1.max((b.y + b.height) * height),
+                )
+            },
+        )
+    };
+    let (pad_x, pad_y) = ((x1 - x0) * padding, (y1 - y0) * padding);
+    (x0, y0, x1, y1) = (x0 - pad_x, y0 - pad_y, x1 + pad_x, y1 + pad_y);
+    let (center_x, center_y) = ((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+    let (mut crop_width, mut crop_height) = (x1 - x0, y1 - y0);
+    if let Some(ratio) = aspect_ratio {
+        if crop_width < crop_height * ratio {
+            crop_width = crop_height * ratio;
+        } else {
+            crop_height = crop_width / ratio;
+        }
+        let fit = (width / crop_width).min(height / crop_height).min(1.0);
+        (crop_width, crop_height) = (crop_width * fit, crop_height * fit);
+    } else {
+        (crop_width, crop_height) = (crop_width.min(width), crop_height.min(height));
+    }
+    // Centered on the region, then moved back inside the image
+    let x = (center_x - crop_width / 2.0).clamp(0.0, (width - crop_width).max(0.0));
+    let y = (center_y - crop_height / 2.0).clamp(0.0, (height - crop_height).max(0.0));
+    BoundingBox {
+        x: x / width,
+        y: y / height,
+        width: crop_width / width,
+        height: crop_height / height,
+    }
+}
+
+/// Find where attention goes in an image and its main objects with Vision's
+/// saliency analysis, and suggest a crop that keeps them, e.g. for a
+/// thumbnail. `image` is encoded image bytes, a file path or an `ImageSource`.
+#[napi(ts_return_type = "Promise<SaliencyAnalysis>")]
+pub fn analyze_saliency(
+    #[napi(ts_arg_type = "Buffer | string | ImageSource")] image: ImageArg,
+    options: Option<AnalyzeSaliencyOptions>,
+) -> napi::Result<AsyncTask<VisionTask<SaliencyAnalysis>>> {
+    let options = options.unwrap_or_default();
+    let invalid = |message: &str| napi::Error::new(Status::InvalidArg, message.to_string());
+    let aspect_ratio = options.aspect_ratio;
+    if aspect_ratio.is_some_and(|r| !r.is_finite() || r <= 0.0) {
+        return Err(invalid("aspectRatio must be a positive number"));
+    }
+    let padding = options.padding.unwrap_or(DEFAULT_PADDING);
+    if !padding.is_finite() || padding < 0.0 {
+        return Err(invalid("padding must not be negative"));
+    }
+    VisionTask::new(image, json!({}), apple_ai_analyze_saliency, move |result| {
+        let attention = salient_regions(&result["attention"]);
+        let objects = salient_regions(&result["objects"]);
+        let (width, height) = (dimension(&result, "width"), dimension(&result, "height"));
+        let focus = if attention.is_empty() {
+            &objects
+        } else {
+            &attention
+        };
+        let crop = suggested_crop(focus, width as f64, height as f64, aspect_ratio, padding);
+        SaliencyAnalysis {
+            attention,
+            objects,
+            crop,
+            width,
+            height,
+        }
+    })
+}
@@ -14,10 +14,14 @@ import type {
   SharedV2Headers,
   SharedV2ProviderMetadata,
 } from "@ai-sdk/provider";
-import type { ModelMessage } from "ai";
 import assert from "node:assert";
 import type { ChatCompletionChunk } from "openai/resources/chat";
-import type { ChatMessage } from "./apple-ai";
+import type {
+  ChatMessage,
+  FinishReason,
+  TokenUsage,
+  VercelStreamEvent,
+} from "./apple-ai";
 import {
   _streamChatForVercelAISDK,
   appleAISDK as appleAIInstance,
@@ -25,6 +29,21 @@ import {
 } from "./apple-ai";
 import type { AppleAIModelId, AppleAISettings } from "./apple-ai-provider";
 
+const FINISH_REASONS: Record<FinishReason, LanguageModelV2FinishReason> = {
+  stop: "stop",
+  length: "length",
+  tool_calls: "tool-calls",
+  content_filter: "content-filter",
+};
+
+function toV2Usage(usage: TokenUsage | undefined): LanguageModelV2Usage {
+  return {
+    inputTokens: usage?.promptTokens,
+    outputTokens: usage?.completionTokens,
+    totalTokens: usage?.totalTokens,
+  };
+}
+
 export interface AppleAIChatConfig {
   provider: string;
   headers: Record<string, string>;
@@ -233,12 +252,8 @@ export class AppleAIChatLanguageModel implements LanguageModelV2 {
       // Regular text response
       return {
         content: [{ type: "text", text: result.text || "" }],
-        finishReason: "stop",
-        usage: {
-          inputTokens: undefined,
-          outputTokens: undefined,
-          totalTokens: undefined,
-        },
+        finishReason: FINISH_REASONS[result.finishReason],
+        usage: toV2Usage(result.usage),
         warnings: [],
       };
     } else {
@@ -251,12 +266,8 @@ export class AppleAIChatLanguageModel implements LanguageModelV2 {
 
       return {
         content: [{ type: "text", text: result.text || "" }],
-        finishReason: "stop",
-        usage: {
-          inputTokens: undefined,
-          outputTokens: undefined,
-          totalTokens: undefined,
-        },
+        finishReason: FINISH_REASONS[result.finishReason],
+        usage: toV2Usage(result.usage),
         warnings: [],
       };
     }
@@ -311,7 +322,7 @@ export class AppleAIChatLanguageModel implements LanguageModelV2 {
 
     // Use the Vercel AI SDK specific streaming function
     const nativeStream = _streamChatForVercelAISDK({
-      messages: messages as ModelMessage[],
+      messages,
       tools: epTools,
       temperature: this.settings.temperature,
     });
@@ -335,28 +346,38 @@ export class AppleAIChatLanguageModel implements LanguageModelV2 {
   }
 
   private createStreamFromEvents(
-    nativeStream: AsyncIterableIterator<
-      | { type: "text"; text: string }
-      | {
-          type: "tool-call";
-          toolCallId: string;
-          toolName: string;
-          args: Record<string, unknown>;
-        }
-    >
+    nativeStream: AsyncIterableIterator<VercelStreamEvent>
   ): ReadableStream<LanguageModelV2StreamPart> {
     const finishStream = this.finishStream; // Capture method reference
     return new ReadableStream<LanguageModelV2StreamPart>({
       async start(controller) {
         try {
+          let finishReason: FinishReason = "stop";
+          let usage: TokenUsage | undefined;
           for await (const event of nativeStream) {
-            if (event.type === "text") {
+            if (event.type === "finish") {
+              finishReason = event.finishReason;
+              usage = event.usage;
+            } else if (event.type === "text") {
               controller.enqueue({
                 type: "text-delta",
                 delta: event.text,
                 id: crypto.randomUUID(),
               });
+            } else if (event.type === "tool-input-start") {
+              controller.enqueue({
+                type: "tool-input-start",
+                id: event.toolCallId,
+                toolName: event.toolName,
+              });
+            } else if (event.type === "tool-input-delta") {
+              controller.enqueue({
+                type: "tool-input-delta",
+                id: event.toolCallId,
+                delta: event.delta,
+              });
             } else if (event.type === "tool-call") {
+              controller.enqueue({ type: "tool-input-end", id: event.toolCallId });
               controller.enqueue({
                 type: "tool-call",
                 toolCallId: event.toolCallId,
@@ -365,7 +386,7 @@ export class AppleAIChatLanguageModel implements LanguageModelV2 {
               });
             }
           }
-          finishStream(controller);
+          finishStream(controller, finishReason, usage);
         } catch (err) {
           controller.error(err);
         }
@@ -380,10 +401,25 @@ export class AppleAIChatLanguageModel implements LanguageModelV2 {
     return new ReadableStream<LanguageModelV2StreamPart>({
       async start(controller) {
         try {
+          let finishReason: FinishReason = "stop";
+          let usage: TokenUsage | undefined;
           for await (const chunk of streamNoTools) {
             let text = "";
             if (typeof chunk === "string") text = chunk;
             else text = chunk.choices?.[0]?.delta?.content ?? "";
+            if (typeof chunk !== "string") {
+              const reason = chunk.choices?.[0]?.finish_reason;
+              if (reason && reason in FINISH_REASONS) {
+                finishReason = reason as FinishReason;
+              }
+              if (chunk.usage) {
+                usage = {
+                  promptTokens: chunk.usage.prompt_tokens,
+                  completionTokens: chunk.usage.completion_tokens,
+                  totalTokens: chunk.usage.total_tokens,
+                };
+              }
+            }
             if (text)
               controller.enqueue({
                 type: "text-delta",
@@ -391,7 +427,7 @@ export class AppleAIChatLanguageModel implements LanguageModelV2 {
                 id: crypto.randomUUID(),
               });
           }
-          finishStream(controller);
+          finishStream(controller, finishReason, usage);
         } catch (error) {
           controller.error(error);
         }
@@ -400,16 +436,14 @@ export class AppleAIChatLanguageModel implements LanguageModelV2 {
   }
 
   private finishStream(
-    controller: ReadableStreamDefaultController<LanguageModelV2StreamPart>
+    controller: ReadableStreamDefaultController<LanguageModelV2StreamPart>,
+    finishReason: FinishReason,
+    usage: TokenUsage | undefined
   ): void {
     controller.enqueue({
       type: "finish",
-      finishReason: "stop",
-      usage: {
-        inputTokens: undefined,
-        outputTokens: undefined,
-        totalTokens: undefined,
-      },
+      finishReason: FINISH_REASONS[finishReason],
+      usage: toV2Usage(usage),
     });
     controller.close();
   }
@@ -417,7 +451,7 @@ export class AppleAIChatLanguageModel implements LanguageModelV2 {
   private convertPromptToMessages(
     prompt: Parameters<LanguageModelV2["doGenerate"]>[0]["prompt"]
   ): ChatMessage[] {
-    return prompt.map((message) => {
+    return prompt.flatMap((message) => {
       switch (message.role) {
         case "system":
           return this.convertSystemMessage(message);
@@ -507,39 +541,23 @@ export class AppleAIChatLanguageModel implements LanguageModelV2 {
 
   private convertToolMessage(
     message: Extract<LanguageModelV2Message, { role: "tool" }>
-  ): ChatMessage {
+  ): ChatMessage[] {
     assert(
       message.content.every((part) => part.type === "tool-result"),
       "Tool message must contain only tool-result parts"
     );
 
-    const toolCalls = message.content
-      .map((part) => {
-        if (part.type === "tool-result") {
-          return {
-            id: part.toolCallId,
-            toolName: part.toolName,
-            segments: [
-              {
-                type: "text",
-                text:
-                  typeof part.output === "string"
-                    ? part.output
-                    : JSON.stringify(part.output),
-              },
-            ],
-          };
-        }
-        return null;
-      })
-      .filter(Boolean);
-
-    const convertedMessage = {
+    // One OpenAI-style tool message per result; the native layer maps them
+    // onto the matching assistant tool calls
+    return message.content.map((part) => ({
       role: "tool" as const,
-      content: JSON.stringify({ tool_calls: toolCalls }),
-    };
-
-    return convertedMessage;
+      name: part.toolName,
+      tool_call_id: part.toolCallId,
+      content:
+        typeof part.output === "string"
+          ? part.output
+          : JSON.stringify(part.output),
+    }));
   }
 
   private convertFallbackMessage(
@@ -1,5 +1,16 @@
+import AVFoundation
+import AppKit
 import Foundation
 import FoundationModels
+import ImageIO
+import ImagePlayground
+import NaturalLanguage
+import PDFKit
+import SoundAnalysis
+import Speech
+import Translation
+import UniformTypeIdentifiers
+import Vision
 
 // MARK: - C-compatible data structures
 
@@ -95,6 +106,2076 @@ public func appleAIGetSupportedLanguage(index: Int32) -> UnsafeMutablePointer<CC
     return strdup("Unknown")
 }
 
+// Whether the model supports a locale given as an identifier such as "de-DE" or "ja"
+@_cdecl("apple_ai_supports_locale")
+public func appleAISupportsLocale(_ identifier: UnsafePointer<CChar>) -> Bool {
+    let locale = Locale(identifier: String(cString: identifier))
+    return SystemLanguageModel.default.supportsLocale(locale)
+}
+
+private struct IdentifyLanguageOptions: Decodable {
+    let maxHypotheses: Int
+    let hints: [String: Double]?
+    let constraints: [String]?
+}
+
+// Languages `text` may be in, as JSON `{"language"?, "hypotheses": [{"language",
+// "probability"}]}` with BCP 47 codes such as "ja" or "zh-Hans", most likely first.
+// `language`, the dominant one, is missing when it can't be determined
+@_cdecl("apple_ai_identify_language")
+public func appleAIIdentifyLanguage(
+    _ text: UnsafePointer<CChar>, _ optionsJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            IdentifyLanguageOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    let recognizer = NLLanguageRecognizer()
+    if let hints = options.hints {
+        recognizer.languageHints = Dictionary(
+            uniqueKeysWithValues: hints.map { (NLLanguage(rawValue: $0.key), $0.value) })
+    }
+    if let constraints = options.constraints {
+        recognizer.languageConstraints = constraints.map(NLLanguage.init(rawValue:))
+    }
+    recognizer.processString(String(cString: text))
+    let hypotheses = recognizer.languageHypotheses(withMaximum: options.maxHypotheses)
+        .filter { $0.key != .undetermined }
+        .sorted { $0.value > $1.value }
+        .map { ["language": $0.key.rawValue, "probability": $0.value] as [String: Any] }
+    var result: [String: Any] = ["hypotheses": hypotheses]
+    if let language = recognizer.dominantLanguage, language != .undetermined {
+        result["language"] = language.rawValue
+    }
+    return jsonCString(result)
+}
+
+// Sentence embedding of `text` in `language` (detected when null), copied to `out` when it
+// fits in `capacity` floats. Returns the vector's dimension, 0 if the text couldn't be
+// embedded, or -1 if the system has no sentence embedding for the language
+@_cdecl("apple_ai_sentence_embedding")
+public func appleAISentenceEmbedding(
+    _ text: UnsafePointer<CChar>, _ language: UnsafePointer<CChar>?,
+    _ out: UnsafeMutablePointer<Float>, _ capacity: Int
+) -> Int {
+    let string = String(cString: text)
+    let nlLanguage = embeddingLanguage(of: string, language)
+    guard let embedding = NLEmbedding.sentenceEmbedding(for: nlLanguage) else { return -1 }
+    guard let vector = embedding.vector(for: string) else { return 0 }
+    return copyVector(vector, to: out, capacity: capacity)
+}
+
+// Contextual embeddings loaded so far, by language; loading one takes a while
+private var contextualEmbeddings: [NLLanguage: NLContextualEmbedding] = [:]
+private let contextualEmbeddingsLock = NSLock()
+
+// Contextual (transformer) embedding of `text`: the mean of its token vectors, copied to
+// `out` when it fits in `capacity` floats. Downloads the model's assets the first time a
+// script needs them. Returns the dimension, 0 if the text couldn't be embedded, -1 if no
+// contextual embedding covers the language, or -2 if its assets can't be downloaded
+@_cdecl("apple_ai_contextual_embedding")
+public func appleAIContextualEmbedding(
+    _ text: UnsafePointer<CChar>, _ language: UnsafePointer<CChar>?,
+    _ out: UnsafeMutablePointer<Float>, _ capacity: Int
+) -> Int {
+    let string = String(cString: text)
+    let nlLanguage = embeddingLanguage(of: string, language)
+    guard let embedding = loadedContextualEmbedding(for: nlLanguage) else {
+        return NLContextualEmbedding(language: nlLanguage) == nil ? -1 : -2
+    }
+    guard !string.isEmpty,
+        let result = try? embedding.embeddingResult(for: string, language: nlLanguage)
+    else { return 0 }
+    var sum = [Double](repeating: 0, count: embedding.dimension)
+    var tokens = 0
+    result.enumerateTokenVectors(in: string.startIndex..<string.endIndex) { vector, _ in
+        for (index, value) in vector.enumerated() where index < sum.count {
+            sum[index] += value
+        }
+        tokens += 1
+        return true
+    }
+    guard tokens > 0 else { return 0 }
+    return copyVector(sum.map { $0 / Double(tokens) }, to: out, capacity: capacity)
+}
+
+private func loadedContextualEmbedding(for language: NLLanguage) -> NLContextualEmbedding? {
+    contextualEmbeddingsLock.lock()
+    defer { contextualEmbeddingsLock.unlock() }
+    if let embedding = contextualEmbeddings[language] {
+        return embedding
+    }
+    guard let embedding = NLContextualEmbedding(language: language) else { return nil }
+    if !embedding.hasAvailableAssets {
+        let semaphore = DispatchSemaphore(value: 0)
+        var available = false
+        embedding.requestAssets { result, _ in
+            available = result == .available
+            semaphore.signal()
+        }
+        semaphore.wait()
+        guard available else { return nil }
+    }
+    guard (try? embedding.load()) != nil else { return nil }
+    contextualEmbeddings[language] = embedding
+    return embedding
+}
+
+// Word embeddings loaded so far, by language
+private var wordEmbeddings: [NLLanguage: NLEmbedding] = [:]
+private let wordEmbeddingsLock = NSLock()
+
+private func wordEmbedding(_ language: UnsafePointer<CChar>) -> NLEmbedding? {
+    let nlLanguage = NLLanguage(rawValue: String(cString: language))
+    wordEmbeddingsLock.lock()
+    defer { wordEmbeddingsLock.unlock() }
+    if let embedding = wordEmbeddings[nlLanguage] {
+        return embedding
+    }
+    let embedding = NLEmbedding.wordEmbedding(for: nlLanguage)
+    wordEmbeddings[nlLanguage] = embedding
+    return embedding
+}
+
+// Vector of `word` in the word embedding for `language`, copied to `out` when it fits in
+// `capacity` floats. Returns the dimension, 0 if the word isn't in the vocabulary, or -1 if
+// the system has no word embedding for the language
+@_cdecl("apple_ai_word_vector")
+public func appleAIWordVector(
+    _ word: UnsafePointer<CChar>, _ language: UnsafePointer<CChar>,
+    _ out: UnsafeMutablePointer<Float>, _ capacity: Int
+) -> Int {
+    guard let embedding = wordEmbedding(language) else { return -1 }
+    guard let vector = embedding.vector(for: String(cString: word)) else { return 0 }
+    return copyVector(vector, to: out, capacity: capacity)
+}
+
+// Cosine distance (0 to 2) between two words, -1 if the system has no word embedding for
+// `language`, or -2 if either word isn't in the vocabulary
+@_cdecl("apple_ai_word_distance")
+public func appleAIWordDistance(
+    _ first: UnsafePointer<CChar>, _ second: UnsafePointer<CChar>,
+    _ language: UnsafePointer<CChar>
+) -> Double {
+    guard let embedding = wordEmbedding(language) else { return -1 }
+    let a = String(cString: first)
+    let b = String(cString: second)
+    guard embedding.contains(a), embedding.contains(b) else { return -2 }
+    return embedding.distance(between: a, and: b, distanceType: .cosine)
+}
+
+// Up to `count` words nearest to `word` as JSON `[{"word","distance"}]`, nearest first and
+// empty if the word isn't in the vocabulary, or `{"error","code"}`
+@_cdecl("apple_ai_word_neighbors")
+public func appleAIWordNeighbors(
+    _ word: UnsafePointer<CChar>, _ language: UnsafePointer<CChar>, _ count: Int
+) -> UnsafeMutablePointer<CChar>? {
+    guard let embedding = wordEmbedding(language) else {
+        return jsonCString([
+            "error": "No word embedding is available for \(String(cString: language))",
+            "code": "UnsupportedLanguage",
+        ])
+    }
+    let neighbors = embedding.neighbors(
+        for: String(cString: word), maximumCount: count, distanceType: .cosine)
+    return jsonCString(neighbors.map { ["word": $0.0, "distance": $0.1] })
+}
+
+private func jsonCString(_ object: Any) -> UnsafeMutablePointer<CChar>? {
+    guard let data = try? JSONSerialization.data(withJSONObject: object),
+        let json = String(data: data, encoding: .utf8)
+    else { return nil }
+    return strdup(json)
+}
+
+// Words, sentences or paragraphs (`unit`) of `text` as JSON `[[start, end], ...]` UTF-8
+// byte offsets, split by NLTokenizer for `language` (detected when null)
+@_cdecl("apple_ai_token_ranges")
+public func appleAITokenRanges(
+    _ text: UnsafePointer<CChar>, _ unit: UnsafePointer<CChar>, _ language: UnsafePointer<CChar>?
+) -> UnsafeMutablePointer<CChar>? {
+    let string = String(cString: text)
+    let units: [String: NLTokenUnit] = ["word": .word, "sentence": .sentence, "paragraph": .paragraph]
+    guard let unit = units[String(cString: unit)] else { return nil }
+    let tokenizer = NLTokenizer(unit: unit)
+    tokenizer.string = string
+    if let language = language {
+        tokenizer.setLanguage(NLLanguage(rawValue: String(cString: language)))
+    }
+    let utf8 = string.utf8
+    let ranges = tokenizer.tokens(for: string.startIndex..<string.endIndex).map { range in
+        [
+            utf8.distance(from: utf8.startIndex, to: range.lowerBound),
+            utf8.distance(from: utf8.startIndex, to: range.upperBound),
+        ]
+    }
+    return jsonCString(ranges)
+}
+
+private struct SentimentOptions: Decodable {
+    let granularity: String
+    let language: String?
+}
+
+// Sentiment of `text` per document or sentence as JSON `[{"start", "end", "score"}]`, UTF-8
+// byte offsets and a score from -1 (negative) to 1 (positive), or `{"error","code"}`
+@_cdecl("apple_ai_sentiment")
+public func appleAISentiment(
+    _ text: UnsafePointer<CChar>, _ optionsJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            SentimentOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    let string = String(cString: text)
+    let tagger = NLTagger(tagSchemes: [.sentimentScore])
+    tagger.string = string
+    if let language = options.language {
+        tagger.setLanguage(NLLanguage(rawValue: language), range: string.startIndex..<string.endIndex)
+    }
+    let unit: NLTokenUnit = options.granularity == "sentence" ? .sentence : .document
+    let utf8 = string.utf8
+    var scores: [[String: Any]] = []
+    tagger.enumerateTags(
+        in: string.startIndex..<string.endIndex, unit: unit, scheme: .sentimentScore, options: []
+    ) { tag, range in
+        if let tag = tag, let score = Double(tag.rawValue) {
+            scores.append([
+                "start": utf8.distance(from: utf8.startIndex, to: range.lowerBound),
+                "end": utf8.distance(from: utf8.startIndex, to: range.upperBound),
+                "score": score,
+            ])
+        }
+        return true
+    }
+    return jsonCString(scores)
+}
+
+// Gazetteers created with `apple_ai_create_gazetteer`, by name, with their labels
+private var gazetteers: [String: (gazetteer: NLGazetteer, labels: Set<String>)] = [:]
+private let gazetteersLock = NSLock()
+
+// Compiles `entriesJSON` (`{"label": ["term", ...]}`) into a gazetteer for `language` (any
+// when null) and registers it as `name`, replacing one of that name. Returns `{}` or
+// `{"error","code"}`
+@_cdecl("apple_ai_create_gazetteer")
+public func appleAICreateGazetteer(
+    _ name: UnsafePointer<CChar>, _ entriesJSON: UnsafePointer<CChar>,
+    _ language: UnsafePointer<CChar>?
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let entries = try? JSONDecoder().decode(
+            [String: [String]].self, from: Data(String(cString: entriesJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid gazetteer entries", "code": "InvalidArg"])
+    }
+    let gazetteer: NLGazetteer
+    do {
+        gazetteer = try NLGazetteer(
+            dictionary: entries, language: language.map { NLLanguage(rawValue: String(cString: $0)) })
+    } catch {
+        return jsonCString([
+            "error": "Couldn't compile gazetteer: \(error.localizedDescription)",
+            "code": "InvalidArg",
+        ])
+    }
+    gazetteersLock.lock()
+    gazetteers[String(cString: name)] = (gazetteer, Set(entries.keys))
+    gazetteersLock.unlock()
+    return jsonCString([String: Any]())
+}
+
+// Unregisters the gazetteer `name`; false if there was none
+@_cdecl("apple_ai_remove_gazetteer")
+public func appleAIRemoveGazetteer(_ name: UnsafePointer<CChar>) -> Bool {
+    gazetteersLock.lock()
+    defer { gazetteersLock.unlock() }
+    return gazetteers.removeValue(forKey: String(cString: name)) != nil
+}
+
+// Attaches the named gazetteers to `tagger` for `scheme`, returning their labels, or nil
+// if one isn't registered
+private func attachGazetteers(
+    _ names: [String]?, to tagger: NLTagger, for scheme: NLTagScheme
+) -> Set<String>? {
+    guard let names = names, !names.isEmpty else { return [] }
+    gazetteersLock.lock()
+    let found = names.compactMap { gazetteers[$0] }
+    gazetteersLock.unlock()
+    guard found.count == names.count else { return nil }
+    tagger.setGazetteers(found.map(\.gazetteer), for: scheme)
+    return found.reduce(into: Set<String>()) { $0.formUnion($1.labels) }
+}
+
+private struct EntityOptions: Decodable {
+    let language: String?
+    let gazetteers: [String]?
+}
+
+// People, places and organizations named in `text` as JSON `[{"start", "end", "type"}]`,
+// UTF-8 byte offsets and `person`, `place`, `organization` or a label of one of the
+// `gazetteers`, or `{"error","code"}`
+@_cdecl("apple_ai_named_entities")
+public func appleAINamedEntities(
+    _ text: UnsafePointer<CChar>, _ optionsJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            EntityOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    let string = String(cString: text)
+    let tagger = NLTagger(tagSchemes: [.nameType])
+    tagger.string = string
+    if let language = options.language {
+        tagger.setLanguage(NLLanguage(rawValue: language), range: string.startIndex..<string.endIndex)
+    }
+    guard let labels = attachGazetteers(options.gazetteers, to: tagger, for: .nameType) else {
+        return jsonCString(["error": "Unknown gazetteer", "code": "InvalidArg"])
+    }
+    let types: [NLTag: String] = [
+        .personalName: "person", .placeName: "place", .organizationName: "organization",
+    ]
+    let utf8 = string.utf8
+    var entities: [[String: Any]] = []
+    tagger.enumerateTags(
+        in: string.startIndex..<string.endIndex, unit: .word, scheme: .nameType,
+        options: [.omitWhitespace, .omitPunctuation, .joinNames]
+    ) { tag, range in
+        if let tag = tag,
+            let type = types[tag] ?? (labels.contains(tag.rawValue) ? tag.rawValue : nil)
+        {
+            entities.append([
+                "start": utf8.distance(from: utf8.startIndex, to: range.lowerBound),
+                "end": utf8.distance(from: utf8.startIndex, to: range.upperBound),
+                "type": type,
+            ])
+        }
+        return true
+    }
+    return jsonCString(entities)
+}
+
+private struct TagTextOptions: Decodable {
+    let scheme: String
+    let language: String?
+    let omitPunctuation: Bool
+    let gazetteers: [String]?
+}
+
+// Words of `text` tagged with an NLTagger scheme (`lexicalClass`, `nameTypeOrLexicalClass`
+// or `lemma`) as JSON `[{"start", "end", "tag"?}]`, UTF-8 byte offsets, or `{"error","code"}`.
+// Words in the `gazetteers` are tagged with their labels
+@_cdecl("apple_ai_tag_text")
+public func appleAITagText(
+    _ text: UnsafePointer<CChar>, _ optionsJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            TagTextOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    let scheme = NLTagScheme(rawValue: options.scheme)
+    if let language = options.language,
+        !NLTagger.availableTagSchemes(for: .word, language: NLLanguage(rawValue: language))
+            .contains(scheme)
+    {
+        return jsonCString([
+            "error": "\(options.scheme) tagging isn't available for \(language)",
+            "code": "UnsupportedLanguage",
+        ])
+    }
+    let string = String(cString: text)
+    let tagger = NLTagger(tagSchemes: [scheme])
+    tagger.string = string
+    if let language = options.language {
+        tagger.setLanguage(NLLanguage(rawValue: language), range: string.startIndex..<string.endIndex)
+    }
+    guard attachGazetteers(options.gazetteers, to: tagger, for: scheme) != nil else {
+        return jsonCString(["error": "Unknown gazetteer", "code": "InvalidArg"])
+    }
+    var taggerOptions: NLTagger.Options = [.omitWhitespace]
+    if options.omitPunctuation {
+        taggerOptions.insert(.omitPunctuation)
+    }
+    if scheme == .nameTypeOrLexicalClass {
+        taggerOptions.insert(.joinNames)
+    }
+    let utf8 = string.utf8
+    var tokens: [[String: Any]] = []
+    tagger.enumerateTags(
+        in: string.startIndex..<string.endIndex, unit: .word, scheme: scheme,
+        options: taggerOptions
+    ) { tag, range in
+        var token: [String: Any] = [
+            "start": utf8.distance(from: utf8.startIndex, to: range.lowerBound),
+            "end": utf8.distance(from: utf8.startIndex, to: range.upperBound),
+        ]
+        token["tag"] = tag?.rawValue
+        tokens.append(token)
+        return true
+    }
+    return jsonCString(tokens)
+}
+
+private let dataDetectorTypes: [String: NSTextCheckingResult.CheckingType] = [
+    "date": .date, "address": .address, "link": .link, "phoneNumber": .phoneNumber,
+    "transitInformation": .transitInformation,
+]
+
+private let addressKeys: [NSTextCheckingKey: String] = [
+    .name: "name", .organization: "organization", .jobTitle: "jobTitle", .street: "street",
+    .city: "city", .state: "state", .zip: "postalCode", .country: "country",
+]
+
+// Dates, addresses, links, phone numbers and flights in `text` (`typesJSON` lists which) as
+// JSON `[{"type", "start", "end", ...values}]`, UTF-8 byte offsets, or `{"error","code"}`
+@_cdecl("apple_ai_detect_data")
+public func appleAIDetectData(
+    _ text: UnsafePointer<CChar>, _ typesJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let types = try? JSONDecoder().decode(
+            [String].self, from: Data(String(cString: typesJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid types", "code": "InvalidArg"])
+    }
+    let checkingTypes = types.compactMap { dataDetectorTypes[$0] }
+        .reduce(NSTextCheckingResult.CheckingType()) { $0.union($1) }
+    let detector: NSDataDetector
+    do {
+        detector = try NSDataDetector(types: checkingTypes.rawValue)
+    } catch {
+        return jsonCString(["error": error.localizedDescription, "code": "InvalidArg"])
+    }
+    let string = String(cString: text)
+    let utf8 = string.utf8
+    let iso = ISO8601DateFormatter()
+    var matches: [[String: Any]] = []
+    detector.enumerateMatches(in: string, range: NSRange(string.startIndex..., in: string)) {
+        result, _, _ in
+        guard let result, let range = Range(result.range, in: string) else { return }
+        var match: [String: Any] = [
+            "start": utf8.distance(from: utf8.startIndex, to: range.lowerBound),
+            "end": utf8.distance(from: utf8.startIndex, to: range.upperBound),
+        ]
+        switch result.resultType {
+        case .date:
+            guard let date = result.date else { return }
+            let timeZone = result.timeZone ?? .current
+            var calendar = Calendar(identifier: .gregorian)
+            calendar.timeZone = timeZone
+            let components = calendar.dateComponents(
+                [.year, .month, .day, .hour, .minute, .second, .weekday], from: date)
+            iso.timeZone = timeZone
+            match["type"] = "date"
+            match["date"] = iso.string(from: date)
+            match["timeZone"] = timeZone.identifier
+            match["components"] = [
+                "year": components.year ?? 0, "month": components.month ?? 0,
+                "day": components.day ?? 0, "hour": components.hour ?? 0,
+                "minute": components.minute ?? 0, "second": components.second ?? 0,
+                "weekday": components.weekday ?? 0,
+            ]
+            if result.duration > 0 { match["duration"] = result.duration }
+        case .address:
+            match["type"] = "address"
+            var address: [String: String] = [:]
+            for (key, value) in result.addressComponents ?? [:] {
+                if let name = addressKeys[key] { address[name] = value }
+            }
+            match["address"] = address
+        case .link:
+            guard let url = result.url else { return }
+            match["type"] = "link"
+            match["url"] = url.absoluteString
+        case .phoneNumber:
+            match["type"] = "phoneNumber"
+            match["phoneNumber"] = result.phoneNumber ?? ""
+        case .transitInformation:
+            match["type"] = "transitInformation"
+            match["airline"] = result.components?[.airline]
+            match["flight"] = result.components?[.flight]
+        default:
+            return
+        }
+        matches.append(match)
+    }
+    return jsonCString(matches)
+}
+
+private struct CheckTextOptions: Decodable {
+    let language: String?
+    let grammar: Bool
+    let maxSuggestions: Int
+}
+
+// NSSpellChecker's shared instance isn't safe to use from several threads at once
+private let spellCheckerLock = NSLock()
+
+// Misspellings and grammar problems in `text` as JSON `[{"kind": "spelling" | "grammar",
+// "start", "end", "message"?, "suggestions"}]`, UTF-8 byte offsets, or `{"error","code"}`
+@_cdecl("apple_ai_check_text")
+public func appleAICheckText(
+    _ text: UnsafePointer<CChar>, _ optionsJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            CheckTextOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    spellCheckerLock.lock()
+    defer { spellCheckerLock.unlock() }
+    let checker = NSSpellChecker.shared
+    // The checker names languages like "en_GB", or "en" for all of them
+    var language = options.language?.replacingOccurrences(of: "-", with: "_")
+    if let code = language {
+        let available = checker.availableLanguages
+        guard
+            let match = available.first(where: { $0 == code })
+                ?? available.first(where: { $0.hasPrefix(code + "_") })
+                ?? available.first(where: { code.hasPrefix($0 + "_") })
+        else {
+            return jsonCString([
+                "error": "Spell checking isn't available for \(options.language ?? code)",
+                "code": "UnsupportedLanguage",
+            ])
+        }
+        language = match
+    }
+    let string = String(cString: text)
+    let utf8 = string.utf8
+    let tag = NSSpellChecker.uniqueSpellDocumentTag()
+    defer { checker.closeSpellDocument(withTag: tag) }
+    var types: NSTextCheckingTypes = NSTextCheckingResult.CheckingType.spelling.rawValue
+    if options.grammar {
+        types |= NSTextCheckingResult.CheckingType.grammar.rawValue
+    }
+    let results = checker.check(
+        string, range: NSRange(string.startIndex..., in: string), types: types,
+        options: [:], inSpellDocumentWithTag: tag,
+        orthography: language.flatMap { NSOrthography.defaultOrthography(forLanguage: $0) },
+        wordCount: nil)
+    func issue(_ kind: String, _ range: NSRange, _ message: String?, _ suggestions: [String])
+        -> [String: Any]?
+    {
+        guard let bounds = Range(range, in: string) else { return nil }
+        var issue: [String: Any] = [
+            "kind": kind,
+            "start": utf8.distance(from: utf8.startIndex, to: bounds.lowerBound),
+            "end": utf8.distance(from: utf8.startIndex, to: bounds.upperBound),
+            "suggestions": Array(suggestions.prefix(options.maxSuggestions)),
+        ]
+        issue["message"] = message
+        return issue
+    }
+    var issues: [[String: Any]] = []
+    for result in results {
+        switch result.resultType {
+        case .spelling:
+            let guesses =
+                checker.guesses(
+                    forWordRange: result.range, in: string, language: language,
+                    inSpellDocumentWithTag: tag) ?? []
+            if let spelling = issue("spelling", result.range, nil, guesses) {
+                issues.append(spelling)
+            }
+        case .grammar:
+            for detail in result.grammarDetails ?? [] {
+                guard let detailRange = (detail[NSGrammarRange] as? NSValue)?.rangeValue else {
+                    continue
+                }
+                let range = NSRange(
+                    location: result.range.location + detailRange.location,
+                    length: detailRange.length)
+                if let grammar = issue(
+                    "grammar", range, detail[NSGrammarUserDescription] as? String,
+                    detail[NSGrammarCorrections] as? [String] ?? [])
+                {
+                    issues.append(grammar)
+                }
+            }
+        default:
+            break
+        }
+    }
+    return jsonCString(issues)
+}
+
+// Text of the document at `path` as JSON `{"pages": [...]}`: one string per page of a
+// PDF, from PDFKit, or one for a plain-text, Markdown, RTF, HTML or Word file.
+// `{"error": ...}` when it can't be read
+@_cdecl("apple_ai_extract_text")
+public func appleAIExtractText(_ path: UnsafePointer<CChar>) -> UnsafeMutablePointer<CChar>? {
+    let url = URL(fileURLWithPath: String(cString: path))
+    let failure = { (reason: String) in jsonCString(["error": reason]) }
+    switch url.pathExtension.lowercased() {
+    case "pdf":
+        guard let document = PDFDocument(url: url) else {
+            return failure("Not a readable PDF")
+        }
+        if document.isLocked {
+            return failure("The PDF is password-protected")
+        }
+        let pages = (0..<document.pageCount).map { document.page(at: $0)?.string ?? "" }
+        return jsonCString(["pages": pages])
+    case "txt", "text", "md", "markdown":
+        var encoding = String.Encoding.utf8
+        guard let text = try? String(contentsOf: url, usedEncoding: &encoding) else {
+            return failure("Cannot read the file as text")
+        }
+        return jsonCString(["pages": [text]])
+    default:
+        do {
+            let document = try NSAttributedString(url: url, options: [:], documentAttributes: nil)
+            return jsonCString(["pages": [document.string]])
+        } catch {
+            return failure(error.localizedDescription)
+        }
+    }
+}
+
+// MARK: - Vision
+
+/// How Vision entry points preprocess their image, from the options' `image`
+private struct VisionImageOptions: Decodable {
+    struct Preprocessing: Decodable {
+        let maxDimension: Int?
+        let orientation: String?
+    }
+    let image: Preprocessing?
+}
+
+// An upright image for Vision from encoded bytes (`data`, `length`) or, when `data` is
+// null, the file at `path`, oriented and scaled as `optionsJSON`'s `image` says
+private func visionImage(
+    _ data: UnsafePointer<UInt8>?, _ length: Int, _ path: UnsafePointer<CChar>?,
+    _ optionsJSON: UnsafePointer<CChar>
+) -> CGImage? {
+    let preprocessing = (try? JSONDecoder().decode(
+        VisionImageOptions.self, from: Data(String(cString: optionsJSON).utf8)))?.image
+    let source: CGImageSource?
+    if let data = data {
+        source = CGImageSourceCreateWithData(Data(bytes: data, count: length) as CFData, nil)
+    } else if let path = path {
+        source = CGImageSourceCreateWithURL(URL(fileURLWithPath: String(cString: path)) as CFURL, nil)
+    } else {
+        source = nil
+    }
+    guard let source else { return nil }
+    return decodeImage(
+        source, maxDimension: preprocessing?.maxDimension, orientation: preprocessing?.orientation)
+}
+
+// A Vision bounding box (normalized, origin at the bottom left) as JSON, origin at the top left
+private func topLeftBounds(_ box: CGRect) -> [String: Double] {
+    [
+        "x": Double(box.minX), "y": Double(1 - box.maxY),
+        "width": Double(box.width), "height": Double(box.height),
+    ]
+}
+
+private struct RecognizeTextOptions: Decodable {
+    let languages: [String]?
+    let level: String?
+    let languageCorrection: Bool?
+}
+
+// Text in an image, recognized by Vision, as JSON `{"blocks": [{"text", "confidence",
+// "bounds"}], "width", "height"}` with blocks in reading order. `{"error", "code"}`
+// when the image can't be read or a language isn't supported
+@_cdecl("apple_ai_recognize_text")
+public func appleAIRecognizeText(
+    _ data: UnsafePointer<UInt8>?, _ length: Int, _ path: UnsafePointer<CChar>?,
+    _ optionsJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            RecognizeTextOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    guard let image = visionImage(data, length, path, optionsJSON) else {
+        return jsonCString(["error": "Not a readable image", "code": "InvalidArg"])
+    }
+    let request = VNRecognizeTextRequest()
+    request.recognitionLevel = options.level == "fast" ? .fast : .accurate
+    request.usesLanguageCorrection = options.languageCorrection ?? true
+    if let languages = options.languages, !languages.isEmpty {
+        let supported = (try? request.supportedRecognitionLanguages()) ?? []
+        if let unsupported = languages.first(where: { !supported.contains($0) }) {
+            return jsonCString([
+                "error": "Text recognition doesn't support \(unsupported)",
+                "code": "UnsupportedLanguage",
+            ])
+        }
+        request.recognitionLanguages = languages
+    } else {
+        request.automaticallyDetectsLanguage = true
+    }
+    do {
+        try VNImageRequestHandler(cgImage: image).perform([request])
+    } catch {
+        return jsonCString(["error": error.localizedDescription])
+    }
+    let blocks: [[String: Any]] = (request.results ?? []).compactMap { observation in
+        guard let candidate = observation.topCandidates(1).first else { return nil }
+        return [
+            "text": candidate.string,
+            "confidence": Double(candidate.confidence),
+            "bounds": topLeftBounds(observation.boundingBox),
+        ]
+    }
+    return jsonCString(["blocks": blocks, "width": image.width, "height": image.height])
+}
+
+private struct ClassifyImageOptions: Decodable {
+    let topK: Int
+    let minConfidence: Double
+}
+
+// Labels of Vision's on-device image classifier for an image, as JSON
+// `{"labels": [{"identifier", "confidence"}]}`: the `topK` most confident that reach
+// `minConfidence`, most confident first
+@_cdecl("apple_ai_classify_image")
+public func appleAIClassifyImage(
+    _ data: UnsafePointer<UInt8>?, _ length: Int, _ path: UnsafePointer<CChar>?,
+    _ optionsJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            ClassifyImageOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    guard let image = visionImage(data, length, path, optionsJSON) else {
+        return jsonCString(["error": "Not a readable image", "code": "InvalidArg"])
+    }
+    let request = VNClassifyImageRequest()
+    do {
+        try VNImageRequestHandler(cgImage: image).perform([request])
+    } catch {
+        return jsonCString(["error": error.localizedDescription])
+    }
+    let labels = (request.results ?? [])
+        .filter { Double($0.confidence) >= options.minConfidence }
+        .sorted { $0.confidence > $1.confidence }
+        .prefix(options.topK)
+        .map { ["identifier": $0.identifier, "confidence": Double($0.confidence)] as [String: Any] }
+    return jsonCString(["labels": Array(labels)])
+}
+
+// Salient regions of an image as JSON `{"attention": [{"bounds", "confidence"}],
+// "objects": [...], "width", "height"}`: where people tend to look, from
+// attention-based saliency, and the main objects, from objectness-based saliency
+@_cdecl("apple_ai_analyze_saliency")
+public func appleAIAnalyzeSaliency(
+    _ data: UnsafePointer<UInt8>?, _ length: Int, _ path: UnsafePointer<CChar>?,
+    _ optionsJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard let image = visionImage(data, length, path, optionsJSON) else {
+        return jsonCString(["error": "Not a readable image", "code": "InvalidArg"])
+    }
+    let attention = VNGenerateAttentionBasedSaliencyImageRequest()
+    let objectness = VNGenerateObjectnessBasedSaliencyImageRequest()
+    do {
+        try VNImageRequestHandler(cgImage: image).perform([attention, objectness])
+    } catch {
+        return jsonCString(["error": error.localizedDescription])
+    }
+    let regions = { (request: VNImageBasedRequest) -> [[String: Any]] in
+        let observations = request.results as? [VNSaliencyImageObservation] ?? []
+        return observations.flatMap { $0.salientObjects ?? [] }.map {
+            ["bounds": topLeftBounds($0.boundingBox), "confidence": Double($0.confidence)]
+        }
+    }
+    return jsonCString([
+        "attention": regions(attention), "objects": regions(objectness),
+        "width": image.width, "height": image.height,
+    ])
+}
+
+// MARK: - Image generation
+
+private let imagePlaygroundStyles: [(String, ImagePlaygroundStyle)] = [
+    ("animation", .animation), ("illustration", .illustration), ("sketch", .sketch),
+]
+
+// `{"error", "code"}` for an error thrown by ImageCreator
+private func imageCreationFailure(_ error: Error) -> [String: Any] {
+    guard let error = error as? ImageCreator.Error else {
+        return ["error": error.localizedDescription, "code": "GenerationFailed"]
+    }
+    let (message, code): (String, String)
+    switch error {
+    case .notSupported:
+        (message, code) = ("Image generation is not supported on this device", "ImageGenerationUnavailable")
+    case .unavailable:
+        (message, code) = (
+            "Image generation is unavailable: Apple Intelligence is off or its assets aren't on the device",
+            "ImageGenerationUnavailable"
+        )
+    case .unsupportedLanguage:
+        (message, code) = ("The prompt's language is not supported for image generation", "UnsupportedLanguage")
+    case .backgroundCreationForbidden:
+        (message, code) = ("Images can't be generated while the app is in the background", "RateLimited")
+    case .creationCancelled:
+        (message, code) = ("Image generation was cancelled", "Cancelled")
+    case .faceInImageTooSmall, .unsupportedInputImage, .conceptsRequirePersonIdentity:
+        (message, code) = (error.localizedDescription, "InvalidArg")
+    case .creationFailed:
+        (message, code) = (error.localizedDescription, "GenerationFailed")
+    @unknown default:
+        (message, code) = (error.localizedDescription, "GenerationFailed")
+    }
+    return ["error": message, "code": code]
+}
+
+// Whether ImageCreator can generate images here, as JSON `{"available", "reason"?,
+// "styles"}`
+@_cdecl("apple_ai_image_generation_status")
+public func appleAIImageGenerationStatus() -> UnsafeMutablePointer<CChar>? {
+    let semaphore = DispatchSemaphore(value: 0)
+    var status: [String: Any] = [:]
+    Task {
+        do {
+            let creator = try await ImageCreator()
+            let styles = imagePlaygroundStyles.filter { creator.availableStyles.contains($0.1) }
+            status = ["available": true, "styles": styles.map(\.0)]
+        } catch {
+            status = [
+                "available": false, "reason": imageCreationFailure(error)["error"] ?? "",
+                "styles": [String](),
+            ]
+        }
+        semaphore.signal()
+    }
+    semaphore.wait()
+    return jsonCString(status)
+}
+
+private struct GenerateImageOptions: Decodable {
+    let prompt: String
+    let style: String?
+    let count: Int
+}
+
+// Generate up to `capacity` images for a prompt with ImageCreator. Each image is PNG
+// data in a malloc'd buffer stored in `images` with its size in `lengths`, for the
+// caller to free. Returns JSON `{"count"}`, or `{"error", "code"}`
+@_cdecl("apple_ai_generate_image")
+public func appleAIGenerateImage(
+    _ optionsJSON: UnsafePointer<CChar>,
+    _ images: UnsafeMutablePointer<UnsafeMutablePointer<UInt8>?>,
+    _ lengths: UnsafeMutablePointer<Int>,
+    _ capacity: Int
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            GenerateImageOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    let semaphore = DispatchSemaphore(value: 0)
+    var response: [String: Any] = [:]
+    Task {
+        defer { semaphore.signal() }
+        var count = 0
+        do {
+            let creator = try await ImageCreator()
+            let available = imagePlaygroundStyles.filter { creator.availableStyles.contains($0.1) }
+            guard
+                let style = options.style.map({ name in available.first { $0.0 == name } })
+                    ?? available.first
+            else {
+                let names = available.map(\.0).joined(separator: ", ")
+                response = [
+                    "error": available.isEmpty
+                        ? "No image styles are available"
+                        : "Style \(options.style ?? "") is not available; available: \(names)",
+                    "code": available.isEmpty ? "ImageGenerationUnavailable" : "InvalidArg",
+                ]
+                return
+            }
+            let created = creator.images(
+                for: [.text(options.prompt)], style: style.1, limit: min(options.count, capacity))
+            for try await image in created {
+                guard count < capacity, let png = pngData(image.cgImage) else { continue }
+                let buffer = malloc(png.count)!.assumingMemoryBound(to: UInt8.self)
+                png.copyBytes(to: buffer, count: png.count)
+                images[count] = buffer
+                lengths[count] = png.count
+                count += 1
+            }
+            response = ["count": count]
+        } catch {
+            (0..<count).forEach { free(images[$0]) }
+            response = imageCreationFailure(error)
+        }
+    }
+    semaphore.wait()
+    return jsonCString(response)
+}
+
+// MARK: - Speech
+
+private struct SpeechFailure: Error {
+    let message: String
+    let code: String
+
+    var json: [String: Any] { ["error": message, "code": code] }
+}
+
+private struct TranscribeOptions: Decodable {
+    let locale: String?
+    let contextualStrings: [String]?
+    let punctuation: Bool?
+}
+
+// Whether this process may use speech recognition, asking the user the first time
+private func speechAuthorization() -> SFSpeechRecognizerAuthorizationStatus {
+    let status = SFSpeechRecognizer.authorizationStatus()
+    guard status == .notDetermined else { return status }
+    let semaphore = DispatchSemaphore(value: 0)
+    var granted = status
+    SFSpeechRecognizer.requestAuthorization { status in
+        granted = status
+        semaphore.signal()
+    }
+    semaphore.wait()
+    return granted
+}
+
+// A recognizer for `locale` (default: the system's) that runs on the device, delivering
+// results on its own queue since Node never drains the main one
+private func onDeviceRecognizer(locale: String?) throws -> SFSpeechRecognizer {
+    switch speechAuthorization() {
+    case .authorized:
+        break
+    case .denied, .restricted:
+        throw SpeechFailure(
+            message: "Speech recognition is not allowed; turn it on for this app in "
+                + "System Settings > Privacy & Security > Speech Recognition",
+            code: "PermissionDenied")
+    default:
+        throw SpeechFailure(message: "Speech recognition was not authorized", code: "PermissionDenied")
+    }
+    let locale = locale.map(Locale.init(identifier:)) ?? Locale.current
+    guard let recognizer = SFSpeechRecognizer(locale: locale) else {
+        throw SpeechFailure(
+            message: "Speech recognition does not support \(locale.identifier)",
+            code: "UnsupportedLanguage")
+    }
+    guard recognizer.supportsOnDeviceRecognition else {
+        throw SpeechFailure(
+            message: "On-device speech recognition for \(recognizer.locale.identifier) is not "
+                + "installed; download it under System Settings > Keyboard > Dictation",
+            code: "AssetsUnavailable")
+    }
+    guard recognizer.isAvailable else {
+        throw SpeechFailure(message: "Speech recognition is not available right now", code: "ModelUnavailable")
+    }
+    recognizer.queue = OperationQueue()
+    return recognizer
+}
+
+// A request's on-device settings from the options
+private func configure(_ request: SFSpeechRecognitionRequest, _ options: TranscribeOptions) {
+    request.requiresOnDeviceRecognition = true
+    request.addsPunctuation = options.punctuation ?? true
+    request.contextualStrings = options.contextualStrings ?? []
+}
+
+private func transcriptionJSON(_ transcription: SFTranscription, locale: Locale) -> [String: Any] {
+    [
+        "text": transcription.formattedString,
+        "locale": locale.identifier,
+        "segments": transcription.segments.map { segment in
+            [
+                "text": segment.substring, "start": segment.timestamp,
+                "duration": segment.duration, "confidence": Double(segment.confidence),
+            ] as [String: Any]
+        },
+    ]
+}
+
+// Whether a recognition error only means the audio held no speech
+private func isNoSpeech(_ error: Error) -> Bool {
+    let error = error as NSError
+    return error.domain == "kAFAssistantErrorDomain" && error.code == 1110
+}
+
+// Transcribe the audio file at `path` on the device, as JSON `{"text", "locale",
+// "segments": [{"text", "start", "duration", "confidence"}]}`, or `{"error", "code"}`
+@_cdecl("apple_ai_transcribe_file")
+public func appleAITranscribeFile(
+    _ path: UnsafePointer<CChar>, _ optionsJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            TranscribeOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    let recognizer: SFSpeechRecognizer
+    do {
+        recognizer = try onDeviceRecognizer(locale: options.locale)
+    } catch {
+        return jsonCString((error as? SpeechFailure)?.json ?? ["error": error.localizedDescription])
+    }
+    let request = SFSpeechURLRecognitionRequest(url: URL(fileURLWithPath: String(cString: path)))
+    request.shouldReportPartialResults = false
+    configure(request, options)
+
+    let semaphore = DispatchSemaphore(value: 0)
+    let lock = NSLock()
+    var response: [String: Any]?
+    // The handler can be called again after the final result, e.g. with a cancellation
+    func finish(_ json: [String: Any]) {
+        lock.lock()
+        defer { lock.unlock() }
+        guard response == nil else { return }
+        response = json
+        semaphore.signal()
+    }
+    let task = recognizer.recognitionTask(with: request) { result, error in
+        if let result, result.isFinal {
+            finish(transcriptionJSON(result.bestTranscription, locale: recognizer.locale))
+        } else if let error {
+            finish(
+                isNoSpeech(error)
+                    ? ["text": "", "locale": recognizer.locale.identifier, "segments": [Any]()]
+                    : ["error": error.localizedDescription, "code": "GenerationFailed"])
+        }
+    }
+    semaphore.wait()
+    task.cancel()
+    return jsonCString(response ?? [:])
+}
+
+private struct StartTranscriptionOptions: Decodable {
+    let locale: String?
+    let contextualStrings: [String]?
+    let punctuation: Bool?
+    let pauseMs: Int
+
+    var recognition: TranscribeOptions {
+        TranscribeOptions(
+            locale: locale, contextualStrings: contextualStrings, punctuation: punctuation)
+    }
+}
+
+// Whether this process may record from the microphone, asking the user the first time
+private func microphoneAuthorized() -> Bool {
+    switch AVCaptureDevice.authorizationStatus(for: .audio) {
+    case .authorized:
+        return true
+    case .notDetermined:
+        let semaphore = DispatchSemaphore(value: 0)
+        var granted = false
+        AVCaptureDevice.requestAccess(for: .audio) { allowed in
+            granted = allowed
+            semaphore.signal()
+        }
+        semaphore.wait()
+        return granted
+    default:
+        return false
+    }
+}
+
+// Live transcription of the microphone. Each utterance is a recognition request fed from
+// the input tap; a pause of `pause` ends its audio, its final result is sent, and the next
+// request starts. All state changes happen on `queue`
+private final class MicrophoneTranscription {
+    private let recognizer: SFSpeechRecognizer
+    private let options: TranscribeOptions
+    private let pause: TimeInterval
+    private let onEvent: @convention(c) (UnsafeMutablePointer<CChar>?) -> Void
+    private let engine = AVAudioEngine()
+    private let queue = DispatchQueue(label: "apple-ai.transcription")
+    // The request the tap appends to, also read from the audio thread
+    private let requestLock = NSLock()
+    private var request: SFSpeechAudioBufferRecognitionRequest?
+    private var task: SFSpeechRecognitionTask?
+    private var pauseTimer: DispatchWorkItem?
+    private var stopping = false
+    private var ended = false
+
+    init(
+        recognizer: SFSpeechRecognizer, options: StartTranscriptionOptions,
+        onEvent: @escaping @convention(c) (UnsafeMutablePointer<CChar>?) -> Void
+    ) {
+        self.recognizer = recognizer
+        self.options = options.recognition
+        self.pause = Double(options.pauseMs) / 1000
+        self.onEvent = onEvent
+        let callbacks = OperationQueue()
+        callbacks.underlyingQueue = queue
+        recognizer.queue = callbacks
+    }
+
+    func start() throws {
+        let input = engine.inputNode
+        let format = input.outputFormat(forBus: 0)
+        guard format.channelCount > 0 else {
+            throw SpeechFailure(message: "No microphone is available", code: "ModelUnavailable")
+        }
+        queue.sync { beginUtterance() }
+        input.installTap(onBus: 0, bufferSize: 1024, format: format) { [weak self] buffer, _ in
+            guard let self else { return }
+            self.requestLock.lock()
+            defer { self.requestLock.unlock() }
+            self.request?.append(buffer)
+        }
+        engine.prepare()
+        do {
+            try engine.start()
+        } catch {
+            input.removeTap(onBus: 0)
+            queue.sync {
+                ended = true
+                task?.cancel()
+                setRequest(nil)
+            }
+            throw SpeechFailure(
+                message: "Cannot start the microphone: \(error.localizedDescription)",
+                code: "GenerationFailed")
+        }
+    }
+
+    // Stop listening; the utterance in progress ends with its final result
+    func stop() {
+        queue.async { [self] in
+            guard !stopping else { return }
+            stopping = true
+            engine.stop()
+            engine.inputNode.removeTap(onBus: 0)
+            pauseTimer?.cancel()
+            guard let request = currentRequest() else { return finish() }
+            request.endAudio()
+            // The recognizer sends nothing more for audio without speech on some systems
+            queue.asyncAfter(deadline: .now() + 3) { [self] in finish() }
+        }
+    }
+
+    private func currentRequest() -> SFSpeechAudioBufferRecognitionRequest? {
+        requestLock.lock()
+        defer { requestLock.unlock() }
+        return request
+    }
+
+    private func setRequest(_ request: SFSpeechAudioBufferRecognitionRequest?) {
+        requestLock.lock()
+        self.request = request
+        requestLock.unlock()
+    }
+
+    private func beginUtterance() {
+        let request = SFSpeechAudioBufferRecognitionRequest()
+        request.shouldReportPartialResults = true
+        configure(request, options)
+        setRequest(request)
+        task = recognizer.recognitionTask(with: request) { [weak self] result, error in
+            self?.handle(result, error, of: request)
+        }
+    }
+
+    private func handle(
+        _ result: SFSpeechRecognitionResult?, _ error: Error?,
+        of request: SFSpeechAudioBufferRecognitionRequest
+    ) {
+        guard !ended, request === currentRequest() else { return }
+        if let result {
+            let text = result.bestTranscription.formattedString
+            if result.isFinal {
+                if !text.isEmpty { send("final", result.bestTranscription) }
+                nextUtterance()
+            } else {
+                send("partial", result.bestTranscription)
+                pauseTimer?.cancel()
+                let timer = DispatchWorkItem { request.endAudio() }
+                pauseTimer = timer
+                queue.asyncAfter(deadline: .now() + pause, execute: timer)
+            }
+        } else if let error {
+            if isNoSpeech(error) || stopping {
+                nextUtterance()
+            } else {
+                emit(["type": "error", "error": error.localizedDescription, "code": "GenerationFailed"])
+                engine.stop()
+                engine.inputNode.removeTap(onBus: 0)
+                finish()
+            }
+        }
+    }
+
+    private func nextUtterance() {
+        pauseTimer?.cancel()
+        if stopping {
+            finish()
+        } else {
+            beginUtterance()
+        }
+    }
+
+    private func send(_ type: String, _ transcription: SFTranscription) {
+        var event = transcriptionJSON(transcription, locale: recognizer.locale)
+        event["type"] = type
+        emit(event)
+    }
+
+    private func emit(_ event: [String: Any]) {
+        onEvent(jsonCString(event))
+    }
+
+    private func finish() {
+        guard !ended else { return }
+        ended = true
+        task?.cancel()
+        setRequest(nil)
+        microphoneTranscriptionLock.lock()
+        if microphoneTranscription === self { microphoneTranscription = nil }
+        microphoneTranscriptionLock.unlock()
+        emit(["type": "end"])
+    }
+}
+
+private let microphoneTranscriptionLock = NSLock()
+private var microphoneTranscription: MicrophoneTranscription?
+
+// Start transcribing the microphone on the device, sending each event to `onEvent` as
+// JSON for the callee to free: `{"type": "partial" | "final", "text", "locale",
+// "segments"}`, `{"type": "error", "error", "code"}`, and `{"type": "end"}` last.
+// Returns `{}` once listening, or `{"error", "code"}`
+@_cdecl("apple_ai_start_transcription")
+public func appleAIStartTranscription(
+    _ optionsJSON: UnsafePointer<CChar>,
+    _ onEvent: @escaping @convention(c) (UnsafeMutablePointer<CChar>?) -> Void
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            StartTranscriptionOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    microphoneTranscriptionLock.lock()
+    let running = microphoneTranscription != nil
+    microphoneTranscriptionLock.unlock()
+    guard !running else {
+        return jsonCString(["error": "A transcription is already running", "code": "ConcurrentRequests"])
+    }
+    do {
+        let recognizer = try onDeviceRecognizer(locale: options.locale)
+        guard microphoneAuthorized() else {
+            throw SpeechFailure(
+                message: "Microphone access is not allowed; turn it on for this app in "
+                    + "System Settings > Privacy & Security > Microphone",
+                code: "PermissionDenied")
+        }
+        let transcription = MicrophoneTranscription(
+            recognizer: recognizer, options: options, onEvent: onEvent)
+        microphoneTranscriptionLock.lock()
+        microphoneTranscription = transcription
+        microphoneTranscriptionLock.unlock()
+        do {
+            try transcription.start()
+        } catch {
+            microphoneTranscriptionLock.lock()
+            microphoneTranscription = nil
+            microphoneTranscriptionLock.unlock()
+            throw error
+        }
+        return jsonCString([String: Any]())
+    } catch {
+        return jsonCString((error as? SpeechFailure)?.json ?? ["error": error.localizedDescription])
+    }
+}
+
+// Stop the microphone transcription; returns whether one was running
+@_cdecl("apple_ai_stop_transcription")
+public func appleAIStopTranscription() -> Bool {
+    microphoneTranscriptionLock.lock()
+    let transcription = microphoneTranscription
+    microphoneTranscriptionLock.unlock()
+    transcription?.stop()
+    return transcription != nil
+}
+
+// MARK: - Sound classification
+
+private struct SoundOptions: Decodable {
+    let topK: Int
+    let minConfidence: Double
+    let windowDuration: Double?
+    let overlap: Double?
+}
+
+// The built-in classifier's request, with the window duration nearest the one asked for
+// that it supports
+private func soundRequest(_ options: SoundOptions) throws -> SNClassifySoundRequest {
+    let request: SNClassifySoundRequest
+    do {
+        request = try SNClassifySoundRequest(classifierIdentifier: .version1)
+    } catch {
+        throw SpeechFailure(
+            message: "The sound classifier isn't available: \(error.localizedDescription)",
+            code: "ModelUnavailable")
+    }
+    if let seconds = options.windowDuration {
+        var window = CMTime(seconds: seconds, preferredTimescale: 48_000)
+        let constraint = request.windowDurationConstraint
+        switch constraint.type {
+        case .enumeratedDurations:
+            window =
+                constraint.enumeratedDurations.min {
+                    abs($0.seconds - seconds) < abs($1.seconds - seconds)
+                } ?? window
+        case .durationRange:
+            window = CMTimeClampToRange(window, range: constraint.durationRange)
+        @unknown default:
+            break
+        }
+        request.windowDuration = window
+    }
+    if let overlap = options.overlap {
+        request.overlapFactor = overlap
+    }
+    return request
+}
+
+// Collects or forwards the classifier's windows as `{"start", "duration",
+// "classifications": [{"identifier", "confidence"}]}`, leaving out those with nothing
+// above `minConfidence`
+private final class SoundObserver: NSObject, SNResultsObserving {
+    private let options: SoundOptions
+    private let onWindow: ([String: Any]) -> Void
+    private let onError: (Error) -> Void
+    private let onComplete: () -> Void
+
+    init(
+        options: SoundOptions, onWindow: @escaping ([String: Any]) -> Void,
+        onError: @escaping (Error) -> Void, onComplete: @escaping () -> Void
+    ) {
+        self.options = options
+        self.onWindow = onWindow
+        self.onError = onError
+        self.onComplete = onComplete
+    }
+
+    func request(_ request: SNRequest, didProduce result: SNResult) {
+        guard let result = result as? SNClassificationResult else { return }
+        let classifications = result.classifications
+            .filter { $0.confidence >= options.minConfidence }
+            .sorted { $0.confidence > $1.confidence }
+            .prefix(options.topK)
+            .map { ["identifier": $0.identifier, "confidence": $0.confidence] as [String: Any] }
+        guard !classifications.isEmpty else { return }
+        onWindow([
+            "start": result.timeRange.start.seconds,
+            "duration": result.timeRange.duration.seconds,
+            "classifications": Array(classifications),
+        ])
+    }
+
+    func request(_ request: SNRequest, didFailWithError error: Error) {
+        onError(error)
+    }
+
+    func requestDidComplete(_ request: SNRequest) {
+        onComplete()
+    }
+}
+
+// Sounds in the audio file at `path` as JSON `{"windows": [...]}` in time order, or
+// `{"error", "code"}`
+@_cdecl("apple_ai_classify_sound_file")
+public func appleAIClassifySoundFile(
+    _ path: UnsafePointer<CChar>, _ optionsJSON: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            SoundOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    do {
+        let request = try soundRequest(options)
+        let analyzer: SNAudioFileAnalyzer
+        do {
+            analyzer = try SNAudioFileAnalyzer(url: URL(fileURLWithPath: String(cString: path)))
+        } catch {
+            throw SpeechFailure(
+                message: "Cannot read audio: \(error.localizedDescription)", code: "InvalidArg")
+        }
+        var windows: [[String: Any]] = []
+        var failure: Error?
+        let observer = SoundObserver(
+            options: options, onWindow: { windows.append($0) }, onError: { failure = $0 },
+            onComplete: {})
+        try analyzer.add(request, withObserver: observer)
+        analyzer.analyze()
+        if let failure {
+            throw SpeechFailure(
+                message: "Sound classification failed: \(failure.localizedDescription)",
+                code: "GenerationFailed")
+        }
+        return jsonCString(["windows": windows])
+    } catch {
+        return jsonCString((error as? SpeechFailure)?.json ?? ["error": error.localizedDescription])
+    }
+}
+
+// Live sound classification of the microphone: the input tap feeds a stream analyzer on
+// `queue`, with times from when listening started
+private final class MicrophoneSoundClassification {
+    private let options: SoundOptions
+    private let onEvent: @convention(c) (UnsafeMutablePointer<CChar>?) -> Void
+    private let engine = AVAudioEngine()
+    private let queue = DispatchQueue(label: "apple-ai.sound-classification")
+    private var analyzer: SNAudioStreamAnalyzer?
+    private var observer: SoundObserver?
+    private var firstFrame: AVAudioFramePosition?
+    private var stopping = false
+    private var ended = false
+
+    init(
+        options: SoundOptions,
+        onEvent: @escaping @convention(c) (UnsafeMutablePointer<CChar>?) -> Void
+    ) {
+        self.options = options
+        self.onEvent = onEvent
+    }
+
+    func start() throws {
+        let input = engine.inputNode
+        let format = input.outputFormat(forBus: 0)
+        guard format.channelCount > 0 else {
+            throw SpeechFailure(message: "No microphone is available", code: "ModelUnavailable")
+        }
+        let request = try soundRequest(options)
+        let analyzer = SNAudioStreamAnalyzer(format: format)
+        let observer = SoundObserver(
+            options: options,
+            onWindow: { [weak self] window in
+                var event = window
+                event["type"] = "window"
+                self?.emit(event)
+            },
+            onError: { [weak self] error in
+                guard let self else { return }
+                self.emit([
+                    "type": "error", "error": error.localizedDescription, "code": "GenerationFailed",
+                ])
+                self.stop()
+            },
+            onComplete: { [weak self] in self?.finish() })
+        try analyzer.add(request, withObserver: observer)
+        self.analyzer = analyzer
+        self.observer = observer
+        input.installTap(onBus: 0, bufferSize: 8192, format: format) { [weak self] buffer, when in
+            guard let self else { return }
+            self.queue.async {
+                guard !self.stopping else { return }
+                let first = self.firstFrame ?? when.sampleTime
+                self.firstFrame = first
+                analyzer.analyze(buffer, atAudioFramePosition: when.sampleTime - first)
+            }
+        }
+        engine.prepare()
+        do {
+            try engine.start()
+        } catch {
+            input.removeTap(onBus: 0)
+            queue.sync { ended = true }
+            throw SpeechFailure(
+                message: "Cannot start the microphone: \(error.localizedDescription)",
+                code: "GenerationFailed")
+        }
+    }
+
+    // Stop listening; windows of the audio heard so far are still sent, then `end`
+    func stop() {
+        queue.async { [self] in
+            guard !stopping else { return }
+            stopping = true
+            engine.stop()
+            engine.inputNode.removeTap(onBus: 0)
+            // Calls requestDidComplete, which finishes
+            analyzer?.completeAnalysis()
+            finish()
+        }
+    }
+
+    private func emit(_ event: [String: Any]) {
+        onEvent(jsonCString(event))
+    }
+
+    private func finish() {
+        guard !ended else { return }
+        ended = true
+        analyzer?.removeAllRequests()
+        microphoneSoundClassificationLock.lock()
+        if microphoneSoundClassification === self { microphoneSoundClassification = nil }
+        microphoneSoundClassificationLock.unlock()
+        emit(["type": "end"])
+    }
+}
+
+private let microphoneSoundClassificationLock = NSLock()
+private var microphoneSoundClassification: MicrophoneSoundClassification?
+
+// Start classifying sounds from the microphone, sending each event to `onEvent` as JSON for
+// the callee to free: `{"type": "window", "start", "duration", "classifications"}`,
+// `{"type": "error", "error", "code"}`, and `{"type": "end"}` last. Returns `{}` once
+// listening, or `{"error", "code"}`
+@_cdecl("apple_ai_start_sound_classification")
+public func appleAIStartSoundClassification(
+    _ optionsJSON: UnsafePointer<CChar>,
+    _ onEvent: @escaping @convention(c) (UnsafeMutablePointer<CChar>?) -> Void
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            SoundOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    microphoneSoundClassificationLock.lock()
+    let running = microphoneSoundClassification != nil
+    microphoneSoundClassificationLock.unlock()
+    guard !running else {
+        return jsonCString([
+            "error": "A sound classification is already running", "code": "ConcurrentRequests",
+        ])
+    }
+    do {
+        guard microphoneAuthorized() else {
+            throw SpeechFailure(
+                message: "Microphone access is not allowed; turn it on for this app in "
+                    + "System Settings > Privacy & Security > Microphone",
+                code: "PermissionDenied")
+        }
+        let classification = MicrophoneSoundClassification(options: options, onEvent: onEvent)
+        microphoneSoundClassificationLock.lock()
+        microphoneSoundClassification = classification
+        microphoneSoundClassificationLock.unlock()
+        do {
+            try classification.start()
+        } catch {
+            microphoneSoundClassificationLock.lock()
+            microphoneSoundClassification = nil
+            microphoneSoundClassificationLock.unlock()
+            throw error
+        }
+        return jsonCString([String: Any]())
+    } catch {
+        return jsonCString((error as? SpeechFailure)?.json ?? ["error": error.localizedDescription])
+    }
+}
+
+// Stop the microphone sound classification; returns whether one was running
+@_cdecl("apple_ai_stop_sound_classification")
+public func appleAIStopSoundClassification() -> Bool {
+    microphoneSoundClassificationLock.lock()
+    let classification = microphoneSoundClassification
+    microphoneSoundClassificationLock.unlock()
+    classification?.stop()
+    return classification != nil
+}
+
+// MARK: - Speech synthesis
+
+private let voiceQualities: [AVSpeechSynthesisVoiceQuality: String] = [
+    .default: "default", .enhanced: "enhanced", .premium: "premium",
+]
+
+private let voiceGenders: [AVSpeechSynthesisVoiceGender: String] = [
+    .male: "male", .female: "female",
+]
+
+// Whether personal voices can be listed and used, asking the user first when `ask` is set
+private func personalVoiceStatus(ask: Bool) -> String {
+    var status = AVSpeechSynthesizer.personalVoiceAuthorizationStatus
+    if status == .notDetermined && ask {
+        let semaphore = DispatchSemaphore(value: 0)
+        AVSpeechSynthesizer.requestPersonalVoiceAuthorization { answer in
+            status = answer
+            semaphore.signal()
+        }
+        semaphore.wait()
+    }
+    switch status {
+    case .authorized: return "authorized"
+    case .denied: return "denied"
+    case .unsupported: return "unsupported"
+    default: return "notDetermined"
+    }
+}
+
+private struct ListVoicesOptions: Decodable {
+    let language: String?
+    let requestPersonalVoice: Bool?
+}
+
+// The voices speech synthesis has, as JSON `{"voices": [{"identifier", "name",
+// "language", "quality", "gender"?, "personal"}], "personalVoice"}`, where
+// `personalVoice` is the authorization to use the user's personal voices
+@_cdecl("apple_ai_list_voices")
+public func appleAIListVoices(_ optionsJSON: UnsafePointer<CChar>) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            ListVoicesOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    let personal = personalVoiceStatus(ask: options.requestPersonalVoice ?? false)
+    let language = options.language?.lowercased()
+    let voices = AVSpeechSynthesisVoice.speechVoices()
+        .filter { voice in
+            guard let language else { return true }
+            let code = voice.language.lowercased()
+            return code == language || code.hasPrefix(language + "-")
+        }
+        .map { voice -> [String: Any] in
+            var entry: [String: Any] = [
+                "identifier": voice.identifier, "name": voice.name, "language": voice.language,
+                "quality": voiceQualities[voice.quality] ?? "default",
+                "personal": voice.voiceTraits.contains(.isPersonalVoice),
+            ]
+            entry["gender"] = voiceGenders[voice.gender]
+            return entry
+        }
+    return jsonCString(["voices": voices, "personalVoice": personal])
+}
+
+private struct SpeechSynthesisOptions: Decodable {
+    let voice: String?
+    let language: String?
+    let rate: Float?
+    let pitch: Float?
+    let volume: Float?
+}
+
+// An utterance of `text` as the options say, or a failure for a voice that isn't there
+private func utterance(_ text: String, _ options: SpeechSynthesisOptions) throws -> AVSpeechUtterance {
+    let utterance = AVSpeechUtterance(string: text)
+    if let identifier = options.voice {
+        guard let voice = AVSpeechSynthesisVoice(identifier: identifier) else {
+            throw SpeechFailure(message: "No voice with identifier \(identifier)", code: "InvalidArg")
+        }
+        utterance.voice = voice
+    } else if let language = options.language {
+        guard let voice = AVSpeechSynthesisVoice(language: language) else {
+            throw SpeechFailure(message: "No voice speaks \(language)", code: "UnsupportedLanguage")
+        }
+        utterance.voice = voice
+    }
+    if let rate = options.rate {
+        utterance.rate =
+            AVSpeechUtteranceMinimumSpeechRate
+            + (AVSpeechUtteranceMaximumSpeechRate - AVSpeechUtteranceMinimumSpeechRate) * rate
+    }
+    utterance.pitchMultiplier = options.pitch ?? 1
+    utterance.volume = options.volume ?? 1
+    return utterance
+}
+
+// Interleaved 16-bit samples of a PCM buffer
+private func int16Samples(_ buffer: AVAudioPCMBuffer) -> [Int16] {
+    let frames = Int(buffer.frameLength)
+    let channels = Int(buffer.format.channelCount)
+    let interleaved = buffer.format.isInterleaved
+    var samples = [Int16](repeating: 0, count: frames * channels)
+    if let data = buffer.int16ChannelData {
+        for frame in 0..<frames {
+            for channel in 0..<channels {
+                samples[frame * channels + channel] =
+                    interleaved ? data[0][frame * channels + channel] : data[channel][frame]
+            }
+        }
+    } else if let data = buffer.floatChannelData {
+        for frame in 0..<frames {
+            for channel in 0..<channels {
+                let value = interleaved ? data[0][frame * channels + channel] : data[channel][frame]
+                samples[frame * channels + channel] = Int16(max(-1, min(1, value)) * Float(Int16.max))
+            }
+        }
+    }
+    return samples
+}
+
+// A WAV file of 16-bit PCM samples
+private func wavData(_ samples: [Int16], sampleRate: Int, channels: Int) -> Data {
+    var data = Data()
+    func append<T: FixedWidthInteger>(_ value: T) {
+        withUnsafeBytes(of: value.littleEndian) { data.append(contentsOf: $0) }
+    }
+    let bytes = samples.count * 2
+    data.append(contentsOf: Array("RIFF".utf8))
+    append(UInt32(36 + bytes))
+    data.append(contentsOf: Array("WAVEfmt ".utf8))
+    append(UInt32(16))
+    append(UInt16(1))
+    append(UInt16(channels))
+    append(UInt32(sampleRate))
+    append(UInt32(sampleRate * channels * 2))
+    append(UInt16(channels * 2))
+    append(UInt16(16))
+    data.append(contentsOf: Array("data".utf8))
+    append(UInt32(bytes))
+    samples.forEach { append($0) }
+    return data
+}
+
+// Stops a synthesis from another thread
+private final class SpeechStop {
+    private let lock = NSLock()
+    private var stopped = false
+    private var action: (() -> Void)?
+
+    var isStopped: Bool {
+        lock.lock()
+        defer { lock.unlock() }
+        return stopped
+    }
+
+    func stop() {
+        lock.lock()
+        stopped = true
+        let action = self.action
+        lock.unlock()
+        action?()
+    }
+
+    // Run `action` on `stop()`, or now if already stopped
+    func onStop(_ action: @escaping () -> Void) {
+        lock.lock()
+        self.action = action
+        let stopped = self.stopped
+        lock.unlock()
+        if stopped { action() }
+    }
+}
+
+// Speak `text` into audio, calling `onBuffer` with each buffer as it's synthesized and
+// `onMarkers` with the markers of words as they're reached, and return once synthesis is
+// done or `stop` stops it. The synthesizer calls back on its own queue; no callback runs
+// after this returns
+private func synthesize(
+    _ text: String, _ options: SpeechSynthesisOptions,
+    stop: SpeechStop? = nil,
+    onMarkers: (([AVSpeechSynthesisMarker]) -> Void)? = nil,
+    onBuffer: @escaping (AVAudioPCMBuffer) -> Void
+) throws {
+    let utterance = try utterance(text, options)
+    let synthesizer = AVSpeechSynthesizer()
+    let semaphore = DispatchSemaphore(value: 0)
+    let lock = NSLock()
+    var finished = false
+    // Runs `body` unless synthesis has finished, ending it when `body` returns true
+    func unlessFinished(_ body: () -> Bool) {
+        lock.lock()
+        defer { lock.unlock() }
+        guard !finished else { return }
+        if body() {
+            finished = true
+            semaphore.signal()
+        }
+    }
+    let bufferCallback: (AVAudioBuffer) -> Void = { buffer in
+        guard let buffer = buffer as? AVAudioPCMBuffer else { return }
+        unlessFinished {
+            // An empty buffer marks the end
+            guard buffer.frameLength > 0 else { return true }
+            onBuffer(buffer)
+            return false
+        }
+    }
+    if let onMarkers {
+        synthesizer.write(utterance, toBufferCallback: bufferCallback) { markers in
+            unlessFinished {
+                onMarkers(markers.filter { $0.mark == .word })
+                return false
+            }
+        }
+    } else {
+        synthesizer.write(utterance, toBufferCallback: bufferCallback)
+    }
+    stop?.onStop {
+        unlessFinished { true }
+        synthesizer.stopSpeaking(at: .immediate)
+    }
+    semaphore.wait()
+    withExtendedLifetime(synthesizer) {}
+}
+
+private let speechStreamsLock = NSLock()
+private var speechStreams: [UInt64: SpeechStop] = [:]
+
+// Speak `text` on the device, streaming as it's synthesized: each buffer goes to `onEvent`
+// as JSON `{"type": "audio", "sampleRate", "channels"}` with its 16-bit PCM samples in
+// `audio` and `length`, and each word, just before the audio it starts in, as `{"type":
+// "word", "start", "length", "time"}` with its range in UTF-16 code units and start in
+// seconds. `context` is passed back on every call, which all happen before this returns.
+// Returns `{"stopped"}` when done, or `{"error", "code"}`
+@_cdecl("apple_ai_stream_speech")
+public func appleAIStreamSpeech(
+    _ id: UInt64, _ text: UnsafePointer<CChar>, _ optionsJSON: UnsafePointer<CChar>,
+    _ context: UnsafeMutableRawPointer?,
+    _ onEvent: @convention(c) (
+        UnsafeMutableRawPointer?, UnsafeMutablePointer<CChar>?, UnsafePointer<UInt8>?, Int
+    ) -> Void
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            SpeechSynthesisOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    let stop = SpeechStop()
+    speechStreamsLock.lock()
+    speechStreams[id] = stop
+    speechStreamsLock.unlock()
+    defer {
+        speechStreamsLock.lock()
+        speechStreams[id] = nil
+        speechStreamsLock.unlock()
+    }
+
+    // Words wait until the audio they start in, whose format gives their time
+    var pending: [AVSpeechSynthesisMarker] = []
+    var seconds = 0.0
+    func emitWords(before end: Double, bytesPerSecond: Double) {
+        while let marker = pending.first,
+            bytesPerSecond == 0 || Double(marker.byteSampleOffset) / bytesPerSecond < end
+        {
+            pending.removeFirst()
+            let time = bytesPerSecond > 0 ? Double(marker.byteSampleOffset) / bytesPerSecond : seconds
+            let event: [String: Any] = [
+                "type": "word", "start": marker.textRange.location,
+                "length": marker.textRange.length, "time": time,
+            ]
+            onEvent(context, jsonCString(event), nil, 0)
+        }
+    }
+    var bytesPerSecond = 0.0
+    do {
+        try synthesize(
+            String(cString: text), options, stop: stop,
+            onMarkers: { pending += $0 }
+        ) { buffer in
+            let format = buffer.format
+            bytesPerSecond = Double(format.streamDescription.pointee.mBytesPerFrame) * format.sampleRate
+            let duration = Double(buffer.frameLength) / format.sampleRate
+            emitWords(before: seconds + duration, bytesPerSecond: bytesPerSecond)
+            seconds += duration
+            let event: [String: Any] = [
+                "type": "audio", "sampleRate": Int(format.sampleRate),
+                "channels": Int(format.channelCount),
+            ]
+            int16Samples(buffer).withUnsafeBytes { bytes in
+                onEvent(
+                    context, jsonCString(event),
+                    bytes.baseAddress?.assumingMemoryBound(to: UInt8.self), bytes.count)
+            }
+        }
+    } catch {
+        return jsonCString((error as? SpeechFailure)?.json ?? ["error": error.localizedDescription])
+    }
+    if !stop.isStopped {
+        emitWords(before: .infinity, bytesPerSecond: bytesPerSecond)
+    }
+    return jsonCString(["stopped": stop.isStopped])
+}
+
+// Stop the speech stream `id`; returns whether it was running
+@_cdecl("apple_ai_stop_speech")
+public func appleAIStopSpeech(_ id: UInt64) -> Bool {
+    speechStreamsLock.lock()
+    let stop = speechStreams[id]
+    speechStreamsLock.unlock()
+    stop?.stop()
+    return stop != nil
+}
+
+// Speak `text` on the device into WAV data, in a malloc'd buffer stored in `audio` with
+// its size in `length` for the caller to free. Returns JSON `{"sampleRate"}`, or
+// `{"error", "code"}`
+@_cdecl("apple_ai_synthesize_speech")
+public func appleAISynthesizeSpeech(
+    _ text: UnsafePointer<CChar>, _ optionsJSON: UnsafePointer<CChar>,
+    _ audio: UnsafeMutablePointer<UnsafeMutablePointer<UInt8>?>,
+    _ length: UnsafeMutablePointer<Int>
+) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            SpeechSynthesisOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    var samples: [Int16] = []
+    var sampleRate = 22_050
+    var channels = 1
+    do {
+        try synthesize(String(cString: text), options) { buffer in
+            sampleRate = Int(buffer.format.sampleRate)
+            channels = Int(buffer.format.channelCount)
+            samples += int16Samples(buffer)
+        }
+    } catch {
+        return jsonCString((error as? SpeechFailure)?.json ?? ["error": error.localizedDescription])
+    }
+    let wav = wavData(samples, sampleRate: sampleRate, channels: channels)
+    let buffer = malloc(wav.count)!.assumingMemoryBound(to: UInt8.self)
+    wav.copyBytes(to: buffer, count: wav.count)
+    audio.pointee = buffer
+    length.pointee = wav.count
+    return jsonCString(["sampleRate": sampleRate])
+}
+
+// MARK: - Translation
+
+private func runBlocking(_ body: @escaping () async -> [String: Any]) -> UnsafeMutablePointer<CChar>? {
+    let semaphore = DispatchSemaphore(value: 0)
+    var response: [String: Any] = [:]
+    Task {
+        response = await body()
+        semaphore.signal()
+    }
+    semaphore.wait()
+    return jsonCString(response)
+}
+
+private func translationStatusName(_ status: LanguageAvailability.Status) -> String {
+    switch status {
+    case .installed: return "installed"
+    case .supported: return "supported"
+    default: return "unsupported"
+    }
+}
+
+// Languages translation supports, as JSON `{"languages": [{"code", "name"}]}` with names
+// in the user's language
+@_cdecl("apple_ai_translation_languages")
+public func appleAITranslationLanguages() -> UnsafeMutablePointer<CChar>? {
+    runBlocking {
+        let languages = await LanguageAvailability().supportedLanguages
+        return [
+            "languages": languages.map { language -> [String: Any] in
+                let code = language.minimalIdentifier
+                return ["code": code, "name": Locale.current.localizedString(forIdentifier: code) ?? code]
+            }
+        ]
+    }
+}
+
+// Whether translating `from` into `to` works offline, as JSON `{"status": "installed" |
+// "supported" | "unsupported"}`, where `supported` pairs need their languages downloaded
+@_cdecl("apple_ai_translation_status")
+public func appleAITranslationStatus(
+    _ from: UnsafePointer<CChar>, _ to: UnsafePointer<CChar>
+) -> UnsafeMutablePointer<CChar>? {
+    let source = Locale.Language(identifier: String(cString: from))
+    let target = Locale.Language(identifier: String(cString: to))
+    return runBlocking {
+        let status = await LanguageAvailability().status(from: source, to: target)
+        return ["status": translationStatusName(status)]
+    }
+}
+
+// Open the Language & Region settings, where translation languages are downloaded
+@_cdecl("apple_ai_open_translation_settings")
+public func appleAIOpenTranslationSettings() -> Bool {
+    guard let url = URL(string: "x-apple.systempreferences:com.apple.Localization-Settings.extension")
+    else { return false }
+    return NSWorkspace.shared.open(url)
+}
+
+private struct TranslateOptions: Decodable {
+    let texts: [String]
+    let from: String?
+    let to: String
+}
+
+// Translate texts on the device with installed languages, as JSON `{"translations",
+// "from"}`, or `{"error", "code"}`. The source language is detected when not given
+@_cdecl("apple_ai_translate")
+public func appleAITranslate(_ optionsJSON: UnsafePointer<CChar>) -> UnsafeMutablePointer<CChar>? {
+    guard
+        let options = try? JSONDecoder().decode(
+            TranslateOptions.self, from: Data(String(cString: optionsJSON).utf8))
+    else {
+        return jsonCString(["error": "Invalid options", "code": "InvalidArg"])
+    }
+    var sourceCode = options.from
+    if sourceCode == nil {
+        let recognizer = NLLanguageRecognizer()
+        recognizer.processString(options.texts.joined(separator: "\n"))
+        sourceCode = recognizer.dominantLanguage.flatMap { $0 == .undetermined ? nil : $0.rawValue }
+    }
+    guard let sourceCode else {
+        return jsonCString([
+            "error": "Cannot tell which language the text is in; pass `from`", "code": "InvalidArg",
+        ])
+    }
+    let source = Locale.Language(identifier: sourceCode)
+    let target = Locale.Language(identifier: options.to)
+    return runBlocking {
+        switch await LanguageAvailability().status(from: source, to: target) {
+        case .installed:
+            break
+        case .supported:
+            return [
+                "error": "Translation from \(sourceCode) to \(options.to) needs its languages "
+                    + "downloaded; see downloadLanguagePair",
+                "code": "AssetsUnavailable",
+            ]
+        default:
+            return [
+                "error": "Translation from \(sourceCode) to \(options.to) is not supported",
+                "code": "UnsupportedLanguage",
+            ]
+        }
+        let session = TranslationSession(installedSource: source, target: target)
+        do {
+            var translations: [String] = []
+            for text in options.texts {
+                translations.append(try await session.translate(text).targetText)
+            }
+            return ["translations": translations, "from": source.minimalIdentifier]
+        } catch {
+            return ["error": error.localizedDescription, "code": "GenerationFailed"]
+        }
+    }
+}
+
+// The language to embed `text` as: the one given, else the text's dominant language,
+// else English
+private func embeddingLanguage(of text: String, _ language: UnsafePointer<CChar>?) -> NLLanguage {
+    if let language = language {
+        return NLLanguage(rawValue: String(cString: language))
+    }
+    let recognizer = NLLanguageRecognizer()
+    recognizer.processString(text)
+    return recognizer.dominantLanguage.flatMap { $0 == .undetermined ? nil : $0 } ?? .english
+}
+
+private func copyVector(_ vector: [Double], to out: UnsafeMutablePointer<Float>, capacity: Int) -> Int {
+    if vector.count <= capacity {
+        for (index, value) in vector.enumerated() {
+            out[index] = Float(value)
+        }
+    }
+    return vector.count
+}
+
 @_cdecl("apple_ai_free_string")
 public func appleAIFreeString(ptr: UnsafeMutablePointer<CChar>?) {
     if let ptr = ptr {
@@ -188,21 +2269,101 @@ struct Guardrails {
 
 /// Centralized conversation preparation logic used by all message-based functions
 private struct ConversationContext {
+    let requestID: UInt64
     let currentPrompt: String
+    let prefix: String?
     let transcriptEntries: [Transcript.Entry]
     let options: GenerationOptions
+    /// Base model or the adapter the request selected
+    let model: SystemLanguageModel
+
+    /// Prompt sent to the model, asking it to open with the prefill if there is one
+    var prompt: String {
+        guard let prefix, !prefix.isEmpty else { return currentPrompt }
+        return currentPrompt + "\n\nBegin your response with exactly: \(prefix)"
+    }
+
+    /// Text the model produced after the prefill. While the output is still a
+    /// partial echo of the prefix nothing is visible yet.
+    func continuation(of text: String) -> String {
+        guard let prefix, !prefix.isEmpty else { return text }
+        if text.hasPrefix(prefix) { return String(text.dropFirst(prefix.count)) }
+        if prefix.hasPrefix(text) { return "" }
+        return text
+    }
 }
 
 private enum ConversationError: Error {
     case intelligenceUnavailable(String)
     case invalidJSON(String)
     case noMessages
+    case multimodalUnsupported
+    case adapterUnloaded
+}
+
+/// The system language model does not take image input in the current
+/// FoundationModels release; flip this once a multimodal model ships.
+private func supportsImageInput() -> Bool {
+    return false
+}
+
+@_cdecl("apple_ai_supports_image_input")
+public func appleAISupportsImageInput() -> Bool {
+    return supportsImageInput()
+}
+
+// Context window of the system model when the OS doesn't report it
+private let DEFAULT_CONTEXT_SIZE = 4096
+
+@_cdecl("apple_ai_model_limits")
+public func appleAIModelLimits() -> UnsafeMutablePointer<CChar>? {
+    var contextSize = DEFAULT_CONTEXT_SIZE
+    var reported = false
+    if #available(macOS 26.4, *) {
+        contextSize = SystemLanguageModel.default.contextSize
+        reported = true
+    }
+    // Prompt and output share the window, so output can use all of it at most
+    return strdup(
+        #"{"contextWindow":\#(contextSize),"maxOutputTokens":\#(contextSize),"#
+            + #""reported":\#(reported),"exactTokenCounts":\#(reported),"#
+            + #""supportsImageInput":\#(supportsImageInput())}"#)
+}
+
+@_cdecl("apple_ai_model_info")
+public func appleAIModelInfo() -> UnsafeMutablePointer<CChar>? {
+    // Newest framework feature level this system provides
+    var frameworkLevel = "26.0"
+    if #available(macOS 26.4, *) {
+        frameworkLevel = "26.4"
+    }
+    let info: [String: Any] = [
+        "osVersion": ProcessInfo.processInfo.operatingSystemVersionString,
+        "frameworkLevel": frameworkLevel,
+        "model": "default",
+        "useCase": "general",
+        "available": SystemLanguageModel.default.isAvailable,
+    ]
+    guard let data = try? JSONSerialization.data(withJSONObject: info, options: [.sortedKeys]),
+        let json = String(data: data, encoding: .utf8)
+    else { return nil }
+    return strdup(json)
 }
 
 private func prepareConversationContext(
+    requestID: UInt64,
     messagesJsonString: String,
+    imageBuffers: [Data],
+    prefix: String?,
     temperature: Double,
-    maxTokens: Int32
+    maxTokens: Int32,
+    topP: Double,
+    topK: Int32,
+    seed: Int64,
+    greedy: Bool,
+    adapterID: UInt64,
+    useCase: Int32,
+    guardrails: Int32
 ) throws -> ConversationContext {
     if DEBUG_LOGS {
         print("\n=== DEBUG: PARSING MESSAGES ===")
@@ -243,6 +2404,13 @@ private func prepareConversationContext(
     guard !messages.isEmpty else {
         throw ConversationError.noMessages
     }
+    let images = messages.flatMap { $0.images ?? [] }
+    if !images.isEmpty && !supportsImageInput() {
+        throw ConversationError.multimodalUnsupported
+    }
+    guard images.allSatisfy({ $0.bytes(buffers: imageBuffers) != nil }) else {
+        throw ConversationError.invalidJSON("Invalid image data")
+    }
 
     if DEBUG_LOGS {
         print("Parsed \(messages.count) messages:")
@@ -276,38 +2444,149 @@ private func prepareConversationContext(
     } else if maxTokens > 0 {
         options.maximumResponseTokens = Int(maxTokens)
     }
+    let samplingSeed: UInt64? = seed >= 0 ? UInt64(seed) : nil
+    if greedy {
+        options.sampling = .greedy
+    } else if topK > 0 {
+        options.sampling = .random(top: Int(topK), seed: samplingSeed)
+    } else if topP > 0 {
+        options.sampling = .random(probabilityThreshold: topP, seed: samplingSeed)
+    } else if let samplingSeed {
+        options.sampling = .random(probabilityThreshold: 1.0, seed: samplingSeed)
+    }
 
     return ConversationContext(
+        requestID: requestID,
         currentPrompt: currentPrompt,
+        prefix: prefix,
         transcriptEntries: transcriptEntries,
-        options: options
+        options: options,
+        model: try makeModel(adapterID: adapterID, useCase: useCase, guardrails: guardrails)
     )
 }
 
+/// Longest edge, in pixels, of message images when no `maxDimension` is given
+private let defaultMaxImageDimension = 1024
+
+/// Image attached to a user message: inline as base64, an index into the raw
+/// buffers passed alongside the messages, or a file on disk
+private struct ImageInput: Codable {
+    let data: String?
+    let buffer: Int?
+    let path: String?
+    let maxDimension: Int?
+    let orientation: String?
+    let mimeType: String?
+
+    /// The image decoded, oriented, downscaled and encoded as PNG
+    func bytes(buffers: [Data]) -> Data? {
+        let source: CGImageSource?
+        if let buffer {
+            guard buffers.indices.contains(buffer) else { return nil }
+            source = CGImageSourceCreateWithData(buffers[buffer] as CFData, nil)
+        } else if let path {
+            source = CGImageSourceCreateWithURL(URL(fileURLWithPath: path) as CFURL, nil)
+        } else {
+            source = data.flatMap { Data(base64Encoded: $0) }
+                .flatMap { CGImageSourceCreateWithData($0 as CFData, nil) }
+        }
+        guard let source,
+            let image = decodeImage(
+                source, maxDimension: maxDimension ?? defaultMaxImageDimension,
+                orientation: orientation)
+        else { return nil }
+        return pngData(image)
+    }
+}
+
+/// Orientations that override an image's EXIF orientation, named as in EXIF: how the
+/// stored pixels must be turned to stand upright
+private let orientationOverrides: [String: CGImagePropertyOrientation] = [
+    "up": .up, "right": .right, "down": .down, "left": .left,
+]
+
+/// Decode the first image of `source` with ImageIO, in any format it reads (PNG, JPEG,
+/// HEIC, TIFF, WebP, GIF, …), and turn it upright: by its EXIF orientation when
+/// `orientation` is nil or "auto", else by the orientation named. Downscales it so
+/// the longest edge is at most `maxDimension`, when given.
+private func decodeImage(_ source: CGImageSource, maxDimension: Int?, orientation: String?)
+    -> CGImage?
+{
+    let override = orientation.flatMap { orientationOverrides[$0] }
+    var options: [CFString: Any] = [
+        kCGImageSourceCreateThumbnailFromImageAlways: true,
+        kCGImageSourceCreateThumbnailWithTransform: override == nil,
+    ]
+    if let maxDimension {
+        options[kCGImageSourceThumbnailMaxPixelSize] = maxDimension
+    }
+    guard let image = CGImageSourceCreateThumbnailAtIndex(source, 0, options as CFDictionary)
+    else { return nil }
+    guard let override, override != .up else { return image }
+    return rotated(image, override)
+}
+
+/// `image` turned upright from `orientation`: `.right` turns it a quarter clockwise,
+/// `.left` a quarter counterclockwise and `.down` half a turn
+private func rotated(_ image: CGImage, _ orientation: CGImagePropertyOrientation) -> CGImage? {
+    let (width, height) = (CGFloat(image.width), CGFloat(image.height))
+    let sideways = orientation == .right || orientation == .left
+    guard
+        let context = CGContext(
+            data: nil, width: sideways ? image.height : image.width,
+            height: sideways ? image.width : image.height, bitsPerComponent: 8, bytesPerRow: 0,
+            space: CGColorSpaceCreateDeviceRGB(),
+            bitmapInfo: CGImageAlphaInfo.premultipliedLast.rawValue)
+    else { return nil }
+    // Core Graphics angles run counterclockwise
+    context.translateBy(x: CGFloat(context.width) / 2, y: CGFloat(context.height) / 2)
+    switch orientation {
+    case .right: context.rotate(by: -.pi / 2)
+    case .left: context.rotate(by: .pi / 2)
+    default: context.rotate(by: .pi)
+    }
+    context.draw(image, in: CGRect(x: -width / 2, y: -height / 2, width: width, height: height))
+    return context.makeImage()
+}
+
+private func pngData(_ image: CGImage) -> Data? {
+    let output = NSMutableData()
+    guard
+        let destination = CGImageDestinationCreateWithData(
+            output, UTType.png.identifier as CFString, 1, nil)
+    else { return nil }
+    CGImageDestinationAddImage(destination, image, nil)
+    guard CGImageDestinationFinalize(destination) else { return nil }
+    return output as Data
+}
+
 private struct ChatMessage: Codable {
     let role: String
     let content: String?  // Made optional to support OpenAI format with tool calls
     let name: String?
     let tool_call_id: String?  // OpenAI-compatible snake_case
     let tool_calls: [[String: Any]]?  // OpenAI-compatible tool calls array
+    let images: [ImageInput]?
 
     init(
         role: String,
         content: String? = nil,
         name: String? = nil,
         tool_call_id: String? = nil,
-        tool_calls: [[String: Any]]? = nil
+        tool_calls: [[String: Any]]? = nil,
+        images: [ImageInput]? = nil
     ) {
         self.role = role
         self.content = content
         self.name = name
         self.tool_call_id = tool_call_id
         self.tool_calls = tool_calls
+        self.images = images
     }
 
     // Custom encoding/decoding to handle the dynamic tool_calls array
     enum CodingKeys: String, CodingKey {
-        case role, content, name, tool_call_id, tool_calls
+        case role, content, name, tool_call_id, tool_calls, images
     }
 
     init(from decoder: Decoder) throws {
@@ -316,6 +2595,7 @@ private struct ChatMessage: Codable {
         content = try container.decodeIfPresent(String.self, forKey: .content)  // Made optional
         name = try container.decodeIfPresent(String.self, forKey: .name)
         tool_call_id = try container.decodeIfPresent(String.self, forKey: .tool_call_id)
+        images = try container.decodeIfPresent([ImageInput].self, forKey: .images)
 
         // Properly decode tool_calls if present
         if container.contains(.tool_calls) {
@@ -332,6 +2612,7 @@ private struct ChatMessage: Codable {
         try container.encode(content, forKey: .content)
         try container.encodeIfPresent(name, forKey: .name)
         try container.encodeIfPresent(tool_call_id, forKey: .tool_call_id)
+        try container.encodeIfPresent(images, forKey: .images)
         // tool_calls encoding would need custom handling
     }
 }
@@ -339,7 +2620,13 @@ private struct ChatMessage: Codable {
 private func convertMessagesToTranscript(_ messages: [ChatMessage]) -> [Transcript.Entry] {
     var entries: [Transcript.Entry] = []
 
-    // Skip system messages - they will be handled separately with tools
+    // All system messages become the session's instructions; tools mode
+    // rebuilds this entry to attach its tool definitions.
+    let systemContent = systemInstructions(in: messages)
+    if !systemContent.isEmpty {
+        entries.append(.instructions(createInstructions(from: systemContent)))
+    }
+
     let nonSystemMessages = messages.filter { $0.role.lowercased() != "system" }
 
     // Debug: Log conversion start
@@ -485,8 +2772,16 @@ private func convertMessagesToTranscript(_ messages: [ChatMessage]) -> [Transcri
     return entries
 }
 
-private func createInstructions(from message: ChatMessage) -> Transcript.Instructions {
-    let textSegment = Transcript.TextSegment(content: message.content ?? "")
+private func systemInstructions(in messages: [ChatMessage]) -> String {
+    messages
+        .filter { $0.role.lowercased() == "system" }
+        .compactMap { $0.content }
+        .filter { !$0.isEmpty }
+        .joined(separator: "\n\n")
+}
+
+private func createInstructions(from content: String) -> Transcript.Instructions {
+    let textSegment = Transcript.TextSegment(content: content)
     return Transcript.Instructions(
         segments: [.text(textSegment)],
         toolDefinitions: []
@@ -660,31 +2955,102 @@ private func createToolOutputEntry(from message: ChatMessage) -> [Transcript.Ent
             segments: transcriptSegments
         )
 
-        entries.append(.toolOutput(toolOutput))
+        entries.append(.toolOutput(toolOutput))
+    }
+
+    return entries
+}
+
+/// Stable code for an error, matching Rust's `ErrorCode`
+private func errorCode(_ error: Error) -> String {
+    switch error {
+    case is CancellationError:
+        return "Cancelled"
+    case let error as ConversationError:
+        switch error {
+        case .intelligenceUnavailable: return "ModelUnavailable"
+        case .invalidJSON, .noMessages: return "InvalidArg"
+        case .multimodalUnsupported: return "MultimodalUnsupported"
+        case .adapterUnloaded: return "AdapterNotFound"
+        }
+    case let error as LanguageModelSession.GenerationError:
+        switch error {
+        case .exceededContextWindowSize: return "ContextWindowExceeded"
+        case .assetsUnavailable: return "AssetsUnavailable"
+        case .guardrailViolation: return "GuardrailViolation"
+        case .unsupportedGuide: return "UnsupportedGuide"
+        case .unsupportedLanguageOrLocale: return "UnsupportedLanguage"
+        case .decodingFailure: return "DecodingFailure"
+        case .rateLimited: return "RateLimited"
+        case .concurrentRequests: return "ConcurrentRequests"
+        case .refusal: return "Refusal"
+        @unknown default: return "GenerationFailed"
+        }
+    case is LanguageModelSession.ToolCallError:
+        return "ToolFailure"
+    default:
+        return "GenerationFailed"
     }
+}
 
-    return entries
+private func errorMessage(_ error: Error) -> String {
+    switch error {
+    case is CancellationError:
+        return "Generation cancelled"
+    case ConversationError.intelligenceUnavailable(let reason):
+        return "Apple Intelligence not available - \(reason)"
+    case ConversationError.invalidJSON(let reason):
+        return reason
+    case ConversationError.noMessages:
+        return "No messages provided"
+    case ConversationError.multimodalUnsupported:
+        return "Multimodal input is not supported on this system"
+    case ConversationError.adapterUnloaded:
+        return "The adapter was unloaded before the request started"
+    default:
+        return error.localizedDescription
+    }
 }
 
-// Control-B (0x02) sentinel prefix marks an error string in streaming callbacks
-private let ERROR_SENTINEL: Character = "\u{0002}"
+/// Error result of a non-streaming request, prefixed with its code: `Error: [Code] message`
+private func describeError(_ error: Error) -> String {
+    return "Error: [\(errorCode(error))] \(errorMessage(error))"
+}
+
+/// Chunk callback of a streaming request: request id and a chunk the
+/// receiver frees, or nil at the end of the stream. The id lets Rust route
+/// chunks of concurrent streams.
+public typealias ChunkCallback = @convention(c) (UInt64, UnsafeMutablePointer<CChar>?) -> Void
+
+/// Object callback of a streaming structured request: request id and a JSON
+/// snapshot of the object generated so far, only valid during the call.
+public typealias ObjectCallback = @convention(c) (UInt64, UnsafePointer<CChar>?) -> Void
+
+/// Error callback of a streaming request: request id, code, message. The
+/// strings are only valid during the call.
+public typealias ErrorCallback = @convention(c) (
+    UInt64, UnsafePointer<CChar>?, UnsafePointer<CChar>?
+) -> Void
 
+/// End a stream with an error; no end-of-stream marker follows
 @inline(__always)
-private func emitError(
-    _ message: String, to onChunk: (@convention(c) (UnsafePointer<CChar>?) -> Void)
-) {
-    let full = String(ERROR_SENTINEL) + message
-    full.withCString { cStr in
-        onChunk(strdup(cStr))
+private func emitError(_ error: Error, requestID: UInt64, to onError: ErrorCallback) {
+    errorCode(error).withCString { code in
+        errorMessage(error).withCString { message in
+            onError(requestID, code, message)
+        }
     }
 }
 
 // MARK: - JS Tool Callback Bridge
 
-// Simple async callback - Rust calls this, expects result via separate callback
+// Async callback - Rust returns immediately and later delivers the result for
+// `callID` through `apple_ai_tool_result_callback`. The request id and tool
+// name let Rust route the call to the handler for it.
 public typealias JSToolCallback =
     @convention(c) (
-        _ toolID: UInt64, _ argsJson: UnsafePointer<CChar>
+        _ requestID: UInt64, _ callID: UInt64, _ toolID: UInt64, _ toolName: UnsafePointer<CChar>,
+        _ argsJson: UnsafePointer<CChar>
     ) -> Void
 
 private var jsToolCallback: JSToolCallback?
@@ -695,6 +3061,73 @@ public func appleAIRegisterToolCallback(_ cb: JSToolCallback?) {
     jsToolCallback = cb
 }
 
+/// How a tool call handed to JS ended.
+private enum ToolOutcome {
+    case success(String)
+    case failure(String)
+
+    /// Text the model sees as the tool's output. Failures are reported as an
+    /// error object so the model can recover or explain what went wrong.
+    var output: String {
+        switch self {
+        case .success(let result):
+            return result
+        case .failure(let message):
+            guard let data = try? JSONSerialization.data(withJSONObject: ["error": message]),
+                let json = String(data: data, encoding: .utf8)
+            else {
+                return "Tool call failed"
+            }
+            return json
+        }
+    }
+}
+
+/// Tool calls handed to JS and suspended until their result arrives. Waiting
+/// on a continuation rather than blocking keeps slow tools from pinning threads.
+private final class PendingToolCalls {
+    static let shared = PendingToolCalls()
+    private let queue = DispatchQueue(label: "pending.tool.calls")
+    private var nextCallID: UInt64 = 1
+    private var continuations: [UInt64: CheckedContinuation<ToolOutcome, Never>] = [:]
+    private var cancelledBeforeStart = Set<UInt64>()
+
+    /// Allocate a call id, pass it to `send`, and suspend until `resume` is
+    /// called for it or the surrounding task is cancelled.
+    func dispatch(_ send: (UInt64) -> Void) async -> ToolOutcome {
+        let callID = queue.sync { () -> UInt64 in
+            defer { nextCallID += 1 }
+            return nextCallID
+        }
+        return await withTaskCancellationHandler {
+            await withCheckedContinuation { continuation in
+                let cancelled = queue.sync { () -> Bool in
+                    if cancelledBeforeStart.remove(callID) != nil { return true }
+                    continuations[callID] = continuation
+                    return false
+                }
+                if cancelled {
+                    continuation.resume(returning: .failure("Tool call cancelled"))
+                } else {
+                    send(callID)
+                }
+            }
+        } onCancel: {
+            if !self.resume(callID, with: .failure("Tool call cancelled")) {
+                _ = self.queue.sync { self.cancelledBeforeStart.insert(callID) }
+            }
+        }
+    }
+
+    /// Deliver the result for `callID`; returns false if nothing was waiting on it.
+    @discardableResult
+    func resume(_ callID: UInt64, with result: ToolOutcome) -> Bool {
+        let continuation = queue.sync { continuations.removeValue(forKey: callID) }
+        continuation?.resume(returning: result)
+        return continuation != nil
+    }
+}
+
 // MARK: - Proxy Tool implementation bridging to JS
 
 @available(macOS 26.0, *)
@@ -709,6 +3142,7 @@ private struct JSArguments: ConvertibleFromGeneratedContent {
 private struct JSProxyTool: Tool {
     typealias Arguments = JSArguments
 
+    let requestID: UInt64
     let toolID: UInt64
     let name: String
     let description: String
@@ -729,21 +3163,22 @@ private struct JSProxyTool: Tool {
             return "Unable to process tool arguments"
         }
 
-        // Notify JavaScript side for collection and external execution
-        jsonStr.withCString { cb(toolID, $0) }
+        // Hand the call to JavaScript and wait for its result without blocking a thread
+        let outcome = await PendingToolCalls.shared.dispatch { callID in
+            name.withCString { namePtr in
+                jsonStr.withCString { cb(requestID, callID, toolID, namePtr, $0) }
+            }
+        }
 
         // Collect this tool call for post-processing
-        if let argsDict = jsonObj as? [String: Any] {
-            ToolCallCollector.shared.append(id: toolID, name: name, arguments: argsDict)
-        } else {
-            ToolCallCollector.shared.append(id: toolID, name: name, arguments: [:])
-        }
+        ToolCallCollector.shared.append(
+            requestID: requestID, id: toolID, name: name,
+            arguments: jsonObj as? [String: Any] ?? [:])
 
         // Signal completion to streaming coordinator for early termination
-        await StreamingCoordinator.shared.toolCompleted()
+        await StreamingCoordinator.shared.toolCompleted(requestID)
 
-        // Return placeholder output to allow generation to continue naturally
-        return "Tool call executed"
+        return outcome.output
     }
 }
 
@@ -843,34 +3278,44 @@ private struct AnyCodable: Codable {
 
 @available(macOS 26.0, *)
 private func convertJSONSchemaToDynamic(_ dict: [String: Any], name: String? = nil)
-    -> DynamicGenerationSchema
+    throws -> DynamicGenerationSchema
 {
     // Handle references (not fully implemented)
     if let ref = dict["$ref"] as? String {
         return .init(referenceTo: ref)
     }
 
-    if let anyOf = dict["anyOf"] as? [[String: Any]] {
+    // Unions: `oneOf` is generated like `anyOf`, the model produces one alternative
+    if let anyOf = (dict["anyOf"] ?? dict["oneOf"]) as? [[String: Any]] {
+        let unionName = name ?? dict["title"] as? String ?? UUID().uuidString
         // Detect simple string enum union
         var stringChoices: [String] = []
         var dynamicChoices: [DynamicGenerationSchema] = []
-        for choice in anyOf {
+        for (index, choice) in anyOf.enumerated() {
             if let enums = choice["enum"] as? [String], enums.count == 1 {
                 stringChoices.append(enums[0])
+            } else if let constant = choice["const"] as? String {
+                stringChoices.append(constant)
             } else {
-                dynamicChoices.append(convertJSONSchemaToDynamic(choice))
+                // Alternatives need distinct names, e.g. "Event" or "ItemOption2"
+                dynamicChoices.append(
+                    try convertJSONSchemaToDynamic(choice, name: "\(unionName)Option\(index + 1)"))
             }
         }
         if !stringChoices.isEmpty && dynamicChoices.isEmpty {
             return .init(
-                name: name ?? UUID().uuidString, description: dict["description"] as? String,
+                name: unionName, description: dict["description"] as? String,
                 anyOf: stringChoices)
         } else {
+            // Mixed unions keep the string choices as single-value alternatives
             let choices =
-                dynamicChoices.isEmpty
-                ? anyOf.map { convertJSONSchemaToDynamic($0) } : dynamicChoices
+                dynamicChoices
+                + stringChoices.enumerated().map { index, choice in
+                    DynamicGenerationSchema(
+                        name: "\(unionName)Value\(index + 1)", description: nil, anyOf: [choice])
+                }
             return .init(
-                name: name ?? UUID().uuidString, description: dict["description"] as? String,
+                name: unionName, description: dict["description"] as? String,
                 anyOf: choices)
         }
     }
@@ -882,6 +3327,10 @@ private func convertJSONSchemaToDynamic(_ dict: [String: Any], name: String? = n
             anyOf: enums)
     }
 
+    if let constant = dict["const"] as? String {
+        return .init(type: String.self, guides: [.constant(constant)])
+    }
+
     guard let type = dict["type"] as? String else {
         // Fallback to string
         return .init(type: String.self)
@@ -889,16 +3338,16 @@ private func convertJSONSchemaToDynamic(_ dict: [String: Any], name: String? = n
 
     switch type {
     case "string":
-        return .init(type: String.self)
+        return .init(type: String.self, guides: try stringGuides(dict))
     case "number":
-        return .init(type: Double.self)
+        return .init(type: Double.self, guides: numberGuides(dict))
     case "integer":
-        return .init(type: Int.self)
+        return .init(type: Int.self, guides: integerGuides(dict))
     case "boolean":
         return .init(type: Bool.self)
     case "array":
         if let items = dict["items"] as? [String: Any] {
-            let itemSchema = convertJSONSchemaToDynamic(items)
+            let itemSchema = try convertJSONSchemaToDynamic(items)
             let min = dict["minItems"] as? Int
             let max = dict["maxItems"] as? Int
             return .init(arrayOf: itemSchema, minimumElements: min, maximumElements: max)
@@ -912,7 +3361,7 @@ private func convertJSONSchemaToDynamic(_ dict: [String: Any], name: String? = n
         if let properties = dict["properties"] as? [String: Any] {
             for (propName, subSchemaAny) in properties {
                 guard let subSchemaDict = subSchemaAny as? [String: Any] else { continue }
-                let subSchema = convertJSONSchemaToDynamic(subSchemaDict, name: propName)
+                let subSchema = try convertJSONSchemaToDynamic(subSchemaDict, name: propName)
                 let isOptional = !required.contains(propName)
                 let prop = DynamicGenerationSchema.Property(
                     name: propName, description: subSchemaDict["description"] as? String,
@@ -920,13 +3369,82 @@ private func convertJSONSchemaToDynamic(_ dict: [String: Any], name: String? = n
                 props.append(prop)
             }
         }
+        // Titles carry definition names, which stay distinct across inlined levels
         return .init(
-            name: name ?? "Object", description: dict["description"] as? String, properties: props)
+            name: dict["title"] as? String ?? name ?? "Object",
+            description: dict["description"] as? String, properties: props)
     default:
         return .init(type: String.self)
     }
 }
 
+// The pattern guide constrains the whole string, so JSON Schema's `^…$`
+// anchors are dropped. Rust checks patterns with its own regex syntax, so
+// one Swift can't compile is still reported here rather than dropped.
+@available(macOS 26.0, *)
+private func stringGuides(_ dict: [String: Any]) throws -> [GenerationGuide<String>] {
+    guard let source = dict["pattern"] as? String else { return [] }
+    var pattern = source
+    if pattern.hasPrefix("^") { pattern.removeFirst() }
+    if pattern.hasSuffix("$") && !pattern.hasSuffix("\\$") { pattern.removeLast() }
+    do {
+        return [.pattern(try Regex(pattern))]
+    } catch {
+        throw ConversationError.invalidJSON("invalid 'pattern' \(source): \(error)")
+    }
+}
+
+/// A JSON number, excluding booleans (draft-4 `exclusiveMinimum: true`)
+private func jsonNumber(_ value: Any?) -> Double? {
+    guard let number = value as? NSNumber, CFGetTypeID(number) != CFBooleanGetTypeID() else {
+        return nil
+    }
+    return number.doubleValue
+}
+
+// Numeric bounds become guides so decoding can't leave the range. Exclusive
+// bounds are exact for integers; for numbers they're applied inclusively and
+// the post-generation validation catches the boundary value.
+// Bounds past what Int holds are clamped rather than converted, which would
+// trap. Rust has already rejected bounds that admit no integer.
+private func clampedInt(_ value: Double) -> Int {
+    if value >= Double(Int.max) { return Int.max }
+    if value <= Double(Int.min) { return Int.min }
+    return Int(value)
+}
+
+@available(macOS 26.0, *)
+private func integerGuides(_ dict: [String: Any]) -> [GenerationGuide<Int>] {
+    var lower = jsonNumber(dict["minimum"]).map { clampedInt($0.rounded(.up)) }
+    var upper = jsonNumber(dict["maximum"]).map { clampedInt($0.rounded(.down)) }
+    if let bound = jsonNumber(dict["exclusiveMinimum"]) {
+        let above = clampedInt(bound.rounded(.down)).addingReportingOverflow(1)
+        lower = max(lower ?? Int.min, above.overflow ? Int.max : above.partialValue)
+    }
+    if let bound = jsonNumber(dict["exclusiveMaximum"]) {
+        let below = clampedInt(bound.rounded(.up)).subtractingReportingOverflow(1)
+        upper = min(upper ?? Int.max, below.overflow ? Int.min : below.partialValue)
+    }
+    switch (lower, upper) {
+    case (let lower?, let upper?) where lower <= upper: return [.range(lower...upper)]
+    case (let lower?, nil): return [.minimum(lower)]
+    case (nil, let upper?): return [.maximum(upper)]
+    default: return []
+    }
+}
+
+@available(macOS 26.0, *)
+private func numberGuides(_ dict: [String: Any]) -> [GenerationGuide<Double>] {
+    let lower = jsonNumber(dict["minimum"]) ?? jsonNumber(dict["exclusiveMinimum"])
+    let upper = jsonNumber(dict["maximum"]) ?? jsonNumber(dict["exclusiveMaximum"])
+    switch (lower, upper) {
+    case (let lower?, let upper?) where lower <= upper: return [.range(lower...upper)]
+    case (let lower?, nil): return [.minimum(lower)]
+    case (nil, let upper?): return [.maximum(upper)]
+    default: return []
+    }
+}
+
 @available(macOS 26.0, *)
 private func generatedContentToJSON(_ content: GeneratedContent) -> Any {
     switch content.kind {
@@ -958,7 +3476,7 @@ private func generatedContentToJSON(_ content: GeneratedContent) -> Any {
 }
 
 @available(macOS 26.0, *)
-private func buildSchemasFromJson(_ json: [String: Any]) -> (
+private func buildSchemasFromJson(_ json: [String: Any]) throws -> (
     DynamicGenerationSchema, [DynamicGenerationSchema]
 ) {
     var dependencies: [DynamicGenerationSchema] = []
@@ -971,7 +3489,7 @@ private func buildSchemasFromJson(_ json: [String: Any]) -> (
         for (name, subAny) in defs {
             if let subDict = subAny as? [String: Any] {
                 if let rootNameFromRef, name == rootNameFromRef { continue }
-                let depSchema = convertJSONSchemaToDynamic(subDict, name: name)
+                let depSchema = try convertJSONSchemaToDynamic(subDict, name: name)
                 dependencies.append(depSchema)
             }
         }
@@ -983,13 +3501,13 @@ private func buildSchemasFromJson(_ json: [String: Any]) -> (
         if let defs = json["definitions"] as? [String: Any],
             let rootDef = defs[name] as? [String: Any]
         {
-            let rootSchema = convertJSONSchemaToDynamic(rootDef, name: name)
+            let rootSchema = try convertJSONSchemaToDynamic(rootDef, name: name)
             return (rootSchema, dependencies)
         }
     }
 
     // Fallback
-    let root = convertJSONSchemaToDynamic(json, name: json["title"] as? String)
+    let root = try convertJSONSchemaToDynamic(json, name: json["title"] as? String)
     return (root, dependencies)
 }
 
@@ -999,7 +3517,8 @@ private func buildSchemasFromJson(_ json: [String: Any]) -> (
 private class ToolCallCollector {
     static let shared = ToolCallCollector()
     private let queue = DispatchQueue(label: "tool.call.collector")
-    private var calls: [ToolCallRecord] = []
+    /// Calls made so far, by request, so concurrent requests keep their own
+    private var calls: [UInt64: [ToolCallRecord]] = [:]
 
     struct ToolCallRecord {
         let id: UInt64
@@ -1008,18 +3527,26 @@ private class ToolCallCollector {
         let callId: String
     }
 
+    func begin(_ requestID: UInt64) {
+        queue.sync { calls[requestID] = [] }
+    }
+
+    func end(_ requestID: UInt64) {
+        _ = queue.sync { calls.removeValue(forKey: requestID) }
+    }
+
     func reset() {
         queue.sync { calls.removeAll() }
     }
 
-    func append(id: UInt64, name: String, arguments: [String: Any]) {
+    func append(requestID: UInt64, id: UInt64, name: String, arguments: [String: Any]) {
         let callId = "call_\(UUID().uuidString.replacingOccurrences(of: "-", with: "").prefix(12))"
         let record = ToolCallRecord(id: id, name: name, arguments: arguments, callId: callId)
-        queue.sync { calls.append(record) }
+        queue.sync { calls[requestID]?.append(record) }
     }
 
-    func getAllCalls() -> [ToolCallRecord] {
-        queue.sync { calls }
+    func getAllCalls(_ requestID: UInt64) -> [ToolCallRecord] {
+        queue.sync { calls[requestID] ?? [] }
     }
 }
 
@@ -1029,40 +3556,398 @@ private class ToolCallCollector {
 private actor StreamingCoordinator {
     static let shared = StreamingCoordinator()
 
-    private var expectedToolCount: Int = 0
-    private var completedToolCount: Int = 0
-    private var shouldStopAfterTools: Bool = false
-    private var allToolsCompleted: Bool = false
+    private struct State {
+        var expectedToolCount: Int
+        var completedToolCount = 0
+        var shouldStopAfterTools: Bool
+    }
+
+    /// Tool progress of each streaming request, so one request's tool calls
+    /// never end another's stream
+    private var states: [UInt64: State] = [:]
+
+    func begin(_ requestID: UInt64, expectedTools: Int, stopAfterToolCalls: Bool) {
+        states[requestID] = State(
+            expectedToolCount: expectedTools, shouldStopAfterTools: stopAfterToolCalls)
+    }
 
-    func reset(expectedTools: Int, stopAfterToolCalls: Bool) {
-        expectedToolCount = expectedTools
-        completedToolCount = 0
-        shouldStopAfterTools = stopAfterToolCalls
-        allToolsCompleted = false
+    func end(_ requestID: UInt64) {
+        states[requestID] = nil
     }
 
-    func toolCompleted() {
-        completedToolCount += 1
-        // Mark completion on any tool call so we can stop immediately if configured
-        allToolsCompleted = true
+    func toolCompleted(_ requestID: UInt64) {
+        states[requestID]?.completedToolCount += 1
     }
 
-    func shouldTerminateStream() -> Bool {
+    func shouldTerminateStream(_ requestID: UInt64) -> Bool {
         // Stop streaming as soon as at least one tool has been invoked when requested
-        return shouldStopAfterTools && completedToolCount > 0
+        guard let state = states[requestID] else { return false }
+        return state.shouldStopAfterTools && state.completedToolCount > 0
     }
 
-    func hasToolsToExecute() -> Bool {
-        return expectedToolCount > 0
+    func hasToolsToExecute(_ requestID: UInt64) -> Bool {
+        return (states[requestID]?.expectedToolCount ?? 0) > 0
     }
 }
 
-// C callback that receives tool results (for compatibility with JS side)
+// C callback that receives tool results and resumes the waiting tool call
 @_cdecl("apple_ai_tool_result_callback")
-public func appleAIToolResultCallback(_ toolID: UInt64, _ resultJson: UnsafePointer<CChar>) {
-    // In natural completion mode, we don't need to resume anything
-    // This callback exists for JS compatibility but doesn't affect Swift execution
-    _ = String(cString: resultJson)
+public func appleAIToolResultCallback(_ callID: UInt64, _ resultJson: UnsafePointer<CChar>) {
+    PendingToolCalls.shared.resume(callID, with: .success(String(cString: resultJson)))
+}
+
+// C callback that fails the waiting tool call; the model sees the error as the tool output
+@_cdecl("apple_ai_tool_error_callback")
+public func appleAIToolErrorCallback(_ callID: UInt64, _ message: UnsafePointer<CChar>) {
+    PendingToolCalls.shared.resume(callID, with: .failure(String(cString: message)))
+}
+
+// MARK: - Generation Registry for Cancellation
+
+/// Tracks in-flight generation tasks by the request id assigned on the Rust side
+private final class GenerationRegistry {
+    static let shared = GenerationRegistry()
+    /// Most early cancellations remembered; older ones are forgotten first
+    /// (Rust assigns ids in increasing order)
+    private static let historyLimit = 1024
+    private let queue = DispatchQueue(label: "generation.registry")
+    private var tasks: [UInt64: Task<Void, Never>] = [:]
+    private var cancelledBeforeStart = Set<UInt64>()
+
+    /// Create the request's task with `makeTask` and track it until `finish`.
+    /// The task is created under the lock, so its `finish` can't run before
+    /// it is registered.
+    func start(_ requestID: UInt64, _ makeTask: () -> Task<Void, Never>) {
+        let (task, alreadyCancelled): (Task<Void, Never>, Bool) = queue.sync {
+            let task = makeTask()
+            if cancelledBeforeStart.remove(requestID) != nil {
+                return (task, true)
+            }
+            tasks[requestID] = task
+            return (task, false)
+        }
+        if alreadyCancelled {
+            task.cancel()
+        }
+    }
+
+    /// Stop tracking a request whose task has ended.
+    func finish(_ requestID: UInt64) {
+        queue.sync {
+            _ = tasks.removeValue(forKey: requestID)
+        }
+    }
+
+    var activeCount: Int {
+        queue.sync { tasks.count }
+    }
+
+    func cancel(_ requestID: UInt64) {
+        let task: Task<Void, Never>? = queue.sync {
+            if let task = tasks.removeValue(forKey: requestID) {
+                return task
+            }
+            // Nothing is running under this id: the cancellation raced ahead
+            // of the start, or landed between two attempts of a request (Rust
+            // reuses the id for candidates, retries and agent steps). Either
+            // way the next start under the id must begin cancelled
+            cancelledBeforeStart.insert(requestID)
+            if cancelledBeforeStart.count > Self.historyLimit,
+                let oldest = cancelledBeforeStart.min()
+            {
+                cancelledBeforeStart.remove(oldest)
+            }
+            return nil
+        }
+        task?.cancel()
+    }
+}
+
+@_cdecl("apple_ai_cancel_generation")
+public func appleAICancelGeneration(_ requestID: UInt64) {
+    GenerationRegistry.shared.cancel(requestID)
+    // A stream records its usage again as it ends, for Rust's finish event
+    UsageRegistry.shared.take(requestID)
+}
+
+/// The last prewarmed session, kept so the model it loaded stays resident
+private final class WarmSession {
+    static let shared = WarmSession()
+    private let queue = DispatchQueue(label: "warm.session")
+    private var session: AnyObject?
+
+    func set(_ session: AnyObject?) {
+        queue.sync { self.session = session }
+    }
+}
+
+// Drop what is kept between requests; sessions of in-flight requests are
+// released when they finish. Returns the number of requests still in flight.
+@available(macOS 26.0, *)
+@_cdecl("apple_ai_release")
+public func appleAIRelease() -> Int32 {
+    WarmSession.shared.set(nil)
+    let active = GenerationRegistry.shared.activeCount
+    if active == 0 {
+        ToolCallCollector.shared.reset()
+    }
+    return Int32(active)
+}
+
+@available(macOS 26.0, *)
+@_cdecl("apple_ai_prewarm")
+public func appleAIPrewarm(
+    instructions: UnsafePointer<CChar>?,  // nullable
+    promptPrefix: UnsafePointer<CChar>?,  // nullable
+    adapterID: UInt64,  // 0 = base model
+    useCase: Int32,  // 0 = general, 1 = content tagging
+    guardrails: Int32  // 0 = developer provided, 1 = default, 2 = permissive transformations
+) -> Bool {
+    guard case .available = SystemLanguageModel.default.availability,
+        let model = try? makeModel(adapterID: adapterID, useCase: useCase, guardrails: guardrails)
+    else { return false }
+    let session =
+        instructions.map {
+            LanguageModelSession(model: model, instructions: String(cString: $0))
+        } ?? LanguageModelSession(model: model)
+    if let promptPrefix {
+        let prefix = String(cString: promptPrefix)
+        session.prewarm(promptPrefix: Prompt { prefix })
+    } else {
+        session.prewarm()
+    }
+    WarmSession.shared.set(session)
+    return true
+}
+
+// MARK: - Adapters
+
+/// Loaded adapters by the handle returned from `apple_ai_load_adapter`;
+/// Rust keeps track of names and which adapter each request uses
+@available(macOS 26.0, *)
+private final class AdapterStore {
+    static let shared = AdapterStore()
+    private let queue = DispatchQueue(label: "adapter.store")
+    private var adapters: [UInt64: SystemLanguageModel.Adapter] = [:]
+    private var nextID: UInt64 = 1
+
+    func add(_ adapter: SystemLanguageModel.Adapter) -> UInt64 {
+        queue.sync {
+            let id = nextID
+            nextID += 1
+            adapters[id] = adapter
+            return id
+        }
+    }
+
+    func get(_ id: UInt64) -> SystemLanguageModel.Adapter? {
+        queue.sync { adapters[id] }
+    }
+
+    func remove(_ id: UInt64) -> Bool {
+        queue.sync { adapters.removeValue(forKey: id) != nil }
+    }
+}
+
+/// Guardrails selected by the `guardrails` option: 0 = developer provided,
+/// 1 = the framework default, 2 = permissive content transformations
+@available(macOS 26.0, *)
+private func guardrails(_ setting: Int32) -> SystemLanguageModel.Guardrails {
+    switch setting {
+    case 1: return .default
+    case 2: return .permissiveContentTransformations
+    default: return Guardrails.developerProvided
+    }
+}
+
+/// Model for a request; adapter 0 selects the base model for the use case
+/// (0 = general, 1 = content tagging)
+@available(macOS 26.0, *)
+private func makeModel(
+    adapterID: UInt64, useCase: Int32 = 0, guardrails setting: Int32 = 0
+) throws -> SystemLanguageModel {
+    let guardrails = guardrails(setting)
+    if useCase == 1 {
+        return SystemLanguageModel(useCase: .contentTagging, guardrails: guardrails)
+    }
+    guard adapterID != 0 else { return SystemLanguageModel(guardrails: guardrails) }
+    guard let adapter = AdapterStore.shared.get(adapterID) else {
+        throw ConversationError.adapterUnloaded
+    }
+    return SystemLanguageModel(adapter: adapter, guardrails: guardrails)
+}
+
+private func adapterResult(_ fields: [String: Any]) -> UnsafeMutablePointer<CChar>? {
+    guard let data = try? JSONSerialization.data(withJSONObject: fields, options: [.sortedKeys]),
+        let json = String(data: data, encoding: .utf8)
+    else { return nil }
+    return strdup(json)
+}
+
+// Load an .fmadapter package. Returns {"id","name"} on success, where `id` is
+// the handle requests select it by, or {"code","error"} on failure.
+@available(macOS 26.0, *)
+@_cdecl("apple_ai_load_adapter")
+public func appleAILoadAdapter(_ path: UnsafePointer<CChar>) -> UnsafeMutablePointer<CChar>? {
+    let url = URL(fileURLWithPath: String(cString: path))
+    guard FileManager.default.fileExists(atPath: url.path) else {
+        return adapterResult([
+            "code": "AdapterNotFound", "error": "No adapter found at \(url.path)",
+        ])
+    }
+    do {
+        let adapter = try SystemLanguageModel.Adapter(fileURL: url)
+        let id = AdapterStore.shared.add(adapter)
+        return adapterResult(["id": id, "name": url.deletingPathExtension().lastPathComponent])
+    } catch let error as SystemLanguageModel.Adapter.AssetError {
+        switch error {
+        case .compatibleAdapterNotFound:
+            // Adapters are tied to the base model version they were trained on
+            return adapterResult([
+                "code": "AdapterIncompatible",
+                "error":
+                    "Adapter \(url.lastPathComponent) was not trained for the model on this OS version; "
+                    + "retrain it with the matching adapter toolkit",
+            ])
+        case .invalidAsset, .invalidAdapterName:
+            return adapterResult([
+                "code": "InvalidAdapter",
+                "error": "Invalid adapter \(url.lastPathComponent): \(error.localizedDescription)",
+            ])
+        @unknown default:
+            return adapterResult(["code": "InvalidAdapter", "error": error.localizedDescription])
+        }
+    } catch {
+        return adapterResult(["code": "InvalidAdapter", "error": error.localizedDescription])
+    }
+}
+
+// Forget a loaded adapter; requests already running keep using it.
+// Returns whether the handle was loaded.
+@available(macOS 26.0, *)
+@_cdecl("apple_ai_unload_adapter")
+public func appleAIUnloadAdapter(_ adapterID: UInt64) -> Bool {
+    return AdapterStore.shared.remove(adapterID)
+}
+
+// MARK: - Token Usage
+
+private struct TokenUsage {
+    let promptTokens: Int
+    let completionTokens: Int
+    /// Whether the completion used up the request's `maxTokens`, by an exact
+    /// count; never set from an estimate
+    let reachedLimit: Bool
+
+    /// `length` when generation was cut off by `maxTokens`
+    var finishReason: String { reachedLimit ? "length" : "stop" }
+}
+
+/// Usage of finished requests, held until Rust collects it via `apple_ai_take_usage`.
+/// Entries nobody collects are dropped, oldest request first, past `limit`.
+private final class UsageRegistry {
+    static let shared = UsageRegistry()
+    private static let limit = 1024
+    private let queue = DispatchQueue(label: "usage.registry")
+    private var usage: [UInt64: TokenUsage] = [:]
+
+    func record(_ requestID: UInt64, usage entry: TokenUsage) {
+        queue.sync {
+            usage[requestID] = entry
+            if usage.count > Self.limit, let oldest = usage.keys.min() {
+                usage.removeValue(forKey: oldest)
+            }
+        }
+    }
+
+    @discardableResult
+    func take(_ requestID: UInt64) -> TokenUsage? {
+        queue.sync { usage.removeValue(forKey: requestID) }
+    }
+}
+
+@available(macOS 26.0, *)
+private func countTokens(_ text: String) async -> Int {
+    await tokenCount(text).count
+}
+
+@available(macOS 26.0, *)
+private func tokenCount(_ text: String) async -> (count: Int, estimated: Bool) {
+    if #available(macOS 26.4, *) {
+        if let count = try? await SystemLanguageModel.default.tokenCount(for: text) {
+            return (count, false)
+        }
+    }
+    // Older systems have no tokenizer access; ~4 characters per token
+    return ((text.count + 3) / 4, true)
+}
+
+@available(macOS 26.0, *)
+@_cdecl("apple_ai_count_tokens")
+public func appleAICountTokens(_ text: UnsafePointer<CChar>) -> UnsafeMutablePointer<CChar>? {
+    let input = String(cString: text)
+    let semaphore = DispatchSemaphore(value: 0)
+    var result = (count: 0, estimated: true)
+    Task {
+        result = await tokenCount(input)
+        semaphore.signal()
+    }
+    semaphore.wait()
+    return strdup(#"{"tokens":\#(result.count),"estimated":\#(result.estimated)}"#)
+}
+
+private func segmentsText(_ segments: [Transcript.Segment]) -> String {
+    segments.compactMap { segment in
+        if case .text(let textSegment) = segment {
+            return textSegment.content
+        }
+        return nil
+    }.joined(separator: " ")
+}
+
+private func entryText(_ entry: Transcript.Entry) -> String {
+    switch entry {
+    case .instructions(let instructions):
+        return segmentsText(instructions.segments)
+    case .prompt(let prompt):
+        return segmentsText(prompt.segments)
+    case .response(let response):
+        return segmentsText(response.segments)
+    case .toolOutput(let toolOutput):
+        return segmentsText(toolOutput.segments)
+    case .toolCalls(let toolCalls):
+        return toolCalls.map { "\($0.toolName)(\($0.arguments.jsonString))" }.joined(separator: "\n")
+    @unknown default:
+        return ""
+    }
+}
+
+@available(macOS 26.0, *)
+@discardableResult
+private func recordUsage(context: ConversationContext, completion: String) async -> TokenUsage {
+    let promptText = (context.transcriptEntries.map(entryText) + [context.prompt])
+        .joined(separator: "\n")
+    let completionCount = await tokenCount(completion)
+    // The framework doesn't say when it stopped at the limit, and an
+    // estimated count is off by enough to call a complete answer cut off
+    // (or miss a cut-off one), so only an exact count reports `length`
+    let reachedLimit = !completionCount.estimated
+        && (context.options.maximumResponseTokens.map { completionCount.count >= $0 } ?? false)
+    let usage = TokenUsage(
+        promptTokens: await countTokens(promptText),
+        completionTokens: completionCount.count,
+        reachedLimit: reachedLimit
+    )
+    UsageRegistry.shared.record(context.requestID, usage: usage)
+    return usage
+}
+
+@_cdecl("apple_ai_take_usage")
+public func appleAITakeUsage(_ requestID: UInt64) -> UnsafeMutablePointer<CChar>? {
+    guard let usage = UsageRegistry.shared.take(requestID) else { return nil }
+    return strdup(
+        #"{"promptTokens":\#(usage.promptTokens),"completionTokens":\#(usage.completionTokens),"finishReason":"\#(usage.finishReason)"}"#
+    )
 }
 
 // MARK: - Unified Generation Function
@@ -1070,22 +3955,42 @@ public func appleAIToolResultCallback(_ toolID: UInt64, _ resultJson: UnsafePoin
 @available(macOS 26.0, *)
 @_cdecl("apple_ai_generate_unified")
 public func appleAIGenerateUnified(
+    requestID: UInt64,
     messagesJson: UnsafePointer<CChar>,
     toolsJson: UnsafePointer<CChar>?,
     schemaJson: UnsafePointer<CChar>?,
+    prefix: UnsafePointer<CChar>?,  // assistant prefill
+    imageData: UnsafePointer<UnsafePointer<UInt8>?>?,  // images referenced from messages by index
+    imageLens: UnsafePointer<Int>?,
+    imageCount: Int,
     temperature: Double,
     maxTokens: Int32,
+    topP: Double,  // 0 = unset
+    topK: Int32,  // 0 = unset
+    seed: Int64,  // -1 = unset
+    greedy: Bool,
     stream: Bool,
     stopAfterToolCalls: Bool,  // New parameter - controls early termination behavior
-    onChunk: (@convention(c) (UnsafePointer<CChar>?) -> Void)?
+    adapterID: UInt64,  // 0 = base model
+    useCase: Int32,  // 0 = general, 1 = content tagging
+    guardrails: Int32,  // 0 = developer provided, 1 = default, 2 = permissive transformations
+    onChunk: ChunkCallback?,
+    onObject: ObjectCallback?,  // required when streaming structured output
+    onError: ErrorCallback?  // required when streaming
 ) -> UnsafeMutablePointer<CChar>? {
     let messagesJsonString = String(cString: messagesJson)
     let toolsJsonString = toolsJson.map { String(cString: $0) }
     let schemaJsonString = schemaJson.map { String(cString: $0) }
+    let prefixString = prefix.map { String(cString: $0) }
+    // Copy the image bytes now; the caller only keeps them alive for this call
+    let imageBuffers: [Data] = (0..<imageCount).map { i in
+        guard let bytes = imageData?[i], let count = imageLens?[i] else { return Data() }
+        return Data(bytes: bytes, count: count)
+    }
 
     // Validate streaming parameters
-    if stream && onChunk == nil {
-        return strdup("Error: Streaming requested but no callback provided")
+    if stream && (onChunk == nil || onError == nil) {
+        return strdup("Error: [InvalidArg] Streaming requested but no callback provided")
     }
 
     // For non-streaming mode, use a semaphore
@@ -1093,96 +3998,127 @@ public func appleAIGenerateUnified(
         let semaphore = DispatchSemaphore(value: 0)
         var result: String = "Error: No response"
 
-        Task {
-            do {
-                // Parse messages and prepare context
-                let context = try prepareConversationContext(
-                    messagesJsonString: messagesJsonString,
-                    temperature: temperature,
-                    maxTokens: maxTokens
-                )
-
-                // Determine operation mode based on provided parameters
-                if let toolsStr = toolsJsonString, !toolsStr.isEmpty {
-                    // Tools mode - takes precedence over schema
-                    result = try await handleToolsMode(
-                        context: context,
-                        toolsJsonString: toolsStr,
+        GenerationRegistry.shared.start(requestID) {
+            Task {
+                do {
+                    // Parse messages and prepare context
+                    let context = try prepareConversationContext(
+                        requestID: requestID,
                         messagesJsonString: messagesJsonString,
-                        streaming: false,
-                        stopAfterToolCalls: stopAfterToolCalls,
-                        onChunk: nil
+                        imageBuffers: imageBuffers,
+                        prefix: prefixString,
+                        temperature: temperature,
+                        maxTokens: maxTokens,
+                        topP: topP,
+                        topK: topK,
+                        seed: seed,
+                        greedy: greedy,
+                        adapterID: adapterID,
+                        useCase: useCase,
+                        guardrails: guardrails
                     )
-                } else if let schemaStr = schemaJsonString, !schemaStr.isEmpty {
-                    // Structured generation mode
-                    result = try await handleStructuredMode(
-                        context: context,
-                        schemaJsonString: schemaStr
-                    )
-                } else {
-                    // Basic generation mode
-                    result = try await handleBasicMode(context: context)
-                }
-            } catch let error as ConversationError {
-                switch error {
-                case .intelligenceUnavailable(let reason):
-                    result = "Error: Apple Intelligence not available - \(reason)"
-                case .invalidJSON(let reason):
-                    result = "Error: \(reason)"
-                case .noMessages:
-                    result = "Error: No messages provided"
+
+                    // Determine operation mode based on provided parameters
+                    if let toolsStr = toolsJsonString, !toolsStr.isEmpty {
+                        // Tools mode - takes precedence over schema
+                        result = try await handleToolsMode(
+                            context: context,
+                            toolsJsonString: toolsStr,
+                            streaming: false,
+                            stopAfterToolCalls: stopAfterToolCalls,
+                            onChunk: nil
+                        )
+                    } else if let schemaStr = schemaJsonString, !schemaStr.isEmpty {
+                        // Structured generation mode
+                        result = try await handleStructuredMode(
+                            context: context,
+                            schemaJsonString: schemaStr
+                        )
+                    } else {
+                        // Basic generation mode
+                        result = try await handleBasicMode(context: context)
+                    }
+                } catch let error as LanguageModelSession.GenerationError {
+                    // Report refusals as a finish reason rather than a failure
+                    if case .guardrailViolation = error {
+                        let json: [String: Any] = [
+                            "text": "", "finishReason": "content_filter",
+                            "guardrail": ["detail": error.localizedDescription],
+                        ]
+                        let data = try? JSONSerialization.data(withJSONObject: json, options: [])
+                        result = data.flatMap { String(data: $0, encoding: .utf8) }
+                            ?? #"{"text":"","finishReason":"content_filter"}"#
+                    } else {
+                        result = describeError(error)
+                    }
+                } catch {
+                    result = describeError(error)
                 }
-            } catch {
-                result = "Error: \(error.localizedDescription)"
+                semaphore.signal()
             }
-            semaphore.signal()
         }
 
         semaphore.wait()
+        GenerationRegistry.shared.finish(requestID)
         return strdup(result)
     } else {
         // Streaming mode
-        Task.detached {
-            do {
-                // Parse messages and prepare context
-                let context = try prepareConversationContext(
-                    messagesJsonString: messagesJsonString,
-                    temperature: temperature,
-                    maxTokens: maxTokens
-                )
-
-                // Determine operation mode and stream
-                if let toolsStr = toolsJsonString, !toolsStr.isEmpty {
-                    // Tools mode with streaming
-                    _ = try await handleToolsMode(
-                        context: context,
-                        toolsJsonString: toolsStr,
+        GenerationRegistry.shared.start(requestID) {
+            Task.detached {
+                defer {
+                    GenerationRegistry.shared.finish(requestID)
+                    // Rust has read the usage while the stream ended; drop it if not
+                    UsageRegistry.shared.take(requestID)
+                }
+                do {
+                    // Parse messages and prepare context
+                    let context = try prepareConversationContext(
+                        requestID: requestID,
                         messagesJsonString: messagesJsonString,
-                        streaming: true,
-                        stopAfterToolCalls: stopAfterToolCalls,
-                        onChunk: onChunk
-                    )
-                } else if let schemaStr = schemaJsonString, !schemaStr.isEmpty {
-                    // Structured generation doesn't support streaming
-                    emitError("Structured generation does not support streaming", to: onChunk!)
-                } else {
-                    // Basic generation with streaming
-                    try await handleBasicModeStream(
-                        context: context,
-                        onChunk: onChunk!
+                        imageBuffers: imageBuffers,
+                        prefix: prefixString,
+                        temperature: temperature,
+                        maxTokens: maxTokens,
+                        topP: topP,
+                        topK: topK,
+                        seed: seed,
+                        greedy: greedy,
+                        adapterID: adapterID,
+                        useCase: useCase,
+                        guardrails: guardrails
                     )
+
+                    // Determine operation mode and stream
+                    if let toolsStr = toolsJsonString, !toolsStr.isEmpty {
+                        // Tools mode with streaming
+                        _ = try await handleToolsMode(
+                            context: context,
+                            toolsJsonString: toolsStr,
+                            streaming: true,
+                            stopAfterToolCalls: stopAfterToolCalls,
+                            onChunk: onChunk
+                        )
+                    } else if let schemaStr = schemaJsonString, !schemaStr.isEmpty {
+                        // Structured generation streams snapshots of the object so far
+                        guard let onObject = onObject else {
+                            throw ConversationError.invalidJSON("No object callback provided for streaming")
+                        }
+                        try await handleStructuredModeStream(
+                            context: context,
+                            schemaJsonString: schemaStr,
+                            onChunk: onChunk!,
+                            onObject: onObject
+                        )
+                    } else {
+                        // Basic generation with streaming
+                        try await handleBasicModeStream(
+                            context: context,
+                            onChunk: onChunk!
+                        )
+                    }
+                } catch {
+                    emitError(error, requestID: requestID, to: onError!)
                 }
-            } catch let error as ConversationError {
-                switch error {
-                case .intelligenceUnavailable(let reason):
-                    emitError("Apple Intelligence not available - \(reason)", to: onChunk!)
-                case .invalidJSON(let reason):
-                    emitError(reason, to: onChunk!)
-                case .noMessages:
-                    emitError("No messages", to: onChunk!)
-                }
-            } catch {
-                emitError(error.localizedDescription, to: onChunk!)
             }
         }
         return nil  // Streaming returns immediately
@@ -1194,14 +4130,16 @@ public func appleAIGenerateUnified(
 @available(macOS 26.0, *)
 private func handleBasicMode(context: ConversationContext) async throws -> String {
     let transcript = Transcript(entries: context.transcriptEntries)
-    debugPrintTranscript(transcript, prompt: context.currentPrompt)
-    let model = SystemLanguageModel(guardrails: Guardrails.developerProvided)
+    debugPrintTranscript(transcript, prompt: context.prompt)
     let session = LanguageModelSession(
-        model: model, transcript: transcript)
-    let response = try await session.respond(to: context.currentPrompt, options: context.options)
+        model: context.model, transcript: transcript)
+    let response = try await session.respond(to: context.prompt, options: context.options)
+    let usage = await recordUsage(context: context, completion: response.content)
 
     // Return as JSON for consistency
-    let json: [String: Any] = ["text": response.content]
+    let json: [String: Any] = [
+        "text": context.continuation(of: response.content), "finishReason": usage.finishReason,
+    ]
     let jsonData = try JSONSerialization.data(withJSONObject: json, options: [])
     return String(data: jsonData, encoding: .utf8) ?? "Error: Encoding failure"
 }
@@ -1209,55 +4147,63 @@ private func handleBasicMode(context: ConversationContext) async throws -> Strin
 @available(macOS 26.0, *)
 private func handleBasicModeStream(
     context: ConversationContext,
-    onChunk: @convention(c) (UnsafePointer<CChar>?) -> Void
+    onChunk: ChunkCallback
 ) async throws {
     let transcript = Transcript(entries: context.transcriptEntries)
-    debugPrintTranscript(transcript, prompt: context.currentPrompt)
-    let model = SystemLanguageModel(guardrails: Guardrails.developerProvided)
+    debugPrintTranscript(transcript, prompt: context.prompt)
     let session = LanguageModelSession(
-        model: model, transcript: transcript)
+        model: context.model, transcript: transcript)
 
     var prev = ""
-    for try await cumulative in session.streamResponse(
-        to: context.currentPrompt, options: context.options)
-    {
-        let delta = String(cumulative.content.dropFirst(prev.count))
-        prev = cumulative.content
-        guard !delta.isEmpty else { continue }
+    do {
+        for try await cumulative in session.streamResponse(
+            to: context.prompt, options: context.options)
+        {
+            let visible = context.continuation(of: cumulative.content)
+            let delta = String(visible.dropFirst(prev.count))
+            prev = visible
+            guard !delta.isEmpty else { continue }
 
-        delta.withCString { cStr in
-            onChunk(strdup(cStr))
+            delta.withCString { cStr in
+                onChunk(context.requestID, strdup(cStr))
+            }
         }
+    } catch is CancellationError {
+        // Rust cancels at a stop sequence and reports the usage when the stream ends
+        await recordUsage(context: context, completion: prev)
+        throw CancellationError()
     }
-    onChunk(nil)  // Signal end of stream
+    await recordUsage(context: context, completion: prev)
+    onChunk(context.requestID, nil)  // Signal end of stream
 }
 
 @available(macOS 26.0, *)
-private func handleStructuredMode(
-    context: ConversationContext,
-    schemaJsonString: String
-) async throws -> String {
-    // Parse JSON Schema
+private func parseGenerationSchema(_ schemaJsonString: String) throws -> GenerationSchema {
     guard let data = schemaJsonString.data(using: .utf8),
         let jsonObj = try JSONSerialization.jsonObject(with: data) as? [String: Any]
     else {
         throw ConversationError.invalidJSON("Invalid JSON Schema")
     }
+    let (rootSchema, deps) = try buildSchemasFromJson(jsonObj)
+    return try GenerationSchema(root: rootSchema, dependencies: deps)
+}
 
-    // Build schema from JSON
-    let (rootSchema, deps) = buildSchemasFromJson(jsonObj)
-    let generationSchema = try GenerationSchema(root: rootSchema, dependencies: deps)
+@available(macOS 26.0, *)
+private func handleStructuredMode(
+    context: ConversationContext,
+    schemaJsonString: String
+) async throws -> String {
+    let generationSchema = try parseGenerationSchema(schemaJsonString)
 
     // Create session without tools (structured generation doesn't use tools constructor)
     let transcript = Transcript(entries: context.transcriptEntries)
-    debugPrintTranscript(transcript, prompt: context.currentPrompt)
-    let model = SystemLanguageModel(guardrails: Guardrails.developerProvided)
+    debugPrintTranscript(transcript, prompt: context.prompt)
     let session = LanguageModelSession(
-        model: model, transcript: transcript)
+        model: context.model, transcript: transcript)
 
     // Generate structured response
     let response = try await session.respond(
-        to: context.currentPrompt,
+        to: context.prompt,
         schema: generationSchema,
         includeSchemaInPrompt: true,
         options: context.options
@@ -1266,24 +4212,62 @@ private func handleStructuredMode(
     let generatedContent = response.content
     let objectJson = generatedContentToJSON(generatedContent)
     let textRepresentation = String(describing: generatedContent)
+    let usage = await recordUsage(context: context, completion: generatedContent.jsonString)
 
     let json: [String: Any] = [
         "text": textRepresentation,
         "object": objectJson,
+        "finishReason": usage.finishReason,
     ]
 
     let jsonData = try JSONSerialization.data(withJSONObject: json, options: [])
     return String(data: jsonData, encoding: .utf8) ?? "Error: Encoding failure"
 }
 
+@available(macOS 26.0, *)
+private func handleStructuredModeStream(
+    context: ConversationContext,
+    schemaJsonString: String,
+    onChunk: ChunkCallback,
+    onObject: ObjectCallback
+) async throws {
+    let generationSchema = try parseGenerationSchema(schemaJsonString)
+
+    let transcript = Transcript(entries: context.transcriptEntries)
+    debugPrintTranscript(transcript, prompt: context.prompt)
+    let session = LanguageModelSession(
+        model: context.model, transcript: transcript)
+
+    // Each snapshot is the whole object generated so far; send it only when it changed
+    var prev = ""
+    for try await snapshot in session.streamResponse(
+        to: context.prompt,
+        schema: generationSchema,
+        includeSchemaInPrompt: true,
+        options: context.options
+    ) {
+        let object = generatedContentToJSON(snapshot.content)
+        guard JSONSerialization.isValidJSONObject(object),
+            let data = try? JSONSerialization.data(withJSONObject: object, options: []),
+            let json = String(data: data, encoding: .utf8), json != prev
+        else { continue }
+        prev = json
+
+        json.withCString { cStr in
+            onObject(context.requestID, cStr)
+        }
+    }
+    await recordUsage(context: context, completion: prev)
+    onChunk(context.requestID, nil)  // Signal end of stream
+}
+
 @available(macOS 26.0, *)
 private func handleToolsMode(
     context: ConversationContext,
     toolsJsonString: String,
-    messagesJsonString: String,  // Added to extract system message
     streaming: Bool,
     stopAfterToolCalls: Bool,  // New parameter
-    onChunk: (@convention(c) (UnsafePointer<CChar>?) -> Void)?
+    onChunk: ChunkCallback?
 ) async throws -> String {
     // Parse tools
     guard let toolsData = toolsJsonString.data(using: .utf8),
@@ -1300,10 +4284,11 @@ private func handleToolsMode(
         else { continue }
         let description = dict["description"] as? String ?? ""
         let paramsSchemaJson = dict["parameters"] as? [String: Any] ?? [:]
-        let (root, deps) = buildSchemasFromJson(paramsSchemaJson)
+        let (root, deps) = try buildSchemasFromJson(paramsSchemaJson)
         let genSchema = try GenerationSchema(root: root, dependencies: deps)
         let proxy = JSProxyTool(
-            toolID: idNum, name: name, description: description, parametersSchema: genSchema
+            requestID: context.requestID, toolID: idNum, name: name, description: description,
+            parametersSchema: genSchema
         )
         tools.append(proxy)
     }
@@ -1311,21 +4296,11 @@ private func handleToolsMode(
     // Build transcript with tools and system message
     var finalEntries = context.transcriptEntries
 
-    // Extract system message content from original messages
+    // Replace the plain instructions entry with one that also carries the tools
     var systemContent = ""
-    if let messagesData = messagesJsonString.data(using: .utf8),
-        let messagesJson = try? JSONSerialization.jsonObject(with: messagesData) as? [[String: Any]]
-    {
-        // Find system message (may not be first)
-        for message in messagesJson {
-            if let role = message["role"] as? String,
-                role.lowercased() == "system",
-                let content = message["content"] as? String
-            {
-                systemContent = content
-                break
-            }
-        }
+    if case .instructions(let existing) = finalEntries.first {
+        systemContent = segmentsText(existing.segments)
+        finalEntries.removeFirst()
     }
 
     // Create instructions with both system message and tools
@@ -1344,22 +4319,46 @@ private func handleToolsMode(
     }
 
     let transcript = Transcript(entries: finalEntries)
-    debugPrintTranscript(transcript, prompt: context.currentPrompt)
-    let model = SystemLanguageModel(guardrails: Guardrails.developerProvided)
+    debugPrintTranscript(transcript, prompt: context.prompt)
     let session = LanguageModelSession(
-        model: model, tools: tools, transcript: transcript)
+        model: context.model, tools: tools, transcript: transcript)
 
-    // Reset tool call collection
-    ToolCallCollector.shared.reset()
+    // Collect this request's tool calls until it returns
+    ToolCallCollector.shared.begin(context.requestID)
+    defer { ToolCallCollector.shared.end(context.requestID) }
 
     if !streaming {
         // Non-streaming with tools
-        let response = try await session.respond(
-            to: context.currentPrompt, options: context.options
-        )
-
-        let text = response.content
-        let toolCalls = ToolCallCollector.shared.getAllCalls()
+        let text: String
+        if stopAfterToolCalls {
+            // End the turn at the first tool call as the streaming path does,
+            // instead of generating an answer the caller is about to replace
+            await StreamingCoordinator.shared.begin(
+                context.requestID, expectedTools: tools.count, stopAfterToolCalls: true)
+            var visible = ""
+            do {
+                for try await cumulative in session.streamResponse(
+                    to: context.prompt, options: context.options
+                ) {
+                    if await StreamingCoordinator.shared.shouldTerminateStream(context.requestID) {
+                        break
+                    }
+                    visible = context.continuation(of: cumulative.content)
+                }
+            } catch {
+                await StreamingCoordinator.shared.end(context.requestID)
+                throw error
+            }
+            await StreamingCoordinator.shared.end(context.requestID)
+            text = visible
+        } else {
+            let response = try await session.respond(
+                to: context.prompt, options: context.options
+            )
+            text = context.continuation(of: response.content)
+        }
+        let toolCalls = ToolCallCollector.shared.getAllCalls(context.requestID)
+        let usage = await recordUsage(context: context, completion: text)
 
         var json: [String: Any] = [:]
 
@@ -1377,10 +4376,13 @@ private func handleToolsMode(
                     ],
                 ]
             }
-            json["text"] = ""  // awaiting tool execution
+            json["text"] = text
             json["toolCalls"] = formattedCalls
+            // Without stopAfterToolCalls the session already answered with the tool outputs
+            json["finishReason"] = stopAfterToolCalls ? "tool_calls" : usage.finishReason
         } else {
             json["text"] = text
+            json["finishReason"] = usage.finishReason
         }
 
         let jsonData = try JSONSerialization.data(withJSONObject: json, options: [])
@@ -1392,34 +4394,48 @@ private func handleToolsMode(
         }
 
         // Initialize coordination with configurable early termination
-        await StreamingCoordinator.shared.reset(
+        await StreamingCoordinator.shared.begin(
+            context.requestID,
             expectedTools: tools.count,
             stopAfterToolCalls: stopAfterToolCalls  // Use the parameter
         )
 
         var prev = ""
-        for try await cumulative in session.streamResponse(
-            to: context.currentPrompt, options: context.options,
-        ) {
-            // Check for early termination only if enabled
-            if stopAfterToolCalls {
-                let shouldTerminate = await StreamingCoordinator.shared.shouldTerminateStream()
-                if shouldTerminate {
-                    break
+        do {
+            for try await cumulative in session.streamResponse(
+                to: context.prompt, options: context.options,
+            ) {
+                // Check for early termination only if enabled
+                if stopAfterToolCalls {
+                    let shouldTerminate = await StreamingCoordinator.shared
+                        .shouldTerminateStream(context.requestID)
+                    if shouldTerminate {
+                        break
+                    }
                 }
-            }
 
-            let delta = String(cumulative.content.dropFirst(prev.count))
-            prev = cumulative.content
-            guard !delta.isEmpty else { continue }
+                let visible = context.continuation(of: cumulative.content)
+                let delta = String(visible.dropFirst(prev.count))
+                prev = visible
+                guard !delta.isEmpty else { continue }
 
-            delta.withCString { cStr in
-                onChunk(strdup(cStr))
+                delta.withCString { cStr in
+                    onChunk(context.requestID, strdup(cStr))
+                }
+            }
+        } catch {
+            await StreamingCoordinator.shared.end(context.requestID)
+            if error is CancellationError {
+                // Rust cancels at a stop sequence and reports the usage when the stream ends
+                await recordUsage(context: context, completion: prev)
             }
+            throw error
         }
+        await StreamingCoordinator.shared.end(context.requestID)
 
         // Signal completion
-        onChunk(nil)
+        await recordUsage(context: context, completion: prev)
+        onChunk(context.requestID, nil)
         return ""  // Not used in streaming mode
     }
 }
@@ -1,4 +1,3 @@
-import type { ModelMessage } from "ai";
 import type { JSONSchema7 } from "json-schema";
 import { Readable } from "node:stream";
 import type { z } from "zod";
@@ -8,33 +7,253 @@ import { getNativeModule } from "./native-loader";
 // Initialize native module using robust loader
 const native = getNativeModule();
 
+/** Options understood by the native `generate*` functions */
+interface NativeGenerationOptions {
+  toolsJson?: string | null;
+  schemaJson?: string | null;
+  temperature?: number;
+  maxTokens?: number;
+  stop?: string[];
+  sampling?: {
+    topP?: number;
+    topK?: number;
+    seed?: number;
+    greedy?: boolean;
+  };
+  stopAfterToolCalls?: boolean;
+  n?: number;
+  timeoutMs?: number;
+  maxParallelToolCalls?: number;
+  toolCacheTtlMs?: number;
+  scanToolResults?: ScanToolResultsOptions;
+  schemaRetries?: number;
+  contextPolicy?: ContextPolicy;
+  condense?: CondenseOptions;
+  adapter?: string;
+  useCase?: ModelUseCase;
+  guardrails?: GuardrailSetting;
+  language?: string;
+  respondIn?: string;
+  registeredTools?: string[];
+  nativeTools?: NativeToolsOptions;
+  mcpServers?: string[];
+  toolHandlers?: Record<string, NativeToolCallback>;
+  toolApproval?: NativeToolApprovalCallback;
+}
+
+/** Native tool handler; answers via `toolBindings.toolResult(toolId, json)` or `toolError` */
+type NativeToolCallback = (
+  err: Error | null,
+  toolId: number,
+  argsJson: string
+) => void;
+
+/** Native approval hook; answers via `toolBindings.toolApproval(toolId, json)` or `toolError` */
+type NativeToolApprovalCallback = (
+  err: Error | null,
+  toolId: number,
+  toolName: string,
+  argsJson: string
+) => void;
+
+/** Message shape understood by the native `generate*` functions */
+interface NativeChatMessage {
+  role: ChatMessage["role"];
+  content?: ChatMessage["content"] | null;
+  name?: string;
+  toolCallId?: string;
+  toolCalls?: ChatMessage["tool_calls"];
+}
+
 // Add unified function bindings
 const unifiedBindings = {
-  generateUnified: native.generateUnified as (
-    messagesJson: string,
-    toolsJson?: string | null,
-    schemaJson?: string | null,
-    temperature?: number,
-    maxTokens?: number,
-    stopAfterToolCalls?: boolean
-  ) => Promise<string>,
-  generateUnifiedStream: native.generateUnifiedStream as (
-    messagesJson: string,
-    toolsJson: string | null | undefined,
-    schemaJson: string | null | undefined,
-    temperature: number | undefined,
-    maxTokens: number | undefined,
-    stopAfterToolCalls: boolean | undefined,
-    cb: (err: unknown, chunk?: string | null) => void
+  generate: native.generate as (
+    messages: NativeChatMessage[],
+    options?: NativeGenerationOptions
+  ) => Promise<GenerationResult>,
+  generateAbortable: native.generateAbortable as (
+    messages: NativeChatMessage[],
+    options?: NativeGenerationOptions
+  ) => { promise: Promise<GenerationResult>; cancel: () => void },
+  generateStream: native.generateStream as (
+    messages: NativeChatMessage[],
+    options: NativeGenerationOptions | undefined,
+    cb: (err: unknown, event?: StreamEvent) => void
   ) => void,
+  runAgent: native.runAgent as (
+    messages: NativeChatMessage[],
+    options: NativeGenerationOptions | undefined,
+    agent: {
+      maxSteps?: number;
+      onStep?: (err: unknown, step: AgentStep) => void;
+    }
+  ) => Promise<AgentResult>,
 };
 
+/**
+ * Stable `code` of every error a native call rejects with and of stream
+ * `error` events. Errors thrown synchronously for invalid arguments carry
+ * "InvalidArg".
+ */
+export type ErrorCode =
+  | "InvalidArg"
+  | "ModelUnavailable"
+  | "AssetsUnavailable"
+  | "GuardrailViolation"
+  | "Refusal"
+  | "ContextWindowExceeded"
+  | "UnsupportedLanguage"
+  | "MultimodalUnsupported"
+  | "UnsupportedGuide"
+  | "DecodingFailure"
+  | "RateLimited"
+  | "ConcurrentRequests"
+  | "Cancelled"
+  | "Timeout"
+  | "ToolFailure"
+  | "SchemaValidation"
+  | "AdapterNotFound"
+  | "InvalidAdapter"
+  | "AdapterIncompatible"
+  | "ImageGenerationUnavailable"
+  | "PermissionDenied"
+  | "GenerationFailed";
+
+/** Thrown when messages contain images but the on-device model cannot take them */
+export class MultimodalUnsupportedError extends Error {
+  readonly code = "MultimodalUnsupported";
+
+  constructor() {
+    super("Multimodal input is not supported by the on-device model on this system");
+    this.name = "MultimodalUnsupportedError";
+  }
+}
+
+/** Thrown when structured output still doesn't match its schema after all retries */
+export class SchemaValidationError extends Error {
+  readonly code = "SchemaValidation";
+
+  constructor(message: string) {
+    super(message);
+    this.name = "SchemaValidationError";
+  }
+}
+
+/** Thrown when this device or system can't generate images, or image generation is off */
+export class ImageGenerationUnavailableError extends Error {
+  readonly code = "ImageGenerationUnavailable";
+
+  constructor(message: string) {
+    super(message);
+    this.name = "ImageGenerationUnavailableError";
+  }
+}
+
+/** Re-throw native `SchemaValidation` errors as `SchemaValidationError` */
+function mapSchemaError(err: unknown): never {
+  if ((err as { code?: string } | null)?.code === "SchemaValidation") {
+    throw new SchemaValidationError((err as Error).message);
+  }
+  throw err;
+}
+
+function toNativeMessages(messages: ChatMessage[]): NativeChatMessage[] {
+  const hasImages = messages.some(
+    ({ content }) =>
+      Array.isArray(content) && content.some((part) => part.type === "image")
+  );
+  if (hasImages && !native.supportsImageInput()) {
+    throw new MultimodalUnsupportedError();
+  }
+  return messages.map(({ role, content, name, tool_call_id, tool_calls }) => ({
+    role,
+    content,
+    name,
+    toolCallId: tool_call_id,
+    toolCalls: tool_calls,
+  }));
+}
+
+function toNativeOptions(options: GenerationOptions): NativeGenerationOptions {
+  const {
+    temperature,
+    maxTokens,
+    stop,
+    topP,
+    topK,
+    seed,
+    greedy,
+    n,
+    timeoutMs,
+    maxParallelToolCalls,
+    toolCacheTtlMs,
+    scanToolResults,
+    schemaRetries,
+    contextPolicy,
+    condense,
+    adapter,
+    useCase,
+    guardrails,
+    language,
+    respondIn,
+    nativeTools,
+    mcpServers,
+  } = options;
+  return {
+    temperature,
+    maxTokens,
+    stop,
+    sampling: { topP, topK, seed, greedy },
+    n,
+    timeoutMs,
+    maxParallelToolCalls,
+    toolCacheTtlMs,
+    scanToolResults,
+    schemaRetries,
+    contextPolicy,
+    condense,
+    adapter,
+    useCase,
+    guardrails,
+    language,
+    respondIn,
+    nativeTools,
+    mcpServers,
+  };
+}
+
+/** Convert a native `error` stream event into an Error carrying its code */
+function streamError(
+  event: Extract<StreamEvent, { type: "error" }>
+): Error & {
+  code?: ErrorCode;
+  retryable?: boolean;
+  guardrail?: GuardrailViolation;
+} {
+  return Object.assign(new Error(event.message), {
+    code: event.code,
+    retryable: event.retryable,
+    guardrail: event.guardrail,
+  });
+}
+
 const toolBindings = {
   setToolCallback: native.setToolCallback as (
-    callback: (err: Error | null, toolId: number, argsJson: string) => void
+    callback: NativeToolCallback
   ) => void,
   clearToolCallback: native.clearToolCallback as () => void,
+  registerTool: native.registerTool as (
+    name: string,
+    callback: NativeToolCallback,
+    definitionJson?: string
+  ) => void,
+  unregisterTool: native.unregisterTool as (name: string) => boolean,
   toolResult: native.toolResult as (toolId: number, resultJson: string) => void,
+  toolError: native.toolError as (toolId: number, message: string) => void,
+  toolApproval: native.toolApproval as (
+    toolId: number,
+    decisionJson: string
+  ) => void,
 };
 
 // ------------------ Shared Types ------------------
@@ -60,10 +279,61 @@ export type EphemeralTool<TSchema extends JSONSchema7, TResult = unknown> = {
   handler: (args: Record<string, unknown>) => PromiseLike<TResult>;
 };
 
+/**
+ * Outcome of a tool approval check. `"modify"` runs the tool with replacement
+ * arguments; a denial is reported to the model as a declined call.
+ */
+export type ToolApprovalDecision =
+  | "approve"
+  | "deny"
+  | { type: "approve" }
+  | { type: "deny"; reason?: string }
+  | { type: "modify"; args: Record<string, unknown> };
+
+/** A tool given to a request, or the name of one added with `registerTool` */
+export type RequestTool = EphemeralTool<JSONSchema7> | string;
+
+/** Decides whether a tool call may run, before its handler is invoked */
+export type ToolApprovalHandler = (
+  toolName: string,
+  args: Record<string, unknown>
+) => ToolApprovalDecision | PromiseLike<ToolApprovalDecision>;
+
 // Types for our Apple AI library
+
+/** Part of a multimodal message; image parts are only allowed on user messages */
+export type ContentPart =
+  | { type: "text"; text: string }
+  | {
+      type: "image";
+      /** Image bytes in any format ImageIO reads (PNG, JPEG, HEIC, TIFF, WebP, …); a Buffer is handed to Swift without base64 re-encoding */
+      data: string | Buffer;
+      /** e.g. "image/png"; sniffed from the bytes when omitted */
+      mimeType?: string;
+      /** Longest edge in pixels after downscaling (default 1024) */
+      maxDimension?: number;
+      /** How to turn the image upright; "auto" (default) follows its EXIF orientation */
+      orientation?: ImageOrientation;
+    }
+  | {
+      type: "image";
+      /** Image file, loaded, oriented and downscaled natively */
+      imagePath: string;
+      /** Longest edge in pixels after downscaling (default 1024) */
+      maxDimension?: number;
+      /** How to turn the image upright; "auto" (default) follows its EXIF orientation */
+      orientation?: ImageOrientation;
+    };
+
+/**
+ * How to turn an image upright: "auto" by its EXIF orientation, "up" as stored,
+ * or "right", "down" or "left" to turn it a quarter clockwise, half or a quarter counterclockwise
+ */
+export type ImageOrientation = "auto" | "up" | "right" | "down" | "left";
+
 export interface ChatMessage {
   role: "system" | "user" | "assistant" | "tool" | "tool_calls";
-  content: string;
+  content: string | ContentPart[];
   name?: string;
   tool_call_id?: string; // OpenAI-compatible snake_case
   tool_calls?: Array<{
@@ -77,9 +347,401 @@ export interface ChatMessage {
   }>;
 }
 
+/** Event delivered by the native streaming callback */
+export type StreamEvent =
+  | { type: "text-delta"; text: string }
+  | {
+      /** Structured output generated so far; fields fill in as generation goes on */
+      type: "object-delta";
+      object: unknown;
+    }
+  | {
+      /**
+       * The model called a tool. Sent together with the call's only
+       * `tool-call-delta` and its `tool-call`: arguments are not streamed
+       */
+      type: "tool-call-start";
+      toolCallId: string;
+      toolName: string;
+    }
+  | {
+      type: "tool-call-delta";
+      toolCallId: string;
+      /** The whole arguments JSON; the model doesn't stream arguments */
+      argsDelta: string;
+    }
+  | {
+      /** The tool call is complete */
+      type: "tool-call";
+      toolCallId: string;
+      toolName: string;
+      /** Arguments as a JSON string */
+      args: string;
+    }
+  | {
+      type: "tool-result";
+      toolCallId: string;
+      toolName: string;
+      /** Result as a JSON string */
+      result: string;
+    }
+  | {
+      type: "finish";
+      finishReason: FinishReason;
+      /** Final structured object, for streams with a schema */
+      object?: unknown;
+      usage?: TokenUsage;
+      metrics: GenerationMetrics;
+      /** Seed used for random sampling, as on `generate` results */
+      seed?: number;
+      toolTraces?: ToolCallTrace[];
+    }
+  | {
+      type: "error";
+      message: string;
+      code?: ErrorCode;
+      /** Whether retrying the same request later may succeed */
+      retryable?: boolean;
+      /** Details for "GuardrailViolation" errors */
+      guardrail?: GuardrailViolation;
+    };
+
+/** Latency measured around the native call */
+export interface GenerationMetrics {
+  /** Time until the first text chunk arrived; only set for streaming requests */
+  timeToFirstTokenMs?: number;
+  totalMs: number;
+  tokensPerSecond?: number;
+}
+
+/** Timing of a single tool call, for finding slow tools */
+export interface ToolCallTrace {
+  toolName: string;
+  /** Size of the arguments JSON in bytes */
+  argsBytes: number;
+  /** Size of the result JSON in bytes */
+  resultBytes: number;
+  /** Time spent waiting for a free slot under `maxParallelToolCalls` */
+  queueWaitMs: number;
+  /** Time from handing the call to its handler until the result arrived */
+  executionMs: number;
+  /** Whether the handler failed or the arguments were rejected */
+  failed: boolean;
+  /** Whether the result was served from the tool result cache */
+  cached: boolean;
+}
+
+/** Token counts for a single request */
+export interface TokenUsage {
+  promptTokens: number;
+  completionTokens: number;
+  totalTokens: number;
+}
+
+/** How messages are made to fit the context window, see `GenerationOptions.contextPolicy` */
+export type ContextPolicy = "error" | "dropOldest" | "dropMiddle";
+
+/** When and how older turns are summarized, see `GenerationOptions.condense` */
+export interface CondenseOptions {
+  /** Fraction of the available context at which to condense (default 0.8) */
+  threshold?: number;
+  /** Instructions for the summarization pass */
+  prompt?: string;
+}
+
+/** Which model answers requests on this system */
+export interface ModelInfo {
+  /** Operating system version, e.g. "Version 26.0 (Build 25A354)" */
+  osVersion: string;
+  /** Newest Foundation Models feature level available, e.g. "26.4" */
+  frameworkLevel: string;
+  /** "adapter" when a custom adapter is active */
+  model: "default" | "adapter";
+  /** Name of the active adapter, if any */
+  adapter?: string;
+  /** Use case the model is specialized for */
+  useCase: string;
+  available: boolean;
+}
+
+/** Variant of the system model, see `GenerationOptions.useCase` */
+export type ModelUseCase = "general" | "contentTagging";
+
+/** Safety guardrails a request runs with, see `GenerationOptions.guardrails` */
+export type GuardrailSetting =
+  | "developerProvided"
+  | "default"
+  | "permissiveContentTransformations";
+
+/** A custom adapter loaded with `loadAdapter` */
+export interface AdapterInfo {
+  /** Name requests select the adapter by, see `GenerationOptions.adapter` */
+  name: string;
+  path: string;
+  /** Whether requests that don't name an adapter use this one */
+  active: boolean;
+}
+
+/** Static limits of the on-device model */
+export interface ModelLimits {
+  /** Tokens the prompt and output share */
+  contextWindow: number;
+  /** Most tokens a single response can have */
+  maxOutputTokens: number;
+  /** Whether the OS reported the context window, rather than the documented default */
+  reported: boolean;
+  /** Whether `countTokens` uses the model's tokenizer rather than an estimate */
+  exactTokenCounts: boolean;
+  supportsImageInput: boolean;
+}
+
+/** Size of a prompt as the on-device model counts it */
+export interface TokenCount {
+  tokens: number;
+  /** Set on systems without tokenizer access (about 4 characters per token) */
+  estimated: boolean;
+}
+
+/** Why a generation ended */
+export type FinishReason = "stop" | "length" | "tool_calls" | "content_filter";
+
+/** Final result of a non-streaming native generation */
+export interface GenerationResult {
+  text: string;
+  /** Parsed object for structured (schema) generation */
+  object?: unknown;
+  toolCalls?: NonNullable<ChatMessage["tool_calls"]>;
+  finishReason: FinishReason;
+  usage?: TokenUsage;
+  metrics: GenerationMetrics;
+  /**
+   * Seed used for random sampling, drawn at random when the request set
+   * none; pass it back as `seed` to reproduce this run. Absent for greedy
+   * sampling
+   */
+  seed?: number;
+  /**
+   * Every candidate when `n` > 1. The top-level fields mirror the first
+   * candidate; `usage` and `metrics` cover the whole request.
+   */
+  candidates?: GenerationResult[];
+  /** Timing of each tool call made during the generation */
+  toolTraces?: ToolCallTrace[];
+  /** Why the guardrails stopped the generation, when `finishReason` is "content_filter" */
+  guardrail?: GuardrailViolation;
+  /** The entry of `stop` that ended the generation, if one did */
+  stopSequence?: string;
+}
+
+/**
+ * Why the safety guardrails stopped a generation. The framework doesn't say
+ * which check fired or name a category, so `side` is inferred from whether
+ * output had started.
+ */
+export interface GuardrailViolation {
+  /** "prompt" if blocked before any output, "output" if generated output was blocked */
+  side: "prompt" | "output" | "unknown";
+  /** The framework's description of the violation */
+  detail?: string;
+  /** Whether asking the user to rephrase may get a response */
+  suggestRephrase: boolean;
+}
+
+/** Output of one tool call made during an agent step */
+export interface AgentToolResult {
+  toolCallId: string;
+  toolName: string;
+  /** Tool output as the model received it (JSON string) */
+  result: string;
+}
+
+/** One generate → tool-call → tool-result round of `runAgent` */
+export interface AgentStep {
+  /** 1-based step number */
+  step: number;
+  text: string;
+  toolCalls: NonNullable<ChatMessage["tool_calls"]>;
+  toolResults: AgentToolResult[];
+  finishReason: FinishReason;
+  usage?: TokenUsage;
+  /** Timing of this step's tool calls */
+  toolTraces: ToolCallTrace[];
+}
+
+/** Final result of `runAgent` */
+export interface AgentResult {
+  /** Text of the last step */
+  text: string;
+  steps: AgentStep[];
+  /** "tool_calls" if `maxSteps` was reached while the model was still calling tools */
+  finishReason: FinishReason;
+  /** Usage summed over all steps */
+  usage?: TokenUsage;
+}
+
 export interface GenerationOptions {
   temperature?: number;
   maxTokens?: number;
+  /** Stop sequences; output is cut before the first match */
+  stop?: string[];
+  /** Nucleus sampling threshold in (0, 1]; cannot be combined with `topK` */
+  topP?: number;
+  /** Sample from the `topK` most likely tokens; cannot be combined with `topP` */
+  topK?: number;
+  /** Seed for reproducible random sampling */
+  seed?: number;
+  /** Always pick the most likely token; excludes `topP`, `topK` and `seed` */
+  greedy?: boolean;
+  /** Number of candidate completions, 1 to 128 (non-streaming only); excludes `greedy` */
+  n?: number;
+  /**
+   * Cancel the generation after this many milliseconds. Promises reject and
+   * streams end with an error whose `code` is "Timeout".
+   */
+  timeoutMs?: number;
+  /**
+   * Most tool handlers to run at once when the model calls several tools in
+   * one turn. Defaults to unlimited; extra calls wait for a free slot.
+   */
+  maxParallelToolCalls?: number;
+  /**
+   * Reuse a tool's successful result for identical arguments for this many
+   * milliseconds instead of calling its handler again. Results are kept for
+   * the process, keyed by tool name and arguments, and shared with other
+   * requests that set this option only when the same handler, native tool
+   * configuration or MCP connection answers them; off by default. A handler
+   * registered again starts without cached results.
+   */
+  toolCacheTtlMs?: number;
+  /**
+   * Scan every tool result with `scanForInjection` before the model gets it.
+   * Results scoring `threshold` (default 0.5) or more are passed on wrapped
+   * with a warning (`action: "warn"`, the default), or withheld, failing the
+   * call with the reason (`"block"`).
+   */
+  scanToolResults?: ScanToolResultsOptions;
+  /**
+   * With a `schema`, re-prompt the model with the validation errors up to
+   * this many times when its output doesn't match (non-streaming only;
+   * default 0). Output that still doesn't match rejects with
+   * `SchemaValidationError`.
+   */
+  schemaRetries?: number;
+  /**
+   * What to do when the messages don't fit the context window: "error"
+   * fails up front with code "ContextWindowExceeded"; "dropOldest" and
+   * "dropMiddle" drop whole turns (oldest first, or after the first user
+   * turn). System messages and the latest turn are always kept. Unset, the
+   * messages are sent as they are.
+   */
+  contextPolicy?: ContextPolicy;
+  /**
+   * Summarize older turns into a system message once the messages approach
+   * the context window, so long conversations keep working. Runs before
+   * `contextPolicy`, which still applies if the result doesn't fit.
+   */
+  condense?: CondenseOptions;
+  /**
+   * Name of an adapter loaded with `loadAdapter` to run this request
+   * against, instead of the active one
+   */
+  adapter?: string;
+  /**
+   * Model variant to run: the general model (default), or "contentTagging",
+   * specialized for tagging and classification such as extracting topics,
+   * entities or actions. Doesn't combine with `adapter`; the active adapter
+   * doesn't apply to "contentTagging" requests.
+   */
+  useCase?: ModelUseCase;
+  /**
+   * Safety guardrails: the framework's "default", or
+   * "permissiveContentTransformations" for apps that rewrite, summarize or
+   * tag user-generated content. Unset, the "developerProvided" configuration
+   * this library has always used applies.
+   */
+  guardrails?: GuardrailSetting;
+  /**
+   * Locale the conversation is in, e.g. "de-DE", or "auto" for the language
+   * of the last user message. If the model doesn't support it the request
+   * fails up front with code "UnsupportedLanguage" instead of partway
+   * through generation.
+   */
+  language?: string;
+  /**
+   * Language to answer in, e.g. "ja", whatever language the user writes in.
+   * A non-streamed text response detected to be in another language is
+   * regenerated once; streams only get the instruction.
+   */
+  respondIn?: string;
+  /** Built-in tools answered natively, without calling into JS */
+  nativeTools?: NativeToolsOptions;
+  /**
+   * Names of connected MCP servers (see `connectMcpServer`) whose tools the
+   * model may call; the native layer forwards the calls to the server.
+   */
+  mcpServers?: string[];
+}
+
+/**
+ * Built-in tools implemented in the native layer. Each is off unless
+ * configured here, and is exposed to the model next to any JS tools.
+ */
+export interface NativeToolsOptions {
+  /** `fetch`: plain-HTTP GET (http:// only) restricted to `allowedHosts` */
+  fetch?: {
+    /** Hosts the tool may contact; `*.example.com` also matches subdomains */
+    allowedHosts: string[];
+    /** Most response body bytes returned to the model (default 65536) */
+    maxBytes?: number;
+    /** Connect and read timeout in milliseconds (default 10000) */
+    timeoutMs?: number;
+    /** Refuse requests without `onToolApproval`, so every fetch is approved (default false) */
+    requireApproval?: boolean;
+  };
+  /** `read_file`: read files below `root`; paths may not escape it */
+  readFile?: {
+    root: string;
+    /** Most bytes returned to the model (default 65536) */
+    maxBytes?: number;
+    /** Refuse requests without `onToolApproval`, so every read is approved (default false) */
+    requireApproval?: boolean;
+  };
+  /** `current_time`: current date and time in UTC */
+  currentTime?: boolean;
+}
+
+/** How to reach an MCP server, see `connectMcpServer` */
+export interface McpServerConfig {
+  /** Name to connect under; requests list it in `mcpServers` */
+  name: string;
+  /** Executable to launch as a stdio server */
+  command?: string;
+  args?: string[];
+  /** Variables added to the inherited environment of the launched server */
+  env?: Record<string, string>;
+  /** Working directory of the launched server */
+  cwd?: string;
+  /**
+   * `http://` endpoint of a Streamable HTTP server. There is no TLS, so
+   * `https://` URLs are refused; reach remote servers through a local proxy
+   */
+  url?: string;
+  /**
+   * Extra headers sent with every HTTP request, e.g. `Authorization`.
+   * Names and values can't contain line breaks
+   */
+  headers?: Record<string, string>;
+  /** How long to wait for each response from the server (default 30000) */
+  timeoutMs?: number;
+}
+
+/** A connected MCP server and the tools it offers */
+export interface McpServerInfo {
+  name: string;
+  /** Name and version the server reported about itself */
+  serverName?: string;
+  serverVersion?: string;
+  tools: { name: string; description?: string }[];
 }
 
 export interface ModelAvailability {
@@ -87,6 +749,35 @@ export interface ModelAvailability {
   reason: string;
 }
 
+/** Download state of the model, see `watchModelStatus` */
+export interface ModelStatus {
+  state: "available" | "downloading" | "unavailable";
+  reason: string;
+  /**
+   * How long the model has been downloading since watching started. The
+   * framework doesn't report a completed fraction, so show an indeterminate
+   * progress indicator with this as the elapsed time.
+   */
+  downloadingMs?: number;
+}
+
+/**
+ * Report the model's download state to `onStatus`, starting with the current
+ * one and then on every change (and every poll while downloading). Watching
+ * ends once the model is available or `stop()` is called.
+ */
+export function watchModelStatus(
+  onStatus: (status: ModelStatus) => void,
+  options: { intervalMs?: number } = {}
+): { stop: () => void } {
+  return native.watchModelStatus(
+    (err: Error | null, status: ModelStatus) => {
+      if (!err) onStatus(status);
+    },
+    options.intervalMs
+  ) as { stop: () => void };
+}
+
 // OpenAI-compatible response types
 export interface ChatCompletionChunk {
   id: string;
@@ -129,6 +820,11 @@ export class AppleAISDK {
     return native.getSupportedLanguages();
   }
 
+  /** Whether the model supports a locale such as "de-DE" or "ja" */
+  isLanguageSupported(locale: string): boolean {
+    return native.isLanguageSupported(locale);
+  }
+
   /** Generate a response for a prompt */
   async generateResponse(
     prompt: string,
@@ -136,20 +832,14 @@ export class AppleAISDK {
   ): Promise<string> {
     // Convert prompt to messages format
     const messages: ChatMessage[] = [{ role: "user", content: prompt }];
-    const messagesJson = JSON.stringify(messages);
-
-    const result = await unifiedBindings.generateUnified(
-      messagesJson,
-      null, // no tools
-      null, // no schema
-      options.temperature ?? undefined,
-      options.maxTokens ?? undefined,
-      true // stopAfterToolCalls default
+    const nativeMessages = toNativeMessages(messages);
+
+    const result = await unifiedBindings.generate(
+      nativeMessages,
+      toNativeOptions(options)
     );
 
-    // Parse result and extract text
-    const parsed = JSON.parse(result);
-    return parsed.text || result;
+    return result.text;
   }
 
   /** Generate a response using conversation history */
@@ -157,20 +847,14 @@ export class AppleAISDK {
     messages: ChatMessage[],
     options: GenerationOptions = {}
   ): Promise<string> {
-    const messagesJson = JSON.stringify(messages);
-
-    const result = await unifiedBindings.generateUnified(
-      messagesJson,
-      null, // no tools
-      null, // no schema
-      options.temperature ?? undefined,
-      options.maxTokens ?? undefined,
-      true // stopAfterToolCalls default
+    const nativeMessages = toNativeMessages(messages);
+
+    const result = await unifiedBindings.generate(
+      nativeMessages,
+      toNativeOptions(options)
     );
 
-    // Parse result and extract text
-    const parsed = JSON.parse(result);
-    return parsed.text || result;
+    return result.text;
   }
 
   /**
@@ -196,7 +880,10 @@ export class AppleAISDK {
     let error: unknown = null;
 
     // Push-based native callback
-    const handleChunk = (err: unknown, chunk?: string | null) => {
+    const handleChunk = (err: unknown, event?: StreamEvent) => {
+      if (!err && event?.type === "error") {
+        err = streamError(event);
+      }
       if (err) {
         error = err;
         done = true;
@@ -210,7 +897,12 @@ export class AppleAISDK {
 
       let chatChunk: ChatCompletionChunk;
 
-      if (chunk == null || chunk === "") {
+      // Tool activity has no representation in chat completion chunks
+      if (!event || (event.type !== "text-delta" && event.type !== "finish")) {
+        return;
+      }
+
+      if (event.type === "finish") {
         // Final chunk
         chatChunk = {
           id: completionId,
@@ -221,9 +913,16 @@ export class AppleAISDK {
             {
               index: 0,
               delta: {},
-              finish_reason: "stop",
+              finish_reason: event.finishReason,
             },
           ],
+          ...(event.usage && {
+            usage: {
+              prompt_tokens: event.usage.promptTokens,
+              completion_tokens: event.usage.completionTokens,
+              total_tokens: event.usage.totalTokens,
+            },
+          }),
         };
         done = true;
       } else {
@@ -238,7 +937,7 @@ export class AppleAISDK {
               index: 0,
               delta: {
                 ...(isFirstChunk ? { role: "assistant" as const } : {}),
-                content: chunk,
+                content: event.text,
               },
               finish_reason: null,
             },
@@ -258,14 +957,10 @@ export class AppleAISDK {
     };
 
     // Use unified streaming
-    const messagesJson = JSON.stringify(messages);
-    unifiedBindings.generateUnifiedStream(
-      messagesJson,
-      null, // no tools
-      null, // no schema
-      options.temperature ?? undefined,
-      options.maxTokens ?? undefined,
-      true, // stopAfterToolCalls default
+    const nativeMessages = toNativeMessages(messages);
+    unifiedBindings.generateStream(
+      nativeMessages,
+      toNativeOptions(options),
       handleChunk
     );
 
@@ -309,270 +1004,2607 @@ export class AppleAISDK {
     schemaJson: string; // JSON Schema as string
     temperature?: number;
     maxTokens?: number;
+    schemaRetries?: number;
   }): Promise<{ text: string; object: T }> {
-    const { prompt, schemaJson, temperature, maxTokens } = params;
+    const { prompt, schemaJson, temperature, maxTokens, schemaRetries } = params;
 
     // Convert prompt to messages format
     const messages: ChatMessage[] = [{ role: "user", content: prompt }];
-    const messagesJson = JSON.stringify(messages);
-
-    const raw = await unifiedBindings.generateUnified(
-      messagesJson,
-      null, // no tools
-      schemaJson,
-      temperature ?? undefined,
-      maxTokens ?? undefined
-    );
-
-    if (!raw) {
-      throw new Error("apple_ai_generate_unified returned null");
-    }
-
-    // Check if the response is an error string from the native layer
-    if (raw.startsWith("Error: ")) {
-      throw new Error(raw.slice(7)); // Remove "Error: " prefix and throw
-    }
-
-    let parsed: unknown;
-    try {
-      parsed = JSON.parse(raw);
-    } catch {
-      throw new Error(`Invalid JSON returned from native: ${raw}`);
-    }
-
-    if (typeof parsed !== "object" || parsed === null) {
-      throw new Error(`Unexpected response shape: ${raw}`);
-    }
-
-    return parsed as { text: string; object: T };
+    const nativeMessages = toNativeMessages(messages);
+
+    const result = await unifiedBindings
+      .generate(nativeMessages, {
+        schemaJson,
+        temperature,
+        maxTokens,
+        schemaRetries,
+      })
+      .catch(mapSchemaError);
+
+    return { text: result.text, object: result.object as T };
   }
 }
 
 export const appleAISDK = new AppleAISDK();
 
 /**
- * Unified structured generation that accepts either Zod schemas or JSON Schema
+ * Load the model ahead of a request, e.g. when a compose window opens, to
+ * hide first-token latency. Passing the instructions and the known start of
+ * the prompt lets those be processed ahead of time as well. Returns
+ * immediately; `false` if the model is unavailable.
  */
-export async function structured<T = unknown>(options: {
-  prompt: string;
-  schema: z.ZodType<T> | JSONSchema7;
-  temperature?: number;
-  maxTokens?: number;
-}): Promise<{ text: string; object: T }> {
-  const { prompt, schema, temperature, maxTokens } = options;
+export function prewarm(options?: {
+  instructions?: string;
+  promptPrefix?: string;
+  adapter?: string;
+  useCase?: ModelUseCase;
+  guardrails?: GuardrailSetting;
+}): boolean {
+  return native.prewarm(options) as boolean;
+}
 
-  let jsonSchemaString: string;
+/**
+ * Release the model resources kept between requests, such as a prewarmed
+ * session, so long-running apps can give memory back when idle. The next
+ * request initializes everything again. Returns `false` if requests were in
+ * flight; their resources are freed as they finish.
+ */
+export function releaseModel(): boolean {
+  return native.releaseModel() as boolean;
+}
 
-  // Auto-detect Zod vs JSON Schema
-  if (typeof schema === "object" && schema !== null && "parse" in schema) {
-    // It's a Zod schema
-    const jsonSchemaObj = zodToJsonSchema(schema as z.ZodType<T>, "Root");
-    jsonSchemaString = JSON.stringify(jsonSchemaObj);
-  } else {
-    // It's already a JSON Schema
-    jsonSchemaString = JSON.stringify(schema);
-  }
+/** OS version, framework level, model and use case, for bug reports and telemetry */
+export function getModelInfo(): ModelInfo {
+  return native.getModelInfo() as ModelInfo;
+}
 
-  return appleAISDK.generateStructured({
-    prompt,
-    schemaJson: jsonSchemaString,
-    temperature,
-    maxTokens,
-  });
+/**
+ * Load a custom adapter (an `.fmadapter` package trained with Apple's adapter
+ * toolkit) under `name` (default: the package file name); requests select it
+ * with the `adapter` option. Unless `activate` is false it also becomes the
+ * adapter for requests that don't name one, and loading under a taken name
+ * replaces that adapter. Rejects with code `AdapterNotFound`,
+ * `InvalidAdapter`, or `AdapterIncompatible` when the adapter was trained for
+ * a different version of the system model.
+ */
+export function loadAdapter(
+  path: string,
+  options?: { name?: string; activate?: boolean }
+): Promise<AdapterInfo> {
+  return native.loadAdapter(path, options) as Promise<AdapterInfo>;
 }
 
 /**
- * @deprecated Don't use this function directly. It's used internally by the Vercel AI SDK.
- * Stream chat that properly integrates with Vercel AI SDK's multi-step tool calling.
- * This function emits tool-call events and ends the stream, allowing the SDK to
- * orchestrate tool execution and restart generation with updated messages.
- *
- * Early termination is enabled by default when tools are present, saving compute
- * resources by stopping the Swift streaming loop after tool calls complete.
- *
+ * Unload the named adapter, or the active one; requests already running
+ * finish with it. Returns `false` if it wasn't loaded.
  */
-export function _streamChatForVercelAISDK<
-  TTools extends ReadonlyArray<EphemeralTool<JSONSchema7>>
->(options: {
-  messages: ModelMessage[];
-  tools: TTools;
-  temperature?: number;
-}): AsyncIterableIterator<
-  | { type: "text"; text: string }
-  | {
-      type: "tool-call";
-      toolCallId: string;
-      toolName: string;
-      args: Record<string, unknown>;
-    }
-> {
-  // Build tool schemas for the native layer
-  const toolSchemas = options.tools.map((tool, idx) => ({
-    id: idx + 1,
-    name: tool.name,
-    description: tool.description ?? "",
-    parameters: tool.jsonSchema,
-  }));
+export function unloadAdapter(name?: string): boolean {
+  return native.unloadAdapter(name) as boolean;
+}
 
-  // Collect all tool calls that occur during generation
-  const collectedToolCalls: Array<{
-    id: number;
-    toolName: string;
-    args: Record<string, unknown>;
-  }> = [];
+/** Make a loaded adapter the one requests use unless they name another */
+export function activateAdapter(name: string): void {
+  native.activateAdapter(name);
+}
 
-  const readable = new Readable({ read() {}, objectMode: true });
+/** Run requests that don't name an adapter against the system model again */
+export function deactivateAdapter(): void {
+  native.deactivateAdapter();
+}
 
-  // Set up tool callback to collect tool calls
-  toolBindings.setToolCallback(async (err, id, argsJson) => {
-    if (err) {
-      // Always provide a result to avoid hanging
-      toolBindings.toolResult(id, "{}");
+/** The loaded adapters, by name */
+export function listAdapters(): AdapterInfo[] {
+  return native.listAdapters() as AdapterInfo[];
+}
+
+/** Whether the on-device model supports a locale such as "de-DE" or "ja" */
+export function isLanguageSupported(locale: string): boolean {
+  return native.isLanguageSupported(locale) as boolean;
+}
+
+export interface DetectLanguageOptions {
+  /** Most hypotheses to return (default 5) */
+  maxHypotheses?: number;
+  /** Prior probabilities by language code, e.g. `{ en: 0.8, de: 0.2 }` */
+  hints?: Record<string, number>;
+  /** Language codes to choose between; others are never reported */
+  constraints?: string[];
+}
+
+export interface LanguageHypothesis {
+  /** BCP 47 code, e.g. "en", "pt" or "zh-Hans" */
+  language: string;
+  /** 0..1 */
+  probability: number;
+}
+
+export interface LanguageDetection {
+  /** The dominant language; absent when the text doesn't tell */
+  language?: string;
+  /** Candidate languages, most probable first */
+  hypotheses: LanguageHypothesis[];
+}
+
+/** Dominant language of `text`, with the likeliest candidates and their probabilities */
+export function detectLanguage(
+  text: string,
+  options?: DetectLanguageOptions,
+): LanguageDetection {
+  return native.detectLanguage(text, options) as LanguageDetection;
+}
+
+/** Options for `analyzeSentiment` */
+export interface AnalyzeSentimentOptions {
+  /** "document" (default) scores the text as a whole, "sentence" each sentence in it */
+  granularity?: "document" | "sentence";
+  /** Language of the text (default: detected) */
+  language?: string;
+}
+
+/** Sentiment of a document or sentence from `analyzeSentiment` */
+export interface SentimentScore {
+  text: string;
+  /** Position in the original text: `text.slice(start, end)` */
+  start: number;
+  end: number;
+  /** From -1 (negative) through 0 (neutral) to 1 (positive) */
+  score: number;
+}
+
+/**
+ * Score the sentiment of a text, as a whole or per sentence, with the
+ * NaturalLanguage sentiment model. Much cheaper than asking the language
+ * model for polarity.
+ */
+export function analyzeSentiment(
+  text: string,
+  options: AnalyzeSentimentOptions = {}
+): Promise<SentimentScore[]> {
+  return native.analyzeSentiment(text, options) as Promise<SentimentScore[]>;
+}
+
+export type EntityType = "person" | "place" | "organization";
+
+/** Options for `extractEntities` */
+export interface ExtractEntitiesOptions {
+  /** Kinds of entity to return, including labels of the `gazetteers` (default: all) */
+  types?: Array<EntityType | (string & {})>;
+  /** Names of gazetteers from `createGazetteer` whose terms are entities too, typed with their label */
+  gazetteers?: string[];
+  /** Language of the text (default: detected) */
+  language?: string;
+}
+
+/** A person, place or organization named in a text, or a gazetteer term, from `extractEntities` */
+export interface Entity {
+  text: string;
+  /** The kind, or the gazetteer label of a term */
+  type: EntityType | (string & {});
+  /** Position in the original text: `text.slice(start, end)` */
+  start: number;
+  end: number;
+}
+
+/**
+ * Find the people, places and organizations named in a text, in order, with
+ * the NaturalLanguage name tagger.
+ */
+export function extractEntities(
+  text: string,
+  options: ExtractEntitiesOptions = {}
+): Promise<Entity[]> {
+  return native.extractEntities(text, options) as Promise<Entity[]>;
+}
+
+/** Options for `tagText` */
+export interface TagTextOptions {
+  /**
+   * "lexicalClass" (default) tags parts of speech, "nameTypeOrLexicalClass"
+   * tags names as such and other words by part of speech, and "lemma" gives
+   * each word's dictionary form
+   */
+  scheme?: "lexicalClass" | "nameTypeOrLexicalClass" | "lemma";
+  /** Leave punctuation out (default false) */
+  omitPunctuation?: boolean;
+  /** Names of gazetteers from `createGazetteer` whose terms are tagged with their label */
+  gazetteers?: string[];
+  /** Language of the text (default: detected) */
+  language?: string;
+}
+
+/** A word of a text with its tag, from `tagText` */
+export interface TaggedToken {
+  text: string;
+  /** Position in the original text: `text.slice(start, end)` */
+  start: number;
+  end: number;
+  /**
+   * e.g. "Noun", "Verb" or "PersonalName", the lemma, or a gazetteer label;
+   * absent when the tagger has none for the word
+   */
+  tag?: string;
+}
+
+/**
+ * Split a text into words tagged with a NaturalLanguage scheme: parts of
+ * speech, names, or lemmas.
+ */
+export function tagText(
+  text: string,
+  options: TagTextOptions = {}
+): Promise<TaggedToken[]> {
+  return native.tagText(text, options) as Promise<TaggedToken[]>;
+}
+
+/** A gazetteer registered with `createGazetteer` */
+export interface GazetteerInfo {
+  name: string;
+  labels: string[];
+  /** Terms across all labels */
+  terms: number;
+}
+
+/**
+ * Compile a gazetteer of domain terms, `{ label: [term, ...] }`, and register
+ * it as `name`. Pass the name in `gazetteers` to `extractEntities` or
+ * `tagText` to have the terms tagged with their label. Replaces a gazetteer
+ * of the same name.
+ */
+export function createGazetteer(
+  name: string,
+  entries: Record<string, string[]>,
+  options: { language?: string } = {}
+): Promise<GazetteerInfo> {
+  return native.createGazetteer(name, entries, options) as Promise<GazetteerInfo>;
+}
+
+/** Unregister a gazetteer. Returns false if there was none by that name */
+export function removeGazetteer(name: string): boolean {
+  return native.removeGazetteer(name) as boolean;
+}
+
+export type DataType = "date" | "address" | "link" | "phoneNumber" | "transitInformation";
+
+/** A date resolved in its time zone */
+export interface DateComponents {
+  year: number;
+  /** 1–12 */
+  month: number;
+  day: number;
+  hour: number;
+  minute: number;
+  second: number;
+  /** 1 (Sunday) to 7 (Saturday) */
+  weekday: number;
+}
+
+export interface PostalAddress {
+  name?: string;
+  organization?: string;
+  jobTitle?: string;
+  street?: string;
+  city?: string;
+  state?: string;
+  postalCode?: string;
+  country?: string;
+}
+
+/** Data found in a text by `detectData`, with the value of its type */
+export interface DataMatch {
+  type: DataType;
+  text: string;
+  /** Position in the original text: `text.slice(start, end)` */
+  start: number;
+  end: number;
+  /** For dates: ISO 8601 with the offset of `timeZone`, for `new Date()` */
+  date?: string;
+  /** For dates: the time zone named in the text, or the system's */
+  timeZone?: string;
+  /** For dates: the date's fields in `timeZone` */
+  components?: DateComponents;
+  /** For date ranges, such as "3–5pm": length in seconds */
+  duration?: number;
+  /** For addresses: the parts found */
+  address?: PostalAddress;
+  /** For links: the URL, with a scheme added when the text has none */
+  url?: string;
+  /** For phone numbers: the number as written */
+  phoneNumber?: string;
+  /** For flights */
+  airline?: string;
+  flight?: string;
+}
+
+/**
+ * Find dates, addresses, links, phone numbers and flights in a text with
+ * NSDataDetector, in order, with their values parsed. Relative dates such
+ * as "next Tuesday at 3" are resolved against now.
+ */
+export function detectData(
+  text: string,
+  options: { types?: DataType[] } = {}
+): Promise<DataMatch[]> {
+  return native.detectData(text, options) as Promise<DataMatch[]>;
+}
+
+/** Options for `checkText` */
+export interface CheckTextOptions {
+  /** Language of the text, e.g. "en" or "en-GB" (default: detected) */
+  language?: string;
+  /** Check grammar as well as spelling (default true) */
+  grammar?: boolean;
+  /** Most suggestions per issue (default 5) */
+  maxSuggestions?: number;
+}
+
+/** A misspelling or grammar problem found by `checkText` */
+export interface TextIssue {
+  type: "spelling" | "grammar";
+  /** The text at fault */
+  text: string;
+  /** Position in the original text: `text.slice(start, end)` */
+  start: number;
+  end: number;
+  /** What's wrong, for grammar issues */
+  message?: string;
+  /** Replacements for `text`, best first; may be empty */
+  suggestions: string[];
+}
+
+/**
+ * Check spelling and grammar with the system's checker, resolving with the
+ * issues in order. Unlike asking the model to proofread, the same text
+ * always gives the same issues. Rejects with code "UnsupportedLanguage" for
+ * a language without a dictionary.
+ */
+export function checkText(
+  text: string,
+  options: CheckTextOptions = {}
+): Promise<TextIssue[]> {
+  return native.checkText(text, options) as Promise<TextIssue[]>;
+}
+
+/** What `redact` can find */
+export type RedactionCategory =
+  | "name"
+  | "organization"
+  | "place"
+  | "address"
+  | "phone"
+  | "email"
+  | "url"
+  | "creditCard"
+  | "ssn"
+  | "ipAddress";
+
+/** Options for `redact` */
+export interface RedactOptions {
+  /**
+   * What to redact (default: names, addresses, phone numbers, emails, credit
+   * card numbers, SSNs and IP addresses)
+   */
+  categories?: RedactionCategory[];
+  /** Patterns to redact as well, in Rust `regex` syntax */
+  rules?: { category: string; pattern: string }[];
+  /**
+   * "placeholder" (default) replaces each value with one like `[EMAIL_1]`,
+   * the same for the same value; "mask" with `*` per character; "remove"
+   * deletes it
+   */
+  strategy?: "placeholder" | "mask" | "remove";
+  /** Return the placeholders' values in `mapping`, for `unredact` */
+  reversible?: boolean;
+  /** Language of the text for name recognition (default: detected) */
+  language?: string;
+}
+
+/** A value redacted by `redact` */
+export interface RedactedSpan {
+  category: RedactionCategory | (string & {});
+  /** The original value */
+  text: string;
+  /** Position in the original text: `text.slice(start, end)` */
+  start: number;
+  end: number;
+  /** What replaced it */
+  replacement: string;
+}
+
+export interface RedactResult {
+  text: string;
+  /** The redacted values, in order */
+  spans: RedactedSpan[];
+  /** Original value of each placeholder, with `reversible` */
+  mapping?: Record<string, string>;
+}
+
+/**
+ * Redact personal information before text is logged or sent to a tool:
+ * names from the name tagger, addresses, phone numbers and links from
+ * NSDataDetector, and emails, card numbers, SSNs, IP addresses and `rules`
+ * by pattern.
+ */
+export function redact(
+  text: string,
+  options: RedactOptions = {}
+): Promise<RedactResult> {
+  return native.redact(text, options) as Promise<RedactResult>;
+}
+
+/** Put back the values `redact` replaced, from its `mapping` */
+export function unredact(text: string, mapping: Record<string, string>): string {
+  const placeholders = Object.keys(mapping);
+  if (placeholders.length === 0) return text;
+  const pattern = new RegExp(
+    placeholders.map((p) => p.replace(/[.*+?^${}()|[\]\\]/g, "\\$&")).join("|"),
+    "g"
+  );
+  return text.replace(pattern, (placeholder) => mapping[placeholder] ?? placeholder);
+}
+
+/** Kind of attempt an `InjectionMatch` is evidence of */
+export type InjectionCategory =
+  | "instructionOverride"
+  | "roleManipulation"
+  | "exfiltration"
+  | "toolManipulation"
+  | "hiddenContent";
+
+/** A suspicious passage found by `scanForInjection` */
+export interface InjectionMatch {
+  /** Name of the pattern, e.g. "ignorePrevious" */
+  pattern: string;
+  category: InjectionCategory;
+  text: string;
+  /** Position in the original text: `text.slice(start, end)` */
+  start: number;
+  end: number;
+}
+
+export interface InjectionScan {
+  /** Likelihood the text tries to steer the model, from 0 to 1 */
+  score: number;
+  /** Whether `score` reaches the threshold */
+  flagged: boolean;
+  /** Matches in text order */
+  matches: InjectionMatch[];
+}
+
+/** Options for `GenerationOptions.scanToolResults` */
+export interface ScanToolResultsOptions {
+  /** Score from which a result counts as an injection attempt (default 0.5) */
+  threshold?: number;
+  /** Warn the model about a flagged result (default), or withhold it */
+  action?: "warn" | "block";
+}
+
+/**
+ * Score text from outside the conversation, such as a tool result or a
+ * retrieved document, for prompt-injection attempts: instruction overrides,
+ * role changes, attempts to leak data or trigger tools, and hidden content.
+ * Heuristic and synchronous; a high score warrants a closer look, not proof.
+ */
+export function scanForInjection(
+  text: string,
+  options: { threshold?: number } = {}
+): InjectionScan {
+  return native.scanForInjection(text, options) as InjectionScan;
+}
+
+/** Context window and other static limits of the on-device model */
+export function getModelLimits(): ModelLimits {
+  return native.getModelLimits() as ModelLimits;
+}
+
+/**
+ * Count the tokens of a prompt or of chat messages, to budget against the
+ * model's context window before sending a request. Images are not counted.
+ */
+export function countTokens(input: string | ChatMessage[]): Promise<TokenCount> {
+  return native.countTokens(
+    typeof input === "string" ? input : toNativeMessages(input)
+  ) as Promise<TokenCount>;
+}
+
+/** Options for `embed` */
+export interface EmbedOptions {
+  /** Language of the texts, e.g. "en" (default: detected per text) */
+  language?: string;
+  /**
+   * "sentence" (default) uses the per-language `NLEmbedding`; "contextual"
+   * mean-pools `NLContextualEmbedding` token vectors, downloading its assets
+   * on first use
+   */
+  model?: "sentence" | "contextual";
+}
+
+/**
+ * Embed texts on the device, one vector per text in order. Vectors of the
+ * same model and language compare by cosine similarity. Rejects with code
+ * `UnsupportedLanguage` when the model doesn't cover a text's language.
+ */
+export function embed(
+  texts: string[],
+  options: EmbedOptions = {}
+): Promise<Float32Array[]> {
+  return native.embed(texts, options) as Promise<Float32Array[]>;
+}
+
+/** Options for `embedBatch` */
+export interface EmbedBatchOptions extends EmbedOptions {
+  /** Texts embedded at once, on separate threads (default: the number of CPU cores) */
+  concurrency?: number;
+}
+
+/** The vectors of an `embedBatch` call, end to end in one buffer */
+export interface EmbeddingBatch {
+  /** All vectors, in the order of the texts */
+  data: Float32Array;
+  /** `texts.length + 1` offsets: vector `i` is `data.subarray(offsets[i], offsets[i + 1])` */
+  offsets: Uint32Array;
+  /** Dimension shared by every vector, or 0 when detected languages gave different ones */
+  dimension: number;
+}
+
+/**
+ * Embed a large array of texts off the main thread, several at a time, and
+ * resolve with every vector in one contiguous `Float32Array` plus offsets,
+ * rather than an array per text. Rejects like `embed`, naming the index of
+ * the text that failed.
+ */
+export function embedBatch(
+  texts: string[],
+  options: EmbedBatchOptions = {}
+): Promise<EmbeddingBatch> {
+  return native.embedBatch(texts, options) as Promise<EmbeddingBatch>;
+}
+
+/** Options for `configureEmbeddingCache` */
+export interface EmbeddingCacheOptions {
+  /** Whether embeddings are cached (default `true`) */
+  enabled?: boolean;
+  /** Most embeddings kept; the oldest are evicted first (default 10000) */
+  maxEntries?: number;
+  /**
+   * File to keep the cache in across restarts: embeddings already in it are
+   * loaded and new ones appended. `''` stops persisting
+   */
+  path?: string;
+}
+
+/** State of the embedding cache */
+export interface EmbeddingCacheStats {
+  entries: number;
+  maxEntries: number;
+  /** Embeddings answered from the cache since it was last cleared */
+  hits: number;
+  /** Embeddings that had to be computed */
+  misses: number;
+  /** File the cache persists to */
+  path?: string;
+  enabled: boolean;
+}
+
+/** Which cached embeddings to clear or invalidate */
+export interface EmbeddingCacheFilter {
+  model?: "sentence" | "contextual";
+  language?: string;
+}
+
+/**
+ * Configure the cache every embedding goes through, keyed by the text, the
+ * model and the requested language, so re-embedding unchanged texts is a
+ * lookup. Options left out keep their current values.
+ */
+export function configureEmbeddingCache(options: EmbeddingCacheOptions): void {
+  native.configureEmbeddingCache(options);
+}
+
+/** Entry count, hits and misses of the embedding cache */
+export function embeddingCacheStats(): EmbeddingCacheStats {
+  return native.embeddingCacheStats() as EmbeddingCacheStats;
+}
+
+/**
+ * Remove every cached embedding, or those of `filter`'s model and language,
+ * and reset the hit and miss counts. Returns how many were removed.
+ */
+export function clearEmbeddingCache(filter?: EmbeddingCacheFilter): number {
+  return native.clearEmbeddingCache(filter) as number;
+}
+
+/**
+ * Remove the cached embeddings of `texts`, as embedded with `options`'
+ * model (default "sentence") and language. Returns how many were removed.
+ */
+export function invalidateEmbeddings(
+  texts: string[],
+  options: EmbeddingCacheFilter = {}
+): number {
+  return native.invalidateEmbeddings(texts, options) as number;
+}
+
+/** Options for `tokenize` */
+export interface TokenizeOptions {
+  /** What to split into (default "word") */
+  unit?: "word" | "sentence" | "paragraph";
+  /** Language of the text (default: detected) */
+  language?: string;
+}
+
+/** A word, sentence or paragraph of a text, from `tokenize` */
+export interface Token {
+  text: string;
+  /** Position in the original text: `text.slice(start, end)` */
+  start: number;
+  end: number;
+  /** Position in the text's UTF-8 encoding, e.g. in `Buffer.from(text)` */
+  byteStart: number;
+  byteEnd: number;
+}
+
+/**
+ * Split a text into words, sentences or paragraphs with the NaturalLanguage
+ * tokenizer. Words leave out punctuation and whitespace.
+ */
+export function tokenize(
+  text: string,
+  options: TokenizeOptions = {}
+): Promise<Token[]> {
+  return native.tokenize(text, options) as Promise<Token[]>;
+}
+
+/** Options for `chunkText` */
+export interface ChunkTextOptions {
+  /**
+   * "token" (default) packs whole sentences into chunks of up to `maxTokens`;
+   * "sentence" groups `maxSentences` sentences per chunk
+   */
+  by?: "token" | "sentence";
+  /** Token budget of a chunk (default 256) */
+  maxTokens?: number;
+  /** Sentences per chunk when chunking by sentence (default 5) */
+  maxSentences?: number;
+  /**
+   * What consecutive chunks share: up to this many tokens of whole sentences
+   * by token, this many sentences by sentence (default 0)
+   */
+  overlap?: number;
+  /** Language of the text for sentence splitting (default: detected) */
+  language?: string;
+}
+
+/** A chunk of a text from `chunkText` */
+export interface TextChunk {
+  text: string;
+  /** Position in the original text: `text.slice(start, end)` */
+  start: number;
+  end: number;
+  /** Tokens in the chunk, when chunking by token */
+  tokens?: number;
+}
+
+/**
+ * Split a text into chunks for embedding and retrieval. Sentences come from
+ * the NaturalLanguage tokenizer and token counts from the model's tokenizer,
+ * so chunks stay within `maxTokens`; sentences longer than that are split
+ * at whitespace.
+ */
+export function chunkText(
+  text: string,
+  options: ChunkTextOptions = {}
+): Promise<TextChunk[]> {
+  return native.chunkText(text, options) as Promise<TextChunk[]>;
+}
+
+/** Options for `semanticSearch` */
+export interface SemanticSearchOptions extends EmbedOptions {
+  /** Number of results (default 10) */
+  topK?: number;
+}
+
+/** A document ranked by `semanticSearch` */
+export interface SemanticSearchHit {
+  /** Position of the document in `documents` */
+  index: number;
+  /** Cosine similarity to the query, from -1 to 1 */
+  score: number;
+}
+
+/**
+ * Rank documents by cosine similarity to a query and resolve with the `topK`
+ * best, best first. Texts are embedded on the device; vectors from `embed`
+ * can be passed instead, to avoid embedding the same documents every time.
+ */
+export function semanticSearch(
+  query: string | Float32Array,
+  documents: string[] | Float32Array[],
+  options: SemanticSearchOptions = {}
+): Promise<SemanticSearchHit[]> {
+  return native.semanticSearch(query, documents, options) as Promise<
+    SemanticSearchHit[]
+  >;
+}
+
+/** Options for `clusterTexts`; pass `k`, `threshold` or both */
+export interface ClusterOptions {
+  /** Number of clusters */
+  k?: number;
+  /** Merge clusters while their average cosine similarity is at least this */
+  threshold?: number;
+  /**
+   * "kmeans" (default with `k`) or "agglomerative" (default with
+   * `threshold`; with `k`, merges down to `k` clusters)
+   */
+  method?: "kmeans" | "agglomerative";
+  /** Most k-means iterations (default 100) */
+  maxIterations?: number;
+  /** Seed for the initial k-means centers (default 0) */
+  seed?: number;
+  /** Language and model to embed texts with, as for `embed` */
+  language?: string;
+  model?: "sentence" | "contextual";
+}
+
+export interface Cluster {
+  /** Positions of the members in the input, in order */
+  members: number[];
+  /** The member closest to the cluster's centroid, as its representative */
+  exemplar: number;
+  /** Mean cosine similarity of the members to the centroid */
+  cohesion: number;
+}
+
+export interface ClusterResult {
+  /** Cluster of each input, indexing `clusters` */
+  assignments: number[];
+  /** Clusters, largest first */
+  clusters: Cluster[];
+}
+
+/**
+ * Group texts (embedded on the device) or vectors from `embed` by cosine
+ * similarity: into `k` clusters by k-means, or by average-linkage merging
+ * while clusters are at least `threshold` similar. Runs in Rust off the
+ * main thread.
+ */
+export function clusterTexts(
+  items: string[] | Float32Array[],
+  options: ClusterOptions
+): Promise<ClusterResult> {
+  return native.clusterTexts(items, options) as Promise<ClusterResult>;
+}
+
+/** Options for `findDuplicates` */
+export interface FindDuplicatesOptions {
+  /** Cosine similarity of embeddings from which short texts are duplicates (default 0.95) */
+  threshold?: number;
+  /** Estimated Jaccard similarity of word shingles from which long texts are duplicates (default 0.8) */
+  jaccardThreshold?: number;
+  /** Texts of more words than this are compared by MinHash instead of by embedding (default 200) */
+  longTextWords?: number;
+  /** Words per shingle for MinHash (default 5) */
+  shingleSize?: number;
+  /** Language and model to embed short texts with, as for `embed` */
+  language?: string;
+  model?: "sentence" | "contextual";
+}
+
+export interface DuplicateGroup {
+  /** Positions of the texts in the input, in order */
+  members: number[];
+  /** The first member, as the one to keep */
+  representative: number;
+  /**
+   * Lowest similarity among the pairs that joined the group: cosine for
+   * short texts, estimated Jaccard for long ones
+   */
+  similarity: number;
+}
+
+/**
+ * Find groups of near-duplicate texts, e.g. to clean a corpus before
+ * summarizing it. Short texts are compared by on-device embeddings, long
+ * ones by MinHash over word shingles. Resolves with the groups of two or
+ * more, in order of their first member.
+ */
+export function findDuplicates(
+  texts: string[],
+  options?: FindDuplicatesOptions
+): Promise<DuplicateGroup[]> {
+  return native.findDuplicates(texts, options) as Promise<DuplicateGroup[]>;
+}
+
+/** Options for `VectorIndex.search` */
+export interface VectorSearchOptions {
+  /** Number of results (default 10) */
+  topK?: number;
+  /** Only entries whose metadata has each of these properties with an equal value */
+  filter?: Record<string, unknown>;
+  /** Leave out entries scoring below this */
+  minScore?: number;
+}
+
+/** An entry found by `VectorIndex.search` or `VectorIndex.keywordSearch` */
+export interface VectorSearchHit<M = unknown> {
+  id: string;
+  /** Cosine similarity to the query, from -1 to 1, or the BM25 score of a keyword search */
+  score: number;
+  metadata?: M;
+}
+
+/** An entry of a `VectorIndex` */
+export interface VectorEntry<M = unknown> {
+  id: string;
+  vector: Float32Array;
+  metadata?: M;
+}
+
+/**
+ * In-memory vector index for local semantic search: vectors (from `embed`
+ * or `embedBatch`) with ids and optional metadata, searched exactly by
+ * cosine similarity in Rust. Each entry's text, in the metadata property
+ * `textField`, is also indexed for BM25 keyword search. After `save`, or
+ * when opened with `VectorIndex.open`, every change is appended to the
+ * index's file.
+ */
+export interface VectorIndex<M = unknown> {
+  /** Number of entries */
+  readonly size: number;
+  /** Dimension of the vectors, unset until the first is added */
+  readonly dimension: number | undefined;
+  /** File the index appends its changes to, after `save` or `open` */
+  readonly path: string | undefined;
+  /** Metadata property whose text is indexed for keyword search */
+  readonly textField: string;
+  /** Add under a new id; throws if the id is taken or the dimension differs */
+  add(id: string, vector: Float32Array, metadata?: M): void;
+  /** Add, or replace the vector and metadata of an existing id */
+  upsert(id: string, vector: Float32Array, metadata?: M): void;
+  /** Remove an entry; returns whether there was one */
+  remove(id: string): boolean;
+  /** Remove every entry whose metadata matches `filter`, e.g. `{ source: path }`; returns how many */
+  removeWhere(filter: Record<string, unknown>): number;
+  has(id: string): boolean;
+  get(id: string): VectorEntry<M> | null;
+  /** Remove every entry; the dimension stays */
+  clear(): void;
+  /** Write the index to `path`, replacing any file there, and append later changes to it */
+  save(path: string): void;
+  /** The `topK` entries most similar to `query`, best first */
+  search(query: Float32Array, options?: VectorSearchOptions): VectorSearchHit<M>[];
+  /** The `topK` entries whose text best matches the words of `query` by BM25 */
+  keywordSearch(query: string, options?: VectorSearchOptions): VectorSearchHit<M>[];
+}
+
+/** Options for `new VectorIndex` and `VectorIndex.open` */
+export interface VectorIndexOptions {
+  /** Dimension of every vector (default: that of the first one added) */
+  dimension?: number;
+  /** Metadata property holding each entry's text, indexed for keyword search (default "text") */
+  textField?: string;
+}
+
+export const VectorIndex: {
+  new <M = unknown>(options?: VectorIndexOptions): VectorIndex<M>;
+  /**
+   * Open an index saved with `save`; its changes keep being appended to the
+   * file. The keyword index is rebuilt from the entries' `textField`
+   */
+  open<M = unknown>(path: string, options?: VectorIndexOptions): VectorIndex<M>;
+} = native.VectorIndex;
+
+/** Options for `ingestDocuments` */
+export interface IngestOptions {
+  /** Walk subfolders of the folders given (default true) */
+  recursive?: boolean;
+  /** Extensions picked up in folders (default pdf, txt, md, markdown, rtf) */
+  extensions?: string[];
+  /** How to chunk each document, as in `chunkText` */
+  chunking?: ChunkTextOptions;
+  /** Language of the documents, for chunking and embedding (default: detected) */
+  language?: string;
+  /** Embedding model (default "sentence") */
+  model?: "sentence" | "contextual";
+  /** Chunks embedded at once (default: the number of CPU cores) */
+  concurrency?: number;
+  /** Properties added to the metadata of every chunk */
+  metadata?: Record<string, unknown>;
+  /** Called after each file, indexed or skipped */
+  onProgress?: (progress: IngestProgress) => void;
+}
+
+/** Progress of `ingestDocuments`, reported after each file */
+export interface IngestProgress {
+  path: string;
+  /** Files finished so far, this one included */
+  filesDone: number;
+  filesTotal: number;
+  /** Chunks this file was split into */
+  chunks: number;
+  /** Why the file was skipped, when it was */
+  error?: string;
+}
+
+export interface IngestResult {
+  /** Files indexed */
+  files: number;
+  /** Chunks written to the index */
+  chunks: number;
+  /** Files that couldn't be read, chunked or embedded */
+  failed: { path: string; error: string }[];
+}
+
+/** Metadata `ingestDocuments` gives each chunk, besides its text */
+export interface IngestedChunk {
+  /** Path of the file */
+  source: string;
+  /** Page of a PDF, from 1 */
+  page?: number;
+  /** Position of the chunk in the file, from 0 */
+  chunk: number;
+  /** Offsets of the chunk in its page or file, in UTF-16 code units */
+  start: number;
+  end: number;
+}
+
+/**
+ * Index files and folders in one call: text is extracted from PDFs (with
+ * PDFKit) and text, Markdown and RTF files, chunked, embedded and written
+ * into `index`, replacing chunks from an earlier run of the same file. Runs
+ * off the main thread; files that fail are reported and skipped.
+ */
+export function ingestDocuments(
+  index: VectorIndex,
+  paths: string | string[],
+  options: IngestOptions = {}
+): Promise<IngestResult> {
+  const { onProgress, ...rest } = options;
+  return native.ingestDocuments(
+    index,
+    Array.isArray(paths) ? paths : [paths],
+    rest,
+    onProgress
+      ? (err: Error | null, progress: IngestProgress) => {
+          if (!err) onProgress(progress);
+        }
+      : undefined
+  ) as Promise<IngestResult>;
+}
+
+/** Options for `hybridSearch` */
+export interface HybridSearchOptions {
+  /** Number of results (default 10) */
+  topK?: number;
+  /** Only entries whose metadata has each of these properties with an equal value */
+  filter?: Record<string, unknown>;
+  /**
+   * "rrf" (default) adds the reciprocal ranks from each search; "weighted"
+   * adds the scores of each search, scaled to 0..1 over its candidates
+   */
+  fusion?: "rrf" | "weighted";
+  /** Share of the vector search in the fused score, from 0 (keywords only) to 1 (default 0.5) */
+  vectorWeight?: number;
+  /** Constant of reciprocal rank fusion (default 60) */
+  rrfK?: number;
+  /** Results taken from each search before fusing (default: `topK` * 4, at least 50) */
+  candidates?: number;
+  /** Embedding of the query, when already computed */
+  vector?: Float32Array;
+  /** Language and model the index's vectors were embedded with */
+  language?: string;
+  model?: "sentence" | "contextual";
+}
+
+/** An entry found by `hybridSearch` */
+export interface HybridSearchHit<M = unknown> {
+  id: string;
+  /** Fused score; higher is better */
+  score: number;
+  /** Cosine similarity to the query, when the vector search found it */
+  vectorScore?: number;
+  /** BM25 score, when the keyword search found it */
+  keywordScore?: number;
+  metadata?: M;
+}
+
+/**
+ * Search a `VectorIndex` by meaning and by keywords at once: the nearest
+ * entries by embedding and the best BM25 matches of their text, fused into
+ * one ranking. Keywords catch exact identifiers and code tokens that
+ * embeddings miss.
+ */
+export function hybridSearch<M = unknown>(
+  index: VectorIndex<M>,
+  query: string,
+  options: HybridSearchOptions = {}
+): Promise<HybridSearchHit<M>[]> {
+  return native.hybridSearch(index, query, options) as Promise<
+    HybridSearchHit<M>[]
+  >;
+}
+
+/** Options for `rerank` */
+export interface RerankOptions {
+  /** Number of results (default: every candidate) */
+  topK?: number;
+  /** Model variant doing the scoring (default "general") */
+  useCase?: "general" | "contentTagging";
+  /** What makes a passage relevant, added to the scoring instructions */
+  criteria?: string;
+}
+
+/** A candidate ranked by `rerank` */
+export interface RerankHit {
+  /** Position of the candidate in `candidates` */
+  index: number;
+  /** Relevance to the query as the model judged it, from 0 to 1 */
+  score: number;
+  text: string;
+}
+
+/**
+ * Reorder candidate passages by their relevance to `query` as the on-device
+ * model judges it, most relevant first. Each candidate is scored by its own
+ * generation, so rerank the few dozen results of a first-stage search, not a
+ * whole corpus.
+ */
+export function rerank(
+  query: string,
+  candidates: string[],
+  options: RerankOptions = {}
+): Promise<RerankHit[]> {
+  return native.rerank(query, candidates, options) as Promise<RerankHit[]>;
+}
+
+/** Options for `ragGenerate` */
+export interface RagGenerateOptions {
+  query: string;
+  /** Chunks to answer from; each entry's metadata holds its text in `textField` */
+  index: VectorIndex;
+  /** Chunks to retrieve (default 5) */
+  topK?: number;
+  /** Only chunks whose metadata has each of these properties with an equal value */
+  filter?: Record<string, unknown>;
+  /** Leave out chunks scoring below this */
+  minScore?: number;
+  /**
+   * Prompt with `{context}` and `{query}` placeholders, and optionally
+   * `{citations}` for the instruction to cite passages
+   */
+  promptTemplate?: string;
+  /** Number the passages and ask the model to cite them as `[n]` (default true) */
+  citations?: boolean;
+  /** Metadata property holding each chunk's text (default: the index's `textField`) */
+  textField?: string;
+  /** Most tokens of retrieved context (default: what fits the context window) */
+  contextTokens?: number;
+  /** Language and model the index's vectors were embedded with */
+  language?: string;
+  model?: "sentence" | "contextual";
+  /** Options for the generation itself */
+  generation?: GenerationOptions;
+}
+
+/** A retrieved chunk that went into a `ragGenerate` prompt */
+export interface RagSource {
+  id: string;
+  /** Cosine similarity to the query */
+  score: number;
+  text: string;
+  metadata?: unknown;
+  /** Number of the passage in the prompt, as the answer cites it */
+  citation: number;
+  /** Whether the answer cites this passage */
+  cited: boolean;
+}
+
+export interface RagResult {
+  text: string;
+  /** The chunks the prompt contained, best match first */
+  sources: RagSource[];
+  finishReason: FinishReason;
+  usage?: TokenUsage;
+}
+
+/**
+ * Answer a query from a `VectorIndex` of document chunks: embed the query,
+ * retrieve the nearest chunks, fit as many as the context window allows into
+ * the prompt, and generate. Runs in Rust off the main thread and resolves
+ * with the answer and the chunks it was given, marking those it cites.
+ */
+export function ragGenerate(options: RagGenerateOptions): Promise<RagResult> {
+  const { query, index, generation = {}, ...rag } = options;
+  return native.ragGenerate(
+    index,
+    query,
+    rag,
+    toNativeOptions(generation)
+  ) as Promise<RagResult>;
+}
+
+/** Options for the word embedding functions */
+export interface WordEmbeddingOptions {
+  /** Language of the word embedding, e.g. "fr" (default "en") */
+  language?: string;
+}
+
+/** A word near another in the word embedding */
+export interface WordNeighbor {
+  word: string;
+  /** Cosine distance, from 0 (same direction) to 2 */
+  distance: number;
+}
+
+/**
+ * Up to `k` words of the language's vocabulary nearest to `word`, nearest
+ * first; empty when the word isn't in the vocabulary. Throws with code
+ * `UnsupportedLanguage` when the system has no word embedding for it.
+ */
+export function nearestNeighbors(
+  word: string,
+  k = 10,
+  options: WordEmbeddingOptions = {}
+): WordNeighbor[] {
+  return native.nearestNeighbors(word, k, options) as WordNeighbor[];
+}
+
+/**
+ * Cosine distance between two words in the word embedding, from 0 to 2, or
+ * `null` when either isn't in the language's vocabulary
+ */
+export function wordDistance(
+  a: string,
+  b: string,
+  options: WordEmbeddingOptions = {}
+): number | null {
+  return native.wordDistance(a, b, options) as number | null;
+}
+
+/** Vector of `word` in the word embedding, or `null` when it's not in the vocabulary */
+export function wordVector(
+  word: string,
+  options: WordEmbeddingOptions = {}
+): Float32Array | null {
+  return native.wordVector(word, options) as Float32Array | null;
+}
+
+/** An image with how to prepare it for analysis */
+export interface ImageSource {
+  /** Encoded image bytes in any format ImageIO reads (PNG, JPEG, HEIC, TIFF, WebP, …) */
+  data?: Buffer;
+  /** Image file to read instead of `data` */
+  path?: string;
+  /** Longest edge in pixels; larger images are downscaled before analysis (default: full size) */
+  maxDimension?: number;
+  /** How to turn the image upright (default "auto") */
+  orientation?: ImageOrientation;
+}
+
+/** An image for Vision: encoded bytes (PNG, JPEG, HEIC, TIFF, WebP, …), a file path, or either with options */
+export type ImageInput = Buffer | string | ImageSource;
+
+/** A region of an image as fractions of its width and height, from the top left corner */
+export interface BoundingBox {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+}
+
+/** Options for `recognizeText` */
+export interface RecognizeTextOptions {
+  /** Languages to recognize, most likely first, e.g. `["en-US", "fr-FR"]` (default: detected) */
+  languages?: string[];
+  /** "accurate" (default) or "fast", which suits real-time use but reads less reliably */
+  level?: "accurate" | "fast";
+  /** Correct recognized words with a language model (default true); turn off for codes */
+  languageCorrection?: boolean;
+}
+
+/** A line of text found in an image */
+export interface TextBlock {
+  text: string;
+  /** From 0 to 1 */
+  confidence: number;
+  bounds: BoundingBox;
+}
+
+export interface RecognizedText {
+  /** The blocks' text, one per line */
+  text: string;
+  /** In reading order */
+  blocks: TextBlock[];
+  /** Size of the image in pixels, once oriented */
+  width: number;
+  height: number;
+}
+
+/**
+ * Recognize the text in a screenshot, photo or scan with Vision, on the
+ * device. Rejects with code `UnsupportedLanguage` for a language Vision
+ * can't read, and `InvalidArg` for an unreadable image.
+ */
+export function recognizeText(
+  image: ImageInput,
+  options?: RecognizeTextOptions
+): Promise<RecognizedText> {
+  return native.recognizeText(image, options) as Promise<RecognizedText>;
+}
+
+/** Options for `classifyImage` */
+export interface ClassifyImageOptions {
+  /** Most labels returned (default 10) */
+  topK?: number;
+  /** Least confidence of a label returned, from 0 to 1 (default 0.1) */
+  minConfidence?: number;
+}
+
+/** A label from Vision's image classifier, such as "dog" or "document" */
+export interface ImageLabel {
+  identifier: string;
+  /** From 0 to 1 */
+  confidence: number;
+}
+
+/**
+ * Classify an image with Vision's on-device classifier, for tagging or
+ * routing images. Resolves with the labels that reach `minConfidence`, most
+ * confident first.
+ */
+export function classifyImage(
+  image: ImageInput,
+  options?: ClassifyImageOptions
+): Promise<ImageLabel[]> {
+  return native.classifyImage(image, options) as Promise<ImageLabel[]>;
+}
+
+/** Options for `analyzeSaliency` */
+export interface AnalyzeSaliencyOptions {
+  /** Width-to-height ratio of the suggested crop, e.g. 1 for a square thumbnail (default: the salient region's own) */
+  aspectRatio?: number;
+  /** Margin kept around the salient region in the crop, as a fraction of its size (default 0.1) */
+  padding?: number;
+}
+
+export interface SalientRegion {
+  bounds: BoundingBox;
+  /** From 0 to 1 */
+  confidence: number;
+}
+
+export interface SaliencyAnalysis {
+  /** Regions people tend to look at first */
+  attention: SalientRegion[];
+  /** The image's main objects */
+  objects: SalientRegion[];
+  /** Crop keeping the attention regions (the objects when there are none) */
+  crop: BoundingBox;
+  /** Size of the image in pixels, once oriented */
+  width: number;
+  height: number;
+}
+
+/**
+ * Find where attention goes in an image and its main objects with Vision's
+ * saliency analysis, and suggest a crop that keeps them, e.g. for a
+ * thumbnail.
+ */
+export function analyzeSaliency(
+  image: ImageInput,
+  options?: AnalyzeSaliencyOptions
+): Promise<SaliencyAnalysis> {
+  return native.analyzeSaliency(image, options) as Promise<SaliencyAnalysis>;
+}
+
+/** Options for `describeImage` */
+export interface DescribeImageOptions {
+  /** "altText" (default) for screen readers, "caption" for a line under the image, or "description" for prose */
+  style?: "altText" | "caption" | "description";
+  /** Length of the description (default "brief" for alt text and captions, "detailed" for descriptions) */
+  detail?: "brief" | "standard" | "detailed";
+  /** Give the model the image as well as Vision's findings when it takes image input (default true) */
+  attachImage?: boolean;
+  /** Options for the generation itself */
+  generation?: GenerationOptions;
+}
+
+export interface ImageDescription {
+  text: string;
+  /** Vision's labels for the image, most confident first */
+  labels: ImageLabel[];
+  /** Text Vision read in the image */
+  recognizedText: string;
+  /** Whether the model saw the image itself rather than only Vision's findings */
+  sawImage: boolean;
+  finishReason: FinishReason;
+  usage?: TokenUsage;
+}
+
+/**
+ * Describe an image in words, e.g. as alt text: Vision classifies it and
+ * reads its text, and the on-device model writes the description from
+ * those findings.
+ */
+export function describeImage(
+  image: ImageInput,
+  options: DescribeImageOptions = {}
+): Promise<ImageDescription> {
+  const { generation = {}, ...describe } = options;
+  return native.describeImage(
+    image,
+    describe,
+    toNativeOptions(generation)
+  ) as Promise<ImageDescription>;
+}
+
+export interface ImageGenerationAvailability {
+  available: boolean;
+  /** Why images can't be generated, when they can't */
+  reason?: string;
+  /** Styles this device can generate in */
+  styles: Array<"animation" | "illustration" | "sketch">;
+}
+
+/** Whether this device can generate images with `generateImage`, and in which styles */
+export function imageGenerationAvailability(): Promise<ImageGenerationAvailability> {
+  return native.imageGenerationAvailability() as Promise<ImageGenerationAvailability>;
+}
+
+/** Options for `generateImage` */
+export interface GenerateImageOptions {
+  /** Style of the images (default: the first the device has, usually "animation") */
+  style?: "animation" | "illustration" | "sketch";
+  /** Number of images, from 1 to 4 (default 1) */
+  count?: number;
+}
+
+/**
+ * Generate images for a prompt on the device with Image Playground.
+ * Resolves with PNG data, one `Buffer` per image. Rejects with
+ * `ImageGenerationUnavailableError` where the device or system can't
+ * generate images.
+ */
+export function generateImage(
+  prompt: string,
+  options?: GenerateImageOptions
+): Promise<Buffer[]> {
+  return (native.generateImage(prompt, options) as Promise<Buffer[]>).catch(
+    (err: unknown) => {
+      if ((err as { code?: string } | null)?.code === "ImageGenerationUnavailable") {
+        throw new ImageGenerationUnavailableError((err as Error).message);
+      }
+      throw err;
+    }
+  );
+}
+
+/** Options for speech recognition */
+export interface TranscribeOptions {
+  /** Locale of the speech, e.g. "en-US" (default: the system's) */
+  locale?: string;
+  /** Words and phrases the audio is likely to contain, such as names and jargon */
+  contextualStrings?: string[];
+  /** Punctuate the transcript (default true) */
+  punctuation?: boolean;
+}
+
+export interface TranscriptSegment {
+  text: string;
+  /** Start in seconds from the beginning of the audio */
+  start: number;
+  /** Length in seconds */
+  duration: number;
+  /** From 0 to 1 */
+  confidence: number;
+}
+
+export interface Transcript {
+  text: string;
+  /** Locale the speech was recognized in */
+  locale: string;
+  /** The transcript word by word, with timings */
+  segments: TranscriptSegment[];
+}
+
+/**
+ * Transcribe an audio file (WAV, M4A, MP3, CAF, …) on the device. Rejects
+ * with code "PermissionDenied" when speech recognition isn't allowed and
+ * "AssetsUnavailable" when the locale's on-device model isn't installed.
+ */
+export function transcribeFile(
+  path: string,
+  options?: TranscribeOptions
+): Promise<Transcript> {
+  return native.transcribeFile(path, options) as Promise<Transcript>;
+}
+
+/** Options for `startTranscription` */
+export interface StartTranscriptionOptions extends TranscribeOptions {
+  /** Silence in milliseconds after which an utterance is final and the next one starts (default 1200) */
+  pauseMs?: number;
+  /** The utterance so far, each call replacing the last */
+  onPartial?: (transcript: Transcript) => void;
+  /** A settled utterance */
+  onFinal?: (transcript: Transcript) => void;
+  /** Recognition failed; the session ends after this */
+  onError?: (error: Error & { code?: ErrorCode }) => void;
+  /** The session is over, after `stopTranscription()` or an error */
+  onEnd?: () => void;
+}
+
+/**
+ * Transcribe the microphone on the device until `stopTranscription()`,
+ * reporting each utterance to `onPartial` as it's spoken and to `onFinal`
+ * once a pause settles it. Resolves once the microphone is listening;
+ * rejects with code "PermissionDenied" when the microphone or speech
+ * recognition isn't allowed, and "ConcurrentRequests" while another
+ * transcription runs.
+ */
+export function startTranscription(options: StartTranscriptionOptions = {}): Promise<void> {
+  const { onPartial, onFinal, onError, onEnd, ...rest } = options;
+  return native.startTranscription(
+    rest,
+    (
+      err: Error | null,
+      event: {
+        type: "partial" | "final" | "error" | "end";
+        transcript?: Transcript;
+        message?: string;
+        code?: ErrorCode;
+      }
+    ) => {
+      if (err) return;
+      switch (event.type) {
+        case "partial":
+          onPartial?.(event.transcript!);
+          break;
+        case "final":
+          onFinal?.(event.transcript!);
+          break;
+        case "error":
+          onError?.(Object.assign(new Error(event.message), { code: event.code }));
+          break;
+        case "end":
+          onEnd?.();
+          break;
+      }
+    }
+  ) as Promise<void>;
+}
+
+/**
+ * Stop the microphone transcription. The utterance in progress still reaches
+ * `onFinal`, then `onEnd` is called. Returns whether a transcription was running.
+ */
+export function stopTranscription(): boolean {
+  return native.stopTranscription() as boolean;
+}
+
+/** Options for `classifySound` and `startSoundClassification` */
+export interface ClassifySoundOptions {
+  /** Most labels per window (default 3) */
+  topK?: number;
+  /** Lowest confidence of a label, 0–1 (default 0.3); windows with no label above it are left out */
+  minConfidence?: number;
+  /** Length of a window in seconds, rounded to one the classifier supports (default about 1.5) */
+  windowDuration?: number;
+  /** How much consecutive windows overlap, from 0 up to 1 (default 0.5) */
+  overlap?: number;
+}
+
+export interface SoundClassification {
+  /** e.g. "speech", "dog_bark" or "applause" */
+  identifier: string;
+  /** 0–1 */
+  confidence: number;
+}
+
+/** The sounds heard in a stretch of audio */
+export interface SoundWindow {
+  /** Start in seconds from the beginning of the audio */
+  start: number;
+  /** Length in seconds */
+  duration: number;
+  /** Most confident first */
+  classifications: SoundClassification[];
+}
+
+/**
+ * Classify the sounds in an audio file, or a Buffer of encoded audio (WAV,
+ * M4A, MP3, CAF, …), with the built-in SoundAnalysis classifier. Resolves
+ * with the windows in time order.
+ */
+export function classifySound(
+  audio: string | Buffer,
+  options: ClassifySoundOptions = {}
+): Promise<SoundWindow[]> {
+  return native.classifySound(audio, options) as Promise<SoundWindow[]>;
+}
+
+/** Options for `startSoundClassification` */
+export interface StartSoundClassificationOptions extends ClassifySoundOptions {
+  /** A stretch of audio with a recognized sound, timed from when listening started */
+  onWindow?: (window: SoundWindow) => void;
+  /** Classification failed; the session ends after this */
+  onError?: (error: Error & { code?: ErrorCode }) => void;
+  /** The session is over, after `stopSoundClassification()` or an error */
+  onEnd?: () => void;
+}
+
+/**
+ * Classify sounds from the microphone until `stopSoundClassification()`,
+ * reporting each window with a recognized sound to `onWindow`. Resolves once
+ * the microphone is listening; rejects with code "PermissionDenied" when the
+ * microphone isn't allowed, and "ConcurrentRequests" while another sound
+ * classification runs. It can run alongside `startTranscription`.
+ */
+export function startSoundClassification(
+  options: StartSoundClassificationOptions = {}
+): Promise<void> {
+  const { onWindow, onError, onEnd, ...rest } = options;
+  return native.startSoundClassification(
+    rest,
+    (
+      err: Error | null,
+      event: {
+        type: "window" | "error" | "end";
+        window?: SoundWindow;
+        message?: string;
+        code?: ErrorCode;
+      }
+    ) => {
+      if (err) return;
+      switch (event.type) {
+        case "window":
+          onWindow?.(event.window!);
+          break;
+        case "error":
+          onError?.(Object.assign(new Error(event.message), { code: event.code }));
+          break;
+        case "end":
+          onEnd?.();
+          break;
+      }
+    }
+  ) as Promise<void>;
+}
+
+/**
+ * Stop the microphone sound classification. Windows of the audio heard so
+ * far still reach `onWindow`, then `onEnd` is called. Returns whether one
+ * was running.
+ */
+export function stopSoundClassification(): boolean {
+  return native.stopSoundClassification() as boolean;
+}
+
+/** Options for `listVoices` */
+export interface ListVoicesOptions {
+  /** Only voices for this language, e.g. "en" or "en-GB" */
+  language?: string;
+  /** Ask the user for access to their personal voices if they haven't been asked yet */
+  requestPersonalVoice?: boolean;
+}
+
+export interface Voice {
+  /** Pass as `voice` to pick this voice */
+  identifier: string;
+  name: string;
+  /** BCP 47 code, e.g. "en-US" */
+  language: string;
+  /** "enhanced" and "premium" voices sound more natural and are downloaded in System Settings */
+  quality: "default" | "enhanced" | "premium";
+  gender?: "male" | "female";
+  /** A personal voice the user made of their own voice */
+  personal: boolean;
+}
+
+export interface VoiceList {
+  voices: Voice[];
+  /** Whether personal voices can be listed and used; they're only among `voices` once "authorized" */
+  personalVoice: "authorized" | "denied" | "notDetermined" | "unsupported";
+}
+
+/** The voices speech synthesis can use on this device, for a voice picker */
+export function listVoices(options?: ListVoicesOptions): Promise<VoiceList> {
+  return native.listVoices(options) as Promise<VoiceList>;
+}
+
+/** Options for speech synthesis */
+export interface SpeechOptions {
+  /** Identifier of the voice from `listVoices` (default: the system voice for `language`) */
+  voice?: string;
+  /** Language to speak in when no `voice` is given, e.g. "fr-FR" (default: the system's) */
+  language?: string;
+  /** Speaking rate from 0 (slowest) to 1 (fastest), default 0.5 */
+  rate?: number;
+  /** Pitch multiplier from 0.5 to 2 (default 1) */
+  pitch?: number;
+  /** Volume from 0 to 1 (default 1) */
+  volume?: number;
+}
+
+/**
+ * Speak text on the device. Resolves with the audio as WAV data (16-bit PCM);
+ * rejects with code "InvalidArg" for an unknown `voice` and
+ * "UnsupportedLanguage" when no voice speaks `language`.
+ */
+export function synthesizeSpeech(text: string, options?: SpeechOptions): Promise<Buffer> {
+  return native.synthesizeSpeech(text, options) as Promise<Buffer>;
+}
+
+/** A piece of streamed speech audio */
+export interface SpeechAudio {
+  /** Interleaved 16-bit little-endian PCM samples */
+  audio: Buffer;
+  sampleRate: number;
+  channels: number;
+}
+
+/** Where a spoken word starts */
+export interface SpeechWord {
+  /** Index of the word in the text */
+  start: number;
+  /** Length of the word in string indices, so `text.slice(start, start + length)` is the word */
+  length: number;
+  /** Seconds into the audio where the word starts */
+  time: number;
+}
+
+/** Options for `streamSpeech` */
+export interface StreamSpeechOptions extends SpeechOptions {
+  /** Each piece of audio as it's synthesized */
+  onAudio?: (chunk: SpeechAudio) => void;
+  /** Each word, just before the audio it starts in */
+  onWord?: (word: SpeechWord) => void;
+  /** All is spoken, or `stop()` ended the stream early (`stopped`) */
+  onEnd?: (stopped: boolean) => void;
+  onError?: (error: Error & { code?: ErrorCode }) => void;
+}
+
+/**
+ * Speak text on the device, streaming the audio to `onAudio` as it's
+ * synthesized so playback can start before a long text is done, with
+ * `onWord` marking where each word starts for highlighting. `stop()` ends
+ * the stream early and returns whether it was still running.
+ */
+export function streamSpeech(
+  text: string,
+  options: StreamSpeechOptions = {}
+): { stop: () => boolean } {
+  const { onAudio, onWord, onEnd, onError, ...speech } = options;
+  return native.streamSpeech(
+    text,
+    speech,
+    (
+      err: Error | null,
+      event: Partial<SpeechAudio & SpeechWord> & {
+        type: "audio" | "word" | "end" | "error";
+        stopped?: boolean;
+        message?: string;
+        code?: ErrorCode;
+      }
+    ) => {
+      if (err) return;
+      switch (event.type) {
+        case "audio":
+          onAudio?.({
+            audio: event.audio!,
+            sampleRate: event.sampleRate!,
+            channels: event.channels!,
+          });
+          break;
+        case "word":
+          onWord?.({ start: event.start!, length: event.length!, time: event.time! });
+          break;
+        case "end":
+          onEnd?.(event.stopped ?? false);
+          break;
+        case "error":
+          onError?.(Object.assign(new Error(event.message), { code: event.code }));
+          break;
+      }
+    }
+  ) as { stop: () => boolean };
+}
+
+export type VoiceSessionState = "listening" | "thinking" | "speaking" | "stopped";
+
+/** Options for `VoiceSession.start` */
+export interface VoiceSessionOptions {
+  /** System instructions for the assistant */
+  instructions?: string;
+  /** Conversation to continue */
+  history?: ChatMessage[];
+  /** How to recognize the user's speech */
+  transcription?: TranscribeOptions & { pauseMs?: number };
+  /** Voice and delivery of replies */
+  speech?: SpeechOptions;
+  /** Generation options for replies */
+  generation?: GenerationOptions;
+  /**
+   * Stop a reply as soon as the user speaks over it (default true). When false,
+   * speech during a reply is ignored.
+   */
+  bargeIn?: boolean;
+  onStateChange?: (state: VoiceSessionState) => void;
+  /** What the user is saying, each call replacing the last until `final` */
+  onTranscript?: (text: string, final: boolean) => void;
+  /** Each piece of the reply as it's generated */
+  onReplyDelta?: (text: string) => void;
+  /** The reply once done, or as far as it got when `interrupted` */
+  onReply?: (text: string, interrupted: boolean) => void;
+  /** Audio of the reply to play, as `streamSpeech` produces it */
+  onAudio?: (chunk: SpeechAudio) => void;
+  onError?: (error: Error & { code?: ErrorCode }) => void;
+}
+
+/** Sentence ends after which the reply so far can be spoken */
+const SENTENCE_END = /[.!?…]+(?=\s)|[。！？]+|\n/g;
+
+/**
+ * A spoken conversation with the on-device model: the microphone is
+ * transcribed, each utterance is answered with a streamed reply, and the
+ * reply is spoken sentence by sentence while the rest is still generated.
+ * Speaking over a reply stops it (barge-in).
+ */
+export class VoiceSession {
+  #state: VoiceSessionState = "listening";
+  #stopping = false;
+  #history: ChatMessage[];
+  /** Number of the current reply; events of earlier ones are ignored */
+  #turn = 0;
+  #reply = "";
+  /** Generated text not yet spoken whole sentences of */
+  #pending = "";
+  #sentences: string[] = [];
+  #generating = false;
+  #cancelGeneration?: () => void;
+  #speech?: { stop: () => boolean };
+
+  private constructor(private readonly options: VoiceSessionOptions) {
+    this.#history = [...(options.history ?? [])];
+  }
+
+  /**
+   * Start listening. Resolves once the microphone is on; rejects as
+   * `startTranscription` does.
+   */
+  static async start(options: VoiceSessionOptions = {}): Promise<VoiceSession> {
+    const session = new VoiceSession(options);
+    await startTranscription({
+      ...options.transcription,
+      onPartial: ({ text }) => session.#heard(text, false),
+      onFinal: ({ text }) => session.#heard(text, true),
+      onError: (error) => options.onError?.(error),
+      onEnd: () => session.#ended(),
+    });
+    options.onStateChange?.("listening");
+    return session;
+  }
+
+  get state(): VoiceSessionState {
+    return this.#state;
+  }
+
+  /** The conversation so far, including interrupted replies */
+  get history(): ChatMessage[] {
+    return [...this.#history];
+  }
+
+  /** Stop the reply in progress, if any, and go back to listening */
+  interrupt(): void {
+    if (this.#state === "thinking" || this.#state === "speaking") {
+      this.#finishReply(true);
+    }
+  }
+
+  /** Stop the reply in progress and the microphone; the state becomes "stopped" */
+  stop(): void {
+    if (this.#stopping) return;
+    this.#stopping = true;
+    this.interrupt();
+    if (!stopTranscription()) this.#ended();
+  }
+
+  #setState(state: VoiceSessionState): void {
+    if (this.#state === state || this.#state === "stopped") return;
+    this.#state = state;
+    this.options.onStateChange?.(state);
+  }
+
+  #heard(text: string, final: boolean): void {
+    if (this.#stopping || !text.trim()) return;
+    const replying = this.#state === "thinking" || this.#state === "speaking";
+    if (replying) {
+      if (!(this.options.bargeIn ?? true)) return;
+      this.#finishReply(true);
+    }
+    this.options.onTranscript?.(text, final);
+    if (final) this.#respond(text);
+  }
+
+  #respond(text: string): void {
+    this.#history.push({ role: "user", content: text });
+    const turn = ++this.#turn;
+    this.#reply = "";
+    this.#pending = "";
+    this.#sentences = [];
+    this.#generating = true;
+    this.#setState("thinking");
+
+    const { instructions, generation } = this.options;
+    const messages: ChatMessage[] = instructions
+      ? [{ role: "system", content: instructions }, ...this.#history]
+      : this.#history;
+    const { nativeMessages, nativeOptions } = chatRequest({ ...generation, messages });
+    const handle = native.generateStreamAbortable(
+      nativeMessages,
+      nativeOptions,
+      (err: Error | null, event?: StreamEvent) => {
+        if (turn !== this.#turn) return;
+        if (err || event?.type === "error") {
+          this.options.onError?.(event?.type === "error" ? streamError(event) : (err as Error));
+          this.#finishReply(true);
+        } else if (event?.type === "text-delta" && event.text) {
+          this.#reply += event.text;
+          this.#pending += event.text;
+          this.options.onReplyDelta?.(event.text);
+          this.#takeSentences(false);
+        } else if (event?.type === "finish") {
+          this.#generating = false;
+          this.#takeSentences(true);
+          if (!this.#speech) this.#speakNext();
+        }
+      }
+    ) as { cancel: () => void };
+    this.#cancelGeneration = handle.cancel;
+  }
+
+  /** Queue the whole sentences of the pending text, or all of it once generation is done */
+  #takeSentences(done: boolean): void {
+    let start = 0;
+    for (const match of this.#pending.matchAll(SENTENCE_END)) {
+      const end = match.index! + match[0].length;
+      this.#enqueue(this.#pending.slice(start, end));
+      start = end;
+    }
+    this.#pending = this.#pending.slice(start);
+    if (done) {
+      this.#enqueue(this.#pending);
+      this.#pending = "";
+    }
+  }
+
+  #enqueue(sentence: string): void {
+    if (!sentence.trim()) return;
+    this.#sentences.push(sentence.trim());
+    if (!this.#speech) this.#speakNext();
+  }
+
+  /** Speak the next queued sentence; the reply is over once none are left */
+  #speakNext(): void {
+    const sentence = this.#sentences.shift();
+    if (sentence === undefined) {
+      this.#speech = undefined;
+      if (!this.#generating) this.#finishReply(false);
       return;
     }
+    const turn = this.#turn;
+    this.#setState("speaking");
+    this.#speech = streamSpeech(sentence, {
+      ...this.options.speech,
+      onAudio: (chunk) => {
+        if (turn === this.#turn) this.options.onAudio?.(chunk);
+      },
+      onEnd: () => {
+        if (turn === this.#turn) this.#speakNext();
+      },
+      onError: (error) => {
+        if (turn !== this.#turn) return;
+        this.options.onError?.(error);
+        this.#finishReply(true);
+      },
+    });
+  }
+
+  #finishReply(interrupted: boolean): void {
+    this.#turn++;
+    this.#cancelGeneration?.();
+    this.#cancelGeneration = undefined;
+    this.#speech?.stop();
+    this.#speech = undefined;
+    this.#generating = false;
+    this.#sentences = [];
+    this.#pending = "";
+    if (this.#reply) {
+      this.#history.push({ role: "assistant", content: this.#reply });
+      this.options.onReply?.(this.#reply, interrupted);
+      this.#reply = "";
+    }
+    this.#setState("listening");
+  }
+
+  #ended(): void {
+    this.#stopping = true;
+    this.interrupt();
+    this.#setState("stopped");
+  }
+}
+
+export interface TranslationLanguage {
+  /** BCP 47 code, e.g. "en" or "zh-Hant" */
+  code: string;
+  /** Name in the user's language */
+  name: string;
+}
+
+/** The languages offline translation supports, installed or not */
+export function getTranslationLanguages(): Promise<TranslationLanguage[]> {
+  return native.getTranslationLanguages() as Promise<TranslationLanguage[]>;
+}
+
+/** Whether translating `from` into `to` works offline now, with both languages on the device */
+export function isPairInstalled(from: string, to: string): Promise<boolean> {
+  return native.isPairInstalled(from, to) as Promise<boolean>;
+}
+
+export interface LanguagePairProgress {
+  state: "notInstalled" | "installed";
+  /**
+   * Time since the download was asked for. The framework doesn't report a
+   * completed fraction, so show an indeterminate progress indicator with this
+   * as the elapsed time.
+   */
+  elapsedMs: number;
+}
+
+/** Options for `downloadLanguagePair` */
+export interface DownloadLanguagePairOptions {
+  /** Open System Settings where the languages are downloaded (default true) */
+  openSettings?: boolean;
+  /** How often to check whether the languages arrived, in milliseconds (default 2000) */
+  intervalMs?: number;
+  /** Give up after this many milliseconds (default 600000) */
+  timeoutMs?: number;
+  onProgress?: (progress: LanguagePairProgress) => void;
+}
+
+/**
+ * Get the languages for translating `from` into `to` onto the device. The
+ * system downloads translation languages only with the user's consent, so
+ * this opens System Settings where they're downloaded and reports progress
+ * until both are installed. Resolves at once when they already are; rejects
+ * with code "UnsupportedLanguage" for a pair translation doesn't support and
+ * "Timeout" when they don't arrive within `timeoutMs`.
+ */
+export function downloadLanguagePair(
+  from: string,
+  to: string,
+  options: DownloadLanguagePairOptions = {}
+): Promise<void> {
+  const { onProgress, ...rest } = options;
+  return native.downloadLanguagePair(
+    from,
+    to,
+    rest,
+    onProgress
+      ? (err: Error | null, progress: LanguagePairProgress) => {
+          if (!err) onProgress(progress);
+        }
+      : undefined
+  ) as Promise<void>;
+}
+
+/** Options for `translate` */
+export interface TranslateOptions {
+  /** Language to translate into, e.g. "de" */
+  to: string;
+  /** Language of the text (default: detected) */
+  from?: string;
+}
+
+/**
+ * Translate text on the device; an array is translated string by string.
+ * Rejects with code "AssetsUnavailable" while the pair's languages aren't
+ * downloaded (see `downloadLanguagePair`) and "UnsupportedLanguage" for a
+ * pair translation doesn't support.
+ */
+export async function translate(text: string, options: TranslateOptions): Promise<string>;
+export async function translate(text: string[], options: TranslateOptions): Promise<string[]>;
+export async function translate(
+  text: string | string[],
+  options: TranslateOptions
+): Promise<string | string[]> {
+  const { translations } = (await native.translate(text, options)) as {
+    translations: string[];
+    from: string;
+  };
+  return Array.isArray(text) ? translations : translations[0];
+}
+
+/**
+ * Run an OpenAI `chat.completions` request body (JSON) on the on-device model
+ * and resolve with the `chat.completion` response (JSON). Tool calls come
+ * back in the response for the caller to run, as with OpenAI; `stream: true`
+ * is rejected.
+ */
+export function openaiChatCompletion(requestJson: string): Promise<string> {
+  return native.openaiChatCompletion(requestJson) as Promise<string>;
+}
+
+/**
+ * Stream an OpenAI `chat.completions` request body (JSON) as OpenAI
+ * server-sent events: each item is one or more `data: {chat.completion.chunk}`
+ * events, ending with `data: [DONE]` (or an error event), ready to write to
+ * an HTTP response as is.
+ */
+export function openaiChatCompletionStream(
+  requestJson: string
+): AsyncIterableIterator<string> {
+  const readable = new Readable({ read() {}, objectMode: true });
+  native.openaiChatCompletionStream(
+    requestJson,
+    (err: Error | null, frames: string, done: boolean) => {
+      if (err) {
+        readable.destroy(err);
+        return;
+      }
+      if (frames) readable.push(frames);
+      if (done) readable.push(null);
+    }
+  );
+  return readable[Symbol.asyncIterator]() as AsyncIterableIterator<string>;
+}
+
+/**
+ * Run an Anthropic Messages request body (JSON) on the on-device model and
+ * resolve with the `message` response (JSON). `tool_use` blocks come back
+ * for the caller to run and answer with `tool_result` blocks.
+ */
+export function anthropicMessages(requestJson: string): Promise<string> {
+  return native.anthropicMessages(requestJson) as Promise<string>;
+}
+
+/**
+ * Connect to an MCP server, launching it when `command` is set, and list its
+ * tools. Requests expose them by naming the server in `mcpServers`.
+ * Connecting under a name already in use replaces that connection.
+ */
+export function connectMcpServer(
+  config: McpServerConfig
+): Promise<McpServerInfo> {
+  return native.connectMcpServer(config) as Promise<McpServerInfo>;
+}
+
+/**
+ * Disconnect an MCP server, stopping it if it was launched. Returns `false`
+ * if no server was connected under `name`.
+ */
+export function disconnectMcpServer(name: string): boolean {
+  return native.disconnectMcpServer(name) as boolean;
+}
+
+/** The connected MCP servers and their tools */
+export function listMcpServers(): McpServerInfo[] {
+  return native.listMcpServers() as McpServerInfo[];
+}
+
+/** Options for `startNativeServer` */
+export interface NativeServerOptions {
+  /** Port to listen on (default 8080); 0 picks a free one */
+  port?: number;
+  /** Address to bind (default "127.0.0.1", local connections only) */
+  host?: string;
+  /** Require `Authorization: Bearer <apiKey>` on every request */
+  apiKey?: string;
+}
+
+/**
+ * Start the OpenAI-compatible HTTP server built into the native module,
+ * serving `/v1/chat/completions` and `/v1/models` from Rust. Unlike
+ * `startServer`, requests don't pass through the Node event loop.
+ */
+export function startNativeServer(options: NativeServerOptions = {}): {
+  url: string;
+  port: number;
+  stop: () => void;
+} {
+  return native.startServer(options);
+}
+
+/**
+ * Serve the model as newline-delimited JSON-RPC on a Unix domain socket at
+ * `path` (`generate`, `stream`, `embed`, `availability` and `ping`), so helper
+ * processes and other runtimes on the Mac can share this process's warm
+ * model. The socket is created with mode 0o600 unless `permissions` says
+ * otherwise; `stop()` closes it and removes the file.
+ */
+export function startSocketServer(
+  path: string,
+  options: { permissions?: number } = {}
+): { path: string; stop: () => void } {
+  return native.startSocketServer(path, options);
+}
+
+/** Options for `startGrpcServer` */
+export interface GrpcServerOptions {
+  /** Port to listen on (default 50051); 0 picks a free one */
+  port?: number;
+  /** Address to bind (default "127.0.0.1", local connections only) */
+  host?: string;
+}
+
+/**
+ * Serve the `apple_ai.v1.AppleAI` gRPC service from `native/proto/apple_ai.proto`
+ * (`Generate`, `GenerateStream` and `Availability`). Only available when the
+ * native module was built with the `grpc` Cargo feature.
+ */
+export function startGrpcServer(options: GrpcServerOptions = {}): {
+  address: string;
+  port: number;
+  stop: () => void;
+} {
+  if (typeof native.startGrpcServer !== "function") {
+    throw new Error(
+      "startGrpcServer needs the native module built with the grpc feature (CARGO_FEATURES=grpc ./build.sh)"
+    );
+  }
+  return native.startGrpcServer(options);
+}
+
+/** A text resource served by `startMcpServer` */
+export interface McpResource {
+  uri: string;
+  name: string;
+  description?: string;
+  /** Default "text/plain" */
+  mimeType?: string;
+  text: string;
+}
+
+/** Options for `startMcpServer` */
+export interface McpServeOptions {
+  /**
+   * "stdio" (default) serves on this process's stdin and stdout, for MCP
+   * hosts that launch the script; "http" listens for Streamable HTTP at `/mcp`
+   */
+  transport?: "stdio" | "http";
+  /** HTTP port (default 8081); 0 picks a free one */
+  port?: number;
+  /** HTTP address to bind (default "127.0.0.1", local connections only) */
+  host?: string;
+  /** Require `Authorization: Bearer <apiKey>` on every HTTP request */
+  apiKey?: string;
+  /** Names of tools from `registerTool` to serve next to `generate` */
+  tools?: string[];
+  /** Text resources to serve */
+  resources?: McpResource[];
+}
+
+/**
+ * Serve the on-device model over the Model Context Protocol, as a `generate`
+ * tool plus any chosen registered tools and resources, so editors and other
+ * MCP hosts can call it. Over HTTP the handle includes the URL and port.
+ */
+export function startMcpServer(options: McpServeOptions = {}): {
+  url?: string;
+  port?: number;
+  stop: () => void;
+} {
+  return native.startMcpServer(options);
+}
+
+/**
+ * Infer a JSON Schema from an example value (as JSON), for `structured` or
+ * `chat({ schema })` without writing the schema by hand. Properties present in
+ * every array element are required; string fields that repeat a few values
+ * across elements become an `enum`.
+ */
+export function schemaFromExample(sampleJson: string): JSONSchema7 {
+  return JSON.parse(native.schemaFromExample(sampleJson)) as JSONSchema7;
+}
+
+/**
+ * Unified structured generation that accepts either Zod schemas or JSON Schema
+ */
+export async function structured<T = unknown>(options: {
+  prompt: string;
+  schema: z.ZodType<T> | JSONSchema7;
+  temperature?: number;
+  maxTokens?: number;
+  schemaRetries?: number;
+}): Promise<{ text: string; object: T }> {
+  const { prompt, schema, temperature, maxTokens, schemaRetries } = options;
+
+  let jsonSchemaString: string;
+
+  // Auto-detect Zod vs JSON Schema
+  if (typeof schema === "object" && schema !== null && "parse" in schema) {
+    // It's a Zod schema
+    const jsonSchemaObj = zodToJsonSchema(schema as z.ZodType<T>, "Root");
+    jsonSchemaString = JSON.stringify(jsonSchemaObj);
+  } else {
+    // It's already a JSON Schema
+    jsonSchemaString = JSON.stringify(schema);
+  }
+
+  return appleAISDK.generateStructured({
+    prompt,
+    schemaJson: jsonSchemaString,
+    temperature,
+    maxTokens,
+    schemaRetries,
+  });
+}
+
+/**
+ * Stream structured generation: yields a snapshot of the object each time
+ * the model fills in more of it, so UIs can render fields as they arrive.
+ * The last snapshot is the complete object.
+ */
+export function structuredStream<T = unknown>(options: {
+  prompt: string;
+  schema: z.ZodType<T> | JSONSchema7;
+  temperature?: number;
+  maxTokens?: number;
+}): AsyncIterableIterator<Partial<T>> {
+  const { prompt, schema, temperature, maxTokens } = options;
+
+  const schemaJson =
+    typeof schema === "object" && schema !== null && "parse" in schema
+      ? JSON.stringify(zodToJsonSchema(schema as z.ZodType<T>, "Root"))
+      : JSON.stringify(schema);
+
+  const readable = new Readable({ read() {}, objectMode: true });
+  unifiedBindings.generateStream(
+    toNativeMessages([{ role: "user", content: prompt }]),
+    { schemaJson, temperature, maxTokens },
+    (err, event) => {
+      if (err || event?.type === "error") {
+        readable.destroy(
+          (err as Error) ??
+            (event?.type === "error" ? streamError(event) : new Error("Stream error"))
+        );
+        return;
+      }
+      if (event?.type === "object-delta") {
+        readable.push(event.object);
+      } else if (event?.type === "finish") {
+        readable.push(null);
+      }
+    }
+  );
+  return readable[Symbol.asyncIterator]() as AsyncIterableIterator<Partial<T>>;
+}
 
-    const tool = options.tools[id - 1]; // toolIds are 1-based
-    if (!tool) {
-      // Always provide a result to avoid hanging
-      toolBindings.toolResult(id, "{}");
-      return;
+/** Events produced by `_streamChatForVercelAISDK` */
+export type VercelStreamEvent =
+  | { type: "text"; text: string }
+  | { type: "tool-input-start"; toolCallId: string; toolName: string }
+  | { type: "tool-input-delta"; toolCallId: string; delta: string }
+  | {
+      type: "tool-call";
+      toolCallId: string;
+      toolName: string;
+      args: Record<string, unknown>;
     }
+  | { type: "finish"; finishReason: FinishReason; usage?: TokenUsage };
 
-    try {
-      const args = JSON.parse(argsJson);
+/**
+ * @deprecated Don't use this function directly. It's used internally by the Vercel AI SDK.
+ * Stream chat that properly integrates with Vercel AI SDK's multi-step tool calling.
+ * This function emits tool-call events and ends the stream, allowing the SDK to
+ * orchestrate tool execution and restart generation with updated messages.
+ *
+ * Early termination is enabled by default when tools are present, saving compute
+ * resources by stopping the Swift streaming loop after tool calls complete.
+ *
+ */
+export function _streamChatForVercelAISDK<
+  TTools extends ReadonlyArray<EphemeralTool<JSONSchema7>>
+>(options: {
+  messages: ChatMessage[];
+  tools: TTools;
+  temperature?: number;
+}): AsyncIterableIterator<VercelStreamEvent> {
+  // Build tool schemas for the native layer
+  const toolSchemas = options.tools.map((tool, idx) => ({
+    id: idx + 1,
+    name: tool.name,
+    description: tool.description ?? "",
+    parameters: tool.jsonSchema,
+  }));
 
-      // Collect tool call for post-processing
-      collectedToolCalls.push({
-        id,
-        toolName: tool.name,
-        args,
-      });
+  // Collect all tool calls that occur during generation
+  const collectedToolCalls: Array<{
+    toolCallId: string;
+    toolName: string;
+    args: Record<string, unknown>;
+  }> = [];
 
-      // Immediately provide placeholder result to Swift to avoid hanging
-      toolBindings.toolResult(id, "{}");
-    } catch (_error) {
-      // Always provide a result to avoid hanging
+  const readable = new Readable({ read() {}, objectMode: true });
+
+  // Per-request handlers that answer with a placeholder so Swift doesn't hang;
+  // the SDK runs the tools itself from the emitted tool-call events
+  const toolHandlers: Record<string, NativeToolCallback> = {};
+  for (const tool of options.tools) {
+    toolHandlers[tool.name] = (_err, id) => {
       toolBindings.toolResult(id, "{}");
-    }
-  });
+    };
+  }
 
-  const messagesJson = JSON.stringify(options.messages);
+  const nativeMessages = toNativeMessages(options.messages);
   const schemasJson = JSON.stringify(toolSchemas);
 
   let generationComplete = false;
 
-  // Helper to emit tool calls and close stream
-  const finishWithToolCalls = () => {
+  // Helper to emit tool calls, then the finish, and close stream
+  const finishWithToolCalls = (finishReason: FinishReason, usage?: TokenUsage) => {
     if (generationComplete) return;
     generationComplete = true;
 
     // Emit all collected tool calls
     for (const call of collectedToolCalls) {
-      readable.push({
-        type: "tool-call",
-        toolCallId: `tool-call-${crypto.randomUUID()}`,
-        toolName: call.toolName,
-        args: call.args,
-      });
+      readable.push({ type: "tool-call", ...call });
     }
 
+    readable.push({ type: "finish", finishReason, usage });
     readable.push(null);
-    toolBindings.clearToolCallback?.();
   };
 
   // Use unified streaming with tools
-  unifiedBindings.generateUnifiedStream(
-    messagesJson,
-    schemasJson,
-    null, // no schema
-    options.temperature ?? undefined,
-    undefined, // maxTokens
-    true, // stopAfterToolCalls default (OpenAI behavior)
-    (err, chunk) => {
-      if (err) {
-        readable.destroy(err as Error);
-        toolBindings.clearToolCallback?.();
+  unifiedBindings.generateStream(
+    nativeMessages,
+    {
+      toolsJson: schemasJson,
+      temperature: options.temperature,
+      stopAfterToolCalls: true, // OpenAI behavior
+      toolHandlers,
+    },
+    (err, event) => {
+      if (err || event?.type === "error") {
+        readable.destroy(
+          (err as Error) ??
+            (event?.type === "error" ? streamError(event) : new Error("Stream error"))
+        );
         return;
       }
 
-      if (chunk === null || chunk === "") {
-        finishWithToolCalls();
+      if (event?.type === "finish") {
+        finishWithToolCalls(event.finishReason, event.usage);
         return;
       }
 
-      // Stream text content
-      readable.push({ type: "text", text: chunk });
+      // Stream text content and tool input as it arrives
+      if (event?.type === "text-delta") {
+        readable.push({ type: "text", text: event.text });
+      } else if (event?.type === "tool-call-start") {
+        readable.push({
+          type: "tool-input-start",
+          toolCallId: event.toolCallId,
+          toolName: event.toolName,
+        });
+      } else if (event?.type === "tool-call-delta") {
+        readable.push({
+          type: "tool-input-delta",
+          toolCallId: event.toolCallId,
+          delta: event.argsDelta,
+        });
+      } else if (event?.type === "tool-call") {
+        collectedToolCalls.push({
+          toolCallId: event.toolCallId,
+          toolName: event.toolName,
+          args: JSON.parse(event.args),
+        });
+      }
     }
   );
 
-  return readable[Symbol.asyncIterator]() as AsyncIterableIterator<
-    | { type: "text"; text: string }
-    | {
-        type: "tool-call";
-        toolCallId: string;
-        toolName: string;
-        args: Record<string, unknown>;
-      }
-  >;
+  return readable[Symbol.asyncIterator]() as AsyncIterableIterator<VercelStreamEvent>;
 }
 
 /**
- * Unified generation function that exposes all capabilities
+ * Register a process-wide handler for calls to `tool.name`. Named handlers
+ * take precedence over the catch-all tool callback, so no dispatch switch is
+ * needed in user code. Requests expose a registered tool to the model by
+ * listing its name in `tools`.
  */
-export async function chat<T = unknown>(options: {
-  messages: ChatMessage[] | string;
-  tools?: EphemeralTool<JSONSchema7>[];
-  schema?: z.ZodType<T> | JSONSchema7;
-  temperature?: number;
-  maxTokens?: number;
+export function registerTool<TSchema extends JSONSchema7>(
+  tool: EphemeralTool<TSchema>
+): void {
+  toolBindings.registerTool(
+    tool.name,
+    nativeToolHandler(tool),
+    JSON.stringify({
+      description: tool.description ?? "",
+      parameters: tool.jsonSchema,
+    })
+  );
+}
+
+/** Adapt an `EphemeralTool` to the native handler protocol */
+function nativeToolHandler<TSchema extends JSONSchema7>(
+  tool: EphemeralTool<TSchema>
+): NativeToolCallback {
+  return async (err, id, argsJson) => {
+    if (err) {
+      toolBindings.toolError(id, err.message);
+      return;
+    }
+    try {
+      const args = JSON.parse(argsJson) as Record<string, unknown>;
+      const result = await tool.handler(args);
+      toolBindings.toolResult(id, JSON.stringify(result ?? null));
+    } catch (error) {
+      toolBindings.toolError(
+        id,
+        error instanceof Error ? error.message : String(error)
+      );
+    }
+  };
+}
+
+/**
+ * Adapt an approval hook to the native protocol. The native layer asks it
+ * before every tool call of the request, whichever kind of tool runs it
+ */
+function nativeToolApproval(
+  approve: ToolApprovalHandler
+): NativeToolApprovalCallback {
+  return async (err, id, toolName, argsJson) => {
+    if (err) {
+      toolBindings.toolError(id, err.message);
+      return;
+    }
+    try {
+      const args = JSON.parse(argsJson) as Record<string, unknown>;
+      const decision = await approve(toolName, args);
+      toolBindings.toolApproval(
+        id,
+        JSON.stringify(
+          typeof decision === "string" ? { type: decision } : decision
+        )
+      );
+    } catch (error) {
+      toolBindings.toolError(
+        id,
+        error instanceof Error ? error.message : String(error)
+      );
+    }
+  };
+}
+
+/**
+ * Tool definitions JSON plus request-scoped handlers for `tools`; names refer
+ * to tools from `registerTool`
+ */
+function requestTools(
+  tools: RequestTool[] | undefined,
+  approve?: ToolApprovalHandler
+): {
+  toolsJson: string | null;
+  toolHandlers: Record<string, NativeToolCallback>;
+  registeredTools?: string[];
+  toolApproval?: NativeToolApprovalCallback;
+} {
+  const toolHandlers: Record<string, NativeToolCallback> = {};
+  const toolApproval = approve && nativeToolApproval(approve);
+  const registeredTools = tools?.filter(
+    (tool): tool is string => typeof tool === "string"
+  );
+  const ephemeralTools = tools?.filter(
+    (tool): tool is EphemeralTool<JSONSchema7> => typeof tool !== "string"
+  );
+  if (!ephemeralTools || ephemeralTools.length === 0) {
+    return { toolsJson: null, toolHandlers, registeredTools, toolApproval };
+  }
+  const toolSchemas = ephemeralTools.map((tool, idx) => {
+    // Handlers are scoped to this request, so concurrent chats don't clash
+    toolHandlers[tool.name] = nativeToolHandler(tool);
+    return {
+      id: idx + 1,
+      name: tool.name,
+      description: tool.description ?? "",
+      parameters: tool.jsonSchema,
+    };
+  });
+  return {
+    toolsJson: JSON.stringify(toolSchemas),
+    toolHandlers,
+    registeredTools,
+    toolApproval,
+  };
+}
+
+/** Remove the handler registered for `name`; returns whether one existed */
+export function unregisterTool(name: string): boolean {
+  return toolBindings.unregisterTool(name);
+}
+
+export interface AgentOptions extends GenerationOptions {
+  /** Most generate → tool → regenerate rounds to run (default 10) */
+  maxSteps?: number;
+  /** Called as each step completes */
+  onStep?: (step: AgentStep) => void;
+  /** Same as `ChatOptions.onToolApproval` */
+  onToolApproval?: ToolApprovalHandler;
+}
+
+/**
+ * Let the model call `tools` until it answers without one or `maxSteps` is
+ * reached. The native layer runs the loop, feeding every tool output back to
+ * the model, and reports each round through `onStep`.
+ */
+export function runAgent(
+  messages: ChatMessage[] | string,
+  tools: RequestTool[],
+  options: AgentOptions = {}
+): Promise<AgentResult> {
+  const { maxSteps, onStep, onToolApproval } = options;
+  const normalizedMessages: ChatMessage[] =
+    typeof messages === "string"
+      ? [{ role: "user", content: messages }]
+      : messages;
+  const { toolsJson, toolHandlers, registeredTools, toolApproval } =
+    requestTools(tools, onToolApproval);
+  return unifiedBindings.runAgent(
+    toNativeMessages(normalizedMessages),
+    {
+      ...toNativeOptions(options),
+      toolsJson,
+      toolHandlers,
+      registeredTools,
+      toolApproval,
+    },
+    {
+      maxSteps,
+      onStep:
+        onStep &&
+        ((err, step) => {
+          if (!err) onStep(step);
+        }),
+    }
+  );
+}
+
+/** Options shared by every `chat` overload */
+export interface ChatOptions<T = unknown> extends GenerationOptions {
   /**
-   * If true, the generation will stop after the tool calls are complete.
-   * This is the default behavior for OpenAI.
-   * @default true
+   * Conversation so far. A trailing assistant message is treated as a prefill:
+   * the reply continues from its content, which is not repeated in the result.
    */
-  stopAfterToolCalls?: boolean;
-  stream?: false;
-}): Promise<{ text: string; object?: T; toolCalls?: any[] }>;
-
-export function chat<T = unknown>(options: {
   messages: ChatMessage[] | string;
-  tools?: EphemeralTool<JSONSchema7>[];
+  /** Tools for this request, or names of tools added with `registerTool` */
+  tools?: RequestTool[];
   schema?: z.ZodType<T> | JSONSchema7;
-  temperature?: number;
-  maxTokens?: number;
   /**
    * If true, the generation will stop after the tool calls are complete.
    * This is the default behavior for OpenAI.
    * @default true
    */
   stopAfterToolCalls?: boolean;
-  stream: true;
-}): AsyncIterableIterator<string>;
-
-export function chat<T = unknown>(options: {
-  messages: ChatMessage[] | string;
-  tools?: EphemeralTool<JSONSchema7>[];
-  schema?: z.ZodType<T> | JSONSchema7;
-  temperature?: number;
-  maxTokens?: number;
   /**
-   * If true, the generation will stop after the tool calls are complete.
-   * This is the default behavior for OpenAI.
-   * @default true
+   * Called before each tool call runs, including registered, native and MCP
+   * tools. Return "approve", "deny" (optionally with a reason) or
+   * `{ type: "modify", args }` to run it with other arguments. Denied calls
+   * reach the model as declined, so it can continue without them.
    */
-  stopAfterToolCalls?: boolean;
-  stream?: boolean;
-}):
-  | Promise<{ text: string; object?: T; toolCalls?: any[] }>
-  | AsyncIterableIterator<string> {
+  onToolApproval?: ToolApprovalHandler;
+}
+
+/** Native messages and options for a `chat` request */
+function chatRequest<T>(options: ChatOptions<T>): {
+  nativeMessages: NativeChatMessage[];
+  nativeOptions: NativeGenerationOptions;
+} {
   const {
     messages,
     tools,
     schema,
-    temperature,
-    maxTokens,
     stopAfterToolCalls = true, // default to true for OpenAI compatibility
-    stream = false,
+    onToolApproval,
   } = options;
 
   // Normalize messages
@@ -580,44 +3612,10 @@ export function chat<T = unknown>(options: {
     typeof messages === "string"
       ? [{ role: "user", content: messages }]
       : messages;
-  const messagesJson = JSON.stringify(normalizedMessages);
-
-  // Prepare tools JSON if provided
-  let toolsJson: string | null = null;
-  const toolMap = new Map<number, EphemeralTool<JSONSchema7>>();
-
-  if (tools && tools.length > 0) {
-    const toolSchemas = tools.map((tool, idx) => {
-      const id = idx + 1;
-      toolMap.set(id, tool);
-      return {
-        id,
-        name: tool.name,
-        description: tool.description ?? "",
-        parameters: tool.jsonSchema,
-      };
-    });
-    toolsJson = JSON.stringify(toolSchemas);
+  const nativeMessages = toNativeMessages(normalizedMessages);
 
-    // Setup tool callback
-    toolBindings.setToolCallback(async (err, id, argsJson) => {
-      if (err) {
-        toolBindings.toolResult(id, "{}");
-        return;
-      }
-      const tool = toolMap.get(id);
-      if (!tool) {
-        toolBindings.toolResult(id, "{}");
-        return;
-      }
-      try {
-        const result = await tool.handler(JSON.parse(argsJson));
-        toolBindings.toolResult(id, JSON.stringify(result ?? null));
-      } catch {
-        toolBindings.toolResult(id, "{}");
-      }
-    });
-  }
+  const { toolsJson, toolHandlers, registeredTools, toolApproval } =
+    requestTools(tools, onToolApproval);
 
   // Prepare schema JSON if provided (and no tools)
   let schemaJson: string | null = null;
@@ -632,29 +3630,107 @@ export function chat<T = unknown>(options: {
     }
   }
 
+  const nativeOptions: NativeGenerationOptions = {
+    ...toNativeOptions(options),
+    toolsJson,
+    schemaJson,
+    stopAfterToolCalls,
+    toolHandlers,
+    registeredTools,
+    toolApproval,
+  };
+
+  return { nativeMessages, nativeOptions };
+}
+
+/**
+ * Stream a `chat` request as Vercel AI SDK data stream parts (`0:"text"\n`,
+ * tool call and result parts, then finish parts with usage), ready to send
+ * as a response body with the `x-vercel-ai-data-stream: v1` header.
+ */
+export function dataStream<T = unknown>(
+  options: ChatOptions<T>
+): AsyncIterableIterator<string> {
+  const { nativeMessages, nativeOptions } = chatRequest(options);
+  const readable = new Readable({ read() {}, objectMode: true });
+  native.generateDataStream(
+    nativeMessages,
+    nativeOptions,
+    (err: Error | null, parts: string, done: boolean) => {
+      if (err) {
+        readable.destroy(err);
+        return;
+      }
+      if (parts) readable.push(parts);
+      if (done) readable.push(null);
+    }
+  );
+  return readable[Symbol.asyncIterator]() as AsyncIterableIterator<string>;
+}
+
+/**
+ * Unified generation function that exposes all capabilities
+ */
+export async function chat<T = unknown>(
+  options: ChatOptions<T> & {
+    /** Aborts the underlying native generation; the promise rejects with code "Cancelled" */
+    signal?: AbortSignal;
+    stream?: false;
+  }
+): Promise<{
+  text: string;
+  object?: T;
+  toolCalls?: any[];
+  finishReason: FinishReason;
+  usage?: TokenUsage;
+  candidates?: GenerationResult[];
+  toolTraces?: ToolCallTrace[];
+  guardrail?: GuardrailViolation;
+}>;
+
+export function chat<T = unknown>(
+  options: ChatOptions<T> & { stream: true }
+): AsyncIterableIterator<string>;
+
+export function chat<T = unknown>(
+  options: ChatOptions<T> & { signal?: AbortSignal; stream?: boolean }
+):
+  | Promise<{
+      text: string;
+      object?: T;
+      toolCalls?: any[];
+      finishReason: FinishReason;
+      usage?: TokenUsage;
+      candidates?: GenerationResult[];
+      toolTraces?: ToolCallTrace[];
+      guardrail?: GuardrailViolation;
+    }>
+  | AsyncIterableIterator<string> {
+  const { signal, stream = false } = options;
+  const { nativeMessages, nativeOptions } = chatRequest(options);
+
   if (stream) {
     // Streaming mode
     const readable = new Readable({ read() {}, objectMode: true });
 
-    unifiedBindings.generateUnifiedStream(
-      messagesJson,
-      toolsJson,
-      schemaJson,
-      temperature,
-      maxTokens,
-      stopAfterToolCalls,
-      (err, chunk) => {
-        if (err) {
-          readable.destroy(err as Error);
-          if (toolMap.size > 0) toolBindings.clearToolCallback?.();
+    unifiedBindings.generateStream(
+      nativeMessages,
+      nativeOptions,
+      (err, event) => {
+        if (err || event?.type === "error") {
+          readable.destroy(
+            (err as Error) ??
+              (event?.type === "error" ? streamError(event) : new Error("Stream error"))
+          );
           return;
         }
-        if (chunk === null || chunk === "") {
+        if (event?.type === "finish") {
           readable.push(null);
-          if (toolMap.size > 0) toolBindings.clearToolCallback?.();
           return;
         }
-        readable.push(chunk);
+        if (event?.type === "text-delta") {
+          readable.push(event.text);
+        }
       }
     );
 
@@ -662,43 +3738,57 @@ export function chat<T = unknown>(options: {
   } else {
     // Non-streaming mode
     return (async () => {
+      let abort: (() => void) | undefined;
       try {
-        const raw = await unifiedBindings.generateUnified(
-          messagesJson,
-          toolsJson,
-          schemaJson,
-          temperature,
-          maxTokens,
-          stopAfterToolCalls
-        );
-
-        if (raw?.startsWith("Error: ")) {
-          throw new Error(raw.slice(7)); // Remove "Error: " prefix and throw
+        let result: GenerationResult;
+        if (signal) {
+          signal.throwIfAborted();
+          const handle = unifiedBindings.generateAbortable(
+            nativeMessages,
+            nativeOptions
+          );
+          abort = () => handle.cancel();
+          signal.addEventListener("abort", abort, { once: true });
+          result = await handle.promise.catch(mapSchemaError);
+        } else {
+          result = await unifiedBindings
+            .generate(nativeMessages, nativeOptions)
+            .catch(mapSchemaError);
         }
 
-        // Parse the result
-        const parsed = JSON.parse(raw);
+        const { text, finishReason, usage, candidates } = result;
 
-        if (schemaJson && parsed.object) {
+        if (nativeOptions.schemaJson && result.object) {
           // Structured generation result
           return {
-            text: parsed.text,
-            object: parsed.object as T,
+            text,
+            object: result.object as T,
+            finishReason,
+            usage,
+            candidates,
           };
-        } else if (parsed.toolCalls) {
+        } else if (result.toolCalls) {
           // Tool calling result
           return {
-            text: parsed.text,
-            toolCalls: parsed.toolCalls,
+            text,
+            toolCalls: result.toolCalls,
+            finishReason,
+            usage,
+            candidates,
+            toolTraces: result.toolTraces,
           };
         } else {
           // Basic generation result
           return {
-            text: parsed.text,
+            text,
+            finishReason,
+            usage,
+            candidates,
+            guardrail: result.guardrail,
           };
         }
       } finally {
-        if (toolMap.size > 0) toolBindings.clearToolCallback?.();
+        if (abort) signal?.removeEventListener("abort", abort);
       }
     })();
   }
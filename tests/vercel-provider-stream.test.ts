@@ -0,0 +1,75 @@
+import { test, expect } from "bun:test";
+import type { LanguageModelV2StreamPart } from "@ai-sdk/provider";
+import { appleAISDK } from "../src/apple-ai";
+import { appleAI } from "../src/apple-ai-provider";
+
+/**
+ * The provider's `finish` part carries the stream's finish reason and token
+ * usage instead of a fixed "stop" with no usage.
+ */
+
+async function finishPart(
+  options: Parameters<ReturnType<typeof appleAI>["doStream"]>[0]
+) {
+  const { stream } = await appleAI("apple-on-device").doStream(options);
+  const parts: LanguageModelV2StreamPart[] = [];
+  for await (const part of stream) {
+    parts.push(part);
+  }
+  const finish = parts.at(-1);
+  if (finish?.type !== "finish") {
+    throw new Error(`Stream ended with ${finish?.type ?? "nothing"}`);
+  }
+  return finish;
+}
+
+test("text streams report their finish reason and usage", async () => {
+  const availability = await appleAISDK.checkAvailability();
+  if (!availability.available) {
+    console.log("Skipping test: Apple Intelligence not available");
+    return;
+  }
+
+  const finish = await finishPart({
+    prompt: [{ role: "user", content: [{ type: "text", text: "Say hello" }] }],
+  });
+
+  expect(finish.finishReason).toBe("stop");
+  expect(finish.usage.inputTokens).toBeGreaterThan(0);
+  expect(finish.usage.outputTokens).toBeGreaterThan(0);
+  expect(finish.usage.totalTokens).toBe(
+    finish.usage.inputTokens! + finish.usage.outputTokens!
+  );
+}, 20000);
+
+test("tool streams finish with tool-calls when the model calls a tool", async () => {
+  const availability = await appleAISDK.checkAvailability();
+  if (!availability.available) {
+    console.log("Skipping test: Apple Intelligence not available");
+    return;
+  }
+
+  const finish = await finishPart({
+    prompt: [
+      {
+        role: "user",
+        content: [{ type: "text", text: "What's the weather in Paris? Use the tool." }],
+      },
+    ],
+    tools: [
+      {
+        type: "function",
+        name: "get_weather",
+        description: "Get the current weather for a city",
+        inputSchema: {
+          type: "object",
+          properties: { city: { type: "string" } },
+          required: ["city"],
+        },
+      },
+    ],
+  });
+
+  expect(finish.finishReason).toBe("tool-calls");
+  expect(finish.usage.inputTokens).toBeGreaterThan(0);
+}, 30000);